@@ -1,49 +1,650 @@
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use bitte_lib::{
-    certs, rebuild, ssh, terraform,
-    types::{BitteFind, ClusterHandle},
+    acl, acme, archive, azure,
+    campaign::{Campaign, PatchStatus, Progress},
+    cancel::{self, CancellationToken},
+    certs::{self, Expiry},
+    config::BitteConfig,
+    consul,
+    costs::{self, CostReport},
+    db, deployment,
+    detail::{self, NodeDetail},
+    drain::{self, Drain},
+    endpoint::{self, Service},
+    error::Error,
+    events, executor, export, history,
+    images::{self, ImageAction},
+    info::{AsgCapacity, SpotStatus},
+    inventory, job, lease, login,
+    logs::{self, LogKind},
+    nomad::{self, NomadApi},
+    preflight::{self, Check},
+    provision::{self, Provision},
+    raft, rebuild,
+    reconcile::NodeSetDiff,
+    recording, restart,
+    rotate::{self, NodeRole},
+    runs::{self, Run},
+    secret,
+    snapshot::{self, SnapshotStore},
+    ssh::ssh_args,
+    store::{self, StoreReport},
+    terraform::{self, PlanSummary},
+    text,
+    tfc::{Phase, RemoteWorkspace},
+    transcript::Transcript,
+    types::{
+        BitteCluster, BitteFind, BitteNode, BitteProvider, ClusterHandle, CueRender,
+        NomadDeployment, NomadDeploymentStatus, TfcVarAttributes,
+    },
+    unseal::{self, Unsealer, VaultInstance, VaultStatus},
+    vault,
+    verify::VerifyConfig,
+    versions::{self, NodeVersions},
 };
 use clap::ArgMatches;
 use deploy::cli;
 use log::*;
-use prettytable::{cell, row, Table};
+use prettytable::{cell, row, Cell, Row, Table};
 use std::net::IpAddr;
-use std::{env, io, path::Path, process::Command, time::Duration};
+use std::{
+    collections::HashMap,
+    env,
+    io::{self, Read, Write},
+    path::Path,
+    process::{Command, Stdio},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 use tokio::task::JoinHandle;
+use uuid::Uuid;
 
 pub(crate) async fn certs(sub: &ArgMatches) -> Result<()> {
     let domain: String = sub.value_of_t_or_exit("domain");
-    env::set_var("VAULT_ADDR", format!("https://vault.{}", domain));
+    env::set_var("VAULT_ADDR", endpoint::address(Service::Vault, &domain));
     env::set_var("VAULT_CACERT", "secrets/ca.pem");
     env::set_var("VAULT_FORMAT", "json");
     env::set_var("VAULT_SKIP_VERIFY", "true");
 
+    let signer = match (sub.value_of("signed"), sub.value_of("ca")) {
+        (Some(cert), Some(ca)) => certs::IssuingSigner::Signed {
+            cert: cert.into(),
+            ca: ca.into(),
+        },
+        (Some(_), None) => bail!("--signed needs the chain of the CA that signed it in --ca"),
+        (None, Some(ca)) => certs::IssuingSigner::Key {
+            ca: ca.into(),
+            key: sub
+                .value_of("ca_key")
+                .context("--ca needs its key in --ca-key, or the signed CSR in --signed")?
+                .into(),
+        },
+        (None, None) => certs::IssuingSigner::default(),
+    };
+    let cross = match (sub.value_of("cross_ca"), sub.value_of("cross_ca_key")) {
+        (Some(ca), Some(key)) => Some((Path::new(ca), Path::new(key))),
+        _ => None,
+    };
+
     certs::vault_login()?;
-    certs::write_issuing_ca(&domain);
-    certs::sign_intermediate()?;
+    // the CSR is already out for signing when the signed certificate comes back
+    if !sub.is_present("signed") {
+        certs::write_issuing_ca(&domain);
+    }
+    if sub.is_present("csr_only") {
+        println!(
+            "wrote the CSR of the issuing CA to {}, have it signed and pass the result with --signed",
+            certs::ISSUING_CSR
+        );
+        return Ok(());
+    }
+    certs::sign_intermediate(&signer, cross)?;
+    Ok(())
+}
+
+/// Write the CA chain of the cluster to a file, and install its root into the OS trust stores
+/// with `--install` so the web UIs of Consul, Nomad, and Vault are trusted.
+pub(crate) async fn certs_export_ca(sub: &ArgMatches, cluster: ClusterHandle) -> Result<()> {
+    let cluster = cluster.await??;
+
+    let chain = match sub.value_of("node") {
+        Some(needle) => {
+            let node = cluster.nodes.clone().find_needle(needle)?;
+            let args = vec!["cat".to_string(), "/etc/ssl/certs/ca.pem".to_string()];
+            let mut cmd = Command::new("ssh");
+            cmd.args(ssh_args(node.pub_ip, args, &cluster.name));
+            bitte_lib::sh(cmd)?
+        }
+        None => certs::vault_ca_chain(&cluster.domain).await?,
+    };
+
+    let blocks = certs::pem_blocks(&chain);
+    let root = blocks
+        .last()
+        .context("the CA chain contains no certificates")?;
+
+    let install = sub.is_present("install");
+    let fingerprint = match certs::verify_root(root, sub.value_of("fingerprint")) {
+        Ok(fingerprint) => fingerprint,
+        Err(e) if install => return Err(e.context("the root was not installed")),
+        Err(e) => {
+            warn!("{:#}, compare the fingerprint with a trusted copy", e);
+            certs::pem_fingerprint(root)?
+        }
+    };
+
+    let out = sub
+        .value_of("out")
+        .map(String::from)
+        .unwrap_or_else(|| format!("{}-ca.pem", cluster.name));
+    std::fs::write(&out, blocks.concat())?;
+
+    let root_path = Path::new(&out).with_extension("root.pem");
+    std::fs::write(&root_path, root)?;
+
+    println!(
+        "wrote {} certificates to {}, root SHA-256 fingerprint {}",
+        blocks.len(),
+        out,
+        fingerprint
+    );
+
+    if install {
+        let prompt = format!(
+            "Trust the root with the fingerprint {} on this machine?",
+            fingerprint
+        );
+        if !sub.is_present("yes") && !bitte_lib::confirm(&prompt, "yes")? {
+            bail!("the root was not installed");
+        }
+        for store in certs::install_ca(&root_path, &format!("bitte-{}", cluster.name))? {
+            println!("installed into {}", store);
+        }
+    }
+
+    Ok(())
+}
+
+/// Print when the certificates of the cluster expire, failing if any of them has to be renewed.
+pub(crate) async fn certs_status(sub: &ArgMatches, cluster: ClusterHandle) -> Result<()> {
+    let thresholds = certs::Thresholds {
+        warn_days: sub.value_of_t("warn_days")?,
+        renew_days: sub.value_of_t("renew_days")?,
+    };
+    let cluster = cluster.await??;
+    let statuses = certs::status(
+        &cluster.domain,
+        &cluster.nodes,
+        sub.is_present("public"),
+        thresholds,
+    )
+    .await;
+
+    if sub.is_present("json") {
+        serde_json::to_writer_pretty(io::stdout().lock(), &statuses)?;
+    } else {
+        let mut table = Table::new();
+        table.add_row(row![
+            "Certificate",
+            "Subject",
+            "Expires",
+            "Days Left",
+            "Detail"
+        ]);
+        for status in statuses.iter() {
+            let style = match status.expiry {
+                Expiry::Valid => "Fg",
+                Expiry::Soon => "Fy",
+                _ => "Fr",
+            };
+            let days_left = match status.expiry {
+                Expiry::Unknown => "?".to_string(),
+                Expiry::Expired => "EXPIRED".to_string(),
+                _ => status.days_left.to_string(),
+            };
+            table.add_row(Row::new(vec![
+                Cell::new(&status.source),
+                Cell::new(&text::cell(&status.subject, 50)),
+                Cell::new(&status.not_after),
+                Cell::new(&days_left).style_spec(style),
+                Cell::new(&text::cell(status.error.as_deref().unwrap_or_default(), 50)),
+            ]));
+        }
+        table.printstd();
+    }
+
+    let failed = statuses
+        .iter()
+        .filter(|status| status.expiry.is_failure())
+        .count();
+    if failed > 0 {
+        bail!(
+            "{} certificates expire within {} days or couldn't be read",
+            failed,
+            thresholds.renew_days
+        );
+    }
     Ok(())
 }
 
-pub(crate) async fn provision(sub: &ArgMatches, cluster: String) -> Result<()> {
-    let ip: IpAddr = sub.value_of_t("ip")?;
-    let flake: String = sub.value_of_t_or_exit("flake");
-    let attr: String = sub.value_of_t_or_exit("attr");
-    let cache: String = sub.value_of_t_or_exit("cache");
+/// Issue new certificates from Vault PKI and switch the nodes over one at a time: Consul, Nomad,
+/// and Vault reload theirs in turn, and each has to present the new certificate and report
+/// healthy before the next one does. A node that doesn't gets its previous certificate back.
+pub(crate) async fn certs_rotate(
+    sub: &ArgMatches,
+    cluster: ClusterHandle,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    let add: Vec<String> = sub.values_of_t("san").unwrap_or_default();
+    let dropped: Vec<String> = sub.values_of_t("drop_san").unwrap_or_default();
+    let public = sub.is_present("public");
+    let cluster = cluster.await??;
+    let nomad = NomadApi::for_cluster(&cluster);
+    let mut settings = rotate::load_settings(&cluster.domain).await?;
+
+    let mut nodes = match sub.values_of("node") {
+        Some(needles) => cluster.nodes.clone().find_needles(needles.collect()),
+        None => cluster.nodes.clone(),
+    };
+    if nodes.is_empty() {
+        bail!("there are no nodes to rotate");
+    }
+    // the servers first, so the clients reconnect to servers that are already done
+    nodes.sort_by_key(|node| (NodeRole::of(node) != NodeRole::Core, node.name.clone()));
+
+    let mut changed = false;
+    for role in [NodeRole::Core, NodeRole::Client, NodeRole::Other].iter() {
+        if nodes.iter().any(|node| NodeRole::of(node) == *role) {
+            changed |= settings
+                .role_mut(*role)
+                .update(&add, &dropped, sub.value_of("ttl"));
+        }
+    }
+
+    let mut table = Table::new();
+    table.add_row(row!["Node", "Role", "Services", "Names", "TTL"]);
+    for node in nodes.iter() {
+        let role = NodeRole::of(node);
+        let role_settings = settings.role(role);
+        let services: Vec<String> = role.services().iter().map(|s| s.to_string()).collect();
+        let (names, ips) = rotate::subject_names(node, role, &cluster.domain, &role_settings.sans)?;
+        table.add_row(row![
+            node.name,
+            role.pki_role(),
+            services.join(", "),
+            names
+                .iter()
+                .chain(ips.iter())
+                .cloned()
+                .collect::<Vec<_>>()
+                .join("\n"),
+            role_settings.ttl.as_deref().unwrap_or("default")
+        ]);
+    }
+    table.printstd();
+    if sub.is_present("dry_run") {
+        return Ok(());
+    }
+
+    for service in [Service::Consul, Service::Nomad].iter() {
+        if let Some(core) = nodes
+            .iter()
+            .find(|node| NodeRole::of(node) == NodeRole::Core)
+        {
+            rotate::check_health(&cluster.domain, &nomad, core, *service, public)
+                .await
+                .context("refusing to rotate the certificates of an unhealthy cluster")?;
+        }
+    }
+
+    let prompt = format!(
+        "Rotate the certificates of {} nodes of {}?",
+        nodes.len(),
+        cluster.name
+    );
+    if !sub.is_present("yes") && !bitte_lib::confirm(&prompt, "yes")? {
+        return Ok(());
+    }
+    if changed {
+        rotate::save_settings(&cluster.domain, &settings).await?;
+    }
+
+    for node in nodes.iter() {
+        cancel::check(cancel)?;
+        let role = NodeRole::of(node);
+        let cert = rotate::issue(node, role, &cluster.domain, settings.role(role)).await?;
+        rotate::install(&cluster.name, node.pub_ip, &cert)?;
+        println!(
+            "{}: installed certificate {}",
+            node.name, cert.serial_number
+        );
+
+        let mut reloaded = Vec::new();
+        let switched = certs_switch(
+            &cluster,
+            &nomad,
+            node,
+            &cert.serial_number,
+            public,
+            &mut reloaded,
+        )
+        .await;
+        if let Err(e) = switched {
+            warn!("rolling back {}: {:#}", node.name, e);
+            rotate::restore(&cluster.name, node.pub_ip)?;
+            for service in reloaded.iter() {
+                rotate::reload(&cluster.name, node.pub_ip, *service)?;
+            }
+            return Err(e.context(format!(
+                "{} is back on its previous certificate, the nodes before it keep their new ones",
+                node.name
+            )));
+        }
+
+        history::record(
+            &cluster.name,
+            "certs-rotate",
+            serde_json::json!({
+                "node": node.name,
+                "role": role,
+                "serial": cert.serial_number,
+            }),
+        )?;
+    }
+
+    println!("rotated the certificates of {} nodes", nodes.len());
+    Ok(())
+}
 
-    rebuild::set_ssh_opts(false, &cluster)?;
-    ssh::wait_for_ssh(&ip).await?;
-    ssh::wait_for_ready(&cluster, &ip)?;
-    ssh::ssh_keygen(&ip)?;
+/// Issue a publicly trusted certificate for the endpoints of the cluster over ACME if the one in
+/// Vault is due for renewal, and deploy it to the edge nodes.
+pub(crate) async fn certs_acme(sub: &ArgMatches, cluster: ClusterHandle) -> Result<()> {
+    let config = BitteConfig::load()?.acme;
+    let renew_days: u64 = sub.value_of_t("renew_days")?;
+    let unit: String = sub.value_of_t("unit")?;
+    let add: Vec<String> = sub.values_of_t("san").unwrap_or_default();
+    let dropped: Vec<String> = sub.values_of_t("drop_san").unwrap_or_default();
+    let cluster = cluster.await??;
 
-    let toplevel = format!(
-        "{}#nixosConfigurations.{}.config.system.build.toplevel",
-        flake, attr
+    let stored = acme::stored(&cluster.domain).await?;
+    let names = acme::names(
+        config.names(&cluster.domain),
+        stored.as_ref(),
+        &add,
+        &dropped,
     );
-    let cache = format!("{}&secret-key=secrets/nix-secret-key-file", &cache);
-    let flake = format!("{}#{}", &flake, &attr);
-    rebuild::nix_copy_to_cache(&toplevel, &cache)?;
-    rebuild::nix_copy_to_machine(&toplevel, &ip)?;
-    rebuild::nixos_rebuild(&flake, &ip)?;
+    let directory = if sub.is_present("staging") {
+        acme::LETS_ENCRYPT_STAGING.to_string()
+    } else {
+        config
+            .directory
+            .unwrap_or_else(|| acme::LETS_ENCRYPT.to_string())
+    };
+    let due = acme::due(stored.as_ref(), &names, &directory, renew_days)?;
+    let cert = if sub.is_present("force") || due {
+        let email = sub
+            .value_of("email")
+            .map(String::from)
+            .or(config.email)
+            .context("give the contact of the ACME account with --email or acme.email")?;
+
+        let cert = acme::issue(&cluster.name, &names, &email, &directory)?;
+        acme::store(&cluster.domain, &cert).await?;
+        println!(
+            "issued a certificate for {}, valid for {} days",
+            names.join(", "),
+            (cert.expires as i64 - SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64)
+                / 86_400
+        );
+        history::record(
+            &cluster.name,
+            "certs-acme",
+            serde_json::json!({ "names": names, "expires": cert.expires }),
+        )?;
+        cert
+    } else {
+        println!(
+            "the certificate in Vault is valid for more than {} days",
+            renew_days
+        );
+        stored.context("there is no certificate in Vault")?
+    };
+
+    let edges = match sub.values_of("node") {
+        Some(needles) => cluster.nodes.clone().find_needles(needles.collect()),
+        None => cluster
+            .nodes
+            .iter()
+            .filter(|node| node.name.starts_with("routing"))
+            .cloned()
+            .collect(),
+    };
+    for node in edges.iter() {
+        acme::deploy(&cluster.name, node.pub_ip, &cluster.domain, &cert, &unit)?;
+        println!("deployed to {}", node.name);
+    }
+    Ok(())
+}
+
+/// Reload the services of `node` one by one, waiting for each to present the certificate with
+/// `serial` and, on core nodes, for its servers to be healthy. `reloaded` collects the services
+/// that have to be reloaded again when rolling back.
+async fn certs_switch(
+    cluster: &BitteCluster,
+    nomad: &NomadApi,
+    node: &BitteNode,
+    serial: &str,
+    public: bool,
+    reloaded: &mut Vec<Service>,
+) -> Result<()> {
+    let role = NodeRole::of(node);
+    let ip = if public { node.pub_ip } else { node.priv_ip };
+    for service in role.services().iter() {
+        rotate::reload(&cluster.name, node.pub_ip, *service)?;
+        reloaded.push(*service);
+        rotate::verify(ip, *service, serial).await?;
+        if role == NodeRole::Core {
+            rotate::wait_healthy(&cluster.domain, nomad, node, *service, public).await?;
+        }
+        println!("{}: {} switched", node.name, service);
+    }
+    Ok(())
+}
+
+pub(crate) async fn provision<F>(
+    sub: &ArgMatches,
+    cluster: String,
+    domain: String,
+    refresh: F,
+    cancel: &CancellationToken,
+) -> Result<()>
+where
+    F: Fn() -> ClusterHandle,
+{
+    if !sub.is_present("target") && !sub.is_present("new") {
+        let name: String = sub.value_of_t_or_exit("name");
+        let provision = Provision {
+            ip: sub.value_of_t("ip")?,
+            transcript: Transcript::create(&cluster, &name)?,
+            name,
+            cluster,
+            flake: sub.value_of_t_or_exit("flake"),
+            attr: sub.value_of_t_or_exit("attr"),
+            cache: sub.value_of_t_or_exit("cache"),
+            labeled: false,
+            upload: sub.is_present("upload_transcripts"),
+        };
+        let config = BitteConfig::load()?.preflight;
+        let shared = preflight::cluster_checks(&provision.cache, &config).await;
+        let checks = preflight::node_checks(&provision, None, &config).await;
+        report_checks(&shared, &[(&provision, &checks)])?;
+
+        let result = provision.run_all(cancel).await;
+        println!("transcript: {}", provision.finish(&result));
+        result?;
+    } else {
+        // new nodes may be missing from the cached cluster
+        provision_many(sub, refresh().await??, cancel).await?;
+    }
+
+    if sub.is_present("bootstrap_consul") {
+        consul_bootstrap(&domain, Path::new(CONSUL_BOOTSTRAP_FILE)).await?;
+    }
+    Ok(())
+}
+
+/// Provision the nodes given with `--target`, or all that need it with `--new`, and summarize
+/// how it went.
+async fn provision_many(
+    sub: &ArgMatches,
+    cluster: BitteCluster,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    let jobs: usize = sub.value_of_t_or_exit("jobs");
+    let cache = cluster
+        .terra
+        .as_ref()
+        .and_then(|terra| terra.s3_cache.clone())
+        .context("the cluster has no binary cache in its terraform state")?;
+
+    let mut nodes = match sub.values_of("target") {
+        Some(needles) => {
+            let needles: Vec<&str> = needles.collect();
+            let nodes = cluster.nodes.clone().find_needles(needles.clone());
+            if let Some(missing) = needles
+                .iter()
+                .find(|needle| !nodes.iter().any(|node| node.matches(needle)))
+            {
+                bail!("{} does not match any nodes", missing);
+            }
+            nodes
+        }
+        None => cluster.nodes.clone(),
+    };
+    inventory::resolve_flake_attrs(&mut nodes, &BitteConfig::load()?.flake_attrs)?;
+    if sub.is_present("new") {
+        nodes = provision::find_unprovisioned(&cluster.name, nodes).await?;
+    }
+    if nodes.is_empty() {
+        println!("there are no nodes to provision");
+        return Ok(());
+    }
+
+    let config = BitteConfig::load()?.preflight;
+    let shared = preflight::cluster_checks(&cache, &config).await;
+    let mut checked = Vec::with_capacity(nodes.len());
+    for node in nodes.iter() {
+        let provision = Provision {
+            ip: node.pub_ip,
+            name: node.name.clone(),
+            cluster: cluster.name.clone(),
+            flake: ".".to_string(),
+            attr: node.nixos.clone(),
+            cache: cache.clone(),
+            labeled: true,
+            transcript: Transcript::create(&cluster.name, &node.name)?,
+            upload: sub.is_present("upload_transcripts"),
+        };
+        // only AWS has the security groups the ports are checked against
+        let known =
+            Some((&cluster.pool, node)).filter(|_| matches!(node.provider, BitteProvider::AWS));
+        let checks = preflight::node_checks(&provision, known, &config).await;
+        checked.push((provision, checks));
+    }
+    let by_node: Vec<(&Provision, &[Check])> = checked
+        .iter()
+        .map(|(provision, checks)| (provision, checks.as_slice()))
+        .collect();
+    report_checks(&shared, &by_node)?;
+    let provisions = checked
+        .into_iter()
+        .map(|(provision, _)| provision)
+        .collect();
+    let outcomes = provision::run_many(provisions, jobs, cancel).await?;
+
+    let mut table = Table::new();
+    table.add_row(row!["Node", "IP", "Took", "Result", "Transcript"]);
+    for outcome in outcomes.iter() {
+        let took = format!("{}m{:02}s", outcome.duration / 60, outcome.duration % 60);
+        let mut row = row![outcome.name, outcome.ip, took];
+        row.add_cell(match &outcome.error {
+            Some(e) => Cell::new(&text::cell(e, 80)).style_spec("Fr"),
+            None => Cell::new("provisioned").style_spec("Fg"),
+        });
+        row.add_cell(Cell::new(&outcome.transcript));
+        table.add_row(row);
+    }
+    println!();
+    table.printstd();
+
+    let failed = outcomes
+        .iter()
+        .filter(|outcome| outcome.error.is_some())
+        .count();
+    if failed > 0 {
+        bail!(
+            "{} of {} nodes failed to provision, run the same command again to retry them",
+            failed,
+            outcomes.len()
+        );
+    }
+    Ok(())
+}
+
+/// Print the checklist of the cluster and of each node, and add them to the transcripts. Fails
+/// before anything is provisioned if any check did.
+fn report_checks(shared: &[Check], checked: &[(&Provision, &[Check])]) -> Result<()> {
+    println!("cluster:");
+    for check in shared.iter() {
+        println!("  {}", check);
+    }
+    for (provision, checks) in checked.iter() {
+        println!("{} ({}):", provision.name, provision.ip);
+        for check in checks.iter() {
+            println!("  {}", check);
+        }
+        for check in shared.iter().chain(checks.iter()) {
+            provision.transcript.record(&format!(
+                "check {}: {:?} {}",
+                check.name, check.status, check.detail
+            ));
+        }
+    }
+    if !preflight::passed(shared) || !checked.iter().all(|(_, checks)| preflight::passed(checks)) {
+        bail!("the checks failed, nothing was provisioned");
+    }
+    Ok(())
+}
+
+const CONSUL_BOOTSTRAP_FILE: &str = "secrets/consul-acl-bootstrap.json";
+
+/// Bootstrap the Consul ACLs, waiting for Consul to elect a leader on a freshly provisioned
+/// cluster.
+async fn consul_bootstrap(domain: &str, output: &Path) -> Result<()> {
+    let mut attempts = 0;
+    let token = loop {
+        attempts += 1;
+        match consul::bootstrap(domain).await {
+            Ok(token) => break token,
+            Err(e) if attempts < 30 => {
+                debug!("consul isn't ready for the ACL bootstrap: {:?}", e);
+                tokio::time::sleep(Duration::from_secs(10)).await;
+            }
+            Err(e) => return Err(e),
+        }
+    };
+
+    match token {
+        Some(token) => {
+            consul::save_token(output, &token)?;
+            println!(
+                "bootstrapped the consul ACLs, the management token {} is saved in {}",
+                token.accessor_id,
+                output.display()
+            );
+        }
+        None => println!("the consul ACLs are bootstrapped already"),
+    }
     Ok(())
 }
 
@@ -52,11 +653,11 @@ pub(crate) async fn ssh(sub: &ArgMatches, cluster: ClusterHandle) -> Result<()>
     let job: Vec<String> = sub.values_of_t("job").unwrap_or_default();
     let delay = Duration::from_secs(sub.value_of_t::<u64>("delay").unwrap_or(0));
 
-    let namespace: String = sub.value_of_t("namespace").unwrap_or_default();
+    let namespace = nomad::read_namespace(sub);
 
     let ip: IpAddr;
 
-    let cluster = cluster.await??;
+    let cluster = at_state_serial(sub, cluster.await??).await?;
 
     if sub.is_present("all") {
         let nodes = if sub.is_present("clients") {
@@ -90,17 +691,51 @@ pub(crate) async fn ssh(sub: &ArgMatches, cluster: ClusterHandle) -> Result<()>
             cluster.nodes
         };
 
-        let mut handles: Vec<JoinHandle<Result<()>>> = Vec::with_capacity(nodes.len());
+        if sub.is_present("raw") {
+            let mut handles: Vec<JoinHandle<Result<()>>> = Vec::with_capacity(nodes.len());
+
+            for node in nodes.into_iter() {
+                let args = args.clone();
+                let name = cluster.name.clone();
+                let handle = tokio::spawn(async move { init_ssh(node.pub_ip, args, name).await });
+                handles.push(handle);
+            }
+
+            for handle in handles.into_iter() {
+                handle.await??;
+            }
+
+            return Ok(());
+        }
+
+        // capture the output of every node and print it grouped by node
+        let mut handles = Vec::with_capacity(nodes.len());
 
         for node in nodes.into_iter() {
-            let args = args.clone();
-            let name = cluster.name.clone();
-            let handle = tokio::spawn(async move { init_ssh(node.pub_ip, args, name).await });
-            handles.push(handle);
+            let label = if node.name.is_empty() {
+                node.pub_ip.to_string()
+            } else {
+                node.name.clone()
+            };
+            let child = tokio::process::Command::new("ssh")
+                .args(ssh_args(node.pub_ip, args.clone(), &cluster.name))
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .with_context(|| format!("couldn't run ssh for {}", label))?;
+            handles.push((label, tokio::spawn(child.wait_with_output())));
         }
 
-        for handle in handles.into_iter() {
-            handle.await??;
+        for (label, handle) in handles.into_iter() {
+            let output = handle
+                .await?
+                .with_context(|| format!("ssh to {} failed", label))?;
+            print_prefixed(&label, &output.stdout);
+            print_prefixed(&label, &output.stderr);
+            if !output.status.success() {
+                warn!("{}: ssh exited with {}", label, output.status);
+            }
         }
 
         return Ok(());
@@ -122,7 +757,8 @@ pub(crate) async fn ssh(sub: &ArgMatches, cluster: ClusterHandle) -> Result<()>
                 };
 
                 allocs.as_ref().unwrap().iter().any(|alloc| {
-                    let is_alloc = alloc.namespace == namespace
+                    let is_alloc = (namespace == nomad::ALL_NAMESPACES
+                        || alloc.namespace == namespace)
                         && alloc.job_id == name
                         && alloc.task_group == group
                         && alloc.index.get() == index.parse().ok()
@@ -162,31 +798,68 @@ pub(crate) async fn ssh(sub: &ArgMatches, cluster: ClusterHandle) -> Result<()>
         ip = node.pub_ip;
     };
 
+    if sub.is_present("record-session") {
+        return record_ssh(ip, args, cluster.name);
+    }
+
     init_ssh(ip, args, cluster.name).await
 }
 
-async fn init_ssh(ip: IpAddr, args: Vec<String>, cluster: String) -> Result<()> {
-    let user_host = &*format!("root@{}", ip);
-    let mut flags = vec!["-x", "-p", "22"];
+/// Run an interactive ssh session under `script(1)` and keep a scrubbed asciicast of it in a
+/// run directory.
+fn record_ssh(ip: IpAddr, args: Vec<String>, cluster: String) -> Result<()> {
+    let run = Run::start("ssh")?;
+    let typescript = run.path("session.typescript");
+    let timing = run.path("session.timing");
+    let cast = run.path("session.cast");
+
+    let mut cmd =
+        recording::recorded_command("ssh", &ssh_args(ip, args, &cluster), &typescript, &timing);
+    info!("cmd: {:?}", cmd);
 
-    let ssh_key_path = format!("secrets/ssh-{}", cluster);
-    let ssh_key = Path::new(&ssh_key_path);
-    if ssh_key.is_file() {
-        flags.push("-i");
-        flags.push(&*ssh_key_path);
+    let status = cmd
+        .status()
+        .with_context(|| "script command failed, is util-linux installed?")?;
+
+    let converted = recording::to_asciicast(&typescript, &timing, &cast);
+    // the raw typescript is not scrubbed, so it must not outlive the conversion
+    for raw in [&typescript, &timing].iter() {
+        if let Err(e) = std::fs::remove_file(raw) {
+            warn!("couldn't remove {}: {}", raw.display(), e);
+        }
     }
+    converted?;
 
-    flags.append(&mut vec!["-o", "StrictHostKeyChecking=accept-new"]);
+    let dir = run.keep();
+    println!("session recorded to {}", dir.join("session.cast").display());
+
+    if !status.success() {
+        bail!("ssh exited with {}", status);
+    }
+    Ok(())
+}
 
-    flags.push(user_host);
+/// Print the output of a remote command with every line prefixed by `label`.
+///
+/// Bytes that aren't valid UTF-8 are shown as `\xNN`, with a warning pointing to `--raw`.
+fn print_prefixed(label: &str, output: &[u8]) {
+    let decoded = text::decode(output);
+    for line in decoded.text.lines() {
+        println!("{} | {}", label, line);
+    }
 
-    if !args.is_empty() {
-        flags.append(&mut args.iter().map(|string| string.as_str()).collect());
+    if decoded.is_lossy() {
+        warn!(
+            "{}: {} bytes of output aren't valid UTF-8 and are shown as \\xNN, \
+             use --raw to get them unchanged",
+            label, decoded.invalid
+        );
     }
-    let ssh_args = flags.into_iter();
+}
 
+async fn init_ssh(ip: IpAddr, args: Vec<String>, cluster: String) -> Result<()> {
     let mut cmd = Command::new("ssh");
-    let cmd_with_args = cmd.args(ssh_args);
+    let cmd_with_args = cmd.args(ssh_args(ip, args, &cluster));
     info!("cmd: {:?}", cmd_with_args);
 
     cmd.spawn()
@@ -196,180 +869,3442 @@ async fn init_ssh(ip: IpAddr, args: Vec<String>, cluster: String) -> Result<()>
     Ok(())
 }
 
-pub(crate) async fn rebuild(sub: &ArgMatches, cluster: ClusterHandle) -> Result<()> {
+pub(crate) async fn rebuild(
+    sub: &ArgMatches,
+    cluster: ClusterHandle,
+    cancel: &CancellationToken,
+) -> Result<()> {
     let only: Vec<String> = sub.values_of_t("only").unwrap_or_default();
     let delay = Duration::from_secs(sub.value_of_t::<u64>("delay").unwrap_or(0));
     let copy: bool = sub.is_present("copy");
     let clients: bool = sub.is_present("clients");
+    let ssm: bool = sub.is_present("ssm");
+    let window = Duration::from_secs(sub.value_of_t::<u64>("window").unwrap_or(120));
 
     let cluster = cluster.await??;
 
     rebuild::set_ssh_opts(true, &cluster.name)?;
-    rebuild::copy(
-        only.iter().map(|o| o.as_str()).collect(),
-        delay,
-        copy,
-        clients,
-        cluster,
-    )
-    .await?;
-    Ok(())
+
+    let run = Run::start("rebuild")?;
+    let only = only.iter().map(|o| o.as_str()).collect();
+    let result = if sub.is_present("two_phase") {
+        rebuild::two_phase(only, window, copy, clients, ssm, cluster, &run, cancel).await
+    } else {
+        rebuild::copy(only, delay, copy, clients, ssm, cluster, &run, cancel).await
+    };
+    run.finish(result)
 }
-pub(crate) async fn deploy(sub: &ArgMatches, cluster: ClusterHandle) -> Result<()> {
-    cluster.await??;
-    match cli::run(Some(sub)).await {
-        Ok(()) => (),
-        Err(err) => {
-            error!("{}", err);
-            std::process::exit(1);
+
+pub(crate) async fn job(
+    sub: &ArgMatches,
+    cluster: ClusterHandle,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    match sub.subcommand() {
+        Some(("promote", sub_sub)) => {
+            cluster.abort();
+            let job: String = sub_sub.value_of_t_or_exit("job");
+            let from: String = sub_sub.value_of_t_or_exit("from");
+            let to: String = sub_sub.value_of_t_or_exit("to");
+            let namespace = nomad::write_namespace(sub_sub)?;
+
+            let config = BitteConfig::load()?;
+            let run = job::promote(&config, &job, &namespace, &from, &to).await?;
+            println!(
+                "promoted {} from {} to {}, evaluation {}",
+                job, from, to, run.eval_id
+            );
+            Ok(())
+        }
+        Some(("list", sub_sub)) => {
+            let cluster = cluster.await??;
+            let namespace = nomad::read_namespace(sub_sub);
+            job_list(sub_sub, &NomadApi::for_cluster(&cluster), &namespace).await
+        }
+        Some((op, sub_sub)) => {
+            let job: String = sub_sub.value_of_t_or_exit("job");
+            let mut cluster = cluster.await??;
+            let nomad = NomadApi::for_cluster(&cluster);
+
+            let namespace = if matches!(op, "status" | "history" | "diff") {
+                job::resolve_namespace(&nomad, &nomad::read_namespace(sub_sub), &job).await?
+            } else {
+                nomad::write_namespace(sub_sub)?
+            };
+
+            let result = match op {
+                "plan" => job_plan(&nomad, &namespace, &job).await.map(|_| ()),
+                "run" => job_run(sub_sub, &nomad, &cluster, &namespace, &job, cancel).await,
+                "stop" => job_stop(sub_sub, &nomad, &cluster.name, &namespace, &job).await,
+                "scale" => job_scale(sub_sub, &nomad, &cluster, &namespace, &job, cancel).await,
+                "restart" => job_restart(sub_sub, &nomad, &cluster, &namespace, &job, cancel).await,
+                "status" => job_status(sub_sub, &nomad, &namespace, &job).await,
+                "history" => job_history(sub_sub, &nomad, &namespace, &job).await,
+                "diff" => job_diff(sub_sub, &nomad, &namespace, &job).await,
+                _ => Err(anyhow!("Unknown command")),
+            };
+
+            // even a failed deployment has moved allocations around
+            if matches!(op, "run" | "scale" | "restart") {
+                refresh_job_allocs(&nomad, &mut cluster, &namespace, &job).await;
+            }
+            result
+        }
+        None => {
+            cluster.abort();
+            Err(anyhow!("Unknown command"))
         }
     }
-    Ok(())
 }
 
-pub(crate) async fn info(sub: &ArgMatches, cluster: ClusterHandle) -> Result<()> {
-    let json: bool = sub.is_present("json");
-    info_print(cluster, json).await?;
+/// Refresh the cached allocations of the clients that held or now hold allocations of `job`.
+async fn refresh_job_allocs(
+    nomad: &NomadApi,
+    cluster: &mut BitteCluster,
+    namespace: &str,
+    job: &str,
+) {
+    let mut clients: Vec<Uuid> = cluster
+        .nodes
+        .iter()
+        .filter_map(|node| node.nomad_client.as_ref())
+        .filter(|client| {
+            client
+                .allocs
+                .iter()
+                .flatten()
+                .any(|alloc| alloc.namespace == namespace && alloc.job_id == job)
+        })
+        .map(|client| client.id)
+        .collect();
+
+    match job::clients(nomad, namespace, job).await {
+        Ok(current) => clients.extend(current),
+        Err(e) => warn!("couldn't find the clients of {}: {:?}", job, e),
+    }
+
+    if let Err(e) = cluster.refresh_allocs(&clients).await {
+        warn!("couldn't refresh the cached allocations: {:?}", e);
+    }
+}
+
+async fn job_list(sub: &ArgMatches, nomad: &NomadApi, namespace: &str) -> Result<()> {
+    let jobs = job::list(nomad, namespace).await?;
+
+    if sub.is_present("json") {
+        let stdout = io::stdout();
+        let handle = stdout.lock();
+        serde_json::to_writer_pretty(handle, &jobs)?;
+        return Ok(());
+    }
+
+    let mut table = Table::new();
+    table.add_row(row![
+        "Namespace",
+        "Job",
+        "Type",
+        "Status",
+        "Running",
+        "Deployment",
+        "Pending Evals"
+    ]);
+    for job in jobs.iter() {
+        let status = if job.stopped {
+            format!("{} (stopped)", job.status)
+        } else {
+            job.status.clone()
+        };
+        let running = match job.desired {
+            Some(desired) => format!("{}/{}", job.running, desired),
+            None => job.running.to_string(),
+        };
+        table.add_row(row![
+            job.namespace,
+            job.id,
+            job.job_type,
+            status,
+            running,
+            job.deployment
+                .as_ref()
+                .map(|status| format!("{:?}", status))
+                .unwrap_or_default(),
+            job.pending_evals
+        ]);
+    }
+    table.printstd();
+
     Ok(())
 }
 
-pub(crate) async fn terraform(sub: &ArgMatches, cluster: ClusterHandle) -> Result<()> {
-    let workspace: String = sub.value_of_t_or_exit("workspace");
+/// Render the job, plan it, and print the diff and the index the plan was made at.
+async fn job_plan(nomad: &NomadApi, namespace: &str, job: &str) -> Result<(CueRender, i64)> {
+    let mut render = job::render(namespace, job)?;
+    let plan = job::plan(nomad, &mut render).await?;
 
-    match sub.subcommand() {
-        Some(("plan", sub_sub)) => terraform_plan(workspace, sub_sub, cluster).await,
-        Some(("apply", sub_sub)) => terraform_apply(workspace, sub_sub, cluster).await,
-        Some(("init", sub_sub)) => terraform_init(workspace, sub_sub, cluster).await,
-        Some(("passthrough", sub_sub)) => terraform_passthrough(workspace, sub_sub, cluster).await,
-        Some(("output", sub_sub)) => terraform_output(sub_sub, cluster).await,
-        _ => {
-            cluster.abort();
-            Err(anyhow!("Unknown command"))
-        }
+    plan.diff.display();
+    for (group, update) in plan.annotations.desired_tg_updates.iter() {
+        println!(
+            "{}: {} place, {} in-place, {} destructive, {} canary, {} stop",
+            group,
+            update.place,
+            update.in_place_update,
+            update.destructive_update,
+            update.canary,
+            update.stop
+        );
     }
+    if !plan.warnings.is_empty() {
+        warn!("{}", plan.warnings);
+    }
+    println!("The job modify index is: {}", plan.job_modify_index);
+
+    Ok((render, plan.job_modify_index))
 }
 
-/// Run `terraform plan` in a workspace
-///
-/// # Arguments
-///
-/// * `workspace` - a string that holds the name of a terraform workspace
-/// * `sub` - `&ArgMatches` holding additional cli flags
-///
-/// # Examples
-///
-/// ```
-/// terraform_plan("network", arg_matches);
-/// ```
-pub async fn terraform_plan(
-    workspace: String,
+async fn job_run(
     sub: &ArgMatches,
-    cluster: ClusterHandle,
+    nomad: &NomadApi,
+    cluster: &BitteCluster,
+    namespace: &str,
+    job: &str,
+    cancel: &CancellationToken,
 ) -> Result<()> {
-    let destroy: bool = sub.is_present("destroy");
-    let plan_file = format!("{}.plan", workspace);
+    let (mut render, index) = job_plan(nomad, namespace, job).await?;
 
-    info!("Plan file: {:?}", plan_file);
+    if !sub.is_present("yes")
+        && !bitte_lib::confirm(&format!("Submit {} to {}?", job, cluster.name), "yes")?
+    {
+        return Ok(());
+    }
 
-    terraform::prepare(workspace, cluster).await?;
+    let run = job::run(nomad, &cluster.name, &mut render, index).await?;
+    println!("submitted {}, evaluation {}", job, run.eval_id);
 
-    let mut cmd = Command::new("terraform");
-    let mut full = cmd.arg("plan").arg("-out").arg(plan_file);
-    if destroy {
-        full = full.arg("-destroy");
+    if sub.is_present("detach") || render.job.periodic.is_some() {
+        return Ok(());
     }
 
-    info!("run: {:?}", full);
-    full.status()
-        .with_context(|| format!("failed to run: {:?}", full))?;
-    Ok(())
+    follow_deployment(nomad, cluster, job, &run.eval_id, "job-run", cancel).await
+}
+
+/// Follow the deployment started by an evaluation, failing if it does.
+async fn follow_deployment(
+    nomad: &NomadApi,
+    cluster: &BitteCluster,
+    job: &str,
+    eval_id: &str,
+    command: &str,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    match job::follow(nomad, eval_id, cancel).await? {
+        Some(deployment) if deployment.status == NomadDeploymentStatus::Failed => {
+            deployment_failed(nomad, cluster, &deployment, command).await
+        }
+        Some(deployment) if deployment.status == NomadDeploymentStatus::Cancelled => {
+            bail!("deployment of {} is {:?}", job, deployment.status)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Fail with a summary of a failed deployment, after archiving the logs and task events of its
+/// failed allocations to S3 so they outlive the garbage collection of the allocations.
+async fn deployment_failed(
+    nomad: &NomadApi,
+    cluster: &BitteCluster,
+    deployment: &NomadDeployment,
+    command: &str,
+) -> Result<()> {
+    let run = Run::start(command)?;
+    let mut summary = format!(
+        "deployment {} of {} is {:?}",
+        deployment.id, deployment.job_id, deployment.status
+    );
+
+    match archive::archive_failure(nomad, cluster, &deployment.id, &run).await {
+        Ok(url) => summary.push_str(&format!(", failed allocations archived to {}", url)),
+        Err(e) => warn!("couldn't archive the failed allocations: {:?}", e),
+    }
+
+    run.finish(Err(anyhow!(summary)))
+}
+
+async fn job_stop(
+    sub: &ArgMatches,
+    nomad: &NomadApi,
+    cluster: &str,
+    namespace: &str,
+    job: &str,
+) -> Result<()> {
+    let purge = sub.is_present("purge");
+    let prompt = format!(
+        "{} {}/{} in {}?",
+        if purge { "Stop and purge" } else { "Stop" },
+        namespace,
+        job,
+        cluster
+    );
+    if !sub.is_present("yes") && !bitte_lib::confirm(&prompt, job)? {
+        return Ok(());
+    }
+
+    let eval_id = job::stop(nomad, cluster, namespace, job, purge).await?;
+    println!("stopped {}, evaluation {}", job, eval_id);
+    Ok(())
+}
+
+async fn job_scale(
+    sub: &ArgMatches,
+    nomad: &NomadApi,
+    cluster: &BitteCluster,
+    namespace: &str,
+    job: &str,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    let group: String = sub.value_of_t_or_exit("group");
+    let count: u64 = sub.value_of_t_or_exit("count");
+    let message = match sub.value_of("message") {
+        Some(message) => message.to_string(),
+        None => format!(
+            "scaled with bitte by {}",
+            env::var("USER").unwrap_or_default()
+        ),
+    };
+
+    let eval_id = job::scale(
+        nomad,
+        &cluster.name,
+        namespace,
+        job,
+        &group,
+        count,
+        &message,
+    )
+    .await?;
+    println!(
+        "scaled {} of {} to {}, evaluation {}",
+        group, job, count, eval_id
+    );
+
+    if !sub.is_present("watch") {
+        return Ok(());
+    }
+    follow_deployment(nomad, cluster, job, &eval_id, "job-scale", cancel).await
+}
+
+/// Restart a job by submitting it again with a new restart stamp, or `--in-place` by restarting
+/// the tasks of its running allocations one after the other.
+async fn job_restart(
+    sub: &ArgMatches,
+    nomad: &NomadApi,
+    cluster: &BitteCluster,
+    namespace: &str,
+    job: &str,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    let in_place = sub.is_present("in_place");
+    let prompt = format!(
+        "Restart {}/{} in {}{}?",
+        namespace,
+        job,
+        cluster.name,
+        if in_place { " in place" } else { "" }
+    );
+    if !sub.is_present("yes") && !bitte_lib::confirm(&prompt, "yes")? {
+        return Ok(());
+    }
+
+    if !in_place {
+        let run = restart::resubmit(nomad, &cluster.name, namespace, job).await?;
+        println!("submitted {} again, evaluation {}", job, run.eval_id);
+        if sub.is_present("detach") {
+            return Ok(());
+        }
+        return follow_deployment(nomad, cluster, job, &run.eval_id, "job-restart", cancel).await;
+    }
+
+    let allocs = restart::running_allocs(nomad, namespace, job).await?;
+    if allocs.is_empty() {
+        bail!("{}/{} has no running allocations", namespace, job);
+    }
+    let task = sub.value_of("task");
+    for alloc in allocs.iter() {
+        cancel::check(cancel)?;
+        restart::in_place(nomad, &cluster.name, alloc, task).await?;
+        // one at a time, the next one only goes down once this one runs again
+        restart::wait_running(nomad, alloc, task, cancel).await?;
+        println!("restarted {}", alloc.name);
+    }
+
+    Ok(())
+}
+
+async fn job_history(sub: &ArgMatches, nomad: &NomadApi, namespace: &str, job: &str) -> Result<()> {
+    let versions = job::history(nomad, namespace, job).await?;
+
+    if sub.is_present("json") {
+        let stdout = io::stdout();
+        let handle = stdout.lock();
+        serde_json::to_writer_pretty(handle, &versions)?;
+        return Ok(());
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let mut table = Table::new();
+    table.add_row(row!["Version", "Submitted", "Stable", "Changed"]);
+    for version in versions.iter() {
+        table.add_row(row![
+            version.version,
+            job::ago(version.submitted, now),
+            version.stable,
+            version.changes.join(", ")
+        ]);
+    }
+    table.printstd();
+
+    Ok(())
+}
+
+async fn job_diff(sub: &ArgMatches, nomad: &NomadApi, namespace: &str, job: &str) -> Result<()> {
+    let from: u64 = sub.value_of_t_or_exit("from");
+    let to: u64 = sub.value_of_t_or_exit("to");
+    let diff = job::diff(nomad, namespace, job, from, to).await?;
+
+    if sub.is_present("json") {
+        let stdout = io::stdout();
+        let handle = stdout.lock();
+        serde_json::to_writer_pretty(handle, &diff)?;
+        return Ok(());
+    }
+
+    if diff.changes().is_empty() {
+        println!(
+            "{}/{} is the same in versions {} and {}",
+            namespace, job, from, to
+        );
+    } else {
+        diff.display();
+    }
+
+    Ok(())
+}
+
+async fn job_status(sub: &ArgMatches, nomad: &NomadApi, namespace: &str, job: &str) -> Result<()> {
+    let status = job::status(nomad, namespace, job).await?;
+
+    if sub.is_present("json") {
+        let stdout = io::stdout();
+        let handle = stdout.lock();
+        serde_json::to_writer_pretty(handle, &status)?;
+        return Ok(());
+    }
+
+    println!(
+        "{}/{} version {}: {}{}",
+        status.namespace,
+        status.id,
+        status.version,
+        status.status,
+        if status.stopped { " (stopped)" } else { "" }
+    );
+
+    let mut groups = Table::new();
+    groups.add_row(row![
+        "Task Group",
+        "Queued",
+        "Starting",
+        "Running",
+        "Failed",
+        "Complete",
+        "Lost"
+    ]);
+    let mut summary: Vec<_> = status.summary.summary.iter().collect();
+    summary.sort_by_key(|(name, _)| name.to_string());
+    for (name, group) in summary {
+        groups.add_row(row![
+            name,
+            group.queued,
+            group.starting,
+            group.running,
+            group.failed,
+            group.complete,
+            group.lost
+        ]);
+    }
+    groups.printstd();
+
+    if let Some(deployment) = &status.deployment {
+        deployment.display();
+    }
+
+    let mut allocs = Table::new();
+    allocs.add_row(row!["ID", "Task Group", "Index", "Node", "Status"]);
+    for alloc in status.allocs.iter() {
+        allocs.add_row(row![
+            alloc.id,
+            alloc.task_group,
+            alloc.index.get().map(|i| i.to_string()).unwrap_or_default(),
+            alloc.node_id,
+            alloc.status
+        ]);
+    }
+    allocs.printstd();
+
+    Ok(())
+}
+
+pub(crate) async fn alloc(sub: &ArgMatches, cluster: ClusterHandle) -> Result<()> {
+    let sub = match sub.subcommand() {
+        Some(("restart", sub)) => sub,
+        _ => {
+            cluster.abort();
+            return Err(anyhow!("Unknown command"));
+        }
+    };
+
+    let id: String = sub.value_of_t_or_exit("alloc");
+    let cluster = cluster.await??;
+    let nomad = NomadApi::for_cluster(&cluster);
+    let alloc = restart::find_alloc(&nomad, &id).await?;
+
+    let prompt = format!(
+        "{} {} of {}/{} in {}?",
+        if sub.is_present("reschedule") {
+            "Reschedule"
+        } else {
+            "Restart"
+        },
+        alloc.name,
+        alloc.namespace,
+        alloc.job_id,
+        cluster.name
+    );
+    if !sub.is_present("yes") && !bitte_lib::confirm(&prompt, "yes")? {
+        return Ok(());
+    }
+
+    if sub.is_present("reschedule") {
+        let eval_id = restart::reschedule(&nomad, &cluster.name, &alloc).await?;
+        println!(
+            "stopped {}, evaluation {} places its replacement",
+            alloc.name, eval_id
+        );
+    } else {
+        restart::in_place(&nomad, &cluster.name, &alloc, sub.value_of("task")).await?;
+        println!("restarted {}", alloc.name);
+    }
+
+    Ok(())
+}
+
+pub(crate) async fn node(
+    sub: &ArgMatches,
+    cluster: ClusterHandle,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    let (op, sub_sub) = match sub.subcommand() {
+        Some(command) => command,
+        None => {
+            cluster.abort();
+            return Err(anyhow!("Unknown command"));
+        }
+    };
+
+    if op == "store" {
+        return node_store(sub_sub, cluster).await;
+    }
+
+    let needle: String = sub_sub.value_of_t_or_exit("node");
+    let cluster = cluster.await??;
+
+    match op {
+        "drain" => return node_drain(sub_sub, cluster, &needle, cancel).await,
+        "eligibility" => return node_eligibility(sub_sub, cluster, &needle).await,
+        _ => {}
+    }
+
+    let node = cluster.nodes.clone().find_needle(&needle)?;
+    let power = cluster.power(&node);
+
+    match op {
+        "reboot" => power.reboot(&node).await?,
+        "stop" => power.stop(&node).await?,
+        "start" => power.start(&node).await?,
+        "console" => {
+            let output = power.console(&node).await?;
+            if sub_sub.is_present("raw") {
+                io::stdout().write_all(&output)?;
+            } else {
+                let decoded = text::decode(&output);
+                print!("{}", decoded.text);
+                if decoded.is_lossy() {
+                    warn!(
+                        "{} bytes of the console output aren't valid UTF-8 and are shown as \\xNN, \
+                         use --raw to get them unchanged",
+                        decoded.invalid
+                    );
+                }
+            }
+        }
+        _ => return Err(anyhow!("Unknown command")),
+    }
+
+    info!("{}: {} {}", op, node.name, node.id);
+    Ok(())
+}
+
+/// Drain a Nomad client and redraw a table of its allocations until all of them migrated
+async fn node_drain(
+    sub: &ArgMatches,
+    mut cluster: BitteCluster,
+    needle: &str,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    let deadline = match sub.value_of("deadline") {
+        Some(deadline) => drain::parse_deadline(deadline)?,
+        None => Duration::from_secs(60 * 60),
+    };
+    let ignore_system = sub.is_present("ignore-system");
+
+    let api = NomadApi::for_cluster(&cluster);
+    let node_names: HashMap<String, String> = cluster
+        .nodes
+        .iter()
+        .filter_map(|node| {
+            node.nomad_client
+                .as_ref()
+                .map(|client| (client.id.to_hyphenated().to_string(), node.name.clone()))
+        })
+        .collect();
+
+    let node = cluster.nodes.clone().find_needle(needle)?;
+    let node_name = node.name.clone();
+    let client = node
+        .nomad_client
+        .as_ref()
+        .with_context(|| format!("{} is not a nomad client", node.name))?
+        .id;
+    let client_id = client.to_hyphenated().to_string();
+
+    let drain = Drain::start(api, &client_id, deadline, ignore_system).await?;
+
+    let migrations = loop {
+        let migrations = drain.migrations().await?;
+        let draining = drain.draining().await?;
+
+        // clear the screen so the table updates in place
+        print!("\x1B[2J\x1B[H");
+        println!(
+            "Draining {} ({}), ETA {}s, deadline in {}s",
+            node_name,
+            client_id,
+            drain.eta(&migrations).as_secs(),
+            deadline
+                .checked_sub(drain.started.elapsed())
+                .unwrap_or_default()
+                .as_secs()
+        );
+        drain.table(&migrations, &node_names).printstd();
+
+        if !draining && migrations.iter().all(|m| m.status.is_done()) {
+            break migrations;
+        }
+
+        tokio::select! {
+            _ = cancel.cancelled() => {
+                println!("stopped following, the drain of {} continues", node_name);
+                return Ok(());
+            }
+            _ = tokio::time::sleep(Duration::from_secs(2)) => {}
+        }
+    };
+
+    println!("{} is drained", node_name);
+
+    // only the drained node and the nodes that took over its allocations changed
+    let mut affected = vec![client];
+    affected.extend(
+        migrations
+            .iter()
+            .filter_map(|m| m.replacement.as_ref()?.node_id.parse::<Uuid>().ok()),
+    );
+    cluster.refresh_allocs(&affected).await
+}
+
+async fn node_eligibility(sub: &ArgMatches, cluster: BitteCluster, needle: &str) -> Result<()> {
+    let eligible = sub.value_of("state") == Some("on");
+    let node = cluster.nodes.clone().find_needle(needle)?;
+    let client = node
+        .nomad_client
+        .as_ref()
+        .with_context(|| format!("{} is not a nomad client", node.name))?;
+
+    let api = NomadApi::for_cluster(&cluster);
+    drain::set_eligibility(&api, &client.id.to_hyphenated().to_string(), eligible).await?;
+
+    info!(
+        "{} is {} for new allocations",
+        node.name,
+        if eligible { "eligible" } else { "ineligible" }
+    );
+    Ok(())
+}
+
+/// Check the contents of the nix store of a node, and fetch corrupted paths again for `repair`
+async fn node_store(sub: &ArgMatches, cluster: ClusterHandle) -> Result<()> {
+    let (op, sub) = match sub.subcommand() {
+        Some(command) => command,
+        None => {
+            cluster.abort();
+            return Err(anyhow!("Unknown command"));
+        }
+    };
+    let needle: String = sub.value_of_t_or_exit("node");
+    let sample: Option<usize> = sub
+        .value_of("sample")
+        .map(|_| sub.value_of_t_or_exit("sample"));
+
+    let cluster = cluster.await??;
+    let node = cluster.nodes.clone().find_needle(&needle)?;
+    let cache = cluster
+        .terra
+        .as_ref()
+        .and_then(|terra| terra.s3_cache.clone());
+
+    rebuild::set_ssh_opts(true, &cluster.name)?;
+    let executor = executor::for_node(&node, sub.is_present("ssm"), &cache).await?;
+    info!(
+        "checking the store of {} via {}",
+        node.name,
+        executor.describe()
+    );
+
+    let corrupted = store::verify(executor.as_ref(), sample).await?;
+    let mut report = StoreReport {
+        node: node.name.clone(),
+        sampled: sample,
+        corrupted,
+        ..Default::default()
+    };
+
+    if op == "repair" && !report.corrupted.is_empty() {
+        let broken = store::repair(executor.as_ref(), cache.as_deref(), &report.corrupted).await?;
+        report.repaired = report
+            .corrupted
+            .iter()
+            .filter(|path| !broken.contains(path))
+            .cloned()
+            .collect();
+    }
+
+    if sub.is_present("json") {
+        serde_json::to_writer_pretty(io::stdout().lock(), &report)?;
+    } else {
+        let checked = match report.sampled {
+            Some(sample) => format!("{} random paths", sample),
+            None => "all paths".to_string(),
+        };
+        if report.corrupted.is_empty() {
+            println!("{}: {} are intact", report.node, checked);
+        } else {
+            let mut table = Table::new();
+            table.add_row(row!["Corrupted path", "Repaired"]);
+            for path in report.corrupted.iter() {
+                let repaired = if report.repaired.contains(path) {
+                    "yes"
+                } else if op == "repair" {
+                    "no"
+                } else {
+                    ""
+                };
+                table.add_row(row![path, repaired]);
+            }
+            println!(
+                "{}: {} of {} are corrupted",
+                report.node,
+                report.corrupted.len(),
+                checked
+            );
+            table.printstd();
+        }
+    }
+
+    if report.repaired.len() < report.corrupted.len() {
+        let hint = if op == "repair" {
+            ""
+        } else {
+            ", `bitte node store repair` fetches them again"
+        };
+        bail!(
+            "{} store paths of {} are corrupted{}",
+            report.corrupted.len() - report.repaired.len(),
+            report.node,
+            hint
+        );
+    }
+
+    Ok(())
+}
+
+pub(crate) async fn query(sub: &ArgMatches) -> Result<()> {
+    let sql: String = match (sub.value_of("sql"), sub.value_of("query")) {
+        (Some(sql), _) => sql.to_string(),
+        (None, Some("list")) => {
+            let mut table = Table::new();
+            table.add_row(row!["Query", "Description"]);
+            for (name, description, _) in db::CANNED_QUERIES {
+                table.add_row(row![name, description]);
+            }
+            table.printstd();
+            return Ok(());
+        }
+        (None, Some(name)) if db::CANNED_QUERIES.iter().any(|(n, _, _)| *n == name) => {
+            name.to_string()
+        }
+        (None, Some(name)) => bail!("unknown query {}, see `bitte query list`", name),
+        (None, None) => bail!("either a query or --sql is required"),
+    };
+
+    let result = db::query(&db::open()?, &sql)?;
+
+    if sub.is_present("json") {
+        let rows: Vec<serde_json::Map<String, serde_json::Value>> = result
+            .rows
+            .iter()
+            .map(|row| {
+                result
+                    .columns
+                    .iter()
+                    .cloned()
+                    .zip(row.iter().map(|value| serde_json::json!(value)))
+                    .collect()
+            })
+            .collect();
+        let stdout = io::stdout();
+        let handle = stdout.lock();
+        serde_json::to_writer_pretty(handle, &rows)?;
+        return Ok(());
+    }
+
+    let mut table = Table::new();
+    table.add_row(result.columns.iter().collect());
+    for row in result.rows.iter() {
+        table.add_row(row.iter().collect());
+    }
+    table.printstd();
+    Ok(())
+}
+
+pub(crate) async fn runbook(sub: &ArgMatches, cluster: String) -> Result<()> {
+    let config = BitteConfig::load()?;
+
+    let name: String = match sub.value_of_t("name") {
+        Ok(name) => name,
+        Err(_) => {
+            let mut names: Vec<&String> = config.runbooks.keys().collect();
+            names.sort();
+
+            let mut table = Table::new();
+            table.add_row(row!["Runbook", "Params", "Steps", "Description"]);
+            for name in names {
+                let runbook = &config.runbooks[name];
+                table.add_row(row![
+                    name,
+                    runbook.params.join(", "),
+                    runbook.steps.len(),
+                    runbook.description
+                ]);
+            }
+            table.printstd();
+            return Ok(());
+        }
+    };
+
+    let params = sub
+        .values_of_lossy("param")
+        .unwrap_or_default()
+        .into_iter()
+        .map(|param| match param.split_once('=') {
+            Some((key, value)) => Ok((key.to_string(), value.to_string())),
+            None => Err(anyhow!("parameter {} must be given as name=value", param)),
+        })
+        .collect::<Result<HashMap<String, String>>>()?;
+
+    let report =
+        config
+            .runbook(&name)?
+            .execute(&cluster, &name, params, sub.is_present("restart"))?;
+
+    println!("runbook {} finished, report in {}", name, report.display());
+    Ok(())
+}
+
+pub(crate) async fn runs(sub: &ArgMatches) -> Result<()> {
+    match sub.subcommand() {
+        Some(("gc", sub_sub)) => {
+            let hours: u64 = sub_sub.value_of_t("max-age").unwrap_or(24 * 7);
+            let removed = runs::gc(Duration::from_secs(hours * 60 * 60))?;
+            for dir in removed.iter() {
+                info!("removed {}", dir.display());
+            }
+            println!("removed {} run directories", removed.len());
+            Ok(())
+        }
+        _ => Err(anyhow!("Unknown command")),
+    }
+}
+pub(crate) async fn deploy(sub: &ArgMatches, cluster: ClusterHandle) -> Result<()> {
+    cluster.await??;
+    match cli::run(Some(sub)).await {
+        Ok(()) => (),
+        Err(err) => {
+            error!("{}", err);
+            std::process::exit(1);
+        }
+    }
+    Ok(())
+}
+
+/// How `bitte info` prints the nodes.
+enum InfoFormat {
+    Table {
+        allocs: bool,
+    },
+    Json,
+    /// Tab separated values of the selected columns, for scripts.
+    Columns {
+        columns: Vec<String>,
+        headers: bool,
+    },
+}
+
+/// What `bitte info` shows besides the nodes.
+#[derive(Default)]
+struct InfoExtras {
+    vault: Option<Vec<VaultStatus>>,
+    versions: Option<Vec<NodeVersions>>,
+    asgs: Option<Vec<AsgCapacity>>,
+    costs: Option<CostReport>,
+    spot: Option<Vec<SpotStatus>>,
+}
+
+/// How long `bitte info --watch` waits between refreshes by default.
+const WATCH_INTERVAL: u64 = 10;
+
+/// The longest `bitte info --watch` waits before trying a failed refresh again.
+const WATCH_MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+pub(crate) async fn info<F>(
+    sub: &ArgMatches,
+    cluster: ClusterHandle,
+    refresh: F,
+    cancel: &CancellationToken,
+) -> Result<()>
+where
+    F: Fn() -> ClusterHandle,
+{
+    let format = match sub.value_of("output") {
+        Some(spec) => InfoFormat::Columns {
+            columns: bitte_lib::info::parse_columns(spec)?,
+            headers: !sub.is_present("no_headers"),
+        },
+        None if sub.is_present("json") => InfoFormat::Json,
+        None => InfoFormat::Table {
+            allocs: sub.is_present("allocs"),
+        },
+    };
+    let filters = sub
+        .values_of("filter")
+        .into_iter()
+        .flatten()
+        .map(bitte_lib::info::NodeFilter::parse)
+        .collect::<Result<Vec<_>>>()?;
+
+    let interval = match sub.value_of("watch") {
+        Some(seconds) => Duration::from_secs(seconds.parse().context("--watch takes seconds")?),
+        None if sub.is_present("watch") => Duration::from_secs(WATCH_INTERVAL),
+        None => return info_show(sub, cluster.await??, &format, &filters).await,
+    };
+
+    // the first load has to work, later ones are tried again while the cluster is shown
+    let mut loaded = Ok(cluster.await??);
+    let mut backoff = interval;
+    loop {
+        // failing to show the cluster is retried like failing to refresh it
+        let shown = match loaded {
+            Ok(loaded) => {
+                // clear the screen and start at the top, like watch(1)
+                print!("\x1b[2J\x1b[H");
+                println!(
+                    "{}, every {}s until interrupted\n",
+                    loaded.name,
+                    interval.as_secs()
+                );
+                info_show(sub, loaded, &format, &filters).await
+            }
+            Err(e) => Err(e),
+        };
+
+        let wait = match shown {
+            Ok(()) => {
+                io::stdout().flush()?;
+                backoff = interval;
+                interval
+            }
+            Err(e) if cancel::is_cancelled(&e) => return Ok(()),
+            Err(e) => {
+                let wait = backoff;
+                backoff = (backoff * 2).min(WATCH_MAX_BACKOFF);
+                warn!(
+                    "couldn't show the cluster, trying again in {}s: {:?}",
+                    wait.as_secs(),
+                    e
+                );
+                wait
+            }
+        };
+
+        tokio::select! {
+            _ = cancel.cancelled() => return Ok(()),
+            _ = tokio::time::sleep(wait) => {}
+        }
+        // the clients and allocations are synced with the changes since the last load
+        loaded = match refresh().await {
+            Ok(loaded) => loaded,
+            Err(e) => Err(e.into()),
+        };
+    }
+}
+
+/// Print the cluster the way `bitte info` was asked to.
+async fn info_show(
+    sub: &ArgMatches,
+    cluster: BitteCluster,
+    format: &InfoFormat,
+    filters: &[bitte_lib::info::NodeFilter],
+) -> Result<()> {
+    let mut cluster = at_state_serial(sub, cluster).await?;
+    cluster
+        .nodes
+        .retain(|node| bitte_lib::info::matches_all(filters, node));
+    if let Some(column) = sub.value_of("sort") {
+        bitte_lib::info::sort_nodes(&mut cluster.nodes, column)?;
+    }
+    if let Some(needle) = sub.value_of("needle") {
+        let node = cluster.nodes.clone().find_needle(needle)?;
+        let detail = detail::load(&cluster, node).await;
+        return info_node(&detail, matches!(format, InfoFormat::Json));
+    }
+    let mut extras = InfoExtras::default();
+    let statuses = if sub.is_present("vault") || sub.is_present("versions") {
+        let instances = VaultInstance::on_core_nodes(&cluster.nodes, sub.is_present("public"));
+        unseal::status(&instances).await?
+    } else {
+        Vec::new()
+    };
+    if sub.is_present("versions") {
+        extras.versions = Some(info_versions(&cluster, &statuses).await);
+    }
+    if sub.is_present("vault") {
+        extras.vault = Some(statuses);
+    }
+    // the groups of an older state version are gone or changed since, and scripts only want
+    // their columns. Spot requests and prices are AWS only, and GCP instance groups aren't
+    // compared yet. The groups are those of the provider with the terraform state.
+    let aws_nodes: Vec<BitteNode> = cluster
+        .nodes
+        .iter()
+        .filter(|node| matches!(node.provider, BitteProvider::AWS))
+        .cloned()
+        .collect();
+    let asgs = cluster
+        .terra
+        .as_ref()
+        .map(|terra| &terra.asgs)
+        .filter(|asgs| !asgs.is_empty() && !sub.is_present("state_serial"))
+        .filter(|_| !matches!(format, InfoFormat::Columns { .. }));
+    match (cluster.provider, asgs) {
+        (BitteProvider::AWS, Some(asgs)) => {
+            extras.asgs = Some(bitte_lib::info::asg_capacity(&cluster.pool, asgs).await);
+        }
+        (BitteProvider::Azure, Some(asgs)) => {
+            extras.asgs = Some(azure::scale_set_capacity(asgs).await);
+        }
+        _ => {}
+    }
+    let spot = aws_nodes
+        .iter()
+        .any(|node| node.lifecycle.as_deref() == Some("spot"));
+    if spot && !matches!(format, InfoFormat::Columns { .. }) {
+        extras.spot = Some(bitte_lib::info::spot_status(&cluster.pool, &aws_nodes).await);
+    }
+    if sub.is_present("costs") {
+        if aws_nodes.len() < cluster.nodes.len() {
+            warn!("--costs only knows the prices of AWS, leaving out the other nodes");
+        }
+        if !aws_nodes.is_empty() {
+            let costs = costs::node_costs(&cluster.pool, &aws_nodes).await;
+            extras.costs = Some(CostReport::new(costs));
+        }
+    }
+    info_print(cluster, extras, format).await
+}
+
+/// The versions of the agents on each node. A service that doesn't answer leaves its column
+/// empty rather than failing `info`.
+async fn info_versions(cluster: &BitteCluster, vault: &[VaultStatus]) -> Vec<NodeVersions> {
+    let nomad = versions::nomad(&NomadApi::for_cluster(cluster))
+        .await
+        .unwrap_or_else(|e| {
+            warn!("couldn't ask nomad for its versions: {:?}", e);
+            HashMap::new()
+        });
+    let consul = versions::consul(&cluster.domain).await.unwrap_or_else(|e| {
+        warn!("couldn't ask consul for its versions: {:?}", e);
+        HashMap::new()
+    });
+    versions::collect(&cluster.nodes, &nomad, &consul, vault)
+}
+
+/// The cluster as recorded in an older state version when `--state-serial` is given.
+async fn at_state_serial(sub: &ArgMatches, cluster: BitteCluster) -> Result<BitteCluster> {
+    if !sub.is_present("state_serial") {
+        return Ok(cluster);
+    }
+
+    let serial: u64 = sub.value_of_t("state_serial")?;
+    let workspace = sub.value_of("workspace").unwrap_or("core");
+    cluster.at_state_serial(workspace, serial).await
+}
+
+pub(crate) async fn verify(
+    sub: &ArgMatches,
+    cluster: ClusterHandle,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    let config: String = sub
+        .value_of_t("config")
+        .unwrap_or_else(|_| "verify.json".to_string());
+    let json: bool = sub.is_present("json");
+
+    let mut config = VerifyConfig::read(Path::new(&config))?;
+    if let Ok(concurrency) = sub.value_of_t::<usize>("concurrency") {
+        config.concurrency = concurrency;
+    }
+
+    let results = config.run(cluster.await??, cancel).await?;
+    let failed = results.iter().filter(|result| !result.passed).count();
+
+    if json {
+        let stdout = io::stdout();
+        let handle = stdout.lock();
+        serde_json::to_writer_pretty(handle, &results)?;
+    } else {
+        let mut table = Table::new();
+        table.add_row(row!["Check", "Probe", "Result", "Attempts", "Detail"]);
+
+        for result in results.iter() {
+            table.add_row(row![
+                result.name,
+                result.probe,
+                if result.passed { "pass" } else { "FAIL" },
+                result.attempts,
+                text::cell(&result.detail, 80)
+            ]);
+        }
+
+        table.printstd();
+    }
+
+    if failed > 0 {
+        bail!("{} of {} checks failed", failed, results.len());
+    }
+
+    Ok(())
+}
+
+pub(crate) async fn deployment(
+    sub: &ArgMatches,
+    cluster: ClusterHandle,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    let (op, sub_sub) = match sub.subcommand() {
+        Some(subcommand) => subcommand,
+        None => {
+            cluster.abort();
+            return Err(anyhow!("Unknown command"));
+        }
+    };
+
+    // deployments are found in all namespaces, and changed in the one they are in
+    let namespace = nomad::read_namespace(sub_sub);
+    let cluster = cluster.await??;
+    let nomad = NomadApi::for_cluster(&cluster);
+
+    if op == "list" {
+        let deployments = deployment::list(&nomad, &namespace).await?;
+
+        if sub_sub.is_present("json") {
+            let stdout = io::stdout();
+            let handle = stdout.lock();
+            serde_json::to_writer_pretty(handle, &deployments)?;
+            return Ok(());
+        }
+
+        let mut table = Table::new();
+        table.add_row(row!["ID", "Job", "Version", "Status", "Description"]);
+        for deployment in deployments.iter() {
+            table.add_row(row![
+                deployment.id,
+                deployment.job_id,
+                deployment.job_version,
+                format!("{:?}", deployment.status),
+                text::cell(
+                    deployment.status_description.as_deref().unwrap_or_default(),
+                    80
+                )
+            ]);
+        }
+        table.printstd();
+        return Ok(());
+    }
+
+    let id: String = sub_sub.value_of_t_or_exit("id");
+    let target = deployment::resolve(&nomad, &namespace, &id).await?;
+
+    let eval_id = match op {
+        "promote" => {
+            let groups: Vec<String> = sub_sub.values_of_t("group").unwrap_or_default();
+            deployment::promote(&nomad, &cluster.name, &target, &groups).await?
+        }
+        "fail" => deployment::fail(&nomad, &cluster.name, &target).await?,
+        "pause" => {
+            let pause = !sub_sub.is_present("resume");
+            deployment::pause(&nomad, &cluster.name, &target, pause).await?
+        }
+        _ => return Err(anyhow!("Unknown command")),
+    };
+    info!(
+        "{} deployment {} of {}, evaluation {}",
+        op, target.id, target.job_id, eval_id
+    );
+
+    if sub_sub.is_present("watch") {
+        // a paused deployment stays paused, it is followed until then unless it finishes first
+        let pausing = op == "pause" && !sub_sub.is_present("resume");
+        let finished = deployment::watch_until(&nomad, &target.id, cancel, |deployment| {
+            deployment.is_done() || (pausing && deployment.status == NomadDeploymentStatus::Paused)
+        })
+        .await?;
+        if op != "fail" {
+            match finished.status {
+                NomadDeploymentStatus::Failed => {
+                    return deployment_failed(&nomad, &cluster, &finished, "deployment").await;
+                }
+                NomadDeploymentStatus::Cancelled => {
+                    bail!("deployment {} is {:?}", target.id, finished.status);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub(crate) async fn logs(
+    sub: &ArgMatches,
+    cluster: ClusterHandle,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    let target: String = sub.value_of_t_or_exit("target");
+    let namespace = nomad::read_namespace(sub);
+    let kind = if sub.is_present("stderr") {
+        LogKind::Stderr
+    } else {
+        LogKind::Stdout
+    };
+
+    let cluster = cluster.await??;
+    let nomad = NomadApi::for_cluster(&cluster);
+
+    let allocs = logs::allocations(&nomad, &namespace, &target).await?;
+    let sources = logs::sources(&allocs, sub.value_of("task"))?;
+
+    // a single task is printed as is, so the output can be piped like the task wrote it
+    let prefixed = sources.len() > 1;
+    let result = logs::stream(
+        &nomad,
+        sources,
+        kind,
+        sub.is_present("follow"),
+        cancel,
+        |line| {
+            if prefixed {
+                println!("{} | {}", line.label, line.text);
+            } else {
+                println!("{}", line.text);
+            }
+        },
+    )
+    .await;
+
+    // Ctrl-C is how following ends
+    match result {
+        Err(e) if cancel::is_cancelled(&e) => Ok(()),
+        result => result,
+    }
+}
+
+/// Log in to the Vault of the cluster, store the token, and lease the Nomad and Consul tokens of
+/// the session with it.
+pub(crate) async fn login(
+    sub: &ArgMatches,
+    domain: &str,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    let address = endpoint::address(Service::Vault, domain);
+    let method = sub.value_of("method").unwrap_or("github");
+    let mount = sub.value_of("mount").unwrap_or(method);
+
+    let token = match method {
+        "oidc" => {
+            let role = sub.value_of("role").unwrap_or_default();
+            login::oidc(&address, mount, role, cancel).await?
+        }
+        _ => login::github(&address, mount).await?,
+    };
+    let stored = login::store(&address, &token)?;
+    println!("logged in to {}, the token is in {}", address, stored);
+
+    // the leases below are taken with the new token
+    env::set_var("VAULT_ADDR", &address);
+    env::set_var("VAULT_TOKEN", &token);
+
+    let mut nomad = Err(anyhow!("there are no session roles"));
+    for role in acl::SESSION_ROLES {
+        nomad = acl::issue(domain, role).await;
+        if nomad.is_ok() {
+            break;
+        }
+    }
+    match nomad {
+        Ok(nomad) => println!("nomad {} token: {}", nomad.role, nomad.accessor_id),
+        Err(e) => warn!("vault issued no nomad token: {:?}", e),
+    }
+
+    match consul::issue_consul_token().await {
+        Ok(consul) => println!("consul developer token: {}", consul.accessor),
+        Err(e) => warn!("vault issued no consul token: {:?}", e),
+    }
+
+    Ok(())
+}
+
+pub(crate) async fn vault(
+    sub: &ArgMatches,
+    cluster: ClusterHandle,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    match sub.subcommand() {
+        Some(("unseal", sub)) => vault_unseal(sub, cluster, cancel).await,
+        Some(("leases", sub)) => vault_leases(sub, cluster).await,
+        Some(("policies", sub)) => match sub.subcommand() {
+            Some(("sync", sub)) => vault_policies_sync(sub, cluster).await,
+            _ => {
+                cluster.abort();
+                Err(anyhow!("Unknown command"))
+            }
+        },
+        _ => {
+            cluster.abort();
+            Err(anyhow!("Unknown command"))
+        }
+    }
+}
+
+async fn vault_unseal(
+    sub: &ArgMatches,
+    cluster: ClusterHandle,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    let (mut keys, from_file) = match sub.value_of("key_file") {
+        Some(path) => (unseal::read_keys(path)?, true),
+        None => (Vec::new(), false),
+    };
+
+    let cluster = cluster.await??;
+    let instances = VaultInstance::on_core_nodes(&cluster.nodes, sub.is_present("public"));
+    if instances.is_empty() {
+        bail!("{} has no core nodes", cluster.name);
+    }
+    let unsealer = Unsealer::new()?;
+
+    let mut sealed = Vec::new();
+    for instance in instances.iter() {
+        match unsealer.health(instance).await {
+            Ok(health) if !health.initialized => warn!("{} is not initialized", instance.node),
+            Ok(health) if health.sealed => {
+                println!("{}: sealed", instance.node);
+                sealed.push(instance);
+            }
+            Ok(health) => println!(
+                "{}: unsealed ({})",
+                instance.node,
+                if health.standby { "standby" } else { "active" }
+            ),
+            Err(e) => warn!("{} is unreachable: {:?}", instance.node, e),
+        }
+    }
+
+    if sealed.is_empty() {
+        println!("no vault instance is sealed");
+        return Ok(());
+    }
+
+    // the instances share their key shares, so each one is only asked for once
+    let mut unsealed = Vec::new();
+    let mut failed = Vec::new();
+    for instance in sealed {
+        cancel::check(cancel)?;
+        match unseal_instance(&unsealer, instance, &mut keys, from_file).await {
+            Ok(()) => {
+                println!("{}: unsealed", instance.node);
+                unsealed.push(instance.node.clone());
+            }
+            Err(e) => {
+                warn!("couldn't unseal {}: {:?}", instance.node, e);
+                failed.push(instance.node.clone());
+            }
+        }
+    }
+
+    history::record(
+        &cluster.name,
+        "vault-unseal",
+        serde_json::json!({ "unsealed": unsealed, "failed": failed }),
+    )?;
+
+    if !failed.is_empty() {
+        bail!("couldn't unseal {}", failed.join(", "));
+    }
+    Ok(())
+}
+
+async fn vault_leases(sub: &ArgMatches, cluster: ClusterHandle) -> Result<()> {
+    let prefix = sub.value_of("prefix").unwrap_or_default();
+
+    if sub.is_present("revoke") || sub.is_present("revoke_all") {
+        let cluster = cluster.await??;
+        let ids: Vec<&str> = sub.values_of("revoke").into_iter().flatten().collect();
+        let prompt = if ids.is_empty() {
+            format!("Revoke every lease under {} in {}?", prefix, cluster.name)
+        } else {
+            format!("Revoke {} leases in {}?", ids.len(), cluster.name)
+        };
+        if !sub.is_present("yes") && !bitte_lib::confirm(&prompt, "yes")? {
+            return Ok(());
+        }
+
+        if ids.is_empty() {
+            lease::revoke_prefix(prefix).await?;
+            println!("revoked every lease under {}", prefix);
+        }
+        for id in ids.iter() {
+            lease::revoke(id).await?;
+            println!("revoked {}", id);
+        }
+
+        return history::record(
+            &cluster.name,
+            "vault-leases-revoke",
+            serde_json::json!({ "prefix": prefix, "leases": ids }),
+        );
+    }
+
+    cluster.abort();
+    let mut leases = Vec::new();
+    for id in lease::ids(prefix).await? {
+        leases.push(lease::lookup(&id).await?);
+    }
+
+    if sub.is_present("json") {
+        serde_json::to_writer_pretty(io::stdout().lock(), &leases)?;
+        return Ok(());
+    }
+
+    let mut table = Table::new();
+    table.add_row(row!["Lease", "Issued", "Expires", "TTL", "Renewable"]);
+    for lease in leases.iter() {
+        table.add_row(row![
+            lease.id,
+            lease.issue_time,
+            lease.expire_time.as_deref().unwrap_or("never"),
+            format!("{}s", lease.ttl),
+            if lease.renewable { "yes" } else { "no" }
+        ]);
+    }
+    table.printstd();
+    Ok(())
+}
+
+async fn vault_policies_sync(sub: &ArgMatches, cluster: ClusterHandle) -> Result<()> {
+    let dir = Path::new(sub.value_of("dir").unwrap_or_default());
+    let local = vault::local_policies(dir)?;
+    let cluster = cluster.await??;
+    let installed = vault::installed_policies().await?;
+
+    let changes = vault::policy_changes(&local, &installed, sub.is_present("prune"));
+    if changes.is_empty() {
+        println!("the policies of {} are up to date", cluster.name);
+        return Ok(());
+    }
+    for change in changes.iter() {
+        change.print();
+    }
+
+    if sub.is_present("dry_run") {
+        return Ok(());
+    }
+    let prompt = format!(
+        "Apply {} policy changes to {}?",
+        changes.len(),
+        cluster.name
+    );
+    if !sub.is_present("yes") && !bitte_lib::confirm(&prompt, "yes")? {
+        return Ok(());
+    }
+
+    for change in changes.iter() {
+        vault::apply_policy(change).await?;
+    }
+    println!("applied {} policy changes", changes.len());
+
+    let names: Vec<&str> = changes.iter().map(|change| change.name.as_str()).collect();
+    history::record(
+        &cluster.name,
+        "vault-policies-sync",
+        serde_json::json!({ "policies": names }),
+    )
+}
+
+/// Submit key shares to `instance` until it is unsealed, taking them from `keys` and asking for
+/// more unless they came from a file.
+async fn unseal_instance(
+    unsealer: &Unsealer,
+    instance: &VaultInstance,
+    keys: &mut Vec<String>,
+    from_file: bool,
+) -> Result<()> {
+    let mut status = unsealer.seal_status(instance).await?;
+    let mut next = 0;
+
+    while status.sealed {
+        let key = match keys.get(next) {
+            Some(key) => key.clone(),
+            None if from_file => bail!(
+                "{} needs {} key shares, the file has {}",
+                instance.node,
+                status.threshold,
+                keys.len()
+            ),
+            None => {
+                let key = bitte_lib::prompt_secret(&format!(
+                    "key share {} of {} for {}: ",
+                    status.progress + 1,
+                    status.threshold,
+                    instance.node
+                ))?;
+                keys.push(key.clone());
+                key
+            }
+        };
+        next += 1;
+
+        status = unsealer.submit(instance, &key).await?;
+        if status.sealed {
+            println!(
+                "{}: {}/{} key shares",
+                instance.node, status.progress, status.threshold
+            );
+        }
+    }
+
+    Ok(())
+}
+
+pub(crate) async fn consul(sub: &ArgMatches, cluster: ClusterHandle) -> Result<()> {
+    match sub.subcommand() {
+        Some(("services", sub)) => consul_services(sub, cluster).await,
+        Some(("token", sub)) => consul_token(sub, cluster).await,
+        Some(("intentions", sub)) => consul_intentions(sub, cluster).await,
+        _ => {
+            cluster.abort();
+            Err(anyhow!("Unknown command"))
+        }
+    }
+}
+
+async fn consul_intentions(sub: &ArgMatches, cluster: ClusterHandle) -> Result<()> {
+    let (op, sub) = match sub.subcommand() {
+        Some(command) => command,
+        None => {
+            cluster.abort();
+            return Err(anyhow!("Unknown command"));
+        }
+    };
+    let cluster = cluster.await??;
+    let mut intentions = consul::intentions(&cluster.domain).await?;
+    intentions.sort_by_key(|intention| std::cmp::Reverse(intention.precedence));
+
+    if op == "list" {
+        if sub.is_present("json") {
+            serde_json::to_writer_pretty(io::stdout().lock(), &intentions)?;
+        } else {
+            let mut table = Table::new();
+            table.add_row(row![
+                "Source",
+                "Destination",
+                "Action",
+                "Precedence",
+                "Description"
+            ]);
+            for intention in intentions.iter() {
+                table.add_row(row![
+                    intention.source_name,
+                    intention.destination_name,
+                    intention.action,
+                    intention.precedence,
+                    text::cell(&intention.description, 50)
+                ]);
+            }
+            table.printstd();
+        }
+        return Ok(());
+    }
+
+    let allow = match op {
+        "allow" => true,
+        "deny" => false,
+        _ => return Err(anyhow!("Unknown command")),
+    };
+    let source = sub.value_of("source").unwrap_or_default();
+    let destination = sub.value_of("destination").unwrap_or_default();
+
+    let impact = consul::intention_impact(&intentions, source, destination);
+    let show = |intention: &consul::Intention| {
+        format!(
+            "{} {} => {}",
+            intention.action, intention.source_name, intention.destination_name
+        )
+    };
+    println!("{} {} => {}", op, source, destination);
+    if let Some(replaced) = &impact.replaces {
+        println!("  replaces {}", show(replaced));
+    }
+    for shadowed in impact.shadows.iter() {
+        println!("  shadows {}", show(shadowed));
+    }
+    for winner in impact.shadowed_by.iter() {
+        println!("  still decided by {} where they overlap", show(winner));
+    }
+
+    if sub.is_present("dry_run") {
+        return Ok(());
+    }
+    let prompt = format!("Write the intention to {}?", cluster.name);
+    if !sub.is_present("yes") && !bitte_lib::confirm(&prompt, "yes")? {
+        return Ok(());
+    }
+
+    let description = sub.value_of("description").unwrap_or_default();
+    consul::set_intention(&cluster.domain, source, destination, allow, description).await?;
+    println!(
+        "{} {} => {}",
+        if allow { "allowed" } else { "denied" },
+        source,
+        destination
+    );
+
+    history::record(
+        &cluster.name,
+        "consul-intention",
+        serde_json::json!({
+            "source": source,
+            "destination": destination,
+            "action": op,
+            "replaces": impact.replaces.map(|replaced| replaced.action),
+        }),
+    )
+}
+
+async fn consul_token(sub: &ArgMatches, cluster: ClusterHandle) -> Result<()> {
+    let (op, sub) = match sub.subcommand() {
+        Some(command) => command,
+        None => {
+            cluster.abort();
+            return Err(anyhow!("Unknown command"));
+        }
+    };
+    let cluster = cluster.await??;
+    let json = sub.is_present("json");
+
+    match op {
+        "bootstrap" => {
+            let output = sub.value_of("output").unwrap_or(CONSUL_BOOTSTRAP_FILE);
+            consul_bootstrap(&cluster.domain, Path::new(output)).await?;
+            history::record(
+                &cluster.name,
+                "consul-token-bootstrap",
+                serde_json::json!({ "output": output }),
+            )?;
+        }
+        "create" => {
+            let template = sub.value_of("template").unwrap_or_default();
+            let name = sub.value_of("name").unwrap_or_default();
+            let config = BitteConfig::load()?;
+            let rendered = config.consul.template(template)?.render(name);
+            let token = consul::create_token(&cluster.domain, &rendered).await?;
+            history::record(
+                &cluster.name,
+                "consul-token-create",
+                serde_json::json!({
+                    "template": template,
+                    "name": name,
+                    "accessor": token.accessor_id,
+                }),
+            )?;
+
+            if json {
+                serde_json::to_writer_pretty(io::stdout().lock(), &token)?;
+            } else {
+                println!("Accessor ID  = {}", token.accessor_id);
+                println!("Secret ID    = {}", token.secret_id);
+                println!("Description  = {}", token.description);
+            }
+        }
+        "list" => {
+            let tokens = consul::list_tokens(&cluster.domain).await?;
+
+            if json {
+                serde_json::to_writer_pretty(io::stdout().lock(), &tokens)?;
+            } else {
+                let mut table = Table::new();
+                table.add_row(row![
+                    "Accessor",
+                    "Description",
+                    "Policies",
+                    "Services",
+                    "Local",
+                    "Created"
+                ]);
+                for token in tokens.iter() {
+                    let policies: Vec<&str> = token
+                        .policies
+                        .iter()
+                        .flatten()
+                        .map(|policy| policy.name.as_str())
+                        .collect();
+                    let services: Vec<&str> = token
+                        .service_identities
+                        .iter()
+                        .flatten()
+                        .map(|identity| identity.service_name.as_str())
+                        .collect();
+                    table.add_row(row![
+                        token.accessor_id,
+                        text::cell(&token.description, 50),
+                        policies.join(", "),
+                        services.join(", "),
+                        token.local,
+                        token.create_time
+                    ]);
+                }
+                table.printstd();
+            }
+        }
+        "revoke" => {
+            let accessor: String = sub.value_of_t_or_exit("accessor");
+            consul::revoke_token(&cluster.domain, &accessor).await?;
+            history::record(
+                &cluster.name,
+                "consul-token-revoke",
+                serde_json::json!({ "accessor": accessor }),
+            )?;
+            println!("revoked {}", accessor);
+        }
+        _ => return Err(anyhow!("Unknown command")),
+    }
+
+    Ok(())
+}
+
+async fn consul_services(sub: &ArgMatches, cluster: ClusterHandle) -> Result<()> {
+    let cluster = cluster.await??;
+    let services =
+        consul::services(&cluster.domain, sub.value_of("node"), sub.value_of("tag")).await?;
+
+    if sub.is_present("json") {
+        serde_json::to_writer_pretty(io::stdout().lock(), &services)?;
+        return Ok(());
+    }
+
+    let mut table = Table::new();
+    table.add_row(row![
+        "Service",
+        "Instances",
+        "Passing",
+        "Warning",
+        "Critical",
+        "Nodes",
+        "Tags"
+    ]);
+    for service in services.iter() {
+        table.add_row(row![
+            service.name,
+            service.instances,
+            service.passing,
+            service.warning,
+            service.critical,
+            service.nodes.join(", "),
+            text::cell(&service.tags.join(", "), 40)
+        ]);
+    }
+    table.printstd();
+    Ok(())
+}
+
+pub(crate) async fn operator(sub: &ArgMatches, cluster: ClusterHandle) -> Result<()> {
+    match sub.subcommand() {
+        Some(("raft", sub)) => operator_raft(sub, cluster).await,
+        _ => {
+            cluster.abort();
+            Err(anyhow!("Unknown command"))
+        }
+    }
+}
+
+async fn operator_raft(sub: &ArgMatches, cluster: ClusterHandle) -> Result<()> {
+    let service = sub.value_of("service").unwrap_or("all");
+    let cluster = cluster.await??;
+
+    let mut statuses = Vec::new();
+    if service == "all" || service == "consul" {
+        statuses.push(raft::consul(&cluster.domain).await?);
+    }
+    if service == "all" || service == "nomad" {
+        statuses.push(raft::nomad(&NomadApi::for_cluster(&cluster)).await?);
+    }
+    let problems: usize = statuses.iter().map(|status| status.problems.len()).sum();
+
+    if sub.is_present("json") {
+        serde_json::to_writer_pretty(io::stdout().lock(), &statuses)?;
+    } else {
+        for status in statuses.iter() {
+            println!(
+                "{}: {}, raft index {}, {} servers can fail",
+                status.service,
+                if status.health.healthy {
+                    "healthy"
+                } else {
+                    "UNHEALTHY"
+                },
+                status.configuration.index,
+                status.health.failure_tolerance
+            );
+
+            let mut table = Table::new();
+            table.add_row(row![
+                "Server",
+                "Address",
+                "Leader",
+                "Voter",
+                "Status",
+                "Healthy",
+                "Term",
+                "Index",
+                "Last Contact",
+                "Version"
+            ]);
+            for server in status.health.servers.iter() {
+                table.add_row(row![
+                    server.name,
+                    server.address,
+                    server.leader,
+                    server.voter,
+                    server.serf_status,
+                    server.healthy,
+                    server.last_term,
+                    server.last_index,
+                    server.last_contact,
+                    server.version
+                ]);
+            }
+            table.printstd();
+
+            for problem in status.problems.iter() {
+                println!("! {}", problem);
+            }
+            println!();
+        }
+    }
+
+    if problems > 0 {
+        bail!("found {} problems with the raft clusters", problems);
+    }
+    Ok(())
+}
+
+pub(crate) async fn secret(sub: &ArgMatches, cluster: ClusterHandle) -> Result<()> {
+    let (op, sub) = match sub.subcommand() {
+        Some(command) => command,
+        None => {
+            cluster.abort();
+            return Err(anyhow!("Unknown command"));
+        }
+    };
+    let mount = sub.value_of("mount").unwrap_or(secret::DEFAULT_MOUNT);
+    let path = sub.value_of("path").unwrap_or_default();
+
+    match op {
+        "get" => {
+            cluster.abort();
+            let data = secret::get(mount, path).await?;
+            match sub.value_of("format") {
+                Some("dotenv") => print!("{}", secret::to_dotenv(&data)),
+                _ => {
+                    serde_json::to_writer_pretty(io::stdout().lock(), &data)?;
+                    println!();
+                }
+            }
+            Ok(())
+        }
+        "list" => {
+            cluster.abort();
+            for key in secret::list(mount, path).await? {
+                println!("{}", key);
+            }
+            Ok(())
+        }
+        "put" => {
+            let mut data = match sub.value_of("file") {
+                Some(file) => secret::parse_file(
+                    &std::fs::read_to_string(file)
+                        .with_context(|| format!("couldn't read {}", file))?,
+                )?,
+                None => Default::default(),
+            };
+            data.extend(secret::parse_pairs(
+                sub.values_of("fields").into_iter().flatten(),
+            )?);
+            if data.is_empty() {
+                cluster.abort();
+                bail!("there are no fields to write, give them as KEY=value or with --file");
+            }
+
+            if sub.is_present("merge") {
+                let mut latest = secret::get(mount, path).await?;
+                latest.extend(data);
+                data = latest;
+            }
+
+            let cluster = cluster.await??;
+            let version = secret::put(mount, path, &data).await?;
+            println!("wrote version {} of {}", version, path);
+
+            // only the names, the values stay in Vault
+            let fields: Vec<&String> = data.keys().collect();
+            history::record(
+                &cluster.name,
+                "secret-put",
+                serde_json::json!({ "mount": mount, "path": path, "version": version, "fields": fields }),
+            )
+        }
+        _ => {
+            cluster.abort();
+            Err(anyhow!("Unknown command"))
+        }
+    }
+}
+
+fn snapshot_service(name: &str) -> Result<Service> {
+    match name {
+        "vault" => Ok(Service::Vault),
+        "consul" => Ok(Service::Consul),
+        _ => Err(anyhow!("Unknown service {}", name)),
+    }
+}
+
+/// Where the snapshots of `cluster` are kept, by the arguments or else bitte.json.
+fn snapshot_store(sub: &ArgMatches, cluster: &BitteCluster) -> Result<SnapshotStore> {
+    let config = BitteConfig::load()?.snapshots;
+    let bucket = sub
+        .value_of("bucket")
+        .map(String::from)
+        .or(config.bucket)
+        .context(
+            "there is no snapshot bucket, give --bucket or set snapshots.bucket in bitte.json",
+        )?;
+    let kms_key = sub.value_of("kms_key").map(String::from).or(config.kms_key);
+    let cache = cluster
+        .terra
+        .as_ref()
+        .and_then(|terra| terra.s3_cache.as_deref());
+    SnapshotStore::new(&bucket, kms_key, cache)
+}
+
+pub(crate) async fn backup(sub: &ArgMatches, cluster: ClusterHandle) -> Result<()> {
+    let keep: usize = sub.value_of_t("keep")?;
+    let services = match sub.value_of("service") {
+        Some("all") | None => snapshot::SERVICES.to_vec(),
+        Some(name) => vec![snapshot_service(name)?],
+    };
+    if keep == 0 {
+        cluster.abort();
+        bail!("--keep has to keep at least the new snapshot");
+    }
+
+    let cluster = cluster.await??;
+    let store = snapshot_store(sub, &cluster)?;
+    let mut failed = Vec::new();
+    for service in services {
+        match snapshot::backup(&cluster, &store, service, keep).await {
+            Ok(url) => {
+                println!("{}: {}", service, url);
+                history::record(
+                    &cluster.name,
+                    "backup",
+                    serde_json::json!({ "service": service.to_string(), "snapshot": url }),
+                )?;
+            }
+            Err(e) => {
+                warn!("couldn't back up {}: {:?}", service, e);
+                failed.push(service.to_string());
+            }
+        }
+    }
+
+    if !failed.is_empty() {
+        bail!("couldn't back up {}", failed.join(", "));
+    }
+    Ok(())
+}
+
+pub(crate) async fn restore(sub: &ArgMatches, cluster: ClusterHandle) -> Result<()> {
+    let service = snapshot_service(sub.value_of("service").unwrap_or_default())?;
+    let cluster = cluster.await??;
+    let store = snapshot_store(sub, &cluster)?;
+    let snapshots = snapshot::list(&cluster, &store, service)?;
+
+    if sub.is_present("list") {
+        for name in snapshots.iter().rev() {
+            println!("{}", name);
+        }
+        return Ok(());
+    }
+
+    let name = match sub.value_of("snapshot") {
+        Some(name) if snapshots.iter().any(|known| known == name) => name.to_string(),
+        Some(name) => bail!("there is no snapshot {} of {}", name, service),
+        None => snapshots
+            .last()
+            .cloned()
+            .with_context(|| format!("there are no snapshots of {}", service))?,
+    };
+
+    // there is no --yes, this replaces everything the service holds
+    let prompt = format!(
+        "Replace the state of {} in {} with {}?",
+        service, cluster.name, name
+    );
+    if !bitte_lib::confirm(&prompt, &cluster.name)? {
+        return Ok(());
+    }
+
+    let file = snapshot::download(&cluster, &store, service, &name)?;
+    snapshot::restore(&cluster, service, &file, sub.is_present("force")).await?;
+    std::fs::remove_file(&file)?;
+    println!("restored {} from {}", service, name);
+
+    history::record(
+        &cluster.name,
+        "restore",
+        serde_json::json!({ "service": service.to_string(), "snapshot": name }),
+    )
+}
+
+pub(crate) async fn nomad_acl(sub: &ArgMatches, cluster: ClusterHandle) -> Result<()> {
+    let (op, sub) = match sub.subcommand() {
+        Some(("token", sub)) => match sub.subcommand() {
+            Some(command) => command,
+            None => {
+                cluster.abort();
+                return Err(anyhow!("Unknown command"));
+            }
+        },
+        _ => {
+            cluster.abort();
+            return Err(anyhow!("Unknown command"));
+        }
+    };
+    let cluster = cluster.await??;
+    let role = sub.value_of("role").unwrap_or(acl::MANAGEMENT_ROLE);
+    let json = sub.is_present("json");
+
+    match op {
+        "create" => {
+            let name: String = sub.value_of_t_or_exit("name");
+            let policies: Vec<String> = match sub.values_of("policy") {
+                Some(policies) => policies.map(String::from).collect(),
+                None => Vec::new(),
+            };
+            let nomad = acl::management_api(&cluster, role).await?;
+            let token = acl::create(&nomad, &name, &policies, sub.is_present("global")).await?;
+            history::record(
+                &cluster.name,
+                "nomad-token-create",
+                serde_json::json!({
+                    "name": name,
+                    "accessor": token.stub.accessor_id,
+                    "policies": policies,
+                }),
+            )?;
+
+            if json {
+                serde_json::to_writer_pretty(io::stdout().lock(), &token)?;
+            } else {
+                println!("Accessor ID  = {}", token.stub.accessor_id);
+                println!("Secret ID    = {}", token.secret_id);
+                println!("Type         = {}", token.stub.token_type);
+            }
+        }
+        "list" => {
+            let nomad = acl::management_api(&cluster, role).await?;
+            let tokens = acl::list(&nomad).await?;
+
+            if json {
+                serde_json::to_writer_pretty(io::stdout().lock(), &tokens)?;
+            } else {
+                let mut table = Table::new();
+                table.add_row(row![
+                    "Accessor", "Name", "Type", "Policies", "Global", "Created"
+                ]);
+                for token in tokens.iter() {
+                    table.add_row(row![
+                        token.accessor_id,
+                        token.name,
+                        token.token_type,
+                        token.policies.as_deref().unwrap_or_default().join(", "),
+                        token.global,
+                        token.create_time
+                    ]);
+                }
+                table.printstd();
+            }
+        }
+        "revoke" => {
+            let accessor: String = sub.value_of_t_or_exit("accessor");
+            let nomad = acl::management_api(&cluster, role).await?;
+            acl::revoke(&nomad, &accessor).await?;
+            history::record(
+                &cluster.name,
+                "nomad-token-revoke",
+                serde_json::json!({ "accessor": accessor }),
+            )?;
+            println!("revoked {}", accessor);
+        }
+        "self" => {
+            let token = acl::token_self(&NomadApi::for_cluster(&cluster)).await?;
+            if json {
+                serde_json::to_writer_pretty(io::stdout().lock(), &token.stub)?;
+            } else {
+                println!("Accessor ID  = {}", token.stub.accessor_id);
+                println!("Name         = {}", token.stub.name);
+                println!("Type         = {}", token.stub.token_type);
+                println!(
+                    "Policies     = {}",
+                    token
+                        .stub
+                        .policies
+                        .as_deref()
+                        .unwrap_or_default()
+                        .join(", ")
+                );
+                println!("Create Time  = {}", token.stub.create_time);
+            }
+        }
+        _ => return Err(anyhow!("Unknown command")),
+    }
+
+    Ok(())
+}
+
+pub(crate) async fn events(
+    sub: &ArgMatches,
+    cluster: ClusterHandle,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    let topics: Vec<String> = sub.values_of_t("topic").unwrap_or_else(|_| {
+        events::DEFAULT_TOPICS
+            .iter()
+            .map(|t| t.to_string())
+            .collect()
+    });
+    let namespace = nomad::read_namespace(sub);
+
+    let cluster = cluster.await??;
+    let nomad = NomadApi::for_cluster(&cluster);
+
+    let result = events::stream(
+        &nomad,
+        &topics,
+        sub.value_of("job"),
+        &namespace,
+        cancel,
+        |event| println!("{}", event.line()),
+    )
+    .await;
+
+    // Ctrl-C is how following ends
+    match result {
+        Err(e) if cancel::is_cancelled(&e) => Ok(()),
+        result => result,
+    }
+}
+
+pub(crate) async fn drift(sub: &ArgMatches, cluster: ClusterHandle) -> Result<()> {
+    match sub.subcommand() {
+        Some(("images", sub_sub)) => drift_images(sub_sub, cluster).await,
+        _ => {
+            cluster.abort();
+            Err(anyhow!("Unknown command"))
+        }
+    }
+}
+
+async fn drift_images(sub: &ArgMatches, cluster: ClusterHandle) -> Result<()> {
+    let cluster = cluster.await??;
+    let drift = images::drift(&cluster, sub.is_present("images_only")).await?;
+
+    if sub.is_present("json") {
+        let stdout = io::stdout();
+        let handle = stdout.lock();
+        serde_json::to_writer_pretty(handle, &drift)?;
+        return Ok(());
+    }
+
+    let mut table = Table::new();
+    table.add_row(row![
+        "Node", "Instance", "Image", "Expected", "System", "Action"
+    ]);
+    for node in drift.iter() {
+        let system = match (&node.running_system, &node.expected_system) {
+            (Some(running), Some(expected)) if running == expected => "current",
+            (Some(_), Some(_)) => "outdated",
+            _ => "",
+        };
+        table.add_row(row![
+            node.name,
+            node.id,
+            node.running_image.as_deref().unwrap_or_default(),
+            node.expected_image.as_deref().unwrap_or_default(),
+            system,
+            format!("{:?}", node.action)
+        ]);
+    }
+    table.printstd();
+
+    let pending: Vec<_> = drift
+        .iter()
+        .filter(|node| node.action != ImageAction::UpToDate)
+        .collect();
+    if pending.is_empty() {
+        println!("All nodes run the images and systems the flake declares.");
+        return Ok(());
+    }
+
+    println!("\nSuggested plan, replacements first:");
+    for (index, node) in pending.iter().enumerate() {
+        println!("{:>3}. {}: {}", index + 1, node.name, node.plan);
+    }
+
+    Ok(())
+}
+
+pub(crate) async fn campaign(
+    sub: &ArgMatches,
+    cluster: ClusterHandle,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    let sub = match sub.subcommand() {
+        Some(("patch", sub)) => sub,
+        _ => {
+            cluster.abort();
+            return Err(anyhow!("Unknown command"));
+        }
+    };
+
+    let input: String = sub.value_of_t_or_exit("input");
+    let cluster = Arc::new(cluster.await??);
+
+    if sub.is_present("restart") {
+        Campaign::discard(&cluster.name, &input)?;
+    }
+    let existing = Campaign::load(&cluster.name, &input)?;
+
+    if sub.is_present("status") {
+        let campaign = existing
+            .with_context(|| format!("there is no campaign for {} on {}", input, cluster.name))?;
+        campaign.table().printstd();
+        println!(
+            "{} of {} waves done, {} nodes patched, {} pending, {} failed",
+            campaign.waves_done,
+            campaign.waves.len(),
+            campaign.count(PatchStatus::Patched),
+            campaign.count(PatchStatus::Pending),
+            campaign.count(PatchStatus::Failed)
+        );
+        return Ok(());
+    }
+
+    let soak = match sub.value_of("soak") {
+        Some(soak) => drain::parse_deadline(soak)?,
+        None => Duration::from_secs(24 * 60 * 60),
+    };
+    let verify = Path::new(sub.value_of("verify").unwrap_or("verify.json"));
+    let verify = if verify.is_file() {
+        Some(verify)
+    } else {
+        warn!(
+            "{} doesn't exist, the waves have no health gate besides the rebuild",
+            verify.display()
+        );
+        None
+    };
+
+    rebuild::set_ssh_opts(true, &cluster.name)?;
+    let run = Run::start("campaign")?;
+
+    let mut campaign = match existing {
+        Some(campaign) => {
+            info!(
+                "resuming the campaign for {} after wave {} of {}",
+                input,
+                campaign.waves_done,
+                campaign.waves.len()
+            );
+            campaign
+        }
+        None => {
+            let waves = sub.value_of("waves").unwrap_or("1,10%,50%,100%");
+            let campaign = Campaign::start(&cluster, &input, waves, &run).await?;
+            campaign.table().printstd();
+
+            let prompt = format!(
+                "Patch {} nodes of {} in {} waves?",
+                campaign.count(PatchStatus::Pending),
+                cluster.name,
+                campaign.waves.len()
+            );
+            if !sub.is_present("yes") && !bitte_lib::confirm(&prompt, "yes")? {
+                println!("the campaign is kept, run this again to start it");
+                return Ok(());
+            }
+            campaign
+        }
+    };
+
+    let ssm = sub.is_present("ssm");
+    let result = campaign
+        .advance(&cluster, ssm, soak, verify, &run, cancel)
+        .await;
+
+    campaign.table().printstd();
+    let report = campaign.finish(&cluster.name)?;
+    println!(
+        "{} nodes patched, {} pending, {} failed, {} unchanged, report in {}",
+        campaign.count(PatchStatus::Patched),
+        campaign.count(PatchStatus::Pending),
+        campaign.count(PatchStatus::Failed),
+        campaign.count(PatchStatus::Unchanged),
+        report.display()
+    );
+
+    match run.finish(result)? {
+        Progress::Done => println!("the campaign for {} is done", input),
+        Progress::Soaking(remaining) => println!(
+            "wave {} of {} soaks for another {}m, run this again to continue",
+            campaign.waves_done,
+            campaign.waves.len(),
+            remaining.as_secs() / 60
+        ),
+    }
+
+    Ok(())
+}
+
+pub(crate) async fn export(sub: &ArgMatches, cluster: ClusterHandle) -> Result<()> {
+    let cluster = cluster.await??;
+
+    let chain = certs::vault_ca_chain(&cluster.domain)
+        .await
+        .context("couldn't fetch the CA chain for its serials")?;
+    let serials = certs::pem_blocks(&chain)
+        .iter()
+        .map(|pem| certs::serial(pem))
+        .collect::<Result<Vec<String>>>()?;
+    let tfvars = export::tfvars(&cluster, serials);
+
+    match sub.value_of("out").unwrap_or("terraform.tfvars.json") {
+        "-" => {
+            let stdout = io::stdout();
+            let handle = stdout.lock();
+            serde_json::to_writer_pretty(handle, &tfvars)?;
+        }
+        out => {
+            std::fs::write(out, serde_json::to_string_pretty(&tfvars)? + "\n")?;
+            println!(
+                "wrote the facts of {} nodes of {} to {}",
+                tfvars.bitte_nodes.len(),
+                cluster.name,
+                out
+            );
+        }
+    }
+
+    Ok(())
+}
+
+pub(crate) async fn terraform<F>(
+    sub: &ArgMatches,
+    cluster: ClusterHandle,
+    refresh: F,
+    cancel: &CancellationToken,
+) -> Result<()>
+where
+    F: FnOnce() -> ClusterHandle,
+{
+    if let Some(("apply-all", sub_sub)) = sub.subcommand() {
+        return terraform_apply_all(sub_sub, cluster).await;
+    }
+
+    let workspace: String = sub.value_of_t_or_exit("workspace");
+
+    match sub.subcommand() {
+        Some(("plan", sub_sub)) => terraform_plan(workspace, sub_sub, cluster).await,
+        Some(("apply", sub_sub)) => {
+            terraform_apply(workspace, sub_sub, cluster, refresh, cancel).await
+        }
+        Some(("destroy", sub_sub)) => terraform_destroy(workspace, sub_sub, cluster).await,
+        Some(("drift", sub_sub)) => terraform_drift(workspace, sub_sub, cluster).await,
+        Some(("import", sub_sub)) => terraform_import(workspace, sub_sub, cluster).await,
+        Some(("init", sub_sub)) => terraform_init(workspace, sub_sub, cluster).await,
+        Some(("passthrough", sub_sub)) => terraform_passthrough(workspace, sub_sub, cluster).await,
+        Some(("output", sub_sub)) => {
+            cluster.abort();
+            terraform_output(workspace, sub_sub).await
+        }
+        Some(("vars", sub_sub)) => {
+            cluster.abort();
+            terraform_vars(workspace, sub_sub).await
+        }
+        Some(("lock", sub_sub)) => {
+            cluster.abort();
+            terraform_lock(workspace, sub_sub).await
+        }
+        Some(("unlock", sub_sub)) => {
+            cluster.abort();
+            terraform_unlock(workspace, sub_sub).await
+        }
+        Some(("history", sub_sub)) => {
+            cluster.abort();
+            terraform_history(workspace, sub_sub).await
+        }
+        _ => {
+            cluster.abort();
+            Err(anyhow!("Unknown command"))
+        }
+    }
+}
+
+/// Run `terraform plan` in a workspace
+///
+/// # Arguments
+///
+/// * `workspace` - a string that holds the name of a terraform workspace
+/// * `sub` - `&ArgMatches` holding additional cli flags
+///
+/// # Examples
+///
+/// ```
+/// terraform_plan("network", arg_matches);
+/// ```
+pub async fn terraform_plan(
+    workspace: String,
+    sub: &ArgMatches,
+    cluster: ClusterHandle,
+) -> Result<()> {
+    let destroy: bool = sub.is_present("destroy");
+    let raw: bool = sub.is_present("raw");
+    let detailed_exitcode: bool = sub.is_present("detailed-exitcode");
+    let targets: Vec<String> = sub.values_of_t("target").unwrap_or_default();
+    let plan_file = format!("{}.plan", workspace);
+
+    info!("Plan file: {:?}", plan_file);
+
+    terraform::prepare(workspace.clone(), cluster).await?;
+
+    let backend = BitteConfig::load()?.terraform.backend;
+    if let Some(remote) = backend.remote_workspace(&workspace).await? {
+        let summary = terraform_remote_plan(&remote, destroy, true, &targets)
+            .await?
+            .1;
+        if detailed_exitcode && summary.has_changes() {
+            return Err(Error::HasChanges { workspace }.into());
+        }
+        return Ok(());
+    }
+
+    let mut cmd = Command::new("terraform");
+    let mut full = cmd.arg("plan").arg("-out").arg(&plan_file);
+    if destroy {
+        full = full.arg("-destroy");
+    }
+    full = full.args(targets.iter().map(|target| format!("-target={}", target)));
+    if !raw {
+        full = full.stdout(Stdio::null());
+    }
+
+    info!("run: {:?}", full);
+    let status = full
+        .status()
+        .with_context(|| format!("failed to run: {:?}", full))?;
+
+    if !status.success() {
+        bail!("terraform plan failed with {}", status);
+    }
+
+    let plan = terraform::show_plan(&plan_file)?;
+    let summary = if raw {
+        terraform::summarize_plan(&plan)
+    } else {
+        terraform::render_plan(&plan)
+    };
+
+    if detailed_exitcode && summary.has_changes() {
+        return Err(Error::HasChanges { workspace }.into());
+    }
+
+    Ok(())
+}
+
+/// Import an existing resource into the state of a workspace, then plan it
+///
+/// The config is generated and initialized against the cluster's backend first, and the plan
+/// afterwards is limited to the imported address, showing how its real attributes differ from
+/// the config.
+///
+/// # Examples
+///
+/// ```
+/// terraform_import("core", arg_matches, cluster);
+/// ```
+pub async fn terraform_import(
+    workspace: String,
+    sub: &ArgMatches,
+    cluster: ClusterHandle,
+) -> Result<()> {
+    let address: String = sub.value_of_t_or_exit("address");
+    let id: String = sub.value_of_t_or_exit("id");
+
+    let cluster = terraform::prepare(workspace.clone(), cluster).await?;
+
+    let backend = BitteConfig::load()?.terraform.backend;
+    let remote = backend.remote_workspace(&workspace).await?;
+    if let Some(remote) = &remote {
+        remote.ensure_unlocked().await?;
+    }
+
+    let mut cmd = Command::new("terraform");
+    cmd.arg("import").arg(&address).arg(&id);
+    info!("run: {:?}", cmd);
+    let status = cmd
+        .status()
+        .with_context(|| format!("failed to run: {:?}", cmd))?;
+    if !status.success() {
+        bail!(
+            "terraform import of {} as {} failed with {}",
+            id,
+            address,
+            status
+        );
+    }
+
+    history::record(
+        &cluster.name,
+        "terraform-import",
+        serde_json::json!({ "workspace": workspace, "address": address, "id": id }),
+    )?;
+
+    let targets = vec![address];
+    if let Some(remote) = &remote {
+        terraform_remote_plan(remote, false, true, &targets).await?;
+        return Ok(());
+    }
+
+    let run = Run::start("import")?;
+    let result = async {
+        let plan_file = run.path(&format!("{}.plan", workspace));
+        let mut cmd = Command::new("terraform");
+        let full = cmd
+            .arg("plan")
+            .arg("-out")
+            .arg(&plan_file)
+            .args(targets.iter().map(|target| format!("-target={}", target)))
+            .stdout(Stdio::null());
+
+        info!("run: {:?}", full);
+        let status = full
+            .status()
+            .with_context(|| format!("failed to run: {:?}", full))?;
+        if !status.success() {
+            bail!("terraform plan failed with {}", status);
+        }
+
+        terraform::show_plan(&plan_file.to_string_lossy())
+    }
+    .await;
+    let plan = run.finish(result)?;
+
+    if !terraform::render_plan(&plan).has_changes() {
+        println!("{} matches its config.", targets[0]);
+    }
+    Ok(())
+}
+
+/// Plan on Terraform Cloud with the freshly generated config, streaming the log as it runs
+///
+/// Returns the id of the run, which waits for confirmation unless `plan_only` is set.
+async fn terraform_remote_plan(
+    remote: &RemoteWorkspace,
+    destroy: bool,
+    plan_only: bool,
+    targets: &[String],
+) -> Result<(String, PlanSummary)> {
+    remote.ensure_unlocked().await?;
+
+    let run = Run::start("remote-plan")?;
+    let result = async {
+        let configuration = remote.upload_configuration(&run, plan_only).await?;
+        let remote_run = remote
+            .create_run(&configuration, destroy, plan_only, targets)
+            .await?;
+        let summary = remote.stream(&remote_run.data.id, Phase::Plan).await?;
+        if summary.has_changes() {
+            print_cost_estimate(remote, &remote_run.data.id).await?;
+        }
+        Ok((remote_run.data.id, summary))
+    }
+    .await;
+    run.finish(result)
+}
+
+/// Print the projected change of the monthly costs per resource, largest increase first
+async fn print_cost_estimate(remote: &RemoteWorkspace, run_id: &str) -> Result<()> {
+    let (estimate, mut resources) = match remote.cost_estimate(run_id).await? {
+        Some(estimate) => estimate,
+        None => return Ok(()),
+    };
+
+    let delta = |cost: &str| cost.parse::<f64>().unwrap_or(0.0);
+    resources.sort_by(|a, b| {
+        delta(&b.delta_monthly_cost)
+            .partial_cmp(&delta(&a.delta_monthly_cost))
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+
+    let mut table = Table::new();
+    table.add_row(row!["Resource", "Prior", "Proposed", "Delta"]);
+    for resource in resources
+        .iter()
+        .filter(|r| delta(&r.delta_monthly_cost) != 0.0)
+    {
+        table.add_row(row![
+            resource.address,
+            r->resource.prior_monthly_cost,
+            r->resource.proposed_monthly_cost,
+            r->format!("{:+.2}", delta(&resource.delta_monthly_cost))
+        ]);
+    }
+
+    println!("Cost estimate (monthly):");
+    table.printstd();
+    println!(
+        "{} of {} resources estimated, {} -> {} ({:+.2})",
+        estimate.matched_resources_count,
+        estimate.matched_resources_count + estimate.unmatched_resources_count,
+        estimate.prior_monthly_cost.unwrap_or_default(),
+        estimate.proposed_monthly_cost.unwrap_or_default(),
+        delta(&estimate.delta_monthly_cost.unwrap_or_default())
+    );
+
+    Ok(())
 }
 
 /// Run any terraform command in a workspace
 ///
-/// # Arguments
+/// # Arguments
+///
+/// * `workspace` - a string that holds the name of a terraform workspace
+/// * `sub` - `&ArgMatches` holding additional cli flags
+///
+/// # Examples
+///
+/// ```
+/// terraform_passthrough("core", arg_matches);
+/// ```
+pub async fn terraform_passthrough(
+    workspace: String,
+    sub: &ArgMatches,
+    cluster: ClusterHandle,
+) -> Result<()> {
+    let init: bool = sub.is_present("init");
+    let config: bool = !sub.is_present("no_config");
+    let args = sub.values_of_lossy("args").unwrap_or_default();
+
+    terraform::set_http_auth().await?;
+
+    if config {
+        terraform::generate_terraform_config(&workspace, cluster).await?;
+    }
+
+    if init {
+        terraform::init(false).await?;
+    }
+
+    let mut cmd = Command::new("terraform");
+    let full = cmd.args(args);
+
+    info!("run: {:?}", full);
+    full.status()
+        .with_context(|| format!("failed to run: {:?}", full))?;
+    Ok(())
+}
+
+pub async fn terraform_init(
+    workspace: String,
+    sub: &ArgMatches,
+    cluster: ClusterHandle,
+) -> Result<()> {
+    let upgrade: bool = sub.is_present("upgrade");
+    let cluster = terraform::generate_terraform_config(&workspace, cluster).await?;
+    if sub.is_present("bootstrap") {
+        terraform::bootstrap(&workspace, &cluster.name).await
+    } else {
+        terraform::init(upgrade).await
+    }
+}
+
+/// Print the outputs of a workspace
+///
+/// # Arguments
+///
+/// * `workspace` - a string that holds the name of a terraform workspace
+/// * `sub` - `&ArgMatches` holding additional cli flags
+///
+/// # Examples
+///
+/// ```
+/// terraform_output("core", arg_matches);
+/// ```
+pub async fn terraform_output(workspace: String, sub: &ArgMatches) -> Result<()> {
+    let json: bool = sub.is_present("json");
+    let raw: Option<String> = sub.value_of_t("raw").ok();
+
+    let output = serde_json::to_value(terraform::output(&workspace).await?)?;
+
+    if let Some(key) = raw {
+        let value = terraform::output_lookup(&output, &key)
+            .with_context(|| format!("{} has no output {}", workspace, key))?;
+        match value {
+            serde_json::Value::String(string) => println!("{}", string),
+            other => println!("{}", other),
+        }
+    } else if json {
+        let stdout = io::stdout();
+        let handle = stdout.lock();
+        serde_json::to_writer_pretty(handle, &output)?;
+    } else {
+        let mut table = Table::new();
+        table.add_row(row!["Output", "Value"]);
+        for (key, value) in terraform::output_flatten(&output) {
+            table.add_row(row![key, value]);
+        }
+        table.printstd();
+    }
+
+    Ok(())
+}
+
+/// Manage the variables of a Terraform Cloud workspace
+///
+/// # Arguments
+///
+/// * `workspace` - a string that holds the name of a terraform workspace
+/// * `sub` - `&ArgMatches` holding the `list`, `set`, or `rm` subcommand
+///
+/// # Examples
+///
+/// ```
+/// terraform_vars("core", arg_matches);
+/// ```
+pub async fn terraform_vars(workspace: String, sub: &ArgMatches) -> Result<()> {
+    let remote = remote_workspace(&workspace).await?;
+
+    match sub.subcommand() {
+        Some(("list", sub_sub)) => {
+            let mut vars = remote.vars().await?;
+            vars.sort_by(|a, b| {
+                (&a.attributes.category, &a.attributes.key)
+                    .cmp(&(&b.attributes.category, &b.attributes.key))
+            });
+
+            if sub_sub.is_present("json") {
+                let attributes: Vec<_> = vars.iter().map(|var| &var.attributes).collect();
+                let stdout = io::stdout();
+                let handle = stdout.lock();
+                serde_json::to_writer_pretty(handle, &attributes)?;
+                return Ok(());
+            }
+
+            let mut table = Table::new();
+            table.add_row(row!["Key", "Category", "Value", "HCL", "Description"]);
+            for var in vars {
+                let attributes = var.attributes;
+                let value = if attributes.sensitive {
+                    "(sensitive)".to_string()
+                } else {
+                    attributes.value.unwrap_or_default()
+                };
+                table.add_row(row![
+                    attributes.key,
+                    attributes.category,
+                    value,
+                    attributes.hcl,
+                    attributes.description.unwrap_or_default()
+                ]);
+            }
+            table.printstd();
+        }
+        Some(("set", sub_sub)) => {
+            let key: String = sub_sub.value_of_t_or_exit("key");
+            let value = match sub_sub.value_of("value") {
+                Some(value) => value.to_string(),
+                None => {
+                    // keeps secrets out of the shell history
+                    let mut value = String::new();
+                    io::stdin().read_to_string(&mut value)?;
+                    value.trim_end_matches('\n').to_string()
+                }
+            };
+
+            remote
+                .set_var(TfcVarAttributes {
+                    key: key.clone(),
+                    value: Some(value),
+                    category: if sub_sub.is_present("env") {
+                        "env".to_string()
+                    } else {
+                        "terraform".to_string()
+                    },
+                    hcl: sub_sub.is_present("hcl"),
+                    sensitive: sub_sub.is_present("sensitive"),
+                    description: sub_sub.value_of("description").map(String::from),
+                })
+                .await?;
+            info!("set {} in {}", key, remote.name);
+        }
+        Some(("rm", sub_sub)) => {
+            let key: String = sub_sub.value_of_t_or_exit("key");
+            let category = if sub_sub.is_present("env") {
+                Some("env")
+            } else {
+                None
+            };
+
+            if remote.remove_var(&key, category).await? == 0 {
+                bail!("{} has no variable {}", remote.name, key);
+            }
+            info!("removed {} from {}", key, remote.name);
+        }
+        _ => return Err(anyhow!("Unknown command")),
+    }
+
+    Ok(())
+}
+
+async fn remote_workspace(workspace: &str) -> Result<RemoteWorkspace> {
+    BitteConfig::load()?
+        .terraform
+        .backend
+        .remote_workspace(workspace)
+        .await?
+        .with_context(|| format!("{} is not in a remote terraform backend", workspace))
+}
+
+/// Lock a remote workspace so that no runs can be queued
 ///
-/// * `workspace` - a string that holds the name of a terraform workspace
-/// * `sub` - `&ArgMatches` holding additional cli flags
+/// # Examples
+///
+/// ```
+/// terraform_lock("core", arg_matches);
+/// ```
+pub async fn terraform_lock(workspace: String, sub: &ArgMatches) -> Result<()> {
+    let remote = remote_workspace(&workspace).await?;
+    remote
+        .lock(sub.value_of("reason").map(String::from))
+        .await?;
+    info!("locked {}", remote.name);
+    Ok(())
+}
+
+/// Unlock a remote workspace
 ///
 /// # Examples
 ///
 /// ```
-/// terraform_passthrough("core", arg_matches);
+/// terraform_unlock("core", arg_matches);
 /// ```
-pub async fn terraform_passthrough(
+pub async fn terraform_unlock(workspace: String, sub: &ArgMatches) -> Result<()> {
+    let remote = remote_workspace(&workspace).await?;
+    remote.unlock(sub.is_present("force")).await?;
+    info!("unlocked {}", remote.name);
+    Ok(())
+}
+
+/// List the state versions of a remote workspace, newest first
+///
+/// # Examples
+///
+/// ```
+/// terraform_history("core", arg_matches);
+/// ```
+pub async fn terraform_history(workspace: String, sub: &ArgMatches) -> Result<()> {
+    let remote = remote_workspace(&workspace).await?;
+    let versions = remote.state_versions().await?;
+
+    if sub.is_present("json") {
+        let stdout = io::stdout();
+        let handle = stdout.lock();
+        serde_json::to_writer_pretty(handle, &versions)?;
+        return Ok(());
+    }
+
+    let mut table = Table::new();
+    table.add_row(row!["Serial", "Created", "Author", "ID"]);
+    for version in versions.iter() {
+        table.add_row(row![
+            version.serial,
+            version.created_at,
+            version.author.as_deref().unwrap_or_default(),
+            version.id
+        ]);
+    }
+    table.printstd();
+    Ok(())
+}
+
+/// Run `terraform apply` with the saved plan of a workspace
+///
+/// After applying `core` or `clients`, the nodes are compared with the snapshot taken before
+/// the apply and new instances can be rebuilt right away.
+///
+/// With `--target`, the saved plan is skipped and terraform plans and asks again for only
+/// those resources.
+pub async fn terraform_apply<F>(
     workspace: String,
     sub: &ArgMatches,
     cluster: ClusterHandle,
+    refresh: F,
+    cancel: &CancellationToken,
+) -> Result<()>
+where
+    F: FnOnce() -> ClusterHandle,
+{
+    let targets: Vec<String> = sub.values_of_t("target").unwrap_or_default();
+    let plan_file = format!("{}.plan", workspace);
+    info!("Plan file: {:?}", plan_file);
+
+    let before = terraform::prepare(workspace.clone(), cluster).await?;
+
+    let backend = BitteConfig::load()?.terraform.backend;
+    if let Some(remote) = backend.remote_workspace(&workspace).await? {
+        // remote runs can't apply a local plan file, so plan again and confirm
+        let (run_id, summary) = terraform_remote_plan(&remote, false, false, &targets).await?;
+
+        // runs without changes finish on their own after planning
+        if !summary.has_changes() {
+            return Ok(());
+        }
+
+        if !bitte_lib::confirm(&format!("Apply these changes to {}?", remote.name), "yes")? {
+            remote.discard(&run_id).await?;
+            bail!("apply of {} aborted", workspace);
+        }
+
+        remote.apply(&run_id).await?;
+        remote.stream(&run_id, Phase::Apply).await?;
+    } else {
+        let mut cmd = Command::new("terraform");
+        let full = if targets.is_empty() {
+            cmd.arg("apply").arg(plan_file)
+        } else {
+            // a saved plan can't be narrowed down any further
+            cmd.arg("apply")
+                .args(targets.iter().map(|target| format!("-target={}", target)))
+        };
+
+        debug!("run: {:?}", full);
+        let status = full
+            .status()
+            .with_context(|| format!("failed to run: {:?}", full))?;
+
+        if !status.success() {
+            bail!("terraform apply failed with {}", status);
+        }
+    }
+
+    terraform::invalidate_output(&workspace)?;
+
+    match workspace.as_str() {
+        "core" | "clients" => reconcile_nodes(before, refresh(), cancel).await,
+        _ => Ok(()),
+    }
+}
+
+async fn reconcile_nodes(
+    before: BitteCluster,
+    after: ClusterHandle,
+    cancel: &CancellationToken,
 ) -> Result<()> {
-    let init: bool = sub.is_present("init");
-    let config: bool = !sub.is_present("no_config");
-    let args = sub.values_of_lossy("args").unwrap_or_default();
+    let after = after.await??;
+    let diff = NodeSetDiff::new(&before.nodes, &after.nodes);
 
-    terraform::set_http_auth()?;
+    if diff.is_empty() {
+        println!("The set of nodes is unchanged.");
+        return Ok(());
+    }
 
-    if config {
-        terraform::generate_terraform_config(&workspace, cluster).await?;
+    let mut table = Table::new();
+    table.add_row(row!["Change", "Name", "ID", "Private IP", "Public IP"]);
+    for node in diff.added.iter() {
+        table.add_row(row!["added", node.name, node.id, node.priv_ip, node.pub_ip]);
+    }
+    for (old, new) in diff.replaced.iter() {
+        let id = format!("{} -> {}", old.id, new.id);
+        table.add_row(row!["replaced", new.name, id, new.priv_ip, new.pub_ip]);
+    }
+    for node in diff.removed.iter() {
+        table.add_row(row![
+            "removed",
+            node.name,
+            node.id,
+            node.priv_ip,
+            node.pub_ip
+        ]);
     }
+    table.printstd();
 
-    if init {
-        terraform::init(false)?;
+    let only: Vec<String> = diff
+        .new_nodes()
+        .iter()
+        .map(|node| node.id.clone())
+        .collect();
+    if only.is_empty() {
+        return Ok(());
     }
 
-    let mut cmd = Command::new("terraform");
-    let full = cmd.args(args);
+    let prompt = format!("Rebuild the {} new nodes now?", only.len());
+    if !bitte_lib::confirm(&prompt, "yes")? {
+        return Ok(());
+    }
+
+    rebuild::set_ssh_opts(true, &after.name)?;
+
+    let run = Run::start("rebuild")?;
+    let result = rebuild::copy(
+        only.iter().map(|id| id.as_str()).collect(),
+        Duration::from_secs(0),
+        false,
+        false,
+        false,
+        after,
+        &run,
+        cancel,
+    )
+    .await;
+    run.finish(result)
+}
+
+struct WorkspaceProgress {
+    workspace: String,
+    status: &'static str,
+    summary: Option<PlanSummary>,
+}
+
+/// Plan and apply every workspace listed in `bitte.json`, in order
+///
+/// The outputs of each applied workspace are passed to the following ones as
+/// `TF_VAR_<workspace>_outputs`, and the first failure stops the whole run.
+pub async fn terraform_apply_all(sub: &ArgMatches, cluster: ClusterHandle) -> Result<()> {
+    let yes: bool = sub.is_present("yes");
+    let config = BitteConfig::load()?;
+    let cluster = cluster.await??;
+
+    let mut progress: Vec<WorkspaceProgress> = config
+        .terraform
+        .workspaces
+        .iter()
+        .map(|workspace| WorkspaceProgress {
+            workspace: workspace.clone(),
+            status: "pending",
+            summary: None,
+        })
+        .collect();
+
+    let run = Run::start("apply-all")?;
+    let result = apply_workspaces(&config, &cluster.name, yes, &run, &mut progress).await;
+
+    let mut table = Table::new();
+    table.add_row(row!["Workspace", "Status", "Add", "Change", "Destroy"]);
+    for step in progress.iter() {
+        match &step.summary {
+            Some(summary) => table.add_row(row![
+                step.workspace,
+                step.status,
+                summary.add,
+                summary.change,
+                summary.destroy
+            ]),
+            None => table.add_row(row![step.workspace, step.status, "", "", ""]),
+        };
+    }
+    table.printstd();
+
+    run.finish(result)
+}
+
+async fn apply_workspaces(
+    config: &BitteConfig,
+    cluster: &str,
+    yes: bool,
+    run: &Run,
+    progress: &mut [WorkspaceProgress],
+) -> Result<()> {
+    let total = progress.len();
+
+    for (i, step) in progress.iter_mut().enumerate() {
+        let workspace = step.workspace.clone();
+        println!("[{}/{}] {}", i + 1, total, workspace);
+        step.status = "failed";
+
+        terraform::prepare_for(&workspace, cluster).await?;
+
+        let plan_file = run.path(&format!("{}.plan", workspace));
+        let mut cmd = Command::new("terraform");
+        let full = cmd
+            .arg("plan")
+            .arg("-out")
+            .arg(&plan_file)
+            .stdout(Stdio::null());
+        info!("run: {:?}", full);
+        if !full.status()?.success() {
+            bail!("terraform plan failed in {}", workspace);
+        }
+
+        let summary = terraform::render_plan(&terraform::show_plan(&plan_file.to_string_lossy())?);
+        let changes = summary.has_changes();
+        step.summary = Some(summary);
+
+        if changes {
+            let prompt = format!("Apply these changes to {}?", workspace);
+            if !yes && !bitte_lib::confirm(&prompt, "yes")? {
+                step.status = "skipped";
+                bail!("stopped before applying {}", workspace);
+            }
+
+            let mut cmd = Command::new("terraform");
+            let full = cmd.arg("apply").arg(&plan_file);
+            info!("run: {:?}", full);
+            if !full.status()?.success() {
+                bail!("terraform apply failed in {}", workspace);
+            }
+            step.status = "applied";
+        } else {
+            step.status = "unchanged";
+        }
+
+        let outputs = config.terraform.backend.read_outputs(&workspace).await?;
+        env::set_var(format!("TF_VAR_{}_outputs", workspace), outputs.to_string());
+    }
 
-    info!("run: {:?}", full);
-    full.status()
-        .with_context(|| format!("failed to run: {:?}", full))?;
     Ok(())
 }
 
-pub async fn terraform_init(
+/// Run a refresh-only plan in a workspace and report the drifted resources
+///
+/// # Arguments
+///
+/// * `workspace` - a string that holds the name of a terraform workspace
+/// * `sub` - `&ArgMatches` holding additional cli flags
+///
+/// # Examples
+///
+/// ```
+/// terraform_drift("core", arg_matches);
+/// ```
+pub async fn terraform_drift(
     workspace: String,
     sub: &ArgMatches,
     cluster: ClusterHandle,
 ) -> Result<()> {
-    let upgrade: bool = sub.is_present("upgrade");
-    terraform::generate_terraform_config(&workspace, cluster).await?;
-    terraform::init(upgrade)?;
-    Ok(())
-}
+    let json: bool = sub.is_present("json");
+    let detailed_exitcode: bool = sub.is_present("detailed-exitcode");
+
+    terraform::prepare(workspace.clone(), cluster).await?;
+
+    let run = Run::start("drift")?;
+    let result = async {
+        let plan_file = run.path(&format!("{}.plan", workspace));
+        let mut cmd = Command::new("terraform");
+        let full = cmd
+            .arg("plan")
+            .arg("-refresh-only")
+            .arg("-out")
+            .arg(&plan_file)
+            .stdout(Stdio::null());
+
+        info!("run: {:?}", full);
+        let status = full
+            .status()
+            .with_context(|| format!("failed to run: {:?}", full))?;
+        if !status.success() {
+            bail!("terraform plan -refresh-only failed with {}", status);
+        }
+
+        terraform::show_plan(&plan_file.to_string_lossy())
+    }
+    .await;
+    let plan = run.finish(result)?;
+
+    let drift = terraform::drift(&plan);
+
+    if json {
+        let stdout = io::stdout();
+        let handle = stdout.lock();
+        serde_json::to_writer_pretty(handle, &drift)?;
+    } else if drift.is_empty() {
+        println!("No drift detected in {}.", workspace);
+    } else {
+        let mut table = Table::new();
+        table.add_row(row!["Type", "Resource", "Actions", "Attributes"]);
+        for (resource_type, resources) in drift.iter() {
+            for resource in resources {
+                table.add_row(row![
+                    resource_type,
+                    resource.address,
+                    resource.actions.join(","),
+                    resource.attributes.join(", ")
+                ]);
+            }
+        }
+        table.printstd();
+    }
+
+    if detailed_exitcode && !drift.is_empty() {
+        return Err(Error::HasChanges { workspace }.into());
+    }
 
-pub async fn terraform_output(_sub: &ArgMatches, cluster: ClusterHandle) -> Result<()> {
-    let output = cluster.await??.terra;
-    let stdout = io::stdout();
-    let handle = stdout.lock();
-    serde_json::to_writer_pretty(handle, &output)?;
     Ok(())
 }
 
-pub async fn terraform_apply(
+/// Run `terraform destroy` in a workspace after the user typed the cluster name
+///
+/// # Arguments
+///
+/// * `workspace` - a string that holds the name of a terraform workspace
+/// * `sub` - `&ArgMatches` holding additional cli flags
+///
+/// # Examples
+///
+/// ```
+/// terraform_destroy("clients", arg_matches);
+/// ```
+pub async fn terraform_destroy(
     workspace: String,
-    _sub: &ArgMatches,
+    sub: &ArgMatches,
     cluster: ClusterHandle,
 ) -> Result<()> {
-    let plan_file = format!("{}.plan", workspace);
-    info!("Plan file: {:?}", plan_file);
+    let targets: Vec<String> = sub.values_of_t("target").unwrap_or_default();
+
+    let cluster = terraform::prepare(workspace.clone(), cluster).await?;
+
+    let prompt = if targets.is_empty() {
+        format!("Destroy all resources of {}/{}?", cluster.name, workspace)
+    } else {
+        format!(
+            "Destroy {} in {}/{}?",
+            targets.join(", "),
+            cluster.name,
+            workspace
+        )
+    };
 
-    terraform::prepare(workspace, cluster).await?;
+    if !bitte_lib::confirm(&prompt, &cluster.name)? {
+        bail!("confirmation didn't match the cluster name, aborting");
+    }
 
     let mut cmd = Command::new("terraform");
-    let full = cmd.arg("apply").arg(plan_file);
+    let full = cmd
+        .arg("destroy")
+        .arg("-auto-approve")
+        .args(targets.iter().map(|target| format!("-target={}", target)));
 
-    debug!("run: {:?}", full);
-    full.status()
+    info!("run: {:?}", full);
+    let status = full
+        .status()
         .with_context(|| format!("failed to run: {:?}", full))?;
+
+    if !status.success() {
+        bail!("terraform destroy failed with {}", status);
+    }
+
     Ok(())
 }
 
-async fn info_print(cluster: ClusterHandle, json: bool) -> Result<()> {
+/// The running allocations of `nodes`, with the name of their node if `with_node`.
+fn allocs_table(nodes: &[BitteNode], with_node: bool) -> Table {
+    let mut table = Table::new();
+    let mut header = row!["Job", "Group", "Index", "Status", "Namespace", "ID"];
+    if with_node {
+        header.insert_cell(0, Cell::new("Node"));
+    }
+    table.add_row(header);
+
+    for node in nodes.iter() {
+        for alloc in bitte_lib::info::live_allocs(node) {
+            let mut row = row![
+                alloc.job_id,
+                alloc.task_group,
+                alloc.index,
+                alloc.status,
+                alloc.namespace,
+                alloc.id.to_hyphenated()
+            ];
+            if with_node {
+                row.insert_cell(0, Cell::new(&node.name));
+            }
+            table.add_row(row);
+        }
+    }
+    table
+}
+
+/// Everything `bitte info` knows about one node.
+fn info_node(detail: &NodeDetail, json: bool) -> Result<()> {
     if json {
+        serde_json::to_writer_pretty(io::stdout().lock(), detail)?;
+        return Ok(());
+    }
+
+    let node = &detail.node;
+    let mut table = Table::new();
+    for column in bitte_lib::info::COLUMNS.iter() {
+        table.add_row(row![column, bitte_lib::info::column(node, column)]);
+    }
+    table.add_row(row![
+        "flake attr",
+        detail.flake_attr.as_deref().unwrap_or_default()
+    ]);
+    table.add_row(row!["booted", detail.booted().unwrap_or_default()]);
+    if let Some(nomad) = &detail.nomad {
+        table.add_row(row!["nomad status", nomad.status]);
+        table.add_row(row!["nomad eligibility", nomad.scheduling_eligibility]);
+        table.add_row(row!["nomad drain", nomad.drain]);
+        table.add_row(row!["nomad datacenter", nomad.datacenter]);
+    }
+    let mut tags: Vec<(&String, &String)> = node.tags.iter().collect();
+    tags.sort();
+    for (key, value) in tags {
+        table.add_row(row![format!("tag:{}", key), value]);
+    }
+    table.printstd();
+
+    if node.nomad_client.is_some() {
+        allocs_table(std::slice::from_ref(node), false).printstd();
+    }
+
+    if !detail.checks.is_empty() {
+        let mut checks = Table::new();
+        checks.add_row(row!["Check", "Service", "Status", "Output"]);
+        for check in detail.checks.iter() {
+            let style = match check.status.as_str() {
+                "passing" => "Fg",
+                "warning" => "Fy",
+                _ => "Fr",
+            };
+            checks.add_row(Row::new(vec![
+                Cell::new(&check.name),
+                Cell::new(&check.service),
+                Cell::new(&check.status).style_spec(style),
+                Cell::new(&text::cell(&check.output, 60)),
+            ]));
+        }
+        checks.printstd();
+    }
+
+    for error in detail.errors.iter() {
+        warn!("{}", error);
+    }
+    Ok(())
+}
+
+async fn info_print(cluster: BitteCluster, extras: InfoExtras, format: &InfoFormat) -> Result<()> {
+    if let InfoFormat::Columns { columns, headers } = format {
+        let mut stdout = io::stdout();
+        if *headers {
+            writeln!(stdout, "{}", columns.join("\t"))?;
+        }
+        for node in cluster.nodes.iter() {
+            let values: Vec<String> = columns
+                .iter()
+                .map(|column| bitte_lib::info::column(node, column))
+                .collect();
+            writeln!(stdout, "{}", values.join("\t"))?;
+        }
+    } else if matches!(format, InfoFormat::Json) {
         let stdout = io::stdout();
         let handle = stdout.lock();
-        let cluster = cluster.await??;
-        env::set_var("BITTE_INFO_NO_ALLOCS", "");
-        serde_json::to_writer_pretty(handle, &cluster)?;
+        // the terraform state is big and may hold secrets, the nodes have what matters of it
+        env::set_var("BITTE_INFO_NO_STATE", "");
+        let mut value = serde_json::to_value(&cluster)?;
+        if let Some(vault) = extras.vault {
+            value["vault"] = serde_json::to_value(vault)?;
+        }
+        if let Some(reported) = extras.versions {
+            value["skew"] = serde_json::to_value(versions::skew(&reported))?;
+            value["versions"] = serde_json::to_value(reported)?;
+        }
+        if let Some(asgs) = extras.asgs {
+            value["asgs"] = serde_json::to_value(asgs)?;
+        }
+        if let Some(costs) = extras.costs {
+            value["costs"] = serde_json::to_value(costs)?;
+        }
+        if let Some(spot) = extras.spot {
+            value["spot"] = serde_json::to_value(spot)?;
+        }
+        serde_json::to_writer_pretty(handle, &value)?;
     } else {
         let mut instance_table = Table::new();
         instance_table.add_row(row![
@@ -378,12 +4313,28 @@ async fn info_print(cluster: ClusterHandle, json: bool) -> Result<()> {
             "Public IP",
             "Type",
             "Zone",
-            "Suffix"
+            "Suffix",
+            "Lifecycle"
         ]);
 
-        let nodes = cluster.await??.nodes;
+        let allocs = match format {
+            InfoFormat::Table { allocs: true } => Some(allocs_table(&cluster.nodes, true)),
+            _ => None,
+        };
+        let nodes = cluster.nodes;
 
         for node in nodes.into_iter() {
+            let interrupted = extras
+                .spot
+                .iter()
+                .flatten()
+                .any(|spot| spot.node == node.name && spot.interrupted);
+            let lifecycle = match node.lifecycle.as_deref() {
+                _ if interrupted => Cell::new("spot, interrupted").style_spec("Fr"),
+                Some(lifecycle) => Cell::new(lifecycle),
+                None => Cell::new("on-demand"),
+            };
+
             let name = if node.nomad_client.is_some() {
                 node.nomad_client.unwrap().id.to_hyphenated().to_string()
             } else {
@@ -414,18 +4365,162 @@ async fn info_print(cluster: ClusterHandle, json: bool) -> Result<()> {
                 }
             };
 
-            instance_table.add_row(row![
+            let mut row = row![
                 name,
                 node.priv_ip,
                 node.pub_ip,
                 node.node_type.unwrap_or_default(),
                 node.zone.unwrap_or_default(),
                 suffix.unwrap_or_default()
-            ]);
+            ];
+            row.add_cell(lifecycle);
+            instance_table.add_row(row);
         }
 
         instance_table.printstd();
+
+        if let Some(asgs) = extras.asgs {
+            asgs_table(&asgs).printstd();
+        }
+
+        if let Some(spot) = extras.spot.filter(|spot| !spot.is_empty()) {
+            let mut spot_table = Table::new();
+            spot_table.add_row(row!["Node", "Spot request", "State", "Status", "Message"]);
+            for status in spot.iter() {
+                let style = if status.interrupted { "Fr" } else { "" };
+                spot_table.add_row(Row::new(vec![
+                    Cell::new(&status.node),
+                    Cell::new(&status.request_id),
+                    Cell::new(&status.state),
+                    Cell::new(&status.code).style_spec(style),
+                    Cell::new(&text::cell(&status.message, 60)),
+                ]));
+            }
+            spot_table.printstd();
+        }
+
+        if let Some(allocs) = allocs {
+            allocs.printstd();
+        }
+
+        if let Some(vault) = extras.vault {
+            let mut vault_table = Table::new();
+            vault_table.add_row(row!["Node", "Vault", "Role", "Version", "Detail"]);
+            for status in vault.iter() {
+                vault_table.add_row(row![
+                    status.node,
+                    status.address,
+                    status.role(),
+                    status
+                        .health
+                        .as_ref()
+                        .map(|health| health.version.as_str())
+                        .unwrap_or_default(),
+                    text::cell(status.error.as_deref().unwrap_or_default(), 60)
+                ]);
+            }
+            vault_table.printstd();
+        }
+
+        if let Some(reported) = extras.versions {
+            versions_table(&reported).printstd();
+        }
+
+        if let Some(costs) = extras.costs {
+            costs_table(&costs).printstd();
+        }
     }
 
     Ok(())
 }
+
+/// The capacity of the auto-scaling groups, in yellow while one is scaling.
+fn asgs_table(asgs: &[AsgCapacity]) -> Table {
+    let mut table = Table::new();
+    table.add_row(row![
+        "ASG",
+        "Region",
+        "Type",
+        "Declared",
+        "Desired",
+        "Min",
+        "Max",
+        "In service",
+        "Pending",
+        "Other",
+        "Error"
+    ]);
+    let count = |count: Option<i64>| count.map(|count| count.to_string()).unwrap_or_default();
+    for asg in asgs.iter() {
+        let mut row = row![
+            asg.name,
+            asg.region,
+            asg.instance_type,
+            asg.declared,
+            count(asg.desired),
+            count(asg.min),
+            count(asg.max),
+            asg.in_service,
+            asg.pending,
+            asg.other,
+            text::cell(asg.error.as_deref().unwrap_or_default(), 60)
+        ];
+        if asg.is_scaling() {
+            for cell in row.iter_mut() {
+                *cell = cell.clone().style_spec("Fy");
+            }
+        }
+        table.add_row(row);
+    }
+    table
+}
+
+/// The hourly cost of each node, and what they add up to.
+fn costs_table(costs: &CostReport) -> Table {
+    let dollars = |amount: f64| format!("${:.2}", amount);
+    let mut table = Table::new();
+    table.add_row(row!["Node", "Type", "Region", "Lifecycle", "Hourly"]);
+    for node in costs.nodes.iter() {
+        table.add_row(row![
+            node.node,
+            node.instance_type,
+            node.region,
+            if node.spot { "spot" } else { "on-demand" },
+            r->node
+                .hourly
+                .map(|hourly| format!("${:.4}", hourly))
+                .unwrap_or_else(|| "unknown".to_string())
+        ]);
+    }
+    table.add_row(row![b->"Total", "", "", "", br->dollars(costs.hourly)]);
+    table.add_row(row![b->"Per month", "", "", "", br->dollars(costs.monthly)]);
+    if costs.unknown > 0 {
+        table.add_row(row![
+            format!("{} nodes without a price are left out", costs.unknown),
+            "",
+            "",
+            "",
+            ""
+        ]);
+    }
+    table
+}
+
+/// The versions on each node, with those that differ from most of their peers in red.
+fn versions_table(reported: &[NodeVersions]) -> Table {
+    let mut table = Table::new();
+    table.add_row(row!["Node", "NixOS", "Nomad", "Consul", "Vault"]);
+    for node in reported.iter() {
+        let mut row = Row::new(vec![Cell::new(&node.node)]);
+        for component in versions::COMPONENTS.iter() {
+            let cell = Cell::new(node.get(component).unwrap_or_default());
+            if versions::skewed(node, component, reported) {
+                row.add_cell(cell.style_spec("Fr"));
+            } else {
+                row.add_cell(cell);
+            }
+        }
+        table.add_row(row);
+    }
+    table
+}
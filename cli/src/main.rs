@@ -1,11 +1,22 @@
 mod cli;
 
 use anyhow::{bail, Context, Result};
-use bitte_lib::types::BitteCluster;
+use bitte_lib::{
+    alias, budget, cancel,
+    config::BitteConfig,
+    db,
+    endpoint::{self, Service},
+    error::Error,
+    login,
+    policy::Action,
+    sts::{self, AssumeRole},
+    types::BitteCluster,
+    vault,
+};
 use clap::clap_app;
 use clap::{Arg, IntoApp};
 use deploy::cli::Opts;
-use std::env;
+use std::{collections::HashMap, env, time::Instant};
 use uuid::Uuid;
 
 #[tokio::main]
@@ -16,36 +27,73 @@ async fn main() -> Result<()> {
       (version: clap::crate_version!())
       (author: clap::crate_authors!("\n"))
       (about: clap::crate_description!())
-      (@arg provider: --provider<NAME> env[BITTE_PROVIDER] "The cluster infrastructure provider")
+      (@arg provider: --provider<NAME> env[BITTE_PROVIDER] "The cluster infrastructure provider, or several separated by commas for hybrid clusters")
       (@arg domain: --domain<NAME> env[BITTE_DOMAIN] "The public domain of the cluster")
       (@arg name: --cluster<NAME> env[BITTE_CLUSTER] "The unique name of the cluster")
-      (@arg "nomad-token": --nomad<TOKEN> env[NOMAD_TOKEN] "The Nomad token used to query node information")
+      (@arg "nomad-token": --nomad[TOKEN] env[NOMAD_TOKEN] "The Nomad token used to query node information, issued by Vault unless given")
+      (@arg profile: --profile "Print the time taken and the API requests made per provider when done")
       (@subcommand rebuild =>
         (about: "nixos-rebuild")
         (@arg only: -o --only +takes_value +multiple "pattern of hosts to deploy")
         (@arg clients: -l --clients conflicts_with[only] "rebuild all nomad client nodes")
-        (@arg delay: -d --delay +takes_value "seconds to delay between rebuilds")
-        (@arg copy: -c --copy "copy to the S3 cache first"))
+        (@arg delay: -d --delay +takes_value conflicts_with[two_phase] "seconds to delay between rebuilds")
+        (@arg two_phase: --("two-phase") "stage all nodes first, then activate them together and roll back the batch on failure")
+        (@arg window: --window +takes_value requires[two_phase] "seconds all activations must finish within, defaults to 120")
+        (@arg copy: -c --copy "copy to the S3 cache first")
+        (@arg ssm: -s --ssm "transport closures and activate through AWS SSM instead of ssh"))
       (@subcommand info =>
         (about: "Show information about instances and auto-scaling groups")
-        (@arg json: -j --json "format as json"))
+        (@arg json: -j --json "format as json, with the nomad clients and their allocations")
+        (@arg output: -o --output +takes_value conflicts_with[json] "print these columns separated by tabs, like name,priv_ip,tag:Role")
+        (@arg no_headers: --("no-headers") requires[output] "leave out the header line of --output")
+        (@arg filter: -f --filter +takes_value +multiple number_of_values(1) "only show the nodes matching all of these, like role=client, zone!=eu-central-1a, or name~^core")
+        (@arg sort: --sort +takes_value "sort the nodes by this column, descending with a leading -")
+        (@arg allocs: -a --allocs conflicts_with[json output] "also list the running allocations of each node")
+        (@arg needle: +takes_value conflicts_with[output vault] "name, id, or ip of a node to show in detail")
+        (@arg state_serial: --("state-serial") +takes_value "show the instances recorded in this state version instead")
+        (@arg workspace: --workspace +takes_value requires[state_serial] "workspace of --state-serial, defaults to core")
+        (@arg namespace: -n --namespace +takes_value env[NOMAD_NAMESPACE] "only show the allocations of this nomad namespace")
+        (@arg vault: --vault conflicts_with[state_serial] "also show whether the Vault of each core node is initialized, sealed, or the leader")
+        (@arg watch: -w --watch +takes_value min_values(0) +require_equals conflicts_with[state_serial] "refresh the display in place every 10 seconds, or --watch=<seconds>, until interrupted")
+        (@arg versions: --versions conflicts_with[output state_serial] "also show the versions of NixOS, Nomad, Consul, and Vault on each node, those that differ from the rest in red")
+        (@arg costs: --costs conflicts_with[output] "also show the approximate hourly cost of each node and the whole cluster, from the AWS list prices")
+        (@arg public: --public "reach the Vault instances at their public ips instead of the private ones"))
       (@subcommand ssh =>
         (about: "SSH to instances")
         (@arg job: -j --job +takes_value +multiple #{3, 3} "specify client by: job group alloc_index\nauto 'cd' to alloc dir when <args> are not specified")
         (@group multi =>
             (@arg all: -a --all conflicts_with[job] requires[args] "run <args> on all nodes")
             (@arg parallel: -p --parallel conflicts_with[job] requires[args] conflicts_with[all] "run <args> on nodes in parallel"))
-        (@arg namespace: -n --namespace +takes_value env[NOMAD_NAMESPACE] "specify nomad namespace to search for <job>\nonly valid with --job flag")
+        (@arg namespace: -n --namespace +takes_value env[NOMAD_NAMESPACE] "specify nomad namespace to search for <job>, defaults to all\nonly valid with --job flag")
         (@arg clients: -l --clients requires[multi] "for -a and -p, execute commands only on Nomad clients")
         (@arg delay: -d --delay +takes_value requires[all] "for -a, seconds to delay between commands")
+        (@arg "record-session": --("record-session") conflicts_with[multi] "record the session as an asciicast in a run directory, with secrets scrubbed")
+        (@arg raw: --raw requires[parallel] "for -p, pass the output of the nodes through as it arrives instead of grouping and prefixing it")
+        (@arg state_serial: --("state-serial") +takes_value "resolve nodes from this state version instead of the running instances")
+        (@arg workspace: --workspace +takes_value requires[state_serial] "workspace of --state-serial, defaults to core")
         (@arg args: +takes_value +multiple "arguments to ssh"))
       (@subcommand terraform =>
         (about: "Run terraform")
         (aliases: &["tf"])
+        (@setting SubcommandsNegateReqs)
         (@arg workspace: +takes_value +required "name of the terraform workspace")
         (@subcommand plan => (about: "terraform plan")
-          (@arg destroy: --destroy -d "create a destruction plan"))
-        (@subcommand apply => (about: "terraform apply"))
+          (@arg destroy: --destroy -d "create a destruction plan")
+          (@arg raw: --raw "show the plain terraform output instead of a summary")
+          (@arg target: -t --target +takes_value +multiple number_of_values(1) "limit the plan to these resource addresses")
+          (@arg "detailed-exitcode": --("detailed-exitcode") "exit with 2 when the plan contains changes"))
+        (@subcommand apply => (about: "terraform apply")
+          (@arg target: -t --target +takes_value +multiple number_of_values(1) "plan and apply only these resource addresses"))
+        (@subcommand "apply-all" => (about: "plan and apply all workspaces in the configured order")
+          (@arg yes: -y --yes "apply without asking for confirmation"))
+        (@subcommand drift => (about: "report resources changed outside of terraform")
+          (@arg json: -j --json "format as json")
+          (@arg "detailed-exitcode": --("detailed-exitcode") "exit with 2 when drift was detected"))
+        (@subcommand import => (about: "import an existing resource, then show its plan")
+          (@arg address: +takes_value +required "resource address to import into, e.g. aws_s3_bucket.cache")
+          (@arg id: +takes_value +required "id of the existing resource"))
+        (@subcommand destroy => (about: "terraform destroy")
+          (@arg target: -t --target +takes_value +multiple number_of_values(1) "limit the destruction to these resource addresses"))
         (@subcommand passthrough =>
           (about: "delegate to terraform")
           (aliases: &["passthru", "pt"])
@@ -54,18 +102,369 @@ async fn main() -> Result<()> {
           delegating to terraform")
           (@arg args: +takes_value +multiple "arguments to terraform"))
         (@subcommand init => (about: "terraform init")
-          (@arg upgrade: --upgrade -u "upgrade provider versions"))
-        (@subcommand output => (about: "terraform output")))
+          (@arg upgrade: --upgrade -u conflicts_with[bootstrap] "upgrade provider versions")
+          (@arg bootstrap: --bootstrap -b "create the workspace on the backend with its variables first"))
+        (@subcommand vars => (about: "manage the variables of a remote workspace")
+          (@subcommand list => (about: "list the variables")
+            (@arg json: -j --json "format as json"))
+          (@subcommand set => (about: "create or replace a variable")
+            (@arg key: +takes_value +required "name of the variable")
+            (@arg value: +takes_value "value of the variable, read from stdin when omitted")
+            (@arg env: -e --env "set an environment variable instead of a terraform variable")
+            (@arg hcl: --hcl "parse the value as HCL")
+            (@arg sensitive: -s --sensitive "hide the value in the API and UI")
+            (@arg description: -d --description +takes_value "description of the variable"))
+          (@subcommand rm => (about: "remove a variable")
+            (@arg key: +takes_value +required "name of the variable")
+            (@arg env: -e --env "remove the environment variable only")))
+        (@subcommand lock => (about: "lock a remote workspace")
+          (@arg reason: -r --reason +takes_value "why the workspace is locked"))
+        (@subcommand unlock => (about: "unlock a remote workspace")
+          (@arg force: -f --force "also release locks held by someone else"))
+        (@subcommand history => (about: "list the state versions of a remote workspace")
+          (@arg json: -j --json "format as json"))
+        (@subcommand output => (about: "terraform output")
+          (@arg json: -j --json conflicts_with[raw] "format as json")
+          (@arg raw: -r --raw +takes_value "print only the value at this dotted path, e.g. s3-cache")))
       (@subcommand provision =>
-        (about: "Initial provisioning from Terraform (do not run yourself)")
-        (@arg ip: +takes_value +required "ip of the node")
-        (@arg name: +takes_value +required "name of the node")
-        (@arg cluster: +takes_value +required "cluster name")
-        (@arg flake: +takes_value +required "flake location")
-        (@arg attr: +takes_value +required "flake host attr")
-        (@arg cache: +takes_value +required "cache location"))
+        (about: "Initial provisioning of new nodes, run by Terraform for each of them")
+        (@arg ip: +takes_value required_unless_present_any(&["target", "new"]) "ip of the node")
+        (@arg name: +takes_value required_unless_present_any(&["target", "new"]) "name of the node")
+        (@arg cluster: +takes_value required_unless_present_any(&["target", "new"]) "cluster name")
+        (@arg flake: +takes_value required_unless_present_any(&["target", "new"]) "flake location")
+        (@arg attr: +takes_value required_unless_present_any(&["target", "new"]) "flake host attr")
+        (@arg cache: +takes_value required_unless_present_any(&["target", "new"]) "cache location")
+        (@arg target: -t --target +takes_value +multiple number_of_values(1) conflicts_with[ip] "provision this node of the cluster instead, by name, id, or ip; repeatable")
+        (@arg new: --new conflicts_with[ip target] "provision every node of the cluster still running the system of its image")
+        (@arg jobs: -j --jobs +takes_value default_value("4") "provision this many nodes at once")
+        (@arg upload_transcripts: --("upload-transcripts") "upload the transcript of each node to the bucket of the binary cache")
+        (@arg bootstrap_consul: --("bootstrap-consul") "bootstrap the Consul ACLs once the node runs, saving the management token in secrets/consul-acl-bootstrap.json"))
       (@subcommand certs =>
-        (@arg domain: +takes_value +required "FQDN of the cluster"))
+        (@setting SubcommandsNegateReqs)
+        (@arg domain: +takes_value +required "FQDN of the cluster")
+        (@arg ca: --ca +takes_value "chain of the CA signing the issuing CA of Vault, instead of secrets/ca.pem")
+        (@arg ca_key: --("ca-key") +takes_value requires[ca] "key of the CA given with --ca")
+        (@arg csr_only: --("csr-only") conflicts_with[signed] "only write the CSR of the issuing CA, for a CA whose key isn't at hand to sign")
+        (@arg signed: --signed +takes_value requires[ca] conflicts_with[ca_key] "the issuing CA as signed from the CSR by the CA given with --ca")
+        (@arg cross_ca: --("cross-sign-ca") +takes_value requires[cross_ca_key] "also sign the issuing CA with this CA, and trust both roots on the nodes")
+        (@arg cross_ca_key: --("cross-sign-key") +takes_value requires[cross_ca] "key of the CA given with --cross-sign-ca")
+        (@subcommand "export-ca" => (about: "write the CA chain of the cluster to a file and optionally trust it")
+          (@arg out: -o --out +takes_value "file to write the chain to, defaults to <cluster>-ca.pem")
+          (@arg node: --node +takes_value "read the chain from this core node instead of Vault")
+          (@arg install: -i --install "install the root CA into the trust stores of this machine")
+          (@arg fingerprint: --fingerprint +takes_value "SHA-256 fingerprint the root must have, instead of being secrets/ca.pem or signed by it")
+          (@arg yes: -y --yes "install without asking for confirmation"))
+        (@subcommand status => (about: "show when the CA, the server certificates of the core nodes, and the client certificates expire")
+          (@arg warn_days: --("warn-days") +takes_value default_value("60") "highlight certificates expiring within this many days")
+          (@arg renew_days: --("renew-days") +takes_value default_value("30") "fail for certificates expiring within this many days")
+          (@arg public: --public "reach the core nodes at their public ips instead of the private ones")
+          (@arg json: -j --json "format as json"))
+        (@subcommand rotate => (about: "issue new certificates from Vault PKI and switch the nodes over one at a time")
+          (@arg node: --node +takes_value +multiple +use_delimiter "only rotate these nodes")
+          (@arg san: --san +takes_value +multiple number_of_values(1) "also issue for this DNS name or ip, kept for later rotations")
+          (@arg drop_san: --("drop-san") +takes_value +multiple number_of_values(1) "stop issuing for a name added with --san")
+          (@arg ttl: --ttl +takes_value "how long the certificates are valid, kept for later rotations, defaults to the maximum of the PKI role")
+          (@arg public: --public "verify the services at the public ips of the nodes instead of the private ones")
+          (@arg dry_run: -n --("dry-run") "only show the nodes and the names their certificates would be issued for")
+          (@arg yes: -y --yes "don't ask for confirmation"))
+        (@subcommand acme => (about: "issue a publicly trusted certificate for the endpoints of the cluster and deploy it to the edge nodes")
+          (@arg email: --email +takes_value "contact of the ACME account, defaults to acme.email in bitte.json")
+          (@arg staging: --staging "use the staging directory of Let's Encrypt")
+          (@arg san: --san +takes_value +multiple number_of_values(1) "also issue for this name, kept for later renewals")
+          (@arg drop_san: --("drop-san") +takes_value +multiple number_of_values(1) "stop issuing for a name added with --san")
+          (@arg renew_days: --("renew-days") +takes_value default_value("30") "renew the certificate in Vault once it expires within this many days")
+          (@arg force: --force "issue a new certificate even if the one in Vault is still valid")
+          (@arg node: --node +takes_value +multiple +use_delimiter "deploy to these nodes instead of the routing nodes")
+          (@arg unit: --unit +takes_value default_value("traefik") "the systemd unit serving the certificate on the edge nodes")))
+      (@subcommand job =>
+        (about: "Manage Nomad jobs")
+        (@subcommand list =>
+          (about: "Summarize the jobs of all namespaces")
+          (@arg namespace: -n --namespace +takes_value env[NOMAD_NAMESPACE] "only show the jobs of this namespace")
+          (@arg json: -j --json "format as json"))
+        (@subcommand promote =>
+          (about: "Submit the job running in one cluster to another")
+          (@arg job: +takes_value +required "name of the job")
+          (@arg from: --from +takes_value +required "cluster to take the job from")
+          (@arg to: --to +takes_value +required "cluster to submit the job to")
+          (@arg namespace: -n --namespace +takes_value env[NOMAD_NAMESPACE] "namespace of the job in the source cluster, defaults to default"))
+        (@subcommand plan =>
+          (about: "Render a job with cue and show what submitting it would change")
+          (@arg job: +takes_value +required "name of the job")
+          (@arg namespace: -n --namespace +takes_value env[NOMAD_NAMESPACE] "namespace of the job, defaults to default"))
+        (@subcommand run =>
+          (about: "Plan a job and submit it, unless it changed since the plan")
+          (@arg job: +takes_value +required "name of the job")
+          (@arg namespace: -n --namespace +takes_value env[NOMAD_NAMESPACE] "namespace of the job, defaults to default")
+          (@arg yes: -y --yes "submit without asking for confirmation")
+          (@arg detach: -d --detach "don't follow the deployment"))
+        (@subcommand stop =>
+          (about: "Stop a job")
+          (@arg job: +takes_value +required "name of the job")
+          (@arg namespace: -n --namespace +takes_value env[NOMAD_NAMESPACE] "namespace of the job, defaults to default")
+          (@arg purge: --purge "remove the job from nomad's state as well")
+          (@arg yes: -y --yes "stop without asking for confirmation"))
+        (@subcommand scale =>
+          (about: "Set the number of allocations of a task group")
+          (@arg job: +takes_value +required "name of the job")
+          (@arg group: +takes_value +required "name of the task group")
+          (@arg count: +takes_value +required "number of allocations the group should have")
+          (@arg namespace: -n --namespace +takes_value env[NOMAD_NAMESPACE] "namespace of the job, defaults to default")
+          (@arg message: -m --message +takes_value "reason for the scaling, kept in the scaling events of the job")
+          (@arg watch: -w --watch "follow the deployment until the allocations are placed"))
+        (@subcommand restart =>
+          (about: "Restart the allocations of a job without changing it")
+          (@arg job: +takes_value +required "name of the job")
+          (@arg namespace: -n --namespace +takes_value env[NOMAD_NAMESPACE] "namespace of the job, defaults to default")
+          (@arg reschedule: --reschedule conflicts_with[in_place] "submit the job again with a new restart stamp, so its allocations are replaced as its update stanza says (the default)")
+          (@arg in_place: --("in-place") "restart the tasks inside the running allocations instead, each once the one before runs again")
+          (@arg task: -t --task +takes_value requires[in_place] "only restart this task")
+          (@arg yes: -y --yes "restart without asking for confirmation")
+          (@arg detach: -d --detach "don't follow the deployment"))
+        (@subcommand history =>
+          (about: "List the submitted versions of a job and what changed in each")
+          (@arg job: +takes_value +required "name of the job")
+          (@arg namespace: -n --namespace +takes_value env[NOMAD_NAMESPACE] "namespace of the job, defaults to all")
+          (@arg json: -j --json "format as json"))
+        (@subcommand diff =>
+          (about: "Show what changed in a job between two of its versions")
+          (@arg job: +takes_value +required "name of the job")
+          (@arg from: +takes_value +required "version to compare from")
+          (@arg to: +takes_value +required "version to compare to")
+          (@arg namespace: -n --namespace +takes_value env[NOMAD_NAMESPACE] "namespace of the job, defaults to all")
+          (@arg json: -j --json "format as json"))
+        (@subcommand status =>
+          (about: "Show the allocations and latest deployment of a job")
+          (@arg job: +takes_value +required "name of the job")
+          (@arg namespace: -n --namespace +takes_value env[NOMAD_NAMESPACE] "namespace of the job, defaults to all")
+          (@arg json: -j --json "format as json")))
+      (@subcommand alloc =>
+        (about: "Operations on single allocations")
+        (@subcommand restart =>
+          (about: "Restart one allocation without redeploying its job")
+          (@arg alloc: +takes_value +required "id or unique prefix of the allocation")
+          (@arg in_place: --("in-place") conflicts_with[reschedule] "restart its tasks where they run, keeping its node and local data (the default)")
+          (@arg reschedule: --reschedule "stop it so nomad places a replacement, possibly on another node")
+          (@arg task: -t --task +takes_value conflicts_with[reschedule] "only restart this task")
+          (@arg yes: -y --yes "restart without asking for confirmation")))
+      (@subcommand node =>
+        (about: "Power, scheduling, and nix store operations on cluster nodes")
+        (@subcommand reboot => (about: "reboot a node")
+          (@arg node: +takes_value +required "name, id, or ip of the node"))
+        (@subcommand stop => (about: "stop a node")
+          (@arg node: +takes_value +required "name, id, or ip of the node"))
+        (@subcommand start => (about: "start a stopped node")
+          (@arg node: +takes_value +required "name, id, or ip of the node"))
+        (@subcommand console => (about: "print the serial console output of a node")
+          (@arg node: +takes_value +required "name, id, or ip of the node")
+          (@arg raw: --raw "write the output unchanged, even if it isn't valid UTF-8"))
+        (@subcommand drain => (about: "drain a nomad client and follow the migration of its allocations")
+          (@arg node: +takes_value +required "name, id, or ip of the node")
+          (@arg deadline: -d --deadline +takes_value "time until remaining allocations are stopped, like 90s, 10m or 2h, defaults to 1h")
+          (@arg "ignore-system": --("ignore-system") "leave system jobs running on the node"))
+        (@subcommand eligibility => (about: "allow or forbid scheduling allocations on a nomad client")
+          (@arg node: +takes_value +required "name, id, or ip of the node")
+          (@arg state: +takes_value +required possible_value[on off] "on to make the node eligible, off to make it ineligible"))
+        (@subcommand store => (about: "check and repair the nix store of a node")
+          (@subcommand verify => (about: "check the contents of the store paths of a node against their hashes")
+            (@arg node: +takes_value +required "name, id, or ip of the node")
+            (@arg sample: --sample +takes_value "check this many random paths instead of all of them")
+            (@arg ssm: -s --ssm "run the check through AWS SSM instead of ssh")
+            (@arg json: -j --json "format as json"))
+          (@subcommand repair => (about: "check the store of a node and fetch corrupted paths again from the s3 cache")
+            (@arg node: +takes_value +required "name, id, or ip of the node")
+            (@arg sample: --sample +takes_value "check this many random paths instead of all of them")
+            (@arg ssm: -s --ssm "run the repair through AWS SSM instead of ssh")
+            (@arg json: -j --json "format as json"))))
+      (@subcommand runs =>
+        (about: "Manage the artifacts kept from previous runs")
+        (@subcommand gc =>
+          (about: "Remove old run directories")
+          (@arg "max-age": --("max-age") +takes_value "remove runs older than this many hours, defaults to 168")))
+      (@subcommand runbook =>
+        (about: "Execute a runbook from bitte.json, or list them without a name")
+        (@arg name: +takes_value "name of the runbook")
+        (@arg param: -p --param +takes_value +multiple number_of_values(1) "parameter of the runbook as name=value")
+        (@arg restart: --restart "start from the first step instead of resuming"))
+      (@subcommand query =>
+        (about: "Query the history of operations recorded on this machine")
+        (@arg query: +takes_value "name of a canned query, or `list` to show them")
+        (@arg sql: --sql +takes_value conflicts_with[query] "run this SQL against the tables history, runs, and node_events")
+        (@arg json: -j --json "format as json"))
+      (@subcommand verify =>
+        (about: "Run health checks against the cluster")
+        (@arg config: -c --config +takes_value "file declaring the checks, defaults to verify.json")
+        (@arg concurrency: --concurrency +takes_value "maximum number of checks to run at once")
+        (@arg json: -j --json "format as json"))
+      (@subcommand deployment =>
+        (about: "Control Nomad deployments")
+        (@subcommand list => (about: "list the deployments of a namespace")
+          (@arg namespace: -n --namespace +takes_value env[NOMAD_NAMESPACE] "only show the deployments of this namespace")
+          (@arg json: -j --json "format as json"))
+        (@subcommand promote => (about: "promote the canaries of a deployment")
+          (@arg id: +takes_value +required "(a prefix of) the deployment id")
+          (@arg namespace: -n --namespace +takes_value env[NOMAD_NAMESPACE] "namespace of the deployment, defaults to all")
+          (@arg group: -g --group +takes_value +multiple number_of_values(1) "only promote these task groups")
+          (@arg watch: -w --watch "follow the deployment until it is done"))
+        (@subcommand fail => (about: "mark a deployment as failed, reverting it if configured")
+          (@arg id: +takes_value +required "(a prefix of) the deployment id")
+          (@arg namespace: -n --namespace +takes_value env[NOMAD_NAMESPACE] "namespace of the deployment, defaults to all")
+          (@arg watch: -w --watch "follow the deployment until it is done"))
+        (@subcommand pause => (about: "pause a deployment")
+          (@arg id: +takes_value +required "(a prefix of) the deployment id")
+          (@arg namespace: -n --namespace +takes_value env[NOMAD_NAMESPACE] "namespace of the deployment, defaults to all")
+          (@arg resume: -r --resume "resume the deployment instead")
+          (@arg watch: -w --watch "follow the deployment until it is paused, or done when resuming")))
+      (@subcommand logs =>
+        (about: "Stream the logs of a job's allocations or of a single allocation")
+        (@arg target: +takes_value +required "name of a job, or (a prefix of) an allocation id")
+        (@arg namespace: -n --namespace +takes_value env[NOMAD_NAMESPACE] "namespace of the job, defaults to all")
+        (@arg task: -t --task +takes_value "only read the logs of this task")
+        (@arg follow: -f --follow "keep streaming new output until the tasks stop")
+        (@arg stderr: --stderr "read stderr instead of stdout"))
+      (@subcommand login =>
+        (about: "Log in to Vault and lease Nomad and Consul tokens, without the vault CLI")
+        (@arg method: -m --method +takes_value possible_value[github oidc] "how to log in, defaults to github")
+        (@arg mount: --mount +takes_value "path the auth method is mounted at, defaults to the name of the method")
+        (@arg role: -r --role +takes_value requires[method] "role to log in as with oidc, defaults to the default role of the mount"))
+      (@subcommand vault =>
+        (about: "Operate the Vault instances of the cluster")
+        (@subcommand unseal =>
+          (about: "Find sealed Vault instances on the core nodes and unseal them one after the other")
+          (@arg key_file: -k --("key-file") +takes_value "file with the key shares, one per line or the json of vault operator init, instead of asking for them")
+          (@arg public: --public "reach the instances at their public ips instead of the private ones"))
+        (@subcommand leases =>
+          (about: "List the leases Vault handed out under a prefix, and revoke them")
+          (@arg prefix: +takes_value default_value("") "prefix of the leases, like aws/creds/admin")
+          (@arg revoke: --revoke +takes_value +multiple +use_delimiter "revoke these leases")
+          (@arg revoke_all: --("revoke-all") conflicts_with[revoke] "revoke every lease under the prefix")
+          (@arg yes: -y --yes "revoke without asking")
+          (@arg json: -j --json "format as json"))
+        (@subcommand policies =>
+          (about: "Manage the ACL policies of Vault")
+          (@subcommand sync =>
+            (about: "Make the policies of Vault match the <name>.hcl files in a directory, showing the changes first")
+            (@arg dir: +takes_value +required "directory with the policies")
+            (@arg prune: --prune "remove policies that have no file, except for root and default")
+            (@arg dry_run: -n --("dry-run") "only show the changes")
+            (@arg yes: -y --yes "apply the changes without asking"))))
+      (@subcommand backup =>
+        (about: "Take Raft snapshots of Vault and Consul and upload them encrypted to the snapshot bucket")
+        (@arg service: +takes_value possible_value[vault consul all] default_value("all") "which service to back up")
+        (@arg keep: -k --keep +takes_value default_value("14") "how many snapshots of each service to keep in the bucket")
+        (@arg bucket: --bucket +takes_value "S3 URL to keep the snapshots under, defaults to snapshots.bucket in bitte.json")
+        (@arg kms_key: --("kms-key") +takes_value "KMS key to encrypt the snapshots with, defaults to snapshots.kms-key in bitte.json or the AWS managed key"))
+      (@subcommand restore =>
+        (about: "Restore Vault or Consul from a snapshot in the snapshot bucket")
+        (@arg service: +takes_value +required possible_value[vault consul] "which service to restore")
+        (@arg snapshot: -s --snapshot +takes_value "name of the snapshot, defaults to the latest one")
+        (@arg list: -l --list conflicts_with[snapshot] "list the snapshots in the bucket instead of restoring one")
+        (@arg force: --force "restore a Vault snapshot taken with other unseal keys, e.g. of another cluster")
+        (@arg bucket: --bucket +takes_value "S3 URL the snapshots are kept under, defaults to snapshots.bucket in bitte.json"))
+      (@subcommand consul =>
+        (about: "Inspect the Consul of the cluster")
+        (@subcommand services =>
+          (about: "List the services in the catalog with the health of their instances")
+          (@arg node: --node +takes_value "only count the instances on this node")
+          (@arg tag: -t --tag +takes_value "only count the instances with this tag")
+          (@arg json: -j --json "format as json"))
+        (@subcommand intentions =>
+          (about: "Manage the intentions deciding which services may talk to each other over Connect")
+          (@subcommand list => (about: "list the intentions, highest precedence first")
+            (@arg json: -j --json "format as json"))
+          (@subcommand allow => (about: "allow traffic from one service to another, replacing an intention between them")
+            (@arg source: +takes_value +required "source service, or * for all")
+            (@arg destination: +takes_value +required "destination service, or * for all")
+            (@arg description: -d --description +takes_value "why")
+            (@arg dry_run: -n --("dry-run") "only show which intentions it would replace or shadow")
+            (@arg yes: -y --yes "write it without asking"))
+          (@subcommand deny => (about: "deny traffic from one service to another, replacing an intention between them")
+            (@arg source: +takes_value +required "source service, or * for all")
+            (@arg destination: +takes_value +required "destination service, or * for all")
+            (@arg description: -d --description +takes_value "why")
+            (@arg dry_run: -n --("dry-run") "only show which intentions it would replace or shadow")
+            (@arg yes: -y --yes "write it without asking")))
+        (@subcommand token =>
+          (about: "Manage Consul ACL tokens")
+          (@subcommand bootstrap => (about: "bootstrap the ACL system and save the initial management token")
+            (@arg output: -o --output +takes_value default_value("secrets/consul-acl-bootstrap.json") "file the management token is saved in"))
+          (@subcommand create => (about: "create a token from a template in bitte.json and print its secret")
+            (@arg template: +takes_value +required "name of the template")
+            (@arg name: +takes_value default_value("") "what the token is for, replaces {name} in the template")
+            (@arg json: -j --json "format as json"))
+          (@subcommand list => (about: "list the tokens without their secrets")
+            (@arg json: -j --json "format as json"))
+          (@subcommand revoke => (about: "revoke a token")
+            (@arg accessor: +takes_value +required "accessor id of the token"))))
+      (@subcommand operator =>
+        (about: "Inspect the servers of Consul and Nomad")
+        (@subcommand raft =>
+          (about: "Show the raft peers, leadership, and autopilot health of Consul and Nomad, and what is wrong with them")
+          (@arg service: +takes_value possible_value[consul nomad all] default_value("all") "which servers to show")
+          (@arg json: -j --json "format as json")))
+      (@subcommand secret =>
+        (about: "Read and write secrets in the KV store of the cluster's Vault")
+        (@subcommand get =>
+          (about: "Print the latest version of a secret")
+          (@arg path: +takes_value +required "path of the secret, relative to the mount")
+          (@arg format: -f --format +takes_value possible_value[json dotenv] default_value("json") "how to print the fields")
+          (@arg mount: -m --mount +takes_value default_value("secret") "the KV-v2 mount"))
+        (@subcommand put =>
+          (about: "Write a new version of a secret")
+          (@arg path: +takes_value +required "path of the secret, relative to the mount")
+          (@arg fields: +takes_value +multiple "fields as KEY=value")
+          (@arg file: --file +takes_value "read the fields from a JSON or dotenv file")
+          (@arg merge: --merge "keep the fields of the latest version that aren't given")
+          (@arg mount: -m --mount +takes_value default_value("secret") "the KV-v2 mount"))
+        (@subcommand list =>
+          (about: "List the secrets under a path")
+          (@arg path: +takes_value default_value("") "path to list, relative to the mount")
+          (@arg mount: -m --mount +takes_value default_value("secret") "the KV-v2 mount")))
+      (@subcommand nomad =>
+        (about: "Manage access to the Nomad API of the cluster")
+        (@subcommand token =>
+          (about: "Manage Nomad ACL tokens, with management tokens issued by Vault")
+          (@subcommand create => (about: "create a token and print its secret")
+            (@arg name: +takes_value +required "name of the token")
+            (@arg policy: -p --policy +takes_value +multiple +use_delimiter required_unless_present[management] "policies of the token")
+            (@arg management: --management conflicts_with[policy] "create a management token, which bypasses all policies")
+            (@arg global: --global "replicate the token to all regions")
+            (@arg role: --role +takes_value "vault role issuing the management token that creates it, defaults to management")
+            (@arg json: -j --json "format as json"))
+          (@subcommand list => (about: "list the tokens without their secrets")
+            (@arg role: --role +takes_value "vault role issuing the management token that lists them, defaults to management")
+            (@arg json: -j --json "format as json"))
+          (@subcommand revoke => (about: "revoke a token")
+            (@arg accessor: +takes_value +required "accessor id of the token")
+            (@arg role: --role +takes_value "vault role issuing the management token that revokes it, defaults to management"))
+          (@subcommand self => (about: "show the token of this session")
+            (@arg json: -j --json "format as json"))))
+      (@subcommand events =>
+        (about: "Follow what the Nomad scheduler does as it happens")
+        (@arg topic: -t --topic +takes_value +multiple +use_delimiter "topics to follow, like Deployment,Allocation, defaults to deployments, allocations, evaluations, and jobs")
+        (@arg job: --job +takes_value "only follow the events about this job")
+        (@arg namespace: -n --namespace +takes_value env[NOMAD_NAMESPACE] "only follow the events of this namespace, defaults to all"))
+      (@subcommand drift =>
+        (about: "Compare the nodes with what the flake and terraform declare")
+        (@subcommand images => (about: "list nodes booted from outdated images, which need replacement instead of a rebuild")
+          (@arg images_only: --("images-only") "only compare images, without connecting to the nodes")
+          (@arg json: -j --json "format as json")))
+      (@subcommand campaign =>
+        (about: "Roll changes out to the whole fleet in waves, over days if need be")
+        (@subcommand patch =>
+          (about: "update a flake input and roll it out to every node it changes, with health gates between the waves")
+          (@arg input: -i --input +takes_value +required "flake input to update, like nixpkgs")
+          (@arg waves: -w --waves +takes_value "node counts or percentages of the changed nodes patched after each wave, defaults to 1,10%,50%,100%")
+          (@arg soak: --soak +takes_value "time a wave runs before the next one starts, like 30m or 24h, defaults to 24h")
+          (@arg verify: --verify +takes_value "checks run as health gate after each wave, defaults to verify.json if it exists")
+          (@arg ssm: -s --ssm "transport closures and activate through AWS SSM instead of ssh")
+          (@arg status: --status "only show the patched and pending nodes of the campaign")
+          (@arg restart: --restart conflicts_with[status] "discard the progress of a previous campaign and start over")
+          (@arg yes: -y --yes "start without asking for confirmation")))
+      (@subcommand export =>
+        (about: "Write the facts of the cluster for tools outside of bitte")
+        (@arg format: -f --format +takes_value possible_value[tfvars] default_value("tfvars") "tfvars writes the node IPs, CA serials, and other facts as terraform variables")
+        (@arg out: -o --out +takes_value "file to write to, - for stdout, defaults to terraform.tfvars.json"))
     )
     .subcommand(<Opts as IntoApp>::into_app().name("deploy"))
     .arg(
@@ -83,45 +482,218 @@ async fn main() -> Result<()> {
         .require_delimiter(true)
         .required_if_eq("provider", "AWS")
         .env("AWS_ASG_REGIONS")
+    ).arg(
+        Arg::new("gcp-zones")
+        .about("GCE zones the cluster runs in, all zones of the project if unset")
+        .long("gcp-zones")
+        .value_delimiter(":")
+        .require_delimiter(true)
+        .env("GCP_ZONES")
+    ).arg(
+        Arg::new("azure-resource-group")
+        .about("Azure resource group the cluster runs in, the whole subscription if unset")
+        .long("azure-resource-group")
+        .takes_value(true)
+        .env("AZURE_RESOURCE_GROUP")
+    ).arg(
+        Arg::new("metal-hosts")
+        .about("JSON file listing bare-metal or local nodes like the instances of the terraform state, instead of the state")
+        .long("metal-hosts")
+        .takes_value(true)
+        .env("BITTE_METAL_HOSTS")
+    ).arg(
+        Arg::new("aws-profile")
+        .about("AWS profile whose credentials every AWS call uses, or assumes the role with")
+        .long("aws-profile")
+        .takes_value(true)
+        .env("AWS_PROFILE")
+    ).arg(
+        Arg::new("aws-role-arn")
+        .about("ARN of an AWS role to assume for every AWS call, like one in the account of the cluster")
+        .long("aws-role-arn")
+        .takes_value(true)
+        .env("BITTE_AWS_ROLE_ARN")
+    ).arg(
+        Arg::new("aws-mfa-serial")
+        .about("ARN of the MFA device the role demands, the code is asked for")
+        .long("aws-mfa-serial")
+        .takes_value(true)
+        .requires("aws-role-arn")
+        .env("BITTE_AWS_MFA_SERIAL")
+    ).arg(
+        Arg::new("aws-session-duration")
+        .about("Seconds the credentials of the assumed role are valid")
+        .long("aws-session-duration")
+        .takes_value(true)
+        .default_value("3600")
+        .env("BITTE_AWS_SESSION_DURATION")
     );
 
     let mut help_text = Vec::new();
     app.write_help(&mut help_text)
         .expect("Failed to write help text to buffer");
 
-    let matches = app.get_matches();
+    // a broken config only fails once the arguments are parsed, so --help and --version work
+    let config = BitteConfig::load();
+    let no_aliases = HashMap::new();
+    let aliases = config
+        .as_ref()
+        .map_or(&no_aliases, |config| &config.aliases);
+    let builtins: Vec<&str> = app.get_subcommands().map(|sub| sub.get_name()).collect();
+    let valued = alias::valued_options(&app);
+    let args = alias::expand(aliases, &builtins, &valued, env::args().collect())?;
+    let matches = app.get_matches_from(&args);
+    let config = config?;
+
+    let mut command = Vec::new();
+    let mut leaf = &matches;
+    while let Some((name, sub)) = leaf.subcommand() {
+        command.push(name.to_string());
+        leaf = sub;
+    }
+    config.policy.check(&Action {
+        command,
+        cluster: matches.value_of("name").unwrap_or_default().to_string(),
+        user: env::var("USER").unwrap_or_default(),
+        args: args[alias::command_position(&args, &valued)..].to_vec(),
+    })?;
+
+    budget::configure(&config.budget, matches.value_of("name"));
+    endpoint::configure(&config.addresses, &config.tls);
 
-    let token: Uuid = matches
-        .value_of_t("nomad-token")
-        .with_context(|| "A Nomad token should be a valid UUID")?;
+    // before anything, vault logins included, calls AWS
+    let role = match matches.value_of("aws-role-arn") {
+        Some(role_arn) => Some(AssumeRole {
+            role_arn: role_arn.to_string(),
+            mfa_serial: matches.value_of("aws-mfa-serial").map(str::to_string),
+            duration: matches.value_of_t("aws-session-duration")?,
+        }),
+        None => None,
+    };
+    sts::configure(matches.value_of("aws-profile"), role.as_ref()).await?;
+
+    let domain: String = matches.value_of_t("domain")?;
+    login::export(&endpoint::address(Service::Vault, &domain));
+
+    let cancel = cancel::on_ctrl_c();
+
+    // there is no token to find before logging in
+    if let Some(("login", sub)) = matches.subcommand() {
+        pretty_env_logger::init();
+        return cli::login(sub, &domain, &cancel).await;
+    }
+
+    // without a token Vault is only asked for one by the commands that look at the cluster
+    let token: Option<Uuid> = match matches.value_of("nomad-token") {
+        Some(token) => Some(
+            token
+                .parse()
+                .with_context(|| "A Nomad token should be a valid UUID")?,
+        ),
+        None => None,
+    };
+
+    // long rebuilds and watches outlive the vault token otherwise
+    let _vault = vault::keep_alive(cancel.clone(), config.vault_login.clone());
 
     let run = |init_log: bool| {
         if init_log {
             pretty_env_logger::init()
         };
-        BitteCluster::init(matches.clone(), token)
+        BitteCluster::init(matches.clone(), token, cancel.clone())
     };
 
-    match matches.subcommand() {
-        Some(("rebuild", sub)) => cli::rebuild(sub, run(true)).await,
+    let refresh = || BitteCluster::refresh(matches.clone(), token, cancel.clone());
+
+    let started = Instant::now();
+    let result = match matches.subcommand() {
+        Some(("rebuild", sub)) => cli::rebuild(sub, run(true), &cancel).await,
         Some(("deploy", sub)) => cli::deploy(sub, run(false)).await,
-        Some(("info", sub)) => cli::info(sub, run(true)).await,
+        Some(("info", sub)) => cli::info(sub, run(true), refresh, &cancel).await,
         Some(("ssh", sub)) => cli::ssh(sub, run(true)).await,
-        Some(("terraform", sub)) => cli::terraform(sub, run(true)).await,
+        Some(("terraform", sub)) => cli::terraform(sub, run(true), refresh, &cancel).await,
+        Some(("node", sub)) => cli::node(sub, run(true), &cancel).await,
+        Some(("verify", sub)) => cli::verify(sub, run(true), &cancel).await,
+        Some(("drift", sub)) => cli::drift(sub, run(true)).await,
+        Some(("campaign", sub)) => cli::campaign(sub, run(true), &cancel).await,
+        Some(("export", sub)) => cli::export(sub, run(true)).await,
+        Some(("logs", sub)) => cli::logs(sub, run(true), &cancel).await,
+        Some(("events", sub)) => cli::events(sub, run(true), &cancel).await,
+        Some(("nomad", sub)) => cli::nomad_acl(sub, run(true)).await,
+        Some(("vault", sub)) => cli::vault(sub, run(true), &cancel).await,
+        Some(("backup", sub)) => cli::backup(sub, run(true)).await,
+        Some(("secret", sub)) => cli::secret(sub, run(true)).await,
+        Some(("consul", sub)) => cli::consul(sub, run(true)).await,
+        Some(("operator", sub)) => cli::operator(sub, run(true)).await,
+        Some(("restore", sub)) => cli::restore(sub, run(true)).await,
+        Some(("deployment", sub)) => cli::deployment(sub, run(true), &cancel).await,
         Some(("provision", sub)) => {
             pretty_env_logger::init();
-            cli::provision(sub, matches.value_of_t("name")?).await
+            cli::provision(
+                sub,
+                matches.value_of_t("name")?,
+                matches.value_of_t("domain")?,
+                refresh,
+                &cancel,
+            )
+            .await
+        }
+        Some(("job", sub)) => cli::job(sub, run(true), &cancel).await,
+        Some(("alloc", sub)) => cli::alloc(sub, run(true)).await,
+        Some(("runbook", sub)) => {
+            pretty_env_logger::init();
+            cli::runbook(sub, matches.value_of_t("name")?).await
         }
-        Some(("certs", sub)) => {
+        Some(("runs", sub)) => {
             pretty_env_logger::init();
-            cli::certs(sub).await
+            cli::runs(sub).await
         }
+        Some(("query", sub)) => {
+            pretty_env_logger::init();
+            cli::query(sub).await
+        }
+        Some(("certs", sub)) => match sub.subcommand() {
+            Some(("export-ca", sub_sub)) => cli::certs_export_ca(sub_sub, run(true)).await,
+            Some(("status", sub_sub)) => cli::certs_status(sub_sub, run(true)).await,
+            Some(("rotate", sub_sub)) => cli::certs_rotate(sub_sub, run(true), &cancel).await,
+            Some(("acme", sub_sub)) => cli::certs_acme(sub_sub, run(true)).await,
+            _ => {
+                pretty_env_logger::init();
+                cli::certs(sub).await
+            }
+        },
         _ => {
             bail!(format!(
                 "Invalid subcommand\n {}",
                 String::from_utf8(help_text).expect("help text contains invalid UTF8")
             ))
         }
-    }?;
-    Ok(())
+    };
+
+    // `--detailed-exitcode` reports changes with the exit status 2, like terraform does
+    let has_changes = matches!(
+        result.as_ref().map_err(|e| e.downcast_ref::<Error>()),
+        Err(Some(Error::HasChanges { .. }))
+    );
+
+    if let Some((command, _)) = matches.subcommand() {
+        let cluster = matches.value_of("name");
+        let error = match &result {
+            Err(e) if !has_changes => Some(format!("{:#}", e)),
+            _ => None,
+        };
+        if let Err(e) = db::record_run(cluster, command, started.elapsed(), error) {
+            log::warn!("couldn't record the run in the history database: {:?}", e);
+        }
+    }
+
+    if matches.is_present("profile") {
+        eprintln!("took {:.1}s", started.elapsed().as_secs_f64());
+        budget::table(&budget::usage()).print(&mut std::io::stderr())?;
+    }
+
+    if has_changes {
+        std::process::exit(2);
+    }
+    result
 }
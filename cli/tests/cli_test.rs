@@ -19,6 +19,7 @@ mod with_no_env {
     test!(bitte tf; should fail with "bitte terraform <workspace>");
     test!(bitte tf network; should fail with "Unknown command"); // <- not really but it does.
     test!(bitte tf network plan; should fail with "BITTE_CLUSTER");
+    test!(bitte tf network destroy; should fail with "BITTE_CLUSTER");
 }
 
 mod with_bitte_cluster {
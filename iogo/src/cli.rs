@@ -9,14 +9,13 @@ use anyhow::{anyhow, Context, Result};
 use bitte_lib::{
     consul::consul_token,
     nomad::nomad_token,
+    runs::Run,
     sh,
     types::{
-        CueRender, NomadDeployment, NomadEvaluation, NomadJobPlan, NomadJobPlanDiff,
-        NomadJobPlanField, NomadJobPlanObject, NomadJobPlanType, NomadJobRun, VaultTokenLookup,
+        CueRender, NomadDeployment, NomadEvaluation, NomadJobPlan, NomadJobRun, VaultTokenLookup,
     },
 };
 use clap::ArgMatches;
-use colored::*;
 use hyper::{body::HttpBody, Client};
 use hyper_tls::HttpsConnector;
 use restson::RestClient;
@@ -48,7 +47,7 @@ pub async fn events(sub: &ArgMatches) -> Result<()> {
     let url_str: String = url.into();
     let request = hyper::Request::builder()
         .method("GET")
-        .header("X-Nomad-Token", nomad_token()?)
+        .header("X-Nomad-Token", nomad_token(&cluster_domain()?).await?)
         .uri(url_str.parse::<hyper::Uri>()?)
         .body(hyper::Body::empty())?;
 
@@ -130,16 +129,18 @@ pub(crate) async fn plan(sub: &ArgMatches) -> Result<()> {
     let vault_token: String = vault_token()?;
     env::set_var("VAULT_TOKEN", &vault_token);
 
-    let nomad_token = nomad_token()?;
+    let nomad_token = nomad_token(&cluster_domain()?).await?;
     env::set_var("NOMAD_TOKEN", &nomad_token);
 
-    let consul_token = consul_token()?;
+    let consul_token = consul_token().await?;
     env::set_var("CONSUL_HTTP_TOKEN", &consul_token);
 
-    match job_arg {
-        Ok(job) => plan_job(namespace, job).await,
-        Err(_) => plan_jobs(&namespace).await,
-    }
+    let run = Run::start("plan")?;
+    let result = match job_arg {
+        Ok(job) => plan_job(namespace, job, &run).await,
+        Err(_) => plan_jobs(&namespace, &run).await,
+    };
+    run.finish(result)
 }
 
 #[derive(Debug, serde::Deserialize)]
@@ -147,21 +148,21 @@ struct CueExport {
     rendered: HashMap<String, HashMap<String, serde_json::Value>>,
 }
 
-async fn plan_jobs(namespace: &str) -> Result<()> {
+async fn plan_jobs(namespace: &str, run: &Run) -> Result<()> {
     let output = sh(execute::command_args!("cue", "export"))?;
     let export: CueExport =
         serde_json::from_str(output.as_str()).with_context(|| "Couldn't parse CUE export")?;
 
     if let Some(n) = export.rendered.get(namespace) {
         for job in n.keys() {
-            plan_job(namespace.to_string(), job.to_string()).await?;
+            plan_job(namespace.to_string(), job.to_string(), run).await?;
         }
     }
 
     Ok(())
 }
 
-async fn plan_job(namespace: String, job: String) -> Result<()> {
+async fn plan_job(namespace: String, job: String, run: &Run) -> Result<()> {
     let output = sh(execute::command_args!(
         "cue",
         "-t",
@@ -170,6 +171,7 @@ async fn plan_job(namespace: String, job: String) -> Result<()> {
         format!("job={}", job),
         "render"
     ))?;
+    run.write(&format!("{}-{}.json", namespace, job), &output)?;
 
     let mut render: CueRender =
         serde_json::from_str(output.as_str()).with_context(|| "couldn't parse CUE render")?;
@@ -199,7 +201,7 @@ async fn plan_job(namespace: String, job: String) -> Result<()> {
 fn execute_plan(client: &mut RestClient, render: &mut CueRender, plan: NomadJobPlan) -> Result<()> {
     println!("Running this job will make following changes:");
 
-    diff(plan.diff);
+    plan.diff.display();
 
     println!("The job modify index is: {}", plan.job_modify_index);
 
@@ -263,102 +265,6 @@ fn ask_for_consent() -> Result<()> {
     Ok(())
 }
 
-fn diff(diff: NomadJobPlanDiff) {
-    println!("{}:", diff.id);
-    diff_fields(2, &diff.fields);
-    diff_objects(2, &diff.objects);
-
-    for task_group in diff.task_groups {
-        println!("{:>2} {}:", "", task_group.name);
-        diff_objects(2, &task_group.objects);
-        diff_fields(2, &task_group.fields);
-        diff_objects(2, &task_group.tasks);
-    }
-}
-
-fn diff_field(indent: usize, field: &NomadJobPlanField) {
-    print_annotations(indent + 4, &field.annotations);
-
-    let old = match redact(field.name.as_str(), field.old.as_str()) {
-        "" => "null",
-        o => o,
-    };
-
-    let new = match redact(field.name.as_str(), field.new.as_str()) {
-        "" => "null",
-        o => o,
-    };
-
-    match field.field_type {
-        NomadJobPlanType::Added => println!(
-            "{:>width$} {}: {}",
-            "+".green(),
-            field.name,
-            new.green(),
-            width = indent
-        ),
-        NomadJobPlanType::Deleted => println!(
-            "{:>width$} {}: {}",
-            "-".red(),
-            field.name,
-            old.red(),
-            width = indent
-        ),
-        NomadJobPlanType::Edited => println!(
-            "{:>width$} {}: {} -> {}",
-            "~".yellow(),
-            field.name,
-            old.red(),
-            new.green(),
-            width = indent
-        ),
-        NomadJobPlanType::None => (),
-    }
-}
-
-fn redact<'a>(name: &str, value: &'a str) -> &'a str {
-    match name {
-        "ConsulToken" => "<redacted>",
-        _ => value,
-    }
-}
-
-fn diff_fields(indent: usize, fields: &Option<Vec<NomadJobPlanField>>) {
-    if let Some(fields) = fields {
-        for field in fields {
-            diff_field(indent + 2, field)
-        }
-    }
-}
-
-fn diff_object(indent: usize, obj: &NomadJobPlanObject) {
-    diff_fields(indent + 2, &obj.fields);
-    diff_objects(indent + 2, &obj.objects);
-}
-
-fn diff_objects(indent: usize, objects: &Option<Vec<NomadJobPlanObject>>) {
-    if let Some(objects) = objects {
-        for obj in objects {
-            println!("{:>width$} {}:", "", obj.name, width = indent + 2);
-            print_annotations(indent + 6, &obj.annotations);
-            diff_object(indent + 2, &obj);
-        }
-    }
-}
-
-fn print_annotations(indent: usize, annotations: &Option<Vec<String>>) {
-    if let Some(annotations) = annotations {
-        for annotation in annotations {
-            println!(
-                "{:>width$} {}",
-                "!".purple(),
-                annotation.purple(),
-                width = indent
-            )
-        }
-    }
-}
-
 fn nomad_client() -> Result<RestClient> {
     let nomad_addr = env::var("NOMAD_ADDR")?;
     let mut client = RestClient::new(&nomad_addr)?;
@@ -369,6 +275,13 @@ fn nomad_client() -> Result<RestClient> {
     Ok(client)
 }
 
+/// The domain of the cluster at `NOMAD_ADDR`, Nomad tokens are cached by it.
+fn cluster_domain() -> Result<String> {
+    let nomad_addr = Url::parse(&env::var("NOMAD_ADDR")?)?;
+    let host = nomad_addr.host_str().context("NOMAD_ADDR names no host")?;
+    Ok(host.trim_start_matches("nomad.").to_string())
+}
+
 fn vault_token() -> Result<String> {
     if let Ok(token) = lookup_current_vault_token(false) {
         return Ok(token);
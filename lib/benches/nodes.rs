@@ -0,0 +1,57 @@
+use aws_sdk_ec2::model::{Instance, InstanceType, Placement, Tag};
+use bitte_lib::types::BitteNode;
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+
+fn instances(count: usize) -> Vec<Instance> {
+    (0..count)
+        .map(|i| {
+            let tags = vec![
+                ("Cluster", "bench".to_string()),
+                ("Name", format!("client-{}", i)),
+                ("UID", format!("bench-client-{}", i)),
+                (
+                    "aws:autoscaling:groupName",
+                    "client-eu-central-1-t3a-medium".into(),
+                ),
+                ("Terraform", "clients".to_string()),
+            ]
+            .into_iter()
+            .map(|(key, value)| Tag::builder().key(key).value(value).build())
+            .collect();
+            Instance::builder()
+                .instance_id(format!("i-{:017x}", i))
+                .instance_type(InstanceType::T3aMedium)
+                .private_ip_address(format!("10.0.{}.{}", i / 256 % 256, i % 256))
+                .public_ip_address(format!("3.120.{}.{}", i / 256 % 256, i % 256))
+                .placement(
+                    Placement::builder()
+                        .availability_zone("eu-central-1a")
+                        .build(),
+                )
+                .set_tags(Some(tags))
+                .build()
+        })
+        .collect()
+}
+
+fn from_instance(c: &mut Criterion) {
+    for count in [100, 10_000].iter() {
+        c.bench_function(&format!("BitteNode::from {} instances", count), |b| {
+            b.iter_batched(
+                || instances(*count),
+                |instances| {
+                    black_box(
+                        instances
+                            .into_iter()
+                            .map(BitteNode::from)
+                            .collect::<Vec<_>>(),
+                    )
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+}
+
+criterion_group!(benches, from_instance);
+criterion_main!(benches);
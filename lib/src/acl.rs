@@ -0,0 +1,205 @@
+use std::{
+    fs,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    budget::{self, Provider},
+    endpoint::{Service, TlsFiles},
+    nomad::NomadApi,
+    pool::ClientPool,
+    types::BitteCluster,
+    vault::{self, Secret},
+};
+
+/// Vault roles of the Nomad secrets engine tried for the token of a session, most privileged
+/// first.
+pub const SESSION_ROLES: &[&str] = &["admin", "developer"];
+
+/// Vault role issuing the management tokens that create and revoke other tokens.
+pub const MANAGEMENT_ROLE: &str = "management";
+
+/// Cached tokens are issued again once they expire within this many seconds.
+const RENEW_MARGIN: u64 = 5 * 60;
+
+/// A Nomad token issued by Vault, cached so every invocation doesn't lease a new one.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct IssuedToken {
+    pub role: String,
+    pub accessor_id: String,
+    pub secret_id: String,
+    pub lease_id: String,
+    pub expires: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct VaultCredsData {
+    accessor_id: String,
+    secret_id: String,
+}
+
+fn now() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+/// Tokens are cached per cluster, by its domain.
+fn cache_file(domain: &str, role: &str) -> Result<PathBuf> {
+    let domain = domain.replace(|c: char| !c.is_ascii_alphanumeric() && c != '.', "_");
+
+    let dir = PathBuf::from(crate::cache_root()?)
+        .join("bitte")
+        .join("nomad-tokens");
+    fs::create_dir_all(&dir)?;
+    Ok(dir.join(format!("{}-{}.json", domain, role)))
+}
+
+fn cached(domain: &str, role: &str) -> Option<IssuedToken> {
+    let file = fs::read_to_string(cache_file(domain, role).ok()?).ok()?;
+    let token: IssuedToken = serde_json::from_str(&file).ok()?;
+    match now() {
+        Ok(now) if token.expires > now + RENEW_MARGIN => Some(token),
+        _ => None,
+    }
+}
+
+/// Lease a token from the Nomad secrets engine of Vault for `role` in the cluster at `domain`,
+/// and cache it where only the user can read it.
+pub async fn issue(domain: &str, role: &str) -> Result<IssuedToken> {
+    let creds: Secret<VaultCredsData> = vault::read(&format!("nomad/creds/{}", role)).await?;
+
+    let token = IssuedToken {
+        role: role.to_string(),
+        accessor_id: creds.data.accessor_id,
+        secret_id: creds.data.secret_id,
+        lease_id: creds.lease_id,
+        expires: now()? + creds.lease_duration,
+    };
+
+    crate::write_private(
+        &cache_file(domain, role)?,
+        serde_json::to_string_pretty(&token)?,
+    )?;
+    info!("vault issued a nomad {} token, {}", role, token.accessor_id);
+
+    Ok(token)
+}
+
+/// The secret of a token for `role`, from the cache while it is valid, otherwise from Vault.
+pub async fn role_token(domain: &str, role: &str) -> Result<String> {
+    match cached(domain, role) {
+        Some(token) => {
+            debug!(
+                "using the cached nomad {} token {}",
+                role, token.accessor_id
+            );
+            Ok(token.secret_id)
+        }
+        None => Ok(issue(domain, role).await?.secret_id),
+    }
+}
+
+/// The token of a session: the most privileged one Vault issues to the user.
+pub async fn session_token(domain: &str) -> Result<String> {
+    let mut last = None;
+    for role in SESSION_ROLES {
+        match role_token(domain, role).await {
+            Ok(token) => return Ok(token),
+            Err(e) => last = Some(e),
+        }
+    }
+    Err(last.expect("there are session roles")).context("vault issued no nomad token")
+}
+
+/// The Nomad API of `cluster`, authenticated with a management token Vault issues for `role`.
+pub async fn management_api(cluster: &BitteCluster, role: &str) -> Result<NomadApi> {
+    let token = role_token(&cluster.domain, role).await?;
+    NomadApi::new(
+        &ClientPool::default(),
+        &cluster.nomad_address(),
+        &token,
+        &TlsFiles::from_env(Service::Nomad),
+    )
+}
+
+/// A token as listed by `/v1/acl/tokens`, without its secret.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct TokenStub {
+    #[serde(rename = "AccessorID")]
+    pub accessor_id: String,
+    pub name: String,
+    #[serde(rename = "Type")]
+    pub token_type: String,
+    #[serde(default)]
+    pub policies: Option<Vec<String>>,
+    pub global: bool,
+    pub create_time: String,
+}
+
+/// A token as returned by `/v1/acl/token/:accessor`, including its secret.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Token {
+    #[serde(flatten)]
+    pub stub: TokenStub,
+    #[serde(rename = "SecretID")]
+    pub secret_id: String,
+}
+
+pub async fn list(nomad: &NomadApi) -> Result<Vec<TokenStub>> {
+    nomad.get("/v1/acl/tokens", &[]).await
+}
+
+pub async fn token_self(nomad: &NomadApi) -> Result<Token> {
+    nomad.get("/v1/acl/token/self", &[]).await
+}
+
+/// Create a `client` token with `policies`, or a `management` token if there are none.
+pub async fn create(
+    nomad: &NomadApi,
+    name: &str,
+    policies: &[String],
+    global: bool,
+) -> Result<Token> {
+    let token_type = if policies.is_empty() {
+        "management"
+    } else {
+        "client"
+    };
+    nomad
+        .post(
+            "/v1/acl/token",
+            &[],
+            &json!({
+                "Name": name,
+                "Type": token_type,
+                "Policies": policies,
+                "Global": global,
+            }),
+        )
+        .await
+        .with_context(|| format!("couldn't create the token {}", name))
+}
+
+/// Revoke the token with `accessor_id`. Nomad answers with an empty body, so this doesn't go
+/// through `NomadApi::delete`.
+pub async fn revoke(nomad: &NomadApi, accessor_id: &str) -> Result<()> {
+    let url = nomad.url(&format!("/v1/acl/token/{}", accessor_id));
+    budget::acquire(Provider::Nomad).await?;
+    nomad
+        .client
+        .delete(&url)
+        .send()
+        .await
+        .with_context(|| format!("failed to delete: {}", &url))?
+        .error_for_status()
+        .with_context(|| format!("couldn't revoke the token {}", accessor_id))?;
+    Ok(())
+}
@@ -0,0 +1,255 @@
+use std::{
+    fs,
+    net::IpAddr,
+    os::unix::fs::PermissionsExt,
+    path::PathBuf,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{certs, secret, sh, ssh};
+
+/// The public endpoints of a cluster, as prefixes of its domain.
+pub const DEFAULT_NAMES: [&str; 4] = ["nomad", "consul", "vault", "monitoring"];
+
+pub const LETS_ENCRYPT: &str = "https://acme-v02.api.letsencrypt.org/directory";
+pub const LETS_ENCRYPT_STAGING: &str = "https://acme-staging-v02.api.letsencrypt.org/directory";
+
+/// Where the edge nodes expect the public certificate, the layout of NixOS `security.acme`.
+const EDGE_DIR: &str = "/var/lib/acme";
+
+/// Settings of `bitte certs acme`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct AcmeConfig {
+    /// The contact of the ACME account, told about expiring certificates.
+    pub email: Option<String>,
+    /// Prefixes of the domain to issue for, the public endpoints by default.
+    pub names: Vec<String>,
+    /// The ACME directory, Let's Encrypt by default.
+    pub directory: Option<String>,
+}
+
+impl AcmeConfig {
+    /// The names to issue for under `domain`, the first one being the subject.
+    pub fn names(&self, domain: &str) -> Vec<String> {
+        let prefixes: Vec<&str> = if self.names.is_empty() {
+            DEFAULT_NAMES.to_vec()
+        } else {
+            self.names.iter().map(String::as_str).collect()
+        };
+        prefixes
+            .iter()
+            .map(|prefix| format!("{}.{}", prefix, domain))
+            .collect()
+    }
+}
+
+/// A publicly trusted certificate, as kept in Vault.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PublicCert {
+    pub names: Vec<String>,
+    /// The certificate followed by its issuers.
+    pub fullchain: String,
+    pub key: String,
+    /// Seconds since the epoch.
+    pub expires: u64,
+    /// The ACME directory that issued the certificate, to tell a staging one apart.
+    #[serde(default)]
+    pub directory: Option<String>,
+}
+
+impl PublicCert {
+    /// Whether the certificate is due for renewal `renew_days` before it expires, isn't issued
+    /// for exactly `names` anymore, or wasn't issued by `directory`, like a staging certificate
+    /// once the production one is asked for.
+    pub fn needs_renewal(
+        &self,
+        names: &[String],
+        directory: &str,
+        renew_days: u64,
+        now: u64,
+    ) -> bool {
+        self.expires <= now + renew_days * 86_400
+            || self.directory.as_deref() != Some(directory)
+            || names.iter().any(|name| !self.names.contains(name))
+            || self.names.iter().any(|name| !names.contains(name))
+    }
+}
+
+/// The names to issue for: the `configured` ones, those the certificate in Vault was issued
+/// for so names added with `--san` stay, and the ones in `add`, without those in `drop`.
+pub fn names(
+    configured: Vec<String>,
+    stored: Option<&PublicCert>,
+    add: &[String],
+    drop: &[String],
+) -> Vec<String> {
+    let mut names = configured;
+    let extra = stored.map(|cert| cert.names.iter()).into_iter().flatten();
+    for name in extra.chain(add.iter()) {
+        if !names.contains(name) {
+            names.push(name.clone());
+        }
+    }
+    names.retain(|name| !drop.contains(name));
+    names
+}
+
+/// Where the certificate of the cluster at `domain` is kept in Vault.
+fn vault_path(domain: &str) -> String {
+    format!("bitte/acme/{}", domain)
+}
+
+pub async fn stored(domain: &str) -> Result<Option<PublicCert>> {
+    match secret::find(secret::DEFAULT_MOUNT, &vault_path(domain)).await? {
+        Some(data) => Ok(Some(serde_json::from_value(Value::Object(data))?)),
+        None => Ok(None),
+    }
+}
+
+pub async fn store(domain: &str, cert: &PublicCert) -> Result<()> {
+    let data = serde_json::from_value(json!(cert))?;
+    secret::put(secret::DEFAULT_MOUNT, &vault_path(domain), &data).await?;
+    Ok(())
+}
+
+/// Where lego keeps the ACME account of the cluster, so it is registered only once.
+fn account_dir(cluster: &str) -> Result<PathBuf> {
+    let dir = PathBuf::from(crate::cache_root()?)
+        .join("bitte")
+        .join("acme")
+        .join(cluster);
+    fs::create_dir_all(&dir)?;
+    fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))?;
+    Ok(dir)
+}
+
+/// Issue a certificate for `names` with lego, answering the DNS-01 challenges in Route53 with
+/// the AWS credentials of the environment.
+pub fn issue(cluster: &str, names: &[String], email: &str, directory: &str) -> Result<PublicCert> {
+    let dir = account_dir(cluster)?;
+    let mut cmd = Command::new("lego");
+    cmd.args(&["--accept-tos", "--dns", "route53", "--email", email])
+        .arg("--server")
+        .arg(directory)
+        .arg("--path")
+        .arg(&dir);
+    for name in names.iter() {
+        cmd.arg("--domains").arg(name);
+    }
+    cmd.arg("run");
+    sh(cmd).context("lego couldn't issue the certificate, is it installed?")?;
+
+    // lego names the files after the first domain, with `_` for a wildcard
+    let certificates = dir.join("certificates");
+    let base = names[0].replace('*', "_");
+    let fullchain = fs::read_to_string(certificates.join(format!("{}.crt", base)))?;
+    let key = fs::read_to_string(certificates.join(format!("{}.key", base)))?;
+    // the key is in Vault from here on
+    fs::remove_dir_all(&certificates)?;
+
+    let expires = certs::expires(&fullchain)?;
+    Ok(PublicCert {
+        names: names.to_vec(),
+        fullchain,
+        key,
+        expires,
+        directory: Some(directory.to_string()),
+    })
+}
+
+/// Whether the certificate in Vault has to be replaced by one from `directory`.
+pub fn due(
+    stored: Option<&PublicCert>,
+    names: &[String],
+    directory: &str,
+    renew_days: u64,
+) -> Result<bool> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    Ok(match stored {
+        Some(cert) => cert.needs_renewal(names, directory, renew_days, now),
+        None => true,
+    })
+}
+
+/// Write `cert` to the edge node at `ip` and have `unit` pick it up.
+pub fn deploy(
+    cluster: &str,
+    ip: IpAddr,
+    domain: &str,
+    cert: &PublicCert,
+    unit: &str,
+) -> Result<()> {
+    if cert.fullchain.trim().is_empty() || cert.key.trim().is_empty() {
+        bail!("the certificate of {} is incomplete", domain);
+    }
+    let dir = format!("{}/{}", EDGE_DIR, domain);
+    ssh::remote(cluster, ip, &format!("mkdir -p {}", dir), "")?;
+    ssh::remote(
+        cluster,
+        ip,
+        &format!("cat > {}/fullchain.pem", dir),
+        &cert.fullchain,
+    )?;
+    ssh::remote(
+        cluster,
+        ip,
+        &format!("umask 077 && cat > {}/key.pem", dir),
+        &cert.key,
+    )?;
+    ssh::remote(
+        cluster,
+        ip,
+        &format!("systemctl try-reload-or-restart {}", unit),
+        "",
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{names, AcmeConfig, PublicCert, LETS_ENCRYPT, LETS_ENCRYPT_STAGING};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_renewal() {
+        let config = AcmeConfig::default();
+        let configured = config.names("cvn.example.com");
+        assert_eq!(configured[0], "nomad.cvn.example.com");
+        assert_eq!(configured.len(), 4);
+
+        let day = 86_400;
+        let cert = PublicCert {
+            names: configured.clone(),
+            fullchain: String::new(),
+            key: String::new(),
+            expires: 60 * day,
+            directory: Some(LETS_ENCRYPT.to_string()),
+        };
+        assert!(!cert.needs_renewal(&configured, LETS_ENCRYPT, 30, 0));
+        assert!(cert.needs_renewal(&configured, LETS_ENCRYPT, 30, 31 * day));
+
+        // a staging certificate, or one from before the directory was recorded
+        assert!(cert.needs_renewal(&configured, LETS_ENCRYPT_STAGING, 30, 0));
+        let unknown = PublicCert {
+            directory: None,
+            ..cert.clone()
+        };
+        assert!(unknown.needs_renewal(&configured, LETS_ENCRYPT, 30, 0));
+
+        let more = vec!["grafana.cvn.example.com".to_string()];
+        assert!(cert.needs_renewal(&more, LETS_ENCRYPT, 30, 0));
+
+        let dropped = vec!["vault.cvn.example.com".to_string()];
+        let kept = names(vec![], Some(&cert), &more, &dropped);
+        assert_eq!(kept.len(), 4);
+        assert_eq!(kept[3], "grafana.cvn.example.com");
+        assert!(!kept.contains(&dropped[0]));
+        assert!(cert.needs_renewal(&kept, LETS_ENCRYPT, 30, 0));
+    }
+}
@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Context, Result};
+use clap::{App, ArgSettings};
+use regex::Regex;
+
+/// The global options of `app` that take a value, as written on the command line, like
+/// `--cluster` and `-c`.
+pub fn valued_options(app: &App) -> Vec<String> {
+    app.get_arguments()
+        .filter(|arg| arg.is_set(ArgSettings::TakesValue))
+        .flat_map(|arg| {
+            let long = arg.get_long().map(|long| format!("--{}", long));
+            let short = arg.get_short().map(|short| format!("-{}", short));
+            long.into_iter().chain(short)
+        })
+        .collect()
+}
+
+/// Index of the subcommand in `args`, after the program name and the global options. Only the
+/// `valued` options are followed by their value, unless it is attached like `--cluster=testnet`
+/// or `-ctestnet`.
+pub fn command_position(args: &[String], valued: &[String]) -> usize {
+    let mut position = 1;
+    while let Some(arg) = args.get(position) {
+        if !arg.starts_with('-') {
+            break;
+        }
+        position += if valued.contains(arg) { 2 } else { 1 };
+    }
+    position
+}
+
+/// Expand a user-defined alias from the `aliases` section of `bitte.json` in `args`.
+///
+/// The argument at the `command_position` names the subcommand. If it is an alias and not one
+/// of `builtins`, it is replaced with the words of the alias. Placeholders like `{cmd}` are
+/// filled in order of appearance with the arguments following the alias name, the remaining
+/// arguments are appended. Aliases aren't expanded recursively.
+pub fn expand(
+    aliases: &HashMap<String, String>,
+    builtins: &[&str],
+    valued: &[String],
+    args: Vec<String>,
+) -> Result<Vec<String>> {
+    let position = command_position(&args, valued);
+    let name = match args.get(position) {
+        Some(name) if !builtins.contains(&name.as_str()) => name,
+        _ => return Ok(args),
+    };
+    let alias = match aliases.get(name) {
+        Some(alias) => alias,
+        None => return Ok(args),
+    };
+
+    let words = shell_words::split(alias).with_context(|| format!("invalid alias {}", name))?;
+
+    let placeholder = Regex::new(r"\{([A-Za-z0-9_-]+)\}").expect("invalid placeholder pattern");
+    let mut placeholders: Vec<String> = Vec::new();
+    for captures in words
+        .iter()
+        .flat_map(|word| placeholder.captures_iter(word))
+    {
+        if !placeholders.contains(&captures[1].to_string()) {
+            placeholders.push(captures[1].to_string());
+        }
+    }
+
+    let mut rest = args[position + 1..].iter();
+    let mut values: HashMap<&str, &str> = HashMap::new();
+    for placeholder in placeholders.iter() {
+        match rest.next() {
+            Some(value) => values.insert(placeholder, value),
+            None => bail!(
+                "alias {} needs a value for each of {:?}",
+                name,
+                placeholders
+            ),
+        };
+    }
+
+    let expanded = words.iter().map(|word| {
+        placeholder
+            .replace_all(word, |captures: &regex::Captures| {
+                values[&captures[1]].to_string()
+            })
+            .to_string()
+    });
+
+    Ok(args[..position]
+        .iter()
+        .cloned()
+        .chain(expanded)
+        .chain(rest.cloned())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{command_position, expand};
+    use pretty_assertions::assert_eq;
+    use std::collections::HashMap;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split(' ').map(String::from).collect()
+    }
+
+    fn valued() -> Vec<String> {
+        args("--cluster -c")
+    }
+
+    #[test]
+    fn test_expand() {
+        let mut aliases = HashMap::new();
+        aliases.insert(
+            "web".to_string(),
+            "ssh --clients --parallel '{cmd}'".to_string(),
+        );
+        aliases.insert("info".to_string(), "ssh".to_string());
+
+        assert_eq!(
+            expand(
+                &aliases,
+                &["info"],
+                &valued(),
+                args("bitte --cluster testnet web uptime -v")
+            )
+            .unwrap(),
+            args("bitte --cluster testnet ssh --clients --parallel uptime -v")
+        );
+        assert_eq!(
+            expand(
+                &aliases,
+                &["info"],
+                &valued(),
+                args("bitte --cluster=testnet info")
+            )
+            .unwrap(),
+            args("bitte --cluster=testnet info")
+        );
+        assert!(expand(&aliases, &["info"], &valued(), args("bitte web")).is_err());
+    }
+
+    #[test]
+    fn test_command_position() {
+        assert_eq!(
+            command_position(&args("bitte --profile --cluster testnet web"), &valued()),
+            4
+        );
+        assert_eq!(
+            command_position(&args("bitte --cluster=testnet --profile web"), &valued()),
+            3
+        );
+        assert_eq!(command_position(&args("bitte -ctestnet web"), &valued()), 2);
+        assert_eq!(
+            command_position(&args("bitte -c testnet web"), &valued()),
+            3
+        );
+    }
+}
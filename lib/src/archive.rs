@@ -0,0 +1,202 @@
+use std::{collections::BTreeMap, io::Write};
+
+use anyhow::{bail, Context, Result};
+use flate2::{write::GzEncoder, Compression};
+use log::{info, warn};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+    budget::{self, Provider},
+    nomad::NomadApi,
+    runs::Run,
+    sh,
+    types::BitteCluster,
+};
+
+/// How much of the end of each log is kept.
+const LOG_TAIL_BYTES: u64 = 64 * 1024;
+
+/// What is left of a failed allocation after Nomad collected it: its task events and the end
+/// of its logs.
+#[derive(Debug, Serialize)]
+pub struct AllocArchive {
+    pub id: String,
+    pub name: String,
+    pub node_id: String,
+    pub client_status: String,
+    pub tasks: BTreeMap<String, TaskArchive>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TaskArchive {
+    pub state: String,
+    pub failed: bool,
+    pub events: Value,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// The bucket and region of an S3 URL like `s3://bucket?region=eu-central-1&...`.
+pub fn s3_bucket(url: &str) -> Result<(String, Option<String>)> {
+    let rest = match url.strip_prefix("s3://") {
+        Some(rest) => rest,
+        None => bail!("{} is not an S3 URL", url),
+    };
+    let (bucket, query) = match rest.split_once('?') {
+        Some((bucket, query)) => (bucket, query),
+        None => (rest, ""),
+    };
+    let region = query
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("region="))
+        .map(String::from);
+
+    Ok((bucket.trim_end_matches('/').to_string(), region))
+}
+
+fn is_failed(alloc: &Value) -> bool {
+    alloc["ClientStatus"] == "failed"
+        || alloc["DeploymentStatus"]["Healthy"] == false
+        || alloc["TaskStates"].as_object().map_or(false, |tasks| {
+            tasks.values().any(|task| task["Failed"] == true)
+        })
+}
+
+async fn log_tail(nomad: &NomadApi, alloc_id: &str, task: &str, kind: &str) -> String {
+    let url = nomad.url(&format!("/v1/client/fs/logs/{}", alloc_id));
+    let offset = LOG_TAIL_BYTES.to_string();
+    if let Err(e) = budget::acquire(Provider::Nomad).await {
+        return format!("<{} unavailable: {}>", kind, e);
+    }
+    let response = nomad
+        .client
+        .get(&url)
+        .query(&[
+            ("task", task),
+            ("type", kind),
+            ("origin", "end"),
+            ("offset", offset.as_str()),
+            ("plain", "true"),
+        ])
+        .send()
+        .await
+        .and_then(|response| response.error_for_status());
+
+    let bytes = match response {
+        Ok(response) => response.bytes().await,
+        Err(e) => Err(e),
+    };
+
+    // the node may be gone already, the events are still worth archiving
+    match bytes {
+        Ok(bytes) => crate::text::decode(&bytes).text,
+        Err(e) => format!("<{} unavailable: {}>", kind, e),
+    }
+}
+
+/// Collect the task events and the end of the logs of the failed allocations of a deployment.
+pub async fn collect(nomad: &NomadApi, deployment_id: &str) -> Result<Vec<AllocArchive>> {
+    let allocs: Vec<Value> = nomad
+        .get(
+            &format!("/v1/deployment/allocations/{}", deployment_id),
+            &[],
+        )
+        .await?;
+
+    let mut archives = Vec::new();
+    for alloc in allocs.iter().filter(|alloc| is_failed(alloc)) {
+        let id = alloc["ID"].as_str().unwrap_or_default().to_string();
+        let mut tasks = BTreeMap::new();
+
+        if let Some(states) = alloc["TaskStates"].as_object() {
+            for (task, state) in states.iter() {
+                tasks.insert(
+                    task.clone(),
+                    TaskArchive {
+                        state: state["State"].as_str().unwrap_or_default().to_string(),
+                        failed: state["Failed"] == true,
+                        events: state["Events"].clone(),
+                        stdout: log_tail(nomad, &id, task, "stdout").await,
+                        stderr: log_tail(nomad, &id, task, "stderr").await,
+                    },
+                );
+            }
+        }
+
+        archives.push(AllocArchive {
+            name: alloc["Name"].as_str().unwrap_or_default().to_string(),
+            node_id: alloc["NodeID"].as_str().unwrap_or_default().to_string(),
+            client_status: alloc["ClientStatus"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            id,
+            tasks,
+        });
+    }
+
+    Ok(archives)
+}
+
+/// Archive the failed allocations of a deployment in the run directory, and upload the archive
+/// to the S3 bucket of the cluster under the id of the run. Returns the S3 URL.
+pub async fn archive_failure(
+    nomad: &NomadApi,
+    cluster: &BitteCluster,
+    deployment_id: &str,
+    run: &Run,
+) -> Result<String> {
+    let archives = collect(nomad, deployment_id).await?;
+    if archives.is_empty() {
+        bail!("deployment {} has no failed allocations", deployment_id);
+    }
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    serde_json::to_writer_pretty(&mut encoder, &archives)?;
+    encoder.flush()?;
+    let name = format!("failed-allocs-{}.json.gz", deployment_id);
+    let path = run.write(&name, encoder.finish()?)?;
+
+    let cache = cluster
+        .terra
+        .as_ref()
+        .and_then(|terra| terra.s3_cache.as_deref())
+        .context("the cluster has no S3 bucket")?;
+    let (bucket, region) = s3_bucket(cache)?;
+    let url = format!(
+        "s3://{}/bitte/failures/{}/{}/{}",
+        bucket, cluster.name, run.id, name
+    );
+
+    let mut cmd = execute::command_args!("aws", "s3", "cp", &path, &url);
+    if let Some(region) = region {
+        cmd.arg("--region").arg(region);
+    }
+    if let Err(e) = sh(cmd) {
+        warn!("the archive is kept at {}", path.display());
+        return Err(e.context(format!("couldn't upload the archive to {}", url)));
+    }
+
+    info!("archived {} failed allocations to {}", archives.len(), url);
+    Ok(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::s3_bucket;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_s3_bucket() {
+        assert_eq!(
+            s3_bucket("s3://iohk-bitte?region=eu-central-1&profile=bitte").unwrap(),
+            ("iohk-bitte".to_string(), Some("eu-central-1".to_string()))
+        );
+        assert_eq!(
+            s3_bucket("s3://cache/").unwrap(),
+            ("cache".to_string(), None)
+        );
+        assert!(s3_bucket("https://cache.nixos.org").is_err());
+    }
+}
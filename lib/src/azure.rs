@@ -0,0 +1,381 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr},
+    process::Command,
+};
+
+use anyhow::{Context, Result};
+use serde::{de::DeserializeOwned, Deserialize};
+
+use crate::{
+    budget::{self, Provider},
+    info::AsgCapacity,
+    sh_async,
+    types::{BitteNode, BitteProvider, TerraformStateAsg},
+};
+
+/// A virtual machine as `az vm list --show-details` describes it, standalone or in a scale set
+/// with flexible orchestration.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AzureVm {
+    pub id: String,
+    pub name: String,
+    pub location: String,
+    #[serde(default)]
+    pub zones: Option<Vec<String>>,
+    pub hardware_profile: Option<AzureHardwareProfile>,
+    /// Comma separated, like the `az` table output.
+    #[serde(default)]
+    pub private_ips: Option<String>,
+    #[serde(default)]
+    pub public_ips: Option<String>,
+    #[serde(default)]
+    pub tags: Option<HashMap<String, String>>,
+    /// `Regular`, `Low`, or `Spot`.
+    pub priority: Option<String>,
+    pub virtual_machine_scale_set: Option<AzureResource>,
+    /// Like `VM running`.
+    pub power_state: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AzureHardwareProfile {
+    pub vm_size: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AzureResource {
+    pub id: String,
+}
+
+/// A virtual machine scale set, as `az vmss list` describes it.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AzureScaleSet {
+    pub name: String,
+    pub resource_group: String,
+    pub location: String,
+    pub sku: AzureSku,
+    #[serde(default)]
+    pub tags: Option<HashMap<String, String>>,
+    /// `Uniform` or `Flexible`, the instances of the latter are listed as virtual machines.
+    pub orchestration_mode: Option<String>,
+    pub virtual_machine_profile: Option<AzureVmProfile>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AzureSku {
+    pub name: String,
+    pub capacity: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AzureVmProfile {
+    pub priority: Option<String>,
+}
+
+/// An instance of a uniform scale set, as `az vmss list-instances` describes it.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AzureScaleSetInstance {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub zones: Option<Vec<String>>,
+    /// `Succeeded` once the instance is up, `Creating`, `Updating`, or `Deleting` before that.
+    pub provisioning_state: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AzureNic {
+    #[serde(default)]
+    ip_configurations: Vec<AzureIpConfiguration>,
+    virtual_machine: Option<AzureResource>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AzureIpConfiguration {
+    #[serde(rename = "privateIPAddress")]
+    private_ip_address: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AzurePublicIp {
+    id: String,
+    ip_address: Option<String>,
+}
+
+/// The last segment of a resource ID, its name.
+fn resource_name(id: &str) -> String {
+    id.rsplit('/').next().unwrap_or_default().to_string()
+}
+
+/// The zone of a node: the location, with the availability zone appended if there is one,
+/// like `westeurope-1`.
+fn zone(location: &str, zones: Option<&Vec<String>>) -> String {
+    match zones.and_then(|zones| zones.first()) {
+        Some(zone) => format!("{}-{}", location, zone),
+        None => location.to_string(),
+    }
+}
+
+fn first_ip(ips: Option<&str>) -> Option<IpAddr> {
+    ips?.split(',').next()?.trim().parse().ok()
+}
+
+fn is_spot(priority: Option<&str>) -> bool {
+    matches!(priority, Some("Spot") | Some("Low"))
+}
+
+impl From<AzureVm> for BitteNode {
+    fn from(vm: AzureVm) -> Self {
+        let mut tags = vm.tags.unwrap_or_default();
+        let priv_ip = first_ip(vm.private_ips.as_deref())
+            .unwrap_or_else(|| IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)));
+
+        Self {
+            id: vm.id,
+            name: vm.name,
+            priv_ip,
+            // machines without a public address are reached through the virtual network
+            pub_ip: first_ip(vm.public_ips.as_deref()).unwrap_or(priv_ip),
+            nixos: tags.remove("UID").unwrap_or_default(),
+            nomad_client: None,
+            node_type: vm.hardware_profile.map(|profile| profile.vm_size),
+            zone: Some(zone(&vm.location, vm.zones.as_ref())),
+            asg: vm
+                .virtual_machine_scale_set
+                .map(|set| resource_name(&set.id)),
+            image: None,
+            lifecycle: if is_spot(vm.priority.as_deref()) {
+                Some("spot".to_string())
+            } else {
+                None
+            },
+            tags,
+            provider: BitteProvider::Azure,
+        }
+    }
+}
+
+/// `az` with `args`, in the subscription it is logged in to.
+pub fn az(args: &[&str]) -> Command {
+    let mut cmd = Command::new("az");
+    cmd.args(args).args(&["--output", "json"]);
+    cmd
+}
+
+async fn list<T: DeserializeOwned>(mut cmd: Command, what: &str) -> Result<T> {
+    cmd.arg("--only-show-errors");
+    budget::acquire(Provider::Azure).await?;
+    let output = sh_async(cmd)
+        .await
+        .with_context(|| format!("couldn't list the {}, is az logged in?", what))?;
+    serde_json::from_str(&output).with_context(|| format!("couldn't parse the {} az listed", what))
+}
+
+fn in_resource_group(mut cmd: Command, resource_group: Option<&str>) -> Command {
+    if let Some(group) = resource_group {
+        cmd.arg("--resource-group").arg(group);
+    }
+    cmd
+}
+
+/// The running virtual machines tagged with the `Cluster`.
+pub async fn vms(cluster: &str, resource_group: Option<&str>) -> Result<Vec<AzureVm>> {
+    let mut cmd = in_resource_group(az(&["vm", "list", "--show-details"]), resource_group);
+    cmd.arg("--query")
+        .arg(format!("[?tags.Cluster=='{}']", cluster));
+    let vms: Vec<AzureVm> = list(cmd, "virtual machines").await?;
+    Ok(vms
+        .into_iter()
+        .filter(|vm| vm.power_state.as_deref() == Some("VM running"))
+        .collect())
+}
+
+/// The scale sets in `resource_group` or the whole subscription.
+pub async fn scale_sets(resource_group: Option<&str>) -> Result<Vec<AzureScaleSet>> {
+    list(
+        in_resource_group(az(&["vmss", "list"]), resource_group),
+        "scale sets",
+    )
+    .await
+}
+
+pub async fn scale_set_instances(set: &AzureScaleSet) -> Result<Vec<AzureScaleSetInstance>> {
+    let cmd = az(&[
+        "vmss",
+        "list-instances",
+        "--resource-group",
+        &set.resource_group,
+        "--name",
+        &set.name,
+    ]);
+    list(cmd, &format!("instances of {}", set.name)).await
+}
+
+/// The nodes of a uniform scale set, with the addresses of their network interfaces. The
+/// instances of flexible scale sets are virtual machines of their own.
+async fn scale_set_nodes(set: &AzureScaleSet) -> Result<Vec<BitteNode>> {
+    let instances = scale_set_instances(set).await?;
+    let nics: Vec<AzureNic> = list(
+        az(&[
+            "vmss",
+            "nic",
+            "list",
+            "--resource-group",
+            &set.resource_group,
+            "--vmss-name",
+            &set.name,
+        ]),
+        &format!("network interfaces of {}", set.name),
+    )
+    .await?;
+    let public_ips: Vec<AzurePublicIp> = list(
+        az(&[
+            "vmss",
+            "list-instance-public-ips",
+            "--resource-group",
+            &set.resource_group,
+            "--name",
+            &set.name,
+        ]),
+        &format!("public addresses of {}", set.name),
+    )
+    .await?;
+
+    let tags = set.tags.clone().unwrap_or_default();
+    let spot = is_spot(
+        set.virtual_machine_profile
+            .as_ref()
+            .and_then(|profile| profile.priority.as_deref()),
+    );
+    let no_ip = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0));
+    let nodes = instances
+        .into_iter()
+        .filter(|instance| instance.provisioning_state.as_deref() == Some("Succeeded"))
+        .map(|instance| {
+            let priv_ip = nics
+                .iter()
+                .filter(|nic| {
+                    nic.virtual_machine
+                        .as_ref()
+                        .map_or(false, |vm| vm.id.eq_ignore_ascii_case(&instance.id))
+                })
+                .flat_map(|nic| nic.ip_configurations.iter())
+                .find_map(|config| config.private_ip_address.as_ref()?.parse().ok())
+                .unwrap_or(no_ip);
+            // the address is a child resource of the instance
+            let prefix = format!("{}/", instance.id.to_lowercase());
+            let pub_ip = public_ips
+                .iter()
+                .filter(|ip| ip.id.to_lowercase().starts_with(&prefix))
+                .find_map(|ip| ip.ip_address.as_ref()?.parse().ok())
+                .unwrap_or(priv_ip);
+            let mut tags = tags.clone();
+            BitteNode {
+                id: instance.id,
+                name: instance.name,
+                priv_ip,
+                pub_ip,
+                nixos: tags.remove("UID").unwrap_or_default(),
+                nomad_client: None,
+                node_type: Some(set.sku.name.clone()),
+                zone: Some(zone(&set.location, instance.zones.as_ref())),
+                asg: Some(set.name.clone()),
+                image: None,
+                lifecycle: if spot { Some("spot".to_string()) } else { None },
+                tags,
+                provider: BitteProvider::Azure,
+            }
+        })
+        .collect();
+    Ok(nodes)
+}
+
+/// The running machines of `cluster`, standalone or in scale sets.
+pub async fn nodes(cluster: &str, resource_group: Option<&str>) -> Result<Vec<BitteNode>> {
+    let mut nodes: Vec<BitteNode> = vms(cluster, resource_group)
+        .await?
+        .into_iter()
+        .map(BitteNode::from)
+        .collect();
+    for set in scale_sets(resource_group).await?.iter() {
+        let tagged = set
+            .tags
+            .as_ref()
+            .and_then(|tags| tags.get("Cluster"))
+            .map_or(false, |tag| tag == cluster);
+        if tagged && set.orchestration_mode.as_deref() != Some("Flexible") {
+            nodes.extend(scale_set_nodes(set).await?);
+        }
+    }
+    Ok(nodes)
+}
+
+/// The capacity of the scale sets `asgs` of the terraform state, the counterpart of
+/// auto-scaling groups. Scale sets scale manually or by autoscale settings, neither of which
+/// sets a minimum and maximum on the scale set itself.
+pub async fn scale_set_capacity(asgs: &HashMap<String, TerraformStateAsg>) -> Vec<AsgCapacity> {
+    let mut capacities = Vec::new();
+    for (name, asg) in asgs.iter() {
+        let mut capacity = AsgCapacity::declared(name, asg);
+        let counted = async {
+            let set: AzureScaleSet = list(
+                az(&["vmss", "show", "--ids", &asg.arn]),
+                &format!("scale set {}", name),
+            )
+            .await?;
+            capacity.desired = set.sku.capacity;
+            for instance in scale_set_instances(&set).await?.iter() {
+                match instance.provisioning_state.as_deref() {
+                    Some("Succeeded") => capacity.in_service += 1,
+                    Some("Creating") | Some("Updating") => capacity.pending += 1,
+                    _ => capacity.other += 1,
+                }
+            }
+            Ok::<_, anyhow::Error>(())
+        }
+        .await;
+        if let Err(e) = counted {
+            capacity.error = Some(format!("{:#}", e));
+        }
+        capacities.push(capacity);
+    }
+    capacities.sort_by(|a, b| a.name.cmp(&b.name));
+    capacities
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AzureVm;
+    use crate::types::BitteNode;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_vm() {
+        let vm: AzureVm = serde_json::from_str(
+            r#"{"id": "/subscriptions/s/resourceGroups/testnet/providers/Microsoft.Compute/virtualMachines/core-1",
+                "name": "core-1", "location": "westeurope", "zones": ["2"],
+                "hardwareProfile": {"vmSize": "Standard_D2s_v3"},
+                "privateIps": "10.0.0.4", "publicIps": "",
+                "tags": {"Cluster": "testnet", "UID": "testnet-core-1"},
+                "priority": "Spot",
+                "virtualMachineScaleSet": {"id": "/subscriptions/s/resourceGroups/testnet/providers/Microsoft.Compute/virtualMachineScaleSets/clients"},
+                "powerState": "VM running"}"#,
+        )
+        .unwrap();
+        let node = BitteNode::from(vm);
+        assert_eq!(node.nixos, "testnet-core-1");
+        assert_eq!(node.pub_ip.to_string(), "10.0.0.4");
+        assert_eq!(node.asg.as_deref(), Some("clients"));
+        assert_eq!(node.lifecycle.as_deref(), Some("spot"));
+        assert_eq!(node.zone.as_deref(), Some("westeurope-2"));
+        assert_eq!(node.region().unwrap(), "westeurope");
+    }
+}
@@ -0,0 +1,262 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+use prettytable::{cell, row, Table};
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+
+/// The APIs whose requests are counted and limited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Provider {
+    Aws,
+    Azure,
+    Gcp,
+    Nomad,
+    Tfc,
+}
+
+impl Provider {
+    pub const ALL: [Provider; 5] = [
+        Provider::Aws,
+        Provider::Azure,
+        Provider::Gcp,
+        Provider::Nomad,
+        Provider::Tfc,
+    ];
+}
+
+impl fmt::Display for Provider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Provider::Aws => "aws",
+            Provider::Azure => "azure",
+            Provider::Gcp => "gcp",
+            Provider::Nomad => "nomad",
+            Provider::Tfc => "tfc",
+        };
+        f.write_str(name)
+    }
+}
+
+/// How many requests one invocation may make to an API.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Limits {
+    /// Requests per second, requests beyond it wait for their turn.
+    pub rate: Option<f64>,
+    /// Requests in total, requests beyond it fail.
+    pub max: Option<u64>,
+}
+
+impl Limits {
+    /// These limits, with the ones `other` sets taking precedence.
+    fn or(self, other: Limits) -> Limits {
+        Limits {
+            rate: other.rate.or(self.rate),
+            max: other.max.or(self.max),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct ProviderLimits {
+    pub aws: Limits,
+    pub azure: Limits,
+    pub gcp: Limits,
+    pub nomad: Limits,
+    pub tfc: Limits,
+}
+
+impl ProviderLimits {
+    fn get(&self, provider: Provider) -> Limits {
+        match provider {
+            Provider::Aws => self.aws,
+            Provider::Azure => self.azure,
+            Provider::Gcp => self.gcp,
+            Provider::Nomad => self.nomad,
+            Provider::Tfc => self.tfc,
+        }
+    }
+}
+
+/// API budget of an invocation, from the `budget` section of `bitte.json`, e.g.
+/// `{ "aws": { "rate": 5 }, "clusters": { "prod": { "aws": { "rate": 2, "max": 500 } } } }`.
+/// Everything is unlimited unless configured.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct BudgetConfig {
+    #[serde(flatten)]
+    pub limits: ProviderLimits,
+    /// Limits of a cluster, taking precedence over the ones above.
+    pub clusters: HashMap<String, ProviderLimits>,
+}
+
+impl BudgetConfig {
+    pub fn limits(&self, cluster: Option<&str>, provider: Provider) -> Limits {
+        let limits = self.limits.get(provider);
+        match cluster.and_then(|cluster| self.clusters.get(cluster)) {
+            Some(overrides) => limits.or(overrides.get(provider)),
+            None => limits,
+        }
+    }
+}
+
+/// Requests made to one API so far.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Usage {
+    pub provider: Option<Provider>,
+    pub requests: u64,
+    /// Time spent waiting for the rate limit.
+    pub waited: Duration,
+    pub limits: Limits,
+    #[serde(skip)]
+    next_slot: Option<Instant>,
+}
+
+impl Usage {
+    /// Count a request, returning how long it has to wait before it may be sent.
+    fn take(&mut self, now: Instant) -> Result<Duration, Error> {
+        if let Some(max) = self.limits.max {
+            if self.requests >= max {
+                return Err(Error::BudgetExhausted {
+                    provider: self.provider.map(|p| p.to_string()).unwrap_or_default(),
+                    max,
+                });
+            }
+        }
+        self.requests += 1;
+
+        let rate = match self.limits.rate {
+            Some(rate) if rate > 0.0 => rate,
+            _ => return Ok(Duration::default()),
+        };
+        let slot = self.next_slot.map_or(now, |next| next.max(now));
+        self.next_slot = Some(slot + Duration::from_secs_f64(1.0 / rate));
+
+        let wait = slot - now;
+        self.waited += wait;
+        Ok(wait)
+    }
+}
+
+/// The budget is shared by every request of the invocation, however its clients were made.
+static BUDGET: Lazy<Mutex<HashMap<Provider, Usage>>> = Lazy::new(Default::default);
+
+fn with_usage<T>(provider: Provider, f: impl FnOnce(&mut Usage) -> T) -> T {
+    let mut budget = BUDGET.lock().expect("API budget poisoned");
+    let usage = budget.entry(provider).or_insert_with(|| Usage {
+        provider: Some(provider),
+        ..Default::default()
+    });
+    f(usage)
+}
+
+/// Apply the limits of `config` for `cluster` to the requests made from now on.
+pub fn configure(config: &BudgetConfig, cluster: Option<&str>) {
+    for provider in Provider::ALL.iter() {
+        let limits = config.limits(cluster, *provider);
+        with_usage(*provider, |usage| usage.limits = limits);
+    }
+}
+
+/// Count a request to `provider`, waiting for the rate limit if needed. Fails once the
+/// invocation made as many requests as it may.
+pub async fn acquire(provider: Provider) -> Result<(), Error> {
+    let wait = with_usage(provider, |usage| usage.take(Instant::now()))?;
+    if wait > Duration::default() {
+        log::debug!("waiting {:?} for the {} rate limit", wait, provider);
+        tokio::time::sleep(wait).await;
+    }
+    Ok(())
+}
+
+/// The requests made to each API so far.
+pub fn usage() -> Vec<Usage> {
+    Provider::ALL
+        .iter()
+        .map(|provider| with_usage(*provider, |usage| usage.clone()))
+        .collect()
+}
+
+pub fn table(usage: &[Usage]) -> Table {
+    let mut table = Table::new();
+    table.add_row(row!["API", "Requests", "Limit", "Rate", "Waited"]);
+    for usage in usage.iter() {
+        table.add_row(row![
+            usage.provider.map(|p| p.to_string()).unwrap_or_default(),
+            usage.requests,
+            usage
+                .limits
+                .max
+                .map(|max| max.to_string())
+                .unwrap_or_default(),
+            usage
+                .limits
+                .rate
+                .map(|rate| format!("{}/s", rate))
+                .unwrap_or_default(),
+            format!("{:.1}s", usage.waited.as_secs_f64())
+        ]);
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BudgetConfig, Limits, Provider, Usage};
+    use pretty_assertions::assert_eq;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_limits() {
+        let config: BudgetConfig = serde_json::from_str(
+            r#"{ "aws": { "rate": 5, "max": 100 },
+                 "clusters": { "prod": { "aws": { "rate": 2 } } } }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.limits(Some("prod"), Provider::Aws),
+            Limits {
+                rate: Some(2.0),
+                max: Some(100)
+            }
+        );
+        assert_eq!(
+            config.limits(Some("dev"), Provider::Aws),
+            Limits {
+                rate: Some(5.0),
+                max: Some(100)
+            }
+        );
+        assert_eq!(config.limits(None, Provider::Nomad), Limits::default());
+    }
+
+    #[test]
+    fn test_take() {
+        let mut usage = Usage {
+            provider: Some(Provider::Aws),
+            limits: Limits {
+                rate: Some(2.0),
+                max: Some(3),
+            },
+            ..Default::default()
+        };
+        let now = Instant::now();
+
+        assert_eq!(usage.take(now).unwrap(), Duration::default());
+        assert_eq!(usage.take(now).unwrap(), Duration::from_millis(500));
+        assert_eq!(usage.take(now).unwrap(), Duration::from_millis(1000));
+        assert!(usage.take(now).is_err());
+        assert_eq!(usage.requests, 3);
+        assert_eq!(usage.waited, Duration::from_millis(1500));
+    }
+}
@@ -0,0 +1,545 @@
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use log::{info, warn};
+use prettytable::{cell, row, Table};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    cancel::{self, CancellationToken},
+    config::BitteConfig,
+    db, executor, history, images, inventory, nix,
+    rebuild::{self, NodeKind},
+    runs::Run,
+    sh,
+    types::{BitteCluster, BitteNode},
+    verify::VerifyConfig,
+};
+
+/// Where a node stands in a patch campaign.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PatchStatus {
+    /// The update doesn't change the system of the node.
+    Unchanged,
+    Pending,
+    Patched,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct NodePatch {
+    pub name: String,
+    pub nixos: String,
+    pub running: Option<String>,
+    pub expected: Option<String>,
+    pub status: PatchStatus,
+    /// The wave the node is rolled out in, counting from 1.
+    pub wave: Option<usize>,
+    pub detail: String,
+}
+
+/// A rollout of an updated flake input to every node whose system it changes, in waves with
+/// health gates between them. Kept in a state file, so the campaign can be resumed over days.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Campaign {
+    pub input: String,
+    pub started: u64,
+    pub locked_before: Option<String>,
+    pub locked_after: Option<String>,
+    /// Nodes in rollout order.
+    pub nodes: Vec<NodePatch>,
+    /// Number of nodes patched after each wave, the last one covers all changed nodes.
+    pub waves: Vec<usize>,
+    pub waves_done: usize,
+    pub last_wave_at: Option<u64>,
+}
+
+/// What an invocation of the campaign got to.
+#[derive(Debug, PartialEq)]
+pub enum Progress {
+    /// Every wave passed its health gate.
+    Done,
+    /// The last wave has to soak this much longer before the next one starts.
+    Soaking(Duration),
+}
+
+fn now() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+fn campaigns_dir() -> Result<PathBuf> {
+    let dir = PathBuf::from(crate::cache_root()?)
+        .join("bitte")
+        .join("campaigns");
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+fn state_file(cluster: &str, input: &str) -> Result<PathBuf> {
+    Ok(campaigns_dir()?.join(format!("{}-patch-{}.json", cluster, input)))
+}
+
+/// Cumulative node counts of the waves in `spec`, like `1,10%,50%,100%` for one canary, then
+/// a tenth, half, and all of `total` nodes. Waves that wouldn't add a node are dropped, and a
+/// last wave is added if `spec` doesn't reach every node.
+pub fn parse_waves(spec: &str, total: usize) -> Result<Vec<usize>> {
+    let mut waves: Vec<usize> = Vec::new();
+
+    for wave in spec.split(',').map(str::trim).filter(|w| !w.is_empty()) {
+        let count = match wave.strip_suffix('%') {
+            Some(percent) => {
+                let percent: f64 = percent
+                    .parse()
+                    .with_context(|| format!("invalid wave {}", wave))?;
+                (total as f64 * percent / 100.0).ceil() as usize
+            }
+            None => wave
+                .parse()
+                .with_context(|| format!("invalid wave {}, use a count or a percentage", wave))?,
+        };
+        let count = count.min(total);
+
+        if count > waves.last().copied().unwrap_or_default() {
+            waves.push(count);
+        }
+    }
+
+    if total > 0 && waves.last() != Some(&total) {
+        waves.push(total);
+    }
+
+    Ok(waves)
+}
+
+/// The locked revision of `input` in `flake.lock`.
+fn locked_rev(input: &str) -> Result<Option<String>> {
+    let lock: Value = serde_json::from_str(
+        &fs::read_to_string("flake.lock").context("couldn't read flake.lock")?,
+    )?;
+    let locked = &lock["nodes"][input]["locked"];
+    Ok(locked["rev"]
+        .as_str()
+        .or_else(|| locked["narHash"].as_str())
+        .map(String::from))
+}
+
+impl Campaign {
+    pub fn load(cluster: &str, input: &str) -> Result<Option<Self>> {
+        let path = state_file(cluster, input)?;
+        match fs::read_to_string(&path) {
+            Ok(file) => Ok(Some(serde_json::from_str(&file).with_context(|| {
+                format!("couldn't parse the campaign in {}", path.display())
+            })?)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    fn save(&self, cluster: &str) -> Result<()> {
+        let path = state_file(cluster, &self.input)?;
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn discard(cluster: &str, input: &str) -> Result<()> {
+        fs::remove_file(state_file(cluster, input)?).ok();
+        Ok(())
+    }
+
+    /// Start a campaign: update `input` in `flake.lock`, build the systems it changes, and
+    /// compare them with the systems running on the nodes to find the ones to patch.
+    pub async fn start(
+        cluster: &BitteCluster,
+        input: &str,
+        waves: &str,
+        run: &Run,
+    ) -> Result<Self> {
+        let locked_before = locked_rev(input)?;
+        sh(execute::command_args!(
+            "nix",
+            "flake",
+            "lock",
+            "--update-input",
+            input
+        ))?;
+        let locked_after = locked_rev(input)?;
+        if locked_before == locked_after {
+            warn!(
+                "{} is already at its latest revision, only lagging nodes are patched",
+                input
+            );
+        }
+
+        let mut nodes: Vec<BitteNode> = cluster.nodes.clone();
+        inventory::resolve_flake_attrs(&mut nodes, &BitteConfig::load()?.flake_attrs)?;
+        // canaries come from the clients, the nodes holding the quorum go last
+        nodes.sort_by_key(|node| (std::cmp::Reverse(NodeKind::of(node)), node.name.clone()));
+
+        let cache = cluster
+            .terra
+            .as_ref()
+            .and_then(|terra| terra.s3_cache.clone());
+        let mut systems: HashMap<String, String> = HashMap::new();
+        let mut patches = Vec::with_capacity(nodes.len());
+
+        for node in nodes.iter() {
+            let expected = match systems.get(&node.nixos) {
+                Some(system) => system.clone(),
+                None => {
+                    let system = images::expected_system(&node.nixos)?;
+                    systems.insert(node.nixos.clone(), system.clone());
+                    system
+                }
+            };
+
+            let (running, detail) = match images::running_system(node, &cache).await {
+                Ok(system) => (Some(system), String::new()),
+                Err(e) => (None, format!("couldn't read its system: {:#}", e)),
+            };
+
+            patches.push(NodePatch {
+                name: node.name.clone(),
+                nixos: node.nixos.clone(),
+                status: if running.as_ref() == Some(&expected) {
+                    PatchStatus::Unchanged
+                } else {
+                    PatchStatus::Pending
+                },
+                running,
+                expected: Some(expected),
+                wave: None,
+                detail,
+            });
+        }
+
+        // build every changed system up front, so a broken update fails before any node changes
+        let mut built: Vec<&str> = Vec::new();
+        for patch in patches.iter() {
+            if patch.status == PatchStatus::Pending && !built.contains(&patch.nixos.as_str()) {
+                info!("build: {}", patch.nixos);
+                nix::build(
+                    &format!(
+                        ".#nixosConfigurations.{}.config.system.build.toplevel",
+                        patch.nixos
+                    ),
+                    &run.path(&format!("result-{}", patch.nixos)),
+                    run,
+                )?;
+                built.push(patch.nixos.as_str());
+            }
+        }
+
+        let pending = patches
+            .iter()
+            .filter(|patch| patch.status == PatchStatus::Pending)
+            .count();
+        let waves = parse_waves(waves, pending)?;
+        for (position, patch) in patches
+            .iter_mut()
+            .filter(|patch| patch.status == PatchStatus::Pending)
+            .enumerate()
+        {
+            patch.wave = Some(
+                waves
+                    .iter()
+                    .position(|count| position < *count)
+                    .unwrap_or(0)
+                    + 1,
+            );
+        }
+
+        let campaign = Campaign {
+            input: input.to_string(),
+            started: now()?,
+            locked_before,
+            locked_after,
+            nodes: patches,
+            waves,
+            waves_done: 0,
+            last_wave_at: None,
+        };
+        campaign.save(&cluster.name)?;
+        history::record(
+            &cluster.name,
+            "campaign-start",
+            json!({
+                "input": campaign.input,
+                "from": campaign.locked_before,
+                "to": campaign.locked_after,
+                "nodes": pending,
+                "waves": campaign.waves,
+            }),
+        )?;
+
+        Ok(campaign)
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.waves_done >= self.waves.len()
+    }
+
+    /// How long the last wave still has to soak before the next one may start.
+    pub fn soak_remaining(&self, soak: Duration, now: u64) -> Option<Duration> {
+        let ready = self.last_wave_at? + soak.as_secs();
+        if now < ready {
+            Some(Duration::from_secs(ready - now))
+        } else {
+            None
+        }
+    }
+
+    /// Roll out the next waves, each followed by the checks of `verify` as a health gate, until
+    /// every node is patched or the last wave has to soak first. A failed node or health gate
+    /// stops the campaign, running it again retries the wave.
+    ///
+    /// Cancelling stops before the next node.
+    pub async fn advance(
+        &mut self,
+        cluster: &Arc<BitteCluster>,
+        ssm: bool,
+        soak: Duration,
+        verify: Option<&Path>,
+        run: &Run,
+        cancel: &CancellationToken,
+    ) -> Result<Progress> {
+        let cache = cluster
+            .terra
+            .as_ref()
+            .and_then(|terra| terra.s3_cache.clone());
+
+        while !self.is_done() {
+            if let Some(remaining) = self.soak_remaining(soak, now()?) {
+                return Ok(Progress::Soaking(remaining));
+            }
+
+            let wave = self.waves_done + 1;
+            info!(
+                "campaign {}: wave {} of {}",
+                self.input,
+                wave,
+                self.waves.len()
+            );
+
+            for index in 0..self.nodes.len() {
+                let patch = &self.nodes[index];
+                if patch.wave != Some(wave) || patch.status == PatchStatus::Patched {
+                    continue;
+                }
+                cancel::check(cancel)?;
+
+                let node = cluster
+                    .nodes
+                    .iter()
+                    .find(|node| node.name == patch.name)
+                    .with_context(|| format!("{} is gone from the cluster", patch.name))?;
+                let mut node = node.clone();
+                node.nixos = patch.nixos.clone();
+
+                info!("patch: {}, {}", node.name, node.pub_ip);
+                let started = Instant::now();
+                let result = match executor::for_node(&node, ssm, &cache).await {
+                    Ok(executor) => rebuild::copy_to(&node, executor.as_ref(), &None, run).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = db::record_node_event(
+                    &cluster.name,
+                    &node.name,
+                    "campaign",
+                    started.elapsed(),
+                    result.is_ok(),
+                ) {
+                    warn!("couldn't record the patch of {}: {:?}", node.name, e);
+                }
+
+                let patch = &mut self.nodes[index];
+                match result {
+                    Ok(()) => {
+                        patch.status = PatchStatus::Patched;
+                        patch.running = patch.expected.clone();
+                        patch.detail = String::new();
+                        self.save(&cluster.name)?;
+                    }
+                    Err(e) => {
+                        patch.status = PatchStatus::Failed;
+                        patch.detail = format!("{:#}", e);
+                        self.save(&cluster.name)?;
+                        bail!(
+                            "patching {} failed in wave {}, run the campaign again to retry: {:?}",
+                            node.name,
+                            wave,
+                            e
+                        );
+                    }
+                }
+            }
+
+            if let Some(verify) = verify {
+                let results = VerifyConfig::read(verify)?
+                    .run(Arc::clone(cluster), cancel)
+                    .await?;
+                let failed: Vec<&str> = results
+                    .iter()
+                    .filter(|result| !result.passed)
+                    .map(|result| result.name.as_str())
+                    .collect();
+                if !failed.is_empty() {
+                    bail!(
+                        "the health gate after wave {} failed: {}, run the campaign again once fixed",
+                        wave,
+                        failed.join(", ")
+                    );
+                }
+            }
+
+            self.waves_done = wave;
+            self.last_wave_at = Some(now()?);
+            self.save(&cluster.name)?;
+            history::record(
+                &cluster.name,
+                "campaign-wave",
+                json!({ "input": self.input, "wave": wave, "nodes": self.waves[wave - 1] }),
+            )?;
+        }
+
+        Ok(Progress::Done)
+    }
+
+    pub fn count(&self, status: PatchStatus) -> usize {
+        self.nodes
+            .iter()
+            .filter(|patch| patch.status == status)
+            .count()
+    }
+
+    pub fn table(&self) -> Table {
+        let mut table = Table::new();
+        table.add_row(row!["Node", "System", "Wave", "Status", "Detail"]);
+        for patch in self.nodes.iter() {
+            table.add_row(row![
+                patch.name,
+                patch.nixos,
+                patch.wave.map(|wave| wave.to_string()).unwrap_or_default(),
+                format!("{:?}", patch.status),
+                patch.detail
+            ]);
+        }
+        table
+    }
+
+    /// Write the markdown report of the campaign next to its state, and forget the state once
+    /// every wave is done.
+    pub fn finish(&self, cluster: &str) -> Result<PathBuf> {
+        let mut report = String::new();
+        writeln!(report, "# Patch campaign `{}` on {}", self.input, cluster)?;
+        writeln!(report)?;
+        writeln!(
+            report,
+            "- revision: `{}` -> `{}`",
+            self.locked_before.as_deref().unwrap_or("?"),
+            self.locked_after.as_deref().unwrap_or("?")
+        )?;
+        writeln!(
+            report,
+            "- waves: {} of {} done",
+            self.waves_done,
+            self.waves.len()
+        )?;
+        for status in [
+            PatchStatus::Patched,
+            PatchStatus::Pending,
+            PatchStatus::Failed,
+            PatchStatus::Unchanged,
+        ]
+        .iter()
+        {
+            writeln!(report, "- {:?}: {}", status, self.count(*status))?;
+        }
+        writeln!(report)?;
+
+        writeln!(report, "| Node | System | Wave | Status | Detail |")?;
+        writeln!(report, "|------|--------|------|--------|--------|")?;
+        for patch in self.nodes.iter() {
+            writeln!(
+                report,
+                "| {} | {} | {} | {:?} | {} |",
+                patch.name,
+                patch.nixos,
+                patch.wave.map(|wave| wave.to_string()).unwrap_or_default(),
+                patch.status,
+                patch.detail
+            )?;
+        }
+
+        let path = campaigns_dir()?.join(format!(
+            "{}-patch-{}-{}.md",
+            cluster, self.input, self.started
+        ));
+        fs::write(&path, report)?;
+
+        if self.is_done() {
+            Campaign::discard(cluster, &self.input)?;
+            history::record(
+                cluster,
+                "campaign",
+                json!({
+                    "input": self.input,
+                    "to": self.locked_after,
+                    "patched": self.count(PatchStatus::Patched),
+                    "report": path,
+                }),
+            )?;
+        }
+
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_waves, Campaign};
+    use pretty_assertions::assert_eq;
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_waves() {
+        assert_eq!(
+            parse_waves("1,10%,50%,100%", 40).unwrap(),
+            vec![1, 4, 20, 40]
+        );
+        assert_eq!(parse_waves("1,10%,50%", 3).unwrap(), vec![1, 2, 3]);
+        assert_eq!(parse_waves("5", 3).unwrap(), vec![3]);
+        assert!(parse_waves("1,10%", 0).unwrap().is_empty());
+        assert!(parse_waves("a few", 3).is_err());
+    }
+
+    #[test]
+    fn test_soak_remaining() {
+        let campaign = Campaign {
+            input: "nixpkgs".into(),
+            started: 0,
+            locked_before: None,
+            locked_after: None,
+            nodes: Vec::new(),
+            waves: vec![1, 4],
+            waves_done: 1,
+            last_wave_at: Some(1000),
+        };
+        let soak = Duration::from_secs(3600);
+
+        assert_eq!(
+            campaign.soak_remaining(soak, 1600),
+            Some(Duration::from_secs(3000))
+        );
+        assert_eq!(campaign.soak_remaining(soak, 4600), None);
+    }
+}
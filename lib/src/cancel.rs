@@ -0,0 +1,67 @@
+use std::future::Future;
+
+use anyhow::Result;
+use log::warn;
+
+use crate::error::Error;
+
+/// Taken by every operation that can run for longer than a few requests. Cancelling it makes the
+/// operation return `Error::Cancelled` at the next point where it can stop without leaving
+/// anything half done, as documented on each operation. Watchers stop right away.
+pub use tokio_util::sync::CancellationToken;
+
+/// Fail with `Error::Cancelled` if `cancel` was cancelled, for the points between the steps of
+/// an operation where it can stop cleanly.
+pub fn check(cancel: &CancellationToken) -> Result<()> {
+    if cancel.is_cancelled() {
+        return Err(Error::Cancelled.into());
+    }
+    Ok(())
+}
+
+/// Run `future` until it finishes or `cancel` is cancelled, whichever happens first. The future
+/// is dropped when cancelled, so it must not be in the middle of something that needs cleanup.
+pub async fn or_cancel<T, F>(cancel: &CancellationToken, future: F) -> Result<T>
+where
+    F: Future<Output = Result<T>>,
+{
+    tokio::select! {
+        _ = cancel.cancelled() => Err(Error::Cancelled.into()),
+        result = future => result,
+    }
+}
+
+/// Sleep for `duration` unless `cancel` is cancelled before.
+pub async fn sleep(cancel: &CancellationToken, duration: std::time::Duration) -> Result<()> {
+    or_cancel(cancel, async {
+        tokio::time::sleep(duration).await;
+        Ok(())
+    })
+    .await
+}
+
+/// Whether `error` is the result of a cancellation rather than a failure.
+pub fn is_cancelled(error: &anyhow::Error) -> bool {
+    matches!(error.downcast_ref::<Error>(), Some(Error::Cancelled))
+}
+
+/// A token cancelled by the first Ctrl-C. The second one exits immediately, for operations
+/// that take long to reach a point where they can stop.
+pub fn on_ctrl_c() -> CancellationToken {
+    let cancel = CancellationToken::new();
+    let token = cancel.clone();
+
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_err() {
+            return;
+        }
+        warn!("cancelling, press Ctrl-C again to exit immediately");
+        token.cancel();
+
+        if tokio::signal::ctrl_c().await.is_ok() {
+            std::process::exit(130);
+        }
+    });
+
+    cancel
+}
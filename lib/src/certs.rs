@@ -1,39 +1,367 @@
-use std::{fs, process::Command};
-
-use crate::Result;
-use serde::Deserialize;
-
-use super::check_cmd;
-
-pub fn sign_intermediate() -> Result<()> {
-    let ca_pem_orig = fs::read_to_string("secrets/ca.pem").expect("Couldn't read ca.pem");
-    let ca_pem = ca_pem_orig.trim();
-    let cert_pem_orig = fs::read_to_string("secrets/cert.pem").expect("Couldn't read cert.pem");
-    let cert_pem = cert_pem_orig.trim();
-
-    let output = cmd_output(Command::new("cfssl").args(vec![
-        "sign",
-        "-ca",
-        "secrets/ca.pem",
-        "-ca-key",
-        "secrets/ca-key.pem",
-        "-hostname",
-        "vault.service.consul",
-        "-config",
-        ca_config_file().as_str(),
-        "-profile",
-        "intermediate",
-        "secrets/issuing-ca.csr",
-    ]));
-
-    let issuing_csr_container: Cert =
-        serde_json::from_str(output.as_str()).expect("couldn't parse output from cfssl");
-    let issuing_pem = issuing_csr_container.cert.trim();
-
-    fs::write("secrets/issuing.pem", issuing_pem).expect("Couldn't write issuing.pem");
+use std::{
+    env, fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    endpoint::{self, Service, TlsFiles},
+    types::BitteNode,
+    Result,
+};
+use anyhow::{anyhow, bail, Context};
+use log::info;
+use rcgen::{
+    BasicConstraints, CertificateParams, CertificateSigningRequest, CustomExtension, IsCa, KeyPair,
+    KeyUsagePurpose, SanType, SignatureAlgorithm,
+};
+use ring::{
+    digest,
+    rand::SystemRandom,
+    signature::{self, EcdsaKeyPair, Ed25519KeyPair, RsaKeyPair},
+};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use x509_parser::{certificate::X509Certificate, extensions::ParsedExtension, pem::parse_x509_pem};
+
+use super::{check_cmd, sh};
+
+/// bitte's own root CA and its key, made when the cluster was set up.
+pub const OWN_CA: &str = "secrets/ca.pem";
+pub const OWN_CA_KEY: &str = "secrets/ca-key.pem";
+
+/// Where the CSR of the issuing CA in Vault is written for signing.
+pub const ISSUING_CSR: &str = "secrets/issuing-ca.csr";
+
+/// Who signs the issuing CA of Vault PKI.
+#[derive(Debug, Clone)]
+pub enum IssuingSigner {
+    /// A CA whose key is at hand, bitte's own root by default.
+    Key { ca: PathBuf, key: PathBuf },
+    /// A CA whose key isn't, like an enterprise CA: `cert` is what it signed the CSR into and
+    /// `ca` its own chain.
+    Signed { cert: PathBuf, ca: PathBuf },
+}
+
+impl Default for IssuingSigner {
+    fn default() -> Self {
+        IssuingSigner::Key {
+            ca: PathBuf::from(OWN_CA),
+            key: PathBuf::from(OWN_CA_KEY),
+        }
+    }
+}
+
+impl IssuingSigner {
+    fn ca(&self) -> &Path {
+        match self {
+            IssuingSigner::Key { ca, .. } | IssuingSigner::Signed { ca, .. } => ca,
+        }
+    }
+}
+
+/// How long the issuing CA of Vault is valid, five years.
+const INTERMEDIATE_TTL_HOURS: i64 = 43_800;
+
+/// Make `params` those of an intermediate CA for Vault, valid from now on.
+fn intermediate_params(params: &mut CertificateParams) {
+    let now = OffsetDateTime::now_utc();
+    params.not_before = now;
+    params.not_after = now + time::Duration::hours(INTERMEDIATE_TTL_HOURS);
+    params.serial_number = Some(now.unix_timestamp_nanos() as u64);
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.key_usages = vec![
+        KeyUsagePurpose::DigitalSignature,
+        KeyUsagePurpose::KeyEncipherment,
+        KeyUsagePurpose::KeyCertSign,
+        KeyUsagePurpose::CrlSign,
+    ];
+    params.subject_alt_names = vec![SanType::DnsName("vault.service.consul".to_string())];
+}
+
+/// `AlgorithmIdentifier` of RSA keys.
+const RSA_ALGORITHM: &[u8] = &[
+    0x30, 0x0d, 0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01, 0x05, 0x00,
+];
+/// `id-ecPublicKey`, the algorithm of EC keys, followed by their curve.
+const EC_PUBLIC_KEY: &[u8] = &[0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const AUTHORITY_KEY_IDENTIFIER: &[u64] = &[2, 5, 29, 35];
+
+/// The tag, the whole encoding, and the content of a DER element.
+type DerElement<'a> = (u8, &'a [u8], &'a [u8]);
+
+/// The DER element `der` starts with.
+fn der_element(der: &[u8]) -> Option<DerElement<'_>> {
+    let tag = *der.first()?;
+    let first = *der.get(1)? as usize;
+    let (len, start) = if first < 0x80 {
+        (first, 2)
+    } else {
+        let size = first & 0x7f;
+        if size == 0 || size > 4 {
+            return None;
+        }
+        let len = der
+            .get(2..2 + size)?
+            .iter()
+            .fold(0, |len, byte| len << 8 | *byte as usize);
+        (len, 2 + size)
+    };
+    let whole = der.get(..start + len)?;
+    Some((tag, whole, &whole[start..]))
+}
+
+/// The elements of a DER sequence, from its `content`.
+fn der_elements(mut content: &[u8]) -> Option<Vec<DerElement<'_>>> {
+    let mut elements = Vec::new();
+    while !content.is_empty() {
+        let element = der_element(content)?;
+        content = &content[element.1.len()..];
+        elements.push(element);
+    }
+    Some(elements)
+}
+
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    if content.len() < 0x80 {
+        out.push(content.len() as u8);
+    } else {
+        let len: Vec<u8> = content
+            .len()
+            .to_be_bytes()
+            .iter()
+            .copied()
+            .skip_while(|byte| *byte == 0)
+            .collect();
+        out.push(0x80 | len.len() as u8);
+        out.extend(len);
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+/// The PKCS#8 DER of the key in `pem`. cfssl writes EC keys as SEC1 and RSA keys as PKCS#1,
+/// those are wrapped the way `openssl pkcs8 -topk8` does.
+fn pkcs8_key(pem: &str) -> Result<Vec<u8>> {
+    // x509-parser only keeps the first word of the label
+    let label = pem
+        .lines()
+        .find_map(|line| {
+            line.trim()
+                .strip_prefix("-----BEGIN ")?
+                .strip_suffix("-----")
+        })
+        .context("not a PEM key")?;
+    let (_, pem) = parse_x509_pem(pem.as_bytes()).context("not a PEM key")?;
+    let algorithm = match label {
+        "PRIVATE KEY" => return Ok(pem.contents),
+        "RSA PRIVATE KEY" => RSA_ALGORITHM.to_vec(),
+        "EC PRIVATE KEY" => {
+            // the curve is in the [0] parameters of the key
+            let curve = der_element(&pem.contents)
+                .and_then(|(_, _, key)| der_elements(key))
+                .and_then(|elements| elements.into_iter().find(|(tag, _, _)| *tag == 0xa0))
+                .context("the EC key doesn't name its curve")?
+                .2;
+            der(0x30, &[EC_PUBLIC_KEY, curve].concat())
+        }
+        label => bail!("keys of the type {} aren't supported", label),
+    };
+    // version 0, the algorithm, and the key
+    Ok(der(
+        0x30,
+        &[
+            &[0x02, 0x01, 0x00][..],
+            &algorithm,
+            &der(0x04, &pem.contents),
+        ]
+        .concat(),
+    ))
+}
+
+/// Sign `message` with the PKCS#8 `key` the way `alg` says.
+fn sign(alg: &SignatureAlgorithm, key: &[u8], message: &[u8]) -> Result<Vec<u8>> {
+    let rng = SystemRandom::new();
+    let rejected = |e| anyhow!("the key of the CA doesn't fit its certificate: {}", e);
+
+    let ecdsa = if alg == &rcgen::PKCS_ECDSA_P256_SHA256 {
+        Some(&signature::ECDSA_P256_SHA256_ASN1_SIGNING)
+    } else if alg == &rcgen::PKCS_ECDSA_P384_SHA384 {
+        Some(&signature::ECDSA_P384_SHA384_ASN1_SIGNING)
+    } else {
+        None
+    };
+    if let Some(ecdsa) = ecdsa {
+        let key = EcdsaKeyPair::from_pkcs8(ecdsa, key).map_err(rejected)?;
+        let signature = key
+            .sign(&rng, message)
+            .map_err(|_| anyhow!("couldn't sign"))?;
+        return Ok(signature.as_ref().to_vec());
+    }
+
+    if alg == &rcgen::PKCS_ED25519 {
+        let key = Ed25519KeyPair::from_pkcs8_maybe_unchecked(key).map_err(rejected)?;
+        return Ok(key.sign(message).as_ref().to_vec());
+    }
+
+    let padding = if alg == &rcgen::PKCS_RSA_SHA256 {
+        &signature::RSA_PKCS1_SHA256
+    } else if alg == &rcgen::PKCS_RSA_SHA384 {
+        &signature::RSA_PKCS1_SHA384
+    } else if alg == &rcgen::PKCS_RSA_SHA512 {
+        &signature::RSA_PKCS1_SHA512
+    } else {
+        bail!("the signature algorithm of the CA isn't supported");
+    };
+    let key = RsaKeyPair::from_pkcs8(key).map_err(rejected)?;
+    let mut signature = vec![0; key.public_modulus_len()];
+    key.sign(padding, &rng, message, &mut signature)
+        .map_err(|_| anyhow!("couldn't sign"))?;
+    Ok(signature)
+}
+
+/// Replace the issuer of the certificate `cert` with `issuer` and sign it again. rcgen writes
+/// the issuer from the attributes it parsed from the CA, which needn't be the same bytes.
+fn reissue(cert: &[u8], issuer: &[u8], alg: &SignatureAlgorithm, key: &[u8]) -> Result<Vec<u8>> {
+    let malformed = || anyhow!("rcgen wrote a malformed certificate");
+    let parts = der_element(cert)
+        .and_then(|(_, _, cert)| der_elements(cert))
+        .ok_or_else(malformed)?;
+    let (tbs, algorithm) = match parts.as_slice() {
+        [tbs, algorithm, _] => (tbs, algorithm),
+        _ => return Err(malformed()),
+    };
+
+    let fields = der_elements(tbs.2).ok_or_else(malformed)?;
+    // the issuer follows the version, if given, the serial, and the signature algorithm
+    let at = if fields.first().map(|field| field.0) == Some(0xa0) {
+        3
+    } else {
+        2
+    };
+    let tbs = der(
+        0x30,
+        &fields
+            .iter()
+            .enumerate()
+            .map(|(index, field)| if index == at { issuer } else { field.1 })
+            .collect::<Vec<_>>()
+            .concat(),
+    );
+
+    let signature = [&[0][..], &sign(alg, key, &tbs)?].concat();
+    Ok(der(
+        0x30,
+        &[&tbs[..], algorithm.1, &der(0x03, &signature)].concat(),
+    ))
+}
+
+fn to_pem(der: &[u8]) -> String {
+    let encoded = base64::encode(der);
+    let lines: Vec<&str> = encoded
+        .as_bytes()
+        .chunks(64)
+        .map(|line| std::str::from_utf8(line).unwrap_or_default())
+        .collect();
+    format!(
+        "-----BEGIN CERTIFICATE-----\n{}\n-----END CERTIFICATE-----",
+        lines.join("\n")
+    )
+}
+
+/// Sign the CSR in `csr` as an intermediate CA, with the first certificate in `ca` and its `key`.
+/// The issuer and the authority key identifier are the subject and the subject key identifier
+/// of `ca` exactly, so the chain is built no matter how they were encoded.
+pub fn sign_csr(ca: &str, key: &str, csr: &str) -> Result<String> {
+    let ca = pem_blocks(ca)
+        .into_iter()
+        .next()
+        .context("the CA has no certificate")?;
+    let key = pkcs8_key(key).context("couldn't read the key of the CA")?;
+    let params = CertificateParams::from_ca_cert_pem(
+        &ca,
+        KeyPair::from_der(&key).context("couldn't read the key of the CA")?,
+    )?;
+    let alg = params.alg;
+    let signer = rcgen::Certificate::from_params(params)?;
+
+    let (issuer, key_id) = with_x509(&ca, |ca| {
+        let key_id = ca
+            .extensions()
+            .iter()
+            .find_map(|ext| match ext.parsed_extension() {
+                ParsedExtension::SubjectKeyIdentifier(key_id) => Some(key_id.0.to_vec()),
+                _ => None,
+            });
+        Ok((ca.subject().as_raw().to_vec(), key_id))
+    })?;
+
+    let mut csr = CertificateSigningRequest::from_pem(csr).context("couldn't read the CSR")?;
+    intermediate_params(&mut csr.params);
+    if let Some(key_id) = key_id {
+        // AuthorityKeyIdentifier with the [0] keyIdentifier only
+        csr.params
+            .custom_extensions
+            .push(CustomExtension::from_oid_content(
+                AUTHORITY_KEY_IDENTIFIER,
+                der(0x30, &der(0x80, &key_id)),
+            ));
+    }
+
+    let cert = csr.serialize_der_with_signer(&signer)?;
+    Ok(to_pem(&reissue(&cert, &issuer, alg, &key)?))
+}
+
+fn sign_file(ca: &Path, key: &Path, csr: &str) -> Result<String> {
+    sign_csr(&read_pem(ca)?, &read_pem(key)?, &read_pem(Path::new(csr))?)
+}
+
+/// Fail unless `cert` is signed by one of the certificates in `ca`, which doesn't have to end
+/// in a root.
+pub fn verify_chain(ca: &str, cert: &str) -> Result<()> {
+    with_x509(cert, |cert| {
+        for ca in pem_blocks(ca).iter() {
+            let signed = with_x509(ca, |ca| {
+                Ok(cert.verify_signature(Some(ca.public_key())).is_ok())
+            })?;
+            if signed {
+                return Ok(());
+            }
+        }
+        bail!("{} isn't signed by the CA", cert.subject())
+    })
+}
+
+fn read_pem(path: &Path) -> Result<String> {
+    let pem =
+        fs::read_to_string(path).with_context(|| format!("Couldn't read {}", path.display()))?;
+    Ok(pem.trim().to_string())
+}
+
+/// Have `signer` sign the issuing CA of Vault PKI and install it. With `cross`, the issuing CA
+/// is signed by that CA too, and the nodes trust both roots: during a migration from one root
+/// to another, certificates verify with either.
+pub fn sign_intermediate(signer: &IssuingSigner, cross: Option<(&Path, &Path)>) -> Result<()> {
+    let ca_pem = read_pem(signer.ca())?;
+    let cert_pem = read_pem(Path::new("secrets/cert.pem"))?;
+
+    let issuing_pem = match signer {
+        IssuingSigner::Key { ca, key } => sign_file(ca, key, ISSUING_CSR)?,
+        IssuingSigner::Signed { cert, .. } => {
+            let cert = read_pem(cert)?;
+            verify_chain(&ca_pem, &cert)
+                .with_context(|| format!("{} didn't sign it", signer.ca().display()))?;
+            cert
+        }
+    };
+
+    fs::write("secrets/issuing.pem", &issuing_pem).expect("Couldn't write issuing.pem");
     fs::write(
         "secrets/issuing_full.pem",
-        vec![issuing_pem, ca_pem].join("\n"),
+        [issuing_pem.as_str(), ca_pem.as_str()].join("\n"),
     )
     .expect("Couldn't write issuing_full.pem");
 
@@ -43,51 +371,50 @@ pub fn sign_intermediate() -> Result<()> {
         "certificate=@secrets/issuing_full.pem",
     ]))?;
 
-    let full = vec![cert_pem, issuing_pem, ca_pem].join("\n");
+    let mut full = vec![cert_pem, issuing_pem];
+    let mut trusted = vec![ca_pem.clone()];
+    if let Some((cross_ca, cross_key)) = cross {
+        let cross_pem = sign_file(cross_ca, cross_key, ISSUING_CSR)?;
+        fs::write("secrets/issuing-cross.pem", &cross_pem)?;
+        info!(
+            "cross-signed the issuing CA with {}, the nodes trust both roots",
+            cross_ca.display()
+        );
+        full.push(cross_pem);
+        trusted.push(read_pem(cross_ca)?);
+    }
+    full.push(ca_pem);
+
+    let full = full.join("\n");
     fs::write("secrets/full.pem", &full).expect("Couldn't write full.pem");
+    sops_set("full", &full)?;
 
+    // bitte's own root is what the nodes trust already
+    let own = matches!(signer, IssuingSigner::Key { ca, .. } if ca == Path::new(OWN_CA));
+    if !own || cross.is_some() {
+        sops_set("ca", &trusted.join("\n"))?;
+    }
+    Ok(())
+}
+
+/// Set `key` of the encrypted certificates the nodes are provisioned with.
+fn sops_set(key: &str, value: &str) -> Result<()> {
     check_cmd(
         Command::new("sops")
             .arg("--set")
             .arg(format!(
-                r#"["full"] {}"#,
-                serde_json::to_string(&full).expect("Couldn't generate JSON for full.pem")
+                r#"["{}"] {}"#,
+                key,
+                serde_json::to_string(value).expect("Couldn't generate JSON for the certificate")
             ))
             .arg("encrypted/cert.json"),
-    )?;
-    Ok(())
-}
-
-fn ca_config_file() -> String {
-    let json = serde_json::json!({
-        "signing": {
-            "default": {
-                "expiry": "87600h",
-            },
-            "profiles": {
-                "default": {
-                    "usages": ["signing", "key encipherment", "server auth", "client auth"],
-                    "expiry": "8760h",
-                },
-
-                "intermediate": {
-                    "usages":        ["signing", "key encipherment", "cert sign", "crl sign"],
-                    "expiry":        "43800h",
-                    "ca_constraint": {"is_ca": true},
-                },
-            },
-        },
-    });
-    let location = "secrets/ca-config.json";
-    fs::write(location, json.to_string()).expect("couldn't write ca-config.json");
-    location.to_string()
+    )
 }
 
 pub fn write_issuing_ca(domain: &str) {
     let issuing_ca = vault_issuing_ca(domain);
     let csr_container: Csr = serde_json::from_str(&issuing_ca).expect("Couldn't parse issuing CA");
-    fs::write("secrets/issuing-ca.csr", csr_container.data.csr)
-        .expect("Couldn't write issuing-ca.csr");
+    fs::write(ISSUING_CSR, csr_container.data.csr).expect("Couldn't write issuing-ca.csr");
 }
 
 fn vault_issuing_ca(domain: &str) -> String {
@@ -110,6 +437,398 @@ fn cmd_output(cmd: &mut Command) -> String {
     String::from_utf8_lossy(&output.stdout).to_string()
 }
 
+/// Fetch the CA chain that signs the cluster's certificates from Vault PKI.
+///
+/// Vault's certificate is checked against the system roots, `VAULT_CACERT`, and bitte's own
+/// root if it is at hand. The chain itself still has to be checked with `verify_root` before
+/// it is trusted.
+pub async fn vault_ca_chain(domain: &str) -> Result<String> {
+    let url = format!(
+        "{}/v1/pki/ca_chain",
+        endpoint::address(Service::Vault, domain)
+    );
+    let mut builder = reqwest::Client::builder();
+    if let Ok(own) = fs::read(OWN_CA) {
+        builder = builder.add_root_certificate(
+            reqwest::Certificate::from_pem(&own)
+                .with_context(|| format!("{} is not a PEM certificate", OWN_CA))?,
+        );
+    }
+    let chain = TlsFiles::from_env(Service::Vault)
+        .apply(builder)?
+        .build()?
+        .get(&url)
+        .send()
+        .await
+        .with_context(|| format!("failed to query: {}", url))?
+        .error_for_status()?
+        .text()
+        .await?;
+
+    if pem_blocks(&chain).is_empty() {
+        bail!("{} returned no certificates", url);
+    }
+    Ok(chain)
+}
+
+/// Check that `root`, the last certificate of a fetched CA chain, is the expected one: the one
+/// with `fingerprint` if given, otherwise bitte's own root or one it signed. Returns the
+/// fingerprint of `root`.
+pub fn verify_root(root: &str, fingerprint: Option<&str>) -> Result<String> {
+    let actual = pem_fingerprint(root)?;
+    match fingerprint {
+        Some(expected) => {
+            let bare = |fingerprint: &str| fingerprint.replace(':', "").to_uppercase();
+            if bare(expected) != bare(&actual) {
+                bail!(
+                    "the root has the fingerprint {}, not the expected {}",
+                    actual,
+                    expected
+                );
+            }
+        }
+        None => {
+            let own = read_pem(Path::new(OWN_CA)).with_context(|| {
+                format!(
+                    "can't verify the root without {} or an expected fingerprint",
+                    OWN_CA
+                )
+            })?;
+            verify_chain(&own, root)
+                .with_context(|| format!("the root isn't the CA of {}", OWN_CA))?;
+        }
+    }
+    Ok(actual)
+}
+
+/// The certificates of a PEM bundle, each with its BEGIN and END lines.
+pub fn pem_blocks(pem: &str) -> Vec<String> {
+    let mut blocks = Vec::new();
+    let mut current: Option<Vec<&str>> = None;
+
+    for line in pem.lines().map(str::trim) {
+        if line == "-----BEGIN CERTIFICATE-----" {
+            current = Some(vec![line]);
+        } else if let Some(block) = current.as_mut() {
+            block.push(line);
+            if line == "-----END CERTIFICATE-----" {
+                blocks.push(block.join("\n") + "\n");
+                current = None;
+            }
+        }
+    }
+
+    blocks
+}
+
+/// Decode the first certificate in `pem` for `f`.
+fn with_x509<T>(pem: &str, f: impl FnOnce(&X509Certificate) -> Result<T>) -> Result<T> {
+    let (_, pem) = parse_x509_pem(pem.as_bytes()).context("not a PEM certificate")?;
+    let cert = pem
+        .parse_x509()
+        .context("couldn't decode the certificate")?;
+    f(&cert)
+}
+
+/// SHA-256 fingerprint of the first certificate in `pem`, as openssl prints it.
+pub fn pem_fingerprint(pem: &str) -> Result<String> {
+    let (_, pem) = parse_x509_pem(pem.as_bytes()).context("not a PEM certificate")?;
+    let digest = digest::digest(&digest::SHA256, &pem.contents);
+    Ok(hex(digest.as_ref(), ":"))
+}
+
+fn hex(bytes: &[u8], separator: &str) -> String {
+    bytes
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+/// Serial number of the certificate in `pem`, as the hex digits openssl prints.
+pub fn serial(pem: &str) -> Result<String> {
+    with_x509(pem, |cert| {
+        let serial = cert.raw_serial();
+        let start = serial.iter().position(|byte| *byte != 0).unwrap_or(0);
+        Ok(hex(&serial[start..], ""))
+    })
+}
+
+/// When the certificate in `pem` expires, in seconds since the epoch.
+pub fn expires(pem: &str) -> Result<u64> {
+    with_x509(pem, |cert| {
+        Ok(cert.validity().not_after.timestamp().max(0) as u64)
+    })
+}
+
+/// The ports the core nodes serve Vault, Consul, and Nomad on with TLS.
+pub const SERVER_PORTS: &[(Service, u16)] = &[
+    (Service::Vault, 8200),
+    (Service::Consul, 8501),
+    (Service::Nomad, 4646),
+];
+
+/// How long a core node gets to present its certificate.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where a certificate stands against the thresholds of `bitte certs status`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Expiry {
+    Valid,
+    /// Expires within the warning threshold.
+    Soon,
+    /// Expires within the renewal window, it should be renewed now.
+    Renew,
+    Expired,
+    /// The certificate couldn't be read.
+    Unknown,
+}
+
+impl Expiry {
+    pub fn of(not_after: u64, now: u64, warn_days: u64, renew_days: u64) -> Self {
+        let day = 86_400;
+        if not_after <= now {
+            Expiry::Expired
+        } else if not_after - now <= renew_days * day {
+            Expiry::Renew
+        } else if not_after - now <= warn_days * day {
+            Expiry::Soon
+        } else {
+            Expiry::Valid
+        }
+    }
+
+    /// Whether the certificate needs attention now.
+    pub fn is_failure(self) -> bool {
+        matches!(self, Expiry::Renew | Expiry::Expired | Expiry::Unknown)
+    }
+}
+
+/// A certificate of the cluster and when it expires.
+#[derive(Debug, Serialize)]
+pub struct CertStatus {
+    /// Where the certificate was found, like `vault ca chain` or `core-1 consul`.
+    pub source: String,
+    pub subject: String,
+    pub not_after: String,
+    pub days_left: i64,
+    pub expiry: Expiry,
+    pub error: Option<String>,
+}
+
+/// Thresholds, in days, of `bitte certs status`.
+#[derive(Debug, Clone, Copy)]
+pub struct Thresholds {
+    pub warn_days: u64,
+    pub renew_days: u64,
+}
+
+impl CertStatus {
+    fn read(source: String, pem: &str, thresholds: Thresholds) -> Self {
+        match Self::inspect(pem) {
+            Ok((subject, not_after, expires)) => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |now| now.as_secs());
+                Self {
+                    source,
+                    subject,
+                    not_after,
+                    days_left: (expires as i64 - now as i64).div_euclid(86_400),
+                    expiry: Expiry::of(expires, now, thresholds.warn_days, thresholds.renew_days),
+                    error: None,
+                }
+            }
+            Err(e) => Self::failed(source, e),
+        }
+    }
+
+    fn failed(source: String, error: anyhow::Error) -> Self {
+        Self {
+            source,
+            subject: String::new(),
+            not_after: String::new(),
+            days_left: 0,
+            expiry: Expiry::Unknown,
+            error: Some(format!("{:#}", error)),
+        }
+    }
+
+    fn inspect(pem: &str) -> Result<(String, String, u64)> {
+        with_x509(pem, |cert| {
+            let not_after = cert.validity().not_after;
+            Ok((
+                cert.subject().to_string(),
+                not_after.to_rfc2822(),
+                not_after.timestamp().max(0) as u64,
+            ))
+        })
+    }
+}
+
+/// The certificate chain `host` presents on `port`.
+pub async fn served_chain(host: IpAddr, port: u16) -> Result<String> {
+    let address = match host {
+        IpAddr::V4(ip) => format!("{}:{}", ip, port),
+        IpAddr::V6(ip) => format!("[{}]:{}", ip, port),
+    };
+    let openssl = tokio::process::Command::new("openssl")
+        .args(&["s_client", "-showcerts", "-connect", &address])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .output();
+    let output = tokio::time::timeout(CONNECT_TIMEOUT, openssl)
+        .await
+        .with_context(|| format!("{} didn't answer", address))?
+        .context("couldn't run openssl, is it installed?")?;
+    let chain = String::from_utf8_lossy(&output.stdout).to_string();
+    if pem_blocks(&chain).is_empty() {
+        bail!("{} presented no certificate", address);
+    }
+    Ok(chain)
+}
+
+/// The expiry of every certificate the cluster depends on: the CA chain in Vault, the server
+/// certificates the core nodes present for Vault, Consul, and Nomad (at their public ips with
+/// `public`), and the client certificates bitte is configured with.
+pub async fn status(
+    domain: &str,
+    nodes: &[BitteNode],
+    public: bool,
+    thresholds: Thresholds,
+) -> Vec<CertStatus> {
+    let mut statuses = Vec::new();
+
+    match vault_ca_chain(domain).await {
+        Ok(chain) => {
+            let blocks = pem_blocks(&chain);
+            let last = blocks.len().saturating_sub(1);
+            for (index, pem) in blocks.iter().enumerate() {
+                let source = if index == last {
+                    "vault root ca"
+                } else {
+                    "vault intermediate ca"
+                };
+                statuses.push(CertStatus::read(source.to_string(), pem, thresholds));
+            }
+        }
+        Err(e) => statuses.push(CertStatus::failed("vault ca chain".to_string(), e)),
+    }
+
+    let mut core: Vec<&BitteNode> = nodes
+        .iter()
+        .filter(|node| node.name.starts_with("core"))
+        .collect();
+    core.sort_by(|a, b| a.name.cmp(&b.name));
+    for node in core {
+        let ip = if public { node.pub_ip } else { node.priv_ip };
+        for (service, port) in SERVER_PORTS.iter() {
+            let source = format!("{} {}", node.name, service);
+            let status = match served_chain(ip, *port).await {
+                Ok(chain) => CertStatus::read(source, &pem_blocks(&chain)[0], thresholds),
+                Err(e) => CertStatus::failed(source, e),
+            };
+            statuses.push(status);
+        }
+    }
+
+    for service in Service::ALL.iter() {
+        if let Some(cert) = TlsFiles::from_env(*service).client_cert {
+            let source = format!("{} client {}", service, cert);
+            let status = match fs::read_to_string(&cert) {
+                Ok(pem) => CertStatus::read(source, &pem, thresholds),
+                Err(e) => CertStatus::failed(source, e.into()),
+            };
+            statuses.push(status);
+        }
+    }
+
+    statuses
+}
+
+/// Trust the root certificate at `root` for TLS on this machine, as `name`.
+///
+/// Installs into the system trust store of the current OS and, where `certutil` is available
+/// on Linux, the NSS database used by Chrome and Firefox. Returns the stores it installed into.
+pub fn install_ca(root: &Path, name: &str) -> Result<Vec<String>> {
+    let mut stores = Vec::new();
+
+    match env::consts::OS {
+        "linux" => {
+            let debian = Path::new("/usr/local/share/ca-certificates");
+            let fedora = Path::new("/etc/pki/ca-trust/source/anchors");
+
+            if debian.is_dir() {
+                sudo_copy(root, &debian.join(format!("{}.crt", name)))?;
+                sh(execute::command_args!("sudo", "update-ca-certificates"))?;
+                stores.push(debian.display().to_string());
+            } else if fedora.is_dir() {
+                sudo_copy(root, &fedora.join(format!("{}.pem", name)))?;
+                sh(execute::command_args!("sudo", "update-ca-trust"))?;
+                stores.push(fedora.display().to_string());
+            } else if Path::new("/etc/NIXOS").exists() {
+                info!(
+                    "the NixOS trust store is immutable, add {} to security.pki.certificateFiles",
+                    root.display()
+                );
+            } else {
+                bail!("don't know how to install certificates into the trust store of this OS");
+            }
+
+            let nssdb = PathBuf::from(shellexpand::tilde("~/.pki/nssdb").to_string());
+            if nssdb.is_dir() && sh(execute::command_args!("certutil", "-H")).is_ok() {
+                sh(execute::command_args!(
+                    "certutil",
+                    "-d",
+                    format!("sql:{}", nssdb.display()),
+                    "-A",
+                    "-t",
+                    "C,,",
+                    "-n",
+                    name,
+                    "-i",
+                    root
+                ))?;
+                stores.push(nssdb.display().to_string());
+            }
+        }
+        "macos" => {
+            sh(execute::command_args!(
+                "sudo",
+                "security",
+                "add-trusted-cert",
+                "-d",
+                "-r",
+                "trustRoot",
+                "-k",
+                "/Library/Keychains/System.keychain",
+                root
+            ))?;
+            stores.push("System.keychain".to_string());
+        }
+        "windows" => {
+            sh(execute::command_args!(
+                "certutil",
+                "-addstore",
+                "-f",
+                "ROOT",
+                root
+            ))?;
+            stores.push("ROOT".to_string());
+        }
+        os => bail!("installing certificates on {} isn't supported", os),
+    }
+
+    Ok(stores)
+}
+
+fn sudo_copy(from: &Path, to: &Path) -> Result<()> {
+    sh(execute::command_args!("sudo", "cp", from, to))?;
+    Ok(())
+}
+
 #[derive(Deserialize)]
 struct Csr {
     data: CsrValue,
@@ -120,7 +839,126 @@ struct CsrValue {
     csr: String,
 }
 
-#[derive(Deserialize)]
-struct Cert {
-    cert: String,
+#[cfg(test)]
+mod tests {
+    use super::{
+        expires, pem_blocks, pem_fingerprint, serial, sign_csr, verify_chain, verify_root,
+        with_x509, Expiry,
+    };
+    use pretty_assertions::assert_eq;
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa};
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use x509_parser::extensions::ParsedExtension;
+
+    #[test]
+    fn test_pem_blocks() {
+        let chain = "-----BEGIN CERTIFICATE-----\nissuing\n-----END CERTIFICATE-----\n\
+                     -----BEGIN CERTIFICATE-----\nroot\n-----END CERTIFICATE-----\n";
+
+        let blocks = pem_blocks(chain);
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(
+            blocks[1],
+            "-----BEGIN CERTIFICATE-----\nroot\n-----END CERTIFICATE-----\n"
+        );
+        assert!(pem_blocks("no certificates here").is_empty());
+    }
+
+    #[test]
+    fn test_sign_csr() {
+        let mut root = CertificateParams::new(vec![]);
+        root.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        root.distinguished_name
+            .push(DnType::CommonName, "bitte root");
+        let root = Certificate::from_params(root).unwrap();
+        let root_pem = root.serialize_pem().unwrap();
+        let issuing = Certificate::from_params(CertificateParams::new(vec![])).unwrap();
+
+        let signed = sign_csr(
+            &root_pem,
+            &root.serialize_private_key_pem(),
+            &issuing.serialize_request_pem().unwrap(),
+        )
+        .unwrap();
+        assert!(!signed.contains('\r'));
+        assert_eq!(pem_blocks(&signed).len(), 1);
+        verify_chain(&root_pem, &signed).unwrap();
+        assert!(verify_chain(&signed, &root_pem).is_err());
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        assert!(expires(&signed).unwrap() > now + 4 * 365 * 86_400);
+        assert!(!serial(&signed).unwrap().starts_with('0'));
+    }
+
+    #[test]
+    fn test_sign_csr_cfssl() {
+        let fixtures = [
+            (
+                include_str!("../tests/fixtures/cfssl-ca.pem"),
+                include_str!("../tests/fixtures/cfssl-ca-key.pem"),
+            ),
+            (
+                include_str!("../tests/fixtures/cfssl-ca-rsa.pem"),
+                include_str!("../tests/fixtures/cfssl-ca-rsa-key.pem"),
+            ),
+        ];
+        let issuing = Certificate::from_params(CertificateParams::new(vec![])).unwrap();
+        let csr = issuing.serialize_request_pem().unwrap();
+
+        for (ca, key) in fixtures.iter() {
+            let signed = sign_csr(ca, key, &csr).unwrap();
+            verify_chain(ca, &signed).unwrap();
+
+            let (subject, key_id) = with_x509(ca, |ca| {
+                let key_id = ca
+                    .extensions()
+                    .iter()
+                    .find_map(|ext| match ext.parsed_extension() {
+                        ParsedExtension::SubjectKeyIdentifier(key_id) => Some(key_id.0.to_vec()),
+                        _ => None,
+                    });
+                Ok((ca.subject().as_raw().to_vec(), key_id.unwrap()))
+            })
+            .unwrap();
+            with_x509(&signed, |cert| {
+                assert_eq!(cert.issuer().as_raw(), &subject[..]);
+                let authority =
+                    cert.extensions()
+                        .iter()
+                        .find_map(|ext| match ext.parsed_extension() {
+                            ParsedExtension::AuthorityKeyIdentifier(aki) => {
+                                aki.key_identifier.as_ref().map(|key_id| key_id.0.to_vec())
+                            }
+                            _ => None,
+                        });
+                assert_eq!(authority, Some(key_id.clone()));
+                Ok(())
+            })
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_verify_root() {
+        let root = Certificate::from_params(CertificateParams::new(vec![])).unwrap();
+        let root_pem = root.serialize_pem().unwrap();
+        let fingerprint = pem_fingerprint(&root_pem).unwrap();
+
+        let bare = fingerprint.replace(':', "").to_lowercase();
+        assert_eq!(verify_root(&root_pem, Some(&bare)).unwrap(), fingerprint);
+        assert!(verify_root(&root_pem, Some("AB:CD")).is_err());
+    }
+
+    #[test]
+    fn test_expiry() {
+        let day = 86_400;
+        assert_eq!(Expiry::of(100 * day, 0, 60, 30), Expiry::Valid);
+        assert_eq!(Expiry::of(45 * day, 0, 60, 30), Expiry::Soon);
+        assert_eq!(Expiry::of(30 * day, 0, 60, 30), Expiry::Renew);
+        assert_eq!(Expiry::of(day, 2 * day, 60, 30), Expiry::Expired);
+        assert!(Expiry::Unknown.is_failure());
+    }
 }
@@ -0,0 +1,222 @@
+use std::{collections::HashMap, env, fs, path::Path};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    acme::AcmeConfig,
+    budget::BudgetConfig,
+    consul::ConsulConfig,
+    endpoint::{Service, ServiceAddrs, ServiceTls},
+    login::LoginConfig,
+    nomad::NomadApi,
+    policy::PolicyConfig,
+    pool::ClientPool,
+    preflight::PreflightConfig,
+    runbook::Runbook,
+    snapshot::SnapshotConfig,
+    terraform::TerraformBackend,
+};
+
+/// Settings of the bitte CLI, kept next to the cluster's flake in `bitte.json`.
+///
+/// Every section is optional, so a missing file behaves like an empty one.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct BitteConfig {
+    pub terraform: TerraformConfig,
+    /// Other clusters this one interacts with, e.g. for job promotion.
+    pub clusters: HashMap<String, ClusterConfig>,
+    pub promotions: Vec<PromotionRule>,
+    pub runbooks: HashMap<String, Runbook>,
+    /// Short names for whole invocations, e.g. `"web": "ssh --clients --parallel '{cmd}'"`.
+    pub aliases: HashMap<String, String>,
+    pub flake_attrs: FlakeAttrConfig,
+    /// Rules checked before any subcommand that changes a cluster.
+    pub policy: PolicyConfig,
+    /// Limits on the API requests of a single invocation.
+    pub budget: BudgetConfig,
+    /// Addresses of the services of this cluster, if they aren't reached through its domain.
+    pub addresses: ServiceAddrs,
+    /// CA and client certificates for the services of this cluster, if they require them.
+    pub tls: ServiceTls,
+    /// Templates of the Consul tokens `bitte consul token create` makes.
+    pub consul: ConsulConfig,
+    /// The public certificates `bitte certs acme` issues.
+    pub acme: AcmeConfig,
+    /// What `bitte provision` checks before touching a node.
+    pub preflight: PreflightConfig,
+    /// Where `bitte backup` keeps the snapshots of Vault and Consul.
+    pub snapshots: SnapshotConfig,
+    /// How long runs log in to Vault again.
+    pub vault_login: LoginConfig,
+}
+
+/// How the flake attr of nodes without a `UID` tag is inferred from their role.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct FlakeAttrConfig {
+    /// The instance tag holding the role of a node.
+    pub role_tag: String,
+    /// Flake attr per role, `{name}` is replaced with the name of the node.
+    pub roles: HashMap<String, String>,
+}
+
+impl Default for FlakeAttrConfig {
+    fn default() -> Self {
+        Self {
+            role_tag: "Role".to_string(),
+            roles: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct TerraformConfig {
+    pub backend: TerraformBackend,
+    /// Workspaces in the order `bitte tf apply-all` applies them.
+    pub workspaces: Vec<String>,
+    /// Variables `bitte tf <workspace> init --bootstrap` sets on remote workspaces.
+    pub variables: Vec<WorkspaceVariable>,
+}
+
+/// A variable every remote workspace needs, e.g. the credentials of the cloud provider.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct WorkspaceVariable {
+    pub key: String,
+    /// Either `env` (the default) or `terraform`.
+    #[serde(default = "default_variable_category")]
+    pub category: String,
+    /// Take the value from this local environment variable.
+    pub from_env: Option<String>,
+    pub value: Option<String>,
+    #[serde(default)]
+    pub sensitive: bool,
+    #[serde(default)]
+    pub hcl: bool,
+}
+
+impl Default for TerraformConfig {
+    fn default() -> Self {
+        Self {
+            backend: TerraformBackend::default(),
+            workspaces: vec![
+                "network".to_string(),
+                "core".to_string(),
+                "clients".to_string(),
+                "hydrate".to_string(),
+            ],
+            variables: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ClusterConfig {
+    pub domain: String,
+    /// Environment variable holding the Nomad token for this cluster.
+    #[serde(default = "default_nomad_token_env")]
+    pub nomad_token_env: String,
+    /// Addresses of the services of this cluster, if they aren't reached through its domain.
+    #[serde(default)]
+    pub addresses: ServiceAddrs,
+    /// CA and client certificates for the services of this cluster, if they require them.
+    #[serde(default)]
+    pub tls: ServiceTls,
+}
+
+/// How namespaces and datacenters are renamed when a job moves from one cluster to another.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct PromotionRule {
+    pub from: String,
+    pub to: String,
+    pub namespaces: HashMap<String, String>,
+    pub datacenters: HashMap<String, String>,
+}
+
+fn default_variable_category() -> String {
+    "env".to_string()
+}
+
+impl WorkspaceVariable {
+    pub fn resolve(&self) -> Result<String> {
+        match (&self.from_env, &self.value) {
+            (Some(name), _) => crate::get_env(name),
+            (None, Some(value)) => Ok(value.clone()),
+            (None, None) => Err(anyhow!(
+                "variable {} has neither from-env nor value",
+                self.key
+            )),
+        }
+    }
+}
+
+fn default_nomad_token_env() -> String {
+    "NOMAD_TOKEN".to_string()
+}
+
+impl ClusterConfig {
+    pub fn nomad(&self, pool: &ClientPool) -> Result<NomadApi> {
+        let token = crate::get_env(&self.nomad_token_env)?;
+        NomadApi::new(
+            pool,
+            &self
+                .addresses
+                .address(Service::Nomad, &self.domain, &self.tls.nomad),
+            &token,
+            &self.tls.nomad,
+        )
+    }
+}
+
+impl BitteConfig {
+    pub fn cluster(&self, name: &str) -> Result<&ClusterConfig> {
+        self.clusters.get(name).ok_or_else(|| {
+            let mut known: Vec<&String> = self.clusters.keys().collect();
+            known.sort();
+            anyhow!(
+                "cluster {} is not configured in bitte.json, known clusters: {:?}",
+                name,
+                known
+            )
+        })
+    }
+
+    pub fn runbook(&self, name: &str) -> Result<&Runbook> {
+        self.runbooks.get(name).ok_or_else(|| {
+            let mut known: Vec<&String> = self.runbooks.keys().collect();
+            known.sort();
+            anyhow!(
+                "runbook {} is not configured in bitte.json, known runbooks: {:?}",
+                name,
+                known
+            )
+        })
+    }
+
+    pub fn promotion(&self, from: &str, to: &str) -> PromotionRule {
+        self.promotions
+            .iter()
+            .find(|rule| rule.from == from && rule.to == to)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Read `bitte.json` from the current directory, or the file named by `BITTE_CONFIG`.
+    pub fn load() -> Result<Self> {
+        let path = env::var("BITTE_CONFIG").unwrap_or_else(|_| "bitte.json".to_string());
+        let path = Path::new(&path);
+
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+
+        let file = fs::read_to_string(path)
+            .with_context(|| format!("couldn't read {}", path.display()))?;
+        serde_json::from_str(&file).with_context(|| format!("couldn't parse {}", path.display()))
+    }
+}
@@ -1,10 +1,23 @@
-use crate::types::ConsulAclTokenRead;
+use std::{
+    collections::{BTreeMap, HashMap},
+    env, fs,
+    path::Path,
+};
+
+use crate::endpoint::{self, Service};
+use crate::pool::ClientPool;
+use crate::types::{ConsulAclLink, ConsulAclTokenRead, ConsulServiceIdentity};
+use crate::vault;
 use crate::Result;
+use anyhow::{anyhow, Context};
+use reqwest::{Method, RequestBuilder, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 use super::sh;
 
 // TODO: check that we have developer or admin policies
-pub fn consul_token() -> Result<String> {
+pub async fn consul_token() -> Result<String> {
     match sh(execute::command_args!(
         "consul", "acl", "token", "read", "-self", "-format", "json"
     )) {
@@ -12,18 +25,280 @@ pub fn consul_token() -> Result<String> {
             let read: ConsulAclTokenRead = serde_json::from_str(output.as_str())?;
             Ok(read.secret_id)
         }
-        Err(_err) => issue_consul_token(),
+        Err(_err) => Ok(issue_consul_token().await?.token),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConsulCreds {
+    pub accessor: String,
+    pub token: String,
+}
+
+/// Lease a Consul token from the Consul secrets engine of Vault.
+pub async fn issue_consul_token() -> Result<ConsulCreds> {
+    Ok(vault::read::<ConsulCreds>("consul/creds/developer")
+        .await?
+        .data)
+}
+
+/// The Consul token of the session: `CONSUL_HTTP_TOKEN`, otherwise the one `consul_token` finds.
+pub async fn token() -> Result<String> {
+    match env::var("CONSUL_HTTP_TOKEN") {
+        Ok(token) if !token.is_empty() => Ok(token),
+        _ => consul_token().await,
+    }
+}
+
+fn anonymous_request(method: Method, domain: &str, path: &str) -> Result<RequestBuilder> {
+    let address = endpoint::address(Service::Consul, domain);
+    let client = ClientPool::shared().service(Service::Consul, &address)?;
+    Ok(client.request(method, &format!("{}/v1/{}", address, path)))
+}
+
+/// A request to `/v1/<path>` of the Consul of the cluster at `domain`, with the token of the
+/// session.
+pub async fn request(method: Method, domain: &str, path: &str) -> Result<RequestBuilder> {
+    Ok(anonymous_request(method, domain, path)?.header("X-Consul-Token", token().await?))
+}
+
+/// The `consul` section of `bitte.json`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct ConsulConfig {
+    pub token_templates: HashMap<String, TokenTemplate>,
+}
+
+impl ConsulConfig {
+    pub fn template(&self, name: &str) -> Result<&TokenTemplate> {
+        self.token_templates.get(name).ok_or_else(|| {
+            let mut known: Vec<&String> = self.token_templates.keys().collect();
+            known.sort();
+            anyhow!(
+                "token template {} is not configured in bitte.json, known templates: {:?}",
+                name,
+                known
+            )
+        })
+    }
+}
+
+/// What a Consul token is allowed to do, e.g. for a service:
+/// `{ "description": "{name} service", "service-identities": ["{name}"] }`. `{name}` is replaced
+/// with the name the token is created for.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct TokenTemplate {
+    pub description: String,
+    pub policies: Vec<String>,
+    pub service_identities: Vec<String>,
+    /// Keep the token in the local datacenter instead of replicating it.
+    pub local: bool,
+}
+
+impl TokenTemplate {
+    pub fn render(&self, name: &str) -> Self {
+        let fill = |text: &String| text.replace("{name}", name);
+        Self {
+            description: fill(&self.description),
+            policies: self.policies.iter().map(fill).collect(),
+            service_identities: self.service_identities.iter().map(fill).collect(),
+            local: self.local,
+        }
+    }
+}
+
+/// Bootstrap the ACL system of the Consul at `domain`, returning the initial management token.
+/// Returns `None` if it was bootstrapped before.
+pub async fn bootstrap(domain: &str) -> Result<Option<ConsulAclTokenRead>> {
+    let response = anonymous_request(Method::PUT, domain, "acl/bootstrap")?
+        .send()
+        .await?;
+    if response.status() == StatusCode::FORBIDDEN {
+        let body = response.text().await.unwrap_or_default();
+        if body.contains("no longer allowed") {
+            return Ok(None);
+        }
+        return Err(anyhow!("consul refused the ACL bootstrap: {}", body.trim()));
+    }
+
+    let token = response
+        .error_for_status()
+        .context("couldn't bootstrap the consul ACLs")?
+        .json()
+        .await?;
+    Ok(Some(token))
+}
+
+/// Keep the bootstrap token in `path`, only readable by the user. It is the only way back into
+/// the ACL system short of resetting it.
+pub fn save_token(path: &Path, token: &ConsulAclTokenRead) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    crate::write_private(path, serde_json::to_string_pretty(token)?)
+        .with_context(|| format!("couldn't write {}", path.display()))?;
+    Ok(())
+}
+
+/// Create a token with what `template` allows.
+pub async fn create_token(domain: &str, template: &TokenTemplate) -> Result<ConsulAclTokenRead> {
+    let token = ConsulAclTokenRead {
+        description: template.description.clone(),
+        policies: Some(
+            template
+                .policies
+                .iter()
+                .map(|name| ConsulAclLink {
+                    id: String::new(),
+                    name: name.clone(),
+                })
+                .collect(),
+        ),
+        service_identities: Some(
+            template
+                .service_identities
+                .iter()
+                .map(|name| ConsulServiceIdentity {
+                    service_name: name.clone(),
+                })
+                .collect(),
+        ),
+        local: template.local,
+        ..ConsulAclTokenRead::default()
+    };
+
+    Ok(request(Method::PUT, domain, "acl/token")
+        .await?
+        .json(&token)
+        .send()
+        .await?
+        .error_for_status()
+        .context("consul refused to create the token")?
+        .json()
+        .await?)
+}
+
+/// The tokens of the Consul at `domain`, without their secrets.
+pub async fn list_tokens(domain: &str) -> Result<Vec<ConsulAclTokenRead>> {
+    Ok(request(Method::GET, domain, "acl/tokens")
+        .await?
+        .send()
+        .await?
+        .error_for_status()
+        .context("couldn't list the consul tokens")?
+        .json()
+        .await?)
+}
+
+pub async fn revoke_token(domain: &str, accessor: &str) -> Result<()> {
+    request(Method::DELETE, domain, &format!("acl/token/{}", accessor))
+        .await?
+        .send()
+        .await?
+        .error_for_status()
+        .with_context(|| format!("couldn't revoke the consul token {}", accessor))?;
+    Ok(())
+}
+
+/// A service of the catalog, with the health of its instances. An instance counts with the
+/// worst status of its checks.
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ServiceHealth {
+    pub name: String,
+    pub tags: Vec<String>,
+    pub nodes: Vec<String>,
+    pub instances: usize,
+    pub passing: usize,
+    pub warning: usize,
+    pub critical: usize,
+}
+
+impl ServiceHealth {
+    /// Summarize the entries of `/v1/health/service/<name>`.
+    pub fn from_entries(name: &str, entries: &[Value]) -> Self {
+        let mut health = Self {
+            name: name.to_string(),
+            ..Self::default()
+        };
+
+        for entry in entries.iter() {
+            health.instances += 1;
+            let statuses: Vec<&str> = entry["Checks"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|check| check["Status"].as_str())
+                .collect();
+            if statuses.contains(&"critical") {
+                health.critical += 1;
+            } else if statuses.contains(&"warning") {
+                health.warning += 1;
+            } else {
+                health.passing += 1;
+            }
+
+            if let Some(node) = entry["Node"]["Node"].as_str() {
+                if !health.nodes.iter().any(|known| known == node) {
+                    health.nodes.push(node.to_string());
+                }
+            }
+            for tag in entry["Service"]["Tags"].as_array().into_iter().flatten() {
+                if let Some(tag) = tag.as_str() {
+                    if !health.tags.iter().any(|known| known == tag) {
+                        health.tags.push(tag.to_string());
+                    }
+                }
+            }
+        }
+
+        health.nodes.sort();
+        health.tags.sort();
+        health
     }
 }
 
-fn issue_consul_token() -> Result<String> {
-    sh(execute::command_args!(
-        "vault",
-        "read",
-        "-field",
-        "token",
-        "consul/creds/developer"
-    ))
+/// The services registered in the catalog, optionally only their instances on `node` or with
+/// `tag`. Services without such instances are left out.
+pub async fn services(
+    domain: &str,
+    node: Option<&str>,
+    tag: Option<&str>,
+) -> Result<Vec<ServiceHealth>> {
+    let catalog: BTreeMap<String, Value> = request(Method::GET, domain, "catalog/services")
+        .await?
+        .send()
+        .await?
+        .error_for_status()
+        .context("couldn't read the consul catalog")?
+        .json()
+        .await?;
+
+    let mut services = Vec::new();
+    for name in catalog.keys() {
+        let mut query = Vec::new();
+        if let Some(node) = node {
+            query.push(("filter", format!("Node.Node == {:?}", node)));
+        }
+        if let Some(tag) = tag {
+            query.push(("tag", tag.to_string()));
+        }
+
+        let entries: Vec<Value> = request(Method::GET, domain, &format!("health/service/{}", name))
+            .await?
+            .query(&query)
+            .send()
+            .await?
+            .error_for_status()
+            .with_context(|| format!("couldn't read the health of {}", name))?
+            .json()
+            .await?;
+        if !entries.is_empty() {
+            services.push(ServiceHealth::from_entries(name, &entries));
+        }
+    }
+
+    Ok(services)
 }
 
 /*
@@ -46,3 +321,210 @@ Example output of `consul acl token read -self -format json`:
     "Hash": "6uzWRYtYfw4elm32i5K0NFrg353DeQiJ4jew4edkwQw="
 }
 */
+
+/// A Connect intention, allowing or denying traffic from one service to another. Either name
+/// may be `*` for every service.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Intention {
+    pub source_name: String,
+    pub destination_name: String,
+    #[serde(default)]
+    pub action: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub precedence: u32,
+}
+
+/// The precedence Consul gives an intention between `source` and `destination`: exact names
+/// win over wildcards, and the destination counts more than the source.
+pub fn precedence(source: &str, destination: &str) -> u32 {
+    match (source == "*", destination == "*") {
+        (false, false) => 9,
+        (true, false) => 8,
+        (false, true) => 6,
+        (true, true) => 5,
+    }
+}
+
+/// Whether two names may match the same service.
+fn overlaps(a: &str, b: &str) -> bool {
+    a == "*" || b == "*" || a == b
+}
+
+/// What an intention from `source` to `destination` does to the existing ones.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct IntentionImpact {
+    /// The intention between the same names, which is replaced.
+    pub replaces: Option<Intention>,
+    /// Intentions of lower precedence that no longer decide the traffic the new one covers.
+    pub shadows: Vec<Intention>,
+    /// Intentions of higher precedence that keep deciding part of the traffic it covers.
+    pub shadowed_by: Vec<Intention>,
+}
+
+pub fn intention_impact(
+    existing: &[Intention],
+    source: &str,
+    destination: &str,
+) -> IntentionImpact {
+    let own = precedence(source, destination);
+    let mut impact = IntentionImpact::default();
+
+    for intention in existing.iter() {
+        if intention.source_name == source && intention.destination_name == destination {
+            impact.replaces = Some(intention.clone());
+            continue;
+        }
+        if !overlaps(&intention.source_name, source)
+            || !overlaps(&intention.destination_name, destination)
+        {
+            continue;
+        }
+
+        let theirs = precedence(&intention.source_name, &intention.destination_name);
+        if theirs < own {
+            impact.shadows.push(intention.clone());
+        } else if theirs > own {
+            impact.shadowed_by.push(intention.clone());
+        }
+    }
+
+    impact
+}
+
+pub async fn intentions(domain: &str) -> Result<Vec<Intention>> {
+    Ok(request(Method::GET, domain, "connect/intentions")
+        .await?
+        .send()
+        .await?
+        .error_for_status()
+        .context("couldn't list the consul intentions")?
+        .json()
+        .await?)
+}
+
+/// Allow or deny traffic from `source` to `destination`, replacing an intention between them.
+pub async fn set_intention(
+    domain: &str,
+    source: &str,
+    destination: &str,
+    allow: bool,
+    description: &str,
+) -> Result<()> {
+    request(Method::PUT, domain, "connect/intentions/exact")
+        .await?
+        .query(&[("source", source), ("destination", destination)])
+        .json(&serde_json::json!({
+            "SourceType": "consul",
+            "Action": if allow { "allow" } else { "deny" },
+            "Description": description,
+        }))
+        .send()
+        .await?
+        .error_for_status()
+        .with_context(|| {
+            format!(
+                "couldn't write the intention from {} to {}",
+                source, destination
+            )
+        })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{intention_impact, Intention, IntentionImpact, ServiceHealth, TokenTemplate};
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    #[test]
+    fn test_token_template() {
+        let template = TokenTemplate {
+            description: "{name} service".to_string(),
+            policies: vec!["read-kv".to_string()],
+            service_identities: vec!["{name}".to_string()],
+            local: false,
+        };
+        assert_eq!(
+            template.render("web"),
+            TokenTemplate {
+                description: "web service".to_string(),
+                policies: vec!["read-kv".to_string()],
+                service_identities: vec!["web".to_string()],
+                local: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_service_health() {
+        let entries = vec![
+            json!({
+                "Node": { "Node": "core-1" },
+                "Service": { "Tags": ["primary"] },
+                "Checks": [{ "Status": "passing" }, { "Status": "warning" }]
+            }),
+            json!({
+                "Node": { "Node": "core-2" },
+                "Service": { "Tags": ["standby"] },
+                "Checks": [{ "Status": "critical" }]
+            }),
+            json!({
+                "Node": { "Node": "core-2" },
+                "Service": { "Tags": [] },
+                "Checks": [{ "Status": "passing" }]
+            }),
+        ];
+        assert_eq!(
+            ServiceHealth::from_entries("vault", &entries),
+            ServiceHealth {
+                name: "vault".to_string(),
+                tags: vec!["primary".to_string(), "standby".to_string()],
+                nodes: vec!["core-1".to_string(), "core-2".to_string()],
+                instances: 3,
+                passing: 1,
+                warning: 1,
+                critical: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_intention_impact() {
+        let intention = |source: &str, destination: &str| Intention {
+            source_name: source.to_string(),
+            destination_name: destination.to_string(),
+            action: "deny".to_string(),
+            ..Intention::default()
+        };
+        let existing = vec![
+            intention("*", "*"),
+            intention("*", "db"),
+            intention("web", "db"),
+            intention("web", "cache"),
+        ];
+
+        assert_eq!(
+            intention_impact(&existing, "web", "*"),
+            IntentionImpact {
+                replaces: None,
+                shadows: vec![intention("*", "*")],
+                shadowed_by: vec![
+                    intention("*", "db"),
+                    intention("web", "db"),
+                    intention("web", "cache"),
+                ],
+            }
+        );
+        assert_eq!(
+            intention_impact(&existing, "web", "db"),
+            IntentionImpact {
+                replaces: Some(intention("web", "db")),
+                shadows: vec![intention("*", "*"), intention("*", "db")],
+                shadowed_by: Vec::new(),
+            }
+        );
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Context, Result};
+use aws_sdk_ec2::model::InstanceType;
+use aws_sdk_pricing::model::{Filter, FilterType};
+use log::warn;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+    budget::{self, Provider},
+    pool::ClientPool,
+    types::BitteNode,
+};
+
+/// Hours in a month, as AWS bills them.
+pub const HOURS_PER_MONTH: f64 = 730.0;
+
+/// What one node costs, going by the list prices.
+#[derive(Debug, Clone, Serialize)]
+pub struct NodeCost {
+    pub node: String,
+    pub instance_type: String,
+    pub region: String,
+    pub spot: bool,
+    /// US dollars per hour, none when the price couldn't be found.
+    pub hourly: Option<f64>,
+}
+
+/// The costs of the nodes and their sum.
+#[derive(Debug, Serialize)]
+pub struct CostReport {
+    pub nodes: Vec<NodeCost>,
+    pub hourly: f64,
+    pub monthly: f64,
+    /// Nodes without a price, left out of the sums.
+    pub unknown: usize,
+}
+
+impl CostReport {
+    pub fn new(nodes: Vec<NodeCost>) -> Self {
+        let hourly: f64 = nodes.iter().filter_map(|node| node.hourly).sum();
+        let unknown = nodes.iter().filter(|node| node.hourly.is_none()).count();
+        Self {
+            nodes,
+            hourly,
+            monthly: hourly * HOURS_PER_MONTH,
+            unknown,
+        }
+    }
+}
+
+/// The hourly on-demand price in the products of `GetProducts`, which are JSON documents
+/// with the price under `terms.OnDemand.<offer>.priceDimensions.<rate>.pricePerUnit.USD`.
+pub fn parse_on_demand(price_list: &[String]) -> Option<f64> {
+    price_list
+        .iter()
+        .filter_map(|product| serde_json::from_str::<Value>(product).ok())
+        .flat_map(|product| {
+            let offers = product["terms"]["OnDemand"].as_object().cloned();
+            offers.into_iter().flat_map(|offers| offers.into_iter())
+        })
+        .flat_map(|(_, offer)| {
+            let rates = offer["priceDimensions"].as_object().cloned();
+            rates.into_iter().flat_map(|rates| rates.into_iter())
+        })
+        .filter_map(|(_, rate)| rate["pricePerUnit"]["USD"].as_str()?.parse::<f64>().ok())
+        // reserved capacity and the like are listed for free
+        .find(|price| *price > 0.0)
+}
+
+/// The on-demand price of a Linux `instance_type` in `region`. The Pricing API is only served
+/// from us-east-1.
+async fn on_demand(
+    pricing: &aws_sdk_pricing::Client,
+    instance_type: &str,
+    region: &str,
+) -> Result<f64> {
+    let filter = |field: &str, value: &str| {
+        Filter::builder()
+            .field(field)
+            .r#type(FilterType::TermMatch)
+            .value(value)
+            .build()
+    };
+    let request = pricing
+        .get_products()
+        .service_code("AmazonEC2")
+        .filters(filter("instanceType", instance_type))
+        .filters(filter("regionCode", region))
+        .filters(filter("operatingSystem", "Linux"))
+        .filters(filter("tenancy", "Shared"))
+        .filters(filter("preInstalledSw", "NA"))
+        .filters(filter("capacitystatus", "Used"));
+
+    budget::acquire(Provider::Aws).await?;
+    let response = request
+        .send()
+        .await
+        .with_context(|| format!("couldn't look up the price of {}", instance_type))?;
+    parse_on_demand(&response.price_list.unwrap_or_default())
+        .with_context(|| format!("there is no price for {} in {}", instance_type, region))
+}
+
+/// The current spot price of a Linux `instance_type` in the availability zone `zone`.
+async fn spot(pool: &ClientPool, instance_type: &str, region: &str, zone: &str) -> Result<f64> {
+    let client = pool.ec2(region).await;
+    let request = client
+        .describe_spot_price_history()
+        .availability_zone(zone)
+        .instance_types(InstanceType::from(instance_type))
+        .product_descriptions("Linux/UNIX");
+
+    budget::acquire(Provider::Aws).await?;
+    let response = request
+        .send()
+        .await
+        .with_context(|| format!("couldn't look up the spot price of {}", instance_type))?;
+    response
+        .spot_price_history
+        .unwrap_or_default()
+        .into_iter()
+        .max_by_key(|price| price.timestamp.map(|timestamp| timestamp.secs()))
+        .and_then(|price| price.spot_price?.parse().ok())
+        .with_context(|| format!("there is no spot price for {} in {}", instance_type, zone))
+}
+
+/// The hourly cost of each of `nodes`: the spot price of the zone for spot instances, the
+/// on-demand price for the others. Each price is looked up once.
+pub async fn node_costs(pool: &ClientPool, nodes: &[BitteNode]) -> Vec<NodeCost> {
+    let pricing = pool.pricing().await;
+    let mut prices: HashMap<(String, String, Option<String>), Option<f64>> = HashMap::new();
+    let mut costs = Vec::new();
+
+    for node in nodes.iter() {
+        let instance_type = node.node_type.clone().unwrap_or_default();
+        let region = node.region().unwrap_or_default();
+        let is_spot = node.lifecycle.as_deref() == Some("spot");
+        let zone = node.zone.clone().filter(|_| is_spot);
+
+        let key = (instance_type.clone(), region.clone(), zone.clone());
+        let hourly = match prices.get(&key) {
+            Some(price) => *price,
+            None => {
+                let found = match &zone {
+                    _ if instance_type.is_empty() || region.is_empty() => {
+                        Err(anyhow!("{} has no instance type or region", node.name))
+                    }
+                    Some(zone) => spot(pool, &instance_type, &region, zone).await,
+                    None => on_demand(&pricing, &instance_type, &region).await,
+                };
+                let price = match found {
+                    Ok(price) => Some(price),
+                    Err(e) => {
+                        warn!("{:?}", e);
+                        None
+                    }
+                };
+                prices.insert(key, price);
+                price
+            }
+        };
+
+        costs.push(NodeCost {
+            node: node.name.clone(),
+            instance_type,
+            region,
+            spot: is_spot,
+            hourly,
+        });
+    }
+    costs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_on_demand, CostReport, NodeCost};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_costs() {
+        let product = r#"{"product": {"attributes": {"instanceType": "t3.xlarge"}},
+            "terms": {"OnDemand": {"ABC.JRT": {"priceDimensions": {"ABC.JRT.6YS": {
+                "unit": "Hrs", "pricePerUnit": {"USD": "0.1920000000"}}}}}}}"#;
+        let free = r#"{"terms": {"OnDemand": {"DEF.JRT": {"priceDimensions": {"DEF.JRT.6YS": {
+                "unit": "Hrs", "pricePerUnit": {"USD": "0.0000000000"}}}}}}}"#;
+        let list = vec![free.to_string(), product.to_string()];
+        assert_eq!(parse_on_demand(&list), Some(0.192));
+        assert_eq!(parse_on_demand(&list[..1]), None);
+
+        let cost = |node: &str, hourly: Option<f64>| NodeCost {
+            node: node.to_string(),
+            instance_type: "t3.xlarge".to_string(),
+            region: "eu-central-1".to_string(),
+            spot: false,
+            hourly,
+        };
+        let report = CostReport::new(vec![
+            cost("core-1", Some(0.25)),
+            cost("core-2", Some(0.25)),
+            cost("client-a", None),
+        ]);
+        assert_eq!(report.hourly, 0.5);
+        assert_eq!(report.monthly, 365.0);
+        assert_eq!(report.unknown, 1);
+    }
+}
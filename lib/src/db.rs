@@ -0,0 +1,262 @@
+use std::{
+    fs,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use rusqlite::{params, types::ValueRef, Connection};
+
+use crate::history::HistoryEntry;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS history (
+    time INTEGER NOT NULL,
+    cluster TEXT NOT NULL,
+    user TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    details TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS runs (
+    started INTEGER NOT NULL,
+    seconds REAL NOT NULL,
+    cluster TEXT,
+    command TEXT NOT NULL,
+    success INTEGER NOT NULL,
+    error TEXT
+);
+CREATE TABLE IF NOT EXISTS node_events (
+    time INTEGER NOT NULL,
+    cluster TEXT NOT NULL,
+    node TEXT NOT NULL,
+    event TEXT NOT NULL,
+    seconds REAL NOT NULL,
+    success INTEGER NOT NULL
+);
+";
+
+/// Queries available by name with `bitte query <name>`.
+pub const CANNED_QUERIES: &[(&str, &str, &str)] = &[
+    (
+        "deploys-per-week",
+        "rebuilds, deploys, and job promotions per week",
+        "SELECT strftime('%Y-%W', started, 'unixepoch') AS week, cluster, command,
+                count(*) AS runs
+         FROM runs
+         WHERE command IN ('rebuild', 'deploy', 'job')
+         GROUP BY week, cluster, command
+         ORDER BY week DESC, cluster, command",
+    ),
+    (
+        "rebuild-duration",
+        "mean duration of successful rebuilds per node",
+        "SELECT cluster, node, count(*) AS rebuilds, round(avg(seconds), 1) AS mean_seconds,
+                round(max(seconds), 1) AS max_seconds
+         FROM node_events
+         WHERE event = 'rebuild' AND success
+         GROUP BY cluster, node
+         ORDER BY mean_seconds DESC",
+    ),
+    (
+        "failure-rate",
+        "share of failed invocations per subcommand",
+        "SELECT command, count(*) AS runs, sum(NOT success) AS failed,
+                round(100.0 * sum(NOT success) / count(*), 1) AS failed_percent
+         FROM runs
+         GROUP BY command
+         ORDER BY failed_percent DESC, runs DESC",
+    ),
+];
+
+/// The result of a query, with every value rendered as text.
+#[derive(Debug, PartialEq)]
+pub struct QueryResult {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<String>>,
+}
+
+pub fn state_root() -> Result<PathBuf> {
+    match std::env::var("XDG_STATE_HOME") {
+        Ok(dir) => Ok(PathBuf::from(dir)),
+        Err(_) => Ok(PathBuf::from(crate::get_env("HOME")?)
+            .join(".local")
+            .join("state")),
+    }
+}
+
+pub fn db_file() -> Result<PathBuf> {
+    let dir = state_root()?.join("bitte");
+    fs::create_dir_all(&dir)?;
+    Ok(dir.join("history.db"))
+}
+
+/// Open the history database of this machine, creating its tables on first use.
+pub fn open() -> Result<Connection> {
+    let path = db_file()?;
+    let conn =
+        Connection::open(&path).with_context(|| format!("couldn't open {}", path.display()))?;
+    init(&conn)?;
+    Ok(conn)
+}
+
+fn init(conn: &Connection) -> Result<()> {
+    conn.execute_batch(SCHEMA)?;
+    Ok(())
+}
+
+fn now() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+pub fn insert_history(conn: &Connection, entry: &HistoryEntry) -> Result<()> {
+    conn.execute(
+        "INSERT INTO history (time, cluster, user, kind, details) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            entry.time as i64,
+            entry.cluster,
+            entry.user,
+            entry.kind,
+            entry.details.to_string()
+        ],
+    )?;
+    Ok(())
+}
+
+/// Record one invocation of a subcommand that took `elapsed` and failed with `error`, if any.
+pub fn record_run(
+    cluster: Option<&str>,
+    command: &str,
+    elapsed: Duration,
+    error: Option<String>,
+) -> Result<()> {
+    let conn = open()?;
+    let started = now()?.saturating_sub(elapsed.as_secs());
+    conn.execute(
+        "INSERT INTO runs (started, seconds, cluster, command, success, error)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            started as i64,
+            elapsed.as_secs_f64(),
+            cluster,
+            command,
+            error.is_none(),
+            error
+        ],
+    )?;
+    Ok(())
+}
+
+/// Record something that happened to a single node, like a rebuild or a reboot.
+pub fn record_node_event(
+    cluster: &str,
+    node: &str,
+    event: &str,
+    elapsed: Duration,
+    success: bool,
+) -> Result<()> {
+    let conn = open()?;
+    conn.execute(
+        "INSERT INTO node_events (time, cluster, node, event, seconds, success)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            now()? as i64,
+            cluster,
+            node,
+            event,
+            elapsed.as_secs_f64(),
+            success
+        ],
+    )?;
+    Ok(())
+}
+
+/// Run `sql`, which may be the name of one of the `CANNED_QUERIES`.
+pub fn query(conn: &Connection, sql: &str) -> Result<QueryResult> {
+    let sql = CANNED_QUERIES
+        .iter()
+        .find(|(name, _, _)| *name == sql)
+        .map_or(sql, |(_, _, query)| *query);
+
+    // only queries that don't modify the database are allowed
+    conn.execute_batch("PRAGMA query_only = ON")?;
+    let result = select(conn, sql);
+    conn.execute_batch("PRAGMA query_only = OFF")?;
+    result
+}
+
+fn select(conn: &Connection, sql: &str) -> Result<QueryResult> {
+    let mut statement = conn.prepare(sql)?;
+    let columns: Vec<String> = statement
+        .column_names()
+        .into_iter()
+        .map(String::from)
+        .collect();
+    let count = columns.len();
+
+    let rows = statement
+        .query_map([], |row| {
+            (0..count)
+                .map(|index| {
+                    Ok(match row.get_ref(index)? {
+                        ValueRef::Null => String::new(),
+                        ValueRef::Integer(value) => value.to_string(),
+                        // keep the fraction of whole numbers, so reals read as reals
+                        ValueRef::Real(value) => format!("{:?}", value),
+                        ValueRef::Text(value) => String::from_utf8_lossy(value).to_string(),
+                        ValueRef::Blob(value) => format!("<{} bytes>", value.len()),
+                    })
+                })
+                .collect()
+        })?
+        .collect::<Result<Vec<Vec<String>>, _>>()?;
+
+    Ok(QueryResult { columns, rows })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{init, query, QueryResult};
+    use pretty_assertions::assert_eq;
+    use rusqlite::Connection;
+
+    #[test]
+    fn test_failure_rate() {
+        let conn = Connection::open_in_memory().unwrap();
+        init(&conn).unwrap();
+        conn.execute_batch(
+            "INSERT INTO runs (started, seconds, cluster, command, success, error) VALUES
+               (0, 1.0, 'testnet', 'rebuild', 1, NULL),
+               (0, 1.0, 'testnet', 'rebuild', 0, 'ssh failed'),
+               (0, 1.0, 'testnet', 'info', 1, NULL);",
+        )
+        .unwrap();
+
+        assert_eq!(
+            query(&conn, "failure-rate").unwrap(),
+            QueryResult {
+                columns: vec![
+                    "command".to_string(),
+                    "runs".to_string(),
+                    "failed".to_string(),
+                    "failed_percent".to_string()
+                ],
+                rows: vec![
+                    vec![
+                        "rebuild".to_string(),
+                        "2".to_string(),
+                        "1".to_string(),
+                        "50.0".to_string()
+                    ],
+                    vec![
+                        "info".to_string(),
+                        "1".to_string(),
+                        "0".to_string(),
+                        "0.0".to_string()
+                    ],
+                ],
+            }
+        );
+
+        assert!(query(&conn, "DELETE FROM runs").is_err());
+    }
+}
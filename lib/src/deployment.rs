@@ -0,0 +1,140 @@
+use std::time::Duration;
+
+use crate::{
+    cancel::{self, CancellationToken},
+    history,
+    nomad::NomadApi,
+    types::NomadDeployment,
+};
+use anyhow::{bail, Context, Result};
+use log::debug;
+use serde_json::{json, Value};
+
+/// How often a deployment is polled while watching it.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The deployments in `namespace`, the latest first.
+pub async fn list(nomad: &NomadApi, namespace: &str) -> Result<Vec<NomadDeployment>> {
+    nomad
+        .get("/v1/deployments", &[("namespace", namespace)])
+        .await
+}
+
+/// Find the deployment whose id starts with `prefix`.
+pub async fn resolve(nomad: &NomadApi, namespace: &str, prefix: &str) -> Result<NomadDeployment> {
+    let mut deployments: Vec<NomadDeployment> = nomad
+        .get(
+            "/v1/deployments",
+            &[("namespace", namespace), ("prefix", prefix)],
+        )
+        .await?;
+
+    match deployments.len() {
+        0 => bail!("no deployment in {} starts with {}", namespace, prefix),
+        1 => Ok(deployments.remove(0)),
+        n => bail!("{} matches {} deployments, use a longer prefix", prefix, n),
+    }
+}
+
+/// Print the deployment whenever it changes until it is done, and return its final state.
+///
+/// Cancelling only stops watching, the deployment continues.
+pub async fn watch(
+    nomad: &NomadApi,
+    deployment_id: &str,
+    cancel: &CancellationToken,
+) -> Result<NomadDeployment> {
+    watch_until(nomad, deployment_id, cancel, NomadDeployment::is_done).await
+}
+
+/// Like `watch`, but stop once `settled` holds for the deployment, e.g. once it is paused.
+pub async fn watch_until(
+    nomad: &NomadApi,
+    deployment_id: &str,
+    cancel: &CancellationToken,
+    settled: impl Fn(&NomadDeployment) -> bool,
+) -> Result<NomadDeployment> {
+    let mut last: Option<NomadDeployment> = None;
+    loop {
+        let deployment: NomadDeployment = cancel::or_cancel(
+            cancel,
+            nomad.get(&format!("/v1/deployment/{}", deployment_id), &[]),
+        )
+        .await?;
+
+        if last.as_ref() != Some(&deployment) {
+            deployment.display();
+        }
+
+        if settled(&deployment) {
+            return Ok(deployment);
+        }
+
+        debug!("deployment {} is still running", deployment_id);
+        last = Some(deployment);
+        cancel::sleep(cancel, POLL_INTERVAL).await?;
+    }
+}
+
+async fn update(
+    nomad: &NomadApi,
+    cluster: &str,
+    kind: &str,
+    deployment: &NomadDeployment,
+    body: Value,
+) -> Result<String> {
+    let path = format!("/v1/deployment/{}/{}", kind, deployment.id);
+    let response: Value = nomad
+        .post(
+            &path,
+            &[("namespace", deployment.namespace.as_str())],
+            &body,
+        )
+        .await
+        .with_context(|| format!("couldn't {} deployment {}", kind, deployment.id))?;
+
+    history::record(
+        cluster,
+        &format!("deployment-{}", kind),
+        json!({
+            "deployment": deployment.id,
+            "job": deployment.job_id,
+            "namespace": deployment.namespace,
+            "request": body,
+        }),
+    )?;
+
+    Ok(response["EvalID"].as_str().unwrap_or_default().to_string())
+}
+
+/// Promote the canaries of `groups`, or of all task groups if none are given.
+pub async fn promote(
+    nomad: &NomadApi,
+    cluster: &str,
+    deployment: &NomadDeployment,
+    groups: &[String],
+) -> Result<String> {
+    let body = json!({
+        "DeploymentID": deployment.id,
+        "All": groups.is_empty(),
+        "Groups": groups,
+    });
+    update(nomad, cluster, "promote", deployment, body).await
+}
+
+/// Mark the deployment as failed, which rolls the job back if its groups have `auto_revert`.
+pub async fn fail(nomad: &NomadApi, cluster: &str, deployment: &NomadDeployment) -> Result<String> {
+    let body = json!({ "DeploymentID": deployment.id });
+    update(nomad, cluster, "fail", deployment, body).await
+}
+
+/// Pause the deployment, or resume it if `pause` is false.
+pub async fn pause(
+    nomad: &NomadApi,
+    cluster: &str,
+    deployment: &NomadDeployment,
+    pause: bool,
+) -> Result<String> {
+    let body = json!({ "DeploymentID": deployment.id, "Pause": pause });
+    update(nomad, cluster, "pause", deployment, body).await
+}
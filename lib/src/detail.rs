@@ -0,0 +1,193 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+use tokio::task;
+
+use crate::{
+    config::BitteConfig,
+    consul,
+    inventory::{self, Inventory},
+    job,
+    nomad::NomadApi,
+    ssh,
+    types::{BitteCluster, BitteNode},
+};
+
+/// What Nomad says about one of its clients, from `/v1/node/:id`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct NomadNodeStatus {
+    /// `initializing`, `ready`, or `down`.
+    pub status: String,
+    #[serde(default)]
+    pub status_description: String,
+    pub scheduling_eligibility: String,
+    #[serde(default)]
+    pub drain: bool,
+    #[serde(default)]
+    pub datacenter: String,
+    #[serde(default)]
+    pub node_class: String,
+}
+
+/// A Consul health check of a node or one of its services, from `/v1/health/node/:node`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ConsulCheck {
+    pub name: String,
+    /// `passing`, `warning`, or `critical`.
+    pub status: String,
+    /// Empty for checks of the node itself.
+    #[serde(default, rename = "ServiceName")]
+    pub service: String,
+    #[serde(default)]
+    pub output: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ConsulNode {
+    node: String,
+}
+
+/// Everything `bitte info <node>` knows about one node. What couldn't be found out is left
+/// empty, with the reason in `errors`.
+#[derive(Debug, Serialize)]
+pub struct NodeDetail {
+    #[serde(flatten)]
+    pub node: BitteNode,
+    pub flake_attr: Option<String>,
+    pub nomad: Option<NomadNodeStatus>,
+    pub checks: Vec<ConsulCheck>,
+    /// Seconds since the node booted.
+    pub uptime: Option<u64>,
+    pub errors: Vec<String>,
+}
+
+impl NodeDetail {
+    /// When the node booted, like `3d ago`.
+    pub fn booted(&self) -> Option<String> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+        Some(job::ago(now.saturating_sub(self.uptime?), now))
+    }
+}
+
+/// The seconds since boot in `/proc/uptime`, like `350735.47 234388.90`.
+pub fn parse_uptime(proc_uptime: &str) -> Option<u64> {
+    let seconds: f64 = proc_uptime.split_whitespace().next()?.parse().ok()?;
+    Some(seconds as u64)
+}
+
+/// The flake attr of `node`: its tag, its entry in the inventory, or the one its role maps to.
+fn flake_attr(node: &BitteNode) -> Result<Option<String>> {
+    if !node.nixos.is_empty() {
+        return Ok(Some(node.nixos.clone()));
+    }
+    if let Some(attr) = Inventory::load()?.flake_attrs.get(&node.id) {
+        return Ok(Some(attr.clone()));
+    }
+    Ok(inventory::infer_flake_attr(
+        node,
+        &BitteConfig::load()?.flake_attrs,
+    ))
+}
+
+async fn consul_checks(domain: &str, node: &BitteNode) -> Result<Vec<ConsulCheck>> {
+    // the Consul node is named after the host, which isn't always the name of the instance
+    let filter = format!("Address == \"{}\"", node.priv_ip);
+    let nodes: Vec<ConsulNode> = consul::request(Method::GET, domain, "catalog/nodes")
+        .await?
+        .query(&[("filter", filter.as_str())])
+        .send()
+        .await?
+        .error_for_status()
+        .context("couldn't find the node in consul")?
+        .json()
+        .await?;
+    let name = match nodes.into_iter().next() {
+        Some(found) => found.node,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut checks: Vec<ConsulCheck> =
+        consul::request(Method::GET, domain, &format!("health/node/{}", name))
+            .await?
+            .send()
+            .await?
+            .error_for_status()
+            .with_context(|| format!("couldn't read the checks of {} from consul", name))?
+            .json()
+            .await?;
+    checks.sort_by(|a, b| (&a.service, &a.name).cmp(&(&b.service, &b.name)));
+    Ok(checks)
+}
+
+/// Gather what the provider, Nomad, Consul, and the node itself know about `node`.
+pub async fn load(cluster: &BitteCluster, node: BitteNode) -> NodeDetail {
+    let mut errors = Vec::new();
+    let mut note = |e: anyhow::Error| errors.push(format!("{:#}", e));
+
+    let flake_attr = flake_attr(&node).unwrap_or_else(|e| {
+        note(e);
+        None
+    });
+
+    let nomad: Option<NomadNodeStatus> = match &node.nomad_client {
+        Some(client) => NomadApi::for_cluster(cluster)
+            .get(&format!("/v1/node/{}", client.id), &[])
+            .await
+            .map_err(&mut note)
+            .ok(),
+        None => None,
+    };
+
+    let checks = consul_checks(&cluster.domain, &node)
+        .await
+        .unwrap_or_else(|e| {
+            note(e);
+            Vec::new()
+        });
+
+    let (name, ip) = (cluster.name.clone(), node.pub_ip);
+    let uptime = task::spawn_blocking(move || ssh::remote(&name, ip, "cat /proc/uptime", ""))
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|output| output)
+        .context("couldn't ask the node for its uptime")
+        .map_err(&mut note)
+        .ok()
+        .and_then(|output| parse_uptime(&output));
+
+    NodeDetail {
+        node,
+        flake_attr,
+        nomad,
+        checks,
+        uptime,
+        errors,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_uptime, ConsulCheck};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse_uptime("350735.47 234388.90\n"), Some(350735));
+        assert_eq!(parse_uptime(""), None);
+
+        let checks: Vec<ConsulCheck> = serde_json::from_str(
+            r#"[{"Node": "core-1", "CheckID": "serfHealth", "Name": "Serf Health Status",
+                 "Status": "passing", "Output": "Agent alive and reachable", "ServiceName": ""},
+                {"Node": "core-1", "CheckID": "service:vault", "Name": "Vault Sealed Status",
+                 "Status": "critical", "Output": "Vault is sealed", "ServiceName": "vault"}]"#,
+        )
+        .unwrap();
+        assert_eq!(checks[1].service, "vault");
+        assert_eq!(checks[1].status, "critical");
+    }
+}
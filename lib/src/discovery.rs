@@ -0,0 +1,279 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use aws_sdk_ec2::model::Filter;
+use clap::ArgMatches;
+
+use crate::{
+    azure, gcp, local, paginate,
+    pool::ClientPool,
+    types::{BitteNode, BitteProvider, TerraformStateInstance, TerraformStateValue},
+};
+
+/// Finds the machines of a cluster in the cloud it runs in.
+#[async_trait]
+pub trait NodeDiscovery: Send + Sync {
+    /// The running machines of the cluster `name`, without their Nomad clients. The terraform
+    /// `state` of the cluster is there for providers without an API of their own.
+    async fn nodes(
+        &self,
+        name: &str,
+        state: Option<&TerraformStateValue>,
+    ) -> Result<Vec<BitteNode>>;
+}
+
+pub fn for_provider(
+    provider: BitteProvider,
+    args: &ArgMatches,
+    pool: &ClientPool,
+) -> Result<Box<dyn NodeDiscovery>> {
+    Ok(match provider {
+        BitteProvider::AWS => {
+            let mut regions: Vec<String> = args.values_of_t("aws-asg-regions")?;
+            regions.push(args.value_of_t("aws-region")?);
+            Box::new(AwsDiscovery {
+                regions: regions.into_iter().collect(),
+                pool: pool.clone(),
+            })
+        }
+        BitteProvider::GCP => Box::new(GcpDiscovery {
+            zones: args.values_of_t("gcp-zones").unwrap_or_default(),
+        }),
+        BitteProvider::Azure => Box::new(AzureDiscovery {
+            resource_group: args.value_of("azure-resource-group").map(str::to_string),
+        }),
+        BitteProvider::Metal => Box::new(MetalDiscovery {
+            hosts: args.value_of("metal-hosts").map(PathBuf::from),
+        }),
+        BitteProvider::Local => Box::new(LocalDiscovery {
+            hosts: args.value_of("metal-hosts").map(PathBuf::from),
+        }),
+    })
+}
+
+/// Instances tagged with the `Cluster` in every region the cluster spans.
+pub struct AwsDiscovery {
+    pub regions: HashSet<String>,
+    pub pool: ClientPool,
+}
+
+#[async_trait]
+impl NodeDiscovery for AwsDiscovery {
+    async fn nodes(
+        &self,
+        name: &str,
+        _state: Option<&TerraformStateValue>,
+    ) -> Result<Vec<BitteNode>> {
+        let mut handles = Vec::new();
+
+        for region_str in self.regions.iter().cloned() {
+            let client = self.pool.ec2(&region_str).await;
+            let filters = vec![
+                Filter::builder().name("tag:Cluster").values(name).build(),
+                Filter::builder()
+                    .name("instance-state-name")
+                    .values("running")
+                    .build(),
+            ];
+            // the regions are listed in parallel, the pages of each one after the other
+            let response = tokio::spawn(async move {
+                paginate::ec2_instances(&client, filters, Vec::new())
+                    .await
+                    .with_context(|| {
+                        format!("failed to connect to ec2.{}.amazonaws.com", region_str)
+                    })
+            });
+            handles.push(response);
+        }
+
+        let mut nodes = Vec::new();
+        for response in handles.into_iter() {
+            let instances = response.await??;
+            nodes.extend(instances.into_iter().map(BitteNode::from));
+        }
+        Ok(nodes)
+    }
+}
+
+/// Compute Engine instances labeled with the cluster, in the given zones or all of them.
+pub struct GcpDiscovery {
+    pub zones: Vec<String>,
+}
+
+#[async_trait]
+impl NodeDiscovery for GcpDiscovery {
+    async fn nodes(
+        &self,
+        name: &str,
+        _state: Option<&TerraformStateValue>,
+    ) -> Result<Vec<BitteNode>> {
+        let instances = gcp::instances(name, &self.zones).await?;
+        Ok(instances.into_iter().map(BitteNode::from).collect())
+    }
+}
+
+/// Virtual machines and scale set instances tagged with the `Cluster`, in the resource group
+/// or the whole subscription.
+pub struct AzureDiscovery {
+    pub resource_group: Option<String>,
+}
+
+#[async_trait]
+impl NodeDiscovery for AzureDiscovery {
+    async fn nodes(
+        &self,
+        name: &str,
+        _state: Option<&TerraformStateValue>,
+    ) -> Result<Vec<BitteNode>> {
+        azure::nodes(name, self.resource_group.as_deref()).await
+    }
+}
+
+/// Machines without a cloud to ask, as the `instances` of the terraform state list them, or of
+/// a hosts file in the same shape for machines terraform doesn't manage.
+pub struct MetalDiscovery {
+    pub hosts: Option<PathBuf>,
+}
+
+/// The nodes of `instances`. Without an instance ID they go by their name, and without a `UID`
+/// tag by the `uid` of the state.
+pub fn metal_nodes(instances: &HashMap<String, TerraformStateInstance>) -> Vec<BitteNode> {
+    instances
+        .values()
+        .map(|instance| {
+            let uid = instance.uid.clone();
+            let mut node = BitteNode::from(instance.clone());
+            node.id = node.name.clone();
+            if node.nixos.is_empty() {
+                node.nixos = uid;
+            }
+            node
+        })
+        .collect()
+}
+
+/// The nodes found by each provider of a hybrid cluster, as one cluster. The terraform state
+/// bare-metal nodes are read from also lists the instances of the other providers, so
+/// bare-metal nodes come last and a node is only added for a private IP no provider before it
+/// found.
+pub fn merge(found: Vec<(BitteProvider, Vec<BitteNode>)>) -> Vec<BitteNode> {
+    let (metal, others): (Vec<_>, Vec<_>) = found
+        .into_iter()
+        .partition(|(provider, _)| matches!(provider, BitteProvider::Metal));
+
+    let mut seen = HashSet::new();
+    let mut nodes = Vec::new();
+    for (provider, found) in others.into_iter().chain(metal) {
+        for mut node in found.into_iter() {
+            if !node.priv_ip.is_unspecified() && !seen.insert(node.priv_ip) {
+                continue;
+            }
+            node.provider = provider;
+            nodes.push(node);
+        }
+    }
+    nodes
+}
+
+/// The nodes of the hosts file at `path`.
+fn read_hosts(path: &Path) -> Result<Vec<BitteNode>> {
+    let file =
+        fs::read_to_string(path).with_context(|| format!("couldn't read {}", path.display()))?;
+    let hosts: TerraformStateValue = serde_json::from_str(&file)
+        .with_context(|| format!("couldn't parse {}", path.display()))?;
+    Ok(metal_nodes(&hosts.instances))
+}
+
+#[async_trait]
+impl NodeDiscovery for MetalDiscovery {
+    async fn nodes(
+        &self,
+        _name: &str,
+        state: Option<&TerraformStateValue>,
+    ) -> Result<Vec<BitteNode>> {
+        match &self.hosts {
+            Some(path) => read_hosts(path),
+            None => match state {
+                Some(state) => Ok(metal_nodes(&state.instances)),
+                None => bail!("bare-metal nodes need the terraform state or --metal-hosts"),
+            },
+        }
+    }
+}
+
+/// The libvirt domains of the cluster, and the virtual machines of the hosts file libvirt
+/// doesn't run, like those of `nixos-shell`.
+pub struct LocalDiscovery {
+    pub hosts: Option<PathBuf>,
+}
+
+#[async_trait]
+impl NodeDiscovery for LocalDiscovery {
+    async fn nodes(
+        &self,
+        name: &str,
+        _state: Option<&TerraformStateValue>,
+    ) -> Result<Vec<BitteNode>> {
+        let mut nodes = local::nodes(name).await?;
+        if let Some(path) = &self.hosts {
+            nodes.extend(read_hosts(path)?);
+        }
+        Ok(nodes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{merge, metal_nodes};
+    use crate::types::{BitteProvider, TerraformStateValue};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_metal_nodes() {
+        let hosts: TerraformStateValue = serde_json::from_str(
+            r#"{"instances": {"core-1": {"flake-attr": "core-1", "instance-type": "c3.small.x86",
+                  "name": "core-1", "private-ip": "10.12.0.1", "public-ip": "147.75.1.2",
+                  "uid": "testnet-core-1"}}}"#,
+        )
+        .unwrap();
+        let nodes = metal_nodes(&hosts.instances);
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].id, "core-1");
+        assert_eq!(nodes[0].nixos, "testnet-core-1");
+        assert_eq!(nodes[0].pub_ip.to_string(), "147.75.1.2");
+    }
+
+    #[test]
+    fn test_merge_providers() {
+        let state: TerraformStateValue = serde_json::from_str(
+            r#"{"instances": {
+                  "core-1": {"flake-attr": "core-1", "instance-type": "t3a.medium",
+                    "name": "core-1", "private-ip": "172.16.0.10", "public-ip": "3.1.2.3",
+                    "uid": "testnet-core-1"},
+                  "client-1": {"flake-attr": "client-1", "instance-type": "c3.small.x86",
+                    "name": "client-1", "private-ip": "10.12.0.1", "public-ip": "147.75.1.2",
+                    "uid": "testnet-client-1"}}}"#,
+        )
+        .unwrap();
+        let mut aws = metal_nodes(&state.instances);
+        aws.retain(|node| node.name == "core-1");
+        aws[0].id = "i-0123456789abcdef0".to_string();
+
+        let mut nodes = merge(vec![
+            (BitteProvider::Metal, metal_nodes(&state.instances)),
+            (BitteProvider::AWS, aws),
+        ]);
+        nodes.sort_by(|a, b| a.name.cmp(&b.name));
+
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(nodes[0].name, "client-1");
+        assert!(matches!(nodes[0].provider, BitteProvider::Metal));
+        assert_eq!(nodes[1].id, "i-0123456789abcdef0");
+        assert!(matches!(nodes[1].provider, BitteProvider::AWS));
+    }
+}
@@ -0,0 +1,315 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use prettytable::{cell, row, Table};
+use serde_json::{json, Value};
+
+use crate::{
+    nomad::NomadApi,
+    types::{NomadNodeAllocation, NomadNodeDrain},
+};
+
+/// Where an allocation on a draining node is in its migration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MigrationStatus {
+    /// Not yet selected for migration.
+    Pending,
+    /// Marked for migration, the replacement isn't running yet.
+    Migrating,
+    /// The replacement runs elsewhere, but isn't healthy yet.
+    Replaced,
+    /// The replacement is running and healthy.
+    Healthy,
+    /// Stopped without a replacement, e.g. finished batch jobs or system jobs.
+    Stopped,
+}
+
+impl MigrationStatus {
+    pub fn is_done(self) -> bool {
+        matches!(self, MigrationStatus::Healthy | MigrationStatus::Stopped)
+    }
+}
+
+impl fmt::Display for MigrationStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            MigrationStatus::Pending => "pending",
+            MigrationStatus::Migrating => "migrating",
+            MigrationStatus::Replaced => "replaced elsewhere",
+            MigrationStatus::Healthy => "healthy",
+            MigrationStatus::Stopped => "stopped",
+        };
+        f.write_str(name)
+    }
+}
+
+pub struct AllocMigration {
+    pub alloc: NomadNodeAllocation,
+    pub replacement: Option<NomadNodeAllocation>,
+    pub status: MigrationStatus,
+}
+
+/// Classify an allocation of the draining node given its replacement, if it has one yet.
+pub fn migration_status(
+    alloc: &NomadNodeAllocation,
+    replacement: Option<&NomadNodeAllocation>,
+) -> MigrationStatus {
+    if let Some(next) = replacement {
+        // allocations outside of deployments never report health, running is as good as it gets
+        let healthy = match &next.deployment_status {
+            Some(status) => status.healthy == Some(true),
+            None => true,
+        };
+        return match next.client_status.as_str() {
+            "running" if healthy => MigrationStatus::Healthy,
+            "complete" => MigrationStatus::Healthy,
+            _ => MigrationStatus::Replaced,
+        };
+    }
+
+    let terminal = matches!(alloc.client_status.as_str(), "complete" | "failed" | "lost");
+
+    if terminal {
+        MigrationStatus::Stopped
+    } else if alloc.desired_transition.migrate == Some(true) || alloc.desired_status != "run" {
+        MigrationStatus::Migrating
+    } else {
+        MigrationStatus::Pending
+    }
+}
+
+/// Parse a deadline like `90s`, `10m` or `2h`. A plain number is a number of minutes.
+pub fn parse_deadline(deadline: &str) -> Result<Duration> {
+    let deadline = deadline.trim();
+    let (number, unit) = match deadline.find(|c: char| !c.is_ascii_digit()) {
+        Some(at) => deadline.split_at(at),
+        None => (deadline, "m"),
+    };
+    let number: u64 = number
+        .parse()
+        .with_context(|| format!("{} is not a valid deadline", deadline))?;
+
+    let seconds = match unit {
+        "s" => number,
+        "m" => number * 60,
+        "h" => number * 60 * 60,
+        _ => bail!(
+            "{} is not a valid deadline, use s, m, or h as unit",
+            deadline
+        ),
+    };
+    Ok(Duration::from_secs(seconds))
+}
+
+/// Make a Nomad client eligible or ineligible for new allocations, without touching the
+/// allocations it already runs.
+pub async fn set_eligibility(api: &NomadApi, node_id: &str, eligible: bool) -> Result<()> {
+    let body = json!({
+        "NodeID": node_id,
+        "Eligibility": if eligible { "eligible" } else { "ineligible" },
+    });
+
+    let _: Value = api
+        .post(&format!("/v1/node/{}/eligibility", node_id), &[], &body)
+        .await
+        .with_context(|| format!("couldn't change the eligibility of {}", node_id))?;
+    Ok(())
+}
+
+/// A drain of one Nomad client, started with `Drain::start`.
+pub struct Drain {
+    pub api: NomadApi,
+    pub node_id: String,
+    pub started: Instant,
+    pub deadline: Duration,
+}
+
+impl Drain {
+    pub async fn start(
+        api: NomadApi,
+        node_id: &str,
+        deadline: Duration,
+        ignore_system_jobs: bool,
+    ) -> Result<Self> {
+        let body = json!({
+            "DrainSpec": {
+                "Deadline": deadline.as_nanos() as u64,
+                "IgnoreSystemJobs": ignore_system_jobs,
+            },
+            "MarkEligible": false,
+            "Meta": { "message": "drained by bitte" },
+        });
+
+        let _: Value = api
+            .post(&format!("/v1/node/{}/drain", node_id), &[], &body)
+            .await
+            .with_context(|| format!("couldn't start draining {}", node_id))?;
+
+        Ok(Self {
+            api,
+            node_id: node_id.to_string(),
+            started: Instant::now(),
+            deadline,
+        })
+    }
+
+    /// Whether Nomad still considers the node to be draining.
+    pub async fn draining(&self) -> Result<bool> {
+        let node: NomadNodeDrain = self
+            .api
+            .get(&format!("/v1/node/{}", self.node_id), &[])
+            .await?;
+        Ok(node.drain || node.drain_strategy.is_some())
+    }
+
+    pub async fn migrations(&self) -> Result<Vec<AllocMigration>> {
+        let allocs: Vec<NomadNodeAllocation> = self
+            .api
+            .get(&format!("/v1/node/{}/allocations", self.node_id), &[])
+            .await?;
+
+        let mut migrations = Vec::with_capacity(allocs.len());
+
+        for alloc in allocs {
+            let replacement = match alloc.next_allocation.as_deref() {
+                Some(next) if !next.is_empty() => Some(
+                    self.api
+                        .get::<NomadNodeAllocation>(&format!("/v1/allocation/{}", next), &[])
+                        .await?,
+                ),
+                _ => None,
+            };
+            let status = migration_status(&alloc, replacement.as_ref());
+            migrations.push(AllocMigration {
+                alloc,
+                replacement,
+                status,
+            });
+        }
+
+        migrations.sort_by(|a, b| a.alloc.name.cmp(&b.alloc.name));
+        Ok(migrations)
+    }
+
+    /// Time left until the drain finishes, extrapolated from the allocations migrated so far
+    /// and never later than the deadline, after which Nomad stops the remaining allocations.
+    pub fn eta(&self, migrations: &[AllocMigration]) -> Duration {
+        let elapsed = self.started.elapsed();
+        let left = self.deadline.checked_sub(elapsed).unwrap_or_default();
+        let done = migrations.iter().filter(|m| m.status.is_done()).count() as u32;
+        let total = migrations.len() as u32;
+
+        match done {
+            _ if done == total => Duration::default(),
+            0 => left,
+            _ => (elapsed / done * (total - done)).min(left),
+        }
+    }
+
+    /// Render the migrations, naming nodes by the names in `node_names` (keyed by Nomad node id).
+    pub fn table(
+        &self,
+        migrations: &[AllocMigration],
+        node_names: &HashMap<String, String>,
+    ) -> Table {
+        let mut table = Table::new();
+        table.add_row(row![
+            "Allocation",
+            "Namespace",
+            "Status",
+            "Replacement",
+            "Target"
+        ]);
+
+        for migration in migrations {
+            let (replacement, target) = match &migration.replacement {
+                Some(next) => (
+                    next.id.chars().take(8).collect(),
+                    node_names
+                        .get(&next.node_id)
+                        .cloned()
+                        .unwrap_or_else(|| next.node_id.chars().take(8).collect()),
+                ),
+                None => (String::new(), String::new()),
+            };
+
+            table.add_row(row![
+                migration.alloc.name,
+                migration.alloc.namespace,
+                migration.status,
+                replacement,
+                target
+            ]);
+        }
+
+        table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{migration_status, parse_deadline, MigrationStatus};
+    use crate::types::{NomadAllocDeploymentStatus, NomadNodeAllocation};
+    use pretty_assertions::assert_eq;
+    use std::time::Duration;
+
+    fn alloc(client_status: &str, desired_status: &str) -> NomadNodeAllocation {
+        NomadNodeAllocation {
+            id: "a".to_string(),
+            name: "web.web[0]".to_string(),
+            job_id: "web".to_string(),
+            namespace: "default".to_string(),
+            task_group: "web".to_string(),
+            node_id: "n".to_string(),
+            client_status: client_status.to_string(),
+            desired_status: desired_status.to_string(),
+            desired_transition: Default::default(),
+            deployment_status: None,
+            next_allocation: None,
+            create_index: 0,
+            task_states: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_migration_status() {
+        let running = alloc("running", "run");
+        assert_eq!(migration_status(&running, None), MigrationStatus::Pending);
+
+        let mut marked = alloc("running", "run");
+        marked.desired_transition.migrate = Some(true);
+        assert_eq!(migration_status(&marked, None), MigrationStatus::Migrating);
+
+        let mut next = alloc("pending", "run");
+        assert_eq!(
+            migration_status(&marked, Some(&next)),
+            MigrationStatus::Replaced
+        );
+
+        next.client_status = "running".to_string();
+        next.deployment_status = Some(NomadAllocDeploymentStatus {
+            healthy: Some(true),
+        });
+        assert_eq!(
+            migration_status(&marked, Some(&next)),
+            MigrationStatus::Healthy
+        );
+
+        let finished = alloc("complete", "stop");
+        assert_eq!(migration_status(&finished, None), MigrationStatus::Stopped);
+    }
+
+    #[test]
+    fn test_parse_deadline() {
+        assert_eq!(parse_deadline("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_deadline("10m").unwrap(), Duration::from_secs(600));
+        assert_eq!(parse_deadline("2h").unwrap(), Duration::from_secs(7200));
+        assert_eq!(parse_deadline("60").unwrap(), Duration::from_secs(3600));
+        assert!(parse_deadline("10d").is_err());
+        assert!(parse_deadline("m").is_err());
+    }
+}
@@ -0,0 +1,280 @@
+use std::{env, fmt, fs, net::IpAddr, process::Command};
+
+use anyhow::{bail, Context, Result};
+use reqwest::{Certificate, ClientBuilder, Identity};
+use serde::{Deserialize, Serialize};
+
+/// The HTTP APIs of a cluster.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Service {
+    Nomad,
+    Consul,
+    Vault,
+}
+
+impl Service {
+    pub const ALL: [Service; 3] = [Service::Nomad, Service::Consul, Service::Vault];
+
+    /// The variable the CLI of the service reads its address from.
+    pub fn env_var(self) -> &'static str {
+        match self {
+            Service::Nomad => "NOMAD_ADDR",
+            Service::Consul => "CONSUL_HTTP_ADDR",
+            Service::Vault => "VAULT_ADDR",
+        }
+    }
+
+    fn env_prefix(self) -> &'static str {
+        match self {
+            Service::Nomad => "NOMAD",
+            Service::Consul => "CONSUL",
+            Service::Vault => "VAULT",
+        }
+    }
+
+    /// Where the service is reached without an override, behind the load balancer of `domain`.
+    pub fn default_address(self, domain: &str) -> String {
+        format!("https://{}.{}", self, domain)
+    }
+}
+
+impl fmt::Display for Service {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Service::Nomad => "nomad",
+            Service::Consul => "consul",
+            Service::Vault => "vault",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Addresses overriding the ones derived from the domain, from the `addresses` section of
+/// `bitte.json`, e.g. `{ "nomad": "https://127.0.0.1:4646" }` for a cluster only reachable
+/// through an ssh tunnel.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct ServiceAddrs {
+    pub nomad: Option<String>,
+    pub consul: Option<String>,
+    pub vault: Option<String>,
+}
+
+impl ServiceAddrs {
+    pub fn get(&self, service: Service) -> Option<&str> {
+        match service {
+            Service::Nomad => self.nomad.as_deref(),
+            Service::Consul => self.consul.as_deref(),
+            Service::Vault => self.vault.as_deref(),
+        }
+    }
+
+    /// The address of `service` of the cluster at `domain`, with these overrides. Addresses
+    /// without a scheme use https if `tls` has files for the service.
+    pub fn address(&self, service: Service, domain: &str, tls: &TlsFiles) -> String {
+        match self.get(service) {
+            Some(address) => normalize(address, tls.is_set()),
+            None => service.default_address(domain),
+        }
+    }
+}
+
+/// Certificates for TLS with a service: the CA its certificate is checked against, and the
+/// client certificate and key it requires, if it does. All of them are paths to PEM files.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct TlsFiles {
+    pub ca_cert: Option<String>,
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+}
+
+/// The `tls` section of `bitte.json`, e.g.
+/// `{ "nomad": { "client-cert": "secrets/nomad-cli.pem", "client-key": "secrets/nomad-cli-key.pem" } }`.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct ServiceTls {
+    pub nomad: TlsFiles,
+    pub consul: TlsFiles,
+    pub vault: TlsFiles,
+}
+
+impl ServiceTls {
+    pub fn get(&self, service: Service) -> &TlsFiles {
+        match service {
+            Service::Nomad => &self.nomad,
+            Service::Consul => &self.consul,
+            Service::Vault => &self.vault,
+        }
+    }
+}
+
+impl TlsFiles {
+    /// The variables the CLI of `service` reads the files from, like `NOMAD_CLIENT_CERT`.
+    fn vars(service: Service) -> [String; 3] {
+        let prefix = service.env_prefix();
+        [
+            format!("{}_CACERT", prefix),
+            format!("{}_CLIENT_CERT", prefix),
+            format!("{}_CLIENT_KEY", prefix),
+        ]
+    }
+
+    fn is_set(&self) -> bool {
+        *self != Self::default()
+    }
+
+    fn values(&self) -> [&Option<String>; 3] {
+        [&self.ca_cert, &self.client_cert, &self.client_key]
+    }
+
+    /// The files for `service` from its variables, which `configure` sets from `bitte.json`.
+    pub fn from_env(service: Service) -> Self {
+        let var = |name: &str| env::var(name).ok().filter(|path| !path.is_empty());
+        let [ca_cert, client_cert, client_key] = Self::vars(service);
+        Self {
+            ca_cert: var(&ca_cert),
+            client_cert: var(&client_cert),
+            client_key: var(&client_key),
+        }
+    }
+
+    /// Trust the CA and present the client certificate on connections of `builder`.
+    pub fn apply(&self, mut builder: ClientBuilder) -> Result<ClientBuilder> {
+        if let Some(ca_cert) = &self.ca_cert {
+            let pem = fs::read(ca_cert).with_context(|| format!("couldn't read {}", ca_cert))?;
+            builder = builder.add_root_certificate(
+                Certificate::from_pem(&pem)
+                    .with_context(|| format!("{} is not a PEM certificate", ca_cert))?,
+            );
+        }
+
+        match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => Ok(builder.identity(identity(cert, key)?)),
+            (None, None) => Ok(builder),
+            _ => bail!("a client certificate needs both the certificate and its key"),
+        }
+    }
+}
+
+/// The TLS backend of reqwest only takes PKCS #12 identities, so the PEM files are converted
+/// with openssl, with a throwaway password since some platforms refuse empty ones.
+fn identity(cert: &str, key: &str) -> Result<Identity> {
+    let output = Command::new("openssl")
+        .args(&["pkcs12", "-export", "-in", cert, "-inkey", key])
+        .args(&["-passout", "pass:bitte"])
+        .output()
+        .context("couldn't run openssl")?;
+    if !output.status.success() {
+        bail!(
+            "openssl couldn't bundle {} and {}: {}",
+            cert,
+            key,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Identity::from_pkcs12_der(&output.stdout, "bitte")
+        .with_context(|| format!("couldn't load the client certificate {}", cert))
+}
+
+/// Export the addresses and TLS files of `bitte.json` to the variables the CLIs of the services
+/// read, unless they are set already, so `vault`, `consul`, and `nomad` run by bitte reach the
+/// same place the same way.
+pub fn configure(addresses: &ServiceAddrs, tls: &ServiceTls) {
+    let unset = |var: &str| env::var(var).map_or(true, |set| set.is_empty());
+
+    for service in Service::ALL.iter() {
+        let files = tls.get(*service);
+        for (var, value) in TlsFiles::vars(*service).iter().zip(files.values().iter()) {
+            if let Some(value) = value {
+                if unset(var) {
+                    env::set_var(var, value);
+                }
+            }
+        }
+
+        if let Some(address) = addresses.get(*service) {
+            if unset(service.env_var()) {
+                env::set_var(service.env_var(), normalize(address, uses_tls(*service)));
+            }
+        }
+    }
+}
+
+/// The address of `service` of the cluster at `domain`: from its environment variable (which
+/// `configure` sets from `bitte.json`), otherwise derived from the domain.
+pub fn address(service: Service, domain: &str) -> String {
+    match env::var(service.env_var()) {
+        Ok(address) if !address.is_empty() => normalize(&address, uses_tls(service)),
+        _ => service.default_address(domain),
+    }
+}
+
+/// Whether `service` is reached over TLS when its address has no scheme. Consul says so with
+/// `CONSUL_HTTP_SSL`, like its CLI, Nomad and Vault when TLS files are set for them.
+pub fn uses_tls(service: Service) -> bool {
+    match service {
+        Service::Consul => {
+            env::var("CONSUL_HTTP_SSL").map_or(false, |ssl| ssl == "true" || ssl == "1")
+        }
+        _ => TlsFiles::from_env(service).is_set(),
+    }
+}
+
+/// `CONSUL_HTTP_ADDR` is usually given without a scheme, like `127.0.0.1:8500`, which is
+/// completed with https if `tls`.
+fn normalize(address: &str, tls: bool) -> String {
+    let address = address.trim_end_matches('/');
+    if address.contains("://") {
+        return address.to_string();
+    }
+
+    format!("{}://{}", if tls { "https" } else { "http" }, address)
+}
+
+/// Whether `address` points at this machine, as it does for a service reached through a tunnel.
+/// The certificate of the service is issued for its name in the cluster domain, so only its
+/// chain can be checked.
+pub fn is_tunnel(address: &str) -> bool {
+    let host = address
+        .split("://")
+        .last()
+        .unwrap_or_default()
+        .split('/')
+        .next()
+        .unwrap_or_default();
+    // strip the port, which leaves the brackets of an IPv6 address
+    let host = match host.rfind(':') {
+        Some(colon) if !host[colon..].contains(']') => &host[..colon],
+        _ => host,
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    host == "localhost" || host.parse::<IpAddr>().map_or(false, |ip| ip.is_loopback())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_tunnel, normalize};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_is_tunnel() {
+        assert!(is_tunnel("https://127.0.0.1:4646"));
+        assert!(is_tunnel("http://localhost:8500/"));
+        assert!(is_tunnel("https://[::1]:8200"));
+        assert!(!is_tunnel("https://nomad.example.com"));
+        assert!(!is_tunnel("https://10.0.0.5:4646"));
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("127.0.0.1:8500", false), "http://127.0.0.1:8500");
+        assert_eq!(normalize("127.0.0.1:4646/", true), "https://127.0.0.1:4646");
+        assert_eq!(
+            normalize("https://vault.example.com/", false),
+            "https://vault.example.com"
+        );
+    }
+}
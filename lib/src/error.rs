@@ -11,6 +11,27 @@ pub enum Error {
     EnvVar(#[from] std::env::VarError),
     #[error("github token missin in ~/.netrc file")]
     NoGithubToken,
+    #[error("no terraform credentials for {hostname}, credentials exist for: {available:?}")]
+    NoTerraformToken {
+        hostname: String,
+        available: Vec<String>,
+    },
+    #[error("terraform cloud answered {path} with {status}: {details}")]
+    TfcRequest {
+        status: u16,
+        path: String,
+        details: String,
+    },
+    #[error("terraform cloud request {path} still failed after {attempts} attempts: {last}")]
+    TfcUnavailable {
+        path: String,
+        attempts: u32,
+        last: String,
+    },
+    #[error("http request failed")]
+    HttpError(#[from] reqwest::Error),
+    #[error("workspace {workspace} is locked by {holder}")]
+    WorkspaceLocked { workspace: String, holder: String },
     #[error("error parsing json")]
     SerdeError(#[from] serde_json::Error),
     #[error("error making rest api request")]
@@ -25,10 +46,25 @@ pub enum Error {
     NetrcMissing,
     #[error("There is no s3 cache for this cluster; remove the `-c` flag")]
     MissingCache,
+    #[error("couldn't evaluate {attribute} at {location}: {message}\nfull log: {log}")]
+    NixEvalError {
+        attribute: String,
+        location: String,
+        message: String,
+        log: String,
+    },
     #[error("error executing external process: {details}")]
     ExeError { details: String },
+    #[error("ssm command finished with status {status}: {details}")]
+    SsmCommandFailed { status: String, details: String },
     #[error("current BITTE_PROVIDER is not valid: {provider}")]
     ProviderError { provider: String },
+    #[error("made all {max} requests the {provider} API budget allows")]
+    BudgetExhausted { provider: String, max: u64 },
+    #[error("cancelled")]
+    Cancelled,
+    #[error("{workspace} has changes")]
+    HasChanges { workspace: String },
     #[error("unknown error")]
     Unknown,
 }
@@ -0,0 +1,277 @@
+use anyhow::{Context, Result};
+use colored::*;
+use log::warn;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+    budget::{self, Provider},
+    cancel::{self, CancellationToken},
+    nomad::NomadApi,
+    reconnect::Reconnect,
+};
+
+/// Topics followed unless others are given.
+pub const DEFAULT_TOPICS: &[&str] = &["Deployment", "Allocation", "Evaluation", "Job"];
+
+/// A frame of `/v1/event/stream`, heartbeats have no events.
+#[derive(Debug, Deserialize)]
+struct EventFrame {
+    #[serde(rename = "Index", default)]
+    index: u64,
+    #[serde(rename = "Events", default)]
+    events: Vec<NomadEvent>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct NomadEvent {
+    #[serde(rename = "Topic")]
+    pub topic: String,
+    #[serde(rename = "Type")]
+    pub event_type: String,
+    #[serde(rename = "Key")]
+    pub key: String,
+    #[serde(rename = "Namespace", default)]
+    pub namespace: String,
+    #[serde(rename = "Index")]
+    pub index: u64,
+    #[serde(rename = "Payload", default)]
+    pub payload: Value,
+}
+
+fn field<'a>(value: &'a Value, name: &str) -> &'a str {
+    value[name].as_str().unwrap_or_default()
+}
+
+impl NomadEvent {
+    /// What changed, in a few words picked from the payload of the topic.
+    pub fn summary(&self) -> String {
+        let payload = &self.payload;
+        match self.topic.as_str() {
+            "Allocation" => {
+                let alloc = &payload["Allocation"];
+                format!(
+                    "{} on {}: {}, desired {}",
+                    field(alloc, "Name"),
+                    &field(alloc, "NodeID")[..8.min(field(alloc, "NodeID").len())],
+                    field(alloc, "ClientStatus"),
+                    field(alloc, "DesiredStatus")
+                )
+            }
+            "Deployment" => {
+                let deployment = &payload["Deployment"];
+                format!(
+                    "{} version {}: {} {}",
+                    field(deployment, "JobID"),
+                    deployment["JobVersion"].as_u64().unwrap_or_default(),
+                    field(deployment, "Status"),
+                    field(deployment, "StatusDescription")
+                )
+            }
+            "Evaluation" => {
+                let eval = &payload["Evaluation"];
+                format!(
+                    "{} triggered by {}: {}",
+                    field(eval, "JobID"),
+                    field(eval, "TriggeredBy"),
+                    field(eval, "Status")
+                )
+            }
+            "Job" => {
+                let job = &payload["Job"];
+                format!(
+                    "version {}: {}",
+                    job["Version"].as_u64().unwrap_or_default(),
+                    field(job, "Status")
+                )
+            }
+            "Node" => {
+                let node = &payload["Node"];
+                format!(
+                    "{}: {}, {}",
+                    field(node, "Name"),
+                    field(node, "Status"),
+                    field(node, "SchedulingEligibility")
+                )
+            }
+            _ => String::new(),
+        }
+    }
+
+    /// The event as one line, colored by how it went.
+    pub fn line(&self) -> String {
+        let summary = self.summary();
+        let lower = summary.to_lowercase();
+        let summary = if ["failed", "lost", "unhealthy", "down"]
+            .iter()
+            .any(|bad| lower.contains(bad))
+        {
+            summary.red()
+        } else if ["successful", "running", "complete", "ready"]
+            .iter()
+            .any(|good| lower.contains(good))
+        {
+            summary.green()
+        } else {
+            summary.normal()
+        };
+
+        format!(
+            "{} {} {} {}",
+            format!("{:<10}", self.topic).cyan(),
+            self.event_type.bold(),
+            format!("{}/{}", self.namespace, self.key).dimmed(),
+            summary
+        )
+    }
+}
+
+/// The `topic` parameters of the stream: every topic, limited to the events about `job` if
+/// given. Nomad matches the key against the id of the object and ids it refers to, like the
+/// job of an allocation.
+pub fn topic_filters(topics: &[String], job: Option<&str>) -> Vec<(String, String)> {
+    topics
+        .iter()
+        .map(|topic| {
+            (
+                "topic".to_string(),
+                format!("{}:{}", topic, job.unwrap_or("*")),
+            )
+        })
+        .collect()
+}
+
+/// Read the event stream from `index` on until it ends, passing every frame after `last_index`
+/// to `print` and moving `last_index` along.
+async fn follow<F>(
+    nomad: &NomadApi,
+    query: &[(String, String)],
+    last_index: &mut Option<u64>,
+    reconnect: &mut Reconnect,
+    cancel: &CancellationToken,
+    print: &mut F,
+) -> Result<()>
+where
+    F: FnMut(NomadEvent),
+{
+    let url = nomad.url("/v1/event/stream");
+    let index = last_index.map_or(0, |index| index + 1).to_string();
+
+    budget::acquire(Provider::Nomad).await?;
+    let mut response = nomad
+        .client
+        .get(&url)
+        .query(query)
+        .query(&[("index", index.as_str())])
+        .send()
+        .await
+        .with_context(|| format!("failed to query: {}", &url))?
+        .error_for_status()?;
+
+    if let Some(gap) = reconnect.connected() {
+        warn!(
+            "reconnected to the event stream after {}s, events since index {} follow",
+            gap.as_secs(),
+            index
+        );
+    }
+
+    let mut buffer: Vec<u8> = Vec::new();
+    while let Some(chunk) = cancel::or_cancel(cancel, async { Ok(response.chunk().await?) }).await?
+    {
+        buffer.extend_from_slice(&chunk);
+        while let Some(end) = buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=end).collect();
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            let frame: EventFrame =
+                serde_json::from_slice(&line).context("couldn't parse the event stream")?;
+
+            // heartbeats have no index, and a reconnect may replay what was already printed
+            if frame.events.is_empty() || Some(frame.index) <= *last_index {
+                continue;
+            }
+            *last_index = Some(frame.index);
+            frame.events.into_iter().for_each(&mut *print);
+        }
+    }
+
+    Ok(())
+}
+
+/// Follow the event stream of the cluster, calling `print` with every event as it arrives.
+/// When the connection breaks, it is resumed after the last event seen.
+///
+/// Cancelling closes the stream right away.
+pub async fn stream<F>(
+    nomad: &NomadApi,
+    topics: &[String],
+    job: Option<&str>,
+    namespace: &str,
+    cancel: &CancellationToken,
+    mut print: F,
+) -> Result<()>
+where
+    F: FnMut(NomadEvent),
+{
+    let mut query = topic_filters(topics, job);
+    query.push(("namespace".to_string(), namespace.to_string()));
+
+    let mut last_index = None;
+    let mut reconnect = Reconnect::default();
+    loop {
+        match follow(
+            nomad,
+            &query,
+            &mut last_index,
+            &mut reconnect,
+            cancel,
+            &mut print,
+        )
+        .await
+        {
+            Ok(()) => reconnect.closed("the event stream", cancel).await?,
+            Err(e) => reconnect.wait("the event stream", e, cancel).await?,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{topic_filters, EventFrame};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_summary() {
+        let frame: EventFrame = serde_json::from_str(
+            r#"{"Index": 12, "Events": [{
+                "Topic": "Allocation", "Type": "AllocationUpdated", "Key": "8a3f12c4",
+                "Namespace": "default", "Index": 12,
+                "Payload": { "Allocation": { "Name": "web.web[0]", "NodeID": "0c1d2e3f4a5b",
+                  "ClientStatus": "running", "DesiredStatus": "run" } } }]}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            frame.events[0].summary(),
+            "web.web[0] on 0c1d2e3f: running, desired run"
+        );
+
+        let heartbeat: EventFrame = serde_json::from_str("{}").unwrap();
+        assert!(heartbeat.events.is_empty());
+    }
+
+    #[test]
+    fn test_topic_filters() {
+        let topics = vec!["Deployment".to_string(), "Allocation".to_string()];
+        assert_eq!(
+            topic_filters(&topics, Some("web")),
+            vec![
+                ("topic".to_string(), "Deployment:web".to_string()),
+                ("topic".to_string(), "Allocation:web".to_string()),
+            ]
+        );
+        assert_eq!(topic_filters(&topics, None)[0].1, "Deployment:*");
+    }
+}
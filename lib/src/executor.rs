@@ -0,0 +1,248 @@
+use std::{
+    env,
+    net::IpAddr,
+    process::Command,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use log::{info, warn};
+use tokio::{task, time};
+
+use crate::{
+    error::Error,
+    rebuild::{nix_copy_to_cache, nix_copy_to_machine, nixos_rebuild},
+    sh, sh_async,
+    ssh::{wait_for_port, wait_for_ssh},
+    types::{BitteNode, SsmCommandInvocation, SsmSendCommand},
+};
+
+/// The system profile that NixOS boots and `switch-to-configuration` activates.
+const SYSTEM_PROFILE: &str = "/nix/var/nix/profiles/system";
+
+/// Transport used to ship a NixOS closure to a node and switch it over.
+#[async_trait]
+pub trait RemoteExecutor: Send + Sync {
+    /// Make the closure of `target` available in the node's nix store.
+    async fn copy_closure(&self, target: &str) -> Result<()>;
+    /// Activate the configuration built from `target` (`flake` names the same host attr).
+    async fn activate(&self, target: &str, flake: &str) -> Result<()>;
+    /// Run `command` as root on the node and return its output.
+    async fn run_command(&self, command: &str) -> Result<String>;
+    fn describe(&self) -> String;
+
+    /// Point the system profile at `target` without activating it yet.
+    async fn stage(&self, target: &str) -> Result<()> {
+        let path = store_path(target).await?;
+        self.run_command(&format!("nix-env -p {} --set {}", SYSTEM_PROFILE, path))
+            .await?;
+        Ok(())
+    }
+
+    /// Activate whatever the system profile points at, usually after `stage`.
+    async fn switch_staged(&self) -> Result<()> {
+        self.run_command(&format!(
+            "{}/bin/switch-to-configuration switch",
+            SYSTEM_PROFILE
+        ))
+        .await?;
+        Ok(())
+    }
+
+    /// Undo `stage`, and switch back to the previous system too if it was `activated`.
+    async fn rollback(&self, activated: bool) -> Result<()> {
+        let mut command = format!("nix-env -p {} --rollback", SYSTEM_PROFILE);
+        if activated {
+            command.push_str(&format!(
+                " && {}/bin/switch-to-configuration switch",
+                SYSTEM_PROFILE
+            ));
+        }
+        self.run_command(&command).await?;
+        Ok(())
+    }
+}
+
+pub struct SshExecutor {
+    pub ip: IpAddr,
+}
+
+impl SshExecutor {
+    fn ssh(&self, command: &str) -> Command {
+        // the same options nix uses, set up by `rebuild::set_ssh_opts`
+        let opts = env::var("NIX_SSHOPTS").unwrap_or_default();
+        let mut cmd = Command::new("ssh");
+        cmd.args(opts.split_whitespace())
+            .arg(format!("root@{}", self.ip))
+            .arg(command);
+        cmd
+    }
+
+    /// `run_command` for callers that aren't async.
+    pub fn run_blocking(&self, command: &str) -> Result<String> {
+        sh(self.ssh(command))
+    }
+}
+
+#[async_trait]
+impl RemoteExecutor for SshExecutor {
+    async fn copy_closure(&self, target: &str) -> Result<()> {
+        let (target, ip) = (target.to_string(), self.ip);
+        task::spawn_blocking(move || nix_copy_to_machine(&target, &ip)).await?
+    }
+
+    async fn activate(&self, _target: &str, flake: &str) -> Result<()> {
+        let (flake, ip) = (flake.to_string(), self.ip);
+        task::spawn_blocking(move || nixos_rebuild(&flake, &ip)).await?
+    }
+
+    async fn run_command(&self, command: &str) -> Result<String> {
+        sh_async(self.ssh(command)).await
+    }
+
+    fn describe(&self) -> String {
+        format!("ssh://root@{}", self.ip)
+    }
+}
+
+/// Stages closures in the cluster's S3 cache and runs commands through AWS SSM RunCommand.
+pub struct SsmExecutor {
+    pub instance_id: String,
+    pub region: String,
+    pub cache: String,
+}
+
+impl SsmExecutor {
+    pub fn new(node: &BitteNode, cache: &str) -> Result<Self> {
+        Ok(Self {
+            instance_id: node.id.clone(),
+            region: node.region()?,
+            cache: cache.to_string(),
+        })
+    }
+
+    /// Run `command` as root on the node and wait for it to finish.
+    async fn send_command(&self, command: &str) -> Result<String> {
+        let parameters = serde_json::json!({ "commands": [command] }).to_string();
+        let output = sh_async(execute::command_args!(
+            "aws",
+            "ssm",
+            "send-command",
+            "--region",
+            &self.region,
+            "--instance-ids",
+            &self.instance_id,
+            "--document-name",
+            "AWS-RunShellScript",
+            "--parameters",
+            parameters,
+            "--output",
+            "json"
+        ))
+        .await?;
+        let sent: SsmSendCommand =
+            serde_json::from_str(&output).with_context(|| "couldn't parse ssm send-command")?;
+        let command_id = sent.command.command_id;
+
+        info!("ssm {}: {} ({})", self.instance_id, command, command_id);
+
+        let deadline = Instant::now() + Duration::from_secs(3600);
+        loop {
+            time::sleep(Duration::from_secs(5)).await;
+
+            let output = sh_async(execute::command_args!(
+                "aws",
+                "ssm",
+                "get-command-invocation",
+                "--region",
+                &self.region,
+                "--instance-id",
+                &self.instance_id,
+                "--command-id",
+                &command_id,
+                "--output",
+                "json"
+            ))
+            .await;
+
+            // the invocation isn't visible for a short while after sending the command
+            let invocation: SsmCommandInvocation = match output {
+                Ok(output) => serde_json::from_str(&output)?,
+                Err(_) if Instant::now() < deadline => continue,
+                Err(e) => return Err(e),
+            };
+
+            match invocation.status.as_str() {
+                "Pending" | "InProgress" | "Delayed" if Instant::now() < deadline => continue,
+                "Success" => return Ok(invocation.standard_output_content),
+                status => {
+                    return Err(Error::SsmCommandFailed {
+                        status: status.to_string(),
+                        details: invocation.standard_error_content,
+                    }
+                    .into())
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl RemoteExecutor for SsmExecutor {
+    async fn copy_closure(&self, target: &str) -> Result<()> {
+        let signed = format!("{}&secret-key=secrets/nix-secret-key-file", self.cache);
+        let copied = target.to_string();
+        task::spawn_blocking(move || nix_copy_to_cache(&copied, &signed)).await??;
+        let path = store_path(target).await?;
+        self.run_command(&format!("nix copy --from '{}' {}", self.cache, path))
+            .await?;
+        Ok(())
+    }
+
+    async fn activate(&self, target: &str, _flake: &str) -> Result<()> {
+        self.stage(target).await?;
+        self.switch_staged().await
+    }
+
+    async fn run_command(&self, command: &str) -> Result<String> {
+        self.send_command(command).await
+    }
+
+    fn describe(&self) -> String {
+        format!("ssm://{}/{}", self.region, self.instance_id)
+    }
+}
+
+async fn store_path(target: &str) -> Result<String> {
+    let output = sh_async(execute::command_args!("nix", "path-info", target)).await?;
+    Ok(output.trim().to_string())
+}
+
+/// Pick the executor for `node`: ssh when port 22 answers, SSM when it is forced or ssh is
+/// unreachable and an S3 cache is available to stage the closure.
+pub async fn for_node(
+    node: &BitteNode,
+    force_ssm: bool,
+    cache: &Option<String>,
+) -> Result<Box<dyn RemoteExecutor>> {
+    if force_ssm {
+        let cache = cache.as_ref().ok_or(Error::MissingCache)?;
+        return Ok(Box::new(SsmExecutor::new(node, cache)?));
+    }
+
+    if wait_for_port(&node.pub_ip, 22, 2000, 3).await.is_ok() {
+        return Ok(Box::new(SshExecutor { ip: node.pub_ip }));
+    }
+
+    match cache {
+        Some(cache) => {
+            warn!("ssh on {} is unreachable, falling back to SSM", node.pub_ip);
+            Ok(Box::new(SsmExecutor::new(node, cache)?))
+        }
+        None => {
+            wait_for_ssh(&node.pub_ip).await?;
+            Ok(Box::new(SshExecutor { ip: node.pub_ip }))
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::types::BitteCluster;
+
+/// Facts about a cluster for Terraform stacks maintained outside of it, like DNS or monitoring,
+/// written as `terraform.tfvars.json`. Every variable is prefixed with `bitte_` so they don't
+/// collide with the variables of those stacks.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct TfVars {
+    pub bitte_cluster: String,
+    pub bitte_domain: String,
+    /// By instance ID, names repeat in auto-scaling groups.
+    pub bitte_nodes: BTreeMap<String, NodeFacts>,
+    /// Private IPs of the nodes outside of auto-scaling groups, usually the core nodes.
+    pub bitte_core_private_ips: Vec<String>,
+    pub bitte_client_private_ips: Vec<String>,
+    pub bitte_public_ips: Vec<String>,
+    pub bitte_asgs: BTreeMap<String, AsgFacts>,
+    /// Serial numbers of the CA chain that signs the certificates of the cluster, leaf first.
+    pub bitte_ca_serials: Vec<String>,
+    pub bitte_s3_cache: Option<String>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct NodeFacts {
+    pub name: String,
+    pub private_ip: String,
+    pub public_ip: String,
+    pub instance_type: Option<String>,
+    pub zone: Option<String>,
+    pub asg: Option<String>,
+    pub nomad_client_id: Option<String>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct AsgFacts {
+    pub count: i64,
+    pub instance_type: String,
+    pub region: String,
+}
+
+/// Collect the facts of `cluster`. Lists are sorted so that unchanged facts produce the same
+/// file, and downstream plans stay empty.
+pub fn tfvars(cluster: &BitteCluster, ca_serials: Vec<String>) -> TfVars {
+    let mut nodes = BTreeMap::new();
+    let mut core_ips = Vec::new();
+    let mut client_ips = Vec::new();
+    let mut public_ips = Vec::new();
+
+    for node in cluster.nodes.iter() {
+        let private_ip = node.priv_ip.to_string();
+        match node.asg {
+            Some(_) => client_ips.push(private_ip.clone()),
+            None => core_ips.push(private_ip.clone()),
+        }
+        public_ips.push(node.pub_ip.to_string());
+
+        nodes.insert(
+            node.id.clone(),
+            NodeFacts {
+                name: node.name.clone(),
+                private_ip,
+                public_ip: node.pub_ip.to_string(),
+                instance_type: node.node_type.clone(),
+                zone: node.zone.clone(),
+                asg: node.asg.clone(),
+                nomad_client_id: node
+                    .nomad_client
+                    .as_ref()
+                    .map(|client| client.id.to_hyphenated().to_string()),
+            },
+        );
+    }
+
+    core_ips.sort();
+    client_ips.sort();
+    public_ips.sort();
+
+    let asgs = cluster
+        .terra
+        .iter()
+        .flat_map(|terra| terra.asgs.iter())
+        .map(|(name, asg)| {
+            (
+                name.clone(),
+                AsgFacts {
+                    count: asg.count,
+                    instance_type: asg.instance_type.clone(),
+                    region: asg.region.clone(),
+                },
+            )
+        })
+        .collect();
+
+    TfVars {
+        bitte_cluster: cluster.name.clone(),
+        bitte_domain: cluster.domain.clone(),
+        bitte_nodes: nodes,
+        bitte_core_private_ips: core_ips,
+        bitte_client_private_ips: client_ips,
+        bitte_public_ips: public_ips,
+        bitte_asgs: asgs,
+        bitte_ca_serials: ca_serials,
+        bitte_s3_cache: cluster
+            .terra
+            .as_ref()
+            .and_then(|terra| terra.s3_cache.clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::tfvars;
+    use crate::types::BitteCluster;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    #[test]
+    fn test_tfvars() {
+        let node = |id: &str, name: &str, ip: &str, asg: Option<&str>| {
+            json!({
+                "id": id, "name": name, "priv_ip": ip, "pub_ip": "3.0.0.1", "nixos": name,
+                "nomad_client": null, "asg": asg
+            })
+        };
+        let cluster: BitteCluster = serde_json::from_value(json!({
+            "name": "test",
+            "domain": "test.example.com",
+            "provider": "AWS",
+            "ttl": { "secs_since_epoch": 0, "nanos_since_epoch": 0 },
+            "nodes": [
+                node("i-1", "core-1", "10.0.0.1", None),
+                node("i-2", "client", "10.0.1.2", Some("client-asg")),
+                node("i-3", "client", "10.0.1.1", Some("client-asg")),
+            ],
+        }))
+        .unwrap();
+
+        let vars = tfvars(&cluster, vec![]);
+        let ids: Vec<&str> = vars.bitte_nodes.keys().map(String::as_str).collect();
+        assert_eq!(ids, vec!["i-1", "i-2", "i-3"]);
+        assert_eq!(vars.bitte_nodes["i-3"].name, "client");
+        assert_eq!(vars.bitte_core_private_ips, vec!["10.0.0.1"]);
+        assert_eq!(vars.bitte_client_private_ips, vec!["10.0.1.1", "10.0.1.2"]);
+    }
+}
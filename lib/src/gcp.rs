@@ -0,0 +1,181 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr},
+    process::Command,
+};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::{
+    budget::{self, Provider},
+    sh_async,
+    types::{BitteNode, BitteProvider},
+};
+
+/// The label naming the cluster of an instance, the counterpart of the `Cluster` tag on AWS.
+pub const CLUSTER_LABEL: &str = "cluster";
+
+/// A Compute Engine instance as `gcloud compute instances list --format=json` describes it.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GceInstance {
+    pub id: String,
+    pub name: String,
+    /// The URL of the zone, like `.../zones/europe-west1-b`.
+    pub zone: String,
+    /// The URL of the machine type, like `.../machineTypes/n2-standard-4`.
+    pub machine_type: String,
+    #[serde(default)]
+    pub network_interfaces: Vec<GceNetworkInterface>,
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    #[serde(default)]
+    pub scheduling: GceScheduling,
+    #[serde(default)]
+    pub metadata: GceMetadata,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GceNetworkInterface {
+    #[serde(rename = "networkIP")]
+    pub network_ip: Option<String>,
+    #[serde(default)]
+    pub access_configs: Vec<GceAccessConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GceAccessConfig {
+    #[serde(rename = "natIP")]
+    pub nat_ip: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GceScheduling {
+    #[serde(default)]
+    pub preemptible: bool,
+    /// `STANDARD` or `SPOT`.
+    pub provisioning_model: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct GceMetadata {
+    #[serde(default)]
+    pub items: Vec<GceMetadataItem>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GceMetadataItem {
+    pub key: String,
+    pub value: Option<String>,
+}
+
+/// The last segment of a resource URL, its name.
+fn resource_name(url: &str) -> String {
+    url.rsplit('/').next().unwrap_or_default().to_string()
+}
+
+impl From<GceInstance> for BitteNode {
+    fn from(instance: GceInstance) -> Self {
+        let mut labels = instance.labels;
+        let no_ip = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0));
+        let interface = instance.network_interfaces.into_iter().next();
+        let priv_ip = interface
+            .as_ref()
+            .and_then(|interface| interface.network_ip.as_ref()?.parse().ok())
+            .unwrap_or(no_ip);
+        // instances without an external address are reached through the VPC
+        let pub_ip = interface
+            .as_ref()
+            .and_then(|interface| {
+                interface
+                    .access_configs
+                    .iter()
+                    .find_map(|c| c.nat_ip.clone())
+            })
+            .and_then(|ip| ip.parse().ok())
+            .unwrap_or(priv_ip);
+        // instances of a managed instance group name it in `created-by`
+        let group = instance
+            .metadata
+            .items
+            .iter()
+            .find(|item| item.key == "created-by")
+            .and_then(|item| item.value.as_deref())
+            .filter(|created_by| created_by.contains("/instanceGroupManagers/"))
+            .map(resource_name);
+        let spot = instance.scheduling.preemptible
+            || instance.scheduling.provisioning_model.as_deref() == Some("SPOT");
+
+        Self {
+            id: instance.id,
+            name: instance.name,
+            priv_ip,
+            pub_ip,
+            nixos: labels.remove("uid").unwrap_or_default(),
+            nomad_client: None,
+            node_type: Some(resource_name(&instance.machine_type)),
+            zone: Some(resource_name(&instance.zone)),
+            asg: group,
+            image: None,
+            lifecycle: if spot { Some("spot".to_string()) } else { None },
+            tags: labels,
+            provider: BitteProvider::GCP,
+        }
+    }
+}
+
+/// `gcloud` with `args`, in the project it is configured for or `CLOUDSDK_CORE_PROJECT`.
+pub fn gcloud(args: &[&str]) -> Command {
+    let mut cmd = Command::new("gcloud");
+    cmd.args(args).arg("--quiet");
+    cmd
+}
+
+/// The running instances labeled with `cluster`, in `zones` or in all of them.
+pub async fn instances(cluster: &str, zones: &[String]) -> Result<Vec<GceInstance>> {
+    let filter = format!("labels.{}={} AND status=RUNNING", CLUSTER_LABEL, cluster);
+    let mut cmd = gcloud(&["compute", "instances", "list", "--format=json"]);
+    cmd.arg("--filter").arg(filter);
+    if !zones.is_empty() {
+        cmd.arg("--zones").arg(zones.join(","));
+    }
+
+    budget::acquire(Provider::Gcp).await?;
+    let output = sh_async(cmd)
+        .await
+        .context("couldn't list the instances, is gcloud set up?")?;
+    serde_json::from_str(&output).context("couldn't parse the instances gcloud listed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GceInstance;
+    use crate::types::BitteNode;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_instance() {
+        let instance: GceInstance = serde_json::from_str(
+            r#"{"id": "4829417125466343101", "name": "client-x7k2",
+                "zone": "https://www.googleapis.com/compute/v1/projects/p/zones/europe-west1-b",
+                "machineType": "https://www.googleapis.com/compute/v1/projects/p/zones/europe-west1-b/machineTypes/n2-standard-4",
+                "status": "RUNNING",
+                "networkInterfaces": [{"networkIP": "10.132.0.7", "accessConfigs": [{"natIP": "34.77.1.2"}]}],
+                "labels": {"cluster": "testnet", "uid": "testnet-client"},
+                "scheduling": {"provisioningModel": "SPOT"},
+                "metadata": {"items": [{"key": "created-by",
+                  "value": "projects/1/zones/europe-west1-b/instanceGroupManagers/clients"}]}}"#,
+        )
+        .unwrap();
+        let node = BitteNode::from(instance);
+        assert_eq!(node.nixos, "testnet-client");
+        assert_eq!(node.node_type.as_deref(), Some("n2-standard-4"));
+        assert_eq!(node.pub_ip.to_string(), "34.77.1.2");
+        assert_eq!(node.asg.as_deref(), Some("clients"));
+        assert_eq!(node.lifecycle.as_deref(), Some("spot"));
+        assert_eq!(node.region().unwrap(), "europe-west1");
+    }
+}
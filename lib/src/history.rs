@@ -0,0 +1,54 @@
+use std::{
+    env, fs,
+    io::Write,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::db;
+
+/// One operation performed against a cluster, appended to `<cache>/bitte/history/<cluster>.jsonl`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub time: u64,
+    pub cluster: String,
+    pub user: String,
+    pub kind: String,
+    pub details: serde_json::Value,
+}
+
+pub fn history_file(cluster: &str) -> Result<PathBuf> {
+    let dir = PathBuf::from(crate::cache_root()?)
+        .join("bitte")
+        .join("history");
+    fs::create_dir_all(&dir)?;
+    Ok(dir.join(format!("{}.jsonl", cluster)))
+}
+
+pub fn record(cluster: &str, kind: &str, details: serde_json::Value) -> Result<()> {
+    let entry = HistoryEntry {
+        time: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        cluster: cluster.to_string(),
+        user: env::var("USER").unwrap_or_default(),
+        kind: kind.to_string(),
+        details,
+    };
+
+    let path = history_file(cluster)?;
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("couldn't open {}", path.display()))?;
+    writeln!(file, "{}", serde_json::to_string(&entry)?)?;
+
+    // the database is for queries across clusters, the file stays the record of truth
+    if let Err(e) = db::open().and_then(|conn| db::insert_history(&conn, &entry)) {
+        warn!("couldn't add the entry to the history database: {:?}", e);
+    }
+    Ok(())
+}
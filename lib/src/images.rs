@@ -0,0 +1,378 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::BitteConfig,
+    executor, rebuild, sh,
+    types::{BitteCluster, BitteNode},
+};
+
+/// The workspaces holding the standalone instances and the auto-scaling groups.
+const INSTANCE_WORKSPACE: &str = "core";
+const ASG_WORKSPACE: &str = "clients";
+
+/// What it takes to bring a node in line with the flake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ImageAction {
+    UpToDate,
+    /// The image is current, but the system isn't, `bitte rebuild` suffices.
+    Rebuild,
+    /// The node was booted from an outdated image and has to be replaced.
+    Replace,
+}
+
+/// How a node differs from the image and system the flake and terraform state declare.
+#[derive(Debug, Serialize)]
+pub struct ImageDrift {
+    pub name: String,
+    pub id: String,
+    pub asg: Option<String>,
+    pub running_image: Option<String>,
+    pub expected_image: Option<String>,
+    pub running_system: Option<String>,
+    pub expected_system: Option<String>,
+    pub action: ImageAction,
+    /// The suggested next step, empty for nodes that are up to date.
+    pub plan: String,
+}
+
+/// Images recorded in the terraform state.
+#[derive(Debug, Default, PartialEq)]
+pub struct StateImages {
+    /// Address and AMI of each standalone instance by instance id.
+    pub instances: HashMap<String, (String, String)>,
+    /// Image of the launch template or configuration of each auto-scaling group by name.
+    pub asgs: HashMap<String, String>,
+}
+
+/// Images the flake declares, by instance and by auto-scaling group.
+#[derive(Debug, Default, Deserialize)]
+pub struct FlakeImages {
+    pub instances: HashMap<String, String>,
+    pub asgs: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct RawState {
+    #[serde(default)]
+    resources: Vec<RawResource>,
+}
+
+#[derive(Deserialize)]
+struct RawResource {
+    module: Option<String>,
+    mode: String,
+    #[serde(rename = "type")]
+    resource_type: String,
+    name: String,
+    #[serde(default)]
+    instances: Vec<RawInstance>,
+}
+
+#[derive(Deserialize)]
+struct RawInstance {
+    index_key: Option<serde_json::Value>,
+    attributes: serde_json::Value,
+}
+
+impl RawResource {
+    fn address(&self, instance: &RawInstance) -> String {
+        let mut address = format!("{}.{}", self.resource_type, self.name);
+        if let Some(module) = &self.module {
+            address = format!("{}.{}", module, address);
+        }
+        match &instance.index_key {
+            Some(serde_json::Value::String(key)) => format!("{}[\"{}\"]", address, key),
+            Some(key) => format!("{}[{}]", address, key),
+            None => address,
+        }
+    }
+}
+
+fn attribute(attributes: &serde_json::Value, pointer: &str) -> Option<String> {
+    attributes
+        .pointer(pointer)
+        .and_then(|value| value.as_str())
+        .filter(|value| !value.is_empty())
+        .map(String::from)
+}
+
+/// Collect the AMIs of instances and the images of auto-scaling groups from a raw state.
+pub fn state_images(raw: &str) -> Result<StateImages> {
+    let state: RawState = serde_json::from_str(raw).context("couldn't parse terraform state")?;
+
+    let mut images = StateImages::default();
+    let mut templates: HashMap<String, String> = HashMap::new();
+    let mut group_templates: Vec<(String, String)> = Vec::new();
+
+    for resource in state.resources.iter().filter(|r| r.mode == "managed") {
+        for instance in resource.instances.iter() {
+            let attributes = &instance.attributes;
+            match resource.resource_type.as_str() {
+                "aws_instance" => {
+                    if let (Some(id), Some(ami)) =
+                        (attribute(attributes, "/id"), attribute(attributes, "/ami"))
+                    {
+                        images
+                            .instances
+                            .insert(id, (resource.address(instance), ami));
+                    }
+                }
+                "aws_launch_template" | "aws_launch_configuration" => {
+                    if let Some(image) = attribute(attributes, "/image_id") {
+                        for key in ["/id", "/name"].iter() {
+                            if let Some(key) = attribute(attributes, key) {
+                                templates.insert(key, image.clone());
+                            }
+                        }
+                    }
+                }
+                "aws_autoscaling_group" => {
+                    let template = attribute(attributes, "/launch_template/0/id")
+                        .or_else(|| attribute(attributes, "/launch_template/0/name"))
+                        .or_else(|| attribute(attributes, "/launch_configuration"));
+                    if let (Some(name), Some(template)) = (attribute(attributes, "/name"), template)
+                    {
+                        group_templates.push((name, template));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    for (group, template) in group_templates {
+        if let Some(image) = templates.get(&template) {
+            images.asgs.insert(group, image.clone());
+        }
+    }
+
+    Ok(images)
+}
+
+/// Evaluate the AMIs the flake declares for the instances and auto-scaling groups of `cluster`.
+pub fn flake_images(cluster: &str) -> Result<FlakeImages> {
+    let output = sh(execute::command_args!(
+        "nix",
+        "eval",
+        "--json",
+        format!(".#clusters.{}.proto.config.cluster", cluster),
+        "--apply",
+        "cluster: { instances = builtins.mapAttrs (_: i: i.ami) cluster.instances; \
+         asgs = builtins.mapAttrs (_: a: a.ami) cluster.autoscalingGroups; }"
+    ))?;
+    serde_json::from_str(&output).context("couldn't parse the images of the flake")
+}
+
+/// The store path of the system the flake declares for `attr`.
+pub(crate) fn expected_system(attr: &str) -> Result<String> {
+    let output = sh(execute::command_args!(
+        "nix",
+        "eval",
+        "--raw",
+        format!(
+            ".#nixosConfigurations.{}.config.system.build.toplevel",
+            attr
+        )
+    ))?;
+    Ok(output.trim().to_string())
+}
+
+pub(crate) async fn running_system(node: &BitteNode, cache: &Option<String>) -> Result<String> {
+    let executor = executor::for_node(node, false, cache).await?;
+    let output = executor
+        .run_command("readlink -f /run/current-system")
+        .await?;
+    Ok(output.trim().to_string())
+}
+
+/// The flake key of an auto-scaling group, whose ARN in the state ends in its AWS name.
+fn flake_asg<'a>(cluster: &'a BitteCluster, asg: &str) -> Option<&'a str> {
+    cluster.terra.as_ref().and_then(|terra| {
+        terra
+            .asgs
+            .iter()
+            .find(|(_, group)| group.arn.ends_with(&format!("/{}", asg)))
+            .map(|(key, _)| key.as_str())
+    })
+}
+
+async fn read_state_images(workspace: &str) -> Result<StateImages> {
+    let backend = BitteConfig::load()?.terraform.backend;
+    state_images(&backend.read_raw_state(workspace).await?)
+}
+
+async fn state_images_or_default(workspace: &str) -> StateImages {
+    match read_state_images(workspace).await {
+        Ok(images) => images,
+        Err(e) => {
+            warn!(
+                "couldn't read the images in the {} state: {:?}",
+                workspace, e
+            );
+            StateImages::default()
+        }
+    }
+}
+
+/// Compare every node with the flake and terraform state. Unless `images_only`, the running
+/// system is also compared with the one the flake builds, which requires reaching the nodes.
+pub async fn drift(cluster: &BitteCluster, images_only: bool) -> Result<Vec<ImageDrift>> {
+    let flake = flake_images(&cluster.name).unwrap_or_else(|e| {
+        warn!("couldn't evaluate the images of the flake: {:?}", e);
+        FlakeImages::default()
+    });
+    let core = state_images_or_default(INSTANCE_WORKSPACE).await;
+    let clients = state_images_or_default(ASG_WORKSPACE).await;
+    let cache = cluster
+        .terra
+        .as_ref()
+        .and_then(|terra| terra.s3_cache.clone());
+    if !images_only {
+        // the running systems are read over ssh, with the key and options of the cluster
+        rebuild::set_ssh_opts(true, &cluster.name)?;
+    }
+
+    let mut systems: HashMap<String, Option<String>> = HashMap::new();
+    let mut result = Vec::with_capacity(cluster.nodes.len());
+
+    for node in cluster.nodes.iter() {
+        let template_image = node.asg.as_ref().and_then(|asg| clients.asgs.get(asg));
+        let expected_image = match &node.asg {
+            Some(asg) => flake_asg(cluster, asg)
+                .and_then(|key| flake.asgs.get(key))
+                .or(template_image),
+            None => flake
+                .instances
+                .get(&node.name)
+                .or_else(|| core.instances.get(&node.id).map(|(_, ami)| ami)),
+        }
+        .cloned();
+
+        let (running_system, expected_system) = if images_only || node.nixos.is_empty() {
+            (None, None)
+        } else {
+            let expected = systems
+                .entry(node.nixos.clone())
+                .or_insert_with(|| match expected_system(&node.nixos) {
+                    Ok(system) => Some(system),
+                    Err(e) => {
+                        warn!("couldn't evaluate the system of {}: {:?}", node.nixos, e);
+                        None
+                    }
+                })
+                .clone();
+            let running = match running_system(node, &cache).await {
+                Ok(system) => Some(system),
+                Err(e) => {
+                    warn!("couldn't read the system of {}: {:?}", node.name, e);
+                    None
+                }
+            };
+            (running, expected)
+        };
+
+        let image_outdated = matches!(
+            (&node.image, &expected_image),
+            (Some(running), Some(expected)) if running != expected
+        );
+        let system_outdated = matches!(
+            (&running_system, &expected_system),
+            (Some(running), Some(expected)) if running != expected
+        );
+
+        let (action, plan) = if image_outdated {
+            let expected = expected_image.as_deref().unwrap_or_default();
+            let plan = match &node.asg {
+                Some(asg) if template_image.map(String::as_str) == Some(expected) => format!(
+                    "bitte node drain {}, then terminate {} so {} launches a replacement",
+                    node.name, node.id, asg
+                ),
+                Some(asg) => format!(
+                    "bitte tf {} apply to move the launch template of {} to {}, \
+                     then drain and terminate {}",
+                    ASG_WORKSPACE, asg, expected, node.id
+                ),
+                None => match core.instances.get(&node.id) {
+                    Some((address, _)) => format!(
+                        "bitte tf {} passthrough -- apply -replace='{}'",
+                        INSTANCE_WORKSPACE, address
+                    ),
+                    None => format!(
+                        "replace {} through terraform, its resource isn't in the {} state",
+                        node.id, INSTANCE_WORKSPACE
+                    ),
+                },
+            };
+            (ImageAction::Replace, plan)
+        } else if system_outdated {
+            (
+                ImageAction::Rebuild,
+                format!("bitte rebuild --only {}", node.name),
+            )
+        } else {
+            (ImageAction::UpToDate, String::new())
+        };
+
+        result.push(ImageDrift {
+            name: node.name.clone(),
+            id: node.id.clone(),
+            asg: node.asg.clone(),
+            running_image: node.image.clone(),
+            expected_image,
+            running_system,
+            expected_system,
+            action,
+            plan,
+        });
+    }
+
+    result.sort_by_key(|drift| std::cmp::Reverse(drift.action as u8));
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{state_images, StateImages};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_state_images() {
+        let raw = r#"{
+          "resources": [
+            { "mode": "managed", "type": "aws_instance", "name": "core",
+              "instances": [
+                { "index_key": "core-1", "attributes": { "id": "i-1", "ami": "ami-old" } }
+              ] },
+            { "mode": "managed", "type": "aws_launch_template", "name": "clients",
+              "instances": [
+                { "attributes": { "id": "lt-1", "name": "clients", "image_id": "ami-new" } }
+              ] },
+            { "mode": "managed", "type": "aws_autoscaling_group", "name": "clients",
+              "instances": [
+                { "attributes": { "name": "client-eu", "launch_template": [ { "id": "lt-1" } ] } }
+              ] },
+            { "mode": "data", "type": "aws_instance", "name": "other",
+              "instances": [ { "attributes": { "id": "i-2", "ami": "ami-x" } } ] }
+          ]
+        }"#;
+
+        let mut expected = StateImages::default();
+        expected.instances.insert(
+            "i-1".to_string(),
+            (
+                r#"aws_instance.core["core-1"]"#.to_string(),
+                "ami-old".to_string(),
+            ),
+        );
+        expected
+            .asgs
+            .insert("client-eu".to_string(), "ami-new".to_string());
+
+        assert_eq!(state_images(raw).unwrap(), expected);
+    }
+}
@@ -1,46 +1,692 @@
-use rusoto_autoscaling::{AutoScalingGroupNamesType, Autoscaling, AutoscalingClient};
-use rusoto_ec2::{DescribeInstancesRequest, Ec2, Ec2Client};
-use std::str::FromStr;
-
-pub async fn asg_info(tf_arn: &str, region_name: &str) -> Vec<rusoto_autoscaling::Instance> {
-    let region = rusoto_core::Region::from_str(region_name).expect("Region not found");
-    let client = AutoscalingClient::new(region);
-    let request = AutoScalingGroupNamesType::default();
-    let response = client
-        .describe_auto_scaling_groups(request)
+use crate::{
+    paginate,
+    pool::ClientPool,
+    types::{BitteNode, NomadAlloc, TerraformStateAsg},
+};
+use anyhow::{bail, Context, Result};
+use aws_sdk_autoscaling::model::AutoScalingGroup;
+use aws_sdk_ec2::model::{Filter, SpotInstanceRequest};
+use log::warn;
+use regex::Regex;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+};
+
+/// The most auto-scaling groups one page describes, and one request may name.
+const MAX_GROUPS: i32 = 100;
+
+/// EC2 takes at most this many values in one filter.
+const MAX_FILTER_VALUES: usize = 200;
+
+pub async fn asg_info(
+    pool: &ClientPool,
+    tf_arn: &str,
+    region_name: &str,
+) -> Vec<aws_sdk_autoscaling::model::Instance> {
+    let client = pool.autoscaling(region_name).await;
+    let groups = describe_groups(&client, Vec::new())
         .await
         .expect("Unable to fetch autoscaling groups info");
-    let iter = response.auto_scaling_groups.into_iter();
-    let matching = iter.filter(|asg| Some(tf_arn.to_string()) == asg.auto_scaling_group_arn);
+    let matching = groups
+        .into_iter()
+        .filter(|asg| Some(tf_arn.to_string()) == asg.auto_scaling_group_arn);
     matching
         .flat_map(|asg| asg.instances.unwrap_or_default())
         .collect()
 }
 
 pub async fn instance_info(
+    pool: &ClientPool,
     instance_ids: Vec<&str>,
     region_name: &str,
-) -> Vec<rusoto_ec2::Instance> {
-    let region = rusoto_core::Region::from_str(region_name).expect("Region not found");
-    let client = Ec2Client::new(region);
-    let request = DescribeInstancesRequest {
-        instance_ids: Some(instance_ids.iter().map(|x| x.to_string()).collect()),
-        dry_run: None,
-        filters: None,
-        max_results: None,
-        next_token: None,
-    };
-
-    let response = client
-        .describe_instances(request)
+) -> Vec<aws_sdk_ec2::model::Instance> {
+    let client = pool.ec2(region_name).await;
+    let ids = instance_ids.iter().map(|x| x.to_string()).collect();
+    paginate::ec2_instances(&client, Vec::new(), ids)
         .await
-        .expect("Unable to fetch EC2 Instance info");
+        .expect("Unable to fetch EC2 Instance info")
+}
 
-    let iter = response.reservations.into_iter();
-    iter.flat_map(|reservations| {
-        reservations
-            .into_iter()
-            .flat_map(|reservation| reservation.instances.unwrap_or_default())
+/// The auto-scaling groups named `names`, or all of them, page by page.
+async fn describe_groups(
+    client: &aws_sdk_autoscaling::Client,
+    names: Vec<String>,
+) -> Result<Vec<AutoScalingGroup>> {
+    paginate::all_pages(|token| {
+        let request = client
+            .describe_auto_scaling_groups()
+            .set_auto_scaling_group_names(Some(names.clone()).filter(|names| !names.is_empty()))
+            .max_records(MAX_GROUPS)
+            .set_next_token(token);
+        async move {
+            let page = request.send().await?;
+            Ok((
+                page.auto_scaling_groups.unwrap_or_default(),
+                page.next_token,
+            ))
+        }
     })
-    .collect()
+    .await
+}
+
+/// An auto-scaling group of the cluster as terraform declared it, and its capacity as AWS
+/// reports it now.
+#[derive(Debug, Serialize)]
+pub struct AsgCapacity {
+    pub name: String,
+    pub region: String,
+    pub instance_type: String,
+    /// The count in the terraform state.
+    pub declared: i64,
+    pub desired: Option<i64>,
+    pub min: Option<i64>,
+    pub max: Option<i64>,
+    pub in_service: usize,
+    pub pending: usize,
+    /// Instances terminating, detaching, or on standby.
+    pub other: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl AsgCapacity {
+    /// Only what the terraform state declares for `asg`, before asking the cloud.
+    pub fn declared(name: &str, asg: &TerraformStateAsg) -> Self {
+        Self {
+            name: name.to_string(),
+            region: asg.region.clone(),
+            instance_type: asg.instance_type.clone(),
+            declared: asg.count,
+            desired: None,
+            min: None,
+            max: None,
+            in_service: 0,
+            pending: 0,
+            other: 0,
+            error: None,
+        }
+    }
+
+    /// Compare `asg` of the terraform state with `group`, as described by AWS.
+    pub fn new(name: &str, asg: &TerraformStateAsg, group: Option<&AutoScalingGroup>) -> Self {
+        let mut capacity = Self::declared(name, asg);
+        let group = match group {
+            Some(group) => group,
+            None => {
+                capacity.error = Some("AWS doesn't know the group".to_string());
+                return capacity;
+            }
+        };
+
+        capacity.desired = group.desired_capacity.map(i64::from);
+        capacity.min = group.min_size.map(i64::from);
+        capacity.max = group.max_size.map(i64::from);
+        for instance in group.instances.iter().flatten() {
+            let state = instance.lifecycle_state.as_ref();
+            // like `Pending:Wait` during a lifecycle hook
+            match state.and_then(|state| state.as_str().split(':').next()) {
+                Some("InService") => capacity.in_service += 1,
+                Some("Pending") => capacity.pending += 1,
+                _ => capacity.other += 1,
+            }
+        }
+        capacity
+    }
+
+    /// Whether the group is still scaling towards its desired capacity.
+    pub fn is_scaling(&self) -> bool {
+        self.desired.map_or(false, |desired| {
+            self.in_service as i64 != desired || self.pending > 0
+        })
+    }
+}
+
+/// The name of an auto-scaling group, the last part of its ARN.
+fn asg_name(arn: &str) -> &str {
+    arn.rsplit("autoScalingGroupName/").next().unwrap_or(arn)
+}
+
+/// The capacity of the auto-scaling groups `asgs` of the terraform state, by name. A region
+/// that can't be asked leaves its groups with an error rather than failing.
+pub async fn asg_capacity(
+    pool: &ClientPool,
+    asgs: &HashMap<String, TerraformStateAsg>,
+) -> Vec<AsgCapacity> {
+    let mut by_region: BTreeMap<&str, Vec<(&String, &TerraformStateAsg)>> = BTreeMap::new();
+    for (name, asg) in asgs.iter() {
+        by_region.entry(&asg.region).or_default().push((name, asg));
+    }
+
+    let mut capacities = Vec::new();
+    for (region, asgs) in by_region {
+        match describe_asgs(pool, region, &asgs).await {
+            Ok(groups) => {
+                for (name, asg) in asgs {
+                    let group = groups.iter().find(|group| {
+                        group.auto_scaling_group_name.as_deref() == Some(asg_name(&asg.arn))
+                    });
+                    capacities.push(AsgCapacity::new(name, asg, group));
+                }
+            }
+            Err(e) => {
+                for (name, asg) in asgs {
+                    let mut capacity = AsgCapacity::new(name, asg, None);
+                    capacity.error = Some(format!("{:#}", e));
+                    capacities.push(capacity);
+                }
+            }
+        }
+    }
+    capacities.sort_by(|a, b| a.name.cmp(&b.name));
+    capacities
+}
+
+async fn describe_asgs(
+    pool: &ClientPool,
+    region: &str,
+    asgs: &[(&String, &TerraformStateAsg)],
+) -> Result<Vec<AutoScalingGroup>> {
+    let client = pool.autoscaling(region).await;
+    let names: Vec<String> = asgs
+        .iter()
+        .map(|(_, asg)| asg_name(&asg.arn).to_string())
+        .collect();
+
+    // a request names at most as many groups as fit on a page, the chunks are asked in parallel
+    let handles: Vec<_> = names
+        .chunks(MAX_GROUPS as usize)
+        .map(|chunk| {
+            let client = client.clone();
+            let chunk = chunk.to_vec();
+            tokio::spawn(async move { describe_groups(&client, chunk).await })
+        })
+        .collect();
+    let mut groups = Vec::new();
+    for handle in handles.into_iter() {
+        groups.extend(handle.await?.with_context(|| {
+            format!("failed to describe the auto-scaling groups in {}", region)
+        })?);
+    }
+    Ok(groups)
+}
+
+/// The spot request behind a spot instance.
+#[derive(Debug, Serialize)]
+pub struct SpotStatus {
+    pub node: String,
+    pub request_id: String,
+    /// `open`, `active`, `closed`, `cancelled`, or `failed`.
+    pub state: String,
+    /// Like `fulfilled` or `marked-for-termination`.
+    pub code: String,
+    pub message: String,
+    /// Whether EC2 gave notice it takes the instance back, two minutes before it does.
+    pub interrupted: bool,
+}
+
+impl SpotStatus {
+    pub fn new(node: &str, request: &SpotInstanceRequest) -> Self {
+        let status = request.status.as_ref();
+        let code = status
+            .and_then(|status| status.code.clone())
+            .unwrap_or_default();
+        Self {
+            node: node.to_string(),
+            request_id: request.spot_instance_request_id.clone().unwrap_or_default(),
+            state: request
+                .state
+                .as_ref()
+                .map(|state| state.as_str().to_string())
+                .unwrap_or_default(),
+            interrupted: code.starts_with("marked-for-"),
+            code,
+            message: status
+                .and_then(|status| status.message.clone())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// The spot requests of the spot instances among `nodes`. A region that can't be asked is
+/// left out rather than failing.
+pub async fn spot_status(pool: &ClientPool, nodes: &[BitteNode]) -> Vec<SpotStatus> {
+    let mut by_region: BTreeMap<String, Vec<&BitteNode>> = BTreeMap::new();
+    for node in nodes.iter() {
+        if node.lifecycle.as_deref() == Some("spot") {
+            if let Ok(region) = node.region() {
+                by_region.entry(region).or_default().push(node);
+            }
+        }
+    }
+
+    let mut statuses = Vec::new();
+    for (region, nodes) in by_region {
+        match describe_spot_requests(pool, &region, &nodes).await {
+            Ok(requests) => {
+                for node in nodes {
+                    let request = requests
+                        .iter()
+                        .find(|request| request.instance_id.as_deref() == Some(&node.id));
+                    if let Some(request) = request {
+                        statuses.push(SpotStatus::new(&node.name, request));
+                    }
+                }
+            }
+            Err(e) => warn!("{:?}", e),
+        }
+    }
+    statuses
+}
+
+async fn describe_spot_chunk(
+    client: &aws_sdk_ec2::Client,
+    ids: Vec<String>,
+) -> Result<Vec<SpotInstanceRequest>> {
+    let filter = Filter::builder()
+        .name("instance-id")
+        .set_values(Some(ids))
+        .build();
+
+    paginate::all_pages(|token| {
+        let request = client
+            .describe_spot_instance_requests()
+            .filters(filter.clone())
+            .set_next_token(token);
+        async move {
+            let page = request.send().await?;
+            Ok((
+                page.spot_instance_requests.unwrap_or_default(),
+                page.next_token,
+            ))
+        }
+    })
+    .await
+}
+
+async fn describe_spot_requests(
+    pool: &ClientPool,
+    region: &str,
+    nodes: &[&BitteNode],
+) -> Result<Vec<SpotInstanceRequest>> {
+    let client = pool.ec2(region).await;
+    let ids: Vec<String> = nodes.iter().map(|node| node.id.clone()).collect();
+
+    // a filter names at most as many instances as EC2 takes, the chunks are asked in parallel
+    let handles: Vec<_> = ids
+        .chunks(MAX_FILTER_VALUES)
+        .map(|chunk| {
+            let client = client.clone();
+            let chunk = chunk.to_vec();
+            tokio::spawn(async move { describe_spot_chunk(&client, chunk).await })
+        })
+        .collect();
+    let mut requests = Vec::new();
+    for handle in handles.into_iter() {
+        requests.extend(
+            handle
+                .await?
+                .with_context(|| format!("failed to describe the spot requests in {}", region))?,
+        );
+    }
+    Ok(requests)
+}
+
+/// The columns `bitte info --output` can select, besides `tag:<key>`.
+pub const COLUMNS: &[&str] = &[
+    "name",
+    "role",
+    "id",
+    "client_id",
+    "priv_ip",
+    "pub_ip",
+    "type",
+    "zone",
+    "asg",
+    "nixos",
+    "image",
+    "lifecycle",
+    "provider",
+];
+
+/// The columns in `spec`, like `name,priv_ip,tag:Role`.
+pub fn parse_columns(spec: &str) -> Result<Vec<String>> {
+    let columns: Vec<String> = spec
+        .split(',')
+        .map(str::trim)
+        .filter(|column| !column.is_empty())
+        .map(String::from)
+        .collect();
+    for column in columns.iter() {
+        check_column(column)?;
+    }
+    if columns.is_empty() {
+        bail!("no columns given");
+    }
+    Ok(columns)
+}
+
+/// The value of `column` for `node`, empty where it is unknown.
+pub fn column(node: &BitteNode, column: &str) -> String {
+    match column {
+        "name" => node.name.clone(),
+        "role" => role(node).to_string(),
+        "id" => node.id.clone(),
+        "client_id" => node
+            .nomad_client
+            .as_ref()
+            .map(|client| client.id.to_hyphenated().to_string())
+            .unwrap_or_default(),
+        "priv_ip" => node.priv_ip.to_string(),
+        "pub_ip" => node.pub_ip.to_string(),
+        "type" => node.node_type.clone().unwrap_or_default(),
+        "zone" => node.zone.clone().unwrap_or_default(),
+        "asg" => node.asg.clone().unwrap_or_default(),
+        "nixos" => node.nixos.clone(),
+        "image" => node.image.clone().unwrap_or_default(),
+        "lifecycle" => node
+            .lifecycle
+            .clone()
+            .unwrap_or_else(|| "on-demand".to_string()),
+        "provider" => format!("{:?}", node.provider),
+        _ => column
+            .strip_prefix("tag:")
+            .and_then(|key| node.tags.get(key).cloned())
+            .unwrap_or_default(),
+    }
+}
+
+/// The allocations Nomad runs or is about to run on `node`, by job, group, and index.
+pub fn live_allocs(node: &BitteNode) -> Vec<&NomadAlloc> {
+    let mut allocs: Vec<&NomadAlloc> = node
+        .nomad_client
+        .iter()
+        .flat_map(|client| client.allocs.iter().flatten())
+        .filter(|alloc| matches!(alloc.status.as_str(), "pending" | "running"))
+        .collect();
+    allocs.sort_by(|a, b| {
+        (&a.job_id, &a.task_group, a.index.get()).cmp(&(&b.job_id, &b.task_group, b.index.get()))
+    });
+    allocs
+}
+
+/// `core` for the servers, `client` for the nodes of auto-scaling groups, and `static` for the
+/// others, like the routing and monitoring nodes.
+pub fn role(node: &BitteNode) -> &'static str {
+    if node.name.starts_with("core") {
+        "core"
+    } else if node.asg.is_some() || node.nomad_client.is_some() {
+        "client"
+    } else {
+        "static"
+    }
+}
+
+fn check_column(column: &str) -> Result<()> {
+    if COLUMNS.contains(&column) || column.starts_with("tag:") {
+        Ok(())
+    } else {
+        bail!(
+            "unknown column {}, known are {} and tag:<key>",
+            column,
+            COLUMNS.join(", ")
+        )
+    }
+}
+
+#[derive(Debug)]
+enum Condition {
+    Equals(String),
+    NotEquals(String),
+    Matches(Regex),
+}
+
+/// A condition on a column of the nodes `bitte info` shows: `role=client`, `zone!=eu-central-1a`,
+/// or `name~^core-[12]$` for a regex.
+#[derive(Debug)]
+pub struct NodeFilter {
+    column: String,
+    condition: Condition,
+}
+
+impl NodeFilter {
+    pub fn parse(filter: &str) -> Result<Self> {
+        let usage = || {
+            format!(
+                "{} is not a filter, like role=client, zone!=eu-central-1a, or name~^core",
+                filter
+            )
+        };
+        // the value may hold any of the operators, a regex especially
+        let at = filter.find(&['!', '~', '='][..]).with_context(usage)?;
+        let (column, rest) = filter.split_at(at);
+        let condition = if let Some(value) = rest.strip_prefix("!=") {
+            Condition::NotEquals(value.to_string())
+        } else if let Some(pattern) = rest.strip_prefix('~') {
+            Condition::Matches(
+                Regex::new(pattern).with_context(|| format!("{} is not a valid regex", pattern))?,
+            )
+        } else if let Some(value) = rest.strip_prefix('=') {
+            Condition::Equals(value.to_string())
+        } else {
+            bail!(usage());
+        };
+
+        let column = column.trim().to_string();
+        check_column(&column)?;
+        Ok(Self { column, condition })
+    }
+
+    pub fn matches(&self, node: &BitteNode) -> bool {
+        let value = column(node, &self.column);
+        match &self.condition {
+            Condition::Equals(expected) => value == *expected,
+            Condition::NotEquals(expected) => value != *expected,
+            Condition::Matches(regex) => regex.is_match(&value),
+        }
+    }
+}
+
+/// Sort `nodes` by `column`, descending if it starts with `-`. Ips sort as ips, not as text.
+pub fn sort_nodes(nodes: &mut [BitteNode], column: &str) -> Result<()> {
+    let (column, descending) = match column.strip_prefix('-') {
+        Some(column) => (column, true),
+        None => (column, false),
+    };
+    check_column(column)?;
+
+    nodes.sort_by(|a, b| {
+        let (a, b) = (self::column(a, column), self::column(b, column));
+        let order = match (a.parse::<IpAddr>(), b.parse::<IpAddr>()) {
+            (Ok(a), Ok(b)) => a.cmp(&b),
+            _ => a.cmp(&b),
+        };
+        if descending {
+            order.reverse()
+        } else {
+            order
+        }
+    });
+    Ok(())
+}
+
+/// Whether every one of `filters` matches `node`.
+pub fn matches_all(filters: &[NodeFilter], node: &BitteNode) -> bool {
+    filters.iter().all(|filter| filter.matches(node))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        asg_name, column, live_allocs, matches_all, parse_columns, sort_nodes, AsgCapacity,
+        NodeFilter, SpotStatus,
+    };
+    use crate::types::{BitteNode, TerraformStateAsg};
+    use aws_sdk_autoscaling::model::{AutoScalingGroup, Instance, LifecycleState};
+    use aws_sdk_ec2::model::{SpotInstanceRequest, SpotInstanceState, SpotInstanceStatus};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_columns() {
+        let node: BitteNode = serde_json::from_str(
+            r#"{"id": "i-1", "name": "core-1", "priv_ip": "10.0.0.1", "pub_ip": "3.0.0.1",
+                "nixos": "abc", "tags": {"Role": "core"}}"#,
+        )
+        .unwrap();
+
+        let columns = parse_columns("name, priv_ip,tag:Role,zone").unwrap();
+        let values: Vec<String> = columns.iter().map(|c| column(&node, c)).collect();
+        assert_eq!(values, vec!["core-1", "10.0.0.1", "core", ""]);
+
+        assert!(parse_columns("name,flavour").is_err());
+        assert!(parse_columns(",").is_err());
+    }
+
+    #[test]
+    fn test_filter_and_sort() {
+        let mut nodes: Vec<BitteNode> = serde_json::from_str(
+            r#"[{"id": "i-1", "name": "core-1", "priv_ip": "10.0.0.10", "pub_ip": "3.0.0.1",
+                 "nixos": "", "tags": {"Env": "prod"}},
+                {"id": "i-2", "name": "client-a", "priv_ip": "10.0.0.9", "pub_ip": "3.0.0.2",
+                 "nixos": "", "asg": "clients-t3"},
+                {"id": "i-3", "name": "core-2", "priv_ip": "10.0.0.2", "pub_ip": "3.0.0.3",
+                 "nixos": ""}]"#,
+        )
+        .unwrap();
+
+        let filters = vec![
+            NodeFilter::parse("role=core").unwrap(),
+            NodeFilter::parse("tag:Env!=prod").unwrap(),
+        ];
+        let names: Vec<&str> = nodes
+            .iter()
+            .filter(|node| matches_all(&filters, node))
+            .map(|node| node.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["core-2"]);
+        let filter = NodeFilter::parse("name~^client-[a-z]!?$").unwrap();
+        assert!(filter.matches(&nodes[1]));
+        assert!(NodeFilter::parse("name~(").is_err());
+        assert!(NodeFilter::parse("flavour=spicy").is_err());
+
+        sort_nodes(&mut nodes, "priv_ip").unwrap();
+        assert_eq!(nodes[0].name, "core-2");
+        sort_nodes(&mut nodes, "-name").unwrap();
+        assert_eq!(nodes[0].name, "core-2");
+        assert_eq!(nodes[2].name, "client-a");
+    }
+
+    #[test]
+    fn test_live_allocs() {
+        let alloc = |id: &str, job: &str, name: &str, status: &str| {
+            format!(
+                r#"{{"ID": "{}", "JobID": "{}", "Namespace": "default", "TaskGroup": "web",
+                    "ClientStatus": "{}", "Name": "{}", "NodeID": "{}"}}"#,
+                id, job, status, name, CLIENT
+            )
+        };
+        const CLIENT: &str = "6b0b3d5e-0000-4000-8000-000000000000";
+        let node: BitteNode = serde_json::from_str(&format!(
+            r#"{{"id": "i-2", "name": "client-a", "priv_ip": "10.0.0.9", "pub_ip": "3.0.0.2",
+                "nixos": "", "nomad_client": {{"ID": "{}", "allocs": [{}, {}, {}]}}}}"#,
+            CLIENT,
+            alloc(
+                "6b0b3d5e-0000-4000-8000-000000000001",
+                "web",
+                "web.web[10]",
+                "running"
+            ),
+            alloc(
+                "6b0b3d5e-0000-4000-8000-000000000002",
+                "web",
+                "web.web[2]",
+                "pending"
+            ),
+            alloc(
+                "6b0b3d5e-0000-4000-8000-000000000003",
+                "api",
+                "api.web[0]",
+                "complete"
+            ),
+        ))
+        .unwrap();
+
+        let allocs: Vec<String> = live_allocs(&node)
+            .iter()
+            .map(|alloc| format!("{}[{}] {}", alloc.job_id, alloc.index, alloc.status))
+            .collect();
+        assert_eq!(allocs, vec!["web[2] pending", "web[10] running"]);
+    }
+
+    #[test]
+    fn test_asg_capacity() {
+        let arn = "arn:aws:autoscaling:eu-central-1:123:autoScalingGroup:abc:\
+                   autoScalingGroupName/client-eu-central-1-t3-xlarge";
+        assert_eq!(asg_name(arn), "client-eu-central-1-t3-xlarge");
+
+        let asg = TerraformStateAsg {
+            arn: arn.to_string(),
+            count: 3,
+            flake_attr: String::new(),
+            instance_type: "t3.xlarge".to_string(),
+            region: "eu-central-1".to_string(),
+            uid: String::new(),
+        };
+        let instance = |state: &str| {
+            Instance::builder()
+                .lifecycle_state(LifecycleState::from(state))
+                .build()
+        };
+        let group = AutoScalingGroup::builder()
+            .auto_scaling_group_name(asg_name(arn))
+            .desired_capacity(4)
+            .min_size(1)
+            .max_size(6)
+            .instances(instance("InService"))
+            .instances(instance("InService"))
+            .instances(instance("Pending:Wait"))
+            .instances(instance("Terminating"))
+            .build();
+
+        let capacity = AsgCapacity::new("client-t3", &asg, Some(&group));
+        assert_eq!(
+            (capacity.declared, capacity.desired, capacity.max),
+            (3, Some(4), Some(6))
+        );
+        assert_eq!(
+            (capacity.in_service, capacity.pending, capacity.other),
+            (2, 1, 1)
+        );
+        assert!(capacity.is_scaling());
+
+        let missing = AsgCapacity::new("client-t3", &asg, None);
+        assert!(missing.error.is_some());
+        assert!(!missing.is_scaling());
+    }
+
+    #[test]
+    fn test_spot_status() {
+        let request = |code: &str| {
+            SpotInstanceRequest::builder()
+                .instance_id("i-2")
+                .spot_instance_request_id("sir-1")
+                .state(SpotInstanceState::Active)
+                .status(
+                    SpotInstanceStatus::builder()
+                        .code(code)
+                        .message("...")
+                        .build(),
+                )
+                .build()
+        };
+
+        let fulfilled = SpotStatus::new("client-a", &request("fulfilled"));
+        assert_eq!(
+            (fulfilled.request_id.as_str(), fulfilled.state.as_str()),
+            ("sir-1", "active")
+        );
+        assert!(!fulfilled.interrupted);
+        assert!(SpotStatus::new("client-a", &request("marked-for-termination")).interrupted);
+    }
 }
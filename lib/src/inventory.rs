@@ -0,0 +1,121 @@
+use std::{collections::BTreeMap, env, fs, path::PathBuf};
+
+use anyhow::{bail, Context, Result};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::{config::FlakeAttrConfig, types::BitteNode};
+
+/// Flake attrs chosen for nodes that don't carry one in their tags, kept next to `bitte.json`
+/// so every rebuild of such a node targets the same system.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Inventory {
+    /// Flake attr by instance id.
+    pub flake_attrs: BTreeMap<String, String>,
+}
+
+impl Inventory {
+    /// `inventory.json` in the current directory, or the file named by `BITTE_INVENTORY`.
+    pub fn path() -> PathBuf {
+        PathBuf::from(env::var("BITTE_INVENTORY").unwrap_or_else(|_| "inventory.json".to_string()))
+    }
+
+    pub fn load() -> Result<Self> {
+        let path = Self::path();
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+
+        let file = fs::read_to_string(&path)
+            .with_context(|| format!("couldn't read {}", path.display()))?;
+        serde_json::from_str(&file).with_context(|| format!("couldn't parse {}", path.display()))
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = Self::path();
+        fs::write(&path, serde_json::to_string_pretty(self)? + "\n")
+            .with_context(|| format!("couldn't write {}", path.display()))
+    }
+}
+
+/// The flake attr for `node` according to its role tag, if its role is mapped.
+pub fn infer_flake_attr(node: &BitteNode, config: &FlakeAttrConfig) -> Option<String> {
+    let role = node.tags.get(&config.role_tag)?;
+    let attr = config.roles.get(role)?;
+    Some(attr.replace("{name}", &node.name))
+}
+
+/// Fill in the flake attr of nodes that have none, from the inventory or else inferred from
+/// their role. Inferred attrs are only used after confirming them, and are then added to the
+/// inventory.
+pub fn resolve_flake_attrs(nodes: &mut [BitteNode], config: &FlakeAttrConfig) -> Result<()> {
+    if nodes.iter().all(|node| !node.nixos.is_empty()) {
+        return Ok(());
+    }
+
+    let mut inventory = Inventory::load()?;
+
+    for node in nodes.iter_mut().filter(|node| node.nixos.is_empty()) {
+        if let Some(attr) = inventory.flake_attrs.get(&node.id) {
+            node.nixos = attr.clone();
+            continue;
+        }
+
+        let attr = match infer_flake_attr(node, config) {
+            Some(attr) => attr,
+            None => bail!(
+                "{} ({}) has no flake attr, add it to {} or map its `{}` tag under \
+                 flake-attrs.roles in bitte.json",
+                node.name,
+                node.id,
+                Inventory::path().display(),
+                config.role_tag
+            ),
+        };
+
+        let prompt = format!(
+            "use the flake attr {} for {} ({})?",
+            attr, node.name, node.id
+        );
+        if !crate::confirm(&prompt, "yes")? {
+            bail!("no flake attr for {} ({})", node.name, node.id);
+        }
+
+        info!("adding {} to {}", node.id, Inventory::path().display());
+        inventory.flake_attrs.insert(node.id.clone(), attr.clone());
+        inventory.save()?;
+        node.nixos = attr;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::infer_flake_attr;
+    use crate::{config::FlakeAttrConfig, types::BitteNode};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_infer_flake_attr() {
+        let mut config = FlakeAttrConfig::default();
+        config
+            .roles
+            .insert("router".to_string(), "testnet-{name}".to_string());
+
+        let mut node: BitteNode = serde_json::from_str(
+            r#"{"id": "i-1", "name": "routing", "priv_ip": "10.0.0.1", "pub_ip": "3.0.0.1",
+                "nixos": "", "tags": {"Role": "router"}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            infer_flake_attr(&node, &config),
+            Some("testnet-routing".to_string())
+        );
+
+        node.tags
+            .insert("Role".to_string(), "monitoring".to_string());
+        assert_eq!(infer_flake_attr(&node, &config), None);
+    }
+}
@@ -0,0 +1,634 @@
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::{
+    cancel::{self, CancellationToken},
+    config::BitteConfig,
+    deployment, history,
+    nomad::{NomadApi, ALL_NAMESPACES},
+    pool::ClientPool,
+    types::{
+        CueRender, NomadAlloc, NomadDeployment, NomadDeploymentStatus, NomadEvaluation,
+        NomadJobPlan, NomadJobPlanDiff, NomadJobRun, NomadJobSummary,
+    },
+};
+
+/// How often evaluations are polled while following a job.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Fields maintained by the Nomad servers that must not be sent back when registering a job.
+pub(crate) const SERVER_FIELDS: &[&str] = &[
+    "Status",
+    "StatusDescription",
+    "Stable",
+    "Version",
+    "SubmitTime",
+    "CreateIndex",
+    "ModifyIndex",
+    "JobModifyIndex",
+];
+
+/// Submit the job specification running in cluster `from` to cluster `to`, renaming its
+/// namespace and datacenters according to the matching promotion rule in `bitte.json`.
+pub async fn promote(
+    config: &BitteConfig,
+    job_id: &str,
+    namespace: &str,
+    from: &str,
+    to: &str,
+) -> Result<NomadJobRun> {
+    let pool = ClientPool::default();
+    let source = config.cluster(from)?.nomad(&pool)?;
+    let target = config.cluster(to)?.nomad(&pool)?;
+    let rule = config.promotion(from, to);
+
+    let mut job: Value = source
+        .get(&format!("/v1/job/{}", job_id), &[("namespace", namespace)])
+        .await
+        .with_context(|| format!("couldn't fetch {}/{} from {}", namespace, job_id, from))?;
+
+    let version = job["Version"].clone();
+    let target_namespace = rule
+        .namespaces
+        .get(namespace)
+        .cloned()
+        .unwrap_or_else(|| namespace.to_string());
+
+    if let Some(spec) = job.as_object_mut() {
+        for field in SERVER_FIELDS {
+            spec.remove(*field);
+        }
+
+        spec.insert("Namespace".into(), json!(target_namespace));
+
+        if let Some(Value::Array(datacenters)) = spec.get_mut("Datacenters") {
+            for datacenter in datacenters.iter_mut() {
+                if let Some(renamed) = datacenter.as_str().and_then(|dc| rule.datacenters.get(dc)) {
+                    *datacenter = json!(renamed);
+                }
+            }
+        }
+
+        let meta = spec.entry("Meta").or_insert_with(|| json!({}));
+        if meta.is_null() {
+            *meta = json!({});
+        }
+        meta["bitte-promoted-from"] = json!(format!("{}/{}@{}", from, namespace, version));
+    }
+
+    info!(
+        "promote {}: {}/{}@{} -> {}/{}",
+        job_id, from, namespace, version, to, target_namespace
+    );
+
+    let run: NomadJobRun = target
+        .post(
+            "/v1/jobs",
+            &[("namespace", target_namespace.as_str())],
+            &json!({ "Job": job }),
+        )
+        .await
+        .with_context(|| format!("couldn't register {} in {}", job_id, to))?;
+
+    let details = json!({
+        "job": job_id,
+        "from": { "cluster": from, "namespace": namespace, "version": version },
+        "to": { "cluster": to, "namespace": target_namespace },
+        "eval": run.eval_id,
+    });
+    history::record(from, "job-promote", details.clone())?;
+    history::record(to, "job-promote", details)?;
+
+    Ok(run)
+}
+
+/// Render `job` in `namespace` from the CUE definitions in the current directory.
+pub fn render(namespace: &str, job: &str) -> Result<CueRender> {
+    let output = crate::sh(execute::command_args!(
+        "cue",
+        "-t",
+        format!("namespace={}", namespace),
+        "-t",
+        format!("job={}", job),
+        "render"
+    ))
+    .with_context(|| format!("couldn't render {}/{} with cue", namespace, job))?;
+    serde_json::from_str(&output).context("couldn't parse the output of cue render")
+}
+
+/// Ask Nomad what submitting `render` would change, including the diff to the running job.
+pub async fn plan(nomad: &NomadApi, render: &mut CueRender) -> Result<NomadJobPlan> {
+    render.diff = Some(true);
+    render.enforce_index = None;
+    render.job_modify_index = None;
+
+    let namespace = render.job.namespace.clone();
+    nomad
+        .post(
+            &format!("/v1/job/{}/plan", render.job.id),
+            &[("namespace", namespace.as_str())],
+            &*render,
+        )
+        .await
+        .with_context(|| format!("couldn't plan {}/{}", namespace, render.job.id))
+}
+
+/// Submit `render`, but only if the job is still at `job_modify_index`, the index its plan was
+/// made at. Otherwise someone changed the job in the meantime and it has to be planned again.
+pub async fn run(
+    nomad: &NomadApi,
+    cluster: &str,
+    render: &mut CueRender,
+    job_modify_index: i64,
+) -> Result<NomadJobRun> {
+    render.diff = None;
+    render.enforce_index = Some(true);
+    render.job_modify_index = Some(job_modify_index);
+
+    let namespace = render.job.namespace.clone();
+    let run: NomadJobRun = nomad
+        .post("/v1/jobs", &[("namespace", namespace.as_str())], &*render)
+        .await
+        .with_context(|| {
+            format!(
+                "couldn't submit {}/{}, if it changed since index {} plan it again",
+                namespace, render.job.id, job_modify_index
+            )
+        })?;
+
+    history::record(
+        cluster,
+        "job-run",
+        json!({
+            "job": render.job.id,
+            "namespace": namespace,
+            "index": job_modify_index,
+            "eval": run.eval_id,
+        }),
+    )?;
+
+    Ok(run)
+}
+
+/// Follow an evaluation and the deployment it started, printing the deployment whenever it
+/// changes, until the deployment is done. Returns `None` if no deployment was started.
+///
+/// Cancelling only stops following, the deployment continues.
+pub async fn follow(
+    nomad: &NomadApi,
+    eval_id: &str,
+    cancel: &CancellationToken,
+) -> Result<Option<NomadDeployment>> {
+    let deployment_id = loop {
+        let evaluation: NomadEvaluation = nomad
+            .get(&format!("/v1/evaluation/{}", eval_id), &[])
+            .await?;
+
+        match (evaluation.status.as_str(), evaluation.deployment_id) {
+            ("pending", _) => cancel::sleep(cancel, POLL_INTERVAL).await?,
+            ("complete", Some(deployment_id)) => break deployment_id,
+            ("complete", None) => return Ok(None),
+            (status, _) => bail!(
+                "evaluation {} is {}: {}",
+                eval_id,
+                status,
+                evaluation.status_description.unwrap_or_default()
+            ),
+        }
+    };
+
+    deployment::watch(nomad, &deployment_id, cancel)
+        .await
+        .map(Some)
+}
+
+/// Stop a job, `purge` removes it from Nomad's state as well so its name can be reused right
+/// away. Returns the id of the evaluation.
+pub async fn stop(
+    nomad: &NomadApi,
+    cluster: &str,
+    namespace: &str,
+    job: &str,
+    purge: bool,
+) -> Result<String> {
+    let response: Value = nomad
+        .delete(
+            &format!("/v1/job/{}", job),
+            &[
+                ("namespace", namespace),
+                ("purge", if purge { "true" } else { "false" }),
+            ],
+        )
+        .await
+        .with_context(|| format!("couldn't stop {}/{}", namespace, job))?;
+
+    let eval_id = response["EvalID"].as_str().unwrap_or_default().to_string();
+    history::record(
+        cluster,
+        "job-stop",
+        json!({ "job": job, "namespace": namespace, "purge": purge, "eval": eval_id }),
+    )?;
+
+    Ok(eval_id)
+}
+
+/// Set the count of task group `group` of a job through the scaling API, which also checks the
+/// scaling policy of the group. Returns the id of the evaluation.
+pub async fn scale(
+    nomad: &NomadApi,
+    cluster: &str,
+    namespace: &str,
+    job: &str,
+    group: &str,
+    count: u64,
+    message: &str,
+) -> Result<String> {
+    let response: Value = nomad
+        .post(
+            &format!("/v1/job/{}/scale", job),
+            &[("namespace", namespace)],
+            &json!({
+                "Count": count,
+                "Target": { "Group": group },
+                "Message": message,
+            }),
+        )
+        .await
+        .with_context(|| format!("couldn't scale {} of {}/{}", group, namespace, job))?;
+
+    let eval_id = response["EvalID"].as_str().unwrap_or_default().to_string();
+    history::record(
+        cluster,
+        "job-scale",
+        json!({
+            "job": job,
+            "namespace": namespace,
+            "group": group,
+            "count": count,
+            "eval": eval_id,
+        }),
+    )?;
+
+    Ok(eval_id)
+}
+
+/// The namespace of `job`. Unless `namespace` is all of them, that's just `namespace`, otherwise
+/// the job has to be in exactly one namespace.
+pub async fn resolve_namespace(nomad: &NomadApi, namespace: &str, job: &str) -> Result<String> {
+    if namespace != ALL_NAMESPACES {
+        return Ok(namespace.to_string());
+    }
+
+    let stubs: Vec<Value> = nomad
+        .get("/v1/jobs", &[("namespace", namespace), ("prefix", job)])
+        .await?;
+    let mut namespaces: Vec<String> = stubs
+        .iter()
+        .filter(|stub| stub["ID"] == job)
+        .filter_map(|stub| stub["Namespace"].as_str().map(String::from))
+        .collect();
+
+    match namespaces.len() {
+        0 => bail!("there is no job {} in any namespace", job),
+        1 => Ok(namespaces.remove(0)),
+        _ => bail!(
+            "{} is in the namespaces {:?}, pick one with --namespace",
+            job,
+            namespaces
+        ),
+    }
+}
+
+/// What `bitte job status` shows about a job.
+#[derive(Debug, Serialize)]
+pub struct JobStatus {
+    pub id: String,
+    pub namespace: String,
+    pub status: String,
+    pub version: u64,
+    pub stopped: bool,
+    pub summary: NomadJobSummary,
+    pub allocs: Vec<NomadAlloc>,
+    pub deployment: Option<NomadDeployment>,
+}
+
+pub async fn status(nomad: &NomadApi, namespace: &str, job: &str) -> Result<JobStatus> {
+    let query = [("namespace", namespace)];
+    let spec: Value = nomad
+        .get(&format!("/v1/job/{}", job), &query)
+        .await
+        .with_context(|| format!("couldn't find {}/{}", namespace, job))?;
+
+    Ok(JobStatus {
+        id: job.to_string(),
+        namespace: namespace.to_string(),
+        status: spec["Status"].as_str().unwrap_or_default().to_string(),
+        version: spec["Version"].as_u64().unwrap_or_default(),
+        stopped: spec["Stop"].as_bool().unwrap_or_default(),
+        summary: nomad
+            .get(&format!("/v1/job/{}/summary", job), &query)
+            .await?,
+        allocs: nomad
+            .get(&format!("/v1/job/{}/allocations", job), &query)
+            .await?,
+        deployment: nomad
+            .get(&format!("/v1/job/{}/deployment", job), &query)
+            .await?,
+    })
+}
+
+/// The Nomad clients holding allocations of `job`, in any state.
+pub async fn clients(nomad: &NomadApi, namespace: &str, job: &str) -> Result<Vec<Uuid>> {
+    let allocs: Vec<NomadAlloc> = nomad
+        .get(
+            &format!("/v1/job/{}/allocations", job),
+            &[("namespace", namespace)],
+        )
+        .await
+        .with_context(|| format!("couldn't find {}/{}", namespace, job))?;
+
+    let mut clients: Vec<Uuid> = allocs.iter().map(|alloc| alloc.node_id).collect();
+    clients.sort();
+    clients.dedup();
+    Ok(clients)
+}
+
+/// A submitted version of a job, as listed by `bitte job history`.
+#[derive(Debug, Serialize)]
+pub struct JobVersion {
+    pub version: u64,
+    /// Seconds since the epoch.
+    pub submitted: u64,
+    pub stable: bool,
+    /// What changed from the version before, as named by `NomadJobPlanDiff::changes`.
+    pub changes: Vec<String>,
+}
+
+/// `/v1/job/:id/versions`, newest first.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct NomadJobVersions {
+    versions: Vec<Value>,
+    #[serde(default)]
+    diffs: Option<Vec<NomadJobPlanDiff>>,
+}
+
+fn version_of(job: &Value) -> Option<u64> {
+    job["Version"].as_u64()
+}
+
+/// The versions of `job` Nomad still keeps, newest first.
+pub async fn history(nomad: &NomadApi, namespace: &str, job: &str) -> Result<Vec<JobVersion>> {
+    let versions: NomadJobVersions = nomad
+        .get(
+            &format!("/v1/job/{}/versions", job),
+            &[("namespace", namespace), ("diffs", "true")],
+        )
+        .await
+        .with_context(|| format!("couldn't find {}/{}", namespace, job))?;
+
+    // diff i is between version i + 1 and version i, the oldest version has none
+    let mut diffs = versions.diffs.unwrap_or_default().into_iter();
+    Ok(versions
+        .versions
+        .iter()
+        .map(|spec| JobVersion {
+            version: version_of(spec).unwrap_or_default(),
+            // nanoseconds
+            submitted: spec["SubmitTime"].as_u64().unwrap_or_default() / 1_000_000_000,
+            stable: spec["Stable"].as_bool().unwrap_or_default(),
+            changes: diffs.next().map(|diff| diff.changes()).unwrap_or_default(),
+        })
+        .collect())
+}
+
+/// What changed in `job` from version `from` to version `to`, whichever is older is the base.
+///
+/// Nomad diffs consecutive versions on its own, other pairs need Nomad 1.9 or later, which
+/// diffs every version against the one in `diff_version`.
+pub async fn diff(
+    nomad: &NomadApi,
+    namespace: &str,
+    job: &str,
+    from: u64,
+    to: u64,
+) -> Result<NomadJobPlanDiff> {
+    let (old, new) = (from.min(to), from.max(to));
+    if old == new {
+        bail!("{} and {} are the same version", from, to);
+    }
+
+    let consecutive = new == old + 1;
+    let old_version = old.to_string();
+    let mut query = vec![("namespace", namespace), ("diffs", "true")];
+    if !consecutive {
+        query.push(("diff_version", old_version.as_str()));
+    }
+
+    let versions: NomadJobVersions = nomad
+        .get(&format!("/v1/job/{}/versions", job), &query)
+        .await
+        .with_context(|| format!("couldn't find {}/{}", namespace, job))?;
+
+    let position = |version: u64| {
+        versions
+            .versions
+            .iter()
+            .position(|spec| version_of(spec) == Some(version))
+            .with_context(|| {
+                format!(
+                    "{}/{} has no version {}, nomad only keeps the latest ones",
+                    namespace, job, version
+                )
+            })
+    };
+    let new_at = position(new)?;
+    let old_at = position(old)?;
+
+    let diffs = versions.diffs.unwrap_or_default();
+    // consecutive versions are diffed to the next older one, otherwise to `diff_version`
+    let aligned = if consecutive {
+        old_at == new_at + 1
+    } else {
+        diffs.len() == versions.versions.len()
+    };
+    if !aligned {
+        bail!(
+            "nomad only diffs consecutive versions before 1.9, try {} {}",
+            new - 1,
+            new
+        );
+    }
+
+    diffs
+        .into_iter()
+        .nth(new_at)
+        .with_context(|| format!("nomad returned no diff for version {}", new))
+}
+
+/// How long before `now` the time `then` was, both in seconds since the epoch, roughly.
+pub fn ago(then: u64, now: u64) -> String {
+    match now.saturating_sub(then) {
+        secs if secs < 60 => format!("{}s ago", secs),
+        secs if secs < 60 * 60 => format!("{}m ago", secs / 60),
+        secs if secs < 24 * 60 * 60 => format!("{}h{}m ago", secs / 3600, secs % 3600 / 60),
+        secs => format!("{}d ago", secs / (24 * 60 * 60)),
+    }
+}
+
+/// A line of `bitte job list`.
+#[derive(Debug, Serialize)]
+pub struct JobOverview {
+    pub id: String,
+    pub namespace: String,
+    #[serde(rename = "type")]
+    pub job_type: String,
+    pub status: String,
+    pub stopped: bool,
+    pub running: i64,
+    /// The sum of the counts of the task groups, unknown for system jobs which run on every
+    /// eligible node.
+    pub desired: Option<i64>,
+    pub deployment: Option<NomadDeploymentStatus>,
+    /// Evaluations still waiting to be scheduled, usually for lack of resources.
+    pub pending_evals: usize,
+}
+
+/// The latest deployment of each job by namespace and name, the one created last. The order
+/// of the listing isn't that of creation.
+fn latest_deployments(
+    deployments: Vec<NomadDeployment>,
+) -> HashMap<(String, String), NomadDeployment> {
+    let mut latest: HashMap<(String, String), NomadDeployment> = HashMap::new();
+    for deployment in deployments {
+        let key = (deployment.namespace.clone(), deployment.job_id.clone());
+        match latest.get(&key) {
+            Some(known) if known.create_index >= deployment.create_index => {}
+            _ => {
+                latest.insert(key, deployment);
+            }
+        }
+    }
+    latest
+}
+
+/// Summarize every job in `namespace`, which may be all of them, sorted by namespace and name.
+pub async fn list(nomad: &NomadApi, namespace: &str) -> Result<Vec<JobOverview>> {
+    let all = [("namespace", namespace)];
+    let stubs: Vec<Value> = nomad.get("/v1/jobs", &all).await?;
+
+    let listed: Vec<NomadDeployment> = nomad.get("/v1/deployments", &all).await?;
+    let mut deployments = latest_deployments(listed);
+
+    let mut pending: HashMap<(String, String), usize> = HashMap::new();
+    let evals: Vec<NomadEvaluation> = nomad
+        .get(
+            "/v1/evaluations",
+            &[
+                ("namespace", namespace),
+                ("filter", r#"Status == "pending" or Status == "blocked""#),
+            ],
+        )
+        .await?;
+    for eval in evals
+        .into_iter()
+        .filter(|eval| eval.status == "pending" || eval.status == "blocked")
+    {
+        let namespace = eval.namespace.unwrap_or_else(|| "default".to_string());
+        *pending.entry((namespace, eval.job_id)).or_default() += 1;
+    }
+
+    let mut jobs = Vec::with_capacity(stubs.len());
+    for stub in stubs.iter() {
+        let id = stub["ID"].as_str().unwrap_or_default().to_string();
+        let namespace = stub["Namespace"].as_str().unwrap_or("default").to_string();
+        let job_type = stub["Type"].as_str().unwrap_or_default().to_string();
+
+        let running = stub["JobSummary"]["Summary"]
+            .as_object()
+            .map_or(0, |groups| {
+                groups
+                    .values()
+                    .map(|group| group["Running"].as_i64().unwrap_or_default())
+                    .sum()
+            });
+
+        // the stubs don't have the task groups, only the job itself has their counts
+        let desired = if job_type == "system" || job_type == "sysbatch" {
+            None
+        } else {
+            let spec: Value = nomad
+                .get(
+                    &format!("/v1/job/{}", id),
+                    &[("namespace", namespace.as_str())],
+                )
+                .await?;
+            spec["TaskGroups"].as_array().map(|groups| {
+                groups
+                    .iter()
+                    .map(|group| group["Count"].as_i64().unwrap_or_default())
+                    .sum()
+            })
+        };
+
+        let key = (namespace, id);
+        jobs.push(JobOverview {
+            deployment: deployments.remove(&key).map(|deployment| deployment.status),
+            pending_evals: pending.get(&key).copied().unwrap_or_default(),
+            namespace: key.0,
+            id: key.1,
+            job_type,
+            status: stub["Status"].as_str().unwrap_or_default().to_string(),
+            stopped: stub["Stop"].as_bool().unwrap_or_default(),
+            running,
+            desired,
+        });
+    }
+
+    jobs.sort_by(|a, b| (&a.namespace, &a.id).cmp(&(&b.namespace, &b.id)));
+    Ok(jobs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ago, latest_deployments};
+    use crate::types::NomadDeployment;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    #[test]
+    fn test_latest_deployments() {
+        let deployment = |id: &str, job: &str, create_index| -> NomadDeployment {
+            serde_json::from_value(json!({
+                "ID": id, "JobID": job, "Namespace": "default", "CreateIndex": create_index,
+                "Status": "successful", "StatusDescription": null, "TaskGroups": {},
+            }))
+            .unwrap()
+        };
+        let latest = latest_deployments(vec![
+            deployment("a", "web", 10),
+            deployment("b", "web", 30),
+            deployment("c", "web", 20),
+            deployment("d", "api", 5),
+        ]);
+        let id = |job: &str| latest[&("default".to_string(), job.to_string())].id.clone();
+        assert_eq!(id("web"), "b");
+        assert_eq!(id("api"), "d");
+    }
+
+    #[test]
+    fn test_ago() {
+        let now = 1_700_000_000;
+        assert_eq!(ago(now - 42, now), "42s ago");
+        assert_eq!(ago(now - 5 * 60 - 10, now), "5m ago");
+        assert_eq!(ago(now - 3 * 3600 - 20 * 60, now), "3h20m ago");
+        assert_eq!(ago(now - 9 * 86400, now), "9d ago");
+        // clocks disagree
+        assert_eq!(ago(now + 5, now), "0s ago");
+    }
+}
@@ -0,0 +1,126 @@
+use anyhow::{bail, Context, Result};
+use reqwest::{Method, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::vault;
+
+/// A lease Vault handed out with a secret, like dynamic AWS credentials or a certificate.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Lease {
+    pub id: String,
+    #[serde(default)]
+    pub issue_time: String,
+    /// Empty for leases that don't expire.
+    #[serde(default)]
+    pub expire_time: Option<String>,
+    /// Seconds left.
+    #[serde(default)]
+    pub ttl: u64,
+    #[serde(default)]
+    pub renewable: bool,
+}
+
+/// `prefix` as Vault wants it: no leading slash, a trailing one for anything but the empty
+/// prefix.
+pub fn normalize(prefix: &str) -> String {
+    let prefix = prefix.trim_matches('/');
+    if prefix.is_empty() {
+        String::new()
+    } else {
+        format!("{}/", prefix)
+    }
+}
+
+async fn keys(prefix: &str) -> Result<Vec<String>> {
+    let response = vault::request(Method::GET, &format!("sys/leases/lookup/{}", prefix))?
+        .query(&[("list", "true")])
+        .send()
+        .await?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(Vec::new());
+    }
+
+    let listing: Value = response
+        .error_for_status()
+        .with_context(|| format!("couldn't list the leases under {}", prefix))?
+        .json()
+        .await?;
+    Ok(listing["data"]["keys"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|key| key.as_str().map(String::from))
+        .collect())
+}
+
+/// The ids of all leases under `prefix`, like `aws/creds/admin/`.
+pub async fn ids(prefix: &str) -> Result<Vec<String>> {
+    let mut ids = Vec::new();
+    let mut pending = vec![normalize(prefix)];
+
+    while let Some(prefix) = pending.pop() {
+        for key in keys(&prefix).await? {
+            if key.ends_with('/') {
+                pending.push(format!("{}{}", prefix, key));
+            } else {
+                ids.push(format!("{}{}", prefix, key));
+            }
+        }
+    }
+
+    ids.sort();
+    Ok(ids)
+}
+
+pub async fn lookup(id: &str) -> Result<Lease> {
+    let lookup: Value = vault::request(Method::PUT, "sys/leases/lookup")?
+        .json(&json!({ "lease_id": id }))
+        .send()
+        .await?
+        .error_for_status()
+        .with_context(|| format!("couldn't look up the lease {}", id))?
+        .json()
+        .await?;
+    Ok(serde_json::from_value(lookup["data"].clone())?)
+}
+
+/// Revoke the lease `id` and the secret it was handed out with.
+pub async fn revoke(id: &str) -> Result<()> {
+    vault::request(Method::PUT, "sys/leases/revoke")?
+        .json(&json!({ "lease_id": id }))
+        .send()
+        .await?
+        .error_for_status()
+        .with_context(|| format!("couldn't revoke the lease {}", id))?;
+    Ok(())
+}
+
+/// Revoke every lease under `prefix`. This needs a token with sudo on the path.
+pub async fn revoke_prefix(prefix: &str) -> Result<()> {
+    let prefix = normalize(prefix);
+    if prefix.is_empty() {
+        bail!("refusing to revoke every lease of the cluster, give a prefix");
+    }
+
+    vault::request(Method::PUT, &format!("sys/leases/revoke-prefix/{}", prefix))?
+        .send()
+        .await?
+        .error_for_status()
+        .with_context(|| format!("couldn't revoke the leases under {}", prefix))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::normalize;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize(""), "");
+        assert_eq!(normalize("/"), "");
+        assert_eq!(normalize("aws/creds/admin"), "aws/creds/admin/");
+        assert_eq!(normalize("/pki/issue/client/"), "pki/issue/client/");
+    }
+}
@@ -1,12 +1,66 @@
+pub mod acl;
+pub mod acme;
+pub mod alias;
+pub mod archive;
+pub mod azure;
+pub mod budget;
+pub mod campaign;
+pub mod cancel;
 pub mod certs;
+pub mod config;
 pub mod consul;
+pub mod costs;
+pub mod db;
+pub mod deployment;
+pub mod detail;
+pub mod discovery;
+pub mod drain;
+pub mod endpoint;
 pub mod error;
+pub mod events;
+pub mod executor;
+pub mod export;
+pub mod gcp;
+pub mod history;
+pub mod images;
 pub mod info;
+pub mod inventory;
+pub mod job;
+pub mod lease;
+pub mod local;
+pub mod login;
+pub mod logs;
+pub mod nix;
 pub mod nomad;
+pub mod paginate;
+pub mod policy;
+pub mod pool;
+pub mod power;
+pub mod preflight;
+pub mod provision;
+pub mod raft;
 pub mod rebuild;
+pub mod reconcile;
+pub mod reconnect;
+pub mod recording;
+pub mod restart;
+pub mod rotate;
+pub mod runbook;
+pub mod runs;
+pub mod secret;
+pub mod snapshot;
 pub mod ssh;
+pub mod store;
+pub mod sts;
 pub mod terraform;
+pub mod text;
+pub mod tfc;
+pub mod transcript;
 pub mod types;
+pub mod unseal;
+pub mod vault;
+pub mod verify;
+pub mod versions;
 
 use anyhow::Result;
 use error::Error;
@@ -15,6 +69,10 @@ use anyhow::Context;
 use execute::Execute;
 use log::debug;
 use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
 use std::process::Command;
 use std::process::Stdio;
 
@@ -23,6 +81,61 @@ pub fn get_env(name: &str) -> anyhow::Result<String> {
     value.with_context(|| format!("{} is not set", name))
 }
 
+pub(crate) fn cache_root() -> Result<String> {
+    env::var("XDG_CACHE_DIR")
+        .or_else::<anyhow::Error, _>(|_| Ok(format!("{}/.cache", env::var("HOME")?)))
+}
+
+/// Write `contents` to `path` so only the user can read it, from the moment the file exists.
+pub(crate) fn write_private(path: &Path, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    // the mode only applies to new files
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    file.write_all(contents.as_ref())
+}
+
+/// Ask the user to type `expected` to confirm a destructive action.
+pub fn confirm(prompt: &str, expected: &str) -> Result<bool> {
+    print!("{} (type `{}` to confirm): ", prompt, expected);
+    io::stdout().flush()?;
+
+    let mut line = String::new();
+    io::stdin().read_line(&mut line)?;
+
+    Ok(line.trim() == expected)
+}
+
+/// Ask the user for a secret, without echoing it where the terminal allows that.
+pub fn prompt_secret(prompt: &str) -> Result<String> {
+    print!("{}", prompt);
+    io::stdout().flush()?;
+
+    let silent = Command::new("stty")
+        .arg("-echo")
+        .stdin(Stdio::inherit())
+        .status()
+        .map_or(false, |status| status.success());
+
+    let mut line = String::new();
+    let read = io::stdin().read_line(&mut line);
+
+    if silent {
+        let _ = Command::new("stty")
+            .arg("echo")
+            .stdin(Stdio::inherit())
+            .status();
+        println!();
+    }
+
+    read?;
+    Ok(line.trim().to_string())
+}
+
 fn handle_command_error_common(
     mut command: std::process::Command,
     pipe_stdout: bool,
@@ -37,10 +150,10 @@ fn handle_command_error_common(
         Ok(output) => match output.status.code() {
             Some(exit_code) => {
                 if exit_code == 0 {
-                    Ok(String::from_utf8_lossy(output.stdout.as_slice()).to_string())
+                    Ok(text::decode(&output.stdout).text)
                 } else {
                     Err(Error::ExeError {
-                        details: String::from_utf8_lossy(&output.stderr).to_string(),
+                        details: text::decode(&output.stderr).text,
                     }
                     .into())
                 }
@@ -65,6 +178,28 @@ pub fn sh(command: std::process::Command) -> Result<String> {
     handle_command_error_common(command, true)
 }
 
+/// Like `sh`, without blocking the runtime while the command runs.
+pub async fn sh_async(command: std::process::Command) -> Result<String> {
+    debug!("run: {:?}", command);
+    let mut command = tokio::process::Command::from(command);
+    command.stdout(Stdio::piped()).stderr(Stdio::piped());
+
+    let output = command.output().await.map_err(|e| Error::ExeError {
+        details: e.to_string(),
+    })?;
+    match output.status.code() {
+        Some(0) => Ok(text::decode(&output.stdout).text),
+        Some(_) => Err(Error::ExeError {
+            details: text::decode(&output.stderr).text,
+        }
+        .into()),
+        None => Err(Error::ExeError {
+            details: "interrupted".to_string(),
+        }
+        .into()),
+    }
+}
+
 fn check_cmd(cmd: &mut Command) -> Result<()> {
     println!("run: {:?}", cmd);
     cmd.status()?;
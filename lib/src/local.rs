@@ -0,0 +1,105 @@
+use std::{collections::HashMap, net::IpAddr, process::Command};
+
+use anyhow::{Context, Result};
+use log::warn;
+
+use crate::{
+    sh_async,
+    types::{BitteNode, BitteProvider},
+};
+
+/// `virsh` with `args`, connected to `LIBVIRT_DEFAULT_URI` like virsh itself.
+pub fn virsh(args: &[&str]) -> Command {
+    let mut cmd = Command::new("virsh");
+    cmd.args(args);
+    cmd
+}
+
+/// The first IPv4 address in the table `virsh domifaddr` prints, without its prefix length:
+///
+/// ```text
+///  Name       MAC address          Protocol     Address
+/// -------------------------------------------------------------------------------
+///  vnet0      52:54:00:4b:73:5f    ipv4         192.168.122.59/24
+/// ```
+pub fn parse_domifaddr(output: &str) -> Option<IpAddr> {
+    output.lines().find_map(|line| {
+        let columns: Vec<&str> = line.split_whitespace().collect();
+        match columns.as_slice() {
+            [_, _, "ipv4", address] => address.split('/').next()?.parse().ok(),
+            _ => None,
+        }
+    })
+}
+
+/// The running domains of `cluster`, named `<cluster>-<node>` after the NixOS configuration
+/// they run, like `testnet-core-1`. Domains still booting, without an IPv4 address yet, are
+/// left out.
+pub async fn nodes(cluster: &str) -> Result<Vec<BitteNode>> {
+    let domains = sh_async(virsh(&["list", "--name"]))
+        .await
+        .context("couldn't list the libvirt domains, is LIBVIRT_DEFAULT_URI set?")?;
+    let prefix = format!("{}-", cluster);
+
+    let mut nodes = Vec::new();
+    for domain in domains.lines().map(str::trim) {
+        let name = match domain.strip_prefix(&prefix) {
+            Some(name) => name,
+            None => continue,
+        };
+        let ip = match sh_async(virsh(&["domifaddr", domain])).await {
+            Ok(addresses) => parse_domifaddr(&addresses),
+            Err(e) => {
+                warn!(
+                    "couldn't ask libvirt for the address of {}: {:?}",
+                    domain, e
+                );
+                continue;
+            }
+        };
+        let ip = match ip {
+            Some(ip) => ip,
+            None => {
+                warn!("skipping {}, it has no IPv4 address yet", domain);
+                continue;
+            }
+        };
+
+        nodes.push(BitteNode {
+            id: domain.to_string(),
+            name: name.to_string(),
+            priv_ip: ip,
+            pub_ip: ip,
+            nixos: domain.to_string(),
+            nomad_client: None,
+            node_type: None,
+            zone: None,
+            asg: None,
+            image: None,
+            lifecycle: None,
+            tags: HashMap::new(),
+            provider: BitteProvider::Local,
+        });
+    }
+    Ok(nodes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_domifaddr;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_parse_domifaddr() {
+        let output = " Name       MAC address          Protocol     Address
+-------------------------------------------------------------------------------
+ vnet0      52:54:00:4b:73:5f    ipv6         fe80::5054:ff:fe4b:735f/64
+ vnet0      52:54:00:4b:73:5f    ipv4         192.168.122.59/24
+";
+        assert_eq!(
+            parse_domifaddr(output).map(|ip| ip.to_string()),
+            Some("192.168.122.59".to_string())
+        );
+        assert_eq!(parse_domifaddr(""), None);
+    }
+}
@@ -0,0 +1,246 @@
+use std::{
+    env, fs,
+    io::Write,
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+use anyhow::{bail, Context, Result};
+use log::{debug, info};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+use crate::{
+    cancel::{self, CancellationToken},
+    endpoint::Service,
+    pool::ClientPool,
+    terraform,
+    types::{HttpPutToken, VaultLogin},
+};
+
+/// Vault sends the browser back to this port after an OIDC login, the redirect URI has to be
+/// allowed for the role. The vault CLI uses the same one.
+const OIDC_PORT: u16 = 8250;
+
+/// The service stored tokens are filed under in the keychain.
+const KEYRING_SERVICE: &str = "bitte-vault";
+
+/// How bitte logs in to Vault again once the token of a long run can't be renewed anymore, the
+/// `vault-login` section of `bitte.json`, e.g. `{ "method": "github", "mount": "github-ops" }`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct LoginConfig {
+    /// `aws` or `github`, OIDC needs the browser.
+    pub method: String,
+    /// Path the auth method is mounted at, defaults to the name of the method.
+    pub mount: Option<String>,
+}
+
+impl Default for LoginConfig {
+    fn default() -> Self {
+        Self {
+            method: "aws".to_string(),
+            mount: None,
+        }
+    }
+}
+
+/// Log in with a GitHub token to the auth method mounted at `mount`, returning the Vault
+/// token. The GitHub token comes from `GITHUB_TOKEN` or `~/.netrc`.
+pub async fn github(address: &str, mount: &str) -> Result<String> {
+    let token = match env::var("GITHUB_TOKEN") {
+        Ok(token) if !token.is_empty() => token,
+        _ => terraform::github_token()?,
+    };
+
+    let url = format!("{}/v1/auth/{}/login", address, mount);
+    let login: VaultLogin = ClientPool::shared()
+        .service(Service::Vault, address)?
+        .put(&url)
+        .json(&HttpPutToken { token })
+        .send()
+        .await
+        .with_context(|| format!("failed to query: {}", url))?
+        .error_for_status()
+        .with_context(|| format!("couldn't log in to {} with github", address))?
+        .json()
+        .await?;
+    info!(
+        "logged in as {} of {}",
+        login.auth.metadata.username, login.auth.metadata.org
+    );
+    Ok(login.auth.client_token)
+}
+
+/// Log in through the OIDC provider of the auth method mounted at `mount` as `role`: the user
+/// completes the login in a browser, which is sent back to a listener on `OIDC_PORT`. Returns
+/// the Vault token.
+pub async fn oidc(
+    address: &str,
+    mount: &str,
+    role: &str,
+    cancel: &CancellationToken,
+) -> Result<String> {
+    let client = ClientPool::shared().service(Service::Vault, address)?;
+    let redirect_uri = format!("http://localhost:{}/oidc/callback", OIDC_PORT);
+
+    // listen first, the browser may be faster than us
+    let listener = TcpListener::bind(("127.0.0.1", OIDC_PORT))
+        .await
+        .with_context(|| format!("couldn't listen on port {} for the login", OIDC_PORT))?;
+
+    let url = format!("{}/v1/auth/{}/oidc/auth_url", address, mount);
+    let response: Value = client
+        .post(&url)
+        .json(&json!({ "role": role, "redirect_uri": redirect_uri }))
+        .send()
+        .await
+        .with_context(|| format!("failed to query: {}", url))?
+        .error_for_status()?
+        .json()
+        .await?;
+    let auth_url = response["data"]["auth_url"].as_str().unwrap_or_default();
+    if auth_url.is_empty() {
+        bail!(
+            "vault has no login url for role {}, {} has to be one of its allowed redirect uris",
+            role,
+            redirect_uri
+        );
+    }
+
+    println!("Complete the login in your browser:\n\n    {}\n", auth_url);
+    let opener = if cfg!(target_os = "macos") {
+        "open"
+    } else {
+        "xdg-open"
+    };
+    if let Err(e) = Command::new(opener).arg(auth_url).spawn() {
+        debug!("couldn't open the browser: {:?}", e);
+    }
+
+    let query = cancel::or_cancel(cancel, callback(listener)).await?;
+
+    let url = format!("{}/v1/auth/{}/oidc/callback", address, mount);
+    let login: Value = client
+        .get(&url)
+        .query(&query)
+        .send()
+        .await
+        .with_context(|| format!("failed to query: {}", url))?
+        .error_for_status()
+        .context("vault rejected the login")?
+        .json()
+        .await?;
+
+    login["auth"]["client_token"]
+        .as_str()
+        .map(String::from)
+        .context("vault returned no token")
+}
+
+/// Wait for the browser to come back from the OIDC provider and return the query it carries,
+/// the code and state Vault needs to finish the login.
+async fn callback(listener: TcpListener) -> Result<Vec<(String, String)>> {
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let mut request = vec![0; 8192];
+        let read = stream.read(&mut request).await?;
+        let request = String::from_utf8_lossy(&request[..read]);
+
+        // GET /oidc/callback?code=...&state=... HTTP/1.1
+        let target = request.split_whitespace().nth(1).unwrap_or_default();
+        let url = Url::parse(&format!("http://localhost{}", target))?;
+        if url.path() != "/oidc/callback" {
+            // e.g. the favicon
+            stream
+                .write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n")
+                .await?;
+            continue;
+        }
+
+        stream
+            .write_all(
+                b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\n\
+                  Logged in to Vault, you can close this window.\n",
+            )
+            .await?;
+        return Ok(url.query_pairs().into_owned().collect());
+    }
+}
+
+fn token_file(address: &str) -> Result<PathBuf> {
+    let name = address
+        .trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .replace(|c: char| !c.is_ascii_alphanumeric() && c != '.', "_");
+
+    let dir = PathBuf::from(crate::cache_root()?)
+        .join("bitte")
+        .join("vault-tokens");
+    fs::create_dir_all(&dir)?;
+    Ok(dir.join(name))
+}
+
+/// Store `token` for the Vault at `address`: in the keychain through `secret-tool` where it is
+/// installed, otherwise in a file only the user can read. Returns where it went.
+pub fn store(address: &str, token: &str) -> Result<String> {
+    let path = token_file(address)?;
+
+    let keyring = Command::new("secret-tool")
+        .args(&["store", "--label", "bitte vault token"])
+        .args(&["service", KEYRING_SERVICE, "address", address])
+        .stdin(Stdio::piped())
+        .spawn();
+    if let Ok(mut child) = keyring {
+        if let Some(stdin) = child.stdin.as_mut() {
+            stdin.write_all(token.as_bytes())?;
+        }
+        drop(child.stdin.take());
+        if child.wait()?.success() {
+            // don't leave an older token behind
+            let _ = fs::remove_file(&path);
+            return Ok("the keychain".to_string());
+        }
+    }
+
+    crate::write_private(&path, token)?;
+    Ok(path.display().to_string())
+}
+
+/// The token `store` stored for the Vault at `address`.
+pub fn stored(address: &str) -> Option<String> {
+    if let Ok(token) = fs::read_to_string(token_file(address).ok()?) {
+        return Some(token.trim().to_string());
+    }
+
+    let output = Command::new("secret-tool")
+        .args(&["lookup", "service", KEYRING_SERVICE, "address", address])
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    let token = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if output.status.success() && !token.is_empty() {
+        Some(token)
+    } else {
+        None
+    }
+}
+
+/// Point the Vault requests of bitte and the `vault` commands it runs at `address`, with the
+/// token `bitte login` stored for it, unless `VAULT_ADDR` and `VAULT_TOKEN` say otherwise.
+pub fn export(address: &str) {
+    if env::var("VAULT_ADDR").map_or(true, |set| set.is_empty()) {
+        env::set_var("VAULT_ADDR", address);
+    }
+    if env::var("VAULT_TOKEN").map_or(true, |set| set.is_empty()) {
+        let address = env::var("VAULT_ADDR").unwrap_or_default();
+        if let Some(token) = stored(&address) {
+            env::set_var("VAULT_TOKEN", token);
+        }
+    }
+}
@@ -0,0 +1,316 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Context, Result};
+use log::warn;
+use tokio::sync::mpsc::{self, UnboundedSender};
+
+use crate::{
+    budget::{self, Provider},
+    cancel::CancellationToken,
+    error::Error,
+    job,
+    nomad::NomadApi,
+    reconnect::Reconnect,
+    text,
+    types::NomadNodeAllocation,
+};
+
+/// Which stream of a task to read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogKind {
+    Stdout,
+    Stderr,
+}
+
+impl LogKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            LogKind::Stdout => "stdout",
+            LogKind::Stderr => "stderr",
+        }
+    }
+}
+
+/// A line of log output and the allocation and task it came from.
+#[derive(Debug)]
+pub struct LogLine {
+    pub label: String,
+    pub text: String,
+}
+
+/// One task of one allocation to stream the logs of.
+#[derive(Debug, Clone)]
+pub struct LogSource {
+    pub alloc_id: String,
+    pub task: String,
+    pub label: String,
+}
+
+fn looks_like_alloc_id(target: &str) -> bool {
+    target.len() >= 8 && target.chars().all(|c| c.is_ascii_hexdigit() || c == '-')
+}
+
+/// Resolve `target`, either the (prefix of an) allocation id or the name of a job, to the
+/// allocations to read. For a job these are its running allocations, or its latest one if none
+/// is running, so the logs of a crashed job can still be read. The job is looked up in all
+/// namespaces if `namespace` is `*`.
+pub async fn allocations(
+    nomad: &NomadApi,
+    namespace: &str,
+    target: &str,
+) -> Result<Vec<NomadNodeAllocation>> {
+    if looks_like_alloc_id(target) {
+        let allocs: Vec<NomadNodeAllocation> = nomad
+            .get("/v1/allocations", &[("prefix", target), ("namespace", "*")])
+            .await?;
+        match allocs.len() {
+            0 => {}
+            1 => return Ok(allocs),
+            _ => bail!(
+                "{} matches {} allocations, use a longer prefix",
+                target,
+                allocs.len()
+            ),
+        }
+    }
+
+    let namespace = job::resolve_namespace(nomad, namespace, target).await?;
+    let mut allocs: Vec<NomadNodeAllocation> = nomad
+        .get(
+            &format!("/v1/job/{}/allocations", target),
+            &[("namespace", namespace.as_str())],
+        )
+        .await
+        .with_context(|| {
+            format!(
+                "{} is neither an allocation nor a job in {}",
+                target, namespace
+            )
+        })?;
+
+    if allocs.is_empty() {
+        bail!("{} has no allocations", target);
+    }
+
+    if allocs.iter().any(|alloc| alloc.client_status == "running") {
+        allocs.retain(|alloc| alloc.client_status == "running");
+    } else {
+        allocs.sort_by_key(|alloc| std::cmp::Reverse(alloc.create_index));
+        allocs.truncate(1);
+    }
+    allocs.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(allocs)
+}
+
+/// The tasks to read for each allocation, all of them unless `task` is given.
+pub fn sources(allocs: &[NomadNodeAllocation], task: Option<&str>) -> Result<Vec<LogSource>> {
+    let mut sources = Vec::new();
+
+    for alloc in allocs.iter() {
+        let mut tasks: Vec<&String> = alloc.task_states.keys().collect();
+        tasks.sort();
+
+        let tasks: Vec<String> = match task {
+            Some(task) if tasks.iter().any(|t| *t == task) => vec![task.to_string()],
+            Some(task) => bail!(
+                "allocation {} has no task {}, its tasks are {:?}",
+                alloc.name,
+                task,
+                tasks
+            ),
+            None => tasks.into_iter().cloned().collect(),
+        };
+
+        for task in tasks {
+            sources.push(LogSource {
+                label: format!("{}/{}", &alloc.id[..8.min(alloc.id.len())], task),
+                alloc_id: alloc.id.clone(),
+                task,
+            });
+        }
+    }
+
+    if sources.is_empty() {
+        bail!("the allocations haven't started any tasks yet");
+    }
+
+    Ok(sources)
+}
+
+/// Read the logs of `source` from `offset` on, passing every line to `send` and moving `offset`
+/// along with every byte received. A line cut off by a disconnect stays in `buffer`.
+#[allow(clippy::too_many_arguments)]
+async fn read_from(
+    nomad: &NomadApi,
+    source: &LogSource,
+    kind: LogKind,
+    follow: bool,
+    offset: &mut u64,
+    buffer: &mut Vec<u8>,
+    reconnect: &mut Reconnect,
+    send: &(dyn Fn(&[u8]) + Sync),
+) -> Result<()> {
+    let url = nomad.url(&format!("/v1/client/fs/logs/{}", source.alloc_id));
+    let follow = if follow { "true" } else { "false" };
+    let start = offset.to_string();
+    budget::acquire(Provider::Nomad).await?;
+    let mut response = nomad
+        .client
+        .get(&url)
+        .query(&[
+            ("task", source.task.as_str()),
+            ("type", kind.as_str()),
+            ("origin", "start"),
+            ("offset", start.as_str()),
+            ("plain", "true"),
+            ("follow", follow),
+        ])
+        .send()
+        .await
+        .with_context(|| format!("failed to query: {}", &url))?
+        .error_for_status()?;
+
+    if let Some(gap) = reconnect.connected() {
+        warn!(
+            "{}: reconnected after {}s, resuming at byte {}",
+            source.label,
+            gap.as_secs(),
+            offset
+        );
+    }
+
+    while let Some(chunk) = response.chunk().await? {
+        *offset += chunk.len() as u64;
+        buffer.extend_from_slice(&chunk);
+        while let Some(end) = buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=end).collect();
+            send(&line[..line.len() - 1]);
+        }
+    }
+
+    Ok(())
+}
+
+/// Read the logs of `source` line by line into `lines`, and keep reading as long as the task
+/// runs if `follow` is set. When the connection breaks, reading resumes at the byte it broke
+/// off at, so nothing is lost or printed twice.
+async fn read(
+    nomad: NomadApi,
+    source: LogSource,
+    kind: LogKind,
+    follow: bool,
+    lines: UnboundedSender<LogLine>,
+    cancel: CancellationToken,
+) -> Result<()> {
+    let send = |bytes: &[u8]| {
+        // the receiver only goes away when the output is closed
+        let _ = lines.send(LogLine {
+            label: source.label.clone(),
+            text: text::decode(bytes).text,
+        });
+    };
+
+    let mut offset = 0;
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut reconnect = Reconnect::default();
+    let what = format!("the logs of {}", source.label);
+    loop {
+        let result = read_from(
+            &nomad,
+            &source,
+            kind,
+            follow,
+            &mut offset,
+            &mut buffer,
+            &mut reconnect,
+            &send,
+        )
+        .await;
+        match result {
+            Ok(()) => break,
+            Err(e) => reconnect.wait(&what, e, &cancel).await?,
+        }
+    }
+    if !buffer.is_empty() {
+        send(&buffer);
+    }
+
+    Ok(())
+}
+
+/// Stream the logs of all `sources` at once, calling `print` with every line as it arrives, so
+/// the output of several allocations is interleaved in the order it was written.
+///
+/// Cancelling closes the connections right away.
+pub async fn stream<F>(
+    nomad: &NomadApi,
+    sources: Vec<LogSource>,
+    kind: LogKind,
+    follow: bool,
+    cancel: &CancellationToken,
+    mut print: F,
+) -> Result<()>
+where
+    F: FnMut(LogLine),
+{
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+
+    let handles: HashMap<String, _> = sources
+        .into_iter()
+        .map(|source| {
+            let label = source.label.clone();
+            let handle = tokio::spawn(read(
+                nomad.clone(),
+                source,
+                kind,
+                follow,
+                sender.clone(),
+                cancel.clone(),
+            ));
+            (label, handle)
+        })
+        .collect();
+    drop(sender);
+
+    loop {
+        tokio::select! {
+            _ = cancel.cancelled() => {
+                for handle in handles.values() {
+                    handle.abort();
+                }
+                return Err(Error::Cancelled.into());
+            }
+            line = receiver.recv() => match line {
+                Some(line) => print(line),
+                None => break,
+            },
+        }
+    }
+
+    let mut failed = Vec::new();
+    for (label, handle) in handles.into_iter() {
+        if let Err(e) = handle.await? {
+            log::error!("{}: {:?}", label, e);
+            failed.push(label);
+        }
+    }
+    if !failed.is_empty() {
+        bail!("couldn't read the logs of {:?}", failed);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::looks_like_alloc_id;
+
+    #[test]
+    fn test_looks_like_alloc_id() {
+        assert!(looks_like_alloc_id("8a3f12c4"));
+        assert!(looks_like_alloc_id("8a3f12c4-09b1-7e2d-5c6a-1f0e9d8c7b6a"));
+        assert!(!looks_like_alloc_id("cardano"));
+        assert!(!looks_like_alloc_id("facade"));
+    }
+}
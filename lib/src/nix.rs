@@ -0,0 +1,204 @@
+use std::{
+    fs,
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde_json::Value;
+
+use crate::{error::Error, runs::Run};
+
+/// `resultType` of build log lines in nix's internal-json log format.
+const RESULT_BUILD_LOG_LINE: u64 = 101;
+
+/// The essentials of a failed evaluation, extracted from nix's internal-json log.
+#[derive(Debug, PartialEq, Eq)]
+pub struct EvalError {
+    pub message: String,
+    /// The innermost attribute nix was evaluating when the error occurred.
+    pub attribute: Option<String>,
+    /// `file:line:column` of the error, or of the closest trace entry that has one.
+    pub location: Option<String>,
+}
+
+fn strip_ansi(text: &str) -> String {
+    let ansi = Regex::new(r"\x1b\[[0-9;]*[A-Za-z]").expect("invalid ansi pattern");
+    ansi.replace_all(text, "").to_string()
+}
+
+/// Parse one line of `--log-format internal-json` output, `@nix {...}`.
+pub fn parse_log_line(line: &str) -> Option<Value> {
+    serde_json::from_str(line.strip_prefix("@nix ")?).ok()
+}
+
+fn position(entry: &Value) -> Option<String> {
+    let pos = if entry["pos"].is_object() {
+        &entry["pos"]
+    } else {
+        entry
+    };
+    let file = pos["file"].as_str()?;
+    match (pos["line"].as_u64(), pos["column"].as_u64()) {
+        (Some(line), Some(column)) => Some(format!("{}:{}:{}", file, line, column)),
+        (Some(line), None) => Some(format!("{}:{}", file, line)),
+        _ => Some(file.to_string()),
+    }
+}
+
+/// Find the first error among the log messages and pinpoint where it happened.
+pub fn eval_error(messages: &[Value]) -> Option<EvalError> {
+    let error = messages
+        .iter()
+        .find(|msg| msg["action"] == "msg" && msg["level"] == 0)?;
+
+    let text = error["raw_msg"]
+        .as_str()
+        .or_else(|| error["msg"].as_str())
+        .unwrap_or_default();
+    let message = strip_ansi(text)
+        .trim_start_matches("error: ")
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_string();
+
+    let attribute_pattern =
+        Regex::new(r"while evaluating the attribute '([^']+)'").expect("invalid pattern");
+    let trace: Vec<&Value> = error["trace"]
+        .as_array()
+        .map(|trace| trace.iter().collect())
+        .unwrap_or_default();
+
+    let attribute = trace.iter().rev().find_map(|entry| {
+        let raw = strip_ansi(entry["raw_msg"].as_str()?);
+        attribute_pattern
+            .captures(&raw)
+            .map(|captures| captures[1].to_string())
+    });
+
+    let location = position(error).or_else(|| trace.iter().rev().find_map(|entry| position(entry)));
+
+    Some(EvalError {
+        message,
+        attribute,
+        location,
+    })
+}
+
+/// The name of the build log next to `out_link`, one per system built in the same run, like
+/// `result-core-1.log`.
+fn log_name(out_link: &Path) -> String {
+    let name = out_link
+        .file_name()
+        .map_or("result".into(), |name| name.to_string_lossy());
+    format!("{}.log", name)
+}
+
+/// Build `target` with its out link at `out_link`, passing build logs through to the terminal.
+///
+/// When nix fails, the complete log is kept in the run directory and the error is reduced to
+/// the failing attribute and its location instead of the whole evaluation trace.
+pub fn build(target: &str, out_link: &Path, run: &Run) -> Result<()> {
+    let mut child = Command::new("nix")
+        .arg("build")
+        .arg("--log-format")
+        .arg("internal-json")
+        .arg("-L")
+        .arg("--out-link")
+        .arg(out_link)
+        .arg(target)
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("couldn't run nix build {}", target))?;
+
+    let log_path = run.path(&log_name(out_link));
+    let mut log = fs::File::create(&log_path)?;
+    let mut errors = Vec::new();
+
+    let stderr = child.stderr.take().context("nix build has no stderr")?;
+    for line in BufReader::new(stderr).lines() {
+        let line = line?;
+        writeln!(log, "{}", line)?;
+
+        match parse_log_line(&line) {
+            Some(msg) if msg["action"] == "msg" && msg["level"] == 0 => errors.push(msg),
+            Some(msg)
+                if msg["action"] == "msg"
+                    && matches!(msg["level"].as_u64(), Some(level) if level <= 1) =>
+            {
+                eprintln!("{}", msg["msg"].as_str().unwrap_or_default())
+            }
+            Some(msg)
+                if msg["action"] == "result"
+                    && msg["type"].as_u64() == Some(RESULT_BUILD_LOG_LINE) =>
+            {
+                eprintln!("{}", msg["fields"][0].as_str().unwrap_or_default())
+            }
+            Some(_) => (),
+            None => eprintln!("{}", line),
+        }
+    }
+
+    let status = child.wait()?;
+    if status.success() {
+        return Ok(());
+    }
+
+    match eval_error(&errors) {
+        Some(error) => Err(Error::NixEvalError {
+            attribute: error.attribute.unwrap_or_else(|| target.to_string()),
+            location: error
+                .location
+                .unwrap_or_else(|| "unknown location".to_string()),
+            message: error.message,
+            log: log_path.display().to_string(),
+        }
+        .into()),
+        None => Err(Error::ExeError {
+            details: format!(
+                "nix build {} failed with {}, see {}",
+                target,
+                status,
+                log_path.display()
+            ),
+        }
+        .into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{eval_error, log_name, parse_log_line, EvalError};
+    use pretty_assertions::assert_eq;
+    use std::path::Path;
+
+    #[test]
+    fn test_eval_error() {
+        let line = r#"@nix {"action":"msg","level":0,"msg":"\u001b[31;1merror:\u001b[0m undefined variable 'pkgs'","raw_msg":"undefined variable 'pkgs'","file":"/nix/store/abc-source/profiles/core.nix","line":12,"column":5,"trace":[{"raw_msg":"while evaluating the attribute 'config.system.build.toplevel'","pos":{"file":"/nix/store/def-source/nixos/modules/system/activation/top-level.nix","line":124,"column":3}},{"raw_msg":"while evaluating the attribute 'services.consul.extraConfig'","pos":{"file":"/nix/store/abc-source/profiles/consul.nix","line":40,"column":7}}]}"#;
+
+        let messages = vec![parse_log_line(line).unwrap()];
+        assert_eq!(
+            eval_error(&messages),
+            Some(EvalError {
+                message: "undefined variable 'pkgs'".to_string(),
+                attribute: Some("services.consul.extraConfig".to_string()),
+                location: Some("/nix/store/abc-source/profiles/core.nix:12:5".to_string()),
+            })
+        );
+
+        assert_eq!(parse_log_line("building '/nix/store/x.drv'..."), None);
+    }
+
+    #[test]
+    fn test_log_name() {
+        assert_eq!(
+            log_name(Path::new("/tmp/runs/1-2-rebuild/result-core-1")),
+            "result-core-1.log"
+        );
+        assert_eq!(log_name(Path::new("/")), "result.log");
+    }
+}
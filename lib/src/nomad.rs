@@ -1,7 +1,16 @@
-use super::sh;
-use anyhow::Result;
-use serde::{Deserialize, Serialize};
+use crate::{
+    acl,
+    budget::{self, Provider},
+    endpoint::TlsFiles,
+    pool::ClientPool,
+    types::BitteCluster,
+};
+use anyhow::{bail, Context, Result};
+use clap::ArgMatches;
+use reqwest::Client;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::collections::HashMap;
+use std::sync::Arc;
 
 // TODO: check that we have developer or admin policies
 /*
@@ -18,23 +27,113 @@ Create Index = 2492001
 Modify Index = 2492001
 */
 
-pub fn nomad_token() -> Result<String> {
-    sh(execute::command_args!(
-        "vault",
-        "read",
-        "-field",
-        "secret_id",
-        "nomad/creds/admin"
-    ))
-    .or_else(|_| {
-        sh(execute::command_args!(
-            "vault",
-            "read",
-            "-field",
-            "secret_id",
-            "nomad/creds/developer"
-        ))
-    })
+/// The Nomad token Vault issues to the user for the cluster at `domain`, cached until it is
+/// about to expire.
+pub async fn nomad_token(domain: &str) -> Result<String> {
+    acl::session_token(domain).await
+}
+
+/// Lets queries that support it read from every namespace.
+pub const ALL_NAMESPACES: &str = "*";
+/// Where jobs are written unless another namespace is given.
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+/// The namespace a command reads from: the one given with `--namespace`, or all of them.
+pub fn read_namespace(args: &ArgMatches) -> String {
+    args.value_of("namespace")
+        .unwrap_or(ALL_NAMESPACES)
+        .to_string()
+}
+
+/// The namespace a command writes to: the one given with `--namespace`, or `default`.
+pub fn write_namespace(args: &ArgMatches) -> Result<String> {
+    match args.value_of("namespace") {
+        Some(ALL_NAMESPACES) => bail!("writes need a single namespace, not {}", ALL_NAMESPACES),
+        Some(namespace) => Ok(namespace.to_string()),
+        None => Ok(DEFAULT_NAMESPACE.to_string()),
+    }
+}
+
+/// Thin wrapper around the Nomad HTTP API of one cluster.
+#[derive(Clone)]
+pub struct NomadApi {
+    pub client: Arc<Client>,
+    pub address: String,
+}
+
+impl NomadApi {
+    pub fn new(pool: &ClientPool, address: &str, token: &str, tls: &TlsFiles) -> Result<Self> {
+        Ok(Self {
+            client: pool.nomad(address, token, tls)?,
+            address: address.trim_end_matches('/').to_string(),
+        })
+    }
+
+    pub fn for_cluster(cluster: &BitteCluster) -> Self {
+        Self {
+            client: Arc::clone(&cluster.nomad_api_client),
+            address: cluster.nomad_address(),
+        }
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{}", self.address, path)
+    }
+
+    pub async fn get<T: DeserializeOwned>(&self, path: &str, query: &[(&str, &str)]) -> Result<T> {
+        let url = self.url(path);
+        budget::acquire(Provider::Nomad).await?;
+        self.client
+            .get(&url)
+            .query(query)
+            .send()
+            .await
+            .with_context(|| format!("failed to query: {}", &url))?
+            .error_for_status()?
+            .json::<T>()
+            .await
+            .with_context(|| format!("failed to decode response from: {}", &url))
+    }
+
+    pub async fn post<B: Serialize + ?Sized, T: DeserializeOwned>(
+        &self,
+        path: &str,
+        query: &[(&str, &str)],
+        body: &B,
+    ) -> Result<T> {
+        let url = self.url(path);
+        budget::acquire(Provider::Nomad).await?;
+        self.client
+            .post(&url)
+            .query(query)
+            .json(body)
+            .send()
+            .await
+            .with_context(|| format!("failed to post: {}", &url))?
+            .error_for_status()?
+            .json::<T>()
+            .await
+            .with_context(|| format!("failed to decode response from: {}", &url))
+    }
+
+    pub async fn delete<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        query: &[(&str, &str)],
+    ) -> Result<T> {
+        let url = self.url(path);
+        budget::acquire(Provider::Nomad).await?;
+        self.client
+            .delete(&url)
+            .query(query)
+            .send()
+            .await
+            .with_context(|| format!("failed to delete: {}", &url))?
+            .error_for_status()?
+            .json::<T>()
+            .await
+            .with_context(|| format!("failed to decode response from: {}", &url))
+    }
 }
 
 impl std::fmt::Display for Topic {
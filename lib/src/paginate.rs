@@ -0,0 +1,92 @@
+use std::future::Future;
+
+use anyhow::Result;
+use aws_sdk_ec2::model::{Filter, Instance};
+
+use crate::budget::{self, Provider};
+
+/// Every item a paginated AWS API lists, fetching page after page until there is no next
+/// token. `fetch` gets the token of the page to fetch, none for the first one, and returns its
+/// items and the token of the next page. Each page counts against the AWS budget.
+///
+/// The pages of one listing can only be fetched one after the other, since each names the
+/// next; separate listings, like those of different regions, are fetched in parallel instead.
+pub async fn all_pages<T, F, Fut>(mut fetch: F) -> Result<Vec<T>>
+where
+    F: FnMut(Option<String>) -> Fut,
+    Fut: Future<Output = Result<(Vec<T>, Option<String>)>>,
+{
+    let mut items = Vec::new();
+    let mut token = None;
+    loop {
+        budget::acquire(Provider::Aws).await?;
+        let (page, next) = fetch(token).await?;
+        items.extend(page);
+        // some APIs end with an empty token rather than none
+        token = next.filter(|next| !next.is_empty());
+        if token.is_none() {
+            return Ok(items);
+        }
+    }
+}
+
+/// All instances matching `filters`, or with the `ids` if given.
+pub async fn ec2_instances(
+    client: &aws_sdk_ec2::Client,
+    filters: Vec<Filter>,
+    ids: Vec<String>,
+) -> Result<Vec<Instance>> {
+    all_pages(|token| {
+        let mut request = client
+            .describe_instances()
+            .set_filters(Some(filters.clone()).filter(|filters| !filters.is_empty()))
+            .set_next_token(token);
+        // the page size can't be set when asking for instances by ID
+        request = if ids.is_empty() {
+            request.max_results(1000)
+        } else {
+            request.set_instance_ids(Some(ids.clone()))
+        };
+        async move {
+            let page = request.send().await?;
+            let instances = page
+                .reservations
+                .unwrap_or_default()
+                .into_iter()
+                .flat_map(|reservation| reservation.instances.unwrap_or_default())
+                .collect();
+            Ok((instances, page.next_token))
+        }
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::all_pages;
+    use pretty_assertions::assert_eq;
+
+    #[tokio::test]
+    async fn test_all_pages() {
+        let pages = [
+            (vec![1, 2], Some("b".to_string())),
+            (vec![3], Some("c".to_string())),
+            (vec![4, 5], Some(String::new())),
+        ];
+        let mut tokens = Vec::new();
+        let items = all_pages(|token| {
+            let index = tokens.len();
+            tokens.push(token);
+            let page = pages[index].clone();
+            async move { Ok(page) }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(items, vec![1, 2, 3, 4, 5]);
+        assert_eq!(
+            tokens,
+            vec![None, Some("b".to_string()), Some("c".to_string())]
+        );
+    }
+}
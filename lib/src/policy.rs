@@ -0,0 +1,354 @@
+use std::{
+    io::Write,
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+use anyhow::{bail, Context, Result};
+use log::warn;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::history;
+
+/// Subcommands that change a cluster and are checked against the policy before they run.
+pub const MUTATING: &[&str] = &[
+    "rebuild",
+    "deploy",
+    "provision",
+    "certs",
+    "runbook",
+    "campaign",
+    "terraform apply",
+    "terraform apply-all",
+    "terraform destroy",
+    "terraform import",
+    "terraform init",
+    "terraform passthrough",
+    "terraform vars set",
+    "terraform vars rm",
+    "terraform lock",
+    "terraform unlock",
+    "nomad token create",
+    "nomad token revoke",
+    "vault unseal",
+    "vault policies sync",
+    "vault leases",
+    "consul token bootstrap",
+    "consul token create",
+    "consul token revoke",
+    "consul intentions allow",
+    "consul intentions deny",
+    "restore",
+    "secret put",
+    "node reboot",
+    "node stop",
+    "node start",
+    "node drain",
+    "node eligibility",
+    "node store repair",
+    "job promote",
+    "job restart",
+    "job scale",
+    "job run",
+    "job stop",
+    "deployment promote",
+    "deployment fail",
+    "deployment pause",
+    "alloc restart",
+];
+
+/// Guards for dangerous operations, from the `policy` section of `bitte.json`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct PolicyConfig {
+    pub rules: Vec<PolicyRule>,
+    /// A rego file evaluated with `opa`, every message in `data.bitte.deny` denies the action.
+    pub rego: Option<PathBuf>,
+}
+
+/// A rule applies to an action if its command, cluster, and arguments match. It then denies the
+/// action unless the user is one of `users` and all of `require-args` were given. A rule without
+/// either always denies.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct PolicyRule {
+    pub name: String,
+    /// Subcommand this rule applies to, like `terraform destroy`, or just `terraform` for all
+    /// of its subcommands. Empty for every mutating subcommand.
+    pub command: String,
+    /// Patterns of cluster names, empty for all clusters.
+    pub clusters: Vec<String>,
+    /// Pattern the arguments, joined by spaces, have to match, e.g. `core-\d` for core nodes.
+    pub arguments: Option<String>,
+    pub users: Vec<String>,
+    pub require_args: Vec<String>,
+    /// Shown to the user when the rule denies an action.
+    pub reason: String,
+}
+
+/// An invocation of a mutating subcommand.
+#[derive(Debug, Clone, Serialize)]
+pub struct Action {
+    /// Names of the subcommands, e.g. `["terraform", "destroy"]`.
+    pub command: Vec<String>,
+    pub cluster: String,
+    pub user: String,
+    /// The arguments after the global options.
+    pub args: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Decision {
+    pub allowed: bool,
+    /// Why each denying rule denied the action.
+    pub reasons: Vec<String>,
+}
+
+impl Action {
+    pub fn is_mutating(&self) -> bool {
+        let command = self.command.join(" ");
+        MUTATING
+            .iter()
+            .any(|mutating| command == *mutating || command.starts_with(&format!("{} ", mutating)))
+    }
+}
+
+fn matches_command(rule: &str, command: &[String]) -> bool {
+    let rule: Vec<&str> = rule.split_whitespace().collect();
+    rule.len() <= command.len() && rule.iter().zip(command).all(|(r, c)| *r == c.as_str())
+}
+
+fn full_match(pattern: &str, text: &str) -> Result<bool> {
+    let regex = Regex::new(&format!("^(?:{})$", pattern))
+        .with_context(|| format!("invalid pattern {} in policy", pattern))?;
+    Ok(regex.is_match(text))
+}
+
+impl PolicyRule {
+    fn applies(&self, action: &Action) -> Result<bool> {
+        if !matches_command(&self.command, &action.command) {
+            return Ok(false);
+        }
+
+        let mut cluster_matches = self.clusters.is_empty();
+        for pattern in self.clusters.iter() {
+            cluster_matches |= full_match(pattern, &action.cluster)?;
+        }
+        if !cluster_matches {
+            return Ok(false);
+        }
+
+        match &self.arguments {
+            Some(pattern) => {
+                let regex = Regex::new(pattern)
+                    .with_context(|| format!("invalid pattern {} in policy", pattern))?;
+                Ok(regex.is_match(&action.args.join(" ")))
+            }
+            None => Ok(true),
+        }
+    }
+
+    /// Why this rule denies `action`, if it does.
+    fn denies(&self, action: &Action) -> Result<Option<String>> {
+        if !self.applies(action)? {
+            return Ok(None);
+        }
+
+        let unconditional = self.users.is_empty() && self.require_args.is_empty();
+        let user_allowed = self.users.is_empty() || self.users.contains(&action.user);
+        let missing: Vec<&String> = self
+            .require_args
+            .iter()
+            .filter(|arg| {
+                !action
+                    .args
+                    .iter()
+                    .any(|given| given == *arg || given.starts_with(&format!("{}=", arg)))
+            })
+            .collect();
+
+        if !unconditional && user_allowed && missing.is_empty() {
+            return Ok(None);
+        }
+
+        let mut reason = format!("{}: {}", self.name, self.reason);
+        if !user_allowed {
+            reason.push_str(&format!(" (only {} may do this)", self.users.join(", ")));
+        }
+        if !missing.is_empty() {
+            reason.push_str(&format!(" (missing {:?})", missing));
+        }
+        Ok(Some(reason))
+    }
+}
+
+/// Ask `opa` for the messages of `data.bitte.deny` with the action as input.
+fn evaluate_rego(path: &PathBuf, action: &Action) -> Result<Vec<String>> {
+    let mut child = Command::new("opa")
+        .arg("eval")
+        .args(&["--format", "json", "--stdin-input", "--data"])
+        .arg(path)
+        .arg("data.bitte.deny")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .context("couldn't run opa, is it installed?")?;
+
+    child
+        .stdin
+        .take()
+        .context("opa has no stdin")?
+        .write_all(&serde_json::to_vec(action)?)?;
+
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        bail!("opa failed to evaluate {}", path.display());
+    }
+
+    let result: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    let messages = match result.pointer("/result/0/expressions/0/value") {
+        Some(serde_json::Value::Array(messages)) => messages
+            .iter()
+            .map(|message| match message {
+                serde_json::Value::String(message) => message.clone(),
+                other => other.to_string(),
+            })
+            .collect(),
+        // an undefined rule denies nothing
+        _ => Vec::new(),
+    };
+    Ok(messages)
+}
+
+impl PolicyConfig {
+    pub fn evaluate(&self, action: &Action) -> Result<Decision> {
+        let mut reasons = Vec::new();
+        for rule in self.rules.iter() {
+            if let Some(reason) = rule.denies(action)? {
+                reasons.push(reason);
+            }
+        }
+        if let Some(path) = &self.rego {
+            reasons.extend(evaluate_rego(path, action)?);
+        }
+
+        Ok(Decision {
+            allowed: reasons.is_empty(),
+            reasons,
+        })
+    }
+
+    /// Evaluate the policy for a mutating `action`, record the decision in the history of the
+    /// cluster, and fail if it was denied.
+    pub fn check(&self, action: &Action) -> Result<()> {
+        if !action.is_mutating() || (self.rules.is_empty() && self.rego.is_none()) {
+            return Ok(());
+        }
+
+        let decision = self.evaluate(action)?;
+        if let Err(e) = history::record(
+            &action.cluster,
+            "policy",
+            json!({ "action": action, "decision": decision }),
+        ) {
+            warn!("couldn't record the policy decision: {:?}", e);
+        }
+
+        if !decision.allowed {
+            bail!(
+                "{} is denied by policy:\n  {}",
+                action.command.join(" "),
+                decision.reasons.join("\n  ")
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Action, PolicyConfig, PolicyRule};
+    use pretty_assertions::assert_eq;
+
+    fn action(command: &str, cluster: &str, user: &str, args: &str) -> Action {
+        Action {
+            command: command.split(' ').map(String::from).collect(),
+            cluster: cluster.to_string(),
+            user: user.to_string(),
+            args: args.split(' ').map(String::from).collect(),
+        }
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let policy = PolicyConfig {
+            rules: vec![
+                PolicyRule {
+                    name: "no-destroy".to_string(),
+                    command: "terraform destroy".to_string(),
+                    reason: "never destroy".to_string(),
+                    ..Default::default()
+                },
+                PolicyRule {
+                    name: "prod-two-phase".to_string(),
+                    command: "rebuild".to_string(),
+                    clusters: vec!["prod-.*".to_string()],
+                    require_args: vec!["--two-phase".to_string()],
+                    reason: "rebuild prod in two phases".to_string(),
+                    ..Default::default()
+                },
+                PolicyRule {
+                    name: "core-drain".to_string(),
+                    command: "node drain".to_string(),
+                    arguments: Some(r"core-\d".to_string()),
+                    users: vec!["alice".to_string()],
+                    reason: "core nodes".to_string(),
+                    ..Default::default()
+                },
+            ],
+            rego: None,
+        };
+
+        let denied = |action: &Action| policy.evaluate(action).unwrap().reasons;
+
+        assert_eq!(
+            denied(&action(
+                "terraform destroy",
+                "testnet",
+                "alice",
+                "core destroy"
+            )),
+            vec!["no-destroy: never destroy".to_string()]
+        );
+        assert_eq!(
+            denied(&action("rebuild", "prod-eu", "bob", "rebuild --two-phase")),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            denied(&action("rebuild", "prod-eu", "bob", "rebuild")),
+            vec![
+                r#"prod-two-phase: rebuild prod in two phases (missing ["--two-phase"])"#
+                    .to_string()
+            ]
+        );
+        assert_eq!(
+            denied(&action("rebuild", "testnet", "bob", "rebuild")),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            denied(&action("node drain", "testnet", "bob", "node drain core-1")),
+            vec!["core-drain: core nodes (only alice may do this)".to_string()]
+        );
+        assert_eq!(
+            denied(&action(
+                "node drain",
+                "testnet",
+                "bob",
+                "node drain client-1"
+            )),
+            Vec::<String>::new()
+        );
+    }
+}
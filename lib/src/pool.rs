@@ -0,0 +1,167 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use aws_types::{config::Config as AwsConfig, region::Region};
+use once_cell::sync::Lazy;
+use reqwest::{
+    header::{HeaderMap, HeaderValue},
+    Client,
+};
+use tokio::sync::OnceCell;
+
+use crate::{
+    endpoint::{self, Service, TlsFiles},
+    sts,
+};
+
+/// API clients shared by every request of one invocation, so connections (and their TLS
+/// handshakes) are reused instead of being set up again by each call.
+///
+/// Cloning is cheap and all clones share the same clients.
+#[derive(Clone, Default)]
+pub struct ClientPool {
+    http: Arc<Mutex<HashMap<String, Arc<Client>>>>,
+    aws: Arc<OnceCell<AwsConfig>>,
+    ec2: Arc<Mutex<HashMap<String, aws_sdk_ec2::Client>>>,
+    autoscaling: Arc<Mutex<HashMap<String, aws_sdk_autoscaling::Client>>>,
+    pricing: Arc<OnceCell<aws_sdk_pricing::Client>>,
+}
+
+impl std::fmt::Debug for ClientPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClientPool").finish()
+    }
+}
+
+impl ClientPool {
+    /// The pool of requests that aren't handed one, like those to Vault and Consul at the
+    /// addresses `endpoint::configure` exported.
+    pub fn shared() -> &'static ClientPool {
+        static SHARED: Lazy<ClientPool> = Lazy::new(Default::default);
+        &SHARED
+    }
+
+    /// A HTTP client sending `headers` with every request, one per distinct set of headers.
+    pub fn http(&self, headers: &[(&'static str, &str)]) -> Result<Arc<Client>> {
+        self.client(None, headers, false, &TlsFiles::default())
+    }
+
+    /// With `tunnel`, certificates are accepted for any host name as long as their chain is
+    /// trusted, since a tunnel reaches the service under another name than its certificate's.
+    fn client(
+        &self,
+        service: Option<Service>,
+        headers: &[(&'static str, &str)],
+        tunnel: bool,
+        tls: &TlsFiles,
+    ) -> Result<Arc<Client>> {
+        let key = headers
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .chain(service.map(|service| format!("service={}", service)))
+            .chain(Some(format!("tunnel={}", tunnel)))
+            .chain(Some(format!("tls={:?}", tls)))
+            .collect::<Vec<String>>()
+            .join("\n");
+
+        let mut clients = self.http.lock().expect("client pool poisoned");
+        if let Some(client) = clients.get(&key) {
+            return Ok(Arc::clone(client));
+        }
+
+        let mut header_map = HeaderMap::new();
+        for (name, value) in headers {
+            let mut value = HeaderValue::from_str(value)?;
+            value.set_sensitive(true);
+            header_map.insert(*name, value);
+        }
+
+        let builder = Client::builder()
+            .default_headers(header_map)
+            .gzip(true)
+            .danger_accept_invalid_hostnames(tunnel);
+        let client = Arc::new(tls.apply(builder)?.build()?);
+        clients.insert(key, Arc::clone(&client));
+        Ok(client)
+    }
+
+    /// The client for the Nomad API at `address` authenticated with `token`, and with the client
+    /// certificate in `tls` if the cluster requires one.
+    pub fn nomad(&self, address: &str, token: &str, tls: &TlsFiles) -> Result<Arc<Client>> {
+        self.client(
+            Some(Service::Nomad),
+            &[("X-Nomad-Token", token)],
+            endpoint::is_tunnel(address),
+            tls,
+        )
+    }
+
+    /// The client for `service` at `address` with the TLS files of its variables, so a client
+    /// certificate is bundled once instead of for every request.
+    pub fn service(&self, service: Service, address: &str) -> Result<Arc<Client>> {
+        self.client(
+            Some(service),
+            &[],
+            endpoint::is_tunnel(address),
+            &TlsFiles::from_env(service),
+        )
+    }
+
+    /// The AWS configuration every AWS client is built from, loaded once through the default
+    /// credential chain: the environment, profiles and SSO, web identity, and IMDSv2. An assumed
+    /// role takes their place, so its credentials are renewed during long runs.
+    pub async fn aws(&self) -> &AwsConfig {
+        self.aws
+            .get_or_init(|| async {
+                match sts::session() {
+                    Some(session) => {
+                        aws_config::from_env()
+                            .credentials_provider(session)
+                            .load()
+                            .await
+                    }
+                    None => aws_config::load_from_env().await,
+                }
+            })
+            .await
+    }
+
+    pub async fn ec2(&self, region: &str) -> aws_sdk_ec2::Client {
+        let config = aws_sdk_ec2::config::Builder::from(self.aws().await)
+            .region(Region::new(region.to_string()))
+            .build();
+        let mut clients = self.ec2.lock().expect("client pool poisoned");
+        clients
+            .entry(region.to_string())
+            .or_insert_with(|| aws_sdk_ec2::Client::from_conf(config))
+            .clone()
+    }
+
+    pub async fn autoscaling(&self, region: &str) -> aws_sdk_autoscaling::Client {
+        let config = aws_sdk_autoscaling::config::Builder::from(self.aws().await)
+            .region(Region::new(region.to_string()))
+            .build();
+        let mut clients = self.autoscaling.lock().expect("client pool poisoned");
+        clients
+            .entry(region.to_string())
+            .or_insert_with(|| aws_sdk_autoscaling::Client::from_conf(config))
+            .clone()
+    }
+
+    /// The Pricing API, which is only served from us-east-1.
+    pub async fn pricing(&self) -> aws_sdk_pricing::Client {
+        let config = self.aws().await;
+        self.pricing
+            .get_or_init(|| async move {
+                let config = aws_sdk_pricing::config::Builder::from(config)
+                    .region(Region::new("us-east-1"))
+                    .build();
+                aws_sdk_pricing::Client::from_conf(config)
+            })
+            .await
+            .clone()
+    }
+}
@@ -0,0 +1,269 @@
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+
+use crate::{
+    azure,
+    budget::{self, Provider},
+    gcp, local,
+    pool::ClientPool,
+    sh_async,
+    types::{BitteNode, BitteProvider},
+};
+
+/// Lifecycle operations on the machine backing a node, independent of the cloud it runs in.
+#[async_trait]
+pub trait NodePowerProvider: Send + Sync {
+    async fn reboot(&self, node: &BitteNode) -> Result<()>;
+    async fn stop(&self, node: &BitteNode) -> Result<()>;
+    async fn start(&self, node: &BitteNode) -> Result<()>;
+    /// The serial console output of the node, as the bytes it was written with.
+    async fn console(&self, node: &BitteNode) -> Result<Vec<u8>>;
+}
+
+pub fn for_provider(provider: BitteProvider, pool: &ClientPool) -> Box<dyn NodePowerProvider> {
+    match provider {
+        BitteProvider::AWS => Box::new(AwsPower { pool: pool.clone() }),
+        BitteProvider::GCP => Box::new(GcpPower),
+        BitteProvider::Azure => Box::new(AzurePower),
+        BitteProvider::Metal => Box::new(MetalPower),
+        BitteProvider::Local => Box::new(LocalPower),
+    }
+}
+
+pub struct AwsPower {
+    pool: ClientPool,
+}
+
+impl AwsPower {
+    async fn client(&self, node: &BitteNode) -> Result<aws_sdk_ec2::Client> {
+        budget::acquire(Provider::Aws).await?;
+        Ok(self.pool.ec2(&node.region()?).await)
+    }
+}
+
+#[async_trait]
+impl NodePowerProvider for AwsPower {
+    async fn reboot(&self, node: &BitteNode) -> Result<()> {
+        self.client(node)
+            .await?
+            .reboot_instances()
+            .instance_ids(&node.id)
+            .send()
+            .await
+            .with_context(|| format!("failed to reboot {}", node.id))?;
+        Ok(())
+    }
+
+    async fn stop(&self, node: &BitteNode) -> Result<()> {
+        self.client(node)
+            .await?
+            .stop_instances()
+            .instance_ids(&node.id)
+            .send()
+            .await
+            .with_context(|| format!("failed to stop {}", node.id))?;
+        Ok(())
+    }
+
+    async fn start(&self, node: &BitteNode) -> Result<()> {
+        self.client(node)
+            .await?
+            .start_instances()
+            .instance_ids(&node.id)
+            .send()
+            .await
+            .with_context(|| format!("failed to start {}", node.id))?;
+        Ok(())
+    }
+
+    async fn console(&self, node: &BitteNode) -> Result<Vec<u8>> {
+        let response = self
+            .client(node)
+            .await?
+            .get_console_output()
+            .instance_id(&node.id)
+            .latest(true)
+            .send()
+            .await
+            .with_context(|| format!("failed to fetch console output of {}", node.id))?;
+
+        Ok(base64::decode(response.output.unwrap_or_default())?)
+    }
+}
+
+/// Compute Engine instances, through `gcloud`. GCE addresses instances by name and zone.
+pub struct GcpPower;
+
+impl GcpPower {
+    async fn instances(&self, action: &str, node: &BitteNode) -> Result<String> {
+        let zone = node.zone.as_deref().unwrap_or_default();
+        let mut cmd = gcp::gcloud(&["compute", "instances", action, &node.name]);
+        if !zone.is_empty() {
+            cmd.arg("--zone").arg(zone);
+        }
+        budget::acquire(Provider::Gcp).await?;
+        sh_async(cmd)
+            .await
+            .with_context(|| format!("failed to {} {}", action, node.name))
+    }
+}
+
+#[async_trait]
+impl NodePowerProvider for GcpPower {
+    async fn reboot(&self, node: &BitteNode) -> Result<()> {
+        self.instances("reset", node).await?;
+        Ok(())
+    }
+
+    async fn stop(&self, node: &BitteNode) -> Result<()> {
+        self.instances("stop", node).await?;
+        Ok(())
+    }
+
+    async fn start(&self, node: &BitteNode) -> Result<()> {
+        self.instances("start", node).await?;
+        Ok(())
+    }
+
+    async fn console(&self, node: &BitteNode) -> Result<Vec<u8>> {
+        Ok(self
+            .instances("get-serial-port-output", node)
+            .await?
+            .into_bytes())
+    }
+}
+
+/// Azure virtual machines and the instances of uniform scale sets, through `az`. Stopping
+/// deallocates the machine, so like on AWS it isn't billed while stopped.
+pub struct AzurePower;
+
+impl AzurePower {
+    async fn run(&self, vm_action: &str, vmss_action: &str, node: &BitteNode) -> Result<String> {
+        // the ID of a scale set instance is the ID of the scale set, `/virtualMachines/`, and
+        // the instance ID
+        let cmd = match node.id.split_once("/virtualMachines/") {
+            Some((set, instance)) if set.contains("/virtualMachineScaleSets/") => azure::az(&[
+                "vmss",
+                vmss_action,
+                "--ids",
+                set,
+                "--instance-ids",
+                instance,
+            ]),
+            _ => azure::az(&["vm", vm_action, "--ids", &node.id]),
+        };
+        budget::acquire(Provider::Azure).await?;
+        sh_async(cmd)
+            .await
+            .with_context(|| format!("failed to {} {}", vm_action, node.name))
+    }
+}
+
+#[async_trait]
+impl NodePowerProvider for AzurePower {
+    async fn reboot(&self, node: &BitteNode) -> Result<()> {
+        self.run("restart", "restart", node).await?;
+        Ok(())
+    }
+
+    async fn stop(&self, node: &BitteNode) -> Result<()> {
+        self.run("deallocate", "deallocate", node).await?;
+        Ok(())
+    }
+
+    async fn start(&self, node: &BitteNode) -> Result<()> {
+        self.run("start", "start", node).await?;
+        Ok(())
+    }
+
+    async fn console(&self, node: &BitteNode) -> Result<Vec<u8>> {
+        if node.id.contains("/virtualMachineScaleSets/") {
+            bail!(
+                "az can't fetch the boot log of scale set instances like {}",
+                node.name
+            );
+        }
+        let cmd = azure::az(&["vm", "boot-diagnostics", "get-boot-log", "--ids", &node.id]);
+        budget::acquire(Provider::Azure).await?;
+        let output = sh_async(cmd)
+            .await
+            .with_context(|| format!("failed to fetch console output of {}", node.name))?;
+        // the log comes as a JSON string
+        let log: String = serde_json::from_str(&output).unwrap_or(output);
+        Ok(log.into_bytes())
+    }
+}
+
+/// Bare-metal machines, which bitte has no API to power them through.
+pub struct MetalPower;
+
+impl MetalPower {
+    fn unsupported<T>(&self, action: &str, node: &BitteNode) -> Result<T> {
+        bail!(
+            "can't {} {}, bare-metal nodes are managed through their provider's console",
+            action,
+            node.name
+        )
+    }
+}
+
+#[async_trait]
+impl NodePowerProvider for MetalPower {
+    async fn reboot(&self, node: &BitteNode) -> Result<()> {
+        self.unsupported("reboot", node)
+    }
+
+    async fn stop(&self, node: &BitteNode) -> Result<()> {
+        self.unsupported("stop", node)
+    }
+
+    async fn start(&self, node: &BitteNode) -> Result<()> {
+        self.unsupported("start", node)
+    }
+
+    async fn console(&self, node: &BitteNode) -> Result<Vec<u8>> {
+        self.unsupported("fetch the console of", node)
+    }
+}
+
+/// Local libvirt domains, through `virsh`. The ID of a node is the name of its domain, the
+/// machines of the hosts file go by their name instead and libvirt doesn't run them.
+pub struct LocalPower;
+
+impl LocalPower {
+    async fn virsh(&self, action: &str, node: &BitteNode) -> Result<()> {
+        if node.id == node.name {
+            bail!(
+                "can't {} {}, it comes from the hosts file rather than libvirt",
+                action,
+                node.name
+            );
+        }
+        sh_async(local::virsh(&[action, &node.id]))
+            .await
+            .with_context(|| format!("failed to {} {}", action, node.id))?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl NodePowerProvider for LocalPower {
+    async fn reboot(&self, node: &BitteNode) -> Result<()> {
+        self.virsh("reboot", node).await
+    }
+
+    async fn stop(&self, node: &BitteNode) -> Result<()> {
+        self.virsh("shutdown", node).await
+    }
+
+    async fn start(&self, node: &BitteNode) -> Result<()> {
+        self.virsh("start", node).await
+    }
+
+    async fn console(&self, node: &BitteNode) -> Result<Vec<u8>> {
+        Err(anyhow!(
+            "libvirt keeps no console log, attach with `virsh console {}`",
+            node.id
+        ))
+    }
+}
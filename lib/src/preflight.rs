@@ -0,0 +1,301 @@
+use std::{fmt, fs};
+
+use anyhow::{anyhow, bail, Context, Result};
+use aws_sdk_ec2::model::IpPermission;
+use colored::Colorize;
+use ring::signature::{Ed25519KeyPair, UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    archive,
+    budget::{self, Provider},
+    pool::ClientPool,
+    provision::Provision,
+    secret, sh,
+    types::BitteNode,
+};
+
+/// The key `bitte provision` signs the system in the binary cache with.
+pub const SIGNING_KEY: &str = "secrets/nix-secret-key-file";
+
+/// What has to be in place before `bitte provision` touches a node.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct PreflightConfig {
+    /// Secrets the nodes read from Vault while booting, like `secret/bitte/nix-key`.
+    pub secrets: Vec<String>,
+    /// Ports the security groups of a node have to open, ssh by default.
+    pub ports: Vec<u16>,
+}
+
+impl Default for PreflightConfig {
+    fn default() -> Self {
+        Self {
+            secrets: Vec::new(),
+            ports: vec![22],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Status {
+    Passed,
+    Failed,
+    Skipped,
+}
+
+/// One line of the checklist.
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub name: String,
+    pub status: Status,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: &str, result: Result<String>) -> Self {
+        let (status, detail) = match result {
+            Ok(detail) => (Status::Passed, detail),
+            Err(e) => (Status::Failed, format!("{:#}", e)),
+        };
+        Self {
+            name: name.to_string(),
+            status,
+            detail,
+        }
+    }
+
+    fn skipped(name: &str, reason: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            status: Status::Skipped,
+            detail: reason.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = match self.status {
+            Status::Passed => "ok".green(),
+            Status::Failed => "FAILED".red(),
+            Status::Skipped => "skipped".yellow(),
+        };
+        write!(f, "[{}] {}", status, self.name)?;
+        if !self.detail.is_empty() {
+            write!(f, ": {}", self.detail)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn passed(checks: &[Check]) -> bool {
+    checks.iter().all(|check| check.status != Status::Failed)
+}
+
+/// Whether `permission` lets TCP traffic to `port` in, from anywhere it allows.
+fn allows(permission: &IpPermission, port: u16) -> bool {
+    let port = i32::from(port);
+    match permission.ip_protocol.as_deref() {
+        Some("-1") => true,
+        Some("tcp") | Some("6") => {
+            permission.from_port.unwrap_or(0) <= port && port <= permission.to_port.unwrap_or(0)
+        }
+        _ => false,
+    }
+}
+
+/// The `ports` none of `permissions` opens.
+pub fn closed_ports(permissions: &[IpPermission], ports: &[u16]) -> Vec<u16> {
+    ports
+        .iter()
+        .filter(|port| {
+            !permissions
+                .iter()
+                .any(|permission| allows(permission, **port))
+        })
+        .copied()
+        .collect()
+}
+
+fn check_cache(cache: &str) -> Result<String> {
+    // local clusters often use a file or ssh store instead
+    if !cache.starts_with("s3://") {
+        let cmd = execute::command_args!("nix", "store", "ping", "--store", cache);
+        sh(cmd).with_context(|| format!("{} isn't a store bitte can reach", cache))?;
+        return Ok(cache.to_string());
+    }
+    // the bucket of a fresh cluster is still empty until the first node is copied to it
+    let (bucket, region) = archive::s3_bucket(cache)?;
+    let mut cmd = execute::command_args!("aws", "s3api", "head-bucket", "--bucket", &bucket);
+    if let Some(region) = region {
+        cmd.arg("--region").arg(region);
+    }
+    sh(cmd).with_context(|| format!("s3://{} isn't a bucket bitte can reach", bucket))?;
+    Ok(format!("s3://{}", bucket))
+}
+
+/// The name of the nix secret key `key`, once it signed something its public key verifies.
+fn signing_key(key: &str) -> Result<String> {
+    // a secret key looks like `<name>:<base64 of the ed25519 seed and public key>`
+    let (name, secret) = match key.trim().split_once(':') {
+        Some((name, secret)) if !name.is_empty() => (name, secret),
+        _ => bail!("not a nix signing key"),
+    };
+    let secret = base64::decode(secret).context("the secret isn't base64")?;
+    if secret.len() != 64 {
+        bail!("the secret has {} bytes rather than 64", secret.len());
+    }
+
+    let (seed, public) = secret.split_at(32);
+    let pair = Ed25519KeyPair::from_seed_and_public_key(seed, public)
+        .map_err(|_| anyhow!("the public key doesn't belong to the secret one"))?;
+    let message = b"bitte preflight";
+    UnparsedPublicKey::new(&ED25519, public)
+        .verify(message, pair.sign(message).as_ref())
+        .map_err(|_| anyhow!("the key doesn't sign"))?;
+    Ok(name.to_string())
+}
+
+fn check_signing_key() -> Result<String> {
+    let key = fs::read_to_string(SIGNING_KEY)
+        .with_context(|| format!("couldn't read {}", SIGNING_KEY))?;
+    signing_key(&key).with_context(|| format!("{} isn't a usable nix signing key", SIGNING_KEY))
+}
+
+async fn check_secrets(paths: &[String]) -> Result<String> {
+    let mut missing = Vec::new();
+    for path in paths.iter() {
+        if secret::find(secret::DEFAULT_MOUNT, path).await?.is_none() {
+            missing.push(path.as_str());
+        }
+    }
+    if !missing.is_empty() {
+        bail!("missing {}", missing.join(", "));
+    }
+    Ok(format!("{} present", paths.len()))
+}
+
+async fn check_ports(pool: &ClientPool, node: &BitteNode, ports: &[u16]) -> Result<String> {
+    let client = pool.ec2(&node.region().unwrap_or_default()).await;
+
+    budget::acquire(Provider::Aws).await?;
+    let instances = client
+        .describe_instances()
+        .instance_ids(&node.id)
+        .send()
+        .await
+        .with_context(|| format!("couldn't describe {}", node.id))?;
+    let groups: Vec<String> = instances
+        .reservations
+        .unwrap_or_default()
+        .into_iter()
+        .flat_map(|reservation| reservation.instances.unwrap_or_default())
+        .flat_map(|instance| instance.security_groups.unwrap_or_default())
+        .filter_map(|group| group.group_id)
+        .collect();
+    if groups.is_empty() {
+        bail!("{} has no security groups", node.id);
+    }
+
+    budget::acquire(Provider::Aws).await?;
+    let permissions: Vec<IpPermission> = client
+        .describe_security_groups()
+        .set_group_ids(Some(groups.clone()))
+        .send()
+        .await
+        .with_context(|| format!("couldn't describe the security groups of {}", node.id))?
+        .security_groups
+        .unwrap_or_default()
+        .into_iter()
+        .flat_map(|group| group.ip_permissions.unwrap_or_default())
+        .collect();
+
+    let closed = closed_ports(&permissions, ports);
+    if !closed.is_empty() {
+        let closed: Vec<String> = closed.iter().map(u16::to_string).collect();
+        bail!("{} don't open {}", groups.join(", "), closed.join(", "));
+    }
+    let open: Vec<String> = ports.iter().map(u16::to_string).collect();
+    Ok(format!("{} open", open.join(", ")))
+}
+
+/// What all nodes share: the binary cache, the key to sign for it, and the secrets in Vault.
+pub async fn cluster_checks(cache: &str, config: &PreflightConfig) -> Vec<Check> {
+    let secrets = if config.secrets.is_empty() {
+        Check::skipped("secrets in vault", "none are required in bitte.json")
+    } else {
+        Check::new("secrets in vault", check_secrets(&config.secrets).await)
+    };
+    vec![
+        Check::new("binary cache reachable", check_cache(cache)),
+        Check::new("signing key", check_signing_key()),
+        secrets,
+    ]
+}
+
+/// What is particular to one node: its system, and the ports its security groups open. The
+/// ports can only be checked for AWS nodes the cluster knows.
+pub async fn node_checks(
+    provision: &Provision,
+    node: Option<(&ClientPool, &BitteNode)>,
+    config: &PreflightConfig,
+) -> Vec<Check> {
+    let ports = match node {
+        Some((pool, node)) => Check::new("ports", check_ports(pool, node, &config.ports).await),
+        None => Check::skipped("ports", "the node has no security groups bitte knows of"),
+    };
+    vec![
+        Check::new(&format!("{} evaluates", provision.attr), provision.system()),
+        ports,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{closed_ports, signing_key};
+    use aws_sdk_ec2::model::IpPermission;
+    use pretty_assertions::assert_eq;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    fn permission(protocol: &str, from: i32, to: i32) -> IpPermission {
+        IpPermission::builder()
+            .ip_protocol(protocol)
+            .from_port(from)
+            .to_port(to)
+            .build()
+    }
+
+    #[test]
+    fn test_closed_ports() {
+        let permissions = vec![permission("tcp", 22, 22), permission("udp", 0, 65535)];
+        assert_eq!(closed_ports(&permissions, &[22, 443]), vec![443]);
+        assert_eq!(
+            closed_ports(&[permission("tcp", 4000, 4999)], &[22, 4646]),
+            vec![22]
+        );
+        assert_eq!(
+            closed_ports(&[permission("-1", 0, 0)], &[22, 443]),
+            Vec::<u16>::new()
+        );
+    }
+
+    #[test]
+    fn test_signing_key() {
+        let seed = [7u8; 32];
+        let pair = Ed25519KeyPair::from_seed_unchecked(&seed).unwrap();
+        let mut secret = seed.to_vec();
+        secret.extend_from_slice(pair.public_key().as_ref());
+        let key = format!("cache.example.com-1:{}\n", base64::encode(&secret));
+        assert_eq!(signing_key(&key).unwrap(), "cache.example.com-1");
+
+        // the public half of another key
+        secret[63] ^= 1;
+        let key = format!("cache.example.com-1:{}", base64::encode(&secret));
+        assert!(signing_key(&key).is_err());
+
+        assert!(signing_key("cache.example.com-1:c2hvcnQ=").is_err());
+        assert!(signing_key("no-secret").is_err());
+    }
+}
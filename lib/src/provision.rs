@@ -0,0 +1,483 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    process::{Command, Stdio},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::{info, warn};
+use serde::Serialize;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, BufReader},
+    net::TcpStream,
+    runtime::Handle,
+    sync::Semaphore,
+    task, time,
+};
+
+use crate::{
+    cancel::{self, CancellationToken},
+    error::Error,
+    executor::{RemoteExecutor, SshExecutor},
+    images, preflight, rebuild, sh, ssh,
+    transcript::Transcript,
+    types::BitteNode,
+};
+
+/// How often a step is attempted before provisioning gives up.
+const MAX_ATTEMPTS: u32 = 5;
+
+/// The first delay between attempts, doubled for every following one.
+const BASE_DELAY: Duration = Duration::from_secs(5);
+
+/// Never wait longer than this between two attempts.
+const MAX_DELAY: Duration = Duration::from_secs(120);
+
+/// The steps of provisioning a node, in order.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Step {
+    /// Wait for ssh and for the node to finish booting.
+    Ready,
+    /// Sign the system and upload it to the binary cache of the cluster.
+    CopyToCache,
+    /// Copy the system from the cache to the node.
+    CopyToMachine,
+    /// Switch the node to the system.
+    Activate,
+}
+
+impl Step {
+    pub const ALL: [Step; 4] = [
+        Step::Ready,
+        Step::CopyToCache,
+        Step::CopyToMachine,
+        Step::Activate,
+    ];
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let step = match self {
+            Step::Ready => "wait for the node",
+            Step::CopyToCache => "copy the system to the cache",
+            Step::CopyToMachine => "copy the system to the node",
+            Step::Activate => "activate the system",
+        };
+        write!(f, "{}", step)
+    }
+}
+
+/// The delay before the attempt following `attempt`.
+pub fn backoff(attempt: u32) -> Duration {
+    (BASE_DELAY * 2u32.saturating_pow(attempt.saturating_sub(1).min(16))).min(MAX_DELAY)
+}
+
+/// How long a node may take to accept a connection before it counts as still booting.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Provisioning of the node at `ip` with the system `attr` of `flake`.
+pub struct Provision {
+    pub ip: IpAddr,
+    pub name: String,
+    pub cluster: String,
+    pub flake: String,
+    pub attr: String,
+    /// The binary cache of the cluster, like `s3://bucket?region=eu-central-1`.
+    pub cache: String,
+    /// Prefix every line of output with the name of the node, for provisioning several at once.
+    pub labeled: bool,
+    pub transcript: Transcript,
+    /// Upload the transcript to the bucket of the binary cache once done.
+    pub upload: bool,
+}
+
+impl Provision {
+    fn toplevel(&self) -> String {
+        format!(
+            "{}#nixosConfigurations.{}.config.system.build.toplevel",
+            self.flake, self.attr
+        )
+    }
+
+    /// The store path of the system, without building it.
+    pub(crate) fn system(&self) -> Result<String> {
+        let mut cmd = Command::new("nix");
+        cmd.args(&["eval", "--raw"])
+            .arg(format!("{}.outPath", self.toplevel()));
+        Ok(self
+            .check(format!("{:?}", cmd), || sh(cmd))
+            .with_context(|| format!("couldn't evaluate {}", self.toplevel()))?
+            .trim()
+            .to_string())
+    }
+
+    /// Run a command whose output isn't worth streaming, recording how it went.
+    fn check<F: FnOnce() -> Result<String>>(&self, command: String, run: F) -> Result<String> {
+        self.transcript.command(&command);
+        let result = run();
+        match &result {
+            Ok(output) => {
+                for line in output.lines() {
+                    self.transcript.output(line);
+                }
+                self.transcript.record("exit 0");
+            }
+            Err(e) => self.transcript.record(&format!("failed: {:#}", e)),
+        }
+        result
+    }
+
+    /// Run `command` on the node.
+    fn remote(&self, command: &str) -> Result<String> {
+        let node = SshExecutor { ip: self.ip };
+        self.check(format!("ssh root@{} {}", self.ip, command), || {
+            node.run_blocking(command)
+        })
+    }
+
+    /// Whether `step` was completed already, by an earlier run that failed later on.
+    fn is_done(&self, step: Step, system: &str) -> Result<bool> {
+        Ok(match step {
+            // checking whether the node is ready takes as long as waiting for it
+            Step::Ready => false,
+            Step::CopyToCache => {
+                let mut cmd = Command::new("nix");
+                cmd.args(&["path-info", "--store", self.cache.as_str(), system]);
+                self.check(format!("{:?}", cmd), || sh(cmd)).is_ok()
+            }
+            Step::CopyToMachine => self
+                .remote(&format!("nix-store --check-validity {}", system))
+                .is_ok(),
+            Step::Activate => {
+                let current = self.remote("readlink -f /run/current-system")?;
+                current.trim() == system
+            }
+        })
+    }
+
+    async fn run(&self, step: Step) -> Result<()> {
+        match step {
+            Step::Ready => {
+                self.transcript
+                    .record(&format!("waiting for {}:22 to accept connections", self.ip));
+                ssh::wait_for_ssh(&self.ip).await?;
+                self.exec(ssh::ready_command(&self.cluster, &self.ip))
+                    .await?;
+                ssh::ssh_keygen(&self.ip)
+            }
+            Step::CopyToCache => {
+                let signed = format!("{}&secret-key={}", self.cache, preflight::SIGNING_KEY);
+                self.exec(rebuild::nix_copy_to_cache_command(
+                    &self.toplevel(),
+                    &signed,
+                ))
+                .await
+            }
+            Step::CopyToMachine => {
+                for cmd in rebuild::nix_copy_to_machine_commands(&self.toplevel(), &self.ip) {
+                    self.exec(cmd).await?;
+                }
+                Ok(())
+            }
+            Step::Activate => {
+                let flake = format!("{}#{}", self.flake, self.attr);
+                self.exec(rebuild::nixos_rebuild_command(&flake, &self.ip))
+                    .await
+            }
+        }
+    }
+
+    /// Run `cmd` without blocking the other nodes, recording its output in the transcript and
+    /// labeling it if need be.
+    async fn exec(&self, cmd: Command) -> Result<()> {
+        let line = format!("{:?}", cmd);
+        if self.labeled {
+            println!("{}: run: {}", self.name, line);
+        } else {
+            println!("run: {}", line);
+        }
+        self.transcript.command(&line);
+
+        let mut child = tokio::process::Command::from(cmd)
+            .kill_on_drop(true)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let stdout = child.stdout.take().context("the command has no stdout")?;
+        let stderr = child.stderr.take().context("the command has no stderr")?;
+        tokio::try_join!(self.relay(stdout), self.relay(stderr))?;
+        let status = child.wait().await?;
+        self.transcript.exit(status);
+
+        if !status.success() {
+            bail!("{} failed with {}", line, status);
+        }
+        Ok(())
+    }
+
+    async fn relay<R: AsyncRead + Unpin>(&self, output: R) -> Result<()> {
+        let mut lines = BufReader::new(output).lines();
+        while let Some(line) = lines.next_line().await? {
+            if self.labeled {
+                println!("{}: {}", self.name, line);
+            } else {
+                println!("{}", line);
+            }
+            self.transcript.output(&line);
+        }
+        Ok(())
+    }
+
+    /// Run `step` until it is done, waiting longer after every failed attempt. A step only
+    /// counts as done once `is_done` says so, not every command reports its failure.
+    async fn attempt(&self, step: Step, system: &str, cancel: &CancellationToken) -> Result<()> {
+        let mut attempt = 1;
+        loop {
+            let result = match self.run(step).await {
+                Ok(()) if step == Step::Ready => Ok(()),
+                Ok(()) => match self.is_done(step, system) {
+                    Ok(true) => Ok(()),
+                    Ok(false) => Err(anyhow!("{} didn't take effect", step)),
+                    Err(e) => Err(e),
+                },
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt >= MAX_ATTEMPTS => {
+                    return Err(e.context(format!("couldn't {} in {} attempts", step, attempt)))
+                }
+                Err(e) => {
+                    let delay = backoff(attempt);
+                    self.transcript.record(&format!(
+                        "attempt {} failed, trying again in {}s: {:#}",
+                        attempt,
+                        delay.as_secs(),
+                        e
+                    ));
+                    warn!(
+                        "couldn't {} on {}, trying again in {}s: {:#}",
+                        step,
+                        self.ip,
+                        delay.as_secs(),
+                        e
+                    );
+                    cancel::sleep(cancel, delay).await?;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    /// Provision the node, skipping the steps an earlier run completed. Cancelling stops
+    /// between attempts.
+    pub async fn run_all(&self, cancel: &CancellationToken) -> Result<()> {
+        rebuild::set_ssh_opts(false, &self.cluster)?;
+        let system = self.system()?;
+        if system.is_empty() {
+            bail!("{} has no system", self.toplevel());
+        }
+
+        for step in Step::ALL.iter() {
+            cancel::check(cancel)?;
+            // a node that can't be asked yet isn't done with anything
+            if self.is_done(*step, &system).unwrap_or(false) {
+                info!("{}: skipping, already done: {}", self.ip, step);
+                self.transcript
+                    .record(&format!("skipping, already done: {}", step));
+                continue;
+            }
+            info!("{}: {}", self.ip, step);
+            self.transcript.record(&format!("step: {}", step));
+            self.attempt(*step, &system, cancel).await?;
+        }
+        Ok(())
+    }
+
+    /// Close the transcript with `result` and upload it if asked to. Returns where it ended up,
+    /// the local file if it couldn't be uploaded.
+    pub fn finish(&self, result: &Result<()>) -> String {
+        match result {
+            Ok(()) => self.transcript.record("provisioned"),
+            Err(e) => self.transcript.record(&format!("failed: {:#}", e)),
+        }
+        let local = self.transcript.path().display().to_string();
+        if !self.upload {
+            return local;
+        }
+        match self.transcript.upload(&self.cache, &self.cluster) {
+            Ok(url) => url,
+            Err(e) => {
+                warn!("{:?}", e);
+                local
+            }
+        }
+    }
+}
+
+/// How provisioning one of several nodes went.
+#[derive(Debug, Serialize)]
+pub struct Outcome {
+    pub name: String,
+    pub ip: IpAddr,
+    /// Seconds it took.
+    pub duration: u64,
+    pub error: Option<String>,
+    /// Where the transcript is, on S3 if it was uploaded.
+    pub transcript: String,
+}
+
+/// Provision all of `provisions`, at most `jobs` at a time, and return how each went in the
+/// order they were given. Cancelling aborts the ones still running, a later run picks them up
+/// where they stopped.
+///
+/// Each provision runs on a thread of its own, some of its steps run commands that block.
+pub async fn run_many(
+    provisions: Vec<Provision>,
+    jobs: usize,
+    cancel: &CancellationToken,
+) -> Result<Vec<Outcome>> {
+    let pool = Arc::new(Semaphore::new(jobs.max(1)));
+
+    let mut handles: Vec<_> = provisions
+        .into_iter()
+        .map(|provision| {
+            let pool = Arc::clone(&pool);
+            let cancel = cancel.clone();
+            let runtime = Handle::current();
+            task::spawn_blocking(move || {
+                let _permit = runtime.block_on(pool.acquire());
+                let started = Instant::now();
+                println!("{}: provisioning {}", provision.name, provision.ip);
+                // dropping the provision on cancel kills the command it runs
+                let result =
+                    runtime.block_on(cancel::or_cancel(&cancel, provision.run_all(&cancel)));
+                let transcript = provision.finish(&result);
+                let error = result.err().map(|e| format!("{:#}", e));
+                match &error {
+                    Some(e) => println!("{}: failed: {}", provision.name, e),
+                    None => println!("{}: done", provision.name),
+                }
+                Outcome {
+                    name: provision.name,
+                    ip: provision.ip,
+                    duration: started.elapsed().as_secs(),
+                    error,
+                    transcript,
+                }
+            })
+        })
+        .collect();
+
+    let mut outcomes = Vec::with_capacity(handles.len());
+    for index in 0..handles.len() {
+        let cancelled = tokio::select! {
+            _ = cancel.cancelled() => true,
+            outcome = &mut handles[index] => {
+                outcomes.push(outcome?);
+                false
+            }
+        };
+
+        if cancelled {
+            for handle in handles.iter() {
+                handle.abort();
+            }
+            return Err(Error::Cancelled.into());
+        }
+    }
+    Ok(outcomes)
+}
+
+/// The systems `unprovisioned` asks a node for: the current one, and the first generation of
+/// the system profile, the one its image or AMI came with. The first generation is gone once it
+/// was garbage collected, which only happens on nodes that were provisioned.
+const SYSTEMS_COMMAND: &str =
+    "readlink -f /run/current-system; readlink -e /nix/var/nix/profiles/system-1-link || true";
+
+/// Whether a node still runs the system of the image it booted from, `image` if it still has
+/// it, rather than the one the flake builds for it. A provisioned node that rebooted since runs
+/// the system it was provisioned with, not the one of its image.
+pub fn is_unprovisioned(current: &str, image: Option<&str>, expected: &str) -> bool {
+    image == Some(current) && current != expected
+}
+
+/// Whether `node` was never provisioned. A node that can't be reached yet is still booting.
+async fn unprovisioned(node: &BitteNode, expected: &str) -> Result<bool> {
+    let address = (node.pub_ip, 22);
+    if !matches!(
+        time::timeout(CONNECT_TIMEOUT, TcpStream::connect(address)).await,
+        Ok(Ok(_))
+    ) {
+        return Ok(true);
+    }
+
+    let executor = SshExecutor { ip: node.pub_ip };
+    let output = executor.run_command(SYSTEMS_COMMAND).await?;
+    let mut systems = output
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty());
+    match systems.next() {
+        Some(current) => Ok(is_unprovisioned(current, systems.next(), expected)),
+        None => bail!("{} didn't tell its system", node.name),
+    }
+}
+
+/// The nodes of `cluster` that still wait for `bitte provision`. `nodes` need their flake
+/// attr, see `inventory::resolve_flake_attrs`.
+pub async fn find_unprovisioned(cluster: &str, nodes: Vec<BitteNode>) -> Result<Vec<BitteNode>> {
+    rebuild::set_ssh_opts(false, cluster)?;
+    let mut systems: HashMap<String, String> = HashMap::new();
+    let mut found = Vec::new();
+
+    for node in nodes.into_iter() {
+        let expected = match systems.get(&node.nixos) {
+            Some(system) => system.clone(),
+            None => {
+                let system = images::expected_system(&node.nixos)
+                    .with_context(|| format!("couldn't evaluate the system of {}", node.nixos))?;
+                systems.insert(node.nixos.clone(), system.clone());
+                system
+            }
+        };
+        match unprovisioned(&node, &expected).await {
+            Ok(true) => found.push(node),
+            Ok(false) => {}
+            Err(e) => warn!(
+                "couldn't tell whether {} is provisioned: {:?}",
+                node.name, e
+            ),
+        }
+    }
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{backoff, is_unprovisioned};
+    use pretty_assertions::assert_eq;
+    use std::time::Duration;
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(1), Duration::from_secs(5));
+        assert_eq!(backoff(3), Duration::from_secs(20));
+        assert_eq!(backoff(10), Duration::from_secs(120));
+    }
+
+    #[test]
+    fn test_is_unprovisioned() {
+        let (image, flake) = ("/nix/store/a-nixos-image", "/nix/store/b-nixos-system");
+        assert!(is_unprovisioned(image, Some(image), flake));
+        assert!(!is_unprovisioned(flake, Some(image), flake));
+        // provisioned, rebooted, and merely behind the flake, a rebuild's business
+        let old = "/nix/store/c-nixos-system";
+        assert!(!is_unprovisioned(old, Some(image), flake));
+        assert!(!is_unprovisioned(old, None, flake));
+    }
+}
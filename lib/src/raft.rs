@@ -0,0 +1,248 @@
+use std::collections::BTreeSet;
+
+use anyhow::{Context, Result};
+use reqwest::Method;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{consul, endpoint::Service, nomad::NomadApi};
+
+/// The peers of the Raft cluster, from `/v1/operator/raft/configuration`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct RaftConfiguration {
+    pub servers: Vec<RaftPeer>,
+    #[serde(default)]
+    pub index: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct RaftPeer {
+    #[serde(rename = "ID")]
+    pub id: String,
+    pub node: String,
+    pub address: String,
+    pub leader: bool,
+    pub voter: bool,
+}
+
+/// What autopilot thinks of the servers, from `/v1/operator/autopilot/health`. Consul and Nomad
+/// answer in the same shape.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct AutopilotHealth {
+    pub healthy: bool,
+    /// How many servers can fail without losing quorum.
+    pub failure_tolerance: u32,
+    pub servers: Vec<ServerHealth>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ServerHealth {
+    #[serde(rename = "ID")]
+    pub id: String,
+    pub name: String,
+    pub address: String,
+    /// `alive`, `failed`, or `left`.
+    pub serf_status: String,
+    #[serde(default)]
+    pub version: String,
+    pub leader: bool,
+    /// Since the leader last heard from the server, like `12.5ms`.
+    #[serde(default)]
+    pub last_contact: String,
+    pub last_term: u64,
+    pub last_index: u64,
+    pub healthy: bool,
+    pub voter: bool,
+}
+
+/// The Raft state of the servers of one service.
+#[derive(Debug, Serialize)]
+pub struct RaftStatus {
+    pub service: String,
+    pub configuration: RaftConfiguration,
+    pub health: AutopilotHealth,
+    pub problems: Vec<String>,
+}
+
+impl RaftStatus {
+    pub fn new(
+        service: Service,
+        configuration: RaftConfiguration,
+        health: AutopilotHealth,
+    ) -> Self {
+        let problems = problems(&configuration, &health);
+        Self {
+            service: service.to_string(),
+            configuration,
+            health,
+            problems,
+        }
+    }
+}
+
+/// What is wrong with a Raft cluster: no leader or more than one, servers that failed or fell
+/// behind, and peers only one of the configuration and autopilot knows about.
+pub fn problems(configuration: &RaftConfiguration, health: &AutopilotHealth) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    let leaders: Vec<&str> = health
+        .servers
+        .iter()
+        .filter(|server| server.leader)
+        .map(|server| server.name.as_str())
+        .collect();
+    match leaders.len() {
+        0 => problems.push("there is no leader".to_string()),
+        1 => {}
+        _ => problems.push(format!(
+            "{} servers claim to lead, the cluster is split: {}",
+            leaders.len(),
+            leaders.join(", ")
+        )),
+    }
+
+    let term = health
+        .servers
+        .iter()
+        .map(|server| server.last_term)
+        .max()
+        .unwrap_or_default();
+    for server in health.servers.iter() {
+        if server.serf_status != "alive" {
+            problems.push(format!("{} is {}", server.name, server.serf_status));
+        } else if !server.healthy {
+            problems.push(format!(
+                "{} is unhealthy, last contact {} ago at index {}",
+                server.name, server.last_contact, server.last_index
+            ));
+        }
+        if server.last_term < term {
+            problems.push(format!(
+                "{} is at term {}, the others at {}",
+                server.name, server.last_term, term
+            ));
+        }
+    }
+
+    let peers: BTreeSet<&str> = configuration
+        .servers
+        .iter()
+        .map(|peer| peer.id.as_str())
+        .collect();
+    let known: BTreeSet<&str> = health
+        .servers
+        .iter()
+        .map(|server| server.id.as_str())
+        .collect();
+    for peer in configuration.servers.iter() {
+        if !known.contains(peer.id.as_str()) {
+            problems.push(format!(
+                "{} ({}) is a raft peer autopilot doesn't know",
+                peer.node, peer.address
+            ));
+        }
+    }
+    for server in health.servers.iter() {
+        if !peers.contains(server.id.as_str()) {
+            problems.push(format!(
+                "{} ({}) is missing from the raft peers",
+                server.name, server.address
+            ));
+        }
+    }
+
+    if health.failure_tolerance == 0 && problems.is_empty() {
+        problems.push("losing any server loses the quorum".to_string());
+    }
+
+    problems
+}
+
+async fn consul_get<T: DeserializeOwned>(domain: &str, path: &str) -> Result<T> {
+    consul::request(Method::GET, domain, path)
+        .await?
+        .send()
+        .await?
+        .error_for_status()
+        .with_context(|| format!("couldn't read {} from consul", path))?
+        .json()
+        .await
+        .with_context(|| format!("failed to decode {} from consul", path))
+}
+
+pub async fn consul(domain: &str) -> Result<RaftStatus> {
+    let configuration = consul_get(domain, "operator/raft/configuration").await?;
+    let health = consul_get(domain, "operator/autopilot/health").await?;
+    Ok(RaftStatus::new(Service::Consul, configuration, health))
+}
+
+pub async fn nomad(nomad: &NomadApi) -> Result<RaftStatus> {
+    let configuration = nomad.get("/v1/operator/raft/configuration", &[]).await?;
+    let health = nomad.get("/v1/operator/autopilot/health", &[]).await?;
+    Ok(RaftStatus::new(Service::Nomad, configuration, health))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{problems, AutopilotHealth, RaftConfiguration, RaftPeer, ServerHealth};
+    use pretty_assertions::assert_eq;
+
+    fn server(id: &str, leader: bool, term: u64) -> ServerHealth {
+        ServerHealth {
+            id: id.to_string(),
+            name: format!("core-{}", id),
+            address: format!("10.0.0.{}:8300", id),
+            serf_status: "alive".to_string(),
+            leader,
+            last_contact: "10ms".to_string(),
+            last_term: term,
+            healthy: true,
+            voter: true,
+            ..ServerHealth::default()
+        }
+    }
+
+    fn peer(id: &str) -> RaftPeer {
+        RaftPeer {
+            id: id.to_string(),
+            node: format!("core-{}", id),
+            address: format!("10.0.0.{}:8300", id),
+            leader: false,
+            voter: true,
+        }
+    }
+
+    #[test]
+    fn test_problems() {
+        let configuration = RaftConfiguration {
+            servers: vec![peer("1"), peer("2"), peer("3")],
+            index: 100,
+        };
+        let mut health = AutopilotHealth {
+            healthy: true,
+            failure_tolerance: 1,
+            servers: vec![
+                server("1", true, 4),
+                server("2", false, 4),
+                server("3", false, 4),
+            ],
+        };
+        assert!(problems(&configuration, &health).is_empty());
+
+        health.servers[1].leader = true;
+        health.servers[1].last_term = 3;
+        health.servers.pop();
+        health.failure_tolerance = 0;
+        assert_eq!(
+            problems(&configuration, &health),
+            vec![
+                "2 servers claim to lead, the cluster is split: core-1, core-2",
+                "core-2 is at term 3, the others at 4",
+                "core-3 (10.0.0.3:8300) is a raft peer autopilot doesn't know",
+            ]
+        );
+    }
+}
@@ -1,25 +1,87 @@
-use anyhow::Result;
-use log::info;
-use std::{env, net::IpAddr, path::Path, process::Command, time::Duration};
+use anyhow::{bail, Result};
+use log::{error, info, warn};
+use std::{
+    env,
+    net::IpAddr,
+    path::Path,
+    process::Command,
+    time::{Duration, Instant},
+};
 
 use crate::{
+    cancel::{self, CancellationToken},
     check_cmd,
+    config::BitteConfig,
+    db,
     error::Error,
-    handle_command_error,
-    ssh::wait_for_ssh,
+    executor::{self, RemoteExecutor},
+    handle_command_error, inventory, nix,
+    runs::Run,
     types::{BitteCluster, BitteFind, BitteNode},
 };
 
+/// Rebuild the nodes one after the other, waiting `delay` between them.
+///
+/// Cancelling stops before the next node. The node being rebuilt is finished first, so no node
+/// is left with a half activated system.
+#[allow(clippy::too_many_arguments)]
 pub async fn copy(
     only: Vec<&str>,
     delay: Duration,
     copy: bool,
     clients: bool,
+    ssm: bool,
     cluster: BitteCluster,
+    run: &Run,
+    cancel: &CancellationToken,
 ) -> Result<()> {
+    let cluster_name = cluster.name.clone();
+    let (instances, s3_cache, cache) = targets(only, copy, clients, cluster)?;
+
+    let mut iter = instances.iter().peekable();
+
+    while let Some(instance) = iter.next() {
+        cancel::check(cancel)?;
+        info!("rebuild: {}, {}", instance.name, instance.pub_ip);
+        let started = Instant::now();
+        let executor = executor::for_node(instance, ssm, &s3_cache).await?;
+        let result = copy_to(instance, executor.as_ref(), &cache, run).await;
+
+        let recorded = db::record_node_event(
+            &cluster_name,
+            &instance.name,
+            "rebuild",
+            started.elapsed(),
+            result.is_ok(),
+        );
+        if let Err(e) = recorded {
+            warn!("couldn't record the rebuild of {}: {:?}", instance.name, e);
+        }
+
+        result?;
+        if iter.peek().is_some() {
+            cancel::sleep(cancel, delay).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The nodes to rebuild, the S3 cache of the cluster, and the cache to copy to first if any.
+fn targets(
+    only: Vec<&str>,
+    copy: bool,
+    clients: bool,
+    cluster: BitteCluster,
+) -> Result<(Vec<BitteNode>, Option<String>, Option<String>)> {
     info!("only: {:?}", only);
 
-    let instances = if only.is_empty() {
+    let s3_cache = cluster
+        .terra
+        .as_ref()
+        .and_then(|terra| terra.s3_cache.clone());
+
+    let mut instances: Vec<BitteNode> = if only.is_empty() {
         if clients {
             cluster
                 .nodes
@@ -33,30 +95,158 @@ pub async fn copy(
         cluster.nodes.find_needles(only)
     };
 
-    let mut iter = instances.iter().peekable();
+    inventory::resolve_flake_attrs(&mut instances, &BitteConfig::load()?.flake_attrs)?;
 
     let cache = if copy {
-        match cluster.terra {
-            Some(terra) => Ok(Some(terra.s3_cache)),
+        match &s3_cache {
+            Some(s3_cache) => Ok(Some(s3_cache.clone())),
             None => Err(Error::MissingCache),
         }
     } else {
         Ok(None)
     }?;
 
-    while let Some(instance) = iter.next() {
-        info!("rebuild: {}, {}", instance.name, instance.pub_ip);
-        wait_for_ssh(&instance.pub_ip).await?;
-        copy_to(instance, 10, &cache)?;
-        if iter.peek().is_some() {
-            tokio::time::sleep(delay).await;
+    Ok((instances, s3_cache, cache))
+}
+
+/// Rebuild nodes whose changes have to land together, e.g. a new consul protocol version.
+///
+/// All nodes are built and their system profiles switched first, without activating anything.
+/// Then they are activated back to back, core nodes before the others and nomad clients last.
+/// If an activation fails or the activations take longer than `window`, every node of the
+/// batch is rolled back to its previous system.
+///
+/// Cancelling is handled like a failure: the nodes staged so far, and the ones already
+/// activated, are rolled back before `Error::Cancelled` is returned.
+#[allow(clippy::too_many_arguments)]
+pub async fn two_phase(
+    only: Vec<&str>,
+    window: Duration,
+    copy: bool,
+    clients: bool,
+    ssm: bool,
+    cluster: BitteCluster,
+    run: &Run,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    let (mut instances, s3_cache, cache) = targets(only, copy, clients, cluster)?;
+    instances.sort_by_key(|node| (NodeKind::of(node), node.name.clone()));
+
+    let mut staged: Vec<(&BitteNode, Box<dyn RemoteExecutor>)> = Vec::new();
+
+    for instance in instances.iter() {
+        if cancel.is_cancelled() {
+            warn!("cancelled while staging, rolling back the batch");
+            rollback(&staged, 0).await;
+            return Err(Error::Cancelled.into());
+        }
+
+        info!("stage: {}, {}", instance.name, instance.pub_ip);
+        // a node that can't be reached fails the batch like one that can't be staged
+        let staging = async {
+            let executor = executor::for_node(instance, ssm, &s3_cache).await?;
+            let target = prepare(instance, executor.as_ref(), &cache, run).await?;
+            executor.stage(&target).await?;
+            Ok::<_, anyhow::Error>(executor)
+        };
+
+        match staging.await {
+            Ok(executor) => staged.push((instance, executor)),
+            Err(e) => {
+                error!("staging {} failed, rolling back the batch", instance.name);
+                rollback(&staged, 0).await;
+                return Err(e);
+            }
         }
     }
 
+    let started = Instant::now();
+    for (activated, (instance, executor)) in staged.iter().enumerate() {
+        if cancel.is_cancelled() {
+            warn!("cancelled while activating, rolling back the batch");
+            rollback(&staged, activated).await;
+            return Err(Error::Cancelled.into());
+        }
+
+        if started.elapsed() > window {
+            error!("activation window of {}s exceeded", window.as_secs());
+            rollback(&staged, activated).await;
+            bail!(
+                "activation didn't finish within {}s, the batch was rolled back",
+                window.as_secs()
+            );
+        }
+
+        info!("activate: {}, {}", instance.name, instance.pub_ip);
+        if let Err(e) = executor.switch_staged().await {
+            error!(
+                "activating {} failed, rolling back the batch",
+                instance.name
+            );
+            rollback(&staged, activated + 1).await;
+            return Err(e);
+        }
+    }
+
+    info!(
+        "activated {} nodes in {}s",
+        staged.len(),
+        started.elapsed().as_secs()
+    );
     Ok(())
 }
 
-fn copy_to(instance: &BitteNode, _attempts: u64, cache: &Option<String>) -> Result<()> {
+/// What a node runs, in the order the kinds are activated: core nodes hold the quorum of
+/// consul, nomad, and vault, so they go first and Nomad clients last. The others, like the
+/// routing and monitoring nodes, don't run Nomad.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum NodeKind {
+    Core,
+    Other,
+    Client,
+}
+
+impl NodeKind {
+    pub fn of(node: &BitteNode) -> Self {
+        if node.name.starts_with("core") {
+            NodeKind::Core
+        } else if node.nomad_client.is_none() {
+            NodeKind::Other
+        } else {
+            NodeKind::Client
+        }
+    }
+}
+
+/// Roll back every staged node, switching back the first `activated` ones as well.
+async fn rollback(staged: &[(&BitteNode, Box<dyn RemoteExecutor>)], activated: usize) {
+    for (index, (instance, executor)) in staged.iter().enumerate().rev() {
+        match executor.rollback(index < activated).await {
+            Ok(()) => info!("rolled back {}", instance.name),
+            Err(e) => error!("couldn't roll back {}: {:?}", instance.name, e),
+        }
+    }
+}
+
+pub(crate) async fn copy_to(
+    instance: &BitteNode,
+    executor: &dyn RemoteExecutor,
+    cache: &Option<String>,
+    run: &Run,
+) -> Result<()> {
+    let target = prepare(instance, executor, cache, run).await?;
+    let rebuild_flake: String = format!(".#{}", instance.nixos);
+    executor.activate(&target, &rebuild_flake).await
+}
+
+/// Build the system of `instance` and copy it to the node, returning the built target.
+async fn prepare(
+    instance: &BitteNode,
+    executor: &dyn RemoteExecutor,
+    cache: &Option<String>,
+    run: &Run,
+) -> Result<String> {
+    info!("transport: {}", executor.describe());
     env::set_var("IP", instance.pub_ip.to_string());
     let flake = ".";
 
@@ -73,73 +263,76 @@ fn copy_to(instance: &BitteNode, _attempts: u64, cache: &Option<String>) -> Resu
         "{}#nixosConfigurations.{}.config.system.build.toplevel",
         flake, instance.nixos
     );
-    let rebuild_flake: String = format!("{}#{}", flake, instance.nixos);
 
-    nix_build(&target)?;
+    nix::build(
+        &target,
+        &run.path(&format!("result-{}", instance.nixos)),
+        run,
+    )?;
 
     if let Some(c) = cache {
         let cache = format!("{}&secret-key=secrets/nix-secret-key-file", c);
         nix_copy_to_cache(&target, &cache)?;
     }
 
-    nix_copy_to_machine(&target, &instance.pub_ip)?;
-    nixos_rebuild(&rebuild_flake, &instance.pub_ip)
+    executor.copy_closure(&target).await?;
+    Ok(target)
 }
 
 pub fn nixos_rebuild(target: &str, ip: &IpAddr) -> Result<()> {
-    check_cmd(
-        Command::new("nixos-rebuild")
-            .arg("switch")
-            .arg("--build-host")
-            .arg("localhost")
-            .arg("--target-host")
-            .arg(format!("root@{}", ip))
-            .arg("--flake")
-            .arg(target),
-    )?;
-    Ok(())
+    check_cmd(&mut nixos_rebuild_command(target, ip))
 }
 
-fn nix_build(target: &str) -> Result<()> {
-    check_cmd(Command::new("nix").arg("-L").arg("build").arg(target))?;
-    Ok(())
+pub(crate) fn nixos_rebuild_command(target: &str, ip: &IpAddr) -> Command {
+    let mut cmd = Command::new("nixos-rebuild");
+    cmd.arg("switch")
+        .arg("--build-host")
+        .arg("localhost")
+        .arg("--target-host")
+        .arg(format!("root@{}", ip))
+        .arg("--flake")
+        .arg(target);
+    cmd
 }
 
 pub fn nix_copy_to_cache(target: &str, cache: &str) -> Result<()> {
-    check_cmd(
-        Command::new("nix")
-            .arg("-L")
-            .arg("copy")
-            .arg("--to")
-            .arg(cache)
-            .arg(target),
-    )?;
-    Ok(())
+    check_cmd(&mut nix_copy_to_cache_command(target, cache))
+}
+
+pub(crate) fn nix_copy_to_cache_command(target: &str, cache: &str) -> Command {
+    let mut cmd = Command::new("nix");
+    cmd.arg("-L").arg("copy").arg("--to").arg(cache).arg(target);
+    cmd
 }
 
 pub fn nix_copy_to_machine(target: &str, ssh: &IpAddr) -> Result<()> {
-    check_cmd(
-        Command::new("nix")
-            .arg("-L")
-            .arg("copy")
-            .arg("--substitute-on-destination")
-            .arg("--to")
-            .arg(format!("ssh://root@{}", ssh))
-            .arg(target),
-    )?;
-    // vulnix operates on store derivations
-    check_cmd(
-        Command::new("nix")
-            .arg("copy")
-            .arg("--derivation")
-            .arg("--substitute-on-destination")
-            .arg("--to")
-            .arg(format!("ssh://root@{}", ssh))
-            .arg(target),
-    )?;
+    for mut cmd in nix_copy_to_machine_commands(target, ssh) {
+        check_cmd(&mut cmd)?;
+    }
     Ok(())
 }
 
+pub(crate) fn nix_copy_to_machine_commands(target: &str, ssh: &IpAddr) -> Vec<Command> {
+    let mut system = Command::new("nix");
+    system
+        .arg("-L")
+        .arg("copy")
+        .arg("--substitute-on-destination")
+        .arg("--to")
+        .arg(format!("ssh://root@{}", ssh))
+        .arg(target);
+    // vulnix operates on store derivations
+    let mut derivation = Command::new("nix");
+    derivation
+        .arg("copy")
+        .arg("--derivation")
+        .arg("--substitute-on-destination")
+        .arg("--to")
+        .arg(format!("ssh://root@{}", ssh))
+        .arg(target);
+    vec![system, derivation]
+}
+
 pub fn set_ssh_opts(key_checking: bool, cluster: &str) -> Result<()> {
     if env::var("NIX_SSHOPTS").is_ok() {
         return Ok(());
@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+
+use crate::types::BitteNode;
+
+/// How the set of nodes changed between two snapshots of a cluster.
+#[derive(Debug, Default)]
+pub struct NodeSetDiff<'a> {
+    pub added: Vec<&'a BitteNode>,
+    pub removed: Vec<&'a BitteNode>,
+    /// Nodes whose name survived but are backed by a new instance, as `(before, after)`.
+    pub replaced: Vec<(&'a BitteNode, &'a BitteNode)>,
+}
+
+impl<'a> NodeSetDiff<'a> {
+    pub fn new(before: &'a [BitteNode], after: &'a [BitteNode]) -> Self {
+        let before_ids: HashMap<&str, &BitteNode> =
+            before.iter().map(|node| (node.id.as_str(), node)).collect();
+        let after_ids: HashMap<&str, &BitteNode> =
+            after.iter().map(|node| (node.id.as_str(), node)).collect();
+
+        // by instance, names repeat in auto-scaling groups and are empty for unnamed nodes
+        let mut gone: Vec<&BitteNode> = before
+            .iter()
+            .filter(|node| !after_ids.contains_key(node.id.as_str()))
+            .collect();
+
+        let mut diff = Self::default();
+
+        for node in after.iter() {
+            if before_ids.contains_key(node.id.as_str()) {
+                continue;
+            }
+
+            let old = gone
+                .iter()
+                .position(|old| !node.name.is_empty() && old.name == node.name);
+            match old {
+                Some(old) => diff.replaced.push((gone.remove(old), node)),
+                None => diff.added.push(node),
+            }
+        }
+
+        diff.removed.extend(gone);
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.replaced.is_empty()
+    }
+
+    /// Nodes that need to be rebuilt to converge: new instances and replacements.
+    pub fn new_nodes(&self) -> Vec<&'a BitteNode> {
+        self.added
+            .iter()
+            .copied()
+            .chain(self.replaced.iter().map(|(_, after)| *after))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NodeSetDiff;
+    use crate::types::BitteNode;
+    use pretty_assertions::assert_eq;
+
+    fn node(id: &str, name: &str) -> BitteNode {
+        BitteNode {
+            id: id.to_string(),
+            name: name.to_string(),
+            priv_ip: "10.0.0.1".parse().unwrap(),
+            pub_ip: "3.0.0.1".parse().unwrap(),
+            nixos: name.to_string(),
+            nomad_client: None,
+            node_type: None,
+            zone: None,
+            asg: None,
+            image: None,
+            lifecycle: None,
+            tags: Default::default(),
+            provider: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_node_set_diff() {
+        let before = vec![
+            node("i-1", "core-1"),
+            node("i-2", "core-2"),
+            node("i-3", ""),
+            node("i-6", "client"),
+            node("i-7", "client"),
+            node("i-8", ""),
+        ];
+        let after = vec![
+            node("i-1", "core-1"),
+            node("i-4", "core-2"),
+            node("i-5", ""),
+            node("i-6", "client"),
+            node("i-9", "client"),
+        ];
+
+        let diff = NodeSetDiff::new(&before, &after);
+        let ids = |nodes: Vec<&BitteNode>| -> Vec<String> {
+            nodes.iter().map(|node| node.id.clone()).collect()
+        };
+
+        assert_eq!(ids(diff.added.clone()), vec!["i-5"]);
+        assert_eq!(ids(diff.removed.clone()), vec!["i-3", "i-8"]);
+        assert_eq!(diff.replaced.len(), 2);
+        assert_eq!(diff.replaced[1].0.id, "i-7");
+        assert_eq!(ids(diff.new_nodes()), vec!["i-5", "i-4", "i-9"]);
+    }
+}
@@ -0,0 +1,77 @@
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use log::warn;
+
+use crate::cancel::{self, CancellationToken};
+
+/// Longest wait between two attempts to reconnect.
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Whether a stream failed because the connection broke (the laptop slept, the VPN flapped)
+/// rather than because the request was wrong, so reconnecting may help.
+pub fn is_transient(error: &anyhow::Error) -> bool {
+    match error.downcast_ref::<reqwest::Error>() {
+        Some(e) => {
+            e.is_connect()
+                || e.is_timeout()
+                || e.is_body()
+                || e.is_request()
+                || e.status().map_or(false, |status| status.is_server_error())
+        }
+        None => false,
+    }
+}
+
+/// Keeps track of the attempts to reconnect a stream, waiting longer after every failure.
+#[derive(Debug, Default)]
+pub struct Reconnect {
+    attempts: u32,
+    disconnected: Option<Instant>,
+}
+
+impl Reconnect {
+    /// Wait before the next attempt after `error`, or fail with it if it isn't transient.
+    /// Cancelling stops waiting.
+    pub async fn wait(
+        &mut self,
+        what: &str,
+        error: anyhow::Error,
+        cancel: &CancellationToken,
+    ) -> Result<()> {
+        if cancel::is_cancelled(&error) || !is_transient(&error) {
+            return Err(error);
+        }
+        self.backoff(what, &format!("{:#}", error), cancel).await
+    }
+
+    /// Wait before the next attempt after the server closed a stream that should go on.
+    pub async fn closed(&mut self, what: &str, cancel: &CancellationToken) -> Result<()> {
+        self.backoff(what, "closed by the server", cancel).await
+    }
+
+    async fn backoff(
+        &mut self,
+        what: &str,
+        reason: &str,
+        cancel: &CancellationToken,
+    ) -> Result<()> {
+        self.disconnected.get_or_insert_with(Instant::now);
+        let delay = Duration::from_secs(1 << self.attempts.min(5)).min(MAX_DELAY);
+        self.attempts += 1;
+
+        warn!(
+            "{} disconnected ({}), reconnecting in {}s",
+            what,
+            reason,
+            delay.as_secs()
+        );
+        cancel::sleep(cancel, delay).await
+    }
+
+    /// Note that the stream is connected again, returning how long it was gone if it was.
+    pub fn connected(&mut self) -> Option<Duration> {
+        self.attempts = 0;
+        self.disconnected.take().map(|since| since.elapsed())
+    }
+}
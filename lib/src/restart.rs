@@ -0,0 +1,310 @@
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use log::info;
+use serde_json::{json, Value};
+
+use crate::{
+    cancel::{self, CancellationToken},
+    history,
+    job::SERVER_FIELDS,
+    nomad::NomadApi,
+    types::{NomadJobRun, NomadNodeAllocation},
+};
+
+/// Job meta key counting the restarts done by submitting the job again.
+pub const RESTART_META: &str = "bitte-restart";
+
+/// How long the restarted tasks of an allocation have to keep running to count as healthy.
+const HEALTHY_AFTER: Duration = Duration::from_secs(10);
+
+/// How long an allocation gets to run again after an in-place restart.
+const HEALTHY_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Increment the restart stamp in the meta of a job specification and return it. Nomad only
+/// replaces the allocations of a job whose specification changed, the stamp is that change.
+pub fn bump_stamp(spec: &mut Value) -> u64 {
+    let meta = &mut spec["Meta"];
+    if !meta.is_object() {
+        *meta = json!({});
+    }
+
+    let stamp = meta[RESTART_META]
+        .as_str()
+        .and_then(|stamp| stamp.parse::<u64>().ok())
+        .unwrap_or_default()
+        + 1;
+    // job meta only holds strings
+    meta[RESTART_META] = json!(stamp.to_string());
+    stamp
+}
+
+/// Restart `job` by submitting it again with a new restart stamp, so all of its allocations are
+/// rescheduled the way its update stanza says, with canaries and health checks. Fails if the job
+/// changed since it was fetched.
+pub async fn resubmit(
+    nomad: &NomadApi,
+    cluster: &str,
+    namespace: &str,
+    job: &str,
+) -> Result<NomadJobRun> {
+    let mut spec: Value = nomad
+        .get(&format!("/v1/job/{}", job), &[("namespace", namespace)])
+        .await
+        .with_context(|| format!("couldn't find {}/{}", namespace, job))?;
+
+    if spec["Stop"].as_bool().unwrap_or_default() {
+        bail!("{}/{} is stopped, run it instead", namespace, job);
+    }
+
+    let index = spec["JobModifyIndex"].as_u64().unwrap_or_default();
+    if let Some(fields) = spec.as_object_mut() {
+        for field in SERVER_FIELDS {
+            fields.remove(*field);
+        }
+    }
+    let stamp = bump_stamp(&mut spec);
+
+    let run: NomadJobRun = nomad
+        .post(
+            "/v1/jobs",
+            &[("namespace", namespace)],
+            &json!({ "Job": spec, "EnforceIndex": true, "JobModifyIndex": index }),
+        )
+        .await
+        .with_context(|| format!("couldn't submit {}/{} again", namespace, job))?;
+
+    history::record(
+        cluster,
+        "job-restart",
+        json!({
+            "job": job,
+            "namespace": namespace,
+            "stamp": stamp,
+            "eval": run.eval_id,
+        }),
+    )?;
+
+    Ok(run)
+}
+
+/// The allocation with id `prefix`, in any namespace.
+pub async fn find_alloc(nomad: &NomadApi, prefix: &str) -> Result<NomadNodeAllocation> {
+    let mut allocs: Vec<NomadNodeAllocation> = nomad
+        .get("/v1/allocations", &[("prefix", prefix), ("namespace", "*")])
+        .await?;
+
+    match allocs.len() {
+        0 => bail!("there is no allocation {}", prefix),
+        1 => Ok(allocs.remove(0)),
+        n => bail!("{} matches {} allocations, use a longer prefix", prefix, n),
+    }
+}
+
+/// The running allocations of `job`.
+pub async fn running_allocs(
+    nomad: &NomadApi,
+    namespace: &str,
+    job: &str,
+) -> Result<Vec<NomadNodeAllocation>> {
+    let mut allocs: Vec<NomadNodeAllocation> = nomad
+        .get(
+            &format!("/v1/job/{}/allocations", job),
+            &[("namespace", namespace)],
+        )
+        .await
+        .with_context(|| format!("couldn't find {}/{}", namespace, job))?;
+
+    allocs.retain(|alloc| alloc.client_status == "running");
+    allocs.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(allocs)
+}
+
+/// Restart the tasks of `alloc` where they run, all of them unless `task` is given. The
+/// allocation keeps its node, its id, and its local data.
+pub async fn in_place(
+    nomad: &NomadApi,
+    cluster: &str,
+    alloc: &NomadNodeAllocation,
+    task: Option<&str>,
+) -> Result<()> {
+    if let Some(task) = task {
+        if !alloc.task_states.contains_key(task) {
+            bail!("allocation {} has no task {}", alloc.name, task);
+        }
+    }
+
+    let _: Value = nomad
+        .post(
+            &format!("/v1/client/allocation/{}/restart", alloc.id),
+            &[("namespace", alloc.namespace.as_str())],
+            &json!({ "TaskName": task.unwrap_or_default(), "AllTasks": task.is_none() }),
+        )
+        .await
+        .with_context(|| format!("couldn't restart {}", alloc.name))?;
+
+    info!("restarted {} ({}) in place", alloc.name, alloc.id);
+    history::record(
+        cluster,
+        "alloc-restart",
+        json!({
+            "alloc": alloc.id,
+            "job": alloc.job_id,
+            "namespace": alloc.namespace,
+            "task": task,
+            "mode": "in-place",
+        }),
+    )
+}
+
+/// Whether the running tasks of `alloc` that were restarted, all of them unless `task` is
+/// given, run again in `now`: each restarted since `alloc` and running. Fails if one of them failed.
+pub fn restarted(
+    alloc: &NomadNodeAllocation,
+    now: &NomadNodeAllocation,
+    task: Option<&str>,
+) -> Result<bool> {
+    let restarts = |state: &Value| state["Restarts"].as_u64().unwrap_or_default();
+
+    let mut running = true;
+    for (name, before) in alloc.task_states.iter() {
+        // finished prestart tasks don't run again
+        if task.map_or(false, |task| task != name) || before["State"] != "running" {
+            continue;
+        }
+        let state = match now.task_states.get(name) {
+            Some(state) => state,
+            None => return Ok(false),
+        };
+        if state["Failed"] == true {
+            bail!("task {} of {} failed after the restart", name, now.name);
+        }
+        running &= state["State"] == "running" && restarts(state) > restarts(before);
+    }
+    Ok(running)
+}
+
+/// Wait until the tasks of `alloc` restarted in place have been running again for
+/// `HEALTHY_AFTER`, so the next allocation is only restarted once this one serves again.
+pub async fn wait_running(
+    nomad: &NomadApi,
+    alloc: &NomadNodeAllocation,
+    task: Option<&str>,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    let deadline = Instant::now() + HEALTHY_TIMEOUT;
+    let mut since: Option<Instant> = None;
+    loop {
+        cancel::sleep(cancel, POLL_INTERVAL).await?;
+        let now: NomadNodeAllocation = nomad
+            .get(
+                &format!("/v1/allocation/{}", alloc.id),
+                &[("namespace", alloc.namespace.as_str())],
+            )
+            .await
+            .with_context(|| format!("couldn't look up {}", alloc.name))?;
+
+        if restarted(alloc, &now, task)? {
+            if since.get_or_insert_with(Instant::now).elapsed() >= HEALTHY_AFTER {
+                return Ok(());
+            }
+        } else {
+            since = None;
+        }
+        if Instant::now() > deadline {
+            bail!(
+                "{} isn't running again {}s after the restart",
+                alloc.name,
+                HEALTHY_TIMEOUT.as_secs()
+            );
+        }
+    }
+}
+
+/// Stop `alloc` so that Nomad places a replacement, possibly on another node. Returns the id of
+/// the evaluation placing it.
+pub async fn reschedule(
+    nomad: &NomadApi,
+    cluster: &str,
+    alloc: &NomadNodeAllocation,
+) -> Result<String> {
+    let response: Value = nomad
+        .post(
+            &format!("/v1/allocation/{}/stop", alloc.id),
+            &[("namespace", alloc.namespace.as_str())],
+            &json!({}),
+        )
+        .await
+        .with_context(|| format!("couldn't stop {}", alloc.name))?;
+
+    let eval_id = response["EvalID"].as_str().unwrap_or_default().to_string();
+    info!("stopped {} ({}) for rescheduling", alloc.name, alloc.id);
+    history::record(
+        cluster,
+        "alloc-restart",
+        json!({
+            "alloc": alloc.id,
+            "job": alloc.job_id,
+            "namespace": alloc.namespace,
+            "mode": "reschedule",
+            "eval": eval_id,
+        }),
+    )?;
+
+    Ok(eval_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bump_stamp, restarted};
+    use crate::types::NomadNodeAllocation;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    fn alloc(tasks: serde_json::Value) -> NomadNodeAllocation {
+        serde_json::from_value(json!({
+            "ID": "a", "Name": "web.web[0]", "JobID": "web", "Namespace": "default",
+            "TaskGroup": "web", "NodeID": "n", "ClientStatus": "running",
+            "DesiredStatus": "run", "TaskStates": tasks,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_restarted() {
+        let before = alloc(json!({
+            "web": { "State": "running", "Restarts": 1 },
+            "sidecar": { "State": "running", "Restarts": 0 },
+        }));
+        let restarting = alloc(json!({
+            "web": { "State": "pending", "Restarts": 2 },
+            "sidecar": { "State": "running", "Restarts": 0 },
+        }));
+        let running = alloc(json!({
+            "web": { "State": "running", "Restarts": 2 },
+            "sidecar": { "State": "running", "Restarts": 0 },
+        }));
+        let failed = alloc(json!({
+            "web": { "State": "dead", "Restarts": 2, "Failed": true },
+        }));
+
+        assert!(!restarted(&before, &restarting, Some("web")).unwrap());
+        assert!(restarted(&before, &running, Some("web")).unwrap());
+        assert!(!restarted(&before, &running, None).unwrap());
+        assert!(restarted(&before, &failed, Some("web")).is_err());
+    }
+
+    #[test]
+    fn test_bump_stamp() {
+        let mut spec = json!({ "ID": "web", "Meta": null });
+        assert_eq!(bump_stamp(&mut spec), 1);
+        assert_eq!(bump_stamp(&mut spec), 2);
+        assert_eq!(spec["Meta"], json!({ "bitte-restart": "2" }));
+
+        let mut spec = json!({ "Meta": { "owner": "ops", "bitte-restart": "41" } });
+        assert_eq!(bump_stamp(&mut spec), 42);
+        assert_eq!(spec["Meta"]["owner"], "ops");
+    }
+}
@@ -0,0 +1,460 @@
+use std::{net::IpAddr, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use log::info;
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    certs,
+    endpoint::Service,
+    nomad::NomadApi,
+    raft::{self, RaftStatus},
+    rebuild::NodeKind,
+    secret, ssh,
+    types::BitteNode,
+    unseal::{Unsealer, VaultInstance},
+    vault,
+};
+
+/// The certificate a node serves with, the same followed by its CA chain, and its key. Consul,
+/// Nomad, and Vault all read theirs from here.
+pub const CERT_FILE: &str = "/etc/ssl/certs/cert.pem";
+pub const FULL_FILE: &str = "/etc/ssl/certs/full.pem";
+pub const KEY_FILE: &str = "/etc/ssl/certs/cert-key.pem";
+
+/// How often and how long apart a reloaded service is asked for its certificate before the
+/// rotation of the node is rolled back.
+const VERIFY_ATTEMPTS: usize = 12;
+const VERIFY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Whether a node runs the servers of the cluster, is a Nomad client, or runs neither, like
+/// the routing and monitoring nodes, which only run a Consul agent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NodeRole {
+    Core,
+    Client,
+    Other,
+}
+
+impl NodeRole {
+    /// The role of `node`, told apart the same way `bitte rebuild` orders the nodes.
+    pub fn of(node: &BitteNode) -> Self {
+        match NodeKind::of(node) {
+            NodeKind::Core => NodeRole::Core,
+            NodeKind::Client => NodeRole::Client,
+            NodeKind::Other => NodeRole::Other,
+        }
+    }
+
+    /// The services serving with the certificate of the node, in the order they are reloaded.
+    /// Vault comes last: it issues the certificates, so it only switches once the others are
+    /// known to work with the new one.
+    pub fn services(self) -> &'static [Service] {
+        match self {
+            NodeRole::Core => &[Service::Consul, Service::Nomad, Service::Vault],
+            NodeRole::Client => &[Service::Consul, Service::Nomad],
+            NodeRole::Other => &[Service::Consul],
+        }
+    }
+
+    /// The role of Vault PKI issuing the certificates of these nodes.
+    pub fn pki_role(self) -> &'static str {
+        match self {
+            NodeRole::Core => "server",
+            NodeRole::Client | NodeRole::Other => "client",
+        }
+    }
+}
+
+/// The names the certificate of `node` is issued for: those Consul and Nomad verify their
+/// servers and clients by, those the services are reached at, and the node itself.
+pub fn alt_names(node: &BitteNode, role: NodeRole, domain: &str, region: &str) -> Vec<String> {
+    let mut names = match role {
+        NodeRole::Core => vec![
+            format!("server.{}.consul", region),
+            "server.global.nomad".to_string(),
+            "consul.service.consul".to_string(),
+            "nomad.service.consul".to_string(),
+            "vault.service.consul".to_string(),
+            format!("consul.{}", domain),
+            format!("nomad.{}", domain),
+            format!("vault.{}", domain),
+        ],
+        NodeRole::Client => vec!["client.global.nomad".to_string()],
+        NodeRole::Other => Vec::new(),
+    };
+    names.push(node.name.clone());
+    names.push("localhost".to_string());
+    names
+}
+
+/// A certificate and key issued by Vault PKI.
+#[derive(Debug, Deserialize)]
+pub struct IssuedCert {
+    pub certificate: String,
+    pub issuing_ca: String,
+    #[serde(default)]
+    pub ca_chain: Vec<String>,
+    pub private_key: String,
+    /// Like `39:dd:2e:90`.
+    pub serial_number: String,
+}
+
+impl IssuedCert {
+    /// The certificate followed by the chain up to the root, as `full.pem` holds it.
+    pub fn full_chain(&self) -> String {
+        let mut blocks = vec![self.certificate.trim().to_string()];
+        if self.ca_chain.is_empty() {
+            blocks.push(self.issuing_ca.trim().to_string());
+        } else {
+            blocks.extend(self.ca_chain.iter().map(|pem| pem.trim().to_string()));
+        }
+        blocks.join("\n") + "\n"
+    }
+}
+
+/// What `bitte certs rotate` asked for beyond the defaults, per kind of node. Kept in Vault so
+/// the next rotation issues equivalent certificates.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct IssueSettings {
+    /// Extra DNS names and ips, like vanity hostnames of the services.
+    #[serde(default)]
+    pub sans: Vec<String>,
+    #[serde(default)]
+    pub ttl: Option<String>,
+}
+
+impl IssueSettings {
+    /// Add the names in `add`, drop those in `drop`, and replace the TTL if `ttl` is given.
+    /// Returns whether anything changed.
+    pub fn update(&mut self, add: &[String], drop: &[String], ttl: Option<&str>) -> bool {
+        let before = self.clone();
+        for san in add.iter() {
+            if !self.sans.contains(san) {
+                self.sans.push(san.clone());
+            }
+        }
+        self.sans.retain(|san| !drop.contains(san));
+        if let Some(ttl) = ttl {
+            self.ttl = Some(ttl.to_string());
+        }
+        *self != before
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SavedSettings {
+    #[serde(default)]
+    pub core: IssueSettings,
+    #[serde(default)]
+    pub client: IssueSettings,
+}
+
+impl SavedSettings {
+    /// The settings of the nodes of `role`, shared by all nodes issued from the same PKI role.
+    pub fn role_mut(&mut self, role: NodeRole) -> &mut IssueSettings {
+        match role {
+            NodeRole::Core => &mut self.core,
+            NodeRole::Client | NodeRole::Other => &mut self.client,
+        }
+    }
+
+    pub fn role(&self, role: NodeRole) -> &IssueSettings {
+        match role {
+            NodeRole::Core => &self.core,
+            NodeRole::Client | NodeRole::Other => &self.client,
+        }
+    }
+}
+
+/// Where the settings of the cluster at `domain` are kept in Vault.
+fn settings_path(domain: &str) -> String {
+    format!("bitte/certs/{}", domain)
+}
+
+pub async fn load_settings(domain: &str) -> Result<SavedSettings> {
+    match secret::find(secret::DEFAULT_MOUNT, &settings_path(domain)).await? {
+        Some(data) => Ok(serde_json::from_value(Value::Object(data))?),
+        None => Ok(SavedSettings::default()),
+    }
+}
+
+pub async fn save_settings(domain: &str, settings: &SavedSettings) -> Result<()> {
+    let data = serde_json::from_value(json!(settings))?;
+    secret::put(secret::DEFAULT_MOUNT, &settings_path(domain), &data).await?;
+    Ok(())
+}
+
+/// The DNS names and the ips the certificate of `node` is issued for, with the extra `sans`.
+pub fn subject_names(
+    node: &BitteNode,
+    role: NodeRole,
+    domain: &str,
+    sans: &[String],
+) -> Result<(Vec<String>, Vec<String>)> {
+    let mut names = alt_names(node, role, domain, &node.region()?);
+    let mut ips = vec![node.priv_ip.to_string(), "127.0.0.1".to_string()];
+    for san in sans.iter() {
+        let known = if san.parse::<IpAddr>().is_ok() {
+            &mut ips
+        } else {
+            &mut names
+        };
+        if !known.contains(san) {
+            known.push(san.clone());
+        }
+    }
+    Ok((names, ips))
+}
+
+/// Issue a certificate for `node` from the PKI role of its kind, with the extra names and for
+/// the TTL of `settings`, otherwise as long as the role allows.
+pub async fn issue(
+    node: &BitteNode,
+    role: NodeRole,
+    domain: &str,
+    settings: &IssueSettings,
+) -> Result<IssuedCert> {
+    let (names, ips) = subject_names(node, role, domain, &settings.sans)?;
+    let mut request = json!({
+        "common_name": names[0],
+        "alt_names": names[1..].join(","),
+        "ip_sans": ips.join(","),
+    });
+    if let Some(ttl) = &settings.ttl {
+        request["ttl"] = json!(ttl);
+    }
+
+    let issued: Value = vault::request(Method::PUT, &format!("pki/issue/{}", role.pki_role()))?
+        .json(&request)
+        .send()
+        .await?
+        .error_for_status()
+        .with_context(|| format!("vault refused to issue a certificate for {}", node.name))?
+        .json()
+        .await?;
+    Ok(serde_json::from_value(issued["data"].clone())?)
+}
+
+/// Whether two serial numbers are the same, one as Vault and one as openssl writes them.
+pub fn same_serial(a: &str, b: &str) -> bool {
+    let normalize = |serial: &str| {
+        serial
+            .chars()
+            .filter(|c| *c != ':')
+            .collect::<String>()
+            .to_uppercase()
+            .trim_start_matches('0')
+            .to_string()
+    };
+    normalize(a) == normalize(b)
+}
+
+/// Write `cert` to the node at `ip`, keeping the files it replaces with a `.bak` suffix. The
+/// files are overwritten in place so they keep their owners and modes.
+pub fn install(cluster: &str, ip: IpAddr, cert: &IssuedCert) -> Result<()> {
+    let files = [
+        (CERT_FILE, cert.certificate.trim().to_string() + "\n"),
+        (FULL_FILE, cert.full_chain()),
+        (KEY_FILE, cert.private_key.trim().to_string() + "\n"),
+    ];
+    for (path, contents) in files.iter() {
+        ssh::remote(
+            cluster,
+            ip,
+            &format!("umask 077 && cat > {}.new", path),
+            contents,
+        )?;
+    }
+
+    let swap: Vec<String> = files
+        .iter()
+        .map(|(path, _)| format!("cp -p {0} {0}.bak && cat {0}.new > {0} && rm {0}.new", path))
+        .collect();
+    ssh::remote(cluster, ip, &swap.join(" && "), "")?;
+    Ok(())
+}
+
+/// Put the certificate that `install` replaced back in place.
+pub fn restore(cluster: &str, ip: IpAddr) -> Result<()> {
+    let restore: Vec<String> = [CERT_FILE, FULL_FILE, KEY_FILE]
+        .iter()
+        .map(|path| format!("cat {0}.bak > {0}", path))
+        .collect();
+    ssh::remote(cluster, ip, &restore.join(" && "), "")?;
+    Ok(())
+}
+
+/// Have `service` on the node at `ip` read its certificate again. All three reload TLS on
+/// SIGHUP, without dropping their connections or leadership.
+pub fn reload(cluster: &str, ip: IpAddr, service: Service) -> Result<()> {
+    ssh::remote(cluster, ip, &format!("systemctl reload {}", service), "")?;
+    Ok(())
+}
+
+/// Wait until `service` at `ip` presents the certificate with `serial`.
+pub async fn verify(ip: IpAddr, service: Service, serial: &str) -> Result<()> {
+    let port = certs::SERVER_PORTS
+        .iter()
+        .find(|(known, _)| *known == service)
+        .map(|(_, port)| *port)
+        .with_context(|| format!("don't know the port of {}", service))?;
+
+    let mut last = String::new();
+    for attempt in 1..=VERIFY_ATTEMPTS {
+        match certs::served_chain(ip, port).await {
+            Ok(chain) => {
+                let served = certs::serial(&certs::pem_blocks(&chain)[0])?;
+                if same_serial(&served, serial) {
+                    return Ok(());
+                }
+                last = format!("it still presents {}", served);
+            }
+            Err(e) => last = format!("{:#}", e),
+        }
+        info!(
+            "waiting for {} on {} to present the new certificate ({}/{}): {}",
+            service, ip, attempt, VERIFY_ATTEMPTS, last
+        );
+        tokio::time::sleep(VERIFY_INTERVAL).await;
+    }
+    bail!(
+        "{} on {} didn't switch to the new certificate: {}",
+        service,
+        ip,
+        last
+    )
+}
+
+/// Whether all servers of a Raft cluster are healthy. Unlike `RaftStatus::problems`, this
+/// doesn't count a failure tolerance of 0 against it, clusters with a single core node have to
+/// be rotated too.
+fn healthy(status: &RaftStatus) -> bool {
+    status.health.healthy && status.health.servers.iter().all(|server| server.healthy)
+}
+
+/// Whether `service` is healthy on its core nodes, so the next node can be rotated. For Vault
+/// that is the instance on `node` being unsealed, reached at its public ip with `public`.
+pub async fn check_health(
+    domain: &str,
+    nomad: &NomadApi,
+    node: &BitteNode,
+    service: Service,
+    public: bool,
+) -> Result<()> {
+    let status = match service {
+        Service::Consul => raft::consul(domain).await?,
+        Service::Nomad => raft::nomad(nomad).await?,
+        Service::Vault => {
+            let unsealer = Unsealer::new()?;
+            for instance in VaultInstance::on_core_nodes(std::slice::from_ref(node), public).iter()
+            {
+                let health = unsealer.health(instance).await?;
+                if !health.initialized || health.sealed {
+                    bail!("vault on {} is sealed", node.name);
+                }
+            }
+            return Ok(());
+        }
+    };
+    if !healthy(&status) {
+        bail!(
+            "the {} servers are unhealthy: {}",
+            service,
+            status.problems.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Wait until `service` is healthy again after its certificate changed on `node`.
+pub async fn wait_healthy(
+    domain: &str,
+    nomad: &NomadApi,
+    node: &BitteNode,
+    service: Service,
+    public: bool,
+) -> Result<()> {
+    let mut attempt = 1;
+    loop {
+        match check_health(domain, nomad, node, service, public).await {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt >= VERIFY_ATTEMPTS => return Err(e),
+            Err(e) => info!(
+                "waiting for {} to be healthy ({}/{}): {:#}",
+                service, attempt, VERIFY_ATTEMPTS, e
+            ),
+        }
+        attempt += 1;
+        tokio::time::sleep(VERIFY_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{alt_names, same_serial, IssueSettings, IssuedCert, NodeRole};
+    use crate::types::{BitteNode, NomadClient};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_node_role() {
+        let node = |name: &str, nomad: bool| BitteNode {
+            id: format!("i-{}", name),
+            name: name.to_string(),
+            priv_ip: "10.0.0.1".parse().unwrap(),
+            pub_ip: "3.0.0.1".parse().unwrap(),
+            nixos: name.to_string(),
+            nomad_client: if nomad {
+                Some(NomadClient::default())
+            } else {
+                None
+            },
+            node_type: None,
+            zone: None,
+            asg: None,
+            image: None,
+            lifecycle: None,
+            tags: Default::default(),
+            provider: Default::default(),
+        };
+        assert_eq!(NodeRole::of(&node("core-1", false)), NodeRole::Core);
+        assert_eq!(NodeRole::of(&node("client-a", true)), NodeRole::Client);
+        let routing = node("routing", false);
+        assert_eq!(NodeRole::of(&routing), NodeRole::Other);
+        assert_eq!(NodeRole::Other.services().len(), 1);
+        assert_eq!(
+            alt_names(&routing, NodeRole::Other, "example.com", "eu-central-1"),
+            vec!["routing", "localhost"]
+        );
+    }
+
+    #[test]
+    fn test_update_settings() {
+        let mut settings = IssueSettings::default();
+        let vanity = vec!["ui.example.com".to_string(), "10.0.0.10".to_string()];
+        assert!(settings.update(&vanity, &[], Some("720h")));
+        assert!(!settings.update(&vanity[..1], &[], None));
+        assert_eq!(settings.ttl.as_deref(), Some("720h"));
+
+        assert!(settings.update(&[], &vanity[1..], None));
+        assert_eq!(settings.sans, vec!["ui.example.com"]);
+    }
+
+    #[test]
+    fn test_serial_and_chain() {
+        assert!(same_serial("0a:3f:dd:2e", "A3FDD2E"));
+        assert!(!same_serial("0a:3f:dd:2e", "A3FDD2F"));
+
+        let cert = IssuedCert {
+            certificate: "leaf\n".to_string(),
+            issuing_ca: "issuing".to_string(),
+            ca_chain: Vec::new(),
+            private_key: "key".to_string(),
+            serial_number: "0a".to_string(),
+        };
+        assert_eq!(cert.full_chain(), "leaf\nissuing\n");
+    }
+}
@@ -0,0 +1,314 @@
+use std::{
+    collections::HashMap,
+    env,
+    fmt::Write as _,
+    fs,
+    path::PathBuf,
+    process::Command,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::history;
+
+/// A named procedure from `bitte.json`, executed step by step with `bitte runbook <name>`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Runbook {
+    pub description: String,
+    /// Names of the parameters that must be given with `-p name=value`.
+    pub params: Vec<String>,
+    pub steps: Vec<RunbookStep>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RunbookStep {
+    pub name: String,
+    /// Identifies the step in the checkpoint, the name unless given. Steps can be added,
+    /// removed, or reordered between a run and its resumption as long as they keep their IDs.
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(flatten)]
+    pub action: StepAction,
+    /// Ask before running this step.
+    #[serde(default)]
+    pub confirm: bool,
+}
+
+/// What a step runs, `{param}` in the arguments is replaced with the parameter's value.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StepAction {
+    /// Arguments to bitte itself, e.g. `["node", "drain", "{node}"]`.
+    Bitte(Vec<String>),
+    /// Any other program and its arguments.
+    Run(Vec<String>),
+}
+
+impl RunbookStep {
+    pub fn id(&self) -> &str {
+        self.id.as_deref().unwrap_or(&self.name)
+    }
+}
+
+impl StepAction {
+    fn command(&self, params: &HashMap<String, String>) -> Result<Command> {
+        let (program, args) = match self {
+            StepAction::Bitte(args) => (env::current_exe()?, args.as_slice()),
+            StepAction::Run(args) => match args.split_first() {
+                Some((program, args)) => (PathBuf::from(program), args),
+                None => bail!("step has nothing to run"),
+            },
+        };
+
+        let mut cmd = Command::new(program);
+        cmd.args(args.iter().map(|arg| substitute(arg, params)));
+        Ok(cmd)
+    }
+}
+
+fn substitute(arg: &str, params: &HashMap<String, String>) -> String {
+    params.iter().fold(arg.to_string(), |arg, (name, value)| {
+        arg.replace(&format!("{{{}}}", name), value)
+    })
+}
+
+/// Outcome of one step, kept in the checkpoint and rendered into the report.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StepReport {
+    /// Checkpoints written before steps had IDs only have names.
+    #[serde(default)]
+    pub id: String,
+    pub name: String,
+    pub command: String,
+    pub status: String,
+    pub seconds: u64,
+}
+
+/// Progress of a runbook, so an interrupted or failed run resumes after the last completed step.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub params: HashMap<String, String>,
+    pub started: u64,
+    pub steps: Vec<StepReport>,
+}
+
+fn runbooks_dir() -> Result<PathBuf> {
+    let dir = PathBuf::from(crate::cache_root()?)
+        .join("bitte")
+        .join("runbooks");
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+fn checkpoint_file(cluster: &str, name: &str) -> Result<PathBuf> {
+    Ok(runbooks_dir()?.join(format!("{}-{}.checkpoint.json", cluster, name)))
+}
+
+impl StepReport {
+    fn id(&self) -> &str {
+        if self.id.is_empty() {
+            &self.name
+        } else {
+            &self.id
+        }
+    }
+}
+
+impl Runbook {
+    /// The steps `checkpoint` has no report of, in the order of the runbook.
+    fn pending<'a>(&'a self, checkpoint: &Checkpoint) -> Vec<&'a RunbookStep> {
+        self.steps
+            .iter()
+            .filter(|step| !checkpoint.steps.iter().any(|done| done.id() == step.id()))
+            .collect()
+    }
+
+    /// Execute the steps not yet completed for the same parameters, and write a markdown report.
+    pub fn execute(
+        &self,
+        cluster: &str,
+        name: &str,
+        params: HashMap<String, String>,
+        restart: bool,
+    ) -> Result<PathBuf> {
+        let missing: Vec<&String> = self
+            .params
+            .iter()
+            .filter(|param| !params.contains_key(*param))
+            .collect();
+        if !missing.is_empty() {
+            bail!("runbook {} needs the parameters {:?}", name, missing);
+        }
+
+        let checkpoint_path = checkpoint_file(cluster, name)?;
+        let mut checkpoint = match fs::read_to_string(&checkpoint_path) {
+            Ok(file) if !restart => serde_json::from_str(&file)?,
+            _ => Checkpoint::default(),
+        };
+
+        if checkpoint.params != params || checkpoint.steps.is_empty() {
+            checkpoint = Checkpoint {
+                params,
+                started: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+                steps: Vec::new(),
+            };
+        } else {
+            info!(
+                "resuming {}, {} of {} steps are left",
+                name,
+                self.pending(&checkpoint).len(),
+                self.steps.len()
+            );
+        }
+
+        for step in self.pending(&checkpoint) {
+            let mut cmd = step.action.command(&checkpoint.params)?;
+            let command = format!("{:?}", cmd);
+
+            if step.confirm
+                && !crate::confirm(&format!("run step `{}`: {}?", step.name, command), "yes")?
+            {
+                bail!(
+                    "stopped before step `{}`, run the runbook again to resume",
+                    step.name
+                );
+            }
+
+            println!("==> {}", step.name);
+            let started = Instant::now();
+            let status = cmd
+                .status()
+                .with_context(|| format!("couldn't run step `{}`", step.name))?;
+
+            if !status.success() {
+                warn!(
+                    "step `{}` failed, progress kept in {}",
+                    step.name,
+                    checkpoint_path.display()
+                );
+                bail!("step `{}` failed with {}", step.name, status);
+            }
+
+            checkpoint.steps.push(StepReport {
+                id: step.id().to_string(),
+                name: step.name.clone(),
+                command,
+                status: status.to_string(),
+                seconds: started.elapsed().as_secs(),
+            });
+            fs::write(&checkpoint_path, serde_json::to_string_pretty(&checkpoint)?)?;
+        }
+
+        let report =
+            runbooks_dir()?.join(format!("{}-{}-{}.md", cluster, name, checkpoint.started));
+        fs::write(&report, self.report(cluster, name, &checkpoint)?)?;
+        fs::remove_file(&checkpoint_path).ok();
+
+        history::record(
+            cluster,
+            "runbook",
+            json!({ "runbook": name, "params": checkpoint.params, "report": report }),
+        )?;
+
+        Ok(report)
+    }
+
+    fn report(&self, cluster: &str, name: &str, checkpoint: &Checkpoint) -> Result<String> {
+        let mut report = String::new();
+        writeln!(report, "# Runbook `{}` on {}", name, cluster)?;
+        writeln!(report)?;
+        if !self.description.is_empty() {
+            writeln!(report, "{}", self.description)?;
+            writeln!(report)?;
+        }
+
+        let mut params: Vec<_> = checkpoint.params.iter().collect();
+        params.sort();
+        for (param, value) in params {
+            writeln!(report, "- {}: `{}`", param, value)?;
+        }
+        writeln!(report)?;
+
+        writeln!(report, "| Step | Command | Status | Seconds |")?;
+        writeln!(report, "|------|---------|--------|---------|")?;
+        for step in checkpoint.steps.iter() {
+            writeln!(
+                report,
+                "| {} | `{}` | {} | {} |",
+                step.name, step.command, step.status, step.seconds
+            )?;
+        }
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{substitute, Checkpoint, Runbook, StepAction, StepReport};
+    use pretty_assertions::assert_eq;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_runbook_config() {
+        let runbook: Runbook = serde_json::from_str(
+            r#"{
+                "params": ["node"],
+                "steps": [
+                    { "name": "drain", "bitte": ["node", "drain", "{node}"], "confirm": true },
+                    { "name": "verify", "bitte": ["verify"] }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(runbook.steps.len(), 2);
+        assert!(runbook.steps[0].confirm);
+        match &runbook.steps[0].action {
+            StepAction::Bitte(args) => assert_eq!(args[2], "{node}"),
+            other => panic!("unexpected action {:?}", other),
+        }
+
+        let mut params = HashMap::new();
+        params.insert("node".to_string(), "client-1".to_string());
+        assert_eq!(substitute("{node}.cluster", &params), "client-1.cluster");
+    }
+
+    #[test]
+    fn test_pending_steps() {
+        let runbook: Runbook = serde_json::from_str(
+            r#"{
+                "steps": [
+                    { "name": "check first", "id": "check", "run": ["true"] },
+                    { "name": "drain the node", "id": "drain", "bitte": ["node", "drain"] },
+                    { "name": "rebuild", "bitte": ["rebuild"] }
+                ]
+            }"#,
+        )
+        .unwrap();
+        // written before the check was added and the drain renamed
+        let checkpoint = Checkpoint {
+            steps: vec![StepReport {
+                id: "drain".to_string(),
+                name: "drain".to_string(),
+                command: String::new(),
+                status: "exit status: 0".to_string(),
+                seconds: 1,
+            }],
+            ..Checkpoint::default()
+        };
+
+        let pending: Vec<&str> = runbook
+            .pending(&checkpoint)
+            .iter()
+            .map(|step| step.id())
+            .collect();
+        assert_eq!(pending, vec!["check", "rebuild"]);
+    }
+}
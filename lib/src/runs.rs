@@ -0,0 +1,84 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use log::{debug, warn};
+
+/// Scratch directory for the artifacts of a single invocation (rendered jobs, plans, build
+/// results). It is removed when the run succeeds and kept around for inspection when it fails.
+pub struct Run {
+    pub id: String,
+    pub dir: PathBuf,
+}
+
+impl Run {
+    pub fn start(command: &str) -> Result<Self> {
+        let started = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let id = format!("{}-{}-{}", started, process::id(), command);
+        let dir = runs_dir()?.join(&id);
+
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("couldn't create run directory {}", dir.display()))?;
+        debug!("run directory: {}", dir.display());
+
+        Ok(Self { id, dir })
+    }
+
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    pub fn write<C: AsRef<[u8]>>(&self, name: &str, contents: C) -> Result<PathBuf> {
+        let path = self.path(name);
+        fs::write(&path, contents).with_context(|| format!("couldn't write {}", path.display()))?;
+        Ok(path)
+    }
+
+    /// Keep the run directory regardless of the outcome, returning its path.
+    pub fn keep(self) -> PathBuf {
+        self.dir
+    }
+
+    /// Clean up after the run, passing `result` through unchanged.
+    pub fn finish<T>(self, result: Result<T>) -> Result<T> {
+        match &result {
+            Ok(_) => {
+                if let Err(e) = fs::remove_dir_all(&self.dir) {
+                    warn!("couldn't remove {}: {}", self.dir.display(), e);
+                }
+            }
+            Err(_) => warn!("artifacts of failed run kept in {}", self.dir.display()),
+        }
+        result
+    }
+}
+
+pub fn runs_dir() -> Result<PathBuf> {
+    Ok(Path::new(&crate::cache_root()?).join("bitte").join("runs"))
+}
+
+/// Remove run directories that were last modified more than `max_age` ago.
+pub fn gc(max_age: Duration) -> Result<Vec<PathBuf>> {
+    let dir = runs_dir()?;
+    let mut removed = Vec::new();
+
+    if !dir.is_dir() {
+        return Ok(removed);
+    }
+
+    for entry in fs::read_dir(&dir)? {
+        let entry = entry?;
+        let age = entry.metadata()?.modified()?.elapsed().unwrap_or_default();
+
+        if entry.file_type()?.is_dir() && age > max_age {
+            fs::remove_dir_all(entry.path())?;
+            removed.push(entry.path());
+        }
+    }
+
+    Ok(removed)
+}
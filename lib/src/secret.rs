@@ -0,0 +1,180 @@
+use anyhow::{bail, Context, Result};
+use reqwest::{Method, StatusCode};
+use serde_json::{json, Map, Value};
+
+use crate::vault;
+
+/// The KV-v2 mount the cluster keeps its secrets in, the terraform state among them.
+pub const DEFAULT_MOUNT: &str = "secret";
+
+/// `path` relative to `mount`, also for raw paths like `secret/data/vbk/...` pasted from
+/// elsewhere.
+pub fn relative<'a>(mount: &str, path: &'a str) -> &'a str {
+    let path = path.trim_matches('/');
+    let path = path
+        .strip_prefix(mount)
+        .and_then(|rest| rest.strip_prefix('/'))
+        .unwrap_or(path);
+    ["data/", "metadata/"]
+        .iter()
+        .find_map(|api| path.strip_prefix(api))
+        .unwrap_or(path)
+}
+
+/// The latest version of the secret at `path`.
+pub async fn get(mount: &str, path: &str) -> Result<Map<String, Value>> {
+    match find(mount, path).await? {
+        Some(data) => Ok(data),
+        None => bail!("there is no secret at {}/{}", mount, relative(mount, path)),
+    }
+}
+
+/// The latest version of the secret at `path`, if there is one.
+pub async fn find(mount: &str, path: &str) -> Result<Option<Map<String, Value>>> {
+    let path = relative(mount, path);
+    let response = vault::request(Method::GET, &format!("{}/data/{}", mount, path))?
+        .send()
+        .await?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+
+    let secret: Value = response
+        .error_for_status()
+        .with_context(|| format!("couldn't read {}/{}", mount, path))?
+        .json()
+        .await?;
+    secret["data"]["data"]
+        .as_object()
+        .cloned()
+        .with_context(|| format!("{}/{} was deleted", mount, path))
+        .map(Some)
+}
+
+/// Write `data` as a new version of the secret at `path`, returning the version.
+pub async fn put(mount: &str, path: &str, data: &Map<String, Value>) -> Result<u64> {
+    let path = relative(mount, path);
+    let written: Value = vault::request(Method::POST, &format!("{}/data/{}", mount, path))?
+        .json(&json!({ "data": data }))
+        .send()
+        .await?
+        .error_for_status()
+        .with_context(|| format!("couldn't write {}/{}", mount, path))?
+        .json()
+        .await?;
+    Ok(written["data"]["version"].as_u64().unwrap_or_default())
+}
+
+/// The secrets and directories under `path`, directories ending in `/`.
+pub async fn list(mount: &str, path: &str) -> Result<Vec<String>> {
+    let path = relative(mount, path);
+    let response = vault::request(Method::GET, &format!("{}/metadata/{}", mount, path))?
+        .query(&[("list", "true")])
+        .send()
+        .await?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(Vec::new());
+    }
+
+    let listing: Value = response
+        .error_for_status()
+        .with_context(|| format!("couldn't list {}/{}", mount, path))?
+        .json()
+        .await?;
+    Ok(listing["data"]["keys"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|key| key.as_str().map(String::from))
+        .collect())
+}
+
+/// The fields of a secret as `KEY="value"` lines. Values that aren't strings are written as
+/// JSON.
+pub fn to_dotenv(data: &Map<String, Value>) -> String {
+    data.iter()
+        .map(|(key, value)| {
+            let value = match value {
+                Value::String(value) => value.clone(),
+                other => other.to_string(),
+            };
+            // a JSON string is also a valid double quoted dotenv value
+            format!("{}={}\n", key, Value::String(value))
+        })
+        .collect()
+}
+
+/// Fields from `KEY=value` pairs, as given on the command line or in a dotenv file. Values in
+/// double quotes are unescaped, blank lines and comments are skipped.
+pub fn parse_pairs<'a>(lines: impl IntoIterator<Item = &'a str>) -> Result<Map<String, Value>> {
+    let mut data = Map::new();
+    for line in lines {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => bail!("{} is not KEY=value", line),
+        };
+
+        let value = if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+            serde_json::from_str(value).with_context(|| format!("couldn't unquote {}", key))?
+        } else {
+            value
+                .trim_start_matches('\'')
+                .trim_end_matches('\'')
+                .to_string()
+        };
+        data.insert(key.to_string(), Value::String(value));
+    }
+    Ok(data)
+}
+
+/// Fields from a file holding either a JSON object or dotenv lines.
+pub fn parse_file(contents: &str) -> Result<Map<String, Value>> {
+    match serde_json::from_str::<Value>(contents) {
+        Ok(Value::Object(data)) => Ok(data),
+        Ok(_) => bail!("the secret has to be a JSON object"),
+        Err(_) => parse_pairs(contents.lines()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_file, parse_pairs, relative, to_dotenv};
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    #[test]
+    fn test_relative() {
+        assert_eq!(relative("secret", "vbk/testnet/core"), "vbk/testnet/core");
+        assert_eq!(
+            relative("secret", "secret/data/vbk/testnet/core"),
+            "vbk/testnet/core"
+        );
+        assert_eq!(relative("secret", "/secret/metadata/vbk/"), "vbk");
+        assert_eq!(relative("kv", "secrets/app"), "secrets/app");
+    }
+
+    #[test]
+    fn test_dotenv() {
+        let data = parse_pairs(vec![
+            "# database",
+            "export DB_USER=bitte",
+            "DB_PASS=\"p\\\"w=d\"",
+            "DB_HOST='db.local'",
+        ])
+        .unwrap();
+        assert_eq!(
+            json!(data),
+            json!({ "DB_USER": "bitte", "DB_PASS": "p\"w=d", "DB_HOST": "db.local" })
+        );
+        assert_eq!(parse_file(&to_dotenv(&data)).unwrap(), data);
+
+        let data = parse_file(r#"{ "port": 5432 }"#).unwrap();
+        assert_eq!(to_dotenv(&data), "port=\"5432\"\n");
+        assert!(parse_pairs(vec!["nope"]).is_err());
+    }
+}
@@ -0,0 +1,318 @@
+use std::{
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    archive::s3_bucket,
+    consul,
+    endpoint::{self, Service},
+    pool::ClientPool,
+    sh,
+    types::BitteCluster,
+    vault,
+};
+
+/// The services whose Raft state is backed up.
+pub const SERVICES: [Service; 2] = [Service::Vault, Service::Consul];
+
+/// Settings of `bitte backup` and `bitte restore`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct SnapshotConfig {
+    /// S3 URL the snapshots are kept under, like `s3://backups/snapshots?region=eu-central-1`.
+    pub bucket: Option<String>,
+    /// The KMS key the snapshots are encrypted with, the AWS managed key of S3 by default.
+    pub kms_key: Option<String>,
+}
+
+/// Where the snapshots of a cluster are kept. Not the cache bucket, which every node can read.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapshotStore {
+    /// `s3://<bucket>[/<prefix>]`, without a trailing slash.
+    url: String,
+    region: Option<String>,
+    kms_key: Option<String>,
+}
+
+impl SnapshotStore {
+    /// The store at the S3 URL `bucket`, refusing the cache bucket `cache` of the cluster.
+    pub fn new(bucket: &str, kms_key: Option<String>, cache: Option<&str>) -> Result<Self> {
+        let (path, region) = s3_bucket(bucket)?;
+        let name = bucket_name(&path);
+        if name.is_empty() {
+            bail!("{} names no bucket", bucket);
+        }
+        if let Some(cache) = cache {
+            if bucket_name(&s3_bucket(cache)?.0) == name {
+                bail!(
+                    "{} is the cache bucket of the cluster, which every node can read, snapshots need a bucket of their own",
+                    name
+                );
+            }
+        }
+        Ok(Self {
+            url: format!("s3://{}", path),
+            region,
+            kms_key,
+        })
+    }
+
+    /// Where the snapshots of `service` of `cluster` are kept.
+    fn prefix(&self, cluster: &str, service: Service) -> String {
+        format!("{}/{}/{}/", self.url, cluster, service)
+    }
+}
+
+fn bucket_name(path: &str) -> &str {
+    path.split('/').next().unwrap_or_default()
+}
+
+fn aws(args: &[&str], region: &Option<String>) -> Result<String> {
+    sh(aws_command(args, region))
+}
+
+fn aws_command(args: &[&str], region: &Option<String>) -> Command {
+    let mut cmd = Command::new("aws");
+    cmd.arg("s3").args(args);
+    if let Some(region) = region {
+        cmd.arg("--region").arg(region);
+    }
+    cmd
+}
+
+/// The snapshot API of `service` and the header its token goes in.
+fn api(service: Service) -> Result<(&'static str, &'static str)> {
+    match service {
+        Service::Vault => Ok(("/v1/sys/storage/raft/snapshot", "X-Vault-Token")),
+        Service::Consul => Ok(("/v1/snapshot", "X-Consul-Token")),
+        Service::Nomad => bail!("nomad snapshots are not supported"),
+    }
+}
+
+async fn token(service: Service) -> Result<String> {
+    match service {
+        Service::Consul => consul::token().await,
+        _ => vault::token(),
+    }
+}
+
+/// The name of a snapshot of `service` taken now. The names sort by the time they were taken.
+fn name(service: Service) -> Result<String> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    Ok(format!("{}-{:012}.snap", service, now))
+}
+
+/// Where snapshots are kept while they are up- or downloaded, only accessible by the user.
+fn local_dir(cluster: &str) -> Result<PathBuf> {
+    let dir = PathBuf::from(crate::cache_root()?)
+        .join("bitte")
+        .join("snapshots")
+        .join(cluster);
+    fs::create_dir_all(&dir)?;
+    fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))?;
+    Ok(dir)
+}
+
+/// Take a snapshot of `service` and upload it encrypted with KMS to `store`, then drop all but
+/// the `keep` latest snapshots from it. Returns the S3 URL of the snapshot.
+pub async fn backup(
+    cluster: &BitteCluster,
+    store: &SnapshotStore,
+    service: Service,
+    keep: usize,
+) -> Result<String> {
+    let prefix = store.prefix(&cluster.name, service);
+    let (path, header) = api(service)?;
+    let address = endpoint::address(service, &cluster.domain);
+
+    let url = format!("{}{}", address, path);
+    let snapshot = ClientPool::shared()
+        .service(service, &address)?
+        .get(&url)
+        .header(header, token(service).await?)
+        .send()
+        .await
+        .with_context(|| format!("failed to query: {}", url))?
+        .error_for_status()
+        .with_context(|| format!("{} refused the snapshot", service))?
+        .bytes()
+        .await?;
+
+    // the snapshots hold every secret and token of the cluster
+    let name = name(service)?;
+    let file = local_dir(&cluster.name)?.join(&name);
+    crate::write_private(&file, &snapshot)?;
+
+    let target = format!("{}{}", prefix, name);
+    let local = file.display().to_string();
+    let mut args = vec!["cp", &local, &target, "--sse", "aws:kms"];
+    if let Some(key) = &store.kms_key {
+        args.extend(&["--sse-kms-key-id", key]);
+    }
+    if let Err(e) = aws(&args, &store.region) {
+        warn!("the snapshot is kept at {}", local);
+        return Err(e.context(format!("couldn't upload the snapshot to {}", target)));
+    }
+    fs::remove_file(&file)?;
+    info!(
+        "uploaded a {} byte snapshot of {} to {}",
+        snapshot.len(),
+        service,
+        target
+    );
+
+    let names = list(cluster, store, service)?;
+    for old in expired(&names, keep) {
+        let old = format!("{}{}", prefix, old);
+        match aws(&["rm", &old], &store.region) {
+            Ok(_) => info!("removed {}", old),
+            Err(e) => warn!("couldn't remove {}: {:?}", old, e),
+        }
+    }
+
+    Ok(target)
+}
+
+/// The snapshots of `service` in `store`, oldest first.
+pub fn list(
+    cluster: &BitteCluster,
+    store: &SnapshotStore,
+    service: Service,
+) -> Result<Vec<String>> {
+    let prefix = store.prefix(&cluster.name, service);
+    let output = aws_command(&["ls", &prefix], &store.region)
+        .output()
+        .context("couldn't run aws")?;
+
+    // a prefix nothing was stored under yet makes `aws s3 ls` exit with 1 without a message
+    let missing = output.status.code() == Some(1) && output.stderr.is_empty();
+    if !output.status.success() && !missing {
+        bail!(
+            "couldn't list the snapshots in {}: {}",
+            prefix,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(parse_listing(
+        &String::from_utf8_lossy(&output.stdout),
+        service,
+    ))
+}
+
+/// The names of the snapshots of `service` in the output of `aws s3 ls`, like
+/// `2021-08-02 10:00:00    1048576 vault-001627898400.snap`, oldest first.
+pub fn parse_listing(output: &str, service: Service) -> Vec<String> {
+    let start = format!("{}-", service);
+    let mut names: Vec<String> = output
+        .lines()
+        .filter_map(|line| line.split_whitespace().last())
+        .filter(|name| name.starts_with(&start) && name.ends_with(".snap"))
+        .map(String::from)
+        .collect();
+    names.sort();
+    names
+}
+
+/// The snapshots beyond the `keep` latest ones.
+pub fn expired(names: &[String], keep: usize) -> &[String] {
+    &names[..names.len().saturating_sub(keep)]
+}
+
+/// Download the snapshot `name` of `service` from `store`.
+pub fn download(
+    cluster: &BitteCluster,
+    store: &SnapshotStore,
+    service: Service,
+    name: &str,
+) -> Result<PathBuf> {
+    let prefix = store.prefix(&cluster.name, service);
+    let file = local_dir(&cluster.name)?.join(name);
+    // the directory is private, the file is too before the snapshot is written to it
+    crate::write_private(&file, [])?;
+    let local = file.display().to_string();
+    aws(
+        &["cp", &format!("{}{}", prefix, name), &local],
+        &store.region,
+    )
+    .with_context(|| format!("couldn't download {}", name))?;
+    Ok(file)
+}
+
+/// Replace the state of `service` with the snapshot at `file`. Vault refuses snapshots taken
+/// with other unseal keys unless `force` is given.
+pub async fn restore(
+    cluster: &BitteCluster,
+    service: Service,
+    file: &Path,
+    force: bool,
+) -> Result<()> {
+    let (path, header) = api(service)?;
+    let address = endpoint::address(service, &cluster.domain);
+    let client = ClientPool::shared().service(service, &address)?;
+    let snapshot = fs::read(file).with_context(|| format!("couldn't read {}", file.display()))?;
+
+    let request = match service {
+        Service::Vault if force => client.post(&format!("{}{}-force", address, path)),
+        Service::Vault => client.post(&format!("{}{}", address, path)),
+        _ => client.put(&format!("{}{}", address, path)),
+    };
+    request
+        .header(header, token(service).await?)
+        .body(snapshot)
+        .send()
+        .await
+        .with_context(|| format!("failed to send the snapshot to {}", service))?
+        .error_for_status()
+        .with_context(|| format!("{} refused the snapshot", service))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{expired, parse_listing, SnapshotStore};
+    use crate::endpoint::Service;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_parse_listing() {
+        let output = "2021-08-02 10:00:00    1048576 vault-001627898400.snap\n\
+                      2021-08-01 10:00:00    1048000 vault-001627812000.snap\n\
+                      2021-08-01 10:00:00        100 notes.txt\n";
+        let names = parse_listing(output, Service::Vault);
+        assert_eq!(
+            names,
+            vec!["vault-001627812000.snap", "vault-001627898400.snap"]
+        );
+        assert!(parse_listing(output, Service::Consul).is_empty());
+
+        assert_eq!(expired(&names, 1), &names[..1]);
+        assert!(expired(&names, 14).is_empty());
+    }
+
+    #[test]
+    fn test_snapshot_store() {
+        let store = SnapshotStore::new(
+            "s3://backups/snapshots?region=eu-central-1",
+            Some("alias/snapshots".to_string()),
+            Some("s3://cache?region=eu-central-1"),
+        )
+        .unwrap();
+        assert_eq!(
+            store.prefix("mainnet", Service::Vault),
+            "s3://backups/snapshots/mainnet/vault/"
+        );
+        assert_eq!(store.region.as_deref(), Some("eu-central-1"));
+
+        assert!(SnapshotStore::new("s3://cache/snapshots", None, Some("s3://cache")).is_err());
+        assert!(SnapshotStore::new("s3://", None, None).is_err());
+    }
+}
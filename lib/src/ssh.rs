@@ -1,11 +1,18 @@
+use std::io::Write;
 use std::net::IpAddr;
 use std::time::Duration;
-use std::{path::Path, process::Command};
+use std::{
+    path::Path,
+    process::{Command, Stdio},
+};
 use tokio::{net::TcpStream, time};
 
 use super::check_cmd;
 use crate::error::Error;
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
+
+/// Seconds `remote` waits for the connection to a node.
+const CONNECT_TIMEOUT: u64 = 5;
 
 pub fn ssh_keygen(ip: &IpAddr) -> Result<()> {
     check_cmd(Command::new("ssh-keygen").arg("-R").arg(ip.to_string()))
@@ -13,7 +20,65 @@ pub fn ssh_keygen(ip: &IpAddr) -> Result<()> {
     Ok(())
 }
 
+/// Arguments for `ssh` to run `args` as root on `ip`, with the key of `cluster` if there is one.
+pub fn ssh_args(ip: IpAddr, args: Vec<String>, cluster: &str) -> Vec<String> {
+    let mut flags: Vec<String> = vec!["-x".into(), "-p".into(), "22".into()];
+
+    let ssh_key_path = format!("secrets/ssh-{}", cluster);
+    let ssh_key = Path::new(&ssh_key_path);
+    if ssh_key.is_file() {
+        flags.push("-i".into());
+        flags.push(ssh_key_path);
+    }
+
+    flags.append(&mut vec![
+        "-o".into(),
+        "StrictHostKeyChecking=accept-new".into(),
+    ]);
+
+    flags.push(format!("root@{}", ip));
+    flags.extend(args);
+    flags
+}
+
+/// Run `command` as root on the node at `ip`, feeding it `input`. Secrets given as input never
+/// show up in a process listing. Nothing is asked for, and an unreachable node fails after
+/// `CONNECT_TIMEOUT` seconds.
+pub fn remote(cluster: &str, ip: IpAddr, command: &str, input: &str) -> Result<String> {
+    let timeout = format!("ConnectTimeout={}", CONNECT_TIMEOUT);
+    let mut child = Command::new("ssh")
+        .args(&["-o", &timeout, "-o", "BatchMode=yes"])
+        .args(ssh_args(ip, vec![command.to_string()], cluster))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("couldn't run ssh")?;
+    child
+        .stdin
+        .take()
+        .context("ssh has no stdin")?
+        .write_all(input.as_bytes())?;
+
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        bail!(
+            "`{}` failed on {}: {}",
+            command,
+            ip,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
 pub fn wait_for_ready(cluster: &str, ip: &IpAddr) -> Result<()> {
+    check_cmd(&mut ready_command(cluster, ip)).map_err(|_| Error::Unknown)?;
+    Ok(())
+}
+
+/// `ssh` to the node at `ip`, returning once it has finished booting.
+pub(crate) fn ready_command(cluster: &str, ip: &IpAddr) -> Command {
     let target = format!("root@{}", ip);
 
     let mut ssh_args = vec![
@@ -37,8 +102,9 @@ pub fn wait_for_ready(cluster: &str, ip: &IpAddr) -> Result<()> {
 
     ssh_args.push(&target);
     ssh_args.push("until grep true /etc/ready &>/dev/null; do sleep 1; done");
-    check_cmd(Command::new("ssh").args(ssh_args)).map_err(|_| Error::Unknown)?;
-    Ok(())
+    let mut cmd = Command::new("ssh");
+    cmd.args(ssh_args);
+    cmd
 }
 
 pub async fn wait_for_ssh(ip: &IpAddr) -> Result<()> {
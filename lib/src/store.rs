@@ -0,0 +1,119 @@
+use anyhow::{bail, Result};
+use serde::Serialize;
+
+use crate::executor::RemoteExecutor;
+
+/// Outcome of checking the nix store of a node.
+#[derive(Debug, Default, Serialize)]
+pub struct StoreReport {
+    pub node: String,
+    /// Number of paths checked if only a sample was, all of them otherwise.
+    pub sampled: Option<usize>,
+    pub corrupted: Vec<String>,
+    /// Corrupted paths that were fetched again from the cache.
+    pub repaired: Vec<String>,
+}
+
+/// Ends the output of `verify_command`, followed by the exit status of `nix store verify`.
+const STATUS_MARKER: &str = "bitte-verify-status:";
+
+/// The command that checks the contents of the store paths on a node against the hashes in
+/// its database, `sample` random paths or all of them. Signatures aren't checked, paths built
+/// on the node have none. `nix store verify` fails when it finds corruption, so its exit status
+/// is printed after its output, for `corrupted` to tell corruption from failing to check.
+fn verify_command(paths: Option<&[String]>, sample: Option<usize>) -> String {
+    let verify = "nix store verify --no-trust --check-contents";
+    let command = match (paths, sample) {
+        (Some(paths), _) => format!("{} {}", verify, paths.join(" ")),
+        (None, Some(sample)) => format!(
+            "set -o pipefail; nix path-info --all | shuf -n {} | xargs -r {}",
+            sample, verify
+        ),
+        (None, None) => format!("{} --all", verify),
+    };
+    format!("{} 2>&1; echo {} $?", command, STATUS_MARKER)
+}
+
+/// The store paths `nix store verify` reported as modified, like
+/// `path '/nix/store/...' was modified! expected hash '...', got '...'`, from the output of
+/// `verify_command`. Fails if the check failed without finding any.
+pub fn corrupted(output: &str) -> Result<Vec<String>> {
+    let (output, status) = match output.trim_end().rsplit_once(STATUS_MARKER) {
+        Some((output, status)) => (output, status.trim()),
+        None => bail!("the store check ended early: {}", output.trim()),
+    };
+
+    let paths: Vec<String> = output
+        .lines()
+        .filter(|line| line.contains("was modified!"))
+        .filter_map(|line| line.split('\'').nth(1))
+        .map(|path| path.to_string())
+        .collect();
+    if status != "0" && paths.is_empty() {
+        bail!(
+            "nix store verify failed with status {}: {}",
+            status,
+            output.trim()
+        );
+    }
+    Ok(paths)
+}
+
+/// Check the store of the node `executor` runs commands on.
+pub async fn verify(executor: &dyn RemoteExecutor, sample: Option<usize>) -> Result<Vec<String>> {
+    let output = executor.run_command(&verify_command(None, sample)).await?;
+    corrupted(&output)
+}
+
+/// Fetch `paths` again, from the S3 cache of the cluster if it has one besides the substituters
+/// the node already uses, and return the ones that are still corrupted afterwards.
+pub async fn repair(
+    executor: &dyn RemoteExecutor,
+    cache: Option<&str>,
+    paths: &[String],
+) -> Result<Vec<String>> {
+    if paths.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let substituters = match cache {
+        Some(cache) => format!(" --extra-substituters '{}'", cache),
+        None => String::new(),
+    };
+    executor
+        .run_command(&format!(
+            "nix store repair{} {}",
+            substituters,
+            paths.join(" ")
+        ))
+        .await?;
+
+    let output = executor
+        .run_command(&verify_command(Some(paths), None))
+        .await?;
+    corrupted(&output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::corrupted;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_corrupted() {
+        let output = "\
+path '/nix/store/0c1d2e3f-glibc-2.33' was modified! expected hash 'sha256:1a2b', got 'sha256:3c4d'
+path '/nix/store/9f8e7d6c-nomad-1.1.4' is untrusted
+1 paths have incorrect contents
+bitte-verify-status: 1
+";
+
+        assert_eq!(
+            corrupted(output).unwrap(),
+            vec!["/nix/store/0c1d2e3f-glibc-2.33".to_string()]
+        );
+        assert!(corrupted("bitte-verify-status: 0\n").unwrap().is_empty());
+        assert!(corrupted("error: unknown command 'store'\nbitte-verify-status: 1\n").is_err());
+        assert!(corrupted("").is_err());
+    }
+}
@@ -0,0 +1,333 @@
+use std::{
+    env, fs,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use aws_config::meta::region::RegionProviderChain;
+use aws_types::{
+    config::Config,
+    credentials::{future, CredentialsError, ProvideCredentials, SharedCredentialsProvider},
+    Credentials,
+};
+use log::{debug, error, info};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+
+use crate::budget::{self, Provider};
+
+/// Assumed credentials are requested again once they expire within this many seconds.
+const RENEW_MARGIN: i64 = 5 * 60;
+
+/// STS is global, this region only signs the request when neither the environment nor the
+/// profile names one.
+const FALLBACK_REGION: &str = "us-east-1";
+
+/// A role to assume for every AWS call, like one of a production account trusting a central
+/// identity account.
+#[derive(Debug, Clone)]
+pub struct AssumeRole {
+    pub role_arn: String,
+    /// ARN of the MFA device the trust policy of the role demands, the code is asked for.
+    pub mfa_serial: Option<String>,
+    /// Seconds the credentials are valid, at most the maximum session duration of the role.
+    pub duration: i32,
+}
+
+/// Temporary credentials of an assumed role.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionCredentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: String,
+    pub expires: i64,
+}
+
+impl SessionCredentials {
+    fn valid_at(&self, now: i64) -> bool {
+        self.expires > now + RENEW_MARGIN
+    }
+
+    /// Export the credentials, they take precedence over any profile for the AWS SDK as well
+    /// as for the `aws` CLI, terraform, and nix run by bitte.
+    pub fn export(&self) {
+        env::set_var("AWS_ACCESS_KEY_ID", &self.access_key_id);
+        env::set_var("AWS_SECRET_ACCESS_KEY", &self.secret_access_key);
+        env::set_var("AWS_SESSION_TOKEN", &self.session_token);
+    }
+
+    fn sdk(&self) -> Credentials {
+        Credentials::new(
+            &self.access_key_id,
+            &self.secret_access_key,
+            Some(self.session_token.clone()),
+            Some(UNIX_EPOCH + Duration::from_secs(self.expires.max(0) as u64)),
+            "bitte",
+        )
+    }
+}
+
+fn now() -> Result<i64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64)
+}
+
+/// Credentials are cached per profile they were assumed from and role.
+fn cache_name(profile: &str, role_arn: &str) -> String {
+    format!("{}-{}", profile, role_arn).replace(
+        |c: char| !c.is_ascii_alphanumeric() && c != '-' && c != '.',
+        "_",
+    )
+}
+
+fn cache_file(role_arn: &str) -> Result<PathBuf> {
+    let profile = env::var("AWS_PROFILE").unwrap_or_else(|_| "default".to_string());
+    let dir = PathBuf::from(crate::cache_root()?)
+        .join("bitte")
+        .join("aws-sessions");
+    fs::create_dir_all(&dir)?;
+    Ok(dir.join(format!("{}.json", cache_name(&profile, role_arn))))
+}
+
+fn cached(role_arn: &str) -> Option<SessionCredentials> {
+    let file = fs::read_to_string(cache_file(role_arn).ok()?).ok()?;
+    let creds: SessionCredentials = serde_json::from_str(&file).ok()?;
+    match now() {
+        Ok(now) if creds.valid_at(now) => Some(creds),
+        _ => None,
+    }
+}
+
+/// The credentials the roles are assumed with, resolved before the assumed ones are exported
+/// and take their place in the environment.
+async fn base_config() -> Result<Config> {
+    let region = RegionProviderChain::default_provider().or_else(FALLBACK_REGION);
+    let config = aws_config::from_env().region(region).load().await;
+    let provider = config
+        .credentials_provider()
+        .context("there is no AWS credential chain")?;
+    let credentials = provider
+        .provide_credentials()
+        .await
+        .context("there are no AWS credentials to assume the role with")?;
+    Ok(Config::builder()
+        .region(config.region().cloned())
+        .credentials_provider(SharedCredentialsProvider::new(credentials))
+        .build())
+}
+
+/// The name of the sessions of the caller `arn`, like `bitte-alice` for
+/// `arn:aws:iam::123456789012:user/alice`.
+fn session_name(arn: &str) -> String {
+    let caller = arn.rsplit(&['/', ':'][..]).next().unwrap_or_default();
+    let name = format!("bitte-{}", caller).replace(
+        |c: char| !c.is_ascii_alphanumeric() && !"+=,.@-_".contains(c),
+        "_",
+    );
+    name.trim_end_matches('-').chars().take(64).collect()
+}
+
+/// Assume `role` with the credentials of `base`, asking for the MFA code if the role needs one,
+/// and cache the credentials where only the user can read them.
+pub async fn assume(base: &Config, role: &AssumeRole) -> Result<SessionCredentials> {
+    let client = aws_sdk_sts::Client::new(base);
+
+    budget::acquire(Provider::Aws).await?;
+    let caller = client
+        .get_caller_identity()
+        .send()
+        .await
+        .context("couldn't look up the AWS caller identity")?;
+    let session_name = session_name(caller.arn.as_deref().unwrap_or_default());
+
+    let mut request = client
+        .assume_role()
+        .role_arn(&role.role_arn)
+        .role_session_name(session_name)
+        .duration_seconds(role.duration);
+    if let Some(serial) = &role.mfa_serial {
+        let code = crate::prompt_secret(&format!("MFA code for {}: ", serial))?;
+        request = request.serial_number(serial).token_code(code);
+    }
+
+    budget::acquire(Provider::Aws).await?;
+    let output = request
+        .send()
+        .await
+        .with_context(|| format!("couldn't assume the role {}", role.role_arn))?;
+    let assumed = output
+        .credentials
+        .with_context(|| format!("STS returned no credentials for {}", role.role_arn))?;
+
+    let creds = SessionCredentials {
+        access_key_id: assumed.access_key_id.context("no access key id")?,
+        secret_access_key: assumed.secret_access_key.context("no secret access key")?,
+        session_token: assumed.session_token.context("no session token")?,
+        expires: match assumed.expiration {
+            Some(expiration) => expiration.secs(),
+            None => now()? + role.duration as i64,
+        },
+    };
+
+    crate::write_private(
+        &cache_file(&role.role_arn)?,
+        serde_json::to_string_pretty(&creds)?,
+    )?;
+    info!("assumed the role {}", role.role_arn);
+
+    Ok(creds)
+}
+
+/// An assumed role, assumed again before its credentials expire.
+#[derive(Debug)]
+pub struct RoleSession {
+    role: AssumeRole,
+    base: Option<Config>,
+    current: tokio::sync::Mutex<SessionCredentials>,
+}
+
+impl RoleSession {
+    /// The credentials of the role, assumed again once they expire within `RENEW_MARGIN`.
+    /// Roles demanding MFA are used until they expire, bitte doesn't ask for a code in the
+    /// middle of a run.
+    pub async fn credentials(&self) -> Result<SessionCredentials> {
+        let mut current = self.current.lock().await;
+        let now = now()?;
+        if current.valid_at(now) {
+            return Ok(current.clone());
+        }
+        if self.role.mfa_serial.is_some() {
+            if current.expires > now {
+                return Ok(current.clone());
+            }
+            bail!(
+                "the credentials of {} expired, assuming it again needs a new MFA code: run bitte again",
+                self.role.role_arn
+            );
+        }
+        let base = self.base.as_ref().with_context(|| {
+            format!(
+                "there are no AWS credentials to assume {} again with",
+                self.role.role_arn
+            )
+        })?;
+        *current = assume(base, &self.role).await?;
+        // for the commands started from now on
+        current.export();
+        Ok(current.clone())
+    }
+
+    /// Assume the role again ahead of expiry, for the commands bitte runs. Roles demanding MFA
+    /// are only warned about.
+    fn keep_renewed(self: Arc<Self>) {
+        tokio::spawn(async move {
+            loop {
+                let expires = self.current.lock().await.expires;
+                let wait = expires - RENEW_MARGIN - now().unwrap_or(expires);
+                tokio::time::sleep(Duration::from_secs(wait.max(0) as u64 + 1)).await;
+                if self.role.mfa_serial.is_some() {
+                    error!(
+                        "the credentials of {} expire within {} minutes, assuming it again needs a new MFA code: run bitte again",
+                        self.role.role_arn,
+                        RENEW_MARGIN / 60
+                    );
+                    return;
+                }
+                if let Err(e) = self.credentials().await {
+                    error!("{:?}", e);
+                    return;
+                }
+            }
+        });
+    }
+}
+
+impl ProvideCredentials for RoleSession {
+    fn provide_credentials<'a>(&'a self) -> future::ProvideCredentials<'a>
+    where
+        Self: 'a,
+    {
+        future::ProvideCredentials::new(async move {
+            self.credentials()
+                .await
+                .map(|creds| creds.sdk())
+                .map_err(CredentialsError::provider_error)
+        })
+    }
+}
+
+static SESSION: OnceCell<Arc<RoleSession>> = OnceCell::new();
+
+/// The assumed role every AWS client uses, if `configure` was given one.
+pub fn session() -> Option<SharedCredentialsProvider> {
+    let session: Arc<dyn ProvideCredentials> = SESSION.get()?.clone();
+    Some(SharedCredentialsProvider::from(session))
+}
+
+/// Make every AWS call of this invocation, and of the commands it runs, use `profile` and the
+/// credentials of `role` if given. Assumed credentials are reused from the cache while valid,
+/// and assumed again before they expire during long runs.
+pub async fn configure(profile: Option<&str>, role: Option<&AssumeRole>) -> Result<()> {
+    if let Some(profile) = profile {
+        env::set_var("AWS_PROFILE", profile);
+    }
+
+    let role = match role {
+        Some(role) => role,
+        None => return Ok(()),
+    };
+
+    let base = base_config().await;
+    let creds = match cached(&role.role_arn) {
+        Some(creds) => {
+            debug!("using the cached credentials of {}", role.role_arn);
+            creds
+        }
+        None => assume(base.as_ref().map_err(|e| anyhow!("{:?}", e))?, role).await?,
+    };
+    creds.export();
+
+    let session = Arc::new(RoleSession {
+        role: role.clone(),
+        base: base.ok(),
+        current: tokio::sync::Mutex::new(creds),
+    });
+    if SESSION.set(session.clone()).is_ok() {
+        session.keep_renewed();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cache_name, session_name, SessionCredentials};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_session_cache() {
+        assert_eq!(
+            cache_name("identity", "arn:aws:iam::123456789012:role/admin"),
+            "identity-arn_aws_iam__123456789012_role_admin"
+        );
+
+        let creds = SessionCredentials {
+            access_key_id: "ASIA".to_string(),
+            secret_access_key: "secret".to_string(),
+            session_token: "token".to_string(),
+            expires: 10_000,
+        };
+        assert!(creds.valid_at(10_000 - 3600));
+        assert!(!creds.valid_at(10_000 - 60));
+
+        assert_eq!(
+            session_name("arn:aws:iam::123456789012:user/alice"),
+            "bitte-alice"
+        );
+        assert_eq!(
+            session_name("arn:aws:sts::123456789012:assumed-role/sso/alice@example.com"),
+            "bitte-alice@example.com"
+        );
+        assert_eq!(session_name(""), "bitte");
+    }
+}
@@ -1,35 +1,67 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::process::Command;
-use std::{env, path::Path};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::{
-    fs::{read_to_string, remove_dir_all},
-    io::Read,
+    env,
+    path::{Path, PathBuf},
+};
+use std::{
+    fs::{self, read_to_string, remove_dir_all},
+    io::{self, Read},
 };
 
+use crate::config::BitteConfig;
 use crate::error::Error;
-use crate::types::ClusterHandle;
-use anyhow::Result;
+use crate::runs::Run;
+use crate::tfc::RemoteWorkspace;
+use crate::types::{BitteCluster, ClusterHandle};
+use anyhow::{bail, Context, Result};
+use colored::*;
 use flate2::read::ZlibDecoder;
-use log::info;
+use log::{debug, info};
 use netrc_rs::Netrc;
-use restson::RestClient;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
 use shellexpand::tilde;
 
 use crate::{
     self as lib,
-    types::{HttpPutToken, RawVaultState, TerraformState, TerraformStateValue, VaultLogin},
+    endpoint::Service,
+    login,
+    pool::ClientPool,
+    types::{
+        RawVaultState, TerraformChange, TerraformCredentials, TerraformPlan,
+        TerraformResourceChange, TerraformState, TerraformStateValue, TfcVarAttributes,
+    },
 };
 
-pub async fn prepare(workspace: String, cluster: ClusterHandle) -> Result<()> {
-    set_http_auth()?;
+/// How long cached outputs of backends other than `remote` are used without reading the state.
+const OUTPUT_CACHE_TTL: Duration = Duration::from_secs(300);
+
+pub async fn prepare(workspace: String, cluster: ClusterHandle) -> Result<BitteCluster> {
+    let cluster = cluster.await??;
+    prepare_for(&workspace, &cluster.name).await?;
+    Ok(cluster)
+}
+
+/// Generate the config of `workspace` for the cluster called `cluster` and initialize it.
+pub async fn prepare_for(workspace: &str, cluster: &str) -> Result<()> {
+    set_http_auth().await?;
     info!("prepare terraform");
-    generate_terraform_config(&workspace, cluster).await?;
-    init(false)?;
-    Ok(())
+    generate_config(workspace, cluster)?;
+    init(false).await
 }
 
-pub async fn generate_terraform_config(workspace: &str, cluster: ClusterHandle) -> Result<()> {
-    let cluster: String = cluster.await??.name;
+pub async fn generate_terraform_config(
+    workspace: &str,
+    cluster: ClusterHandle,
+) -> Result<BitteCluster> {
+    let cluster = cluster.await??;
+    generate_config(workspace, &cluster.name)?;
+    Ok(cluster)
+}
 
+pub fn generate_config(workspace: &str, cluster: &str) -> Result<()> {
     // To work on Darwin, we need to pass the current system
     let status = Command::new("nix")
         .arg("-L")
@@ -55,52 +87,780 @@ pub async fn generate_terraform_config(workspace: &str, cluster: ClusterHandle)
     }
 }
 
-pub fn init(upgrade: bool) -> Result<()> {
-    set_http_auth()?;
+pub async fn init(upgrade: bool) -> Result<()> {
+    set_http_auth().await?;
     println!("run: terraform init");
 
     remove_dir_all(".terraform").ok();
 
+    let mut cmd = Command::new("terraform");
+    cmd.arg("init");
     if upgrade {
-        Command::new("terraform")
-            .args(&["init", "-upgrade"])
-            .status()
-            .expect("terraform init -upgrade failed")
-    } else {
-        Command::new("terraform")
-            .args(&["init"])
-            .status()
-            .expect("terraform init failed")
-    };
+        cmd.arg("-upgrade");
+    }
+
+    let status = cmd
+        .status()
+        .with_context(|| format!("failed to run: {:?}", cmd))?;
+    if !status.success() {
+        bail!("terraform init failed with {}", status);
+    }
     Ok(())
 }
 
-fn terraform_vault_client() -> Result<RestClient> {
-    let mut client = RestClient::new("https://vault.infra.aws.iohkdev.io")?;
-    let token = vault_token()?;
-    client.set_header("X-Vault-Token", &token)?;
-    client.set_header("X-Vault-Request", "true")?;
-    Ok(client)
+/// Set up `workspace` of `cluster` from scratch: create the remote workspace with its execution
+/// mode and variables if the backend has workspaces, then initialize against the backend.
+pub async fn bootstrap(workspace: &str, cluster: &str) -> Result<()> {
+    let config = BitteConfig::load()?.terraform;
+
+    if let TerraformBackend::Remote {
+        hostname,
+        organization,
+        workspace: name,
+        execution_mode,
+        aliases,
+    } = &config.backend
+    {
+        let name = name
+            .replace("{cluster}", cluster)
+            .replace("{workspace}", workspace);
+        let credentials = TerraformCredentialSource::new(hostname, aliases);
+        let remote =
+            RemoteWorkspace::ensure(&credentials, organization, &name, execution_mode).await?;
+
+        for variable in config.variables.iter() {
+            info!("set {} in {}", variable.key, name);
+            remote
+                .set_var(TfcVarAttributes {
+                    key: variable.key.clone(),
+                    value: Some(variable.resolve()?),
+                    category: variable.category.clone(),
+                    hcl: variable.hcl,
+                    sensitive: variable.sensitive,
+                    description: Some("managed by bitte tf init --bootstrap".to_string()),
+                })
+                .await?;
+        }
+    }
+
+    set_http_auth().await?;
+    remove_dir_all(".terraform").ok();
+
+    let run = Run::start("bootstrap")?;
+    let mut cmd = Command::new("terraform");
+    cmd.args(&["init", "-reconfigure"]);
+    if let Some(backend_config) = config.backend.backend_config(cluster, workspace) {
+        let path = run.write("backend.hcl", backend_config)?;
+        cmd.arg(format!("-backend-config={}", path.display()));
+    }
+
+    println!("run: {:?}", cmd);
+    let status = cmd.status();
+    let result = match status {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(anyhow::anyhow!("terraform init failed with {}", status)),
+        Err(e) => Err(e.into()),
+    };
+    run.finish(result)
+}
+
+/// Where the state of the terraform workspaces is stored, selected in `bitte.json`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum TerraformBackend {
+    /// The http backend served by the Vault state bridge.
+    Vault {
+        #[serde(default = "default_vault_address")]
+        address: String,
+    },
+    /// State files on disk, `{workspace}` in `path` is replaced with the workspace name.
+    Local {
+        #[serde(default = "default_local_path")]
+        path: String,
+    },
+    /// State objects in S3, `{cluster}` and `{workspace}` in `key` are replaced.
+    S3 {
+        bucket: String,
+        #[serde(default = "default_s3_key")]
+        key: String,
+        region: Option<String>,
+        #[serde(rename = "dynamodb-table")]
+        dynamodb_table: Option<String>,
+    },
+    /// Terraform Cloud or a Terraform Enterprise install at `hostname`, `{cluster}` and
+    /// `{workspace}` in `workspace` are replaced.
+    Remote {
+        #[serde(default = "default_remote_hostname")]
+        hostname: String,
+        organization: String,
+        #[serde(default = "default_remote_workspace")]
+        workspace: String,
+        #[serde(default = "default_execution_mode", rename = "execution-mode")]
+        execution_mode: String,
+        /// Other names of the same Terraform Enterprise install, whose credentials are used
+        /// when there are none for `hostname`.
+        #[serde(default)]
+        aliases: Vec<String>,
+    },
+}
+
+impl Default for TerraformBackend {
+    fn default() -> Self {
+        TerraformBackend::Vault {
+            address: default_vault_address(),
+        }
+    }
+}
+
+fn default_vault_address() -> String {
+    "https://vault.infra.aws.iohkdev.io".to_string()
+}
+
+fn default_local_path() -> String {
+    "{workspace}.tfstate".to_string()
+}
+
+fn default_s3_key() -> String {
+    "{cluster}/{workspace}.tfstate".to_string()
+}
+
+fn default_remote_hostname() -> String {
+    "app.terraform.io".to_string()
+}
+
+fn default_remote_workspace() -> String {
+    "{cluster}_{workspace}".to_string()
+}
+
+fn default_execution_mode() -> String {
+    "remote".to_string()
+}
+
+impl TerraformBackend {
+    pub async fn read_state(&self, workspace: &str) -> Result<TerraformState> {
+        Ok(serde_json::from_str(
+            &self.read_raw_state(workspace).await?,
+        )?)
+    }
+
+    /// All outputs of `workspace` as `{name: value}`, without assuming their shape.
+    pub async fn read_outputs(&self, workspace: &str) -> Result<serde_json::Value> {
+        let state: serde_json::Value =
+            serde_json::from_str(&self.read_raw_state(workspace).await?)?;
+        let outputs = state["outputs"]
+            .as_object()
+            .map(|outputs| {
+                outputs
+                    .iter()
+                    .map(|(name, output)| (name.clone(), output["value"].clone()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        Ok(serde_json::Value::Object(outputs))
+    }
+
+    /// The Terraform Cloud workspace behind `workspace` when using the remote backend.
+    pub async fn remote_workspace(&self, workspace: &str) -> Result<Option<RemoteWorkspace>> {
+        match self {
+            TerraformBackend::Remote {
+                hostname,
+                organization,
+                workspace: name,
+                aliases,
+                ..
+            } => {
+                let name = name
+                    .replace("{cluster}", &lib::get_env("BITTE_CLUSTER")?)
+                    .replace("{workspace}", workspace);
+                let credentials = TerraformCredentialSource::new(hostname, aliases);
+                Ok(Some(
+                    RemoteWorkspace::find(&credentials, organization, &name).await?,
+                ))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// The `-backend-config` file for `terraform init` that points `workspace` of `cluster`
+    /// at this backend. The Vault backend is configured by the generated config alone.
+    pub fn backend_config(&self, cluster: &str, workspace: &str) -> Option<String> {
+        let expand = |template: &str| {
+            template
+                .replace("{cluster}", cluster)
+                .replace("{workspace}", workspace)
+        };
+
+        let lines = match self {
+            TerraformBackend::Vault { .. } => return None,
+            TerraformBackend::Local { path } => vec![format!("path = {:?}", expand(path))],
+            TerraformBackend::S3 {
+                bucket,
+                key,
+                region,
+                dynamodb_table,
+            } => {
+                let mut lines = vec![
+                    format!("bucket = {:?}", bucket),
+                    format!("key = {:?}", expand(key)),
+                ];
+                if let Some(region) = region {
+                    lines.push(format!("region = {:?}", region));
+                }
+                if let Some(table) = dynamodb_table {
+                    lines.push(format!("dynamodb_table = {:?}", table));
+                }
+                lines
+            }
+            TerraformBackend::Remote {
+                hostname,
+                organization,
+                workspace: name,
+                ..
+            } => vec![
+                format!("hostname = {:?}", hostname),
+                format!("organization = {:?}", organization),
+                format!("workspaces {{ name = {:?} }}", expand(name)),
+            ],
+        };
+
+        Some(lines.join("\n") + "\n")
+    }
+
+    pub async fn read_raw_state(&self, workspace: &str) -> Result<String> {
+        let raw = match self {
+            TerraformBackend::Vault { address } => {
+                set_http_auth().await?;
+                let state = terraform_vault_state(address, workspace).await?;
+                let decoded = base64::decode(state)?;
+                let mut decoder = ZlibDecoder::new(decoded.as_slice());
+                let mut buf = "".to_string();
+                decoder.read_to_string(&mut buf)?;
+                buf
+            }
+            TerraformBackend::Local { path } => {
+                let path = path.replace("{workspace}", workspace);
+                read_to_string(&path).with_context(|| format!("couldn't read {}", path))?
+            }
+            TerraformBackend::S3 {
+                bucket,
+                key,
+                region,
+                ..
+            } => {
+                let key = key
+                    .replace("{cluster}", &lib::get_env("BITTE_CLUSTER")?)
+                    .replace("{workspace}", workspace);
+                let mut cmd = Command::new("aws");
+                cmd.args(&["s3", "cp", &format!("s3://{}/{}", bucket, key), "-"]);
+                if let Some(region) = region {
+                    cmd.args(&["--region", region]);
+                }
+                lib::sh(cmd)?
+            }
+            TerraformBackend::Remote { .. } => {
+                self.remote_workspace(workspace)
+                    .await?
+                    .context("remote backend without workspace")?
+                    .state()
+                    .await?
+            }
+        };
+
+        Ok(raw)
+    }
+
+    /// The state of `workspace` as it was at `serial`, only the remote backend keeps a history.
+    pub async fn read_raw_state_at(&self, workspace: &str, serial: u64) -> Result<String> {
+        match self.remote_workspace(workspace).await? {
+            Some(remote) => remote.state_at(serial).await,
+            None => bail!("reading older state versions requires the remote backend"),
+        }
+    }
+}
+
+/// Where to look up the API token of a Terraform Cloud/Enterprise install.
+#[derive(Debug, Clone)]
+pub struct TerraformCredentialSource {
+    pub hostname: String,
+    /// Other names the install is known by, tried after `hostname`.
+    pub aliases: Vec<String>,
+}
+
+impl TerraformCredentialSource {
+    pub fn new(hostname: &str, aliases: &[String]) -> Self {
+        Self {
+            hostname: hostname.to_string(),
+            aliases: aliases.to_vec(),
+        }
+    }
+
+    /// The API token, looked up the way terraform does it.
+    ///
+    /// `TERRAFORM_TOKEN` overrides everything, then for `hostname` and each of its aliases:
+    /// `TF_TOKEN_<hostname>`, a `credentials` block in the CLI config (`TF_CLI_CONFIG_FILE` or
+    /// `~/.terraformrc`), the `credentials_helper` configured there, and finally the
+    /// credentials written by `terraform login` to `~/.terraform.d/credentials.tfrc.json`.
+    pub fn token(&self) -> Result<String> {
+        if let Ok(token) = env::var("TERRAFORM_TOKEN") {
+            return Ok(token);
+        }
+
+        let cli_config = TerraformCliConfig::load()?;
+        let stored = stored_credentials()?;
+
+        for host in std::iter::once(&self.hostname).chain(self.aliases.iter()) {
+            if let Ok(token) = env::var(token_env_name(host)) {
+                return Ok(token);
+            }
+
+            if let Some(token) = cli_config.credentials.get(host) {
+                return Ok(token.clone());
+            }
+
+            // like in terraform, a configured helper replaces the credentials file
+            if let Some((helper, args)) = &cli_config.credentials_helper {
+                if let Some(token) = helper_token(helper, args, host)? {
+                    return Ok(token);
+                }
+                continue;
+            }
+
+            if let Some(credential) = stored.credentials.get(host) {
+                return Ok(credential.token.clone());
+            }
+        }
+
+        let mut available: Vec<String> = stored
+            .credentials
+            .keys()
+            .chain(cli_config.credentials.keys())
+            .cloned()
+            .collect();
+        available.sort();
+        available.dedup();
+        Err(Error::NoTerraformToken {
+            hostname: self.hostname.clone(),
+            available,
+        }
+        .into())
+    }
+}
+
+/// `TF_TOKEN_<hostname>` with dots replaced by underscores and hyphens by double underscores.
+fn token_env_name(hostname: &str) -> String {
+    format!("TF_TOKEN_{}", hostname.replace('-', "__").replace('.', "_"))
+}
+
+fn stored_credentials() -> Result<TerraformCredentials> {
+    let path = tilde("~/.terraform.d/credentials.tfrc.json").to_string();
+    match read_to_string(&path) {
+        Ok(file) => serde_json::from_str(&file).with_context(|| format!("couldn't parse {}", path)),
+        Err(_) => Ok(TerraformCredentials::default()),
+    }
+}
+
+/// The credential related settings of the terraform CLI config file.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct TerraformCliConfig {
+    /// Tokens of `credentials "<hostname>" { token = "..." }` blocks.
+    pub credentials: HashMap<String, String>,
+    /// Name and arguments of the `credentials_helper "<name>" { args = [...] }` block.
+    pub credentials_helper: Option<(String, Vec<String>)>,
+}
+
+impl TerraformCliConfig {
+    fn load() -> Result<Self> {
+        let path =
+            env::var("TF_CLI_CONFIG_FILE").unwrap_or_else(|_| tilde("~/.terraformrc").to_string());
+        match read_to_string(&path) {
+            Ok(file) => Ok(Self::parse(&file)),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    /// Read the blocks this needs from the HCL of the config, ignoring everything else.
+    pub fn parse(config: &str) -> Self {
+        let credentials =
+            Regex::new(r#"(?s)credentials\s+"([^"]+)"\s*\{[^}]*?token\s*=\s*"([^"]*)""#)
+                .expect("invalid credentials pattern");
+        let helper = Regex::new(r#"(?s)credentials_helper\s+"([^"]+)"\s*\{([^}]*)\}"#)
+            .expect("invalid credentials_helper pattern");
+        let args = Regex::new(r#"(?s)args\s*=\s*\[([^\]]*)\]"#).expect("invalid args pattern");
+        let string = Regex::new(r#""([^"]*)""#).expect("invalid string pattern");
+
+        Self {
+            credentials: credentials
+                .captures_iter(config)
+                .map(|captures| (captures[1].to_string(), captures[2].to_string()))
+                .collect(),
+            credentials_helper: helper.captures(config).map(|captures| {
+                let helper_args = args
+                    .captures(&captures[2])
+                    .map(|list| {
+                        string
+                            .captures_iter(&list[1])
+                            .map(|arg| arg[1].to_string())
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                (captures[1].to_string(), helper_args)
+            }),
+        }
+    }
 }
 
-fn terraform_vault_state(workspace: &str) -> Result<String> {
-    let mut client = terraform_vault_client()?;
-    let value: RawVaultState = client.get((lib::get_env("BITTE_CLUSTER")?.as_str(), workspace))?;
+/// Ask the credentials helper `terraform-credentials-<name>` for the token of `hostname`.
+fn helper_token(name: &str, args: &[String], hostname: &str) -> Result<Option<String>> {
+    let program = format!("terraform-credentials-{}", name);
+    let plugin = Path::new(&tilde("~/.terraform.d/plugins").to_string()).join(&program);
+    let program = if plugin.is_file() {
+        plugin.display().to_string()
+    } else {
+        program
+    };
+
+    let mut cmd = Command::new(&program);
+    cmd.args(args).arg("get").arg(hostname);
+    let output = lib::sh(cmd).with_context(|| format!("credentials helper {} failed", name))?;
+
+    let credential: serde_json::Value = serde_json::from_str(&output)
+        .with_context(|| format!("couldn't parse the output of {}", program))?;
+    Ok(credential["token"].as_str().map(String::from))
+}
+
+async fn terraform_vault_state(address: &str, workspace: &str) -> Result<String> {
+    let token = vault_token(address).await?;
+    let url = format!(
+        "{}/v1/secret/data/vbk/{}/{}",
+        address,
+        lib::get_env("BITTE_CLUSTER")?,
+        workspace
+    );
+    let value: RawVaultState = ClientPool::shared()
+        .service(Service::Vault, address)?
+        .get(&url)
+        .header("X-Vault-Token", token)
+        .header("X-Vault-Request", "true")
+        .send()
+        .await
+        .with_context(|| format!("failed to query: {}", url))?
+        .error_for_status()?
+        .json()
+        .await?;
     Ok(value.data.data.value)
 }
 
-pub fn output(workspace: &str) -> Result<TerraformStateValue> {
-    set_http_auth()?;
-    let state = terraform_vault_state(workspace)?;
-    let decoded = base64::decode(state)?;
-    let mut decoder = ZlibDecoder::new(decoded.as_slice());
-    let mut buf = "".to_string();
-    decoder.read_to_string(&mut buf)?;
-    let state: TerraformState = serde_json::from_str(&buf)?;
+#[derive(Deserialize)]
+struct CachedOutput {
+    serial: i64,
+    fetched: u64,
+    value: TerraformStateValue,
+}
+
+fn output_cache_file(workspace: &str) -> Result<PathBuf> {
+    let dir = PathBuf::from(lib::cache_root()?)
+        .join("bitte")
+        .join("outputs");
+    fs::create_dir_all(&dir)?;
+    Ok(dir.join(format!(
+        "{}-{}.json",
+        lib::get_env("BITTE_CLUSTER")?,
+        workspace
+    )))
+}
+
+fn now() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+/// The cluster output of `workspace`, cached on disk.
+///
+/// With the remote backend, the cache is used as long as Terraform Cloud reports the same
+/// state serial, which is much faster than downloading the whole state. Other backends have
+/// no cheap way to ask for the serial, so their outputs are reused for `OUTPUT_CACHE_TTL`.
+pub async fn output(workspace: &str) -> Result<TerraformStateValue> {
+    let backend = BitteConfig::load()?.terraform.backend;
+    let remote = backend.remote_workspace(workspace).await?;
+    let path = output_cache_file(workspace)?;
+
+    let cached: Option<CachedOutput> = fs::read_to_string(&path)
+        .ok()
+        .and_then(|file| serde_json::from_str(&file).ok());
+
+    if let Some(cached) = cached {
+        let fresh = match &remote {
+            Some(remote) => remote.state_serial().await? as i64 == cached.serial,
+            None => now()?.saturating_sub(cached.fetched) < OUTPUT_CACHE_TTL.as_secs(),
+        };
+        if fresh {
+            debug!(
+                "using cached outputs of {} at serial {}",
+                workspace, cached.serial
+            );
+            return Ok(cached.value);
+        }
+    }
+
+    let raw = match &remote {
+        Some(remote) => remote.state().await?,
+        None => backend.read_raw_state(workspace).await?,
+    };
+    let state: TerraformState = serde_json::from_str(&raw)?;
+    let value = state.outputs.cluster.value;
+
+    let cached = serde_json::json!({ "serial": state.serial, "fetched": now()?, "value": &value });
+    if let Err(e) = fs::write(&path, cached.to_string()) {
+        debug!("couldn't cache the outputs of {}: {}", workspace, e);
+    }
+
+    Ok(value)
+}
+
+/// Forget the cached outputs of `workspace`, e.g. after applying it.
+pub fn invalidate_output(workspace: &str) -> Result<()> {
+    match fs::remove_file(output_cache_file(workspace)?) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// The cluster output of `workspace` as it was at state version `serial`.
+pub async fn output_at(workspace: &str, serial: u64) -> Result<TerraformStateValue> {
+    let config = BitteConfig::load()?;
+    let raw = config
+        .terraform
+        .backend
+        .read_raw_state_at(workspace, serial)
+        .await?;
+    let state: TerraformState = serde_json::from_str(&raw)?;
     Ok(state.outputs.cluster.value)
 }
 
-fn github_token() -> Result<String> {
+/// Counts of the resources a plan touches.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct PlanSummary {
+    pub add: usize,
+    pub change: usize,
+    pub destroy: usize,
+}
+
+impl PlanSummary {
+    pub fn has_changes(&self) -> bool {
+        self.add + self.change + self.destroy > 0
+    }
+}
+
+/// Read a saved plan through `terraform show -json`.
+pub fn show_plan(plan_file: &str) -> Result<TerraformPlan> {
+    let output = lib::sh(execute::command_args!(
+        "terraform",
+        "show",
+        "-json",
+        plan_file
+    ))?;
+    serde_json::from_str(&output).with_context(|| format!("couldn't parse plan {}", plan_file))
+}
+
+/// Count how many resources `plan` adds, changes, and destroys. Replacements count as both.
+pub fn summarize_plan(plan: &TerraformPlan) -> PlanSummary {
+    let mut summary = PlanSummary::default();
+
+    for change in plan.resource_changes.iter() {
+        let actions: Vec<&str> = change.change.actions.iter().map(|a| a.as_str()).collect();
+        match actions.as_slice() {
+            ["no-op"] | ["read"] => (),
+            ["create"] => summary.add += 1,
+            ["delete"] => summary.destroy += 1,
+            ["update"] => summary.change += 1,
+            _ => {
+                summary.add += 1;
+                summary.destroy += 1;
+            }
+        }
+    }
+
+    summary
+}
+
+/// Print the resource changes of `plan` grouped by resource type, with the attributes that
+/// change for updates and replacements.
+pub fn render_plan(plan: &TerraformPlan) -> PlanSummary {
+    let mut groups: BTreeMap<&str, Vec<&TerraformResourceChange>> = BTreeMap::new();
+
+    for change in plan.resource_changes.iter() {
+        let actions: Vec<&str> = change.change.actions.iter().map(|a| a.as_str()).collect();
+        if let ["no-op"] | ["read"] = actions.as_slice() {
+            continue;
+        }
+        groups
+            .entry(change.resource_type.as_str())
+            .or_default()
+            .push(change);
+    }
+
+    for (resource_type, changes) in groups {
+        println!("{} ({})", resource_type.bold(), changes.len());
+
+        for change in changes {
+            let actions: Vec<&str> = change.change.actions.iter().map(|a| a.as_str()).collect();
+            let symbol = match actions.as_slice() {
+                ["create"] => "+".green(),
+                ["delete"] => "-".red(),
+                ["update"] => "~".yellow(),
+                _ => "-/+".red(),
+            };
+            println!("  {} {}", symbol, change.address);
+
+            if actions.as_slice() == ["update"] || actions.len() > 1 {
+                for (name, before, after) in changed_attributes(&change.change) {
+                    println!("      {}: {} -> {}", name, before.red(), after.green());
+                }
+            }
+        }
+    }
+
+    let summary = summarize_plan(plan);
+    println!(
+        "Plan: {} to add, {} to change, {} to destroy.",
+        summary.add.to_string().green(),
+        summary.change.to_string().yellow(),
+        summary.destroy.to_string().red()
+    );
+
+    summary
+}
+
+/// A resource whose live state no longer matches the state terraform recorded.
+#[derive(Debug, Serialize)]
+pub struct DriftedResource {
+    pub address: String,
+    pub actions: Vec<String>,
+    pub attributes: Vec<String>,
+}
+
+/// Group the drift found by a refresh-only plan by resource type.
+pub fn drift(plan: &TerraformPlan) -> BTreeMap<String, Vec<DriftedResource>> {
+    let mut drift: BTreeMap<String, Vec<DriftedResource>> = BTreeMap::new();
+
+    for change in plan.resource_drift.iter() {
+        drift
+            .entry(change.resource_type.clone())
+            .or_default()
+            .push(DriftedResource {
+                address: change.address.clone(),
+                actions: change.change.actions.clone(),
+                attributes: changed_attributes(&change.change)
+                    .into_iter()
+                    .map(|(name, _, _)| name)
+                    .collect(),
+            });
+    }
+
+    drift
+}
+
+/// Whether the value marked by `marker` of `before_sensitive` or `after_sensitive` is sensitive
+/// anywhere within.
+fn is_sensitive(marker: Option<&serde_json::Value>) -> bool {
+    match marker {
+        Some(serde_json::Value::Bool(sensitive)) => *sensitive,
+        Some(serde_json::Value::Array(markers)) => markers.iter().any(|m| is_sensitive(Some(m))),
+        Some(serde_json::Value::Object(markers)) => markers.values().any(|m| is_sensitive(Some(m))),
+        _ => false,
+    }
+}
+
+/// The marker of the attribute `key` in `before_sensitive` or `after_sensitive`, all of them
+/// are sensitive if the whole value is.
+fn sensitive_attribute(marker: Option<&serde_json::Value>, key: &str) -> bool {
+    match marker {
+        Some(serde_json::Value::Bool(sensitive)) => *sensitive,
+        Some(serde_json::Value::Object(markers)) => is_sensitive(markers.get(key)),
+        _ => false,
+    }
+}
+
+fn changed_attributes(change: &TerraformChange) -> Vec<(String, String, String)> {
+    let empty = serde_json::Map::new();
+    let before = change
+        .before
+        .as_ref()
+        .and_then(|v| v.as_object())
+        .unwrap_or(&empty);
+    let after = change
+        .after
+        .as_ref()
+        .and_then(|v| v.as_object())
+        .unwrap_or(&empty);
+    let unknown = change
+        .after_unknown
+        .as_ref()
+        .and_then(|v| v.as_object())
+        .unwrap_or(&empty);
+
+    let keys: BTreeSet<&String> = before.keys().chain(after.keys()).collect();
+
+    keys.into_iter()
+        .filter_map(|key| {
+            let old = before.get(key).cloned().unwrap_or(serde_json::Value::Null);
+            let new = after.get(key).cloned().unwrap_or(serde_json::Value::Null);
+            let known = unknown.get(key) != Some(&serde_json::Value::Bool(true));
+            if known && new == old {
+                return None;
+            }
+
+            let render = |value: serde_json::Value, marker: &Option<serde_json::Value>| {
+                if sensitive_attribute(marker.as_ref(), key) {
+                    "(sensitive)".to_string()
+                } else {
+                    value.to_string()
+                }
+            };
+            let new = if known {
+                render(new, &change.after_sensitive)
+            } else {
+                "(known after apply)".to_string()
+            };
+            Some((key.to_string(), render(old, &change.before_sensitive), new))
+        })
+        .collect()
+}
+
+/// Find the value at a dot separated path like `asgs.client-eu-central-1.arn`.
+pub fn output_lookup<'a>(
+    output: &'a serde_json::Value,
+    key: &str,
+) -> Option<&'a serde_json::Value> {
+    key.split('.')
+        .try_fold(output, |value, segment| value.get(segment))
+}
+
+/// Flatten the outputs into sorted `(path, value)` pairs suitable for a table.
+pub fn output_flatten(output: &serde_json::Value) -> Vec<(String, String)> {
+    fn walk(prefix: String, value: &serde_json::Value, rows: &mut Vec<(String, String)>) {
+        match value {
+            serde_json::Value::Object(map) => {
+                for (key, value) in map {
+                    let path = if prefix.is_empty() {
+                        key.to_string()
+                    } else {
+                        format!("{}.{}", prefix, key)
+                    };
+                    walk(path, value, rows);
+                }
+            }
+            serde_json::Value::String(string) => rows.push((prefix, string.to_string())),
+            other => rows.push((prefix, other.to_string())),
+        }
+    }
+
+    let mut rows = Vec::new();
+    walk("".to_string(), output, &mut rows);
+    rows.sort();
+    rows
+}
+
+pub(crate) fn github_token() -> Result<String> {
     let exp = &tilde("~/.netrc").to_string();
     let path = Path::new(exp);
     let netrc_file = read_to_string(path).map_err(|_| Error::NetrcMissing)?;
@@ -119,22 +879,145 @@ fn github_token() -> Result<String> {
     Err(Error::NoGithubToken.into())
 }
 
-fn vault_token() -> Result<String> {
-    let gh_token = github_token()?;
-    let mut client = RestClient::new("https://vault.infra.aws.iohkdev.io")?;
-    let data = HttpPutToken { token: gh_token };
-    let result: VaultLogin = client.put_capture((), &data)?;
-    Ok(result.auth.client_token)
+async fn vault_token(address: &str) -> Result<String> {
+    login::github(address, "github-terraform").await
 }
 
-pub fn set_http_auth() -> Result<()> {
+pub async fn set_http_auth() -> Result<()> {
+    let address = match BitteConfig::load()?.terraform.backend {
+        TerraformBackend::Vault { address } => address,
+        TerraformBackend::Remote {
+            hostname, aliases, ..
+        } => {
+            // fail before terraform does, with the hosts that do have credentials
+            let token = TerraformCredentialSource::new(&hostname, &aliases).token()?;
+            // terraform itself only knows the credentials of the canonical hostname
+            env::set_var(token_env_name(&hostname), token);
+            return Ok(());
+        }
+        _ => return Ok(()),
+    };
+
     if env::var("TF_HTTP_PASSWORD").is_ok() {
         info!("reusing existing TF_HTTP_* variables");
     } else {
         info!("set TF_HTTP_* variables");
         env::set_var("TF_HTTP_USERNAME", "TOKEN");
-        env::set_var("TF_HTTP_PASSWORD", vault_token()?);
+        env::set_var("TF_HTTP_PASSWORD", vault_token(&address).await?);
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        changed_attributes, output_flatten, output_lookup, token_env_name, TerraformBackend,
+        TerraformCliConfig,
+    };
+    use crate::types::TerraformChange;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    #[test]
+    fn test_cli_config() {
+        let config = TerraformCliConfig::parse(
+            r#"
+            plugin_cache_dir = "$HOME/.terraform.d/plugin-cache"
+
+            credentials "tfe.example.com" {
+              token = "abc.atlasv1.def"
+            }
+
+            credentials_helper "vault" {
+              args = ["--mount", "secret/terraform"]
+            }
+            "#,
+        );
+
+        assert_eq!(
+            config.credentials.get("tfe.example.com"),
+            Some(&"abc.atlasv1.def".to_string())
+        );
+        assert_eq!(
+            config.credentials_helper,
+            Some((
+                "vault".to_string(),
+                vec!["--mount".to_string(), "secret/terraform".to_string()]
+            ))
+        );
+        assert_eq!(
+            token_env_name("tfe-01.example.com"),
+            "TF_TOKEN_tfe__01_example_com"
+        );
+    }
+
+    #[test]
+    fn test_backend_config() {
+        let backend: TerraformBackend =
+            serde_json::from_value(json!({ "type": "s3", "bucket": "state" })).unwrap();
+        assert_eq!(
+            backend.backend_config("testnet", "core"),
+            Some("bucket = \"state\"\nkey = \"testnet/core.tfstate\"\n".to_string())
+        );
+        assert_eq!(
+            TerraformBackend::default().backend_config("testnet", "core"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_output_lookup_and_flatten() {
+        let output = json!({
+            "s3-cache": "s3://cache?region=eu-central-1",
+            "asgs": { "client": { "count": 3 } },
+        });
+
+        assert_eq!(output_lookup(&output, "asgs.client.count"), Some(&json!(3)));
+        assert_eq!(output_lookup(&output, "asgs.core"), None);
+        assert_eq!(
+            output_flatten(&output),
+            vec![
+                ("asgs.client.count".to_string(), "3".to_string()),
+                (
+                    "s3-cache".to_string(),
+                    "s3://cache?region=eu-central-1".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sensitive_attributes() {
+        let change: TerraformChange = serde_json::from_value(json!({
+            "actions": ["update"],
+            "before": {"name": "core-1", "password": "hunter2", "tags": {"token": "abc"}},
+            "after": {"name": "core-2", "password": "hunter3", "tags": {"token": "def"}},
+            "after_unknown": {},
+            "before_sensitive": {"password": true, "tags": {"token": true}},
+            "after_sensitive": {"password": true, "tags": {"token": true}}
+        }))
+        .unwrap();
+
+        assert_eq!(
+            changed_attributes(&change),
+            vec![
+                (
+                    "name".to_string(),
+                    "\"core-1\"".to_string(),
+                    "\"core-2\"".to_string()
+                ),
+                (
+                    "password".to_string(),
+                    "(sensitive)".to_string(),
+                    "(sensitive)".to_string()
+                ),
+                (
+                    "tags".to_string(),
+                    "(sensitive)".to_string(),
+                    "(sensitive)".to_string()
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,193 @@
+use std::fmt::Write as _;
+
+use unicode_width::UnicodeWidthChar;
+
+/// Output of a command decoded for display.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Decoded {
+    pub text: String,
+    /// How many bytes weren't valid UTF-8 and are shown as `\xNN` escapes instead.
+    pub invalid: usize,
+}
+
+impl Decoded {
+    pub fn is_lossy(&self) -> bool {
+        self.invalid > 0
+    }
+}
+
+/// Decode `bytes` as UTF-8, showing invalid sequences as `\xNN` rather than silently replacing
+/// them, so binary output or output in a legacy locale is recognizable as such.
+pub fn decode(mut bytes: &[u8]) -> Decoded {
+    let mut text = String::with_capacity(bytes.len());
+    let mut invalid = 0;
+
+    loop {
+        match std::str::from_utf8(bytes) {
+            Ok(valid) => {
+                text.push_str(valid);
+                break;
+            }
+            Err(e) => {
+                let (valid, rest) = bytes.split_at(e.valid_up_to());
+                text.push_str(std::str::from_utf8(valid).expect("prefix is valid UTF-8"));
+
+                // a truncated sequence at the end has no error length
+                let len = e.error_len().unwrap_or(rest.len());
+                for byte in &rest[..len] {
+                    write!(text, "\\x{:02x}", byte).expect("writing to a String can't fail");
+                }
+                invalid += len;
+                bytes = &rest[len..];
+            }
+        }
+    }
+
+    Decoded { text, invalid }
+}
+
+/// Make `text` safe to put into a table cell: escape sequences and control characters other
+/// than newlines are dropped, tabs become spaces, and every line is cut to `max_width` columns,
+/// counting wide characters like CJK as two.
+pub fn cell(text: &str, max_width: usize) -> String {
+    let mut lines = Vec::new();
+
+    for line in strip_escapes(text).lines() {
+        let line: String = line
+            .chars()
+            .map(|c| if c == '\t' { ' ' } else { c })
+            .filter(|c| !c.is_control())
+            .collect();
+
+        let width = |c: char| c.width().unwrap_or(0);
+        if line.chars().map(width).sum::<usize>() <= max_width {
+            lines.push(line);
+            continue;
+        }
+
+        // leave a column for the ellipsis
+        let mut cut = String::new();
+        let mut used = 0;
+        for c in line.chars() {
+            if used + width(c) >= max_width {
+                break;
+            }
+            used += width(c);
+            cut.push(c);
+        }
+        cut.push('…');
+        lines.push(cut);
+    }
+
+    lines.join("\n")
+}
+
+/// A line of a diff between two texts.
+#[derive(Debug, PartialEq, Eq)]
+pub enum DiffLine<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+/// The lines that turn `old` into `new`, from their longest common subsequence. Meant for small
+/// texts like config files, it takes time and memory quadratic in their lines.
+pub fn line_diff<'a>(old: &'a str, new: &'a str) -> Vec<DiffLine<'a>> {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+
+    // common[i][j] is the length of the common subsequence of old[i..] and new[j..]
+    let mut common = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            lines.push(DiffLine::Same(old[i]));
+            i += 1;
+            j += 1;
+        } else if j == new.len() || (i < old.len() && common[i + 1][j] >= common[i][j + 1]) {
+            lines.push(DiffLine::Removed(old[i]));
+            i += 1;
+        } else {
+            lines.push(DiffLine::Added(new[j]));
+            j += 1;
+        }
+    }
+    lines
+}
+
+/// Remove ANSI CSI sequences like colors, which would be counted as visible characters.
+fn strip_escapes(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c == '\u{1b}' && chars.peek() == Some(&'[') {
+            chars.next();
+            for c in chars.by_ref() {
+                if ('@'..='~').contains(&c) {
+                    break;
+                }
+            }
+        } else {
+            result.push(c);
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cell, decode, line_diff, Decoded, DiffLine};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(
+            decode("grüße".as_bytes()),
+            Decoded {
+                text: "grüße".to_string(),
+                invalid: 0
+            }
+        );
+        assert_eq!(
+            decode(b"gr\xfc\xdfe\n\xe2\x82"),
+            Decoded {
+                text: "gr\\xfc\\xdfe\n\\xe2\\x82".to_string(),
+                invalid: 4
+            }
+        );
+    }
+
+    #[test]
+    fn test_cell() {
+        assert_eq!(cell("\u{1b}[31mfailed\u{1b}[0m\tnow", 20), "failed now");
+        assert_eq!(cell("日本語のログ", 7), "日本語…");
+        assert_eq!(cell("short\nlines", 5), "short\nlines");
+    }
+
+    #[test]
+    fn test_line_diff() {
+        assert_eq!(
+            line_diff("a\nb\nc\n", "a\nc\nd\n"),
+            vec![
+                DiffLine::Same("a"),
+                DiffLine::Removed("b"),
+                DiffLine::Same("c"),
+                DiffLine::Added("d"),
+            ]
+        );
+        assert_eq!(line_diff("", "a"), vec![DiffLine::Added("a")]);
+    }
+}
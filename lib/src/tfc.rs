@@ -0,0 +1,833 @@
+use std::{collections::HashMap, fs, path::Path, process::Command, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use log::{debug, info, warn};
+use reqwest::{
+    header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_TYPE},
+    Client, RequestBuilder, Response, StatusCode,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::json;
+use tokio::{signal, time};
+
+use crate::{
+    self as lib,
+    budget::{self, Provider},
+    error::Error,
+    runs::Run,
+    terraform::{PlanSummary, TerraformCredentialSource},
+    types::{
+        TfcApply, TfcConfigurationVersion, TfcConfigurationVersionCreate,
+        TfcConfigurationVersionCreateAttributes, TfcConfigurationVersionCreateData,
+        TfcCostEstimate, TfcCostEstimateAttributes, TfcCostEstimateOutput, TfcLockAction,
+        TfcPhaseAttributes, TfcPlan, TfcResourceCost, TfcRun, TfcRunAction, TfcRunCreate,
+        TfcRunCreateAttributes, TfcRunCreateData, TfcStateVersion, TfcStateVersions, TfcUser,
+        TfcVar, TfcVarAttributes, TfcVarData, TfcVars, TfcWorkspace, TfcWorkspaceUpdate,
+        TfcWorkspaceUpdateAttributes, TfcWorkspaceUpdateData,
+    },
+};
+
+/// Statuses after which a plan or apply won't produce any more log output.
+const FINAL_STATUSES: &[&str] = &["finished", "errored", "canceled", "unreachable"];
+
+/// How often runs and their logs are polled.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How long a single API request may take before it fails instead of hanging the command.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Uploads of the configuration and downloads of the state can be larger.
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// How often a request is attempted when it is rate limited or the API is unavailable.
+const MAX_ATTEMPTS: u32 = 6;
+
+/// The first delay between attempts, doubled for every following one.
+const BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// Never wait longer than this between two attempts.
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// Async client for the API of a Terraform Cloud/Enterprise install.
+///
+/// Failed requests are returned as `Error::TfcRequest` with the status and the messages of
+/// the response, so callers can tell e.g. missing workspaces apart from other failures.
+/// Rate limited requests are retried with exponential backoff, reads and other idempotent
+/// requests also after server errors and timeouts, since a post may have created a run already.
+#[derive(Clone)]
+pub struct TfcClient {
+    api: Client,
+    /// Without the token, for the pre-signed upload and download urls.
+    transfer: Client,
+    base: String,
+}
+
+impl TfcClient {
+    pub fn new(credentials: &TerraformCredentialSource) -> Result<Self> {
+        let mut token = HeaderValue::from_str(&format!("Bearer {}", credentials.token()?))?;
+        token.set_sensitive(true);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, token);
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/vnd.api+json"),
+        );
+
+        Ok(Self {
+            api: Client::builder()
+                .default_headers(headers)
+                .timeout(REQUEST_TIMEOUT)
+                .build()?,
+            transfer: Client::builder().timeout(TRANSFER_TIMEOUT).build()?,
+            base: format!("https://{}/api/v2", credentials.hostname),
+        })
+    }
+
+    async fn send(
+        request: RequestBuilder,
+        path: &str,
+        idempotent: bool,
+    ) -> Result<Response, Error> {
+        let mut attempt = 0;
+
+        loop {
+            attempt += 1;
+            budget::acquire(Provider::Tfc).await?;
+            let retry = request
+                .try_clone()
+                .expect("requests to terraform cloud have no streaming bodies");
+
+            let (error, retry_after) = match retry.send().await {
+                Ok(response) if response.status().is_success() => return Ok(response),
+                Ok(response) => {
+                    let status = response.status();
+                    let retry_after = retry_after(&response);
+                    let body = response.text().await.unwrap_or_default();
+                    let error = Error::TfcRequest {
+                        status: status.as_u16(),
+                        path: path.to_string(),
+                        details: api_errors(&body),
+                    };
+
+                    if !is_retryable(status, idempotent) {
+                        return Err(error);
+                    }
+                    (error, retry_after)
+                }
+                Err(e) if e.is_connect() || (idempotent && e.is_timeout()) => (e.into(), None),
+                Err(e) => return Err(e.into()),
+            };
+
+            if attempt >= MAX_ATTEMPTS {
+                return Err(Error::TfcUnavailable {
+                    path: path.to_string(),
+                    attempts: attempt,
+                    last: error.to_string(),
+                });
+            }
+
+            let delay = backoff(attempt, retry_after);
+            warn!(
+                "{}, attempt {} of {}, retrying in {}s",
+                error,
+                attempt,
+                MAX_ATTEMPTS,
+                delay.as_secs()
+            );
+            time::sleep(delay).await;
+        }
+    }
+
+    pub async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T, Error> {
+        let request = self.api.get(format!("{}{}", self.base, path));
+        Ok(Self::send(request, path, true).await?.json().await?)
+    }
+
+    pub async fn post<B: Serialize, T: DeserializeOwned>(
+        &self,
+        path: &str,
+        body: &B,
+    ) -> Result<T, Error> {
+        let request = self.api.post(format!("{}{}", self.base, path)).json(body);
+        Ok(Self::send(request, path, false).await?.json().await?)
+    }
+
+    /// Post to endpoints that answer without a body, like the actions of runs and workspaces.
+    pub async fn post_action<B: Serialize>(&self, path: &str, body: &B) -> Result<(), Error> {
+        let request = self.api.post(format!("{}{}", self.base, path)).json(body);
+        Self::send(request, path, false).await?;
+        Ok(())
+    }
+
+    pub async fn patch<B: Serialize>(&self, path: &str, body: &B) -> Result<(), Error> {
+        let request = self.api.patch(format!("{}{}", self.base, path)).json(body);
+        Self::send(request, path, false).await?;
+        Ok(())
+    }
+
+    pub async fn delete(&self, path: &str) -> Result<(), Error> {
+        let request = self.api.delete(format!("{}{}", self.base, path));
+        Self::send(request, path, true).await?;
+        Ok(())
+    }
+
+    /// Fetch a pre-signed url, e.g. a state download or a log.
+    pub async fn download(&self, url: &str) -> Result<String, Error> {
+        Ok(Self::send(self.transfer.get(url), "(download)", true)
+            .await?
+            .text()
+            .await?)
+    }
+
+    pub async fn upload(&self, url: &str, body: Vec<u8>) -> Result<(), Error> {
+        let request = self
+            .transfer
+            .put(url)
+            .header(CONTENT_TYPE, "application/octet-stream")
+            .body(body);
+        Self::send(request, "(upload)", true).await?;
+        Ok(())
+    }
+}
+
+/// Rate limits and server errors are usually over after a while, other errors won't be.
+/// A rate limited request was never processed, but after a server error it may have been.
+fn is_retryable(status: StatusCode, idempotent: bool) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || (idempotent && status.is_server_error())
+}
+
+/// How long the API asks to wait, from `Retry-After` or the rate limit reset of TFC.
+fn retry_after(response: &Response) -> Option<Duration> {
+    ["retry-after", "x-ratelimit-reset"]
+        .iter()
+        .filter_map(|name| response.headers().get(*name)?.to_str().ok())
+        .find_map(|value| value.trim().parse::<f64>().ok())
+        .filter(|seconds| seconds.is_finite() && *seconds >= 0.0)
+        .map(Duration::from_secs_f64)
+}
+
+/// The delay before the attempt following `attempt`, preferring what the API asked for.
+fn backoff(attempt: u32, retry_after: Option<Duration>) -> Duration {
+    let exponential = BASE_DELAY * 2u32.saturating_pow(attempt.saturating_sub(1).min(16));
+    retry_after.unwrap_or(exponential).min(MAX_DELAY)
+}
+
+/// The messages of a JSON:API error response, or the raw body if it isn't one.
+fn api_errors(body: &str) -> String {
+    let errors: Vec<String> = serde_json::from_str::<serde_json::Value>(body)
+        .ok()
+        .and_then(|value| value["errors"].as_array().cloned())
+        .unwrap_or_default()
+        .iter()
+        .map(|error| {
+            let title = error["title"].as_str().unwrap_or_default();
+            match error["detail"].as_str() {
+                Some(detail) if !detail.is_empty() => format!("{}: {}", title, detail),
+                _ => title.to_string(),
+            }
+        })
+        .collect();
+
+    if errors.is_empty() {
+        body.trim().to_string()
+    } else {
+        errors.join("; ")
+    }
+}
+
+/// A workspace on Terraform Cloud/Enterprise that runs plans and applies remotely.
+pub struct RemoteWorkspace {
+    client: TfcClient,
+    pub id: String,
+    pub name: String,
+    pub organization: String,
+}
+
+/// One entry of the state history of a workspace.
+#[derive(Debug, Serialize)]
+pub struct StateVersion {
+    pub id: String,
+    pub serial: u64,
+    pub created_at: String,
+    /// The user who wrote the state, or the run that did when no user is known.
+    pub author: Option<String>,
+}
+
+/// Which phase of a run to follow.
+#[derive(Debug, Clone, Copy)]
+pub enum Phase {
+    Plan,
+    Apply,
+}
+
+impl PlanSummary {
+    fn from_phase(attributes: &TfcPhaseAttributes) -> Self {
+        Self {
+            add: attributes.resource_additions.unwrap_or(0),
+            change: attributes.resource_changes.unwrap_or(0),
+            destroy: attributes.resource_destructions.unwrap_or(0),
+        }
+    }
+}
+
+impl RemoteWorkspace {
+    pub async fn find(
+        credentials: &TerraformCredentialSource,
+        organization: &str,
+        name: &str,
+    ) -> Result<Self> {
+        let client = TfcClient::new(credentials)?;
+        let workspace: TfcWorkspace = client
+            .get(&format!(
+                "/organizations/{}/workspaces/{}",
+                organization, name
+            ))
+            .await
+            .with_context(|| format!("couldn't find workspace {}/{}", organization, name))?;
+
+        Ok(Self {
+            client,
+            id: workspace.data.id,
+            name: workspace.data.attributes.name,
+            organization: organization.to_string(),
+        })
+    }
+
+    /// Find the workspace `name`, creating it if it doesn't exist, and make sure it uses
+    /// `execution_mode` (`remote`, `local`, or `agent`).
+    pub async fn ensure(
+        credentials: &TerraformCredentialSource,
+        organization: &str,
+        name: &str,
+        execution_mode: &str,
+    ) -> Result<Self> {
+        let client = TfcClient::new(credentials)?;
+        let update = TfcWorkspaceUpdate {
+            data: TfcWorkspaceUpdateData {
+                kind: "workspaces".to_string(),
+                attributes: TfcWorkspaceUpdateAttributes {
+                    name: name.to_string(),
+                    execution_mode: execution_mode.to_string(),
+                },
+            },
+        };
+
+        let path = format!("/organizations/{}/workspaces", organization);
+        let workspace: TfcWorkspace = match client.get(&format!("{}/{}", path, name)).await {
+            Ok(workspace) => workspace,
+            Err(Error::TfcRequest { status: 404, .. }) => {
+                info!("create workspace {}/{}", organization, name);
+                client
+                    .post(&path, &update)
+                    .await
+                    .with_context(|| format!("couldn't create workspace {}", name))?
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let remote = Self {
+            client,
+            id: workspace.data.id,
+            name: workspace.data.attributes.name,
+            organization: organization.to_string(),
+        };
+
+        if workspace.data.attributes.execution_mode.as_deref() != Some(execution_mode) {
+            info!("set execution mode of {} to {}", name, execution_mode);
+            remote
+                .client
+                .patch(&format!("/workspaces/{}", remote.id), &update)
+                .await?;
+        }
+
+        Ok(remote)
+    }
+
+    /// Who holds the lock of the workspace, if it is locked.
+    pub async fn lock_holder(&self) -> Result<Option<String>> {
+        let workspace: TfcWorkspace = self.client.get(&format!("/workspaces/{}", self.id)).await?;
+        if !workspace.data.attributes.locked {
+            return Ok(None);
+        }
+
+        let holder = match workspace.data.relationships.locked_by.and_then(|r| r.data) {
+            Some(data) if data.kind == "users" => {
+                let user = self
+                    .client
+                    .get::<TfcUser>(&format!("/users/{}", data.id))
+                    .await;
+                match user {
+                    Ok(user) => format!("user {}", user.data.attributes.username),
+                    Err(_) => format!("user {}", data.id),
+                }
+            }
+            Some(data) if data.kind == "runs" => format!("run {}", data.id),
+            Some(data) => format!("{} {}", data.kind.trim_end_matches('s'), data.id),
+            None => "an unknown holder".to_string(),
+        };
+        Ok(Some(holder))
+    }
+
+    /// Fail with the holder of the lock instead of letting the API reject the next request.
+    pub async fn ensure_unlocked(&self) -> Result<()> {
+        match self.lock_holder().await? {
+            Some(holder) => Err(Error::WorkspaceLocked {
+                workspace: self.name.clone(),
+                holder,
+            }
+            .into()),
+            None => Ok(()),
+        }
+    }
+
+    pub async fn lock(&self, reason: Option<String>) -> Result<()> {
+        self.ensure_unlocked().await?;
+        self.client
+            .post_action(
+                &format!("/workspaces/{}/actions/lock", self.id),
+                &TfcLockAction { reason },
+            )
+            .await
+            .with_context(|| format!("couldn't lock {}", self.name))?;
+        Ok(())
+    }
+
+    /// Release the lock, `force` releases locks held by other users too.
+    pub async fn unlock(&self, force: bool) -> Result<()> {
+        let action = if force { "force-unlock" } else { "unlock" };
+        let holder = self.lock_holder().await?;
+        if holder.is_none() {
+            return Ok(());
+        }
+
+        self.client
+            .post_action(
+                &format!("/workspaces/{}/actions/{}", self.id, action),
+                &TfcLockAction { reason: None },
+            )
+            .await
+            .with_context(|| {
+                format!(
+                    "couldn't unlock {}, it is locked by {}",
+                    self.name,
+                    holder.unwrap_or_default()
+                )
+            })?;
+        Ok(())
+    }
+
+    /// Upload the generated `config.tf.json`, and the provider lock file if there is one, as
+    /// a new configuration version.
+    pub async fn upload_configuration(&self, run: &Run, speculative: bool) -> Result<String> {
+        let request = TfcConfigurationVersionCreate {
+            data: TfcConfigurationVersionCreateData {
+                kind: "configuration-versions".to_string(),
+                attributes: TfcConfigurationVersionCreateAttributes {
+                    auto_queue_runs: false,
+                    speculative,
+                },
+            },
+        };
+        let created: TfcConfigurationVersion = self
+            .client
+            .post(
+                &format!("/workspaces/{}/configuration-versions", self.id),
+                &request,
+            )
+            .await?;
+        let upload_url = created
+            .data
+            .attributes
+            .upload_url
+            .context("configuration version has no upload url")?;
+
+        let mut files = vec!["config.tf.json"];
+        if Path::new(".terraform.lock.hcl").is_file() {
+            files.push(".terraform.lock.hcl");
+        }
+
+        let archive = run.path("configuration.tar.gz");
+        lib::sh({
+            let mut cmd = Command::new("tar");
+            cmd.arg("-czf").arg(&archive).args(&files);
+            cmd
+        })?;
+        self.client
+            .upload(&upload_url, fs::read(&archive)?)
+            .await
+            .context("couldn't upload the configuration")?;
+
+        loop {
+            let version: TfcConfigurationVersion = self
+                .client
+                .get(&format!("/configuration-versions/{}", created.data.id))
+                .await?;
+            match version.data.attributes.status.as_str() {
+                "uploaded" => return Ok(version.data.id),
+                "errored" => bail!("upload of configuration {} failed", version.data.id),
+                status => debug!("configuration {} is {}", version.data.id, status),
+            }
+            time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    pub async fn create_run(
+        &self,
+        configuration: &str,
+        destroy: bool,
+        plan_only: bool,
+        targets: &[String],
+    ) -> Result<TfcRun> {
+        let request = TfcRunCreate {
+            data: TfcRunCreateData {
+                kind: "runs".to_string(),
+                attributes: TfcRunCreateAttributes {
+                    message: format!("bitte tf {}", self.name),
+                    is_destroy: destroy,
+                    plan_only,
+                    auto_apply: false,
+                    target_addrs: targets.to_vec(),
+                },
+                relationships: json!({
+                    "workspace": { "data": { "type": "workspaces", "id": self.id } },
+                    "configuration-version": {
+                        "data": { "type": "configuration-versions", "id": configuration }
+                    },
+                }),
+            },
+        };
+
+        let run: TfcRun = self.client.post("/runs", &request).await?;
+        info!("remote run {} on {}", run.data.id, self.name);
+        Ok(run)
+    }
+
+    async fn current_state_version(&self) -> Result<TfcStateVersion> {
+        Ok(self
+            .client
+            .get(&format!("/workspaces/{}/current-state-version", self.id))
+            .await?)
+    }
+
+    /// The serial of the current state, without downloading the state itself.
+    pub async fn state_serial(&self) -> Result<u64> {
+        Ok(self.current_state_version().await?.data.attributes.serial)
+    }
+
+    /// Download the current state of the workspace.
+    pub async fn state(&self) -> Result<String> {
+        let version = self.current_state_version().await?;
+        Ok(self
+            .client
+            .download(&version.data.attributes.hosted_state_download_url)
+            .await?)
+    }
+
+    /// All state versions of the workspace, newest first.
+    pub async fn state_versions(&self) -> Result<Vec<StateVersion>> {
+        let mut versions = Vec::new();
+        let mut page = 1;
+
+        loop {
+            let response: TfcStateVersions = self
+                .client
+                .get(&format!(
+                    "/state-versions?filter[workspace][name]={}&filter[organization][name]={}\
+                     &include=created_by&page[size]=100&page[number]={}",
+                    self.name, self.organization, page
+                ))
+                .await?;
+
+            let users: HashMap<&str, &str> = response
+                .included
+                .iter()
+                .filter(|included| included.kind == "users")
+                .filter_map(|user| Some((user.id.as_str(), user.attributes["username"].as_str()?)))
+                .collect();
+
+            for version in response.data.iter() {
+                let author = version
+                    .relationships
+                    .created_by
+                    .as_ref()
+                    .and_then(|created_by| created_by.data.as_ref())
+                    .map(|data| match users.get(data.id.as_str()) {
+                        Some(username) => username.to_string(),
+                        None => format!("{} {}", data.kind.trim_end_matches('s'), data.id),
+                    });
+
+                versions.push(StateVersion {
+                    id: version.id.clone(),
+                    serial: version.attributes.serial,
+                    created_at: version.attributes.created_at.clone(),
+                    author,
+                });
+            }
+
+            match response.meta.pagination.and_then(|p| p.next_page) {
+                Some(next) => page = next,
+                None => break,
+            }
+        }
+
+        versions.sort_by_key(|version| std::cmp::Reverse(version.serial));
+        Ok(versions)
+    }
+
+    /// Download the state as it was at `serial`.
+    pub async fn state_at(&self, serial: u64) -> Result<String> {
+        let id = self
+            .state_versions()
+            .await?
+            .into_iter()
+            .find(|version| version.serial == serial)
+            .with_context(|| format!("{} has no state with serial {}", self.name, serial))?
+            .id;
+
+        let version: TfcStateVersion = self.client.get(&format!("/state-versions/{}", id)).await?;
+        Ok(self
+            .client
+            .download(&version.data.attributes.hosted_state_download_url)
+            .await?)
+    }
+
+    pub async fn run(&self, id: &str) -> Result<TfcRun> {
+        Ok(self.client.get(&format!("/runs/{}", id)).await?)
+    }
+
+    pub async fn apply(&self, id: &str) -> Result<()> {
+        self.action(id, "apply").await
+    }
+
+    async fn action(&self, id: &str, action: &str) -> Result<()> {
+        let comment = Some(format!("{} from bitte", action));
+        self.client
+            .post_action(
+                &format!("/runs/{}/actions/{}", id, action),
+                &TfcRunAction { comment },
+            )
+            .await
+            .with_context(|| format!("couldn't {} run {}", action, id))?;
+        Ok(())
+    }
+
+    /// Stop a run that was interrupted locally, discarding it if it is waiting for
+    /// confirmation and canceling it if it is still planning or applying.
+    pub async fn discard(&self, id: &str) -> Result<()> {
+        let run = self.run(id).await?;
+        let actions = &run.data.attributes.actions;
+        if actions.is_discardable {
+            self.action(id, "discard").await
+        } else if actions.is_cancelable {
+            self.action(id, "cancel").await
+        } else {
+            warn!(
+                "run {} is {} and can't be stopped",
+                id, run.data.attributes.status
+            );
+            Ok(())
+        }
+    }
+
+    /// Wait for the cost estimate of a run and return it with the per-resource costs, or
+    /// `None` if cost estimation isn't enabled or was skipped.
+    pub async fn cost_estimate(
+        &self,
+        run_id: &str,
+    ) -> Result<Option<(TfcCostEstimateAttributes, Vec<TfcResourceCost>)>> {
+        let run = self.run(run_id).await?;
+        let id = match run.data.relationships.cost_estimate.and_then(|r| r.data) {
+            Some(data) => data.id,
+            None => return Ok(None),
+        };
+
+        loop {
+            let estimate: TfcCostEstimate =
+                self.client.get(&format!("/cost-estimates/{}", id)).await?;
+            match estimate.data.attributes.status.as_str() {
+                "finished" => {
+                    let output: TfcCostEstimateOutput = self
+                        .client
+                        .get(&format!("/cost-estimates/{}/output", id))
+                        .await?;
+                    return Ok(Some((estimate.data.attributes, output.resources.matched)));
+                }
+                "pending" | "queued" => time::sleep(POLL_INTERVAL).await,
+                status => {
+                    debug!("cost estimate {} is {}", id, status);
+                    return Ok(None);
+                }
+            }
+        }
+    }
+
+    pub async fn vars(&self) -> Result<Vec<TfcVarData>> {
+        let vars: TfcVars = self
+            .client
+            .get(&format!("/workspaces/{}/vars", self.id))
+            .await?;
+        Ok(vars.data)
+    }
+
+    /// Create the variable `attributes.key`, or replace it if it exists in the same category.
+    pub async fn set_var(&self, attributes: TfcVarAttributes) -> Result<()> {
+        let existing = self.vars().await?.into_iter().find(|var| {
+            var.attributes.key == attributes.key && var.attributes.category == attributes.category
+        });
+
+        let mut var = TfcVar {
+            data: TfcVarData {
+                id: None,
+                kind: "vars".to_string(),
+                attributes,
+            },
+        };
+
+        let path = format!("/workspaces/{}/vars", self.id);
+        match existing.and_then(|existing| existing.id) {
+            Some(id) => {
+                var.data.id = Some(id.clone());
+                self.client.patch(&format!("{}/{}", path, id), &var).await?
+            }
+            None => self.client.post_action(&path, &var).await?,
+        }
+
+        Ok(())
+    }
+
+    /// Remove the variable `key`, returning how many variables were removed across categories.
+    pub async fn remove_var(&self, key: &str, category: Option<&str>) -> Result<usize> {
+        let matching: Vec<String> = self
+            .vars()
+            .await?
+            .into_iter()
+            .filter(|var| {
+                var.attributes.key == key
+                    && category.map_or(true, |category| var.attributes.category == category)
+            })
+            .filter_map(|var| var.id)
+            .collect();
+
+        for id in matching.iter() {
+            self.client
+                .delete(&format!("/workspaces/{}/vars/{}", self.id, id))
+                .await?;
+        }
+
+        Ok(matching.len())
+    }
+
+    async fn phase(&self, phase: Phase, id: &str) -> Result<TfcPhaseAttributes> {
+        let attributes = match phase {
+            Phase::Plan => {
+                self.client
+                    .get::<TfcPlan>(&format!("/plans/{}", id))
+                    .await?
+                    .data
+                    .attributes
+            }
+            Phase::Apply => {
+                self.client
+                    .get::<TfcApply>(&format!("/applies/{}", id))
+                    .await?
+                    .data
+                    .attributes
+            }
+        };
+        Ok(attributes)
+    }
+
+    /// Print the log of `phase` of the run as it is written until the phase is done, and
+    /// return the resource counts it reported. Ctrl-C discards the run.
+    pub async fn stream(&self, run_id: &str, phase: Phase) -> Result<PlanSummary> {
+        let run = self.run(run_id).await?;
+        let relationship = match phase {
+            Phase::Plan => &run.data.relationships.plan,
+            Phase::Apply => &run.data.relationships.apply,
+        };
+        let phase_id = relationship
+            .data
+            .as_ref()
+            .with_context(|| format!("run {} has no {:?} yet", run_id, phase))?
+            .id
+            .clone();
+
+        let mut printed = 0;
+
+        loop {
+            let attributes = self.phase(phase, &phase_id).await?;
+
+            if let Some(url) = &attributes.log_read_url {
+                // a log that can't be read right now is simply read again on the next poll
+                match self.client.download(url).await {
+                    Ok(log) => {
+                        // the log is wrapped in STX/ETX markers that shouldn't reach the terminal
+                        let log = log.replace(&['\u{2}', '\u{3}'][..], "");
+                        if let Some(new) = log.get(printed..) {
+                            print!("{}", new);
+                            printed = log.len();
+                        }
+                    }
+                    Err(e) => debug!("couldn't read the log of {}: {}", phase_id, e),
+                }
+            }
+
+            if FINAL_STATUSES.contains(&attributes.status.as_str()) {
+                if attributes.status != "finished" {
+                    bail!("remote {:?} of run {} {}", phase, run_id, attributes.status);
+                }
+                return Ok(PlanSummary::from_phase(&attributes));
+            }
+
+            tokio::select! {
+                _ = signal::ctrl_c() => {
+                    self.discard(run_id).await?;
+                    bail!("interrupted, run {} was discarded", run_id);
+                }
+                _ = time::sleep(POLL_INTERVAL) => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{api_errors, backoff, is_retryable};
+    use pretty_assertions::assert_eq;
+    use reqwest::StatusCode;
+    use std::time::Duration;
+
+    #[test]
+    fn test_api_errors() {
+        let body = r#"{"errors":[{"status":"422","title":"invalid attribute","detail":"Name has already been taken"},{"status":"404","title":"not found"}]}"#;
+        assert_eq!(
+            api_errors(body),
+            "invalid attribute: Name has already been taken; not found"
+        );
+        assert_eq!(api_errors("Bad Gateway\n"), "Bad Gateway");
+    }
+
+    #[test]
+    fn test_is_retryable() {
+        assert!(is_retryable(StatusCode::TOO_MANY_REQUESTS, false));
+        assert!(is_retryable(StatusCode::BAD_GATEWAY, true));
+        assert!(!is_retryable(StatusCode::BAD_GATEWAY, false));
+        assert!(!is_retryable(StatusCode::NOT_FOUND, true));
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(1, None), Duration::from_secs(1));
+        assert_eq!(backoff(3, None), Duration::from_secs(4));
+        assert_eq!(backoff(10, None), Duration::from_secs(60));
+        assert_eq!(
+            backoff(1, Some(Duration::from_millis(2500))),
+            Duration::from_millis(2500)
+        );
+        assert_eq!(
+            backoff(1, Some(Duration::from_secs(3600))),
+            Duration::from_secs(60)
+        );
+    }
+}
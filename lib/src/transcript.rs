@@ -0,0 +1,115 @@
+use std::{
+    fs::{self, File},
+    io::Write,
+    os::unix::process::ExitStatusExt,
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use time::OffsetDateTime;
+
+use crate::{archive, recording, sh};
+
+/// Every command run to provision a node, with its output, when it ran, and how it exited.
+/// Credentials in the output are scrubbed like in recordings.
+pub struct Transcript {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+/// Where the transcripts of the nodes of `cluster` are kept.
+pub fn transcripts_dir(cluster: &str) -> Result<PathBuf> {
+    Ok(Path::new(&crate::cache_root()?)
+        .join("bitte")
+        .join("transcripts")
+        .join(cluster))
+}
+
+/// How a command exited, like `exit 1` or `killed by signal 9`.
+pub fn describe_exit(code: Option<i32>, signal: Option<i32>) -> String {
+    match (code, signal) {
+        (Some(code), _) => format!("exit {}", code),
+        (None, Some(signal)) => format!("killed by signal {}", signal),
+        (None, None) => "exited".to_string(),
+    }
+}
+
+impl Transcript {
+    /// Start the transcript of `node`, named after it and the time provisioning started.
+    pub fn create(cluster: &str, node: &str) -> Result<Self> {
+        let dir = transcripts_dir(cluster)?;
+        fs::create_dir_all(&dir).with_context(|| format!("couldn't create {}", dir.display()))?;
+        let started = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let path = dir.join(format!("{}-{}.log", node, started));
+        let file =
+            File::create(&path).with_context(|| format!("couldn't create {}", path.display()))?;
+        Ok(Self {
+            path,
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Add `line`, stamped with the current time.
+    pub fn record(&self, line: &str) {
+        let line = format!("{} {}\n", OffsetDateTime::now_utc(), recording::scrub(line));
+        let mut file = match self.file.lock() {
+            Ok(file) => file,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            warn!("couldn't write to {}: {}", self.path.display(), e);
+        }
+    }
+
+    pub fn command(&self, command: &str) {
+        self.record(&format!("$ {}", command));
+    }
+
+    pub fn output(&self, line: &str) {
+        self.record(&format!("| {}", line));
+    }
+
+    pub fn exit(&self, status: ExitStatus) {
+        self.record(&describe_exit(status.code(), status.signal()));
+    }
+
+    /// Upload the transcript next to the binary cache of the cluster, returning its S3 URL.
+    pub fn upload(&self, cache: &str, cluster: &str) -> Result<String> {
+        let (bucket, region) = archive::s3_bucket(cache)?;
+        let name = self
+            .path
+            .file_name()
+            .context("the transcript has no name")?
+            .to_string_lossy();
+        let url = format!("s3://{}/bitte/transcripts/{}/{}", bucket, cluster, name);
+
+        let mut cmd = execute::command_args!("aws", "s3", "cp", &self.path, &url);
+        if let Some(region) = region {
+            cmd.arg("--region").arg(region);
+        }
+        sh(cmd).with_context(|| format!("couldn't upload the transcript to {}", url))?;
+        info!("uploaded the transcript to {}", url);
+        Ok(url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::describe_exit;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_describe_exit() {
+        assert_eq!(describe_exit(Some(0), None), "exit 0");
+        assert_eq!(describe_exit(Some(1), None), "exit 1");
+        assert_eq!(describe_exit(None, Some(9)), "killed by signal 9");
+    }
+}
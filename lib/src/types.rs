@@ -1,17 +1,18 @@
 use std::collections::HashMap;
 
+use aws_sdk_ec2::model::Instance;
 use clap::ArgMatches;
 use colored::*;
 use restson::RestPath;
-use rusoto_core::Region;
-use rusoto_ec2::{DescribeInstancesRequest, Ec2, Ec2Client, Filter, Instance, Tag};
 use serde::{de::Deserializer, Deserialize, Serialize};
-use std::collections::hash_set::HashSet;
 use std::env;
 use std::fs;
 use std::io::BufReader;
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
 use std::time::{Duration, SystemTime};
 
 use anyhow::{Context, Result};
@@ -19,15 +20,22 @@ use enum_utils::FromStr;
 use std::net::{IpAddr, Ipv4Addr};
 use uuid::Uuid;
 
+use futures::future::join_all;
 use tokio::task::JoinHandle;
 
-use reqwest::{
-    header::{HeaderMap, HeaderValue},
-    Client,
+use reqwest::Client;
+
+use crate::{
+    budget::{self, Provider},
+    cancel::CancellationToken,
+    discovery::{self, NodeDiscovery},
+    endpoint::{self, Service, TlsFiles},
+    nomad,
+    pool::ClientPool,
+    power::{self, NodePowerProvider},
+    terraform, Error,
 };
 
-use crate::{terraform, Error};
-
 use regex::Regex;
 
 #[derive(Deserialize)]
@@ -84,6 +92,16 @@ impl RestPath<()> for HttpPutToken {
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct NomadDeployment {
+    #[serde(rename = "ID", default)]
+    pub id: String,
+    #[serde(rename = "JobID", default)]
+    pub job_id: String,
+    #[serde(rename = "Namespace", default)]
+    pub namespace: String,
+    #[serde(rename = "JobVersion", default)]
+    pub job_version: u64,
+    #[serde(rename = "CreateIndex", default)]
+    pub create_index: u64,
     #[serde(rename = "Status")]
     pub status: NomadDeploymentStatus,
     #[serde(rename = "StatusDescription")]
@@ -121,9 +139,15 @@ require progress by: {}",
 
         match &self.status_description {
             Some(description) => match self.status {
-                NomadDeploymentStatus::Running => {
+                NomadDeploymentStatus::Running
+                | NomadDeploymentStatus::Pending
+                | NomadDeploymentStatus::Blocked
+                | NomadDeploymentStatus::Unblocking => {
                     println!("{}", description.yellow());
                 }
+                NomadDeploymentStatus::Paused => {
+                    println!("{}", description.blue());
+                }
                 NomadDeploymentStatus::Complete => {
                     println!("{}", description.green());
                 }
@@ -144,6 +168,10 @@ require progress by: {}",
     pub fn is_done(self: &NomadDeployment) -> bool {
         match self.status {
             NomadDeploymentStatus::Running => false,
+            NomadDeploymentStatus::Paused => false,
+            NomadDeploymentStatus::Pending => false,
+            NomadDeploymentStatus::Blocked => false,
+            NomadDeploymentStatus::Unblocking => false,
             NomadDeploymentStatus::Complete => true,
             NomadDeploymentStatus::Successful => true,
             NomadDeploymentStatus::Failed => true,
@@ -190,6 +218,14 @@ pub enum NomadDeploymentStatus {
     Successful,
     #[serde(rename = "cancelled")]
     Cancelled,
+    #[serde(rename = "paused")]
+    Paused,
+    #[serde(rename = "pending")]
+    Pending,
+    #[serde(rename = "blocked")]
+    Blocked,
+    #[serde(rename = "unblocking")]
+    Unblocking,
 }
 
 #[derive(Debug, Deserialize)]
@@ -322,8 +358,9 @@ pub struct NomadJobPlanDiff {
     pub id: String,
     #[serde(rename = "Objects")]
     pub objects: Option<Vec<NomadJobPlanObject>>,
+    /// Null when no task group changed.
     #[serde(rename = "TaskGroups")]
-    pub task_groups: Vec<NomadJobPlanTaskGroup>,
+    pub task_groups: Option<Vec<NomadJobPlanTaskGroup>>,
     #[serde(rename = "Type")]
     pub diff_type: NomadJobPlanType,
 }
@@ -372,6 +409,128 @@ pub struct NomadJobPlanObject {
     pub annotations: Option<Vec<String>>,
 }
 
+impl NomadJobPlanDiff {
+    /// Print the diff like `nomad job plan` does, with `+`, `-`, and `~` per field.
+    pub fn display(&self) {
+        println!("{}:", self.id);
+        diff_fields(2, &self.fields);
+        diff_objects(2, &self.objects);
+
+        for task_group in self.task_groups.iter().flatten() {
+            println!("{:>2} {}:", "", task_group.name);
+            diff_objects(2, &task_group.objects);
+            diff_fields(2, &task_group.fields);
+            diff_objects(2, &task_group.tasks);
+        }
+    }
+
+    /// The names of the job fields, job objects, and task groups that changed.
+    pub fn changes(&self) -> Vec<String> {
+        let fields = self
+            .fields
+            .iter()
+            .flatten()
+            .filter(|field| field.field_type.changed())
+            .map(|field| field.name.clone());
+        let objects = self
+            .objects
+            .iter()
+            .flatten()
+            .filter(|object| object.object_type.changed())
+            .map(|object| object.name.clone());
+        let groups = self
+            .task_groups
+            .iter()
+            .flatten()
+            .filter(|group| group.task_group_type.changed())
+            .map(|group| group.name.clone());
+        fields.chain(objects).chain(groups).collect()
+    }
+}
+
+fn diff_field(indent: usize, field: &NomadJobPlanField) {
+    print_annotations(indent + 4, &field.annotations);
+
+    let old = match redact(field.name.as_str(), field.old.as_str()) {
+        "" => "null",
+        o => o,
+    };
+
+    let new = match redact(field.name.as_str(), field.new.as_str()) {
+        "" => "null",
+        o => o,
+    };
+
+    match field.field_type {
+        NomadJobPlanType::Added => println!(
+            "{:>width$} {}: {}",
+            "+".green(),
+            field.name,
+            new.green(),
+            width = indent
+        ),
+        NomadJobPlanType::Deleted => println!(
+            "{:>width$} {}: {}",
+            "-".red(),
+            field.name,
+            old.red(),
+            width = indent
+        ),
+        NomadJobPlanType::Edited => println!(
+            "{:>width$} {}: {} -> {}",
+            "~".yellow(),
+            field.name,
+            old.red(),
+            new.green(),
+            width = indent
+        ),
+        NomadJobPlanType::None => (),
+    }
+}
+
+fn redact<'a>(name: &str, value: &'a str) -> &'a str {
+    match name {
+        "ConsulToken" => "<redacted>",
+        _ => value,
+    }
+}
+
+fn diff_fields(indent: usize, fields: &Option<Vec<NomadJobPlanField>>) {
+    if let Some(fields) = fields {
+        for field in fields {
+            diff_field(indent + 2, field)
+        }
+    }
+}
+
+fn diff_object(indent: usize, obj: &NomadJobPlanObject) {
+    diff_fields(indent + 2, &obj.fields);
+    diff_objects(indent + 2, &obj.objects);
+}
+
+fn diff_objects(indent: usize, objects: &Option<Vec<NomadJobPlanObject>>) {
+    if let Some(objects) = objects {
+        for obj in objects {
+            println!("{:>width$} {}:", "", obj.name, width = indent + 2);
+            print_annotations(indent + 6, &obj.annotations);
+            diff_object(indent + 2, obj);
+        }
+    }
+}
+
+fn print_annotations(indent: usize, annotations: &Option<Vec<String>>) {
+    if let Some(annotations) = annotations {
+        for annotation in annotations {
+            println!(
+                "{:>width$} {}",
+                "!".purple(),
+                annotation.purple(),
+                width = indent
+            )
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct NomadJobPlanUpdates {
     pub create: Option<i64>,
@@ -385,6 +544,12 @@ pub enum NomadJobPlanType {
     None,
 }
 
+impl NomadJobPlanType {
+    pub fn changed(&self) -> bool {
+        !matches!(self, Self::None)
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CueRender {
     #[serde(rename = "Job")]
@@ -445,10 +610,90 @@ pub struct Periodic {
     pub prohibit_overlap: bool,
 }
 
-#[derive(Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NomadJobSummary {
+    #[serde(rename = "JobID")]
+    pub job_id: String,
+    #[serde(rename = "Namespace")]
+    pub namespace: String,
+    #[serde(rename = "Summary")]
+    pub summary: HashMap<String, NomadTaskGroupSummary>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NomadTaskGroupSummary {
+    #[serde(rename = "Queued")]
+    pub queued: i64,
+    #[serde(rename = "Complete")]
+    pub complete: i64,
+    #[serde(rename = "Failed")]
+    pub failed: i64,
+    #[serde(rename = "Running")]
+    pub running: i64,
+    #[serde(rename = "Starting")]
+    pub starting: i64,
+    #[serde(rename = "Lost")]
+    pub lost: i64,
+}
+
+/// A Consul ACL token, as read, created, or listed. Listings leave the secret out.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
 pub struct ConsulAclTokenRead {
-    #[serde(rename = "SecretID")]
+    #[serde(
+        rename = "AccessorID",
+        default,
+        skip_serializing_if = "String::is_empty"
+    )]
+    pub accessor_id: String,
+    #[serde(rename = "SecretID", default, skip_serializing_if = "String::is_empty")]
     pub secret_id: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub policies: Option<Vec<ConsulAclLink>>,
+    #[serde(default)]
+    pub service_identities: Option<Vec<ConsulServiceIdentity>>,
+    #[serde(default)]
+    pub local: bool,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub create_time: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ConsulAclLink {
+    #[serde(rename = "ID", default, skip_serializing_if = "String::is_empty")]
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ConsulServiceIdentity {
+    pub service_name: String,
+}
+
+#[derive(Deserialize)]
+pub struct SsmSendCommand {
+    #[serde(rename = "Command")]
+    pub command: SsmCommand,
+}
+
+#[derive(Deserialize)]
+pub struct SsmCommand {
+    #[serde(rename = "CommandId")]
+    pub command_id: String,
+}
+
+#[derive(Deserialize)]
+pub struct SsmCommandInvocation {
+    #[serde(rename = "Status")]
+    pub status: String,
+    #[serde(rename = "StandardOutputContent")]
+    pub standard_output_content: String,
+    #[serde(rename = "StandardErrorContent")]
+    pub standard_error_content: String,
 }
 
 #[derive(Deserialize)]
@@ -461,6 +706,363 @@ pub struct VaultTokenLookupData {
     pub id: String,
 }
 
+/// The contents of `~/.terraform.d/credentials.tfrc.json` written by `terraform login`.
+#[derive(Deserialize, Default)]
+pub struct TerraformCredentials {
+    #[serde(default)]
+    pub credentials: HashMap<String, TerraformCredential>,
+}
+
+#[derive(Deserialize)]
+pub struct TerraformCredential {
+    pub token: String,
+}
+
+#[derive(Deserialize)]
+pub struct TfcWorkspace {
+    pub data: TfcWorkspaceData,
+}
+
+#[derive(Deserialize)]
+pub struct TfcWorkspaceData {
+    pub id: String,
+    pub attributes: TfcWorkspaceAttributes,
+    #[serde(default)]
+    pub relationships: TfcWorkspaceRelationships,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct TfcWorkspaceRelationships {
+    pub locked_by: Option<TfcRelationship>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TfcWorkspaceAttributes {
+    pub name: String,
+    #[serde(default)]
+    pub locked: bool,
+    pub execution_mode: Option<String>,
+}
+
+/// Body for creating and updating workspaces.
+#[derive(Serialize)]
+pub struct TfcWorkspaceUpdate {
+    pub data: TfcWorkspaceUpdateData,
+}
+
+#[derive(Serialize)]
+pub struct TfcWorkspaceUpdateData {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub attributes: TfcWorkspaceUpdateAttributes,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TfcWorkspaceUpdateAttributes {
+    pub name: String,
+    pub execution_mode: String,
+}
+
+#[derive(Deserialize)]
+pub struct TfcStateVersion {
+    pub data: TfcStateVersionData,
+}
+
+#[derive(Deserialize)]
+pub struct TfcStateVersionData {
+    pub id: String,
+    pub attributes: TfcStateVersionAttributes,
+    #[serde(default)]
+    pub relationships: TfcStateVersionRelationships,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TfcStateVersionAttributes {
+    pub serial: u64,
+    pub hosted_state_download_url: String,
+    #[serde(default)]
+    pub created_at: String,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct TfcStateVersionRelationships {
+    pub created_by: Option<TfcRelationship>,
+}
+
+/// A page of `/state-versions`, with the users that created them when asked to include them.
+#[derive(Deserialize)]
+pub struct TfcStateVersions {
+    pub data: Vec<TfcStateVersionData>,
+    #[serde(default)]
+    pub included: Vec<TfcIncludedUser>,
+    #[serde(default)]
+    pub meta: TfcMeta,
+}
+
+#[derive(Deserialize)]
+pub struct TfcIncludedUser {
+    pub id: String,
+    #[serde(default, rename = "type")]
+    pub kind: String,
+    pub attributes: serde_json::Value,
+}
+
+#[derive(Deserialize, Default)]
+pub struct TfcMeta {
+    pub pagination: Option<TfcPagination>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TfcPagination {
+    pub next_page: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct TfcConfigurationVersionCreate {
+    pub data: TfcConfigurationVersionCreateData,
+}
+
+#[derive(Serialize)]
+pub struct TfcConfigurationVersionCreateData {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub attributes: TfcConfigurationVersionCreateAttributes,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TfcConfigurationVersionCreateAttributes {
+    pub auto_queue_runs: bool,
+    pub speculative: bool,
+}
+
+#[derive(Deserialize)]
+pub struct TfcConfigurationVersion {
+    pub data: TfcConfigurationVersionData,
+}
+
+#[derive(Deserialize)]
+pub struct TfcConfigurationVersionData {
+    pub id: String,
+    pub attributes: TfcConfigurationVersionAttributes,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TfcConfigurationVersionAttributes {
+    pub status: String,
+    pub upload_url: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct TfcRunCreate {
+    pub data: TfcRunCreateData,
+}
+
+#[derive(Serialize)]
+pub struct TfcRunCreateData {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub attributes: TfcRunCreateAttributes,
+    pub relationships: serde_json::Value,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TfcRunCreateAttributes {
+    pub message: String,
+    pub is_destroy: bool,
+    pub plan_only: bool,
+    pub auto_apply: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub target_addrs: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct TfcRunAction {
+    pub comment: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct TfcRun {
+    pub data: TfcRunData,
+}
+
+#[derive(Deserialize)]
+pub struct TfcRunData {
+    pub id: String,
+    pub attributes: TfcRunAttributes,
+    pub relationships: TfcRunRelationships,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TfcRunAttributes {
+    pub status: String,
+    pub actions: TfcRunActions,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TfcRunActions {
+    pub is_cancelable: bool,
+    pub is_confirmable: bool,
+    pub is_discardable: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TfcRunRelationships {
+    pub plan: TfcRelationship,
+    pub apply: TfcRelationship,
+    /// Only present when cost estimation is enabled for the organization.
+    pub cost_estimate: Option<TfcRelationship>,
+}
+
+#[derive(Deserialize)]
+pub struct TfcRelationship {
+    pub data: Option<TfcRelationshipData>,
+}
+
+#[derive(Deserialize)]
+pub struct TfcRelationshipData {
+    pub id: String,
+    /// `users`, `runs`, `teams`, ...
+    #[serde(default, rename = "type")]
+    pub kind: String,
+}
+
+#[derive(Serialize)]
+pub struct TfcLockAction {
+    pub reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct TfcUser {
+    pub data: TfcUserData,
+}
+
+#[derive(Deserialize)]
+pub struct TfcUserData {
+    pub attributes: TfcUserAttributes,
+}
+
+#[derive(Deserialize)]
+pub struct TfcUserAttributes {
+    pub username: String,
+}
+
+/// Shared by `TfcPlan` and `TfcApply`, which only differ in the counts they report.
+#[derive(Deserialize)]
+pub struct TfcPlan {
+    pub data: TfcPhaseData,
+}
+
+#[derive(Deserialize)]
+pub struct TfcApply {
+    pub data: TfcPhaseData,
+}
+
+#[derive(Deserialize)]
+pub struct TfcPhaseData {
+    pub id: String,
+    pub attributes: TfcPhaseAttributes,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TfcPhaseAttributes {
+    pub status: String,
+    pub log_read_url: Option<String>,
+    pub resource_additions: Option<usize>,
+    pub resource_changes: Option<usize>,
+    pub resource_destructions: Option<usize>,
+}
+
+#[derive(Deserialize)]
+pub struct TfcCostEstimate {
+    pub data: TfcCostEstimateData,
+}
+
+#[derive(Deserialize)]
+pub struct TfcCostEstimateData {
+    pub id: String,
+    pub attributes: TfcCostEstimateAttributes,
+}
+
+/// Monthly costs are decimal strings in the currency of the organization.
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TfcCostEstimateAttributes {
+    pub status: String,
+    pub prior_monthly_cost: Option<String>,
+    pub proposed_monthly_cost: Option<String>,
+    pub delta_monthly_cost: Option<String>,
+    #[serde(default)]
+    pub matched_resources_count: usize,
+    #[serde(default)]
+    pub unmatched_resources_count: usize,
+}
+
+#[derive(Deserialize)]
+pub struct TfcCostEstimateOutput {
+    pub resources: TfcCostEstimateResources,
+}
+
+#[derive(Deserialize)]
+pub struct TfcCostEstimateResources {
+    #[serde(default)]
+    pub matched: Vec<TfcResourceCost>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TfcResourceCost {
+    pub address: String,
+    pub prior_monthly_cost: String,
+    pub proposed_monthly_cost: String,
+    pub delta_monthly_cost: String,
+}
+
+#[derive(Deserialize)]
+pub struct TfcVars {
+    pub data: Vec<TfcVarData>,
+}
+
+/// A workspace variable, used both for requests and responses.
+#[derive(Serialize, Deserialize)]
+pub struct TfcVar {
+    pub data: TfcVarData,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TfcVarData {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub attributes: TfcVarAttributes,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TfcVarAttributes {
+    pub key: String,
+    /// Not returned for sensitive variables.
+    pub value: Option<String>,
+    /// Either `terraform` or `env`.
+    pub category: String,
+    pub hcl: bool,
+    pub sensitive: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
 #[derive(Serialize)]
 pub struct HttpPutToken {
     pub token: String,
@@ -514,41 +1116,87 @@ pub struct TerraformStateCluster {
     pub value: TerraformStateValue,
 }
 
+/// The `cluster` output of the terraform state. The GCP and Azure configurations name some of
+/// it after their own resources, instance groups, scale sets, and labels, and leave out what
+/// they don't have.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct TerraformStateValue {
+    #[serde(default, alias = "instance-groups", alias = "scale-sets")]
     pub asgs: HashMap<String, TerraformStateAsg>,
+    #[serde(default)]
     pub instances: HashMap<String, TerraformStateInstance>,
-    #[serde(rename = "s3-cache")]
-    pub s3_cache: String,
+    /// Left out by clusters without a binary cache, the commands needing one refuse to run.
+    #[serde(rename = "s3-cache", alias = "cache", default)]
+    pub s3_cache: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct TerraformStateAsg {
+    /// The ARN of an auto-scaling group, the self link of a managed instance group, or the ID
+    /// of a scale set.
+    #[serde(alias = "self-link", alias = "id")]
     pub arn: String,
+    #[serde(alias = "target-size")]
     pub count: i64,
     #[serde(rename = "flake-attr")]
     pub flake_attr: String,
-    #[serde(rename = "instance-type")]
+    #[serde(rename = "instance-type", alias = "machine-type", alias = "vm-size")]
     pub instance_type: String,
+    #[serde(alias = "location")]
     pub region: String,
+    #[serde(default)]
     pub uid: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TerraformStateInstance {
     #[serde(rename = "flake-attr")]
     pub flake_attr: String,
-    #[serde(rename = "instance-type")]
+    #[serde(rename = "instance-type", alias = "machine-type")]
     pub instance_type: String,
     pub name: String,
     #[serde(rename = "private-ip")]
     pub private_ip: String,
-    #[serde(rename = "public-ip")]
+    /// Empty for instances without an external address.
+    #[serde(rename = "public-ip", default)]
     pub public_ip: String,
+    #[serde(default, alias = "labels")]
     pub tags: HashMap<String, String>,
+    #[serde(default)]
     pub uid: String,
 }
 
+/// The parts of `terraform show -json <plan>` needed to summarize a plan.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TerraformPlan {
+    #[serde(default)]
+    pub resource_changes: Vec<TerraformResourceChange>,
+    /// Changes made outside of terraform, detected while refreshing.
+    #[serde(default)]
+    pub resource_drift: Vec<TerraformResourceChange>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TerraformResourceChange {
+    pub address: String,
+    #[serde(rename = "type")]
+    pub resource_type: String,
+    pub change: TerraformChange,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TerraformChange {
+    pub actions: Vec<String>,
+    pub before: Option<serde_json::Value>,
+    pub after: Option<serde_json::Value>,
+    pub after_unknown: Option<serde_json::Value>,
+    /// The attributes whose values are sensitive, `true` where they are.
+    #[serde(default)]
+    pub before_sensitive: Option<serde_json::Value>,
+    #[serde(default)]
+    pub after_sensitive: Option<serde_json::Value>,
+}
+
 /// A description of a Bitte cluster and its nodes
 #[derive(Debug, Serialize, Deserialize)]
 pub struct BitteCluster {
@@ -560,13 +1208,128 @@ pub struct BitteCluster {
     pub terra: Option<TerraformStateValue>,
     #[serde(skip)]
     pub nomad_api_client: Arc<Client>,
+    #[serde(skip)]
+    pub pool: ClientPool,
     pub ttl: SystemTime,
+    /// How far the Nomad clients and allocations in the cache are synced.
+    #[serde(default, skip_serializing_if = "skip_sync")]
+    pub nomad_sync: Option<NomadSync>,
+}
+
+/// The Raft indexes the cached Nomad clients and allocations were last fetched at, so the next
+/// load only asks for what changed since.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct NomadSync {
+    pub namespace: String,
+    pub nodes_index: u64,
+    pub allocs_index: u64,
+    /// When everything was last fetched. Changes don't include garbage collected allocations and
+    /// purged nodes, fetching everything now and then drops them from the cache.
+    pub full_sync: SystemTime,
+    /// The clients that aren't on any of the nodes, like those of an instance that is gone.
+    #[serde(default)]
+    pub clients: NomadClients,
+    /// The allocations that aren't on any client of the nodes.
+    #[serde(default)]
+    pub allocs: NomadAllocs,
+}
+
+/// How long a cache is synced with changes only before everything is fetched again.
+const FULL_SYNC_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How long Nomad holds a blocking query that has no changes to report, for commands that
+/// watch the cluster. It returns as soon as there are.
+const BLOCKING_WAIT: &str = "1s";
+
+impl NomadSync {
+    /// Whether a cache synced this way can be brought up to date with changes only.
+    fn is_current(&self, namespace: &str) -> bool {
+        self.namespace == namespace
+            && self
+                .full_sync
+                .elapsed()
+                .map_or(false, |elapsed| elapsed < FULL_SYNC_INTERVAL)
+    }
+}
+
+/// The clients and allocations aren't in a cache written without them, so it can't be synced.
+fn skip_sync(sync: &Option<NomadSync>) -> bool {
+    sync.is_none() || skip_info(sync)
+}
+
+/// Fetch a Nomad list for what changed after `index`, or all of it without one. Returns the
+/// items and the index to pass next time. Only commands that `watch` wait for changes with a
+/// blocking query, the others get what changed right away.
+async fn fetch_since<T: serde::de::DeserializeOwned>(
+    client: &Client,
+    url: &str,
+    query: &[(&str, &str)],
+    index: Option<u64>,
+    watch: bool,
+) -> Result<(Vec<T>, u64)> {
+    let mut query: Vec<(&str, String)> = query
+        .iter()
+        .map(|(key, value)| (*key, value.to_string()))
+        .collect();
+    if let Some(index) = index {
+        if watch {
+            query.push(("index", index.to_string()));
+            query.push(("wait", BLOCKING_WAIT.to_string()));
+        }
+        query.push(("filter", format!("ModifyIndex > {}", index)));
+    }
+
+    budget::acquire(Provider::Nomad).await?;
+    let response = client
+        .get(url)
+        .query(&query)
+        .send()
+        .await
+        .with_context(|| format!("failed to query: {}", url))?;
+
+    let index = response
+        .headers()
+        .get("X-Nomad-Index")
+        .and_then(|index| index.to_str().ok())
+        .and_then(|index| index.parse().ok())
+        .unwrap_or_default();
+
+    let items = response
+        .json::<Vec<T>>()
+        .await
+        .with_context(|| format!("failed to decode response from: {}", url))?;
+    Ok((items, index))
+}
+
+/// Replace the items of `items` that are in `changed`, by `key`, and append the new ones.
+pub fn merge_changes<T, K: PartialEq>(items: &mut Vec<T>, changed: Vec<T>, key: impl Fn(&T) -> K) {
+    for item in changed {
+        match items.iter().position(|known| key(known) == key(&item)) {
+            Some(position) => items[position] = item,
+            None => items.push(item),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Copy, Clone, FromStr)]
 pub enum BitteProvider {
     #[allow(clippy::upper_case_acronyms)]
     AWS,
+    #[allow(clippy::upper_case_acronyms)]
+    GCP,
+    Azure,
+    /// Machines without a cloud API, like on Equinix Metal, known from the terraform state or
+    /// a hosts file.
+    Metal,
+    /// Virtual machines on the local libvirt, for development and CI.
+    Local,
+}
+
+/// Nodes cached before clusters could span providers all ran on AWS.
+impl Default for BitteProvider {
+    fn default() -> Self {
+        BitteProvider::AWS
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
@@ -576,31 +1339,43 @@ pub struct NomadClient {
     pub allocs: Option<NomadAllocs>,
     #[serde(rename = "Address")]
     pub address: Option<IpAddr>,
+    /// Clients that register again with the same address, like after their data dir was wiped,
+    /// are told apart by it.
+    #[serde(rename = "CreateIndex", default)]
+    pub create_index: u64,
 }
 
 impl NomadClient {
-    async fn find_nomad_nodes(client: Arc<Client>, domain: String) -> Result<NomadClients> {
-        let url = format!("https://nomad.{}/v1/nodes", domain);
-        let nodes = client
-            .get(&url)
-            .send()
-            .await
-            .with_context(|| format!("failed to query: {}", &url))?
-            .json::<NomadClients>()
-            .await
-            .with_context(|| format!("failed to decode response from: {}", &url))?;
-        Ok(nodes)
+    /// The Nomad clients, updated from `previous` with the changes since its index if given.
+    async fn find_nomad_nodes(
+        client: Arc<Client>,
+        domain: String,
+        previous: Option<(NomadClients, u64)>,
+        index: Arc<AtomicU64>,
+        watch: bool,
+    ) -> Result<NomadClients> {
+        let url = format!("{}/v1/nodes", endpoint::address(Service::Nomad, &domain));
+        let since = previous.as_ref().map(|(_, index)| *index);
+        let (changed, latest) = fetch_since(&client, &url, &[], since, watch).await?;
+        index.store(latest, Ordering::Relaxed);
+
+        Ok(match previous {
+            Some((mut nodes, _)) => {
+                merge_changes(&mut nodes, changed, |node: &NomadClient| node.id);
+                nodes
+            }
+            None => changed,
+        })
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BitteNode {
     pub id: String,
     pub name: String,
     pub priv_ip: IpAddr,
     pub pub_ip: IpAddr,
     pub nixos: String,
-    #[serde(skip_serializing_if = "skip_info")]
     pub nomad_client: Option<NomadClient>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub node_type: Option<String>,
@@ -608,10 +1383,22 @@ pub struct BitteNode {
     pub zone: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub asg: Option<String>,
+    /// The AMI the instance was booted from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+    /// `spot` or `scheduled` as EC2 reports it, none for on-demand instances.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lifecycle: Option<String>,
+    /// Tags of the instance other than the ones above.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub tags: HashMap<String, String>,
+    /// The provider the node runs on, one of those of the cluster.
+    #[serde(default)]
+    pub provider: BitteProvider,
 }
 
 fn skip_info<T>(_: &Option<T>) -> bool {
-    env::var("BITTE_INFO_NO_ALLOCS").is_ok()
+    env::var("BITTE_INFO_NO_STATE").is_ok()
 }
 
 pub trait BitteFind
@@ -625,22 +1412,7 @@ where
 impl BitteFind for BitteNodes {
     fn find_needle(self, needle: &str) -> Result<Self::Item> {
         self.into_iter()
-            .find(|node| {
-                let ip = needle.parse::<IpAddr>().ok();
-
-                node.id == needle
-                    || node.name == needle
-                    || node
-                        .nomad_client
-                        .as_ref()
-                        .unwrap_or(&Default::default())
-                        .id
-                        .to_hyphenated()
-                        .to_string()
-                        == needle
-                    || Some(node.priv_ip) == ip
-                    || Some(node.pub_ip) == ip
-            })
+            .find(|node| node.matches(needle))
             .with_context(|| format!("{} does not match any nodes", needle))
     }
 
@@ -672,178 +1444,200 @@ impl BitteFind for BitteNodes {
 
 impl From<Instance> for BitteNode {
     fn from(instance: Instance) -> Self {
-        let tags = instance.tags.unwrap_or_default();
-
-        let nixos = tags
-            .iter()
-            .find(|tag| tag.key == Some("UID".into()))
-            .unwrap_or(&Tag {
-                key: None,
-                value: None,
-            })
-            .value
-            .as_ref();
-
-        let name = tags
-            .iter()
-            .find(|tag| tag.key == Some("Name".into()))
-            .unwrap_or(&Tag {
-                key: None,
-                value: None,
-            })
-            .value
-            .as_ref();
-
-        let asg = tags
-            .iter()
-            .find(|tag| tag.key == Some("aws:autoscaling:groupName".into()))
-            .unwrap_or(&Tag {
-                key: None,
-                value: None,
-            })
-            .value
-            .as_ref();
+        // index the tags once and move the values out instead of scanning and cloning per field
+        let mut tags: HashMap<String, String> = instance
+            .tags
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|tag| Some((tag.key?, tag.value?)))
+            .collect();
 
         let no_ip = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0));
-
-        let zone = if let Some(p) = instance.placement {
-            p.availability_zone
-        } else {
-            None
+        let parse_ip = |ip: Option<String>| {
+            ip.and_then(|ip| IpAddr::from_str(&ip).ok())
+                .unwrap_or(no_ip)
         };
 
         Self {
             id: instance.instance_id.unwrap_or_default(),
-            name: match name {
-                Some(name) => name.to_owned(),
-                None => "".into(),
-            },
-            priv_ip: IpAddr::from_str(&instance.private_ip_address.unwrap_or_default())
-                .unwrap_or(no_ip),
-            pub_ip: IpAddr::from_str(&instance.public_ip_address.unwrap_or_default())
-                .unwrap_or(no_ip),
+            name: tags.remove("Name").unwrap_or_default(),
+            priv_ip: parse_ip(instance.private_ip_address),
+            pub_ip: parse_ip(instance.public_ip_address),
             nomad_client: None,
-            nixos: match nixos {
-                Some(nixos) => nixos.to_owned(),
-                None => "".into(),
-            },
-            node_type: instance.instance_type,
-            zone,
-            asg: asg.map(|asg| asg.to_owned()),
+            nixos: tags.remove("UID").unwrap_or_default(),
+            node_type: instance
+                .instance_type
+                .map(|instance_type| instance_type.as_str().to_string()),
+            zone: instance
+                .placement
+                .and_then(|placement| placement.availability_zone),
+            asg: tags.remove("aws:autoscaling:groupName"),
+            image: instance.image_id,
+            lifecycle: instance
+                .instance_lifecycle
+                .map(|lifecycle| lifecycle.as_str().to_string()),
+            tags,
+            provider: BitteProvider::AWS,
+        }
+    }
+}
+
+impl From<TerraformStateInstance> for BitteNode {
+    fn from(instance: TerraformStateInstance) -> Self {
+        let no_ip = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0));
+
+        Self {
+            id: String::new(),
+            priv_ip: instance.private_ip.parse().unwrap_or(no_ip),
+            pub_ip: instance.public_ip.parse().unwrap_or(no_ip),
+            name: instance.name,
+            nomad_client: None,
+            nixos: instance.tags.get("UID").cloned().unwrap_or_default(),
+            node_type: Some(instance.instance_type),
+            zone: None,
+            asg: None,
+            image: None,
+            lifecycle: None,
+            tags: instance.tags,
+            provider: BitteProvider::default(),
         }
     }
 }
 
 impl BitteNode {
+    /// Whether `needle` is the id, name, nomad client id, or one of the ips of this node.
+    pub fn matches(&self, needle: &str) -> bool {
+        let ip = needle.parse::<IpAddr>().ok();
+
+        self.id == needle
+            || self.name == needle
+            || self
+                .nomad_client
+                .as_ref()
+                .unwrap_or(&Default::default())
+                .id
+                .to_hyphenated()
+                .to_string()
+                == needle
+            || Some(self.priv_ip) == ip
+            || Some(self.pub_ip) == ip
+    }
+
+    /// The region of the node, derived from its zone or the default AWS region. AWS appends a
+    /// letter to the region, `eu-central-1a`, GCP a dash and a letter, `europe-west1-b`, and
+    /// Azure a dash and the number of the availability zone, if any, `westeurope-1`.
+    pub fn region(&self) -> Result<String> {
+        let zone = match &self.zone {
+            Some(zone) => zone,
+            None => return crate::get_env("AWS_DEFAULT_REGION"),
+        };
+        Ok(match self.provider {
+            BitteProvider::GCP | BitteProvider::Azure => match zone.rsplit_once('-') {
+                Some((region, suffix)) if suffix.len() == 1 => region.to_string(),
+                _ => zone.to_string(),
+            },
+            _ => zone.trim_end_matches(char::is_alphabetic).to_string(),
+        })
+    }
+
     async fn find_nodes(
-        provider: BitteProvider,
+        discoveries: Vec<(BitteProvider, Box<dyn NodeDiscovery>)>,
         name: String,
         allocs: AllocHandle,
         clients: ClientHandle,
         state: TerraHandle,
-        args: ArgMatches,
-    ) -> Result<(BitteNodes, Option<TerraformStateValue>)> {
-        match provider {
-            BitteProvider::AWS => {
-                let regions: HashSet<String> = {
-                    let mut result = args.values_of_t("aws-asg-regions")?;
-                    let default = args.value_of_t("aws-region")?;
-                    result.push(default);
-                    result.into_iter().collect()
-                };
-
-                let mut handles = Vec::new();
-
-                for region_str in regions {
-                    let region = Region::from_str(&region_str)?;
-                    let client = Ec2Client::new(region);
-                    let request = DescribeInstancesRequest {
-                        instance_ids: None,
-                        dry_run: None,
-                        filters: Some(vec![
-                            Filter {
-                                name: Some("tag:Cluster".to_owned()),
-                                values: Some(vec![name.to_owned()]),
-                            },
-                            Filter {
-                                name: Some("instance-state-name".to_owned()),
-                                values: Some(vec!["running".to_owned()]),
-                            },
-                        ]),
-                        max_results: None,
-                        next_token: None,
-                    };
-                    let response = tokio::spawn(async move {
-                        client.describe_instances(request).await.with_context(|| {
-                            format!("failed to connect to ec2.{}.amazonaws.com", region_str)
-                        })
-                    });
-                    handles.push(response);
-                }
+    ) -> Result<FoundNodes> {
+        let state = if let Some(state) = state {
+            Some(state.await??)
+        } else {
+            None
+        };
+
+        let found = join_all(discoveries.iter().map(|(provider, discovery)| {
+            let state = state.as_ref();
+            let name = &name;
+            async move { Ok((*provider, discovery.nodes(name, state).await?)) }
+        }))
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?;
+        let instances = discovery::merge(found);
+
+        let allocs = allocs.await??;
+        let clients = clients.await??;
+
+        let mut allocs_by_node: HashMap<Uuid, NomadAllocs> = HashMap::new();
+        for alloc in allocs.into_iter() {
+            allocs_by_node.entry(alloc.node_id).or_default().push(alloc);
+        }
 
-                let mut result: BitteNodes = Vec::new();
-
-                let allocs = allocs.await??;
-                let clients = clients.await??;
-
-                let state = if let Some(state) = state {
-                    Some(state.await??)
-                } else {
-                    None
-                };
-
-                for response in handles.into_iter() {
-                    let response = response.await??;
-                    let iter = response.reservations.into_iter();
-                    let mut nodes: BitteNodes = iter
-                        .flat_map(|reservations| {
-                            reservations
-                                .into_iter()
-                                .flat_map(|reservation| reservation.instances.unwrap_or_default())
-                        })
-                        .map(|instance| {
-                            let mut node = BitteNode::from(instance);
-                            node.nomad_client = match clients
-                                .iter()
-                                .find(|client| client.address == Some(node.priv_ip))
-                            {
-                                Some(client) => {
-                                    let mut client = client.to_owned();
-                                    client.allocs = {
-                                        Some(
-                                            allocs
-                                                .iter()
-                                                .filter(|alloc| alloc.node_id == client.id)
-                                                .map(|alloc| alloc.to_owned())
-                                                .collect::<NomadAllocs>(),
-                                        )
-                                    };
-                                    Some(client)
-                                }
-                                None => None,
-                            };
-
-                            if node.name.is_empty() {
-                                if let Some(state) = &state {
-                                    for inst in state.instances.values() {
-                                        if inst.private_ip == node.priv_ip.to_string() {
-                                            node.name = inst.name.clone()
-                                        };
-                                    }
-                                };
-                            }
-
-                            node
-                        })
-                        .collect();
-
-                    result.append(&mut nodes);
+        let (mut clients_by_ip, mut unmatched) = clients_by_ip(clients);
+
+        let names_by_ip: HashMap<String, String> = state
+            .iter()
+            .flat_map(|state| state.instances.values())
+            .map(|inst| (inst.private_ip.clone(), inst.name.clone()))
+            .collect();
+
+        let mut result: BitteNodes = Vec::with_capacity(instances.len());
+        for mut node in instances.into_iter() {
+            node.nomad_client = clients_by_ip.remove(&node.priv_ip).map(|mut client| {
+                client.allocs = Some(allocs_by_node.remove(&client.id).unwrap_or_default());
+                client
+            });
+
+            if node.name.is_empty() {
+                if let Some(name) = names_by_ip.get(&node.priv_ip.to_string()) {
+                    node.name = name.to_string();
                 }
+            }
 
-                Ok((result, state))
+            result.push(node);
+        }
+
+        unmatched.extend(clients_by_ip.into_values());
+        Ok(FoundNodes {
+            nodes: result,
+            state,
+            clients: unmatched,
+            allocs: allocs_by_node.into_values().flatten().collect(),
+        })
+    }
+}
+
+/// The Nomad clients by their address, and the others. Of the clients sharing an address, the
+/// newest one by `CreateIndex` is the one running there now, the older ones are left over from
+/// before the node registered again.
+fn clients_by_ip(clients: NomadClients) -> (HashMap<IpAddr, NomadClient>, NomadClients) {
+    let mut others = Vec::new();
+    let mut by_ip: HashMap<IpAddr, NomadClient> = HashMap::new();
+    for client in clients {
+        let address = match client.address {
+            Some(address) => address,
+            None => {
+                others.push(client);
+                continue;
+            }
+        };
+        match by_ip.get_mut(&address) {
+            Some(known) if known.create_index < client.create_index => {
+                others.push(std::mem::replace(known, client))
+            }
+            Some(_) => others.push(client),
+            None => {
+                by_ip.insert(address, client);
             }
         }
     }
+    (by_ip, others)
+}
+
+/// The nodes of a cluster, and the Nomad clients and allocations that aren't on any of them.
+struct FoundNodes {
+    nodes: BitteNodes,
+    state: Option<TerraformStateValue>,
+    clients: NomadClients,
+    allocs: NomadAllocs,
 }
 
 type NomadClients = Vec<NomadClient>;
@@ -867,6 +1661,15 @@ impl AllocIndex {
     }
 }
 
+impl std::fmt::Display for AllocIndex {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Int(i) => write!(f, "{}", i),
+            Self::String(s) => write!(f, "{}", s),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct NomadAlloc {
     #[serde(rename = "ID")]
@@ -889,12 +1692,103 @@ pub struct NomadAlloc {
     pub node_id: Uuid,
 }
 
+/// An allocation as returned by `/v1/node/:id/allocations` and `/v1/allocation/:id`, with the
+/// fields needed to follow it through a drain or read its logs.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct NomadNodeAllocation {
+    #[serde(rename = "ID")]
+    pub id: String,
+    pub name: String,
+    #[serde(rename = "JobID")]
+    pub job_id: String,
+    pub namespace: String,
+    pub task_group: String,
+    #[serde(rename = "NodeID")]
+    pub node_id: String,
+    pub client_status: String,
+    pub desired_status: String,
+    #[serde(default)]
+    pub desired_transition: NomadDesiredTransition,
+    pub deployment_status: Option<NomadAllocDeploymentStatus>,
+    pub next_allocation: Option<String>,
+    #[serde(default)]
+    pub create_index: u64,
+    /// State of each task by name, only the names are used. Null until the client picked the
+    /// allocation up.
+    #[serde(default, deserialize_with = "null_as_default")]
+    pub task_states: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct NomadDesiredTransition {
+    pub migrate: Option<bool>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct NomadAllocDeploymentStatus {
+    pub healthy: Option<bool>,
+}
+
+/// The drain related fields of `/v1/node/:id`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct NomadNodeDrain {
+    pub drain: bool,
+    pub drain_strategy: Option<serde_json::Value>,
+    pub scheduling_eligibility: String,
+}
+
 impl NomadAlloc {
-    async fn find_allocs(client: Arc<Client>, domain: String) -> Result<NomadAllocs> {
-        let url = format!("https://nomad.{}/v1/allocations", domain);
+    /// The allocations of `namespace`, updated from `previous` with the changes since its index
+    /// if given.
+    async fn find_allocs(
+        client: Arc<Client>,
+        domain: String,
+        namespace: String,
+        previous: Option<(NomadAllocs, u64)>,
+        index: Arc<AtomicU64>,
+        watch: bool,
+    ) -> Result<NomadAllocs> {
+        let url = format!(
+            "{}/v1/allocations",
+            endpoint::address(Service::Nomad, &domain)
+        );
+        let since = previous.as_ref().map(|(_, index)| *index);
+        let (changed, latest) = fetch_since(
+            &client,
+            &url,
+            &[("namespace", namespace.as_str()), ("task_states", "false")],
+            since,
+            watch,
+        )
+        .await?;
+        index.store(latest, Ordering::Relaxed);
+
+        Ok(match previous {
+            Some((mut allocs, _)) => {
+                merge_changes(&mut allocs, changed, |alloc: &NomadAlloc| alloc.id);
+                allocs
+            }
+            None => changed,
+        })
+    }
+
+    async fn find_node_allocs(
+        client: Arc<Client>,
+        domain: &str,
+        node: Uuid,
+    ) -> Result<NomadAllocs> {
+        let url = format!(
+            "{}/v1/node/{}/allocations",
+            endpoint::address(Service::Nomad, domain),
+            node
+        );
+        budget::acquire(Provider::Nomad).await?;
         let allocs = client
             .get(&url)
-            .query(&[("namespace", "*"), ("task_states", "false")])
             .send()
             .await
             .with_context(|| format!("failed to query: {}", &url))?
@@ -924,91 +1818,260 @@ where
     }
 }
 
+/// Nomad sends null for empty maps and lists.
+fn null_as_default<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + Default,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
+
 type TerraHandle = Option<JoinHandle<Result<TerraformStateValue>>>;
 type ClientHandle = JoinHandle<Result<NomadClients>>;
 type AllocHandle = JoinHandle<Result<NomadAllocs>>;
 
 impl BitteCluster {
-    pub async fn new(args: &ArgMatches, token: Uuid) -> Result<Self> {
+    /// Fetch the cluster from its provider and update the cache.
+    ///
+    /// Cancelling stops waiting for the provider and leaves the cache as it was. Requests
+    /// already sent finish in the background and their results are dropped.
+    pub async fn new(args: &ArgMatches, token: Uuid, cancel: &CancellationToken) -> Result<Self> {
         let name: String = args.value_of_t("name")?;
         let domain: String = args.value_of_t("domain")?;
-        let provider: BitteProvider = {
-            let provider: String = args.value_of_t("provider")?;
-            match provider.parse() {
-                Ok(v) => Ok(v),
-                Err(_) => Err(Error::ProviderError { provider }),
-            }?
+        // a hybrid cluster names all of its providers, like `AWS,Metal`, the first of them
+        // hosting the terraform state, auto-scaling groups and such
+        let providers: Vec<BitteProvider> = {
+            let providers: String = args.value_of_t("provider")?;
+            providers
+                .split(',')
+                .map(|provider| {
+                    provider.trim().parse().map_err(|_| Error::ProviderError {
+                        provider: provider.to_string(),
+                    })
+                })
+                .collect::<Result<_, _>>()?
         };
-
-        let t_state = match &provider {
-            BitteProvider::AWS => Some(tokio::spawn(async move {
-                terraform::output("clients")
-                    .or_else::<anyhow::Error, _>(|_| terraform::output("core"))
-            })),
+        let provider = providers[0];
+
+        // clusters outside of terraform have no state to read
+        let stateless = providers.iter().all(|provider| match provider {
+            BitteProvider::Local => true,
+            BitteProvider::Metal => args.is_present("metal-hosts"),
+            _ => false,
+        });
+        let t_state = if stateless {
+            None
+        } else {
+            Some(tokio::spawn(async move {
+                match terraform::output("clients").await {
+                    Ok(output) => Ok(output),
+                    Err(_) => terraform::output("core").await,
+                }
+            }))
         };
 
-        let nomad_api_client = {
-            let mut token = HeaderValue::from_str(&token.to_string())?;
-            token.set_sensitive(true);
-            let mut headers = HeaderMap::new();
-            headers.insert("X-Nomad-Token", token);
-            Arc::new(
-                Client::builder()
-                    .default_headers(headers)
-                    .gzip(true)
-                    .build()?,
-            )
+        let pool = ClientPool::default();
+        let nomad_api_client = pool.nomad(
+            &endpoint::address(Service::Nomad, &domain),
+            &token.to_string(),
+            &TlsFiles::from_env(Service::Nomad),
+        )?;
+
+        // the allocations of the namespace the subcommand reads from, if it takes one
+        let namespace = args
+            .subcommand()
+            .map_or(nomad::ALL_NAMESPACES.to_string(), |(_, sub)| {
+                nomad::read_namespace(sub)
+            });
+
+        // commands that watch the cluster wait for changes, the others take what there is
+        let watch = args
+            .subcommand()
+            .map_or(false, |(_, sub)| sub.is_present("watch"));
+
+        // bring the clients and allocations of the last load up to date instead of fetching
+        // them all again, while it is recent and of the same namespace
+        let previous = Self::cached(&name).filter(|cached| {
+            cached
+                .nomad_sync
+                .as_ref()
+                .map_or(false, |sync| sync.is_current(&namespace))
+        });
+        let (previous_nodes, previous_allocs, full_sync) = match previous {
+            Some(cached) => {
+                let sync = cached.nomad_sync.expect("the cache is synced");
+                let mut nodes = sync.clients;
+                let mut allocs = sync.allocs;
+                for mut client in cached
+                    .nodes
+                    .into_iter()
+                    .filter_map(|node| node.nomad_client)
+                {
+                    allocs.extend(client.allocs.take().unwrap_or_default());
+                    nodes.push(client);
+                }
+                (
+                    Some((nodes, sync.nodes_index)),
+                    Some((allocs, sync.allocs_index)),
+                    sync.full_sync,
+                )
+            }
+            None => (None, None, SystemTime::now()),
         };
 
+        let allocs_index = Arc::new(AtomicU64::new(0));
         let allocs = tokio::spawn(NomadAlloc::find_allocs(
             Arc::clone(&nomad_api_client),
             domain.to_owned(),
+            namespace.clone(),
+            previous_allocs,
+            Arc::clone(&allocs_index),
+            watch,
         ));
 
+        let nodes_index = Arc::new(AtomicU64::new(0));
         let client_nodes = tokio::spawn(NomadClient::find_nomad_nodes(
             Arc::clone(&nomad_api_client),
             domain.to_owned(),
+            previous_nodes,
+            Arc::clone(&nodes_index),
+            watch,
         ));
 
-        let args = args.clone();
-
-        let nodes = tokio::spawn(BitteNode::find_nodes(
-            provider,
+        let discoveries = providers
+            .iter()
+            .map(|provider| Ok((*provider, discovery::for_provider(*provider, args, &pool)?)))
+            .collect::<Result<_>>()?;
+        let mut nodes = tokio::spawn(BitteNode::find_nodes(
+            discoveries,
             name.to_owned(),
             allocs,
             client_nodes,
             t_state,
-            args,
         ));
 
-        let (nodes, terra) = nodes.await??;
-
-        let cache_name = name.clone();
+        let found = tokio::select! {
+            _ = cancel.cancelled() => None,
+            result = &mut nodes => Some(result??),
+        };
+        let found = match found {
+            Some(found) => found,
+            None => {
+                nodes.abort();
+                return Err(Error::Cancelled.into());
+            }
+        };
 
         let cluster = Self {
             name,
             domain,
             provider,
             nomad_api_client,
-            nodes,
-            terra,
+            pool,
+            nodes: found.nodes,
+            terra: found.state,
             ttl: SystemTime::now()
                 .checked_add(Duration::from_secs(300))
                 .unwrap(),
+            nomad_sync: Some(NomadSync {
+                namespace,
+                nodes_index: nodes_index.load(Ordering::Relaxed),
+                allocs_index: allocs_index.load(Ordering::Relaxed),
+                full_sync,
+                clients: found.clients,
+                allocs: found.allocs,
+            }),
         };
 
-        let file = std::fs::File::create(cache_dir(cache_name)?).ok();
+        cluster.save()?;
+
+        Ok(cluster)
+    }
+
+    /// The cluster as last written to the cache, expired or not.
+    fn cached(name: &str) -> Option<Self> {
+        let file = std::fs::File::open(cache_dir(name.to_string()).ok()?).ok()?;
+        serde_json::from_reader(BufReader::new(file)).ok()
+    }
+
+    /// Write the cluster to the cache read by `init`.
+    pub fn save(&self) -> Result<()> {
+        let file = std::fs::File::create(cache_dir(self.name.clone())?).ok();
 
         if let Some(file) = file {
-            serde_json::to_writer(file, &cluster)?;
+            serde_json::to_writer(file, self)?;
         }
 
-        Ok(cluster)
+        Ok(())
     }
 
+    /// Fetch the allocations of the given Nomad clients again and update the cache with them,
+    /// so operations that move allocations around don't leave it stale or force a full refresh.
+    ///
+    /// The cache keeps its expiry, everything else about the cluster is assumed to be unchanged.
+    pub async fn refresh_allocs(&mut self, clients: &[Uuid]) -> Result<()> {
+        for node in self.nodes.iter_mut() {
+            let client = match node.nomad_client.as_mut() {
+                Some(client) if clients.contains(&client.id) => client,
+                _ => continue,
+            };
+
+            client.allocs = Some(
+                NomadAlloc::find_node_allocs(
+                    Arc::clone(&self.nomad_api_client),
+                    &self.domain,
+                    client.id,
+                )
+                .await?,
+            );
+        }
+
+        self.save()
+    }
+
+    /// Replace the nodes with the instances recorded in state version `serial` of `workspace`,
+    /// to see the cluster as it was before an incident. Nomad clients in auto-scaling groups
+    /// aren't part of the state and are left out. The cache is not updated.
+    pub async fn at_state_serial(mut self, workspace: &str, serial: u64) -> Result<Self> {
+        let state = terraform::output_at(workspace, serial).await?;
+        self.nodes = state.instances.into_values().map(BitteNode::from).collect();
+        for node in self.nodes.iter_mut() {
+            node.provider = self.provider;
+        }
+        self.nodes.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(self)
+    }
+
+    /// Fetch the cluster from the provider again, bypassing and replacing the cached copy.
+    pub fn refresh(
+        args: ArgMatches,
+        token: Option<Uuid>,
+        cancel: CancellationToken,
+    ) -> ClusterHandle {
+        tokio::spawn(async move {
+            let token = nomad_token(&args, token).await?;
+            BitteCluster::new(&args, token, &cancel).await
+        })
+    }
+
+    /// Where the Nomad API of the cluster is reached, see `endpoint::address`.
+    pub fn nomad_address(&self) -> String {
+        endpoint::address(Service::Nomad, &self.domain)
+    }
+
+    /// Power operations for `node`, backed by the provider it runs on.
+    pub fn power(&self, node: &BitteNode) -> Box<dyn NodePowerProvider> {
+        power::for_provider(node.provider, &self.pool)
+    }
+
+    /// The cluster from the cache while it is fresh, otherwise from the provider. `token` is
+    /// the Nomad token given on the command line, without one Vault is only asked for a token
+    /// once the cluster is awaited, so commands that abort the handle don't need Vault.
     #[inline(always)]
-    pub fn init(args: ArgMatches, token: Uuid) -> ClusterHandle {
+    pub fn init(args: ArgMatches, token: Option<Uuid>, cancel: CancellationToken) -> ClusterHandle {
         tokio::spawn(async move {
+            let token = nomad_token(&args, token).await?;
             let file = std::fs::File::open(cache_dir(args.value_of_t("name")?)?).ok();
 
             let cluster: BitteCluster;
@@ -1021,16 +2084,25 @@ impl BitteCluster {
                         let cluster = serde_json::from_reader(reader);
                         match cluster.ok() {
                             Some(c) => c,
-                            None => BitteCluster::new(&args, token).await?,
+                            None => BitteCluster::new(&args, token, &cancel).await?,
                         }
                     };
                     match cluster.ttl.duration_since(SystemTime::now()) {
-                        Ok(_) => cluster,
-                        Err(_) => BitteCluster::new(&args, token).await?,
+                        Ok(_) => {
+                            // the client isn't cached along with the cluster
+                            let mut cluster: BitteCluster = cluster;
+                            cluster.nomad_api_client = cluster.pool.nomad(
+                                &cluster.nomad_address(),
+                                &token.to_string(),
+                                &TlsFiles::from_env(Service::Nomad),
+                            )?;
+                            cluster
+                        }
+                        Err(_) => BitteCluster::new(&args, token, &cancel).await?,
                     }
                 }
             } else {
-                cluster = BitteCluster::new(&args, token).await?;
+                cluster = BitteCluster::new(&args, token, &cancel).await?;
             }
 
             Ok(cluster)
@@ -1038,14 +2110,122 @@ impl BitteCluster {
     }
 }
 
+/// `token`, or else the Nomad token Vault issues to the user for the cluster of `args`.
+async fn nomad_token(args: &ArgMatches, token: Option<Uuid>) -> Result<Uuid> {
+    let domain: String = args.value_of_t("domain")?;
+    match token {
+        Some(token) => Ok(token),
+        None => nomad::nomad_token(&domain)
+            .await?
+            .trim()
+            .parse()
+            .context("Vault issued a Nomad token that isn't a UUID"),
+    }
+}
+
 fn cache_dir(name: String) -> Result<String> {
-    let dir = format!(
-        "{}/bitte",
-        env::var("XDG_CACHE_DIR")
-            .or_else::<anyhow::Error, _>(|_| Ok(format!("{}/.cache", env::var("HOME")?)))?
-    );
+    let dir = format!("{}/bitte", crate::cache_root()?);
 
     fs::create_dir_all(&dir)?;
 
     Ok(format!("{}/{}.json", &dir, name))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        clients_by_ip, merge_changes, BitteNode, BitteProvider, NomadClient, NomadNodeAllocation,
+        TerraformStateValue,
+    };
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_region() {
+        let node = |provider, zone: &str| BitteNode {
+            id: "i-1".to_string(),
+            name: "client".to_string(),
+            priv_ip: "10.0.0.1".parse().unwrap(),
+            pub_ip: "3.0.0.1".parse().unwrap(),
+            nixos: "client".to_string(),
+            nomad_client: None,
+            node_type: None,
+            zone: Some(zone.to_string()),
+            asg: None,
+            image: None,
+            lifecycle: None,
+            tags: Default::default(),
+            provider,
+        };
+        let region = |provider, zone| node(provider, zone).region().unwrap();
+        assert_eq!(region(BitteProvider::AWS, "eu-central-1a"), "eu-central-1");
+        assert_eq!(
+            region(BitteProvider::AWS, "us-east-1-wl1-bos-wlz-1"),
+            "us-east-1-wl1-bos-wlz-1"
+        );
+        assert_eq!(region(BitteProvider::GCP, "europe-west1-b"), "europe-west1");
+        assert_eq!(region(BitteProvider::Azure, "westeurope-1"), "westeurope");
+        assert_eq!(region(BitteProvider::Azure, "westeurope"), "westeurope");
+    }
+
+    #[test]
+    fn test_merge_changes() {
+        let mut items = vec![(1, "pending"), (2, "running")];
+        merge_changes(&mut items, vec![(2, "complete"), (3, "pending")], |item| {
+            item.0
+        });
+        assert_eq!(items, vec![(1, "pending"), (2, "complete"), (3, "pending")]);
+    }
+
+    #[test]
+    fn test_clients_by_ip() {
+        let client = |id: u128, address: Option<&str>, create_index| NomadClient {
+            id: uuid::Uuid::from_u128(id),
+            address: address.map(|address| address.parse().unwrap()),
+            create_index,
+            ..Default::default()
+        };
+        let (by_ip, others) = clients_by_ip(vec![
+            client(1, Some("10.0.0.1"), 10),
+            client(2, Some("10.0.0.1"), 20),
+            client(3, Some("10.0.0.1"), 5),
+            client(4, None, 30),
+        ]);
+        assert_eq!(by_ip.len(), 1);
+        assert_eq!(
+            by_ip[&"10.0.0.1".parse().unwrap()].id,
+            uuid::Uuid::from_u128(2)
+        );
+        let mut others: Vec<u128> = others.iter().map(|client| client.id.as_u128()).collect();
+        others.sort_unstable();
+        assert_eq!(others, vec![1, 3, 4]);
+    }
+
+    #[test]
+    fn test_pending_allocation() {
+        let alloc: NomadNodeAllocation = serde_json::from_str(
+            r#"{"ID": "a", "Name": "web.web[0]", "JobID": "web", "Namespace": "default",
+                "TaskGroup": "web", "NodeID": "n", "ClientStatus": "pending",
+                "DesiredStatus": "run", "DeploymentStatus": null, "NextAllocation": null,
+                "TaskStates": null}"#,
+        )
+        .unwrap();
+        assert!(alloc.task_states.is_empty());
+    }
+
+    #[test]
+    fn test_gcp_state() {
+        let state: TerraformStateValue = serde_json::from_str(
+            r#"{"instance-groups": {"clients": {"self-link": "projects/p/zones/europe-west1-b/instanceGroupManagers/clients",
+                  "target-size": 3, "flake-attr": "clients", "machine-type": "n2-standard-4",
+                  "region": "europe-west1"}},
+                "instances": {"core-1": {"flake-attr": "core-1", "machine-type": "n2-standard-2",
+                  "name": "core-1", "private-ip": "10.132.0.10",
+                  "labels": {"cluster": "testnet", "uid": "testnet-core-1"}}}}"#,
+        )
+        .unwrap();
+        assert_eq!(state.asgs["clients"].count, 3);
+        assert_eq!(state.instances["core-1"].public_ip, "");
+        assert_eq!(state.instances["core-1"].tags["uid"], "testnet-core-1");
+        assert_eq!(state.s3_cache, None);
+    }
+}
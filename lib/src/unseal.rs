@@ -0,0 +1,219 @@
+use std::{fs, net::IpAddr, time::Duration};
+
+use anyhow::{Context, Result};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    endpoint::{Service, TlsFiles},
+    types::BitteNode,
+};
+
+/// The port Vault listens on on the core nodes.
+const VAULT_PORT: u16 = 8200;
+
+/// How long an instance gets to answer, an unreachable one shouldn't hold up the others.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A Vault instance of the cluster, reached directly rather than through the load balancer,
+/// which only sends requests to unsealed instances.
+#[derive(Debug, Clone, Serialize)]
+pub struct VaultInstance {
+    pub node: String,
+    pub address: String,
+}
+
+impl VaultInstance {
+    /// The Vault on each core node, at its public ip with `public`, otherwise at its private
+    /// one, which needs a VPN or a tunnel into the cluster.
+    pub fn on_core_nodes(nodes: &[BitteNode], public: bool) -> Vec<Self> {
+        let mut instances: Vec<Self> = nodes
+            .iter()
+            .filter(|node| node.name.starts_with("core"))
+            .map(|node| {
+                let ip = if public { node.pub_ip } else { node.priv_ip };
+                let host = match ip {
+                    IpAddr::V4(ip) => ip.to_string(),
+                    IpAddr::V6(ip) => format!("[{}]", ip),
+                };
+                Self {
+                    node: node.name.clone(),
+                    address: format!("https://{}:{}", host, VAULT_PORT),
+                }
+            })
+            .collect();
+        instances.sort_by(|a, b| a.node.cmp(&b.node));
+        instances
+    }
+}
+
+/// `/v1/sys/health`, asked to answer with 200 in every state so the body can be read.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VaultHealth {
+    pub initialized: bool,
+    pub sealed: bool,
+    pub standby: bool,
+    #[serde(default)]
+    pub version: String,
+}
+
+/// `/v1/sys/seal-status`, also the answer to each submitted key share.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SealStatus {
+    pub sealed: bool,
+    /// The number of key shares needed to unseal.
+    #[serde(rename = "t")]
+    pub threshold: u32,
+    /// The number of key shares submitted so far.
+    pub progress: u32,
+}
+
+/// The health of a Vault instance, or why it couldn't be asked.
+#[derive(Debug, Serialize)]
+pub struct VaultStatus {
+    pub node: String,
+    pub address: String,
+    pub health: Option<VaultHealth>,
+    pub error: Option<String>,
+}
+
+impl VaultStatus {
+    /// The active instance is the leader of the Raft cluster.
+    pub fn role(&self) -> &'static str {
+        match &self.health {
+            None => "unreachable",
+            Some(health) if !health.initialized => "uninitialized",
+            Some(health) if health.sealed => "sealed",
+            Some(health) if health.standby => "standby",
+            Some(_) => "active",
+        }
+    }
+}
+
+/// Ask each of `instances` for its health.
+pub async fn status(instances: &[VaultInstance]) -> Result<Vec<VaultStatus>> {
+    let unsealer = Unsealer::new()?;
+    let mut statuses = Vec::new();
+    for instance in instances.iter() {
+        let (health, error) = match unsealer.health(instance).await {
+            Ok(health) => (Some(health), None),
+            Err(e) => (None, Some(format!("{:#}", e))),
+        };
+        statuses.push(VaultStatus {
+            node: instance.node.clone(),
+            address: instance.address.clone(),
+            health,
+            error,
+        });
+    }
+    Ok(statuses)
+}
+
+/// Talks to the instances one by one.
+pub struct Unsealer {
+    client: Client,
+}
+
+impl Unsealer {
+    /// The certificates of the instances are issued for the names of the cluster, not for the
+    /// ips they are reached at here, so only their chain is checked.
+    pub fn new() -> Result<Self> {
+        let builder = Client::builder()
+            .danger_accept_invalid_hostnames(true)
+            .timeout(TIMEOUT);
+        Ok(Self {
+            client: TlsFiles::from_env(Service::Vault).apply(builder)?.build()?,
+        })
+    }
+
+    pub async fn health(&self, instance: &VaultInstance) -> Result<VaultHealth> {
+        let url = format!("{}/v1/sys/health", instance.address);
+        self.client
+            .get(&url)
+            .query(&[
+                ("standbyok", "true"),
+                ("sealedcode", "200"),
+                ("uninitcode", "200"),
+                ("perfstandbyok", "true"),
+            ])
+            .send()
+            .await
+            .with_context(|| format!("failed to query: {}", url))?
+            .error_for_status()?
+            .json()
+            .await
+            .with_context(|| format!("failed to decode response from: {}", url))
+    }
+
+    pub async fn seal_status(&self, instance: &VaultInstance) -> Result<SealStatus> {
+        let url = format!("{}/v1/sys/seal-status", instance.address);
+        self.client
+            .get(&url)
+            .send()
+            .await
+            .with_context(|| format!("failed to query: {}", url))?
+            .error_for_status()?
+            .json()
+            .await
+            .with_context(|| format!("failed to decode response from: {}", url))
+    }
+
+    /// Submit one key share to `instance`, returning how far unsealing it got.
+    pub async fn submit(&self, instance: &VaultInstance, key: &str) -> Result<SealStatus> {
+        let url = format!("{}/v1/sys/unseal", instance.address);
+        self.client
+            .put(&url)
+            .json(&json!({ "key": key }))
+            .send()
+            .await
+            .with_context(|| format!("failed to query: {}", url))?
+            .error_for_status()
+            .with_context(|| format!("{} rejected the key share", instance.node))?
+            .json()
+            .await
+            .with_context(|| format!("failed to decode response from: {}", url))
+    }
+}
+
+/// Key shares from the output of `vault operator init -format json`, or one per line.
+pub fn parse_keys(contents: &str) -> Vec<String> {
+    if let Ok(init) = serde_json::from_str::<Value>(contents) {
+        let keys = init["unseal_keys_b64"]
+            .as_array()
+            .or_else(|| init["keys_base64"].as_array());
+        if let Some(keys) = keys {
+            return keys
+                .iter()
+                .filter_map(|key| key.as_str().map(String::from))
+                .collect();
+        }
+    }
+
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect()
+}
+
+pub fn read_keys(path: &str) -> Result<Vec<String>> {
+    let contents = fs::read_to_string(path).with_context(|| format!("couldn't read {}", path))?;
+    Ok(parse_keys(&contents))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_keys;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_parse_keys() {
+        let init = r#"{ "unseal_keys_b64": ["a2V5MQ==", "a2V5Mg=="], "root_token": "s.x" }"#;
+        assert_eq!(parse_keys(init), vec!["a2V5MQ==", "a2V5Mg=="]);
+
+        let lines = "# core unseal keys\nkey1\n\n  key2  \n";
+        assert_eq!(parse_keys(lines), vec!["key1", "key2"]);
+    }
+}
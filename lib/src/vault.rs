@@ -0,0 +1,406 @@
+use std::{collections::BTreeMap, env, fs, path::Path, sync::RwLock, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use colored::*;
+use log::{debug, info, warn};
+use once_cell::sync::Lazy;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use tokio::task::JoinHandle;
+
+use crate::{
+    cancel::{self, CancellationToken},
+    endpoint::Service,
+    login::{self, LoginConfig},
+    pool::ClientPool,
+    text::{self, DiffLine},
+};
+
+/// How long into an invocation the token is first looked up, so short commands don't pay for
+/// it.
+const FIRST_CHECK: Duration = Duration::from_secs(30);
+
+/// A renewal that leaves less than this means the token is at its maximum TTL, and is replaced
+/// by logging in again.
+const MIN_TTL: Duration = Duration::from_secs(2 * 60);
+
+/// Policies Vault keeps itself: `root` can't be changed and `default` is attached to every
+/// token, so neither is removed for missing from the policy directory.
+const BUILTIN_POLICIES: &[&str] = &["root", "default"];
+
+/// The token `keep_alive` logged in for, replacing the one the invocation started with.
+static RENEWED: Lazy<RwLock<Option<String>>> = Lazy::new(Default::default);
+
+#[derive(Debug, Deserialize)]
+struct TokenLookup {
+    data: TokenData,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenData {
+    /// Seconds left, 0 for tokens that don't expire.
+    ttl: u64,
+    renewable: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenRenewal {
+    auth: TokenAuth,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenAuth {
+    lease_duration: u64,
+}
+
+/// A secret read from Vault, with the lease it is valid for.
+#[derive(Debug, Deserialize)]
+pub struct Secret<T> {
+    #[serde(default)]
+    pub lease_id: String,
+    /// Seconds.
+    #[serde(default)]
+    pub lease_duration: u64,
+    pub data: T,
+}
+
+/// The Vault token of the session: the one `keep_alive` logged in for once it had to, otherwise
+/// `VAULT_TOKEN`, which `login::export` sets from the token `bitte login` stored, or the one
+/// the vault CLI keeps in `~/.vault-token`.
+pub fn token() -> Result<String> {
+    if let Some(token) = RENEWED.read().expect("vault token poisoned").as_ref() {
+        return Ok(token.clone());
+    }
+    match env::var("VAULT_TOKEN") {
+        Ok(token) if !token.is_empty() => Ok(token),
+        _ => fs::read_to_string(shellexpand::tilde("~/.vault-token").as_ref())
+            .map(|token| token.trim().to_string())
+            .context("there is no vault token, log in with `bitte login`"),
+    }
+}
+
+/// Read the secret at `path` from the Vault at `VAULT_ADDR`, without the vault CLI.
+pub async fn read<T: DeserializeOwned>(path: &str) -> Result<Secret<T>> {
+    request(reqwest::Method::GET, path)?
+        .send()
+        .await?
+        .error_for_status()
+        .with_context(|| format!("couldn't read {} from vault", path))?
+        .json()
+        .await
+        .with_context(|| format!("failed to decode {} from vault", path))
+}
+
+/// A change to an ACL policy of Vault: `installed` is what Vault holds, `local` what the policy
+/// directory holds, `None` where there is no such policy.
+#[derive(Debug, PartialEq, Eq)]
+pub struct PolicyChange {
+    pub name: String,
+    pub installed: Option<String>,
+    pub local: Option<String>,
+}
+
+impl PolicyChange {
+    pub fn print(&self) {
+        match (&self.installed, &self.local) {
+            (None, _) => println!("{} {}", "+".green(), self.name.green()),
+            (_, None) => println!("{} {}", "-".red(), self.name.red()),
+            _ => println!("{} {}", "~".yellow(), self.name.yellow()),
+        }
+
+        let installed = self.installed.as_deref().unwrap_or_default();
+        let local = self.local.as_deref().unwrap_or_default();
+        for line in text::line_diff(installed, local) {
+            match line {
+                DiffLine::Same(line) => println!("    {}", line),
+                DiffLine::Removed(line) => println!("  {} {}", "-".red(), line.red()),
+                DiffLine::Added(line) => println!("  {} {}", "+".green(), line.green()),
+            }
+        }
+    }
+}
+
+/// The policies in `dir`, one per `<name>.hcl` file.
+pub fn local_policies(dir: &Path) -> Result<BTreeMap<String, String>> {
+    let mut policies = BTreeMap::new();
+    for entry in fs::read_dir(dir).with_context(|| format!("couldn't read {}", dir.display()))? {
+        let path = entry?.path();
+        if path.extension().map_or(true, |ext| ext != "hcl") {
+            continue;
+        }
+        if let Some(name) = path.file_stem().and_then(|name| name.to_str()) {
+            let policy = fs::read_to_string(&path)
+                .with_context(|| format!("couldn't read {}", path.display()))?;
+            policies.insert(name.to_string(), policy);
+        }
+    }
+    Ok(policies)
+}
+
+/// A request to `/v1/<path>` of the Vault at `VAULT_ADDR`, with the token of the session.
+pub fn request(method: reqwest::Method, path: &str) -> Result<reqwest::RequestBuilder> {
+    let address = env::var("VAULT_ADDR").context("VAULT_ADDR is not set")?;
+    let url = format!("{}/v1/{}", address, path.trim_end_matches('/'));
+    let client = ClientPool::shared().service(Service::Vault, &url)?;
+    Ok(client
+        .request(method, &url)
+        .header("X-Vault-Token", token()?))
+}
+
+fn policy_request(method: reqwest::Method, name: &str) -> Result<reqwest::RequestBuilder> {
+    request(method, &format!("sys/policies/acl/{}", name))
+}
+
+/// The ACL policies installed in the Vault at `VAULT_ADDR`.
+pub async fn installed_policies() -> Result<BTreeMap<String, String>> {
+    let list: Value = policy_request(reqwest::Method::GET, "")?
+        .query(&[("list", "true")])
+        .send()
+        .await?
+        .error_for_status()
+        .context("couldn't list the vault policies")?
+        .json()
+        .await?;
+
+    let mut policies = BTreeMap::new();
+    for name in list["data"]["keys"].as_array().into_iter().flatten() {
+        let name = name.as_str().unwrap_or_default();
+        let read: Value = policy_request(reqwest::Method::GET, name)?
+            .send()
+            .await?
+            .error_for_status()
+            .with_context(|| format!("couldn't read the vault policy {}", name))?
+            .json()
+            .await?;
+        let policy = read["data"]["policy"].as_str().unwrap_or_default();
+        policies.insert(name.to_string(), policy.to_string());
+    }
+    Ok(policies)
+}
+
+/// What turns the `installed` policies into the `local` ones. Policies that are only installed
+/// are removed with `prune`, except for the builtin ones. Whitespace at the end of a policy
+/// doesn't count as a change.
+pub fn policy_changes(
+    local: &BTreeMap<String, String>,
+    installed: &BTreeMap<String, String>,
+    prune: bool,
+) -> Vec<PolicyChange> {
+    let mut changes = Vec::new();
+
+    for (name, policy) in local.iter() {
+        let current = installed.get(name);
+        if current.map_or(false, |current| current.trim_end() == policy.trim_end()) {
+            continue;
+        }
+        changes.push(PolicyChange {
+            name: name.clone(),
+            installed: current.cloned(),
+            local: Some(policy.clone()),
+        });
+    }
+
+    if prune {
+        for (name, policy) in installed.iter() {
+            if local.contains_key(name) || BUILTIN_POLICIES.contains(&name.as_str()) {
+                continue;
+            }
+            changes.push(PolicyChange {
+                name: name.clone(),
+                installed: Some(policy.clone()),
+                local: None,
+            });
+        }
+    }
+
+    changes
+}
+
+/// Write the local side of `change` to Vault, or remove the policy if there is none.
+pub async fn apply_policy(change: &PolicyChange) -> Result<()> {
+    let request = match &change.local {
+        Some(policy) => {
+            policy_request(reqwest::Method::PUT, &change.name)?.json(&json!({ "policy": policy }))
+        }
+        None => policy_request(reqwest::Method::DELETE, &change.name)?,
+    };
+    request
+        .send()
+        .await?
+        .error_for_status()
+        .with_context(|| format!("couldn't write the vault policy {}", change.name))?;
+    Ok(())
+}
+
+/// Run `vault` with `args` and the current token.
+async fn vault(args: &[&str]) -> Result<String> {
+    let output = tokio::process::Command::new("vault")
+        .args(args)
+        .env("VAULT_TOKEN", token()?)
+        .output()
+        .await
+        .context("couldn't run vault")?;
+    if !output.status.success() {
+        bail!(
+            "vault {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+async fn lookup() -> Result<TokenData> {
+    let output = vault(&["token", "lookup", "-format", "json"]).await?;
+    let lookup: TokenLookup =
+        serde_json::from_str(&output).context("couldn't parse the vault token lookup")?;
+    Ok(lookup.data)
+}
+
+/// Renew the token of the session, returning how long it is valid now.
+async fn renew() -> Result<Duration> {
+    let output = vault(&["token", "renew", "-format", "json"]).await?;
+    let renewal: TokenRenewal =
+        serde_json::from_str(&output).context("couldn't parse the vault token renewal")?;
+    Ok(Duration::from_secs(renewal.auth.lease_duration))
+}
+
+/// Log in to the Vault at `address` again with the method of `config`, returning the token.
+/// OIDC needs the browser, so it isn't done in the middle of a run.
+pub async fn login(address: &str, config: &LoginConfig) -> Result<String> {
+    let mount = config.mount.as_deref().unwrap_or(&config.method);
+    let token = match config.method.as_str() {
+        "aws" => {
+            let path = format!("-path={}", mount);
+            vault(&["login", "-method", "aws", &path, "-token-only"])
+                .await?
+                .trim()
+                .to_string()
+        }
+        "github" => login::github(address, mount).await?,
+        method => bail!(
+            "logging in to vault with {} needs you, run `bitte login` again",
+            method
+        ),
+    };
+    info!("logged in to vault again");
+    Ok(token)
+}
+
+/// When a token with `ttl` left is renewed: once two thirds of it have passed, leaving time to
+/// log in again if renewal fails.
+pub fn renew_after(ttl: Duration) -> Duration {
+    ttl * 2 / 3
+}
+
+/// Keep the Vault token of the session valid for as long as the invocation runs, renewing it
+/// before it expires and logging in again with `config` once it can't be renewed anymore. The
+/// new token is what `token` returns from then on.
+///
+/// Without a Vault session there is nothing to keep alive and the task ends quietly. It also
+/// ends with `cancel`.
+pub fn keep_alive(cancel: CancellationToken, config: LoginConfig) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        if let Err(e) = renew_until_cancelled(&cancel, &config).await {
+            if !cancel::is_cancelled(&e) {
+                warn!("stopped renewing the vault token: {:?}", e);
+            }
+        }
+    })
+}
+
+async fn renew_until_cancelled(cancel: &CancellationToken, config: &LoginConfig) -> Result<()> {
+    cancel::sleep(cancel, FIRST_CHECK).await?;
+
+    let mut token = match lookup().await {
+        Ok(token) => token,
+        Err(e) => {
+            debug!("no vault token to renew: {:?}", e);
+            return Ok(());
+        }
+    };
+
+    loop {
+        if token.ttl == 0 {
+            debug!("the vault token doesn't expire");
+            return Ok(());
+        }
+
+        let ttl = Duration::from_secs(token.ttl);
+        cancel::sleep(cancel, renew_after(ttl)).await?;
+
+        let renewed = token.renewable
+            && match renew().await {
+                Ok(ttl) if ttl >= MIN_TTL => {
+                    debug!("renewed the vault token for {:?}", ttl);
+                    true
+                }
+                Ok(_) => false,
+                Err(e) => {
+                    warn!("couldn't renew the vault token: {:?}", e);
+                    false
+                }
+            };
+        if !renewed {
+            let address = env::var("VAULT_ADDR").context("VAULT_ADDR is not set")?;
+            let new = login(&address, config)
+                .await
+                .context("couldn't log in to vault again")?;
+            *RENEWED.write().expect("vault token poisoned") = Some(new);
+        }
+
+        token = lookup().await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{policy_changes, renew_after, PolicyChange};
+    use pretty_assertions::assert_eq;
+    use std::{collections::BTreeMap, time::Duration};
+
+    #[test]
+    fn test_renew_after() {
+        assert_eq!(
+            renew_after(Duration::from_secs(3600)),
+            Duration::from_secs(2400)
+        );
+        assert_eq!(
+            renew_after(Duration::from_secs(90)),
+            Duration::from_secs(60)
+        );
+    }
+
+    #[test]
+    fn test_policy_changes() {
+        let policies = |entries: &[(&str, &str)]| -> BTreeMap<String, String> {
+            entries
+                .iter()
+                .map(|(name, policy)| (name.to_string(), policy.to_string()))
+                .collect()
+        };
+        let local = policies(&[
+            ("admin", "path \"*\" {}\n"),
+            ("nomad", "path \"nomad/*\" {}"),
+        ]);
+        let installed = policies(&[
+            ("admin", "path \"*\" {}"),
+            ("nomad", "path \"nomad/creds/*\" {}"),
+            ("old", "path \"old\" {}"),
+            ("default", "path \"sys\" {}"),
+        ]);
+
+        let nomad = PolicyChange {
+            name: "nomad".to_string(),
+            installed: Some("path \"nomad/creds/*\" {}".to_string()),
+            local: Some("path \"nomad/*\" {}".to_string()),
+        };
+        let old = PolicyChange {
+            name: "old".to_string(),
+            installed: Some("path \"old\" {}".to_string()),
+            local: None,
+        };
+        assert_eq!(policy_changes(&local, &installed, false), vec![nomad]);
+        assert_eq!(policy_changes(&local, &installed, true)[1], old);
+    }
+}
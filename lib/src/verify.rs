@@ -0,0 +1,355 @@
+use std::{
+    fs,
+    net::SocketAddr,
+    path::Path,
+    process::Stdio,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    net::{lookup_host, TcpStream},
+    process::Command,
+    sync::Semaphore,
+    time,
+};
+
+use crate::{
+    cancel::CancellationToken,
+    certs,
+    error::Error,
+    types::{BitteCluster, NomadJobSummary},
+};
+
+/// The set of checks run by `bitte verify`, usually read from `verify.json`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VerifyConfig {
+    /// Upper bound on the number of probes in flight at the same time.
+    #[serde(default = "default_concurrency")]
+    pub concurrency: usize,
+    pub checks: Vec<Check>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Check {
+    pub name: String,
+    #[serde(flatten)]
+    pub probe: Probe,
+    /// Seconds to wait for a single attempt.
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    /// Additional attempts made after the first one failed.
+    #[serde(default)]
+    pub retries: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Probe {
+    DnsResolves {
+        host: String,
+    },
+    TcpOpen {
+        host: String,
+        port: u16,
+    },
+    HttpStatus {
+        url: String,
+        #[serde(default = "default_status")]
+        status: u16,
+    },
+    TlsValidDays {
+        host: String,
+        #[serde(default = "default_tls_port")]
+        port: u16,
+        days: u64,
+    },
+    NomadJobHealthy {
+        job: String,
+        #[serde(default = "default_namespace")]
+        namespace: String,
+    },
+}
+
+#[derive(Debug, Serialize)]
+pub struct CheckResult {
+    pub name: String,
+    pub probe: String,
+    pub passed: bool,
+    pub attempts: usize,
+    pub detail: String,
+}
+
+fn default_concurrency() -> usize {
+    8
+}
+
+fn default_timeout() -> u64 {
+    5
+}
+
+fn default_status() -> u16 {
+    200
+}
+
+fn default_tls_port() -> u16 {
+    443
+}
+
+fn default_namespace() -> String {
+    "default".to_string()
+}
+
+impl Probe {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Probe::DnsResolves { .. } => "dns-resolves",
+            Probe::TcpOpen { .. } => "tcp-open",
+            Probe::HttpStatus { .. } => "http-status",
+            Probe::TlsValidDays { .. } => "tls-valid-days",
+            Probe::NomadJobHealthy { .. } => "nomad-job-healthy",
+        }
+    }
+
+    async fn probe(&self, cluster: &BitteCluster) -> Result<String> {
+        match self {
+            Probe::DnsResolves { host } => {
+                let addrs: Vec<SocketAddr> = lookup_host((host.as_str(), 0)).await?.collect();
+                match addrs.first() {
+                    Some(addr) => Ok(format!("{} resolves to {}", host, addr.ip())),
+                    None => bail!("{} did not resolve to any address", host),
+                }
+            }
+            Probe::TcpOpen { host, port } => {
+                let stream = TcpStream::connect((host.as_str(), *port)).await?;
+                Ok(format!("connected to {}", stream.peer_addr()?))
+            }
+            Probe::HttpStatus { url, status } => {
+                let response = reqwest::get(url.as_str()).await?;
+                let actual = response.status().as_u16();
+                if actual == *status {
+                    Ok(format!("{} returned {}", url, actual))
+                } else {
+                    bail!("{} returned {}, expected {}", url, actual, status)
+                }
+            }
+            Probe::TlsValidDays { host, port, days } => tls_valid_days(host, *port, *days).await,
+            Probe::NomadJobHealthy { job, namespace } => {
+                nomad_job_healthy(cluster, job, namespace).await
+            }
+        }
+    }
+}
+
+impl Check {
+    async fn run(self, cluster: Arc<BitteCluster>) -> CheckResult {
+        let timeout = Duration::from_secs(self.timeout);
+        let mut attempts = 0;
+
+        let outcome = loop {
+            attempts += 1;
+            let outcome = match time::timeout(timeout, self.probe.probe(&cluster)).await {
+                Ok(result) => result,
+                Err(_) => Err(anyhow!("timed out after {}s", self.timeout)),
+            };
+
+            match outcome {
+                Err(e) if attempts <= self.retries => {
+                    debug!("{} attempt {} failed: {}", self.name, attempts, e);
+                    time::sleep(Duration::from_secs(1)).await;
+                }
+                outcome => break outcome,
+            }
+        };
+
+        CheckResult {
+            name: self.name,
+            probe: self.probe.kind().to_string(),
+            passed: outcome.is_ok(),
+            attempts,
+            detail: match outcome {
+                Ok(detail) => detail,
+                Err(e) => e.to_string(),
+            },
+        }
+    }
+}
+
+impl VerifyConfig {
+    pub fn read(path: &Path) -> Result<Self> {
+        let file = fs::read_to_string(path)
+            .with_context(|| format!("couldn't read {}", path.display()))?;
+        serde_json::from_str(&file).with_context(|| format!("couldn't parse {}", path.display()))
+    }
+
+    /// Run all checks concurrently, at most `concurrency` at a time, and return their results
+    /// in the order they were declared.
+    ///
+    /// Cancelling aborts the checks still running, the probes don't change anything.
+    pub async fn run(
+        self,
+        cluster: impl Into<Arc<BitteCluster>>,
+        cancel: &CancellationToken,
+    ) -> Result<Vec<CheckResult>> {
+        let cluster = cluster.into();
+        let pool = Arc::new(Semaphore::new(self.concurrency.max(1)));
+
+        let mut handles: Vec<_> = self
+            .checks
+            .into_iter()
+            .map(|check| {
+                let cluster = Arc::clone(&cluster);
+                let pool = Arc::clone(&pool);
+                tokio::spawn(async move {
+                    let _permit = pool.acquire().await;
+                    check.run(cluster).await
+                })
+            })
+            .collect();
+
+        let mut results = Vec::with_capacity(handles.len());
+        for index in 0..handles.len() {
+            let cancelled = tokio::select! {
+                _ = cancel.cancelled() => true,
+                result = &mut handles[index] => {
+                    results.push(result?);
+                    false
+                }
+            };
+
+            if cancelled {
+                for handle in handles.iter() {
+                    handle.abort();
+                }
+                return Err(Error::Cancelled.into());
+            }
+        }
+
+        Ok(results)
+    }
+}
+
+async fn tls_valid_days(host: &str, port: u16, days: u64) -> Result<String> {
+    let connect = format!("{}:{}", host, port);
+    let s_client = Command::new("openssl")
+        .args(&["s_client", "-connect", &connect, "-servername", host])
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .with_context(|| "failed to run openssl s_client")?;
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    served_valid_days(
+        &connect,
+        &String::from_utf8_lossy(&s_client.stdout),
+        &String::from_utf8_lossy(&s_client.stderr),
+        now,
+        days,
+    )
+}
+
+/// Check the certificate `openssl s_client` got from `connect`. An endpoint that served none
+/// is reported as unreachable with the error of openssl, not as expired.
+fn served_valid_days(
+    connect: &str,
+    served: &str,
+    error: &str,
+    now: u64,
+    days: u64,
+) -> Result<String> {
+    let cert = match certs::pem_blocks(served).into_iter().next() {
+        Some(cert) => cert,
+        None => bail!(
+            "couldn't get a certificate from {}: {}",
+            connect,
+            error.trim().lines().last().unwrap_or("no response")
+        ),
+    };
+
+    let expires = certs::expires(&cert)?;
+    if expires <= now {
+        bail!("certificate expired")
+    } else if expires - now <= days * 24 * 60 * 60 {
+        bail!("certificate expires within {} days", days)
+    } else {
+        Ok(format!("certificate valid for at least {} days", days))
+    }
+}
+
+async fn nomad_job_healthy(cluster: &BitteCluster, job: &str, namespace: &str) -> Result<String> {
+    let url = format!("{}/v1/job/{}/summary", cluster.nomad_address(), job);
+    let summary = cluster
+        .nomad_api_client
+        .get(&url)
+        .query(&[("namespace", namespace)])
+        .send()
+        .await
+        .with_context(|| format!("failed to query: {}", &url))?
+        .error_for_status()?
+        .json::<NomadJobSummary>()
+        .await
+        .with_context(|| format!("failed to decode response from: {}", &url))?;
+
+    let unhealthy: Vec<&String> = summary
+        .summary
+        .iter()
+        .filter(|(_, group)| group.running == 0 || group.queued > 0 || group.starting > 0)
+        .map(|(name, _)| name)
+        .collect();
+
+    if unhealthy.is_empty() {
+        Ok(format!("{} task groups running", summary.summary.len()))
+    } else {
+        bail!("task groups not running: {:?}", unhealthy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::served_valid_days;
+    use pretty_assertions::assert_eq;
+    use rcgen::{Certificate, CertificateParams};
+
+    #[test]
+    fn test_served_valid_days() {
+        let mut params = CertificateParams::new(vec!["vault.example.com".to_string()]);
+        params.not_after = time::OffsetDateTime::from_unix_timestamp(10 * 24 * 60 * 60).unwrap();
+        let cert = Certificate::from_params(params).unwrap();
+        let served = format!(
+            "CONNECTED(00000003)\n{}---\n",
+            cert.serialize_pem().unwrap()
+        );
+
+        assert_eq!(
+            served_valid_days("vault:443", &served, "", 0, 5).unwrap(),
+            "certificate valid for at least 5 days"
+        );
+        assert_eq!(
+            served_valid_days("vault:443", &served, "", 0, 30)
+                .unwrap_err()
+                .to_string(),
+            "certificate expires within 30 days"
+        );
+        assert_eq!(
+            served_valid_days("vault:443", &served, "", 11 * 24 * 60 * 60, 5)
+                .unwrap_err()
+                .to_string(),
+            "certificate expired"
+        );
+        assert_eq!(
+            served_valid_days(
+                "vault:443",
+                "CONNECTED(00000003)\n",
+                "connect:errno=111\n",
+                0,
+                5
+            )
+            .unwrap_err()
+            .to_string(),
+            "couldn't get a certificate from vault:443: connect:errno=111"
+        );
+    }
+}
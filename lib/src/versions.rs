@@ -0,0 +1,278 @@
+use std::{cmp::Ordering, collections::HashMap, net::IpAddr};
+
+use anyhow::{Context, Result};
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+
+use crate::{consul, nomad::NomadApi, types::BitteNode, unseal::VaultStatus};
+
+/// What `bitte info --versions` compares across the nodes.
+pub const COMPONENTS: [&str; 4] = ["nixos", "nomad", "consul", "vault"];
+
+/// A member of the gossip pool, from `/v1/agent/members` of Consul or Nomad.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Member {
+    addr: String,
+    #[serde(default)]
+    tags: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct NomadMembers {
+    members: Vec<Member>,
+}
+
+/// The fields of `/v1/nodes` about the Nomad client itself.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct NomadNodeStub {
+    address: String,
+    version: String,
+}
+
+/// The version in the `build` tag of a member, like `1.10.1:db839f18`.
+fn build_version(tags: &HashMap<String, String>) -> Option<String> {
+    let build = tags.get("build")?;
+    build.split(':').next().map(String::from)
+}
+
+fn by_address(members: Vec<Member>) -> HashMap<IpAddr, String> {
+    members
+        .into_iter()
+        .filter_map(|member| Some((member.addr.parse().ok()?, build_version(&member.tags)?)))
+        .collect()
+}
+
+/// The version of the Consul agent on each node, by its private ip.
+pub async fn consul(domain: &str) -> Result<HashMap<IpAddr, String>> {
+    let members: Vec<Member> = consul::request(Method::GET, domain, "agent/members")
+        .await?
+        .send()
+        .await?
+        .error_for_status()
+        .context("couldn't list the consul members")?
+        .json()
+        .await
+        .context("failed to decode the consul members")?;
+    Ok(by_address(members))
+}
+
+/// The version of the Nomad servers and clients, by their private ip.
+pub async fn nomad(nomad: &NomadApi) -> Result<HashMap<IpAddr, String>> {
+    let servers: NomadMembers = nomad.get("/v1/agent/members", &[]).await?;
+    let clients: Vec<NomadNodeStub> = nomad.get("/v1/nodes", &[]).await?;
+
+    let mut versions = by_address(servers.members);
+    for client in clients {
+        if let Ok(ip) = client.address.parse() {
+            versions.insert(ip, client.version);
+        }
+    }
+    Ok(versions)
+}
+
+/// The versions one node runs, `None` where its agent didn't answer or there is none.
+#[derive(Debug, Clone, Serialize)]
+pub struct NodeVersions {
+    pub node: String,
+    /// The auto-scaling group, whose nodes should all run the same system.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub asg: Option<String>,
+    pub nixos: String,
+    pub nomad: Option<String>,
+    pub consul: Option<String>,
+    pub vault: Option<String>,
+}
+
+impl NodeVersions {
+    pub fn get(&self, component: &str) -> Option<&str> {
+        match component {
+            "nixos" if !self.nixos.is_empty() => Some(&self.nixos),
+            "nomad" => self.nomad.as_deref(),
+            "consul" => self.consul.as_deref(),
+            "vault" => self.vault.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// The nodes whose `component` is compared with this one's. Every node has its own
+    /// system, but those of an auto-scaling group share it.
+    fn peers_in<'a>(&self, component: &str, nodes: &'a [NodeVersions]) -> Vec<&'a NodeVersions> {
+        nodes
+            .iter()
+            .filter(|node| component != "nixos" || (self.asg.is_some() && node.asg == self.asg))
+            .collect()
+    }
+}
+
+/// Match the versions the agents reported to `nodes`.
+pub fn collect(
+    nodes: &[BitteNode],
+    nomad: &HashMap<IpAddr, String>,
+    consul: &HashMap<IpAddr, String>,
+    vault: &[VaultStatus],
+) -> Vec<NodeVersions> {
+    nodes
+        .iter()
+        .map(|node| NodeVersions {
+            node: node.name.clone(),
+            asg: node.asg.clone(),
+            nixos: node.nixos.clone(),
+            nomad: nomad.get(&node.priv_ip).cloned(),
+            consul: consul.get(&node.priv_ip).cloned(),
+            vault: vault
+                .iter()
+                .find(|status| status.node == node.name)
+                .and_then(|status| status.health.as_ref())
+                .map(|health| health.version.clone()),
+        })
+        .collect()
+}
+
+/// Order the dot-separated parts of versions, numbers as numbers, so `1.10` comes after `1.9`.
+/// Parts that aren't numbers, like the commit of a NixOS version, compare as text.
+fn compare_parts(a: &str, b: &str) -> Ordering {
+    let (a_parts, b_parts) = (a.split('.'), b.split('.'));
+    for (a, b) in a_parts.clone().zip(b_parts.clone()) {
+        let order = match (a.parse::<u64>(), b.parse::<u64>()) {
+            (Ok(a), Ok(b)) => a.cmp(&b),
+            (Ok(_), Err(_)) => Ordering::Greater,
+            (Err(_), Ok(_)) => Ordering::Less,
+            (Err(_), Err(_)) => a.cmp(b),
+        };
+        if order != Ordering::Equal {
+            return order;
+        }
+    }
+    a_parts.count().cmp(&b_parts.count())
+}
+
+/// Split a version into its release and pre-release, dropping the build metadata.
+fn pre_release(version: &str) -> (&str, Option<&str>) {
+    let version = version.split('+').next().unwrap_or_default();
+    match version.find('-') {
+        Some(dash) => (&version[..dash], Some(&version[dash + 1..])),
+        None => (version, None),
+    }
+}
+
+/// Order versions like semver does, a pre-release like `1.2.0-beta1` comes before `1.2.0`.
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    let ((a, a_pre), (b, b_pre)) = (pre_release(a), pre_release(b));
+    compare_parts(a, b).then_with(|| match (a_pre, b_pre) {
+        (None, None) => Ordering::Equal,
+        (None, Some(_)) => Ordering::Greater,
+        (Some(_), None) => Ordering::Less,
+        (Some(a), Some(b)) => compare_parts(a, b),
+    })
+}
+
+/// The version of `component` most of `nodes` run.
+pub fn majority<'a>(nodes: &[&'a NodeVersions], component: &str) -> Option<&'a str> {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for version in nodes.iter().filter_map(|node| node.get(component)) {
+        *counts.entry(version).or_default() += 1;
+    }
+    // on a tie the later version wins, the others are the ones behind
+    counts
+        .into_iter()
+        .max_by(|(a, a_count), (b, b_count)| {
+            a_count.cmp(b_count).then_with(|| compare_versions(a, b))
+        })
+        .map(|(version, _)| version)
+}
+
+/// Whether `node` runs another version of `component` than most of its peers.
+pub fn skewed(node: &NodeVersions, component: &str, nodes: &[NodeVersions]) -> bool {
+    let peers = node.peers_in(component, nodes);
+    if peers.len() < 2 {
+        return false;
+    }
+    match (node.get(component), majority(&peers, component)) {
+        (Some(version), Some(majority)) => version != majority,
+        _ => false,
+    }
+}
+
+/// Where the nodes differ from the majority, like `nomad on client-2 is 1.1.2, not 1.1.3`.
+pub fn skew(nodes: &[NodeVersions]) -> Vec<String> {
+    let mut skew = Vec::new();
+    for component in COMPONENTS.iter() {
+        for node in nodes.iter() {
+            if skewed(node, component, nodes) {
+                let peers = node.peers_in(component, nodes);
+                skew.push(format!(
+                    "{} on {} is {}, not {}",
+                    component,
+                    node.node,
+                    node.get(component).unwrap_or_default(),
+                    majority(&peers, component).unwrap_or_default()
+                ));
+            }
+        }
+    }
+    skew
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compare_versions, majority, skew, skewed, NodeVersions};
+    use pretty_assertions::assert_eq;
+    use std::cmp::Ordering;
+
+    fn node(name: &str, asg: Option<&str>, nixos: &str, nomad: &str) -> NodeVersions {
+        NodeVersions {
+            node: name.to_string(),
+            asg: asg.map(String::from),
+            nixos: nixos.to_string(),
+            nomad: Some(nomad.to_string()),
+            consul: Some("1.10.1".to_string()),
+            vault: None,
+        }
+    }
+
+    #[test]
+    fn test_skew() {
+        let nodes = vec![
+            node("core-1", None, "a", "1.1.3"),
+            node("core-2", None, "b", "1.1.3"),
+            node("client-1", Some("clients"), "c", "1.1.3"),
+            node("client-2", Some("clients"), "c", "1.1.2"),
+            node("client-3", Some("clients"), "d", "1.1.3"),
+        ];
+        assert!(!skewed(&nodes[0], "nixos", &nodes));
+        assert!(skewed(&nodes[3], "nomad", &nodes));
+        assert!(!skewed(&nodes[3], "consul", &nodes));
+        assert_eq!(
+            skew(&nodes),
+            vec![
+                "nixos on client-3 is d, not c",
+                "nomad on client-2 is 1.1.2, not 1.1.3",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_majority_tie() {
+        let nodes = [
+            node("client-1", None, "a", "1.9.0"),
+            node("client-2", None, "a", "1.10.0"),
+        ];
+        let peers: Vec<&NodeVersions> = nodes.iter().collect();
+        assert_eq!(majority(&peers, "nomad"), Some("1.10.0"));
+
+        assert_eq!(compare_versions("1.10", "1.9"), Ordering::Greater);
+        assert_eq!(compare_versions("1.1.3", "1.1"), Ordering::Greater);
+        assert_eq!(compare_versions("1.2.0-beta1", "1.2.0"), Ordering::Less);
+        assert_eq!(
+            compare_versions("1.2.0-beta2", "1.2.0-beta1"),
+            Ordering::Greater
+        );
+        assert_eq!(
+            compare_versions("21.05.20210802.abc", "21.05.20210802.abd"),
+            Ordering::Less
+        );
+    }
+}